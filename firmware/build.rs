@@ -0,0 +1,19 @@
+//! Put the memory layout of the selected chip on the linker search path (as
+//! `memory.x`, which is included by the `link.x` of cortex-m-rt).
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let chip = if env::var_os("CARGO_FEATURE_NRF52810").is_some() {
+        "nrf52810"
+    } else {
+        "nrf52832"
+    };
+    let source = format!("memory/{}.x", chip);
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy(&source, out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed={}", source);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,13 @@
+MEMORY
+{
+    /* NOTE K = KiBi = 1024 bytes */
+    FLASH : ORIGIN = 0x00000000, LENGTH = 192K
+    RAM : ORIGIN = 0x20000000, LENGTH = 23K
+
+    /* Reserve 1 KiB of RAM for panic message dumps */
+    PANDUMP : ORIGIN = 0x20005C00, LENGTH = 1K
+}
+
+/* Used for panic-persist crate */
+_panic_dump_start = ORIGIN(PANDUMP);
+_panic_dump_end   = ORIGIN(PANDUMP) + LENGTH(PANDUMP);
@@ -5,7 +5,7 @@ MEMORY
     RAM : ORIGIN = 0x20000000, LENGTH = 63K
 
     /* Reserve 1 KiB of RAM for panic message dumps */
-    PANDUMP: ORIGIN = 0x2000FC00, LENGTH = 1K
+    PANDUMP : ORIGIN = 0x2000FC00, LENGTH = 1K
 }
 
 /* Used for panic-persist crate */
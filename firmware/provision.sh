@@ -0,0 +1,90 @@
+#!/bin/bash
+#
+# Write a payload authentication key (and optionally an identity resolving
+# key for the private addresses) to the UICR of the connected node (see
+# src/key.rs for the layout), using nrfjprog. The device entry for the gateway
+# config is printed.
+#
+# Usage: ./provision.sh [--irk] [KEY [IRK]]
+#
+# Keys are 32 hex digits. If no key is given, a random key is generated. With
+# --irk, an IRK is written as well (for the `private-address` feature).
+set -euo pipefail
+
+with_irk=false
+if [ "${1:-}" = "--irk" ]; then
+    with_irk=true
+    shift
+fi
+
+# Return the key from the argument (or a random key)
+function get_key {
+    local key=${1:-$(od -An -N16 -tx1 /dev/urandom | tr -d ' \n')}
+    key=$(echo "$key" | tr 'A-F' 'a-f')
+    if ! [[ $key =~ ^[0-9a-f]{32}$ ]]; then
+        echo "Invalid key (expected 32 hex digits): $key" >&2
+        exit 1
+    fi
+    echo "$key"
+}
+
+# Exit if the key slot at the address is not erased (the registers can only
+# be written once after an erase)
+function check_erased {
+    if nrfjprog --memrd "$1" --w 32 --n 24 \
+        | awk '{ for (i = 2; i <= NF && $i !~ /\|/; i++) print $i }' \
+        | grep -qv '^FFFFFFFF$'; then
+        echo "A key has already been provisioned at $1 (erase the UICR first)" >&2
+        exit 1
+    fi
+}
+
+# Write a key slot: address, header, key
+function write_slot {
+    local address=$1 header=$2 key=$3
+
+    # Key words (little endian)
+    local words=()
+    for i in 0 1 2 3; do
+        local chunk=${key:$((i * 8)):8}
+        words+=("0x${chunk:6:2}${chunk:4:2}${chunk:2:2}${chunk:0:2}")
+    done
+    local check
+    check=$(printf '0x%08x' $((header ^ words[0] ^ words[1] ^ words[2] ^ words[3])))
+
+    nrfjprog --memwr "$address" --val "$header"
+    for i in 0 1 2 3; do
+        nrfjprog --memwr "$(printf '0x%08x' $((address + 4 + i * 4)))" --val "${words[$i]}"
+    done
+    # The check word must be written last
+    nrfjprog --memwr "$(printf '0x%08x' $((address + 20)))" --val "$check"
+}
+
+# Key slots (CUSTOMER[1] and CUSTOMER[7])
+key_slot=0x10001084
+irk_slot=0x1000109c
+
+key=$(get_key "${1:-}")
+check_erased $key_slot
+if $with_irk; then
+    irk=$(get_key "${2:-}")
+    check_erased $irk_slot
+fi
+
+write_slot $key_slot 0x4b590001 "$key"
+if $with_irk; then
+    write_slot $irk_slot 0x49520001 "$irk"
+fi
+
+# Device address from the FICR (DEVICEADDR[0..1])
+read -r addr0 addr1 < <(nrfjprog --memrd 0x100000a4 --w 32 --n 8 | awk '{print $2, $3}')
+address=$(printf '%04x%08x' $((0x$addr1 & 0xffff)) $((0x$addr0)))
+
+echo "Provisioned key for device $address. Gateway config:"
+echo
+echo "[[devices]]"
+echo "hex_addr = \"$address\""
+echo "key = \"$key\""
+if $with_irk; then
+    echo "irk = \"$irk\""
+fi
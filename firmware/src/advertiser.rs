@@ -0,0 +1,130 @@
+//! Radio abstraction: Building and broadcasting the beacons.
+//!
+//! The measurement pipeline only uses the [`Advertiser`] trait, so that the
+//! BLE stack can be swapped without touching it. The implementation is
+//! selected with a feature:
+//!
+//! - `ble-rubble` (default): The rubble BLE stack.
+//! - `ble-raw`: A minimal driver for the RADIO peripheral, without any
+//!   dependencies. It only supports non-connectable advertising, which is
+//!   all that the beacons need. With the `ext-adv` feature, it sends the
+//!   beacons as BLE 5 extended advertisements.
+
+use core::fmt;
+
+use crate::hal::pac;
+
+/// Maximum length of an advertisement data field.
+pub const MAX_ADVERTISEMENT_DATA_LEN: usize = 31;
+
+/// Maximum length of the advertisement data of an extended advertisement
+/// (with the `ext-adv` feature). An `AUX_ADV_IND` could carry 245 bytes, but
+/// adapters split data of more than 229 bytes across several HCI events,
+/// which the gateway doesn't reassemble.
+#[cfg(feature = "ext-adv")]
+pub const MAX_EXTENDED_ADVERTISEMENT_DATA_LEN: usize = 229;
+
+/// Maximum length of the advertisement data of a Sensilo beacon.
+#[cfg(not(feature = "ext-adv"))]
+pub const MAX_BEACON_DATA_LEN: usize = MAX_ADVERTISEMENT_DATA_LEN;
+#[cfg(feature = "ext-adv")]
+pub const MAX_BEACON_DATA_LEN: usize = MAX_EXTENDED_ADVERTISEMENT_DATA_LEN;
+
+/// AD type of the complete local name.
+pub const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+
+/// AD type of the manufacturer specific data.
+pub const AD_TYPE_MANUFACTURER_DATA: u8 = 0xff;
+
+/// AD types of the compatibility frames (see the `compat` module).
+#[cfg_attr(not(any(feature = "eddystone-tlm", feature = "ibeacon")), allow(dead_code))]
+pub const AD_TYPE_FLAGS: u8 = 0x01;
+#[cfg_attr(not(feature = "eddystone-tlm"), allow(dead_code))]
+pub const AD_TYPE_COMPLETE_SERVICE_UUIDS16: u8 = 0x03;
+#[cfg_attr(not(feature = "eddystone-tlm"), allow(dead_code))]
+pub const AD_TYPE_SERVICE_DATA16: u8 = 0x16;
+
+/// A Bluetooth device address.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DeviceAddress {
+    /// Least significant byte first (as sent over the air)
+    raw: [u8; 6],
+    random: bool,
+}
+
+impl DeviceAddress {
+    pub fn public(raw: [u8; 6]) -> Self {
+        Self { raw, random: false }
+    }
+
+    pub fn random(raw: [u8; 6]) -> Self {
+        Self { raw, random: true }
+    }
+
+    /// Read the address of this device from the FICR.
+    pub fn from_ficr(ficr: &pac::FICR) -> Self {
+        let mut raw = [0; 6];
+        raw[..4].copy_from_slice(&ficr.deviceaddr[0].read().bits().to_le_bytes());
+        raw[4..].copy_from_slice(&ficr.deviceaddr[1].read().bits().to_le_bytes()[..2]);
+        if ficr.deviceaddrtype.read().bits() & 1 != 0 {
+            Self::random(raw)
+        } else {
+            Self::public(raw)
+        }
+    }
+
+    /// The address bytes, least significant byte first.
+    pub fn raw(&self) -> &[u8; 6] {
+        &self.raw
+    }
+
+    pub fn is_random(&self) -> bool {
+        self.random
+    }
+}
+
+impl fmt::Debug for DeviceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.raw.iter().rev().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        f.write_str(if self.random { " (random)" } else { " (public)" })
+    }
+}
+
+/// The advertisement data does not fit into a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataTooLong;
+
+/// A BLE stack that can broadcast non-connectable advertisements.
+pub trait Advertiser {
+    /// A beacon, ready to be broadcast
+    type Frame;
+
+    /// Build a beacon with the complete local name and the manufacturer
+    /// specific data (including the company identifier).
+    fn frame(
+        address: DeviceAddress,
+        name: &str,
+        manufacturer_data: &[u8],
+    ) -> Result<Self::Frame, DataTooLong> {
+        Self::raw_frame(
+            address,
+            &[
+                (AD_TYPE_COMPLETE_LOCAL_NAME, name.as_bytes()),
+                (AD_TYPE_MANUFACTURER_DATA, manufacturer_data),
+            ],
+        )
+    }
+
+    /// Build a beacon with arbitrary AD structures (type and data, up to 3
+    /// structures).
+    fn raw_frame(address: DeviceAddress, structures: &[(u8, &[u8])]) -> Result<Self::Frame, DataTooLong>;
+
+    /// Broadcast a beacon once on every advertising channel. The HFXO must
+    /// be running.
+    fn broadcast(&mut self, frame: &Self::Frame);
+}
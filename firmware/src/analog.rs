@@ -0,0 +1,112 @@
+//! Analog inputs (SAADC), for simple analog sensors (e.g. soil moisture or
+//! a voltage divider).
+//!
+//! The analog inputs to sample are defined in `board::ANALOG_INPUTS`. Every
+//! input is sampled with 16x oversampling and sent as voltage in mV, along with
+//! the number of the analog input (AIN0-AIN7). The gateway maps the input
+//! numbers to metric names.
+//!
+//! The SAADC is only enabled while sampling.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::console::rprintln;
+use crate::hal::pac;
+use crate::sensors::{self, Readings, Sensor, SENSOR_ANALOG};
+
+/// Full scale voltage in mV (internal 0.6 V reference, gain 1/6).
+const FULL_SCALE_MV: i32 = 3600;
+
+/// Maximum value at 12 bit resolution.
+const MAX_VALUE: i32 = 4096;
+
+pub struct AnalogInputs {
+    saadc: pac::SAADC,
+    inputs: &'static [u8],
+}
+
+impl AnalogInputs {
+    pub fn new(saadc: pac::SAADC, inputs: &'static [u8]) -> Self {
+        saadc.resolution.write(|w| w.val()._12bit());
+        saadc.oversample.write(|w| w.oversample().over16x());
+        saadc.ch[0].config.write(|w| {
+            w.refsel()
+                .internal()
+                .gain()
+                .gain1_6()
+                .tacq()
+                ._10us()
+                .mode()
+                .se()
+                .resp()
+                .bypass()
+                .resn()
+                .bypass()
+                // Take all oversampling samples with a single SAMPLE task
+                .burst()
+                .enabled()
+        });
+
+        // Calibrate the offset
+        saadc.enable.write(|w| w.enable().enabled());
+        saadc.events_calibratedone.reset();
+        saadc.tasks_calibrateoffset.write(|w| unsafe { w.bits(1) });
+        while saadc.events_calibratedone.read().bits() == 0 {}
+        saadc.enable.write(|w| w.enable().disabled());
+
+        Self { saadc, inputs }
+    }
+
+    /// Sample an analog input. Return the voltage in mV.
+    fn sample(&mut self, input: u8) -> u16 {
+        let saadc = &self.saadc;
+        let mut result: i16 = 0;
+
+        // PSELP values start at 1 for AIN0
+        saadc.ch[0]
+            .pselp
+            .write(|w| unsafe { w.bits(u32::from(input) + 1) });
+        saadc
+            .result
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(&mut result as *mut i16 as u32) });
+        saadc.result.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+
+        saadc.events_started.reset();
+        saadc.tasks_start.write(|w| unsafe { w.bits(1) });
+        while saadc.events_started.read().bits() == 0 {}
+
+        saadc.events_end.reset();
+        saadc.tasks_sample.write(|w| unsafe { w.bits(1) });
+        while saadc.events_end.read().bits() == 0 {}
+
+        saadc.events_stopped.reset();
+        saadc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        while saadc.events_stopped.read().bits() == 0 {}
+        compiler_fence(Ordering::SeqCst);
+
+        // Slightly negative values are possible in single ended mode
+        let raw = i32::from(result).max(0);
+        (raw * FULL_SCALE_MV / MAX_VALUE) as u16
+    }
+}
+
+impl Sensor for AnalogInputs {
+    fn start(&mut self) -> Result<u32, sensors::Error> {
+        // Sampling takes less than 1 ms, it is done when collecting
+        Ok(0)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), sensors::Error> {
+        self.saadc.enable.write(|w| w.enable().enabled());
+        for &input in self.inputs {
+            let millivolts = self.sample(input);
+            rprintln!("SAADC: AIN{}: {} mV", input, millivolts);
+            let mv = millivolts.to_le_bytes();
+            // Analog input number (u8) + u16 LE
+            readings.push(SENSOR_ANALOG, &[input, mv[0], mv[1]]);
+        }
+        self.saadc.enable.write(|w| w.enable().disabled());
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Backlog of past measurements.
+//!
+//! With the `backlog` feature, the node keeps the temperature and humidity of
+//! its last `BACKLOG_LEN` measurements in RAM. Every beacon carries one of
+//! them in a backlog entry (type `0x0f`), in rotation. If the gateway missed
+//! some measurements (e.g. while it was restarted), it recovers them from the
+//! backlog entries sent within the next `BACKLOG_LEN` measurements.
+//!
+//! Entry value (6 bytes):
+//!
+//! - Age (u16 LE): Number of measurements before the measurement of the beacon
+//! - Temperature (i16 LE): Centidegrees Celsius
+//! - Humidity (u16 LE): Centipercent relative humidity
+//!
+//! The backlog is lost on a reset.
+
+/// Number of stored measurements (about 3 minutes at the default interval).
+pub const BACKLOG_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    counter: u16,
+    temperature: i16,
+    humidity: u16,
+}
+
+pub struct Backlog {
+    samples: [Option<Sample>; BACKLOG_LEN],
+}
+
+impl Backlog {
+    pub const fn new() -> Self {
+        Self {
+            samples: [None; BACKLOG_LEN],
+        }
+    }
+
+    /// Store the temperature (millidegrees Celsius) and humidity
+    /// (millipercent) of a measurement.
+    pub fn push(&mut self, counter: u16, temperature: i32, humidity: i32) {
+        let sample = Sample {
+            counter,
+            temperature: (temperature / 10).clamp(i16::MIN.into(), i16::MAX.into()) as i16,
+            humidity: (humidity / 10).clamp(0, u16::MAX.into()) as u16,
+        };
+        self.samples[usize::from(counter) % BACKLOG_LEN] = Some(sample);
+    }
+
+    /// The backlog entry value for the beacon of the measurement `counter`
+    /// (before that measurement is stored). `None` if the sample in turn is
+    /// missing (e.g. shortly after startup).
+    pub fn entry_value(&self, counter: u16) -> Option<[u8; 6]> {
+        let age = 1 + counter % BACKLOG_LEN as u16;
+        let sample_counter = counter.wrapping_sub(age);
+        let sample = self.samples[usize::from(sample_counter) % BACKLOG_LEN]?;
+        if sample.counter != sample_counter {
+            return None;
+        }
+        let mut value = [0; 6];
+        value[0..2].copy_from_slice(&age.to_le_bytes());
+        value[2..4].copy_from_slice(&sample.temperature.to_le_bytes());
+        value[4..6].copy_from_slice(&sample.humidity.to_le_bytes());
+        Some(value)
+    }
+}
@@ -0,0 +1,248 @@
+//! Board abstraction: Pin assignment and the distribution of the sensors
+//! across the I²C buses.
+//!
+//! By default, all sensors are on a single I²C bus (TWIM0). Some carrier
+//! boards put the lux sensor on a second bus (e.g. to avoid address
+//! conflicts). With the `i2c1` feature, a second bus (TWIM1) is configured
+//! and the VEML7700 is moved to it.
+//!
+//! With the `max31855` feature, a thermocouple converter is attached to an
+//! SPI bus (SPIM2). With the `ds18b20` feature, temperature probes are
+//! attached to a 1-Wire bus. With the `pulse-counter` feature, pulses on an
+//! input pin are counted. With the `analog` feature, analog inputs are
+//! sampled. With the `contact` feature, a reed switch is monitored. With the
+//! `buzzer` feature, a piezo buzzer and a button are attached.
+//!
+//! The oversampling of the sensors (with the `oversampling` feature), the
+//! heater of the SHT4x (with the `heater` feature) and the second
+//! temperature and humidity sensor (with the `dual-sensor` feature) are
+//! configured here as well.
+
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+
+use crate::hal::{
+    self as hal,
+    gpio::{p0, Disconnected, Input, Level, Output, Pin, PullUp, PushPull},
+    pac, spim, twim,
+};
+use crate::oversampling::{Oversampling, Reduction};
+#[cfg(any(feature = "sht4x", feature = "dual-sensor"))]
+use crate::sht4x::Heater;
+
+/// An I²C bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    I2c0 = 0,
+    I2c1 = 1,
+}
+
+/// Bus of the SHTC3 temperature and humidity sensor.
+pub const SHT_BUS: Bus = Bus::I2c0;
+
+/// Bus of the second temperature and humidity sensor (an SHT4x) with the
+/// `dual-sensor` feature.
+#[cfg(feature = "dual-sensor")]
+pub const SECONDARY_SHT_BUS: Bus = SHT_BUS;
+
+/// Address of the second sensor. Next to an SHTC3 (at 0x70), it is an
+/// SHT4x-AD1B at 0x44. Next to another SHT4x (with the `sht4x` feature), it
+/// must be a -BD1B variant at 0x45 (or be on another bus).
+#[cfg(feature = "dual-sensor")]
+pub const SECONDARY_SHT_ADDRESS: u8 = if cfg!(feature = "sht4x") { 0x45 } else { 0x44 };
+
+/// Differences between the two sensors (with the `dual-sensor` feature) above
+/// which the readings are flagged as disagreeing, in m°C and m%RH. The
+/// sensors are specified to ±0.2 °C and ±2 %RH, so healthy sensors stay well
+/// within these limits.
+#[cfg(feature = "dual-sensor")]
+pub const SHT_DISAGREEMENT_TEMP: i32 = 1_000;
+#[cfg(feature = "dual-sensor")]
+pub const SHT_DISAGREEMENT_HUMI: i32 = 5_000;
+
+/// Bus of the VEML7700 lux sensor.
+pub const VEML_BUS: Bus = if cfg!(feature = "i2c1") {
+    Bus::I2c1
+} else {
+    Bus::I2c0
+};
+
+/// Frequency of the I²C buses.
+pub const I2C_FREQUENCY: twim::Frequency = twim::Frequency::K250;
+
+/// Analog inputs sampled with the `analog` feature (AIN4 is P0.28, AIN5 is
+/// P0.29). AIN1 and AIN2 are taken by the 1-Wire bus and the pulse counter.
+pub const ANALOG_INPUTS: &[u8] = &[4, 5];
+
+/// Samples per measurement of the SHTC3 with the `oversampling` feature
+/// (about 12 ms per sample). The median rejects single outliers.
+pub const SHT_OVERSAMPLING: Oversampling = Oversampling {
+    samples: 4,
+    reduction: Reduction::Median,
+};
+
+/// Samples per measurement of the VEML7700 with the `oversampling` feature
+/// (the integration time plus 4 ms per sample). The mean smooths the flicker
+/// of artificial light.
+pub const VEML_OVERSAMPLING: Oversampling = Oversampling {
+    samples: 3,
+    reduction: Reduction::Mean,
+};
+
+/// Heater pulse of the SHT4x with the `heater` feature. A short pulse at full
+/// power dries the sensor without heating up its surroundings much.
+#[cfg(any(feature = "sht4x", feature = "dual-sensor"))]
+pub const SHT_HEATER: Heater = Heater::Power200mW1s;
+
+/// Measurements between two heater pulses (about 10 minutes at the default
+/// interval).
+#[cfg(any(feature = "sht4x", feature = "dual-sensor"))]
+pub const HEATER_INTERVAL: u16 = 200;
+
+/// Measurements after a heater pulse whose readings are flagged as heated
+/// (the sensor needs about a minute to cool down).
+#[cfg(any(feature = "sht4x", feature = "dual-sensor"))]
+pub const HEATER_COOLDOWN: u16 = 20;
+
+/// Frequency of the SPI bus.
+pub const SPI_FREQUENCY: spim::Frequency = spim::Frequency::M1;
+
+/// Pins of the SPI bus and the chip select line of the attached sensor.
+pub struct SpiPins {
+    pub spi: spim::Pins,
+    pub cs: Pin<Output<PushPull>>,
+}
+
+/// The pins used by the firmware.
+pub struct Pins {
+    /// Status LED (active low)
+    pub led: Pin<Output<PushPull>>,
+    /// First I²C bus (SDA P0.26, SCL P0.25)
+    pub i2c0: twim::Pins,
+    /// Second I²C bus (SDA P0.30, SCL P0.31), only with the `i2c1` feature
+    pub i2c1: Option<twim::Pins>,
+    /// SPI bus (SCK P0.14, MISO P0.15, CS P0.16), only with the `max31855`
+    /// feature
+    pub spi: Option<SpiPins>,
+    /// 1-Wire bus (P0.03), only with the `ds18b20` feature
+    pub onewire: Option<Pin<Disconnected>>,
+    /// Pulse counter input (P0.04, active low with internal pull-up), only
+    /// with the `pulse-counter` feature
+    pub pulses: Option<Pin<Input<PullUp>>>,
+    /// Door/window contact input (P0.05, closed contact pulls it low), only
+    /// with the `contact` feature
+    pub contact: Option<Pin<Input<PullUp>>>,
+    /// Piezo buzzer (P0.08) and button (P0.06, active low), only with the
+    /// `buzzer` feature
+    pub buzzer: Option<(Pin<Output<PushPull>>, Pin<Input<PullUp>>)>,
+}
+
+impl Pins {
+    pub fn new(gpio: p0::Parts) -> Self {
+        let i2c0 = twim::Pins {
+            sda: gpio.p0_26.into_floating_input().degrade(),
+            scl: gpio.p0_25.into_floating_input().degrade(),
+        };
+        let i2c1 = if cfg!(feature = "i2c1") {
+            Some(twim::Pins {
+                sda: gpio.p0_30.into_floating_input().degrade(),
+                scl: gpio.p0_31.into_floating_input().degrade(),
+            })
+        } else {
+            None
+        };
+        let spi = if cfg!(feature = "max31855") {
+            Some(SpiPins {
+                spi: spim::Pins {
+                    sck: gpio.p0_14.into_push_pull_output(Level::Low).degrade(),
+                    mosi: None,
+                    miso: Some(gpio.p0_15.into_floating_input().degrade()),
+                },
+                cs: gpio.p0_16.into_push_pull_output(Level::High).degrade(),
+            })
+        } else {
+            None
+        };
+        let onewire = if cfg!(feature = "ds18b20") {
+            Some(gpio.p0_03.degrade())
+        } else {
+            None
+        };
+        let pulses = if cfg!(feature = "pulse-counter") {
+            Some(gpio.p0_04.into_pullup_input().degrade())
+        } else {
+            None
+        };
+        let contact = if cfg!(feature = "contact") {
+            Some(gpio.p0_05.into_pullup_input().degrade())
+        } else {
+            None
+        };
+        let buzzer = if cfg!(feature = "buzzer") {
+            Some((
+                gpio.p0_08.into_push_pull_output(Level::Low).degrade(),
+                gpio.p0_06.into_pullup_input().degrade(),
+            ))
+        } else {
+            None
+        };
+        Self {
+            led: gpio.p0_07.into_push_pull_output(Level::High).degrade(),
+            i2c0,
+            i2c1,
+            spi,
+            onewire,
+            pulses,
+            contact,
+            buzzer,
+        }
+    }
+}
+
+/// A TWIM (I²C master) instance. This allows sharing the sensor driver types
+/// across both buses.
+pub enum AnyTwim {
+    Twim0(hal::twim::Twim<pac::TWIM0>),
+    #[cfg(feature = "i2c1")]
+    Twim1(hal::twim::Twim<pac::TWIM1>),
+}
+
+impl Write for AnyTwim {
+    type Error = twim::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            AnyTwim::Twim0(twim) => twim.write(address, bytes),
+            #[cfg(feature = "i2c1")]
+            AnyTwim::Twim1(twim) => twim.write(address, bytes),
+        }
+    }
+}
+
+impl Read for AnyTwim {
+    type Error = twim::Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        match self {
+            AnyTwim::Twim0(twim) => twim.read(address, buffer),
+            #[cfg(feature = "i2c1")]
+            AnyTwim::Twim1(twim) => twim.read(address, buffer),
+        }
+    }
+}
+
+impl WriteRead for AnyTwim {
+    type Error = twim::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        match self {
+            AnyTwim::Twim0(twim) => twim.write_read(address, bytes, buffer),
+            #[cfg(feature = "i2c1")]
+            AnyTwim::Twim1(twim) => twim.write_read(address, bytes, buffer),
+        }
+    }
+}
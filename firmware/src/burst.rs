@@ -0,0 +1,88 @@
+//! Beacon burst parameters.
+//!
+//! By default, every beacon is sent 5 times, 20 ms apart. Nodes close to the
+//! gateway usually get through with a single beacon, while distant nodes
+//! need the repetitions. To save power, the parameters can be configured per
+//! node (without rebuilding the firmware) through the UICR register
+//! `CUSTOMER[0]`:
+//!
+//! - Bits 0-7: Number of beacons per burst (1-16)
+//! - Bits 8-15: Interval between the beacons in milliseconds (1-255)
+//! - Bits 16-31: Must be `0x5b00` (marks the register as configured)
+//!
+//! The register is erased (`0xffffffff`) by default. Invalid values are
+//! ignored.
+
+use crate::console::rprintln;
+use crate::hal::pac;
+use crate::jitter::BURST_JITTER_MS;
+
+/// Marker in the upper half of the register.
+const MAGIC: u32 = 0x5b00;
+
+/// Upper bound of the number of beacons per burst.
+const MAX_COUNT: u8 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BurstConfig {
+    /// Number of beacons per burst
+    pub count: u8,
+    /// Time between two beacons
+    pub interval_ms: u32,
+}
+
+impl BurstConfig {
+    /// Send 5 beacons, spaced 20 ms apart.
+    pub const DEFAULT: BurstConfig = BurstConfig {
+        count: 5,
+        interval_ms: 20,
+    };
+
+    /// Time from the first to the last beacon of a burst.
+    pub const fn duration_ms(&self) -> u32 {
+        (self.count as u32 - 1) * self.interval_ms
+    }
+
+    /// Time from the first to the last beacon of a burst, if every interval
+    /// is extended by the maximum jitter.
+    pub const fn max_duration_ms(&self) -> u32 {
+        (self.count as u32 - 1) * (self.interval_ms + BURST_JITTER_MS)
+    }
+
+    /// Decode the value of the UICR register. The burst (including jitter)
+    /// must be finished within `max_duration_ms` (before the next measurement
+    /// starts).
+    fn decode(value: u32, max_duration_ms: u32) -> Option<Self> {
+        if value >> 16 != MAGIC {
+            return None;
+        }
+        let config = BurstConfig {
+            count: value as u8,
+            interval_ms: (value >> 8) & 0xff,
+        };
+        let valid = (1..=MAX_COUNT).contains(&config.count)
+            && config.interval_ms > 0
+            && config.max_duration_ms() < max_duration_ms;
+        if valid {
+            Some(config)
+        } else {
+            None
+        }
+    }
+
+    /// Read the configuration from the UICR. Fall back to the defaults if it
+    /// is not configured or invalid.
+    pub fn from_uicr(max_duration_ms: u32) -> Self {
+        let value = unsafe { &*pac::UICR::ptr() }.customer[0].read().bits();
+        if value == 0xffff_ffff {
+            return Self::DEFAULT;
+        }
+        match Self::decode(value, max_duration_ms) {
+            Some(config) => config,
+            None => {
+                rprintln!("Warning: Invalid beacon burst configuration {:#010x}", value);
+                Self::DEFAULT
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Piezo buzzer and button, to find nodes and to acknowledge commissioning.
+//!
+//! The buzzer is driven with PWM0 at its resonance frequency. The beep
+//! patterns are played entirely in hardware: Every step of a pattern is a PWM
+//! sequence value, which is repeated for the duration of the step (through the
+//! REFRESH setting). After the last loop, the PWM is stopped (through a short).
+//!
+//! Pressing the button plays the identify pattern (through the GPIOTE PORT
+//! event, like the door/window contact).
+
+use crate::console::rprintln;
+use crate::hal::{
+    gpio::{Input, Output, Pin, PullUp, PushPull},
+    pac,
+};
+
+/// Frequency of the buzzer (typical resonance frequency of small piezo
+/// buzzers).
+const FREQUENCY_HZ: u32 = 4_000;
+
+/// PWM counter top value (the PWM clock is 16 MHz).
+const COUNTERTOP: u16 = (16_000_000 / FREQUENCY_HZ) as u16;
+
+/// Duration of a beep and of the pause between beeps.
+const STEP_MS: u32 = 100;
+
+/// PWM sequence values: 50 % duty cycle (beep) and a constant level (pause).
+/// EasyDMA can only read from RAM, so these are `static mut`.
+static mut BEEP: [u16; 1] = [COUNTERTOP / 2];
+static mut PAUSE: [u16; 1] = [0];
+
+fn p0() -> &'static pac::p0::RegisterBlock {
+    unsafe { &*pac::P0::ptr() }
+}
+
+/// A beep pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// A single short beep
+    Chirp,
+    /// Three beeps
+    Identify,
+}
+
+pub struct Buzzer {
+    pwm: pac::PWM0,
+    _pin: Pin<Output<PushPull>>,
+    _button: Pin<Input<PullUp>>,
+    button: usize,
+}
+
+impl Buzzer {
+    pub fn new(pwm: pac::PWM0, pin: Pin<Output<PushPull>>, button: Pin<Input<PullUp>>) -> Self {
+        pwm.psel.out[0].write(|w| unsafe { w.bits(u32::from(pin.pin())) });
+        pwm.enable.write(|w| w.enable().enabled());
+        pwm.mode.write(|w| w.updown().up());
+        pwm.prescaler.write(|w| w.prescaler().div_1());
+        pwm.countertop
+            .write(|w| unsafe { w.countertop().bits(COUNTERTOP) });
+        pwm.decoder
+            .write(|w| w.load().common().mode().refresh_count());
+        // Every value is repeated for the duration of a step
+        let refresh = STEP_MS * FREQUENCY_HZ / 1000 - 1;
+        unsafe {
+            pwm.seq0.ptr.write(|w| w.bits(BEEP.as_ptr() as u32));
+            pwm.seq0.cnt.write(|w| w.bits(1));
+            pwm.seq0.refresh.write(|w| w.bits(refresh));
+            pwm.seq1.ptr.write(|w| w.bits(PAUSE.as_ptr() as u32));
+            pwm.seq1.cnt.write(|w| w.bits(1));
+            pwm.seq1.refresh.write(|w| w.bits(refresh));
+        }
+        pwm.shorts.write(|w| w.loopsdone_stop().enabled());
+
+        let button_pin = usize::from(button.pin());
+        let buzzer = Self {
+            pwm,
+            _pin: pin,
+            _button: button,
+            button: button_pin,
+        };
+        buzzer.arm();
+        unsafe { &*pac::GPIOTE::ptr() }
+            .intenset
+            .write(|w| w.port().set());
+        buzzer
+    }
+
+    /// Play a pattern (in the background).
+    pub fn play(&mut self, pattern: Pattern) {
+        let loops = match pattern {
+            Pattern::Chirp => 1,
+            Pattern::Identify => 3,
+        };
+        self.pwm.loop_.write(|w| unsafe { w.cnt().bits(loops) });
+        self.pwm
+            .tasks_seqstart[0]
+            .write(|w| unsafe { w.bits(1) });
+    }
+
+    /// The button is pressed if the pin is low.
+    fn pressed(&self) -> bool {
+        p0().in_.read().bits() & (1 << self.button) == 0
+    }
+
+    /// Sense the opposite of the current level.
+    fn arm(&self) {
+        let pressed = self.pressed();
+        p0().pin_cnf[self.button].modify(|_, w| if pressed { w.sense().high() } else { w.sense().low() });
+    }
+
+    /// Handle a GPIOTE PORT event. Play the identify pattern if the button was
+    /// pressed.
+    pub fn on_interrupt(&mut self) {
+        self.arm();
+        if self.pressed() {
+            rprintln!("Button pressed");
+            self.play(Pattern::Identify);
+        }
+    }
+}
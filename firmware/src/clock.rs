@@ -0,0 +1,69 @@
+//! Approximate wall-clock time.
+//!
+//! The node has no calendar clock. Once it has learned the time (with the
+//! `time-sync` feature, from the time beacons of the gateway), it is kept
+//! relative to the RTC. The RTC is clocked by the internal RC oscillator, so
+//! the time drifts by up to a few seconds per hour until it is set again.
+
+use crate::monotonic_nrf52::{Instant, U32Ext};
+
+/// Seconds per day.
+pub const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// The anchor is moved forward after this time, to keep the duration since
+/// the anchor within the range of the instants (see `Instant`).
+const ANCHOR_MAX_AGE_S: u32 = 60 * 60;
+
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    instant: Instant,
+    /// Unix time at the instant
+    unix_time: u32,
+}
+
+pub struct WallClock {
+    anchor: Option<Anchor>,
+    /// Offset of the local time from UTC
+    utc_offset_s: i32,
+}
+
+impl WallClock {
+    /// A clock that does not know the time yet.
+    pub const fn new() -> Self {
+        Self {
+            anchor: None,
+            utc_offset_s: 0,
+        }
+    }
+
+    /// Set the Unix time and the UTC offset of the local time (in minutes)
+    /// at the instant `now`.
+    #[cfg_attr(not(feature = "time-sync"), allow(dead_code))]
+    pub fn set(&mut self, now: Instant, unix_time: u32, utc_offset_min: i16) {
+        self.anchor = Some(Anchor {
+            instant: now,
+            unix_time,
+        });
+        self.utc_offset_s = i32::from(utc_offset_min) * 60;
+    }
+
+    /// The Unix time at the instant `now`, if known. Must be called at least
+    /// every 18 hours.
+    pub fn unix_time(&mut self, now: Instant) -> Option<u32> {
+        let anchor = self.anchor.as_mut()?;
+        let elapsed_s = (now - anchor.instant).as_ticks() / 1.secs().as_ticks();
+        let unix_time = anchor.unix_time.wrapping_add(elapsed_s);
+        if elapsed_s >= ANCHOR_MAX_AGE_S {
+            anchor.instant += elapsed_s.secs();
+            anchor.unix_time = unix_time;
+        }
+        Some(unix_time)
+    }
+
+    /// The local time of day (seconds since midnight) at the instant `now`,
+    /// if known.
+    pub fn second_of_day(&mut self, now: Instant) -> Option<u32> {
+        let local = i64::from(self.unix_time(now)?) + i64::from(self.utc_offset_s);
+        Some(local.rem_euclid(i64::from(SECONDS_PER_DAY)) as u32)
+    }
+}
@@ -0,0 +1,197 @@
+//! Compatibility frames for existing beacon scanners.
+//!
+//! Scanners and apps that only know the standard beacon formats can at least
+//! see the nodes, if a compatibility frame is sent at the end of every beacon
+//! burst (after the Sensilo frames). The format is selected with a feature:
+//!
+//! - `eddystone-tlm`: An unencrypted Eddystone-TLM frame with the supply
+//!   voltage, the temperature, the number of transmitted beacons and the time
+//!   since the first measurement.
+//! - `ibeacon`: An iBeacon frame with the UUID `IBEACON_UUID`, major 0 and
+//!   the lowest two bytes of the device address as minor.
+//!
+//! The gateway ignores both (they contain no Sensilo payload), unless the
+//! device is configured with the `eddystone-tlm` protocol.
+
+use crate::advertiser::DeviceAddress;
+#[cfg(any(feature = "eddystone-tlm", feature = "ibeacon"))]
+use crate::advertiser::{Advertiser, AD_TYPE_FLAGS};
+#[cfg(feature = "ibeacon")]
+use crate::advertiser::AD_TYPE_MANUFACTURER_DATA;
+#[cfg(feature = "eddystone-tlm")]
+use crate::advertiser::{AD_TYPE_COMPLETE_SERVICE_UUIDS16, AD_TYPE_SERVICE_DATA16};
+use crate::monotonic_nrf52::{Instant, U32Ext};
+use crate::sensors::Readings;
+use crate::Beacon;
+#[cfg(any(feature = "eddystone-tlm", feature = "ibeacon"))]
+use crate::Radio;
+#[cfg(feature = "eddystone-tlm")]
+use crate::{hal::pac, sensors::SENSOR_TEMP, telemetry};
+
+/// Flags: LE General Discoverable Mode, BR/EDR not supported.
+#[cfg(any(feature = "eddystone-tlm", feature = "ibeacon"))]
+const FLAGS: u8 = 0x06;
+
+/// 16 bit UUID of the Eddystone service.
+#[cfg(feature = "eddystone-tlm")]
+const EDDYSTONE_UUID: [u8; 2] = [0xaa, 0xfe];
+
+/// Eddystone frame type and version of unencrypted TLM frames.
+#[cfg(feature = "eddystone-tlm")]
+const EDDYSTONE_TLM: [u8; 2] = [0x20, 0x00];
+
+/// Proximity UUID of the iBeacon frames.
+#[cfg(feature = "ibeacon")]
+pub const IBEACON_UUID: [u8; 16] = [
+    0x5e, 0x25, 0x51, 0x10, 0x8a, 0x4c, 0x4b, 0x3d, 0x9c, 0x6a, 0x53, 0x65, 0x6e, 0x73, 0x69, 0x6c,
+];
+
+/// Company identifier of Apple (little endian) and iBeacon type and length.
+#[cfg(feature = "ibeacon")]
+const IBEACON_PREFIX: [u8; 4] = [0x4c, 0x00, 0x02, 0x15];
+
+/// Calibrated RSSI at 1 m (at 0 dBm TX power).
+#[cfg(feature = "ibeacon")]
+const IBEACON_MEASURED_POWER: i8 = -59;
+
+/// The compatibility frame of the next beacon burst, and the uptime for the
+/// Eddystone-TLM frames.
+pub struct CompatBeacon {
+    beacon: Option<Beacon>,
+    /// Tenths of seconds since the first measurement
+    uptime_ds: u32,
+    last_update: Option<Instant>,
+}
+
+impl CompatBeacon {
+    pub const fn new() -> Self {
+        Self {
+            beacon: None,
+            uptime_ds: 0,
+            last_update: None,
+        }
+    }
+
+    /// Build the frame for the measurement at the instant `now`. Must be
+    /// called at least every 18 hours.
+    #[cfg_attr(not(feature = "eddystone-tlm"), allow(unused_variables))]
+    pub fn update(&mut self, address: DeviceAddress, readings: &Readings, now: Instant) {
+        let decisecond = 100.millis();
+        if let Some(last_update) = self.last_update {
+            let elapsed_ds = (now - last_update).as_ticks() / decisecond.as_ticks();
+            self.uptime_ds = self.uptime_ds.wrapping_add(elapsed_ds);
+            // Keep the remainder for the next update
+            self.last_update = Some(last_update + (elapsed_ds * 100).millis());
+        } else {
+            self.last_update = Some(now);
+        }
+
+        #[cfg(feature = "eddystone-tlm")]
+        let beacon = eddystone_tlm(address, readings, self.uptime_ds);
+        #[cfg(feature = "ibeacon")]
+        let beacon = ibeacon(address);
+        #[cfg(not(any(feature = "eddystone-tlm", feature = "ibeacon")))]
+        let beacon: Option<Beacon> = None;
+        self.beacon = beacon;
+    }
+
+    /// The frame to send at the end of the burst (only once).
+    pub fn take(&mut self) -> Option<Beacon> {
+        self.beacon.take()
+    }
+}
+
+#[cfg(feature = "eddystone-tlm")]
+fn eddystone_tlm(address: DeviceAddress, readings: &Readings, uptime_ds: u32) -> Option<Beacon> {
+    // Signed 8.8 fixed point, 0x8000 if not supported
+    let temperature = readings.i32_value(SENSOR_TEMP).map_or(i16::MIN, |millis| {
+        (millis * 256 / 1000).clamp(i16::MIN.into(), i16::MAX.into()) as i16
+    });
+    let mut data = [0; 16];
+    data[0..2].copy_from_slice(&EDDYSTONE_UUID);
+    data[2..4].copy_from_slice(&EDDYSTONE_TLM);
+    data[4..6].copy_from_slice(&supply_voltage_mv().to_be_bytes());
+    data[6..8].copy_from_slice(&temperature.to_be_bytes());
+    data[8..12].copy_from_slice(&u32::from(telemetry::tx_beacons()).to_be_bytes());
+    data[12..16].copy_from_slice(&uptime_ds.to_be_bytes());
+    Radio::raw_frame(
+        address,
+        &[
+            (AD_TYPE_FLAGS, &[FLAGS]),
+            (AD_TYPE_COMPLETE_SERVICE_UUIDS16, &EDDYSTONE_UUID),
+            (AD_TYPE_SERVICE_DATA16, &data),
+        ],
+    )
+    .ok()
+}
+
+#[cfg(feature = "ibeacon")]
+fn ibeacon(address: DeviceAddress) -> Option<Beacon> {
+    let raw = address.raw();
+    let mut data = [0; 25];
+    data[0..4].copy_from_slice(&IBEACON_PREFIX);
+    data[4..20].copy_from_slice(&IBEACON_UUID);
+    // Major 0, minor (big endian)
+    data[22] = raw[1];
+    data[23] = raw[0];
+    data[24] = IBEACON_MEASURED_POWER as u8;
+    Radio::raw_frame(
+        address,
+        &[(AD_TYPE_FLAGS, &[FLAGS]), (AD_TYPE_MANUFACTURER_DATA, &data)],
+    )
+    .ok()
+}
+
+/// Measure the supply voltage in mV with the SAADC (on channel 1, channel 0
+/// is used by the `analog` feature and disconnected meanwhile).
+#[cfg(feature = "eddystone-tlm")]
+fn supply_voltage_mv() -> u16 {
+    use core::sync::atomic::{compiler_fence, Ordering};
+
+    // Safety: The SAADC is only used by tasks at the same priority, and
+    // disabled again when sampling is done
+    let saadc = unsafe { &*pac::SAADC::ptr() };
+    let mut result: i16 = 0;
+    // The same configuration as for the analog inputs: 12 bit, 16x
+    // oversampling (in a single SAMPLE task), internal 0.6 V reference and
+    // gain 1/6 (full scale 3.6 V)
+    saadc.resolution.write(|w| w.val()._12bit());
+    saadc.oversample.write(|w| w.oversample().over16x());
+    saadc.ch[1].config.write(|w| {
+        w.refsel()
+            .internal()
+            .gain()
+            .gain1_6()
+            .tacq()
+            ._10us()
+            .mode()
+            .se()
+            .burst()
+            .enabled()
+    });
+    let ch0_pselp = saadc.ch[0].pselp.read().bits();
+    saadc.ch[0].pselp.write(|w| w.pselp().nc());
+    saadc.ch[1].pselp.write(|w| w.pselp().vdd());
+    saadc
+        .result
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(&mut result as *mut i16 as u32) });
+    saadc.result.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+    saadc.enable.write(|w| w.enable().enabled());
+
+    saadc.events_started.reset();
+    saadc.tasks_start.write(|w| unsafe { w.bits(1) });
+    while saadc.events_started.read().bits() == 0 {}
+    saadc.events_end.reset();
+    saadc.tasks_sample.write(|w| unsafe { w.bits(1) });
+    while saadc.events_end.read().bits() == 0 {}
+    saadc.events_stopped.reset();
+    saadc.tasks_stop.write(|w| unsafe { w.bits(1) });
+    while saadc.events_stopped.read().bits() == 0 {}
+    compiler_fence(Ordering::SeqCst);
+
+    saadc.ch[1].pselp.write(|w| w.pselp().nc());
+    saadc.ch[0].pselp.write(|w| unsafe { w.bits(ch0_pselp) });
+    saadc.enable.write(|w| w.enable().disabled());
+    (i32::from(result).max(0) * 3600 / 4096) as u16
+}
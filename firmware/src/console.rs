@@ -0,0 +1,23 @@
+//! Debug output on the RTT console (with the `rtt` feature, enabled by
+//! default).
+//!
+//! Without the `rtt` feature (e.g. to save flash and RAM on the nRF52810),
+//! the output is discarded. The arguments are still type checked.
+
+#[cfg(feature = "rtt")]
+pub use rtt_target::{rprintln, rtt_init_print};
+
+#[cfg(not(feature = "rtt"))]
+macro_rules! rprintln {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+#[cfg(not(feature = "rtt"))]
+macro_rules! rtt_init_print {
+    () => {};
+}
+
+#[cfg(not(feature = "rtt"))]
+pub(crate) use {rprintln, rtt_init_print};
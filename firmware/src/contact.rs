@@ -0,0 +1,105 @@
+//! Door/window contact (reed switch).
+//!
+//! The reed switch connects the input pin to GND when the magnet is near
+//! (closed). A change of the state is detected with the GPIOTE PORT event
+//! (through the SENSE mechanism of the pin, which works in System ON sleep
+//! without keeping the HF clock running). Every change increments an event
+//! counter.
+//!
+//! The state and the event counter are sent with every measurement. When the
+//! state changes, a measurement is started immediately.
+
+use crate::console::rprintln;
+use crate::hal::{
+    gpio::{Input, Pin, PullUp},
+    pac,
+};
+use crate::monotonic_nrf52::{Instant, U32Ext};
+use crate::sensors::{self, Readings, Sensor, SENSOR_CONTACT};
+
+/// Changes within this time after a change are ignored (contact bounce). The
+/// final state is sent with the next measurement in any case.
+const DEBOUNCE_MS: u32 = 20;
+
+fn p0() -> &'static pac::p0::RegisterBlock {
+    unsafe { &*pac::P0::ptr() }
+}
+
+pub struct Contact {
+    _pin: Pin<Input<PullUp>>,
+    pin: usize,
+    open: bool,
+    events: u16,
+    last_change: Option<Instant>,
+}
+
+impl Contact {
+    pub fn new(pin: Pin<Input<PullUp>>) -> Self {
+        let mut contact = Self {
+            pin: usize::from(pin.pin()),
+            _pin: pin,
+            open: false,
+            events: 0,
+            last_change: None,
+        };
+        contact.open = contact.read();
+        contact.arm();
+        unsafe { &*pac::GPIOTE::ptr() }
+            .intenset
+            .write(|w| w.port().set());
+        rprintln!("Contact: {}", if contact.open { "open" } else { "closed" });
+        contact
+    }
+
+    /// The pin is high (pulled up) if the contact is open.
+    fn read(&self) -> bool {
+        p0().in_.read().bits() & (1 << self.pin) != 0
+    }
+
+    /// Sense the opposite of the current level.
+    fn arm(&self) {
+        let high = self.read();
+        p0().pin_cnf[self.pin].modify(|_, w| if high { w.sense().low() } else { w.sense().high() });
+    }
+
+    /// Handle a GPIOTE PORT event. Return true if the state changed.
+    pub fn on_interrupt(&mut self) -> bool {
+        self.arm();
+
+        let now = Instant::now();
+        if let Some(last_change) = self.last_change {
+            if now.duration_since(last_change) < DEBOUNCE_MS.millis() {
+                return false;
+            }
+        }
+        let open = self.read();
+        if open == self.open {
+            return false;
+        }
+        self.open = open;
+        self.events = self.events.wrapping_add(1);
+        self.last_change = Some(now);
+        rprintln!("Contact {} (event {})", if open { "opened" } else { "closed" }, self.events);
+        true
+    }
+}
+
+impl Sensor for Contact {
+    fn start(&mut self) -> Result<u32, sensors::Error> {
+        Ok(0)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), sensors::Error> {
+        // Update the state, in case the last change was within the debounce
+        // time
+        let open = self.read();
+        if open != self.open {
+            self.open = open;
+            self.events = self.events.wrapping_add(1);
+        }
+        let events = self.events.to_le_bytes();
+        // State (u8, 1 = open) + event counter (u16 LE)
+        readings.push(SENSOR_CONTACT, &[u8::from(self.open), events[0], events[1]]);
+        Ok(())
+    }
+}
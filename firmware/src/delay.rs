@@ -4,7 +4,8 @@
 //! default also wants SYST for its Delay implementation.
 
 use embedded_hal::blocking::delay::{DelayUs, DelayMs};
-use nrf52832_hal::{
+
+use crate::hal::{
     self as hal,
     pac,
     timer::Timer,
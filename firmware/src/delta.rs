@@ -0,0 +1,103 @@
+//! Delta encoding of the temperature and humidity.
+//!
+//! With the `delta` feature, only every `FULL_INTERVAL`th measurement (the
+//! full measurement) contains the temperature and humidity entries. In
+//! between, they are replaced by a single delta entry (type `0x10`) with the
+//! differences to the last full measurement, which saves 4 bytes per
+//! measurement (e.g. to fit more sensors into a single frame).
+//!
+//! Entry value (5 bytes):
+//!
+//! - Reference age (u8): Number of measurements since the full measurement
+//! - Temperature (i16 LE): Centidegrees Celsius
+//! - Humidity (i16 LE): Centipercent relative humidity
+//!
+//! The lowest value (`i16::MIN`) means that the sensor failed. Since the
+//! differences only refer to the last full measurement, a gateway that missed
+//! it only loses the values up to the next full measurement. If a difference
+//! is out of range (or the reference value is missing), a full measurement is
+//! sent instead.
+
+use core::convert::TryFrom;
+
+use crate::sensors::{Readings, SENSOR_DELTA, SENSOR_HUMI, SENSOR_TEMP};
+
+/// Every how many measurements the absolute values are sent.
+pub const FULL_INTERVAL: u16 = 8;
+
+/// Temperature (millidegrees Celsius) and humidity (millipercent) of the last
+/// full measurement.
+struct Reference {
+    counter: u16,
+    temperature: Option<i32>,
+    humidity: Option<i32>,
+}
+
+impl Reference {
+    /// The delta entry value for the measurement `counter`, if it can be
+    /// encoded relative to this full measurement.
+    fn entry_value(
+        &self,
+        counter: u16,
+        temperature: Option<i32>,
+        humidity: Option<i32>,
+    ) -> Option<[u8; 5]> {
+        let age = counter.wrapping_sub(self.counter);
+        if age == 0 || age >= FULL_INTERVAL {
+            return None;
+        }
+        let delta = |value, reference| match (value, reference) {
+            (None, _) => Some(i16::MIN),
+            (Some(value), Some(reference)) => i16::try_from((value - reference) / 10)
+                .ok()
+                .filter(|&delta| delta != i16::MIN),
+            (Some(_), None) => None,
+        };
+        let temperature = delta(temperature, self.temperature)?;
+        let humidity = delta(humidity, self.humidity)?;
+        let mut value = [0; 5];
+        value[0] = age as u8;
+        value[1..3].copy_from_slice(&temperature.to_le_bytes());
+        value[3..5].copy_from_slice(&humidity.to_le_bytes());
+        Some(value)
+    }
+}
+
+pub struct DeltaEncoder {
+    reference: Option<Reference>,
+}
+
+impl DeltaEncoder {
+    pub const fn new() -> Self {
+        Self { reference: None }
+    }
+
+    /// Replace the temperature and humidity readings of the measurement
+    /// `counter` with a delta entry, unless it is a full measurement.
+    pub fn encode(&mut self, counter: u16, readings: &mut Readings) {
+        let temperature = readings.i32_value(SENSOR_TEMP);
+        let humidity = readings.i32_value(SENSOR_HUMI);
+        if temperature.is_none() && humidity.is_none() {
+            return;
+        }
+        let value = self
+            .reference
+            .as_ref()
+            .filter(|_| counter % FULL_INTERVAL != 0)
+            .and_then(|reference| reference.entry_value(counter, temperature, humidity));
+        match value {
+            Some(value) => {
+                readings.remove(SENSOR_TEMP);
+                readings.remove(SENSOR_HUMI);
+                readings.push(SENSOR_DELTA, &value);
+            }
+            None => {
+                self.reference = Some(Reference {
+                    counter,
+                    temperature,
+                    humidity,
+                })
+            }
+        }
+    }
+}
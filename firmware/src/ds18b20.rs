@@ -0,0 +1,111 @@
+//! DS18B20 temperature probes on a 1-Wire bus.
+//!
+//! The probes are discovered at startup. Their index (sent with every reading)
+//! is their position in the order of the ROM search, which only depends on
+//! the ROM codes. As long as no probes are added or removed, the index of a
+//! probe stays the same across reboots.
+//!
+//! The probes must be powered externally (parasite power is not supported).
+
+use crate::console::rprintln;
+use crate::onewire::{crc8, OneWire, Rom, CMD_SKIP_ROM};
+use crate::sensors::{self, Readings, Sensor, SENSOR_EXT_TEMP};
+
+/// Maximum number of probes on the bus.
+pub const MAX_PROBES: usize = 4;
+
+/// Family code of the DS18B20.
+const FAMILY_CODE: u8 = 0x28;
+
+// Function commands
+const CMD_CONVERT_T: u8 = 0x44;
+const CMD_READ_SCRATCHPAD: u8 = 0xbe;
+
+/// Conversion time at the default resolution of 12 bits.
+const CONVERSION_TIME_US: u32 = 750_000;
+
+pub struct Ds18b20Probes {
+    bus: OneWire,
+    roms: [Rom; MAX_PROBES],
+    count: usize,
+}
+
+impl Ds18b20Probes {
+    /// Discover the probes on the bus.
+    pub fn new(mut bus: OneWire) -> Self {
+        let mut found = [[0; 8]; MAX_PROBES];
+        let found_count = bus.search(&mut found);
+        let mut roms = [[0; 8]; MAX_PROBES];
+        let mut count = 0;
+        for rom in found.iter().take(found_count) {
+            if rom[0] != FAMILY_CODE {
+                rprintln!("1-Wire: Ignoring device with family code {:#04x}", rom[0]);
+                continue;
+            }
+            rprintln!("DS18B20: Probe {} has ROM code {:02x?}", count, rom);
+            roms[count] = *rom;
+            count += 1;
+        }
+        if count == 0 {
+            rprintln!("DS18B20: No probes found");
+        }
+        Self { bus, roms, count }
+    }
+
+    /// Read the temperature (in m°C) of a probe.
+    fn read_temperature(&mut self, rom: &Rom) -> Option<i32> {
+        if !self.bus.select(rom) {
+            return None;
+        }
+        self.bus.write_byte(CMD_READ_SCRATCHPAD);
+        let mut scratchpad = [0; 9];
+        for byte in scratchpad.iter_mut() {
+            *byte = self.bus.read_byte();
+        }
+        if crc8(&scratchpad) != 0 {
+            return None;
+        }
+        // 1/16 °C
+        let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+        Some(i32::from(raw) * 125 / 2)
+    }
+}
+
+impl Sensor for Ds18b20Probes {
+    fn start(&mut self) -> Result<u32, sensors::Error> {
+        if self.count == 0 {
+            return Ok(0);
+        }
+        // Start the conversion on all probes at once
+        if !self.bus.reset() {
+            rprintln!("DS18B20: No presence pulse");
+            return Err(sensors::Error);
+        }
+        self.bus.write_byte(CMD_SKIP_ROM);
+        self.bus.write_byte(CMD_CONVERT_T);
+        Ok(CONVERSION_TIME_US)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), sensors::Error> {
+        let mut result = Ok(());
+        for index in 0..self.count {
+            let rom = self.roms[index];
+            match self.read_temperature(&rom) {
+                Some(temp) => {
+                    rprintln!("DS18B20 measurement: Probe {}: {} m°C", index, temp);
+                    let temp = temp.to_le_bytes();
+                    // Probe index (u8) + i32 LE
+                    readings.push(
+                        SENSOR_EXT_TEMP,
+                        &[index as u8, temp[0], temp[1], temp[2], temp[3]],
+                    );
+                }
+                None => {
+                    rprintln!("DS18B20: Could not read probe {}", index);
+                    result = Err(sensors::Error);
+                }
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,40 @@
+//! Firmware-wide entropy source.
+//!
+//! Wraps the RNG peripheral, which generates random numbers from thermal
+//! noise. With the bias correction (enabled by the HAL), the output is
+//! uniformly distributed and suitable for cryptographic use, e.g. for the
+//! nonces of authenticated payloads. The peripheral only runs while random
+//! bytes are requested.
+//!
+//! Generating a byte takes about 120 µs with bias correction, so only request
+//! what is needed.
+
+use crate::hal::{pac, rng::Rng};
+
+pub struct Entropy {
+    rng: Rng,
+}
+
+impl Entropy {
+    pub fn new(rng: pac::RNG) -> Self {
+        Self { rng: Rng::new(rng) }
+    }
+
+    /// Fill the buffer with random bytes.
+    #[allow(dead_code)] // Not used yet (groundwork for payload authentication)
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        self.rng.random(buf);
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.rng.random_u32()
+    }
+
+    /// Random number in the range `0..=max`.
+    ///
+    /// The modulo bias is negligible for small ranges, which is all this is
+    /// used for.
+    pub fn up_to(&mut self, max: u32) -> u32 {
+        self.next_u32() % (max + 1)
+    }
+}
@@ -0,0 +1,215 @@
+//! Connectable GATT server with the Environmental Sensing Service.
+//!
+//! With the `gatt` feature, the node additionally advertises connectably
+//! (through the rubble link layer), so that phones and generic BLE apps can
+//! connect and read the current values. The server only exposes the
+//! Environmental Sensing Service (`0x181a`) with three read-only
+//! characteristics:
+//!
+//! - Temperature (`0x2a6e`): sint16, 0.01 °C
+//! - Humidity (`0x2a6f`): uint16, 0.01 %
+//! - Illuminance (`0x2afb`): uint24, 0.01 lux
+//!
+//! The values are updated with every measurement. Until the first
+//! measurement (or if a sensor failed), they are set to the "unknown" values
+//! of the characteristics.
+
+use core::sync::atomic::{AtomicI16, AtomicU16, AtomicU32, Ordering};
+
+use rubble::att::{AttUuid, Attribute, AttributeProvider, Handle, HandleRange};
+use rubble::config::Config;
+use rubble::l2cap::BleChannelMap;
+use rubble::link::queue::SimpleQueue;
+use rubble::link::Responder;
+use rubble::security::NoSecurity;
+use rubble::uuid::Uuid16;
+use rubble::Error;
+
+use crate::console::rprintln;
+use crate::rubble_radio::{BleRadio, BleTimer};
+use crate::sensors::{Readings, SENSOR_HUMI, SENSOR_LUX, SENSOR_TEMP};
+
+/// Interval of the connectable advertisements.
+pub const ADVERTISING_INTERVAL_MS: u16 = 1000;
+
+/// Environmental Sensing Service.
+pub const ESS_UUID: u16 = 0x181a;
+
+// Attribute types
+const PRIMARY_SERVICE_UUID: u16 = 0x2800;
+const CHARACTERISTIC_UUID: u16 = 0x2803;
+
+// Characteristics
+const TEMPERATURE_UUID: u16 = 0x2a6e;
+const HUMIDITY_UUID: u16 = 0x2a6f;
+const ILLUMINANCE_UUID: u16 = 0x2afb;
+
+/// Characteristic property: Read.
+const PROPERTY_READ: u8 = 0x02;
+
+// "Value is not known" of the characteristics
+const TEMPERATURE_UNKNOWN: i16 = i16::MIN;
+const HUMIDITY_UNKNOWN: u16 = 0xffff;
+const ILLUMINANCE_UNKNOWN: u32 = 0xff_ffff;
+
+static TEMPERATURE: AtomicI16 = AtomicI16::new(TEMPERATURE_UNKNOWN);
+static HUMIDITY: AtomicU16 = AtomicU16::new(HUMIDITY_UNKNOWN);
+static ILLUMINANCE: AtomicU32 = AtomicU32::new(ILLUMINANCE_UNKNOWN);
+
+pub enum GattConfig {}
+
+impl Config for GattConfig {
+    type Timer = BleTimer;
+    type Transmitter = BleRadio;
+    type ChannelMapper = BleChannelMap<EssAttrs, NoSecurity>;
+    type PacketQueue = &'static mut SimpleQueue;
+}
+
+pub type GattResponder = Responder<GattConfig>;
+
+/// Update the characteristic values from the readings of a measurement.
+pub fn update(readings: &Readings) {
+    let temperature = readings.i32_value(SENSOR_TEMP).map_or(TEMPERATURE_UNKNOWN, |millis| {
+        // The lowest value means unknown
+        (millis / 10).clamp(-27315, i16::MAX.into()) as i16
+    });
+    let humidity = readings.i32_value(SENSOR_HUMI).map_or(HUMIDITY_UNKNOWN, |millis| {
+        (millis / 10).clamp(0, 10000) as u16
+    });
+    let illuminance = readings
+        .value(SENSOR_LUX)
+        .map_or(ILLUMINANCE_UNKNOWN, |value| {
+            let lux = f32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+            // Saturating cast, the highest value means unknown
+            ((lux * 100.0) as u32).min(ILLUMINANCE_UNKNOWN - 1)
+        });
+    TEMPERATURE.store(temperature, Ordering::Relaxed);
+    HUMIDITY.store(humidity, Ordering::Relaxed);
+    ILLUMINANCE.store(illuminance, Ordering::Relaxed);
+}
+
+/// Process the pending requests of the connected client.
+pub fn process(responder: &mut GattResponder) {
+    while responder.has_work() {
+        if let Err(e) = responder.process_one() {
+            rprintln!("GATT: Could not process request: {:?}", e);
+            break;
+        }
+    }
+}
+
+/// An attribute value (up to 5 bytes, the size of a characteristic
+/// declaration).
+#[derive(Debug, Clone, Copy)]
+pub struct Value {
+    bytes: [u8; 5],
+    len: usize,
+}
+
+impl Value {
+    fn new(bytes: &[u8]) -> Self {
+        let mut value = Self {
+            bytes: [0; 5],
+            len: bytes.len(),
+        };
+        value.bytes[..bytes.len()].copy_from_slice(bytes);
+        value
+    }
+}
+
+impl AsRef<[u8]> for Value {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// The attributes of the Environmental Sensing Service. Handle `n` is at
+/// index `n - 1`: The service declaration, followed by the declaration and
+/// the value of every characteristic.
+pub struct EssAttrs {
+    attributes: [Attribute<Value>; 7],
+}
+
+fn attribute(handle: u16, uuid: u16, value: &[u8]) -> Attribute<Value> {
+    Attribute::new(
+        AttUuid::Uuid16(Uuid16(uuid)),
+        Handle::from_raw(handle),
+        Value::new(value),
+    )
+}
+
+/// Declaration of a read-only characteristic, with its value at the next
+/// handle.
+fn characteristic(handle: u16, uuid: u16) -> Attribute<Value> {
+    let [value_lo, value_hi] = (handle + 1).to_le_bytes();
+    let [uuid_lo, uuid_hi] = uuid.to_le_bytes();
+    attribute(
+        handle,
+        CHARACTERISTIC_UUID,
+        &[PROPERTY_READ, value_lo, value_hi, uuid_lo, uuid_hi],
+    )
+}
+
+impl EssAttrs {
+    pub fn new() -> Self {
+        let mut attrs = Self {
+            attributes: [
+                attribute(1, PRIMARY_SERVICE_UUID, &ESS_UUID.to_le_bytes()),
+                characteristic(2, TEMPERATURE_UUID),
+                attribute(3, TEMPERATURE_UUID, &[]),
+                characteristic(4, HUMIDITY_UUID),
+                attribute(5, HUMIDITY_UUID, &[]),
+                characteristic(6, ILLUMINANCE_UUID),
+                attribute(7, ILLUMINANCE_UUID, &[]),
+            ],
+        };
+        attrs.refresh();
+        attrs
+    }
+
+    /// Load the current characteristic values.
+    fn refresh(&mut self) {
+        let temperature = TEMPERATURE.load(Ordering::Relaxed).to_le_bytes();
+        let humidity = HUMIDITY.load(Ordering::Relaxed).to_le_bytes();
+        let illuminance = ILLUMINANCE.load(Ordering::Relaxed).to_le_bytes();
+        self.attributes[2] = attribute(3, TEMPERATURE_UUID, &temperature);
+        self.attributes[4] = attribute(5, HUMIDITY_UUID, &humidity);
+        self.attributes[6] = attribute(7, ILLUMINANCE_UUID, &illuminance[..3]);
+    }
+}
+
+impl AttributeProvider for EssAttrs {
+    fn for_attrs_in_range(
+        &mut self,
+        range: HandleRange,
+        mut f: impl FnMut(&Self, &Attribute<dyn AsRef<[u8]>>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.refresh();
+        let handles = range.start().as_u16()..=range.end().as_u16();
+        for (handle, attribute) in (1..).zip(self.attributes.iter()) {
+            if handles.contains(&handle) {
+                f(self, attribute)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_grouping_attr(&self, uuid: AttUuid) -> bool {
+        matches!(
+            uuid,
+            AttUuid::Uuid16(Uuid16(PRIMARY_SERVICE_UUID | CHARACTERISTIC_UUID))
+        )
+    }
+
+    fn group_end(&self, handle: Handle) -> Option<&Attribute<dyn AsRef<[u8]>>> {
+        let end = match handle.as_u16() {
+            // The service contains all attributes
+            1 => 7,
+            // A characteristic ends with its value
+            handle @ (2 | 4 | 6) => handle + 1,
+            _ => return None,
+        };
+        let attribute: &Attribute<dyn AsRef<[u8]>> = &self.attributes[usize::from(end) - 1];
+        Some(attribute)
+    }
+}
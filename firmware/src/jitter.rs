@@ -0,0 +1,29 @@
+//! Random jitter on the advertising timing.
+//!
+//! Nodes that are powered on at the same time (e.g. after a power outage) run
+//! their measurement cycles in lockstep, because their clocks only drift
+//! apart slowly. Their beacons then collide on the advertising channels in
+//! every interval. To avoid this, every measurement interval is varied
+//! randomly by up to ±`MEASUREMENT_JITTER_MS` (the average interval stays the
+//! same), and a random delay of up to `BURST_JITTER_MS` is added between the
+//! beacons of a burst.
+//!
+//! The random numbers are taken from the entropy source.
+
+use crate::entropy::Entropy;
+
+/// Maximum deviation from the measurement interval.
+pub const MEASUREMENT_JITTER_MS: u32 = 100;
+
+/// Maximum additional delay between two beacons of a burst.
+pub const BURST_JITTER_MS: u32 = 10;
+
+/// Time from the start of a measurement to the start of the next one.
+pub fn measurement_interval_ms(entropy: &mut Entropy, interval_ms: u32) -> u32 {
+    interval_ms - MEASUREMENT_JITTER_MS + entropy.up_to(2 * MEASUREMENT_JITTER_MS)
+}
+
+/// Time from the start of a beacon to the start of the next one.
+pub fn burst_interval_ms(entropy: &mut Entropy, interval_ms: u32) -> u32 {
+    interval_ms + entropy.up_to(BURST_JITTER_MS)
+}
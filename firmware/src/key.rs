@@ -0,0 +1,83 @@
+//! Per-device keys: The key for the payload authentication, and the identity
+//! resolving key (IRK) for the private addresses.
+//!
+//! The keys are written to the UICR at manufacture time (see `provision.sh`),
+//! so that they are not part of the firmware binary. Every key occupies a
+//! slot of 6 registers:
+//!
+//! - Header (marker and layout version 1)
+//! - 128 bit key in 4 registers (little endian, the first key byte is the
+//!   lowest byte of the first register)
+//! - Check word, XOR of the header and the key words
+//!
+//! | Key | Registers | Header |
+//! |-----|-----------|--------|
+//! | Payload key | `CUSTOMER[1..=6]` | `0x4b590001` |
+//! | IRK | `CUSTOMER[7..=12]` | `0x49520001` |
+//!
+//! The check word is written last, so that an interrupted provisioning
+//! (with some words still erased) is detected.
+//!
+//! To protect the keys from being read out with a debugger, enable the access
+//! port protection after provisioning (`nrfjprog --rbp ALL`). This can only
+//! be reverted with a full erase, which also erases the keys.
+
+use crate::hal::pac;
+
+/// Key length in bytes.
+pub const KEY_LEN: usize = 16;
+
+pub type Key = [u8; KEY_LEN];
+
+/// A key slot in the UICR.
+#[derive(Debug, Clone, Copy)]
+pub enum Slot {
+    /// Key for the payload authentication
+    Payload,
+    /// Identity resolving key for the private addresses
+    Irk,
+}
+
+impl Slot {
+    /// Index of the first `CUSTOMER` register.
+    fn register(self) -> usize {
+        match self {
+            Slot::Payload => 1,
+            Slot::Irk => 7,
+        }
+    }
+
+    fn header(self) -> u32 {
+        match self {
+            Slot::Payload => 0x4b59_0001,
+            Slot::Irk => 0x4952_0001,
+        }
+    }
+}
+
+/// Decode the registers of a key slot.
+fn decode(words: &[u32; 6], expected_header: u32) -> Option<Key> {
+    let (header, key_words, check) = (words[0], &words[1..5], words[5]);
+    if header != expected_header {
+        return None;
+    }
+    if key_words.iter().fold(header, |acc, word| acc ^ word) != check {
+        return None;
+    }
+    let mut key = [0; KEY_LEN];
+    for (chunk, word) in key.chunks_mut(4).zip(key_words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    Some(key)
+}
+
+/// Read a key from the UICR. Return `None` if no (valid) key has been
+/// provisioned.
+pub fn from_uicr(slot: Slot) -> Option<Key> {
+    let customer = &unsafe { &*pac::UICR::ptr() }.customer;
+    let mut words = [0; 6];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = customer[slot.register() + i].read().bits();
+    }
+    decode(&words, slot.header())
+}
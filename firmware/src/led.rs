@@ -0,0 +1,95 @@
+//! Status LED with a configurable behavior.
+//!
+//! The policy is selected at compile time:
+//!
+//! - `led-off` feature: The LED is never turned on.
+//! - `led-error-only` feature: The LED is turned on when an error occurs, and
+//!   turned off at the end of the next beacon burst.
+//! - `led-identify` feature: The LED blinks slowly (on during every other
+//!   measurement cycle), to find a node.
+//! - Otherwise, the LED is on during beacon bursts (and errors are shown like
+//!   with `led-error-only`). In low power mode (`low-power` feature), only
+//!   errors are shown by default.
+
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::hal::gpio::{Output, Pin, PushPull};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedPolicy {
+    Off,
+    BlinkOnBeacon,
+    BlinkOnError,
+    Identify,
+}
+
+/// The policy selected through the features.
+pub const LED_POLICY: LedPolicy = if cfg!(feature = "led-off") {
+    LedPolicy::Off
+} else if cfg!(feature = "led-identify") {
+    LedPolicy::Identify
+} else if cfg!(feature = "led-error-only") || cfg!(feature = "low-power") {
+    LedPolicy::BlinkOnError
+} else {
+    LedPolicy::BlinkOnBeacon
+};
+
+pub struct StatusLed {
+    /// The LED is active low
+    pin: Pin<Output<PushPull>>,
+    policy: LedPolicy,
+    cycle: u32,
+}
+
+impl StatusLed {
+    /// Create a status LED. The LED is turned off initially.
+    pub fn new(pin: Pin<Output<PushPull>>, policy: LedPolicy) -> Self {
+        let mut led = Self {
+            pin,
+            policy,
+            cycle: 0,
+        };
+        led.set(false);
+        led
+    }
+
+    fn set(&mut self, on: bool) {
+        if on {
+            self.pin.set_low().ok();
+        } else {
+            self.pin.set_high().ok();
+        }
+    }
+
+    /// A beacon burst starts.
+    pub fn burst_start(&mut self) {
+        match self.policy {
+            LedPolicy::BlinkOnBeacon => self.set(true),
+            LedPolicy::Identify => self.set(self.cycle % 2 == 0),
+            LedPolicy::Off | LedPolicy::BlinkOnError => {}
+        }
+    }
+
+    /// Whether the LED is on for identification during the current burst.
+    pub fn identifying(&self) -> bool {
+        self.policy == LedPolicy::Identify && self.cycle % 2 == 0
+    }
+
+    /// A beacon burst ended.
+    pub fn burst_end(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+        match self.policy {
+            LedPolicy::BlinkOnBeacon | LedPolicy::BlinkOnError => self.set(false),
+            // Stay on or off until the next burst
+            LedPolicy::Identify | LedPolicy::Off => {}
+        }
+    }
+
+    /// An error occurred.
+    pub fn error(&mut self) {
+        match self.policy {
+            LedPolicy::BlinkOnBeacon | LedPolicy::BlinkOnError => self.set(true),
+            LedPolicy::Identify | LedPolicy::Off => {}
+        }
+    }
+}
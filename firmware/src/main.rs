@@ -6,78 +6,385 @@ use panic_persist::get_panic_message_utf8;
 
 use core::cmp::max;
 
-use nrf52832_hal::{self as hal, pac, prelude::*};
+#[cfg(feature = "nrf52810")]
+use nrf52810_hal as hal;
+#[cfg(feature = "nrf52832")]
+use nrf52832_hal as hal;
 use rtic::app;
-use rtt_target::{rprintln, rtt_init_print};
-use rubble::{
-    beacon::Beacon,
-    link::{ad_structure::AdStructure, DeviceAddress, MIN_PDU_BUF},
-};
-use rubble_nrf5x::{
-    radio::{BleRadio, PacketBuffer},
-    utils::get_device_address,
-};
 use shared_bus_rtic::SharedBus;
+#[cfg(not(feature = "sht4x"))]
 use shtcx::{shtc3, ShtC3};
 use veml6030::Veml6030;
 
+mod advertiser;
+#[cfg(feature = "analog")]
+mod analog;
+#[cfg(feature = "backlog")]
+mod backlog;
+mod board;
+mod burst;
+mod clock;
+// Always compiled, the (optional) compatibility frame is a resource
+#[cfg_attr(not(any(feature = "eddystone-tlm", feature = "ibeacon")), allow(dead_code))]
+mod compat;
+mod console;
+// Always compiled, the (optional) buzzer is a resource
+#[cfg_attr(not(feature = "buzzer"), allow(dead_code))]
+mod buzzer;
+// Always compiled, the (optional) contact is a resource
+#[cfg_attr(not(feature = "contact"), allow(dead_code))]
+mod contact;
+#[cfg(feature = "delta")]
+mod delta;
+#[cfg(feature = "ds18b20")]
+mod ds18b20;
+mod entropy;
+#[cfg(feature = "gatt")]
+mod gatt;
+mod jitter;
+mod key;
+mod led;
+#[cfg(feature = "max31855")]
+mod max31855;
 mod monotonic_nrf52;
+#[cfg(feature = "ds18b20")]
+mod onewire;
+// Always compiled, the (optional) samples are a resource
+#[cfg_attr(not(feature = "oversampling"), allow(dead_code))]
+mod oversampling;
+mod payload;
+mod power;
+mod profiling;
+#[cfg(feature = "pulse-counter")]
+mod pulse;
+mod quiet;
+#[cfg(feature = "ble-raw")]
+mod raw_advertiser;
+// Always compiled, the (optional) private address is a resource
+#[cfg_attr(not(feature = "private-address"), allow(dead_code))]
+mod rpa;
+#[cfg(feature = "ble-rubble")]
+mod rubble_advertiser;
+// The receiver and the timer are only used by the `gatt` feature
+#[cfg(feature = "ble-rubble")]
+#[cfg_attr(not(feature = "gatt"), allow(dead_code))]
+mod rubble_radio;
+mod sensors;
+#[cfg(any(feature = "sht4x", feature = "dual-sensor"))]
+mod sht4x;
+mod telemetry;
+#[cfg(feature = "time-sync")]
+mod timesync;
+#[cfg(feature = "diagnostics")]
+mod timing;
 
-use monotonic_nrf52::{Instant, U32Ext};
+use advertiser::{Advertiser, DeviceAddress};
+#[cfg(feature = "analog")]
+use analog::AnalogInputs;
+#[cfg(feature = "backlog")]
+use backlog::Backlog;
+use board::{AnyTwim, Bus};
+use burst::BurstConfig;
+use buzzer::{Buzzer, Pattern};
+use clock::WallClock;
+use compat::CompatBeacon;
+use console::{rprintln, rtt_init_print};
+use contact::Contact;
+#[cfg(feature = "delta")]
+use delta::DeltaEncoder;
+#[cfg(feature = "ds18b20")]
+use ds18b20::Ds18b20Probes;
+#[cfg(feature = "gatt")]
+use gatt::GattResponder;
+use entropy::Entropy;
+use jitter::MEASUREMENT_JITTER_MS;
+use key::{Key, Slot};
+use led::{StatusLed, LED_POLICY};
+#[cfg(feature = "max31855")]
+use max31855::Max31855;
+use monotonic_nrf52::{Instant, Rtc1, U32Ext};
+#[cfg(feature = "oversampling")]
+use oversampling::Oversampling;
+use oversampling::Samples;
+use payload::{frame_count, max_payload_len, PayloadWriter, CRC_ENTRY_LEN, MAC_ENTRY_LEN};
+use profiling::Phase;
+#[cfg(feature = "pulse-counter")]
+use pulse::PulseCounter;
+use quiet::{QuietHours, QuietMode};
+#[cfg(feature = "ble-raw")]
+use raw_advertiser::RawAdvertiser;
+use rpa::PrivateAddress;
+#[cfg(feature = "ble-rubble")]
+use rubble_advertiser::RubbleAdvertiser;
+#[cfg(any(feature = "sht4x", feature = "dual-sensor"))]
+use sht4x::Sht4x;
+use sensors::{Readings, Sensor, SENSOR_STATUS, SENSOR_TELEMETRY, STATUS_BUZZER};
+#[cfg(feature = "heater")]
+use sensors::STATUS_HEATED;
+#[cfg(feature = "dual-sensor")]
+use sensors::STATUS_DISAGREEMENT;
+#[cfg(feature = "diagnostics")]
+use sensors::SENSOR_TIMING;
+#[cfg(feature = "backlog")]
+use sensors::{SENSOR_BACKLOG, SENSOR_HUMI, SENSOR_TEMP};
+
+#[cfg(feature = "max31855")]
+use hal::gpio::{Output, Pin, PushPull};
+use hal::pac;
+
+#[cfg(not(any(feature = "nrf52832", feature = "nrf52810")))]
+compile_error!("Select a chip with the `nrf52832` or `nrf52810` feature");
+#[cfg(all(feature = "nrf52832", feature = "nrf52810"))]
+compile_error!("The `nrf52832` and `nrf52810` features are mutually exclusive (use --no-default-features)");
+
+// The nRF52810 (and nRF52811) lacks TWIM1, SPIM2 and TIMER3
+#[cfg(all(feature = "nrf52810", any(feature = "i2c1", feature = "max31855", feature = "pulse-counter")))]
+compile_error!("The `i2c1`, `max31855` and `pulse-counter` features are not supported on the nRF52810");
+
+#[cfg(not(any(feature = "ble-rubble", feature = "ble-raw")))]
+compile_error!("Select a BLE stack with the `ble-rubble` or `ble-raw` feature");
+#[cfg(all(feature = "ble-rubble", feature = "ble-raw"))]
+compile_error!("The `ble-rubble` and `ble-raw` features are mutually exclusive (use --no-default-features)");
+#[cfg(all(feature = "time-sync", not(feature = "ble-raw")))]
+compile_error!("The `time-sync` feature needs the `ble-raw` feature");
+#[cfg(all(feature = "ext-adv", not(feature = "ble-raw")))]
+compile_error!("The `ext-adv` feature needs the `ble-raw` feature");
+#[cfg(all(feature = "eddystone-tlm", feature = "ibeacon"))]
+compile_error!("The `eddystone-tlm` and `ibeacon` features are mutually exclusive");
+#[cfg(all(feature = "gatt", feature = "ble-raw"))]
+compile_error!("The `gatt` feature needs the `ble-rubble` feature");
+// The link layer advertises with the device address
+#[cfg(all(feature = "gatt", feature = "private-address"))]
+compile_error!("The `gatt` and `private-address` features are mutually exclusive");
+
+// BLE stack, see the `advertiser` module
+#[cfg(feature = "ble-rubble")]
+type Radio = RubbleAdvertiser;
+#[cfg(feature = "ble-raw")]
+type Radio = RawAdvertiser;
+type Beacon = <Radio as Advertiser>::Frame;
+/// Local of the `ble_worker` task, which exists without the `gatt` feature
+/// as well.
+#[cfg(not(feature = "gatt"))]
+type GattResponder = ();
 
 // Measure at a specific interval
 const MEASURE_INTERVAL_MS: u32 = 3000;
 
-// Send 5 beacons, spaced 20 ms apart
-const BEACON_BURST_COUNT: u8 = 5;
-const BEACON_BURST_INTERVAL_MS: u32 = 20;
+// The interval varies randomly by up to ±MEASUREMENT_JITTER_MS
+const MIN_MEASURE_INTERVAL_MS: u32 = MEASURE_INTERVAL_MS - MEASUREMENT_JITTER_MS;
 
-// Sensor types
-const SENSOR_TEMP: u8 = 0x01;
-const SENSOR_HUMI: u8 = 0x02;
-const SENSOR_LUX: u8 = 0x04;
+// The burst must be finished before the next measurement starts (this is
+// also checked for the burst configuration in the UICR)
+const _: () = assert!(BurstConfig::DEFAULT.max_duration_ms() < MIN_MEASURE_INTERVAL_MS);
+
+// During quiet hours without beacons, the schedule is checked again after
+// this time
+const QUIET_CHECK_INTERVAL_MS: u32 = 60_000;
+
+// The private address (with the `private-address` feature) is changed after
+// this number of measurement cycles
+const ADDRESS_ROTATION_CYCLES: u32 = rpa::ROTATION_INTERVAL_MS / MEASURE_INTERVAL_MS;
+
+// If not all measurements fit into a single beacon, they are split into up to
+// 4 beacon frames (2 on the nRF52810, which has fewer sensors). These are
+// sent in turns during a beacon burst.
+#[cfg(not(feature = "nrf52810"))]
+const MAX_BEACON_FRAMES: usize = 4;
+#[cfg(feature = "nrf52810")]
+const MAX_BEACON_FRAMES: usize = 2;
+const NO_BEACON: Option<Beacon> = None;
 
 // BLE Beacon
-const AD_STRUCTURE_MANUFACTURER_DATA: u8 = 0xff;
+const DEVICE_NAME: &str = "Sensilo";
+
+type SharedBusType = AnyTwim;
+
+// Temperature and humidity sensor
+#[cfg(not(feature = "sht4x"))]
+type Sht = ShtC3<SharedBus<SharedBusType>>;
+#[cfg(feature = "sht4x")]
+type Sht = Sht4x<SharedBus<SharedBusType>>;
+
+/// The sensors, across all buses.
+pub struct Sensors {
+    sht: Sht,
+    /// Second temperature and humidity sensor, to detect a drifting one
+    #[cfg(feature = "dual-sensor")]
+    secondary_sht: Sht4x<SharedBus<SharedBusType>>,
+    veml: Veml6030<SharedBus<SharedBusType>>,
+    #[cfg(feature = "max31855")]
+    thermocouple: Max31855<hal::spim::Spim<pac::SPIM2>, Pin<Output<PushPull>>>,
+    #[cfg(feature = "ds18b20")]
+    probes: Ds18b20Probes,
+    #[cfg(feature = "pulse-counter")]
+    pulses: PulseCounter,
+    #[cfg(feature = "analog")]
+    analog: AnalogInputs,
+}
+
+impl Sensors {
+    /// Call `f` for every sensor.
+    fn for_each(&mut self, mut f: impl FnMut(&mut dyn Sensor)) {
+        f(&mut self.sht);
+        #[cfg(feature = "dual-sensor")]
+        f(&mut self.secondary_sht);
+        f(&mut self.veml);
+        #[cfg(feature = "max31855")]
+        f(&mut self.thermocouple);
+        #[cfg(feature = "ds18b20")]
+        f(&mut self.probes);
+        #[cfg(feature = "pulse-counter")]
+        f(&mut self.pulses);
+        #[cfg(feature = "analog")]
+        f(&mut self.analog);
+    }
+
+    /// Call `f` for every sensor that is sampled several times per
+    /// measurement (with the `oversampling` feature), with its oversampling.
+    #[cfg(feature = "oversampling")]
+    fn for_each_oversampled(&mut self, mut f: impl FnMut(&mut dyn Sensor, &Oversampling)) {
+        f(&mut self.sht, &board::SHT_OVERSAMPLING);
+        f(&mut self.veml, &board::VEML_OVERSAMPLING);
+    }
+}
 
-// VEML sensor integration time
-const VEML_INTEGRATION_TIME: veml6030::IntegrationTime = veml6030::IntegrationTime::Ms25;
+/// The quiet mode at the instant `now`, if the node knows the time of day and
+/// it is within the quiet hours.
+fn quiet_mode(
+    clock: &mut WallClock,
+    quiet_hours: Option<&QuietHours>,
+    now: Instant,
+) -> Option<QuietMode> {
+    // Always read the clock, it must be read regularly
+    let second_of_day = clock.second_of_day(now)?;
+    quiet_hours
+        .filter(|quiet_hours| quiet_hours.contains(second_of_day))
+        .map(|quiet_hours| quiet_hours.mode)
+}
 
-pub struct SharedBusResources<T: 'static> {
-    sht: ShtC3<SharedBus<T>>,
-    veml: Veml6030<SharedBus<T>>,
+/// Print the configured timings, to compare them with a power profile.
+#[cfg(feature = "power-profiling")]
+fn print_timing_report(sht_us: u16, burst: &BurstConfig) {
+    rprintln!("Timings:");
+    rprintln!(
+        "  Measurement interval: {} ms ± {} ms",
+        MEASURE_INTERVAL_MS,
+        MEASUREMENT_JITTER_MS
+    );
+    rprintln!("  SHT max measurement duration: {} µs", sht_us);
+    rprintln!(
+        "  VEML7700 measurement duration: {} µs",
+        sensors::veml_measurement_duration_us()
+    );
+    rprintln!(
+        "  Beacon burst: {} beacons, {}-{} ms apart ({}-{} ms)",
+        burst.count,
+        burst.interval_ms,
+        burst.interval_ms + jitter::BURST_JITTER_MS,
+        burst.duration_ms(),
+        burst.max_duration_ms()
+    );
 }
 
-type SharedBusType = hal::twim::Twim<pac::TWIM0>;
+// All tasks run at priority 1 and never preempt each other, so the shared
+// resources don't need locks. The only exception are the link layer tasks of
+// the GATT server (with the `gatt` feature), which share the radio. Every
+// `#[lock_free]` resource lists the tasks that use it: RTIC rejects lock-free
+// resources that are shared across priorities, so a task that gets another
+// priority (or is bound to an interrupt with another priority) needs locks for
+// all of the listed resources it uses.
+#[app(device = crate::pac, peripherals = true, dispatchers = [SWI0_EGU0])]
+mod app {
+    use super::*;
+
+    #[monotonic(binds = RTC1, default = true)]
+    type Mono = Rtc1;
 
-#[app(device = crate::pac, peripherals = true, monotonic = crate::monotonic_nrf52::Tim1)]
-const APP: () = {
-    struct Resources {
+    #[shared]
+    struct Shared {
         // LED
-        led: hal::gpio::p0::P0_07<hal::gpio::Output<hal::gpio::PushPull>>,
+        // Priority 1 only: start_measurement, collect_sample, collect_measurement, broadcast_beacon
+        #[lock_free]
+        led: StatusLed,
 
-        // BLE
-        #[init([0; MIN_PDU_BUF])]
-        ble_tx_buf: PacketBuffer,
-        #[init([0; MIN_PDU_BUF])]
-        ble_rx_buf: PacketBuffer,
-        radio: BleRadio,
-        device_address: DeviceAddress,
+        // Random numbers (for the timing jitter)
+        // Priority 1 only: collect_measurement, broadcast_beacon
+        #[lock_free]
+        entropy: Entropy,
 
-        // I²C devices
-        i2c: SharedBusResources<SharedBusType>,
+        // Sensors
+        // Priority 1 only: start_measurement, collect_sample, collect_measurement
+        #[lock_free]
+        sensors: Sensors,
+        // Samples of the current measurement (only with the `oversampling`
+        // feature)
+        // Priority 1 only: start_measurement, collect_sample, collect_measurement
+        #[lock_free]
+        samples: Samples,
+        // Door/window contact (only with the `contact` feature)
+        // Priority 1 only: gpiote, collect_measurement
+        #[lock_free]
+        contact: Option<Contact>,
+        // Buzzer and button (only with the `buzzer` feature)
+        // Priority 1 only: gpiote, collect_measurement, broadcast_beacon
+        #[lock_free]
+        buzzer: Option<Buzzer>,
 
         // Measurements
-        #[init(None)]
+        // Priority 1 only: gpiote, start_measurement, collect_measurement
+        #[lock_free]
+        next_measurement: Instant,
+        // Priority 1 only: gpiote, start_measurement, collect_measurement
+        #[lock_free]
         measurement_start: Option<Instant>,
+        // Scheduled start of the next measurement (to move it when the
+        // contact state changes)
+        // Priority 1 only: gpiote, start_measurement, collect_measurement
+        #[lock_free]
+        scheduled_start: Option<start_measurement::SpawnHandle>,
+
+        // Beacon frames
+        // Priority 1 only: collect_measurement, broadcast_beacon
+        #[lock_free]
+        beacons: [Option<Beacon>; MAX_BEACON_FRAMES],
+        // Priority 1 only: collect_measurement, broadcast_beacon
+        #[lock_free]
+        beacon_index: u8,
+        // Eddystone-TLM or iBeacon frame (only with the `eddystone-tlm` or
+        // `ibeacon` feature)
+        // Priority 1 only: collect_measurement, broadcast_beacon
+        #[lock_free]
+        compat: CompatBeacon,
 
-        // Beacon
-        #[init(None)]
-        beacon: Option<Beacon>,
+        // Time of day and quiet hours
+        // Priority 1 only: start_measurement, collect_measurement, broadcast_beacon
+        #[lock_free]
+        clock: WallClock,
+        // Priority 1 only: start_measurement, collect_measurement
+        #[lock_free]
+        quiet_hours: Option<QuietHours>,
+
+        // BLE (locked, see above)
+        radio: Radio,
     }
 
-    #[init(resources = [ble_tx_buf, ble_rx_buf], spawn = [start_measurement])]
-    fn init(ctx: init::Context) -> init::LateResources {
+    #[local]
+    struct Local {
+        // BLE
+        device_address: DeviceAddress,
+        // Rotating address of the beacons (only with the `private-address`
+        // feature)
+        private_address: Option<PrivateAddress>,
+        burst: BurstConfig,
+        // Key for the payload authentication (if provisioned)
+        key: Option<Key>,
+        // GATT server (only with the `gatt` feature, see `ble_worker`)
+        responder: GattResponder,
+    }
+
+    #[init]
+    fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         // Init RTT
         rtt_init_print!();
         rprintln!("Initializing…");
@@ -92,242 +399,707 @@ const APP: () = {
             CLOCK,
             FICR,
             P0,
+            POWER,
             RADIO,
-            TIMER1,
+            RNG,
+            RTC1,
+            #[cfg(any(feature = "gatt", feature = "ext-adv"))]
+            TIMER0,
             TWIM0,
+            #[cfg(feature = "i2c1")]
+            TWIM1,
+            #[cfg(feature = "max31855")]
+            SPIM2,
+            #[cfg(feature = "pulse-counter")]
+            GPIOTE,
+            #[cfg(any(feature = "pulse-counter", feature = "ext-adv"))]
+            PPI,
+            #[cfg(feature = "pulse-counter")]
+            TIMER2,
+            #[cfg(feature = "pulse-counter")]
+            TIMER3,
+            #[cfg(feature = "analog")]
+            SAADC,
+            #[cfg(feature = "buzzer")]
+            PWM0,
             ..
         } = ctx.device;
 
         // Set up clocks. On reset, the high frequency clock is already used,
         // but we also need to switch to the external HF oscillator. This is
-        // needed for Bluetooth to work.
-        let _clocks = hal::clocks::Clocks::new(CLOCK).enable_ext_hfosc();
+        // needed for Bluetooth to work. It is stopped between beacon bursts.
+        //
+        // The low frequency clock (from the internal RC oscillator, the
+        // accuracy is sufficient for the measurement interval) drives the RTC
+        // used for scheduling.
+        let _clocks = hal::clocks::Clocks::new(CLOCK)
+            .enable_ext_hfosc()
+            .set_lfclk_src_rc()
+            .start_lfclk();
+
+        // Configure regulator, unused peripherals and RAM
+        #[cfg(feature = "low-power")]
+        power::configure(&POWER);
+        #[cfg(not(feature = "low-power"))]
+        drop(POWER);
 
         // Set up GPIO peripheral
         let gpio = hal::gpio::p0::Parts::new(P0);
+        let pins = board::Pins::new(gpio);
+
+        // Initialize monotonic timer on RTC1 (for the RTIC timer queue)
+        let mono = Rtc1::new(RTC1);
 
-        // Initialize monotonic timer on TIMER1 (for RTIC)
-        monotonic_nrf52::Tim1::initialize(TIMER1);
+        // Initialize RNG peripheral as entropy source
+        #[cfg_attr(not(feature = "private-address"), allow(unused_mut))]
+        let mut entropy = Entropy::new(RNG);
+
+        // Initialize power profiling pins
+        profiling::init();
 
         // Initialize LED pin
-        // TODO: LED wrapper that knows whether low power mode is enabled
-        let led = gpio.p0_07.into_push_pull_output(hal::gpio::Level::High);
+        let led = StatusLed::new(pins.led, LED_POLICY);
+        rprintln!("LED policy: {:?}", LED_POLICY);
 
-        // Initialize TWIM (I²C) peripheral
-        let sda = gpio.p0_26.into_floating_input().degrade();
-        let scl = gpio.p0_25.into_floating_input().degrade();
-        let twim = hal::twim::Twim::new(
-            TWIM0,
-            hal::twim::Pins { sda, scl },
-            hal::twim::Frequency::K250,
-        );
+        // Read the beacon burst parameters of this node
+        let burst = BurstConfig::from_uicr(MIN_MEASURE_INTERVAL_MS);
+        rprintln!("Beacon burst: {} beacons, {} ms apart", burst.count, burst.interval_ms);
+
+        // Read the quiet hours of this node
+        let quiet_hours = QuietHours::from_uicr();
+        if let Some(quiet) = quiet_hours {
+            rprintln!(
+                "Quiet hours: {}:00-{}:00, {:?} (once the time is known)",
+                quiet.start_hour,
+                quiet.end_hour,
+                quiet.mode
+            );
+        }
 
-        // Create shared bus
-        let bus_manager = shared_bus_rtic::new!(twim, SharedBusType);
+        // Read the payload key (the key itself is never printed)
+        let key = key::from_uicr(Slot::Payload);
+        match key {
+            Some(_) => rprintln!("Payload key: Provisioned, payloads are authenticated"),
+            None => rprintln!("Payload key: Not provisioned"),
+        }
+
+        // Initialize TWIM (I²C) peripherals and create shared buses
+        let twim0 = AnyTwim::Twim0(hal::twim::Twim::new(
+            TWIM0,
+            pins.i2c0,
+            board::I2C_FREQUENCY,
+        ));
+        let bus_manager0 = shared_bus_rtic::new!(twim0, SharedBusType);
+        #[cfg(feature = "i2c1")]
+        let bus_manager1 = {
+            let twim1 = AnyTwim::Twim1(hal::twim::Twim::new(
+                TWIM1,
+                pins.i2c1.expect("Missing pins for second I²C bus"),
+                board::I2C_FREQUENCY,
+            ));
+            Some(shared_bus_rtic::new!(twim1, SharedBusType))
+        };
+        #[cfg(not(feature = "i2c1"))]
+        let bus_manager1 = None;
+        let bus_manager = |bus: Bus| match bus {
+            Bus::I2c0 => bus_manager0,
+            Bus::I2c1 => bus_manager1.expect("Second I²C bus is not enabled"),
+        };
 
         // Initialize SHT sensor
-        let mut sht = shtc3(bus_manager.acquire());
+        #[cfg(not(feature = "sht4x"))]
+        let mut sht = shtc3(bus_manager(board::SHT_BUS).acquire());
+        #[cfg(not(feature = "sht4x"))]
         rprintln!(
             "SHTC3: Device identifier is {}",
             sht.device_identifier().unwrap()
         );
+        #[cfg(feature = "sht4x")]
+        let mut sht = Sht4x::new(bus_manager(board::SHT_BUS).acquire());
+        #[cfg(feature = "sht4x")]
+        rprintln!(
+            "SHT4x: Serial number is {:#010x}",
+            sht.serial_number().unwrap()
+        );
+
+        #[cfg(feature = "dual-sensor")]
+        let mut secondary_sht =
+            Sht4x::secondary(bus_manager(board::SECONDARY_SHT_BUS).acquire());
+        #[cfg(feature = "dual-sensor")]
+        rprintln!(
+            "SHT4x (secondary): Serial number is {:#010x}",
+            secondary_sht.serial_number().unwrap()
+        );
+
+        #[cfg(all(feature = "power-profiling", not(feature = "sht4x")))]
+        print_timing_report(
+            shtcx::max_measurement_duration(&sht, shtcx::PowerMode::NormalMode),
+            &burst,
+        );
+        #[cfg(all(feature = "power-profiling", feature = "sht4x"))]
+        print_timing_report(sht4x::MEASUREMENT_DURATION_US as u16, &burst);
 
         // Initialize VEML7700 lux sensor
-        let mut veml = Veml6030::new(bus_manager.acquire(), veml6030::SlaveAddr::default());
+        let mut veml = Veml6030::new(
+            bus_manager(board::VEML_BUS).acquire(),
+            veml6030::SlaveAddr::default(),
+        );
         if let Err(e) = veml.set_gain(veml6030::Gain::OneQuarter) {
             rprintln!("VEML7700: Could not set gain: {:?}", e);
         }
-        if let Err(e) = veml.set_integration_time(VEML_INTEGRATION_TIME) {
+        if let Err(e) = veml.set_integration_time(sensors::VEML_INTEGRATION_TIME) {
             rprintln!("VEML7700: Could not set gain: {:?}", e);
         }
 
+        // Initialize SPIM (SPI) peripheral and MAX31855 thermocouple converter
+        #[cfg(feature = "max31855")]
+        let thermocouple = {
+            let pins = pins.spi.expect("Missing pins for SPI bus");
+            let spim = hal::spim::Spim::new(SPIM2, pins.spi, board::SPI_FREQUENCY, hal::spim::MODE_0, 0);
+            Max31855::new(spim, pins.cs)
+        };
+
+        // Discover DS18B20 temperature probes on the 1-Wire bus
+        #[cfg(feature = "ds18b20")]
+        let probes = Ds18b20Probes::new(onewire::OneWire::new(
+            pins.onewire.expect("Missing pin for 1-Wire bus"),
+        ));
+
+        // Start the pulse counter
+        #[cfg(feature = "pulse-counter")]
+        let pulses = PulseCounter::new(
+            pins.pulses.expect("Missing pin for pulse counter"),
+            &GPIOTE,
+            &PPI,
+            TIMER2,
+            TIMER3,
+        );
+
+        // Calibrate the SAADC for the analog inputs
+        #[cfg(feature = "analog")]
+        let analog = AnalogInputs::new(SAADC, board::ANALOG_INPUTS);
+
+        // Monitor the door/window contact
+        #[cfg(feature = "contact")]
+        let contact = Some(Contact::new(
+            pins.contact.expect("Missing pin for contact"),
+        ));
+        #[cfg(not(feature = "contact"))]
+        let contact = None;
+
+        // Set up the buzzer, and acknowledge the startup with a chirp
+        #[cfg(feature = "buzzer")]
+        let buzzer = {
+            let (pin, button) = pins.buzzer.expect("Missing pins for buzzer");
+            let mut buzzer = Buzzer::new(PWM0, pin, button);
+            buzzer.play(Pattern::Chirp);
+            Some(buzzer)
+        };
+        #[cfg(not(feature = "buzzer"))]
+        let buzzer = None;
+
         // Get bluetooth device address
-        let device_address = get_device_address();
+        let device_address = DeviceAddress::from_ficr(&FICR);
         rprintln!("Bluetooth device address: {:?}", device_address);
 
+        // Generate the first private address
+        #[cfg(feature = "private-address")]
+        let private_address = match key::from_uicr(Slot::Irk) {
+            Some(irk) => {
+                let address = PrivateAddress::new(irk, ADDRESS_ROTATION_CYCLES, &mut entropy);
+                rprintln!("Private address: {:?}", address.current());
+                Some(address)
+            }
+            None => {
+                rprintln!("Warning: No IRK provisioned, using the device address");
+                None
+            }
+        };
+        #[cfg(not(feature = "private-address"))]
+        let private_address = None;
+
         // Initialize radio
-        let radio = BleRadio::new(
-            RADIO,
-            &FICR,
-            ctx.resources.ble_tx_buf,
-            ctx.resources.ble_rx_buf,
-        );
+        #[cfg(not(any(feature = "gatt", feature = "ext-adv")))]
+        let radio = Radio::new(RADIO, &FICR);
+        #[cfg(feature = "ext-adv")]
+        let radio = Radio::with_extended(RADIO, &FICR, TIMER0, &PPI);
+        #[cfg(feature = "gatt")]
+        let (radio, responder) = {
+            power::hfxo_start();
+            Radio::with_gatt(RADIO, TIMER0, &FICR, device_address, DEVICE_NAME)
+        };
+        #[cfg(not(feature = "gatt"))]
+        let responder = ();
 
         // Schedule measurement immediately
-        ctx.spawn.start_measurement().unwrap();
+        start_measurement::spawn().unwrap();
 
         rprintln!("Init done");
-        init::LateResources {
+        let shared = Shared {
+            led,
+            entropy,
+            sensors: Sensors {
+                sht,
+                #[cfg(feature = "dual-sensor")]
+                secondary_sht,
+                veml,
+                #[cfg(feature = "max31855")]
+                thermocouple,
+                #[cfg(feature = "ds18b20")]
+                probes,
+                #[cfg(feature = "pulse-counter")]
+                pulses,
+                #[cfg(feature = "analog")]
+                analog,
+            },
+            samples: Samples::new(),
+            contact,
+            buzzer,
+            // The counter is cleared when the tasks start
+            next_measurement: Rtc1::zero(),
+            measurement_start: None,
+            scheduled_start: None,
+            beacons: [NO_BEACON; MAX_BEACON_FRAMES],
+            beacon_index: 0,
+            compat: CompatBeacon::new(),
+            clock: WallClock::new(),
+            quiet_hours,
             radio,
+        };
+        let local = Local {
             device_address,
-            i2c: SharedBusResources { sht, veml },
-            led,
-        }
+            private_address,
+            burst,
+            key,
+            responder,
+        };
+        (shared, local, init::Monotonics(mono))
     }
 
     #[idle]
     fn idle(_ctx: idle::Context) -> ! {
-        // It seems that the HFCLK is stopped in standby mode (entered through WFE/WFI).
-        // This prevents the monotonic timer from working. To avoid this issue, don't go into sleep
-        // mode in idle, but instead do busy-looping for now.
+        // Sleep until the next interrupt (System ON sleep). The RTC keeps
+        // running and wakes up the CPU when the next task is due.
         loop {
-            cortex_m::asm::nop();
+            profiling::enter(Phase::Sleep);
+            cortex_m::asm::wfi();
+            profiling::exit(Phase::Sleep);
+        }
+    }
+
+    /// Handle the GPIOTE PORT event: Start a measurement immediately when the
+    /// contact state changes, and play the identify pattern when the button is
+    /// pressed.
+    #[task(
+        binds = GPIOTE,
+        shared = [contact, buzzer, next_measurement, measurement_start, scheduled_start],
+    )]
+    fn gpiote(ctx: gpiote::Context) {
+        unsafe { &*pac::GPIOTE::ptr() }
+            .events_port
+            .write(|w| unsafe { w.bits(0) });
+        if let Some(buzzer) = ctx.shared.buzzer.as_mut() {
+            buzzer.on_interrupt();
+        }
+        let changed = match ctx.shared.contact.as_mut() {
+            Some(contact) => contact.on_interrupt(),
+            None => false,
+        };
+        // A running measurement will contain the new state
+        if changed && ctx.shared.measurement_start.is_none() {
+            let now = monotonics::now();
+            *ctx.shared.next_measurement = now;
+            // Fails if the measurement is already about to start
+            if let Some(handle) = ctx.shared.scheduled_start.take() {
+                *ctx.shared.scheduled_start = handle.reschedule_at(now).ok();
+            }
         }
     }
 
     /// Start a measurement
-    #[task(resources = [i2c, measurement_start], schedule = [collect_measurement])]
+    #[task(shared = [sensors, samples, next_measurement, measurement_start, scheduled_start, led, clock, quiet_hours])]
     fn start_measurement(ctx: start_measurement::Context) {
-        let i2c = ctx.resources.i2c;
-        let power_mode = shtcx::PowerMode::NormalMode;
+        let led = ctx.shared.led;
+
+        // No measurements during quiet hours without beacons, check the
+        // schedule again later
+        let scheduled = *ctx.shared.next_measurement;
+        let quiet = quiet_mode(ctx.shared.clock, ctx.shared.quiet_hours.as_ref(), scheduled);
+        if quiet == Some(QuietMode::Stop) {
+            let next_check = scheduled + QUIET_CHECK_INTERVAL_MS.millis();
+            *ctx.shared.next_measurement = next_check;
+            *ctx.shared.scheduled_start = start_measurement::spawn_at(next_check).ok();
+            if ctx.shared.scheduled_start.is_none() {
+                rprintln!("Error: Could not schedule start_measurement");
+            }
+            return;
+        }
 
-        // Store the instant when this task was scheduled.
-        // This ensures that there is no jitter in scheduling.
-        *ctx.resources.measurement_start = Some(ctx.scheduled);
+        // Store the instant when this task was scheduled (instead of the time
+        // it started running). This ensures that there is no jitter in
+        // scheduling.
+        *ctx.shared.measurement_start = Some(*ctx.shared.next_measurement);
+        #[cfg(feature = "diagnostics")]
+        timing::measurement_started(*ctx.shared.next_measurement, monotonics::now());
 
-        // Trigger SHTC3 measurement
-        i2c.sht.start_measurement(power_mode).unwrap();
-        let sht_delta_us: u32 = shtcx::max_measurement_duration(&i2c.sht, power_mode) as u32;
+        // Trigger measurements, and determine the time until the slowest
+        // sensor is ready
+        profiling::enter(Phase::Sensors);
+        ctx.shared.samples.clear();
+        let mut delta_us: u32 = 0;
+        ctx.shared.sensors.for_each(|sensor| {
+            // Retry once, a single failure is often a glitch on the bus
+            let result = sensor.start().or_else(|_| {
+                telemetry::sensor_retry();
+                sensor.start()
+            });
+            match result {
+                Ok(us) => delta_us = max(delta_us, us),
+                Err(_) => led.error(),
+            }
+        });
+        profiling::exit(Phase::Sensors);
 
-        // Turn on VEML7700
-        //
-        // Note: After enabling the sensor, a startup time of 4 ms plus the integration time must
-        // be awaited.
-        if let Err(e) = i2c.veml.enable() {
-            rprintln!("VEML7700: Could not enable sensor: {:?}", e);
+        // Schedule measurement collection (after taking the additional
+        // samples with the `oversampling` feature)
+        #[cfg(not(feature = "oversampling"))]
+        let collect = collect_measurement::spawn_after(delta_us.micros());
+        #[cfg(feature = "oversampling")]
+        let collect = collect_sample::spawn_after(delta_us.micros());
+        if collect.is_err() {
+            rprintln!("Error: Could not schedule the measurement collection");
         }
-        let veml_delta_us: u32 = VEML_INTEGRATION_TIME.as_us() + 4_000;
+    }
+
+    /// Collect a sample of the oversampled sensors and start their next
+    /// measurement, until all but their last sample are taken. The last
+    /// sample is collected with the measurement. Never spawned without the
+    /// `oversampling` feature (RTIC 1 doesn't support `#[cfg]` on software
+    /// tasks that can be scheduled with the monotonic).
+    #[task(shared = [sensors, samples, led])]
+    fn collect_sample(ctx: collect_sample::Context) {
+        #[cfg(feature = "oversampling")]
+        {
+            let led = ctx.shared.led;
+            let samples = ctx.shared.samples;
+            let round = samples.round();
 
-        // Calculate timedelta until collection
-        let timedelta = max(sht_delta_us, veml_delta_us).micros();
+            profiling::enter(Phase::Sensors);
+            let mut sampling = false;
+            let mut delta_us: u32 = 0;
+            ctx.shared.sensors.for_each_oversampled(|sensor, oversampling| {
+                if round + 1 >= oversampling.samples {
+                    return;
+                }
+                let mut readings = Readings::new();
+                if sensor.collect(&mut readings).is_err() {
+                    led.error();
+                }
+                samples.push(&readings, oversampling.reduction);
+                match sensor.start() {
+                    Ok(us) => {
+                        sampling = true;
+                        delta_us = max(delta_us, us);
+                    }
+                    Err(_) => led.error(),
+                }
+            });
+            samples.next_round();
+            profiling::exit(Phase::Sensors);
 
-        // Schedule measurement collection
-        ctx.schedule
-            .collect_measurement(Instant::now() + timedelta)
-            .unwrap();
+            if sampling {
+                if collect_sample::spawn_after(delta_us.micros()).is_err() {
+                    rprintln!("Error: Could not schedule collect_sample");
+                }
+            } else if collect_measurement::spawn().is_err() {
+                rprintln!("Error: Could not schedule collect_measurement");
+            }
+        }
+        #[cfg(not(feature = "oversampling"))]
+        let _ = ctx;
     }
 
     /// Collect a measurement. Then send the data using non-connectable BLE
     /// advertisement frames (beacons).
     #[task(
-        resources = [i2c, measurement_start, device_address, beacon],
-        schedule = [start_measurement],
-        spawn = [broadcast_beacon],
+        shared = [sensors, samples, contact, buzzer, next_measurement, measurement_start, scheduled_start, beacons, beacon_index, compat, led, entropy, clock, quiet_hours],
+        local = [device_address, private_address, key, counter: u16 = 0, #[cfg(feature = "backlog")] backlog: Backlog = Backlog::new(), #[cfg(feature = "delta")] delta: DeltaEncoder = DeltaEncoder::new()],
     )]
     fn collect_measurement(ctx: collect_measurement::Context) {
-        static mut COUNTER: u16 = 0;
-
-        let i2c = ctx.resources.i2c;
+        let led = ctx.shared.led;
+        let counter = ctx.local.counter;
 
         // Take measurement start time
         let measurement_start = ctx
-            .resources
+            .shared
             .measurement_start
             .take()
             .expect("Cannot collect measurement without starting a measurement first");
+        #[cfg(feature = "diagnostics")]
+        let timing = timing::entry_value(monotonics::now());
 
-        // Collect SHTC3 measurement result
-        let sht_measurement = i2c.sht.get_measurement_result().unwrap();
-        rprintln!(
-            "SHTC3 measurement: {}°C / {} %RH",
-            sht_measurement.temperature.as_degrees_celsius(),
-            sht_measurement.humidity.as_percent()
-        );
-
-        // Collect VEML7700 measurement result
-        let veml_measurement = match i2c.veml.read_lux() {
-            Ok(lux) => {
-                rprintln!("VEML7700 measurement: {:.1} lx", lux);
-                Some(lux)
+        // Collect measurement results
+        profiling::enter(Phase::Sensors);
+        let mut readings = Readings::new();
+        ctx.shared.sensors.for_each(|sensor| {
+            if sensor.collect(&mut readings).is_err() {
+                led.error();
             }
-            Err(e) => {
-                rprintln!("VEML7700: Could not measure lux: {:?}", e);
-                None
+        });
+        if let Some(contact) = ctx.shared.contact.as_mut() {
+            if contact.collect(&mut readings).is_err() {
+                led.error();
             }
+        }
+        // Reduce the samples of the oversampled readings to a single value
+        #[cfg(feature = "oversampling")]
+        ctx.shared.samples.reduce(&mut readings);
+        profiling::exit(Phase::Sensors);
+
+        // Optional hardware of the node
+        #[allow(unused_mut)]
+        let mut status = if ctx.shared.buzzer.is_some() {
+            STATUS_BUZZER
+        } else {
+            0
         };
-        if let Err(e) = i2c.veml.disable() {
-            rprintln!("VEML7700: Could not shut down: {:?}", e);
+        // Readings affected by a heater pulse (the next one is triggered now,
+        // after the readings were collected)
+        #[cfg(feature = "heater")]
+        let heated = ctx.shared.sensors.sht.heater_cycle();
+        #[cfg(feature = "heater")]
+        if heated {
+            status |= STATUS_HEATED;
+        }
+        // Compare the redundant sensors (a heated primary sensor is expected
+        // to disagree)
+        #[cfg(all(feature = "dual-sensor", feature = "heater"))]
+        let compare = !heated;
+        #[cfg(all(feature = "dual-sensor", not(feature = "heater")))]
+        let compare = true;
+        #[cfg(feature = "dual-sensor")]
+        if compare && sensors::sensors_disagree(&readings) {
+            status |= STATUS_DISAGREEMENT;
+        }
+        readings.push(SENSOR_STATUS, &[status]);
+
+        // Health counters (not in every measurement, to save airtime)
+        if *counter % telemetry::TELEMETRY_INTERVAL == 0 {
+            readings.push(SENSOR_TELEMETRY, &telemetry::entry_value());
+        }
+
+        // Duration and scheduling latency of the measurement
+        #[cfg(feature = "diagnostics")]
+        readings.push(SENSOR_TIMING, &timing);
+
+        // One of the past measurements, in case the gateway missed it
+        #[cfg(feature = "backlog")]
+        {
+            let backlog = ctx.local.backlog;
+            if let Some(value) = backlog.entry_value(*counter) {
+                readings.push(SENSOR_BACKLOG, &value);
+            }
+            let temp = readings.i32_value(SENSOR_TEMP);
+            let humi = readings.i32_value(SENSOR_HUMI);
+            if let (Some(temp), Some(humi)) = (temp, humi) {
+                backlog.push(*counter, temp, humi);
+            }
         }
 
+        // Current values of the GATT server
+        #[cfg(feature = "gatt")]
+        gatt::update(&readings);
+
+        // Address of the beacons (changed regularly with the
+        // `private-address` feature)
+        let address = match ctx.local.private_address.as_mut() {
+            Some(private_address) => private_address.next(ctx.shared.entropy),
+            None => *ctx.local.device_address,
+        };
+        ctx.shared.compat.update(address, &readings, measurement_start);
+
+        // Only the differences to the last full measurement (after the
+        // absolute values were used above)
+        #[cfg(feature = "delta")]
+        ctx.local.delta.encode(*counter, &mut readings);
+
         // Prepare beacon payload
-        let temp = sht_measurement
-            .temperature
-            .as_millidegrees_celsius()
-            .to_le_bytes();
-        let humi = sht_measurement.humidity.as_millipercent().to_le_bytes();
-        let lux = veml_measurement
-            .expect("TODO: Allow VEML measurement errors")
-            .to_le_bytes();
-        let counter_bytes = COUNTER.to_le_bytes();
-        #[rustfmt::skip]
-        let payload = [
-            0xff, 0xff,
-            counter_bytes[0], counter_bytes[1],
-            SENSOR_TEMP, temp[0], temp[1], temp[2], temp[3], // i32 LE
-            SENSOR_HUMI, humi[0], humi[1], humi[2], humi[3], // i32 LE
-            SENSOR_LUX, lux[0], lux[1], lux[2], lux[3], // f32 LE
-        ];
-
-        // Create beacon
-        let advertisement_data = [
-            AdStructure::CompleteLocalName("Sensilo"),
-            AdStructure::Unknown {
-                ty: AD_STRUCTURE_MANUFACTURER_DATA,
-                data: &payload,
-            },
-        ];
-        let beacon = Beacon::new(*ctx.resources.device_address, &advertisement_data)
-            .expect("Could not create beacon");
-        *ctx.resources.beacon = Some(beacon);
-        rprintln!("Created beacon with counter {}", COUNTER);
+        let entries = readings.entries();
+
+        // Split entries into one or more beacon frames (every frame needs
+        // space for the MAC if the payload is authenticated, and for the CRC)
+        let key = ctx.local.key.as_ref();
+        let max_len = max_payload_len(DEVICE_NAME.len());
+        let mac_len = if key.is_some() { MAC_ENTRY_LEN } else { 0 };
+        let crc_len = if cfg!(feature = "crc") { CRC_ENTRY_LEN } else { 0 };
+        let frames = frame_count(&entries, max_len - mac_len - crc_len);
+        if frames > MAX_BEACON_FRAMES {
+            rprintln!("Warning: Payload needs {} frames, only sending {}", frames, MAX_BEACON_FRAMES);
+        }
+        // The frame info contains the number of frames that are actually sent,
+        // the gateway would wait for the others in vain
+        let frames = frames.min(MAX_BEACON_FRAMES);
+        let mut next_entry = 0;
+        for (i, slot) in ctx.shared.beacons.iter_mut().enumerate() {
+            *slot = None;
+            if i >= frames {
+                continue;
+            }
+            let mut payload = PayloadWriter::new(max_len, *counter);
+            if key.is_some() {
+                payload.reserve_mac();
+            }
+            if cfg!(feature = "crc") {
+                payload.reserve_crc();
+            }
+            payload
+                .write_frame_info(i as u8, frames as u8)
+                .expect("Payload too small for frame info");
+            next_entry = payload.write_entries(&entries, next_entry);
+            if let Some(key) = key {
+                payload.write_mac(key, ctx.local.device_address.raw());
+            }
+            if cfg!(feature = "crc") {
+                payload.write_crc();
+            }
+
+            // Create beacon
+            let beacon = Radio::frame(address, DEVICE_NAME, payload.as_bytes())
+                .expect("Could not create beacon");
+            *slot = Some(beacon);
+        }
+        rprintln!("Created {} beacon frame(s) with counter {}", frames, counter);
 
         // Broadcast beacon
-        if ctx.spawn.broadcast_beacon(0).is_err() {
+        *ctx.shared.beacon_index = 0;
+        if broadcast_beacon::spawn().is_err() {
             rprintln!("Error: Could not spawn broadcast_beacon");
         }
 
         // Increment counter (allow wrap-around)
-        *COUNTER = COUNTER.wrapping_add(1);
+        *counter = counter.wrapping_add(1);
 
-        // Schedule a new measurement
-        ctx.schedule
-            .start_measurement(measurement_start + MEASURE_INTERVAL_MS.millis())
-            .unwrap();
+        // Schedule a new measurement (with jitter, to avoid collisions with
+        // other nodes), less frequently during quiet hours
+        let mut interval_ms = MEASURE_INTERVAL_MS;
+        let quiet = quiet_mode(ctx.shared.clock, ctx.shared.quiet_hours.as_ref(), measurement_start);
+        if let Some(QuietMode::Lengthen(factor)) = quiet {
+            interval_ms *= u32::from(factor);
+        }
+        let interval_ms = jitter::measurement_interval_ms(ctx.shared.entropy, interval_ms);
+        let next_measurement = measurement_start + interval_ms.millis();
+        *ctx.shared.next_measurement = next_measurement;
+        *ctx.shared.scheduled_start = start_measurement::spawn_at(next_measurement).ok();
+        if ctx.shared.scheduled_start.is_none() {
+            rprintln!("Error: Could not schedule start_measurement");
+        }
     }
 
-    /// Broadcast the beacon until the BEACON_BURST_COUNT has been reached.
-    #[task(resources = [radio, beacon, led], schedule = [broadcast_beacon])]
-    fn broadcast_beacon(ctx: broadcast_beacon::Context, i: u8) {
+    /// Broadcast the beacon frames (in turns) until the configured number of
+    /// beacons has been sent. Every frame is sent at least once.
+    #[task(
+        shared = [entropy, beacons, beacon_index, compat, led, buzzer, clock, radio],
+        local = [burst, sync_countdown: u16 = 0],
+    )]
+    fn broadcast_beacon(mut ctx: broadcast_beacon::Context) {
+        let burst = ctx.local.burst;
+        let beacons = ctx.shared.beacons;
+        let frames = beacons.iter().filter(|beacon| beacon.is_some()).count();
+        let count = max(burst.count, frames as u8);
+
+        let i = *ctx.shared.beacon_index;
         if i == 0 {
-            ctx.resources.led.set_low().ok();
-        } else if i >= BEACON_BURST_COUNT {
-            ctx.resources.led.set_high().ok();
+            // The radio needs the HFXO
+            power::hfxo_start();
+            ctx.shared.led.burst_start();
+            if ctx.shared.led.identifying() {
+                if let Some(buzzer) = ctx.shared.buzzer.as_mut() {
+                    buzzer.play(Pattern::Chirp);
+                }
+            }
+        } else if i >= count {
+            // Followed by the compatibility frame, if enabled
+            if let Some(beacon) = ctx.shared.compat.take() {
+                ctx.shared.radio.lock(|radio| radio.broadcast(&beacon));
+                telemetry::beacon_sent();
+            }
+            ctx.shared.led.burst_end();
+            // Listen for a time beacon while the HFXO is still running
+            #[cfg(feature = "time-sync")]
+            {
+                let countdown = ctx.local.sync_countdown;
+                if *countdown == 0 {
+                    let mut time = None;
+                    ctx.shared.radio.lock(|radio| {
+                        radio.listen(timesync::LISTEN_TIMEOUT_MS.millis(), |pdu| {
+                            time = timesync::parse(pdu);
+                            time.is_some()
+                        })
+                    });
+                    *countdown = match time {
+                        Some(time) => {
+                            ctx.shared
+                                .clock
+                                .set(monotonics::now(), time.unix_time, time.utc_offset_min);
+                            rprintln!("Time sync: Unix time {}", time.unix_time);
+                            timesync::SYNC_CYCLES
+                        }
+                        None => timesync::RETRY_CYCLES,
+                    };
+                }
+                *countdown -= 1;
+            }
+            power::hfxo_stop();
             return;
         }
 
-        if let Some(beacon) = ctx.resources.beacon {
-            beacon.broadcast(ctx.resources.radio);
+        if let Some(beacon) = beacons.get(i as usize % max(frames, 1)).and_then(Option::as_ref) {
+            let start = monotonics::now();
+            profiling::enter(Phase::RadioTx);
+            ctx.shared.radio.lock(|radio| radio.broadcast(beacon));
+            profiling::exit(Phase::RadioTx);
+            telemetry::beacon_sent();
             rprintln!("Sent beacon");
 
-            if ctx
-                .schedule
-                .broadcast_beacon(ctx.scheduled + BEACON_BURST_INTERVAL_MS.millis(), i + 1)
-                .is_err()
-            {
-                rprintln!("Error: Could not re-schedule broadcast_beacon");
+            *ctx.shared.beacon_index = i + 1;
+            let interval_ms = jitter::burst_interval_ms(ctx.shared.entropy, burst.interval_ms);
+            if broadcast_beacon::spawn_at(start + interval_ms.millis()).is_err() {
+                rprintln!("Error: Could not schedule broadcast_beacon");
             }
         } else {
             rprintln!("Error: No beacon that can be broadcasted");
+            ctx.shared.led.error();
+            power::hfxo_stop();
+        }
+    }
+
+    /// Handle the radio events of the GATT server link layer.
+    #[cfg(feature = "gatt")]
+    #[task(binds = RADIO, shared = [radio], priority = 2)]
+    fn radio(mut ctx: radio::Context) {
+        if ctx.shared.radio.lock(|radio| radio.on_radio_interrupt()) {
+            // Fails if the worker is already pending
+            ble_worker::spawn().ok();
         }
     }
 
-    // Provide unused interrupts to RTIC for its scheduling
-    extern "C" {
-        fn SWI0_EGU0();
-        fn SWI1_EGU1();
-        fn SWI2_EGU2();
-        fn SWI3_EGU3();
-        fn SWI4_EGU4();
-        fn SWI5_EGU5();
+    /// Handle the timer events of the GATT server link layer.
+    #[cfg(feature = "gatt")]
+    #[task(binds = TIMER0, shared = [radio], priority = 2)]
+    fn ble_timer(mut ctx: ble_timer::Context) {
+        if ctx.shared.radio.lock(|radio| radio.on_timer_interrupt()) {
+            ble_worker::spawn().ok();
+        }
+    }
+
+    /// Respond to the requests of a connected GATT client. Never spawned
+    /// without the `gatt` feature (RTIC 1 doesn't support `#[cfg]` on
+    /// software tasks that can be scheduled with the monotonic).
+    #[task(local = [responder])]
+    fn ble_worker(ctx: ble_worker::Context) {
+        #[cfg(feature = "gatt")]
+        gatt::process(ctx.local.responder);
+        #[cfg(not(feature = "gatt"))]
+        let _ = ctx.local.responder;
     }
-};
+}
@@ -0,0 +1,116 @@
+//! Driver for the MAX31855 thermocouple-to-digital converter (SPI, read-only).
+//!
+//! The MAX31855 converts continuously (about 100 ms per conversion). Reading
+//! the 32 bit frame returns the result of the last conversion:
+//!
+//! | Bits  | Content                                            |
+//! |-------|----------------------------------------------------|
+//! | 31–18 | Thermocouple temperature (14 bit signed, 0.25 °C)  |
+//! | 16    | Fault                                              |
+//! | 15–4  | Internal temperature (12 bit signed, 0.0625 °C)    |
+//! | 2     | Thermocouple shorted to VCC                        |
+//! | 1     | Thermocouple shorted to GND                        |
+//! | 0     | Thermocouple not connected                         |
+
+use embedded_hal::{blocking::spi::Transfer, digital::v2::OutputPin};
+
+use crate::console::rprintln;
+use crate::sensors::{self, Readings, Sensor, SENSOR_THERMOCOUPLE};
+
+/// A thermocouple fault, reported by the converter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    ShortToVcc,
+    ShortToGnd,
+    OpenCircuit,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    ChipSelect,
+    Fault(Fault),
+}
+
+/// A decoded conversion result.
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    /// Thermocouple temperature in m°C
+    pub thermocouple: i32,
+    /// Internal (cold junction) temperature in m°C
+    pub internal: i32,
+}
+
+pub struct Max31855<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS, E> Max31855<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E>,
+    CS: OutputPin,
+{
+    /// Create a new driver. The SPI bus must be configured in mode 0, with
+    /// up to 5 MHz.
+    pub fn new(spi: SPI, mut cs: CS) -> Self {
+        cs.set_high().ok();
+        Self { spi, cs }
+    }
+
+    /// Read the result of the last conversion.
+    pub fn read(&mut self) -> Result<Reading, Error<E>> {
+        let mut buf = [0; 4];
+        self.cs.set_low().map_err(|_| Error::ChipSelect)?;
+        let result = self.spi.transfer(&mut buf).map(|_| ());
+        self.cs.set_high().map_err(|_| Error::ChipSelect)?;
+        result.map_err(Error::Spi)?;
+        decode(u32::from_be_bytes(buf)).map_err(Error::Fault)
+    }
+}
+
+fn decode(frame: u32) -> Result<Reading, Fault> {
+    if frame & (1 << 16) != 0 {
+        let fault = if frame & 0b100 != 0 {
+            Fault::ShortToVcc
+        } else if frame & 0b010 != 0 {
+            Fault::ShortToGnd
+        } else {
+            Fault::OpenCircuit
+        };
+        return Err(fault);
+    }
+    // Sign extend by shifting the value to the top bits and back
+    let thermocouple = (frame as i32) >> 18;
+    let internal = ((frame << 16) as i32) >> 20;
+    Ok(Reading {
+        thermocouple: thermocouple * 250,
+        internal: internal * 625 / 10,
+    })
+}
+
+impl<SPI, CS, E> Sensor for Max31855<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E>,
+    CS: OutputPin,
+    E: core::fmt::Debug,
+{
+    fn start(&mut self) -> Result<u32, sensors::Error> {
+        // Conversions run continuously
+        Ok(0)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), sensors::Error> {
+        let reading = self.read().map_err(|e| {
+            rprintln!("MAX31855: Could not read temperature: {:?}", e);
+            sensors::Error
+        })?;
+        rprintln!(
+            "MAX31855 measurement: {} m°C (internal {} m°C)",
+            reading.thermocouple,
+            reading.internal
+        );
+        readings.push(SENSOR_THERMOCOUPLE, &reading.thermocouple.to_le_bytes()); // i32 LE
+        Ok(())
+    }
+}
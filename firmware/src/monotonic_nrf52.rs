@@ -1,24 +1,52 @@
-//! Using NRF52 as monotonic timer
+//! Using the NRF52 RTC as monotonic timer
 //!
-//! Source:
-//! https://github.com/rtic-rs/rtic-examples/blob/master/rtic_v5/monotonic_nrf52/src/monotonic_nrf52.rs
+//! Unlike the TIMER peripherals, the RTC is clocked by the low frequency clock
+//! (LFCLK), which keeps running in System ON sleep. This allows the chip to
+//! sleep with the HF clock stopped between measurements.
+//!
+//! The RTC counter only has 24 bits and wraps around every 512 seconds (at
+//! 32768 Hz). It is extended to 32 bits by counting overflows: The period
+//! counter is incremented on overflow and when the counter passes the half
+//! (through compare channel 3), which allows reading the time without races.
+//!
+//! RTIC drives its timer queue with compare channel 0. Instants that are
+//! further away than the counter range let the compare event fire early, and
+//! RTIC sets the compare value again until the instant is reached.
 
 use core::u32;
 use core::{
     cmp::Ordering,
-    convert::{Infallible, TryInto},
     fmt, ops,
+    sync::atomic::{compiler_fence, AtomicU32, Ordering as AtomicOrdering},
 };
-use nrf52832_hal::pac;
+use cortex_m::peripheral::NVIC;
 use rtic::Monotonic;
 
+use crate::hal::pac;
+
+/// Frequency of the RTC (LFCLK without prescaler)
+const RTC_FREQUENCY_HZ: u64 = 32_768;
+
+/// Compare channel used by the RTIC timer queue
+const QUEUE_CHANNEL: usize = 0;
+
+/// Compare channel used to detect the half of the counter period
+const HALF_PERIOD_CHANNEL: usize = 3;
+
+/// Number of half periods of the 24 bit counter
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+
+fn rtc() -> &'static pac::rtc0::RegisterBlock {
+    unsafe { &*pac::RTC1::ptr() }
+}
+
 /// A measurement of the counter. Opaque and useful only with `Duration`
 ///
 /// # Correctness
 ///
-/// Adding or subtracting a `Duration` of more than `(1 << 31)` cycles to an `Instant` effectively
-/// makes it "wrap around" and creates an incorrect value. This is also true if the operation is
-/// done in steps, e.g. `(instant + dur) + dur` where `dur` is `(1 << 30)` ticks.
+/// Adding or subtracting a `Duration` of more than `(1 << 31)` ticks to an `Instant` effectively
+/// makes it "wrap around" and creates an incorrect value. At 32768 Hz, this is the case for
+/// durations of more than 18 hours.
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct Instant {
     inner: i32,
@@ -27,13 +55,15 @@ pub struct Instant {
 impl Instant {
     /// Returns an instant corresponding to "now"
     pub fn now() -> Self {
-        let now = {
-            let timer = unsafe { &*pac::TIMER1::ptr() };
-            timer.tasks_capture[0].write(|w| unsafe { w.bits(1) });
-            timer.cc[0].read().bits()
-        };
-
-        Instant { inner: now as i32 }
+        let period = PERIOD.load(AtomicOrdering::Relaxed);
+        compiler_fence(AtomicOrdering::Acquire);
+        let counter = rtc().counter.read().bits();
+
+        // In odd periods, the counter is in its upper half (unless it already
+        // overflowed, but the overflow has not been counted yet). Flipping the
+        // highest bit handles both cases.
+        let ticks = (period << 23).wrapping_add(counter ^ ((period & 1) << 23));
+        Instant { inner: ticks as i32 }
     }
 
     /// Returns the amount of time elapsed since this instant was created.
@@ -48,7 +78,7 @@ impl Instant {
 
     /// Returns the amount of time elapsed from another instant to this one.
     pub fn duration_since(&self, earlier: Instant) -> Duration {
-        let diff = self.inner - earlier.inner;
+        let diff = self.inner.wrapping_sub(earlier.inner);
         assert!(diff >= 0, "second instant is later than self");
         Duration { inner: diff as u32 }
     }
@@ -119,36 +149,26 @@ impl PartialOrd for Instant {
 
 /// A `Duration` type to represent a span of time.
 ///
-/// This data type is only available on ARMv7-M
-///
-/// # Correctness
-///
-/// This type is *not* appropriate for representing time spans in the order of, or larger than,
-/// seconds because it can hold a maximum of `(1 << 31)` "ticks" where each tick is the inverse of
-/// the CPU frequency, which usually is dozens of MHz.
+/// The unit is one RTC tick (1/32768 s, approx. 30.5 µs).
 #[derive(Clone, Copy, Default, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Duration {
     inner: u32,
 }
 
 impl Duration {
-    /// Creates a new `Duration` from the specified number of clock cycles
-    pub fn from_cycles(cycles: u32) -> Self {
-        Duration { inner: cycles }
+    /// Creates a new `Duration` from the specified number of RTC ticks
+    pub fn from_ticks(ticks: u32) -> Self {
+        Duration { inner: ticks }
     }
 
-    /// Returns the total number of clock cycles contained by this `Duration`
-    pub fn as_cycles(&self) -> u32 {
+    /// Returns the total number of RTC ticks contained by this `Duration`
+    pub fn as_ticks(&self) -> u32 {
         self.inner
     }
-}
-
-// Used internally by RTIC to convert the duration into a known type
-impl TryInto<u32> for Duration {
-    type Error = Infallible;
 
-    fn try_into(self) -> Result<u32, Infallible> {
-        Ok(self.as_cycles())
+    /// Returns the duration in microseconds (rounded down)
+    pub fn as_micros(&self) -> u32 {
+        (u64::from(self.inner) * 1_000_000 / RTC_FREQUENCY_HZ) as u32
     }
 }
 
@@ -202,7 +222,8 @@ impl ops::Sub<Duration> for Duration {
 
 /// Adds the `millis` and `micros` methods to the `u32` type
 ///
-/// This trait is only available on ARMv7-M
+/// Durations are rounded up to the next RTC tick, so that a delay is never
+/// shorter than requested.
 pub trait U32Ext {
     /// Converts the `u32` value as seconds into ticks
     fn secs(self) -> Duration;
@@ -217,20 +238,25 @@ pub trait U32Ext {
     fn hz(self) -> Duration;
 }
 
+/// Convert a value with the specified number of units per second into ticks
+/// (rounded up).
+fn to_ticks(value: u32, per_second: u64) -> Duration {
+    Duration {
+        inner: ((u64::from(value) * RTC_FREQUENCY_HZ + per_second - 1) / per_second) as u32,
+    }
+}
+
 impl U32Ext for u32 {
     fn secs(self) -> Duration {
-        self.millis() * 1_000
+        to_ticks(self, 1)
     }
 
     fn millis(self) -> Duration {
-        self.micros() * 1_000
+        to_ticks(self, 1_000)
     }
 
     fn micros(self) -> Duration {
-        let frac = Tim1::ratio();
-        Duration {
-            inner: (64 * frac.denominator * self) / frac.numerator,
-        }
+        to_ticks(self, 1_000_000)
     }
 
     fn hz(self) -> Duration {
@@ -238,80 +264,84 @@ impl U32Ext for u32 {
     }
 }
 
-/// Implementor of the `rtic::Monotonic` traits and used to consume the timer
-/// to not allow for erroneous configuration.
+/// Implementor of the `rtic::Monotonic` trait, consuming the timer to not
+/// allow for erroneous configuration.
 ///
-/// The timer must be initialized through `initialize()`.
-pub struct Tim1;
-
-impl Tim1 {
-    pub fn initialize(timer: pac::TIMER1) {
-        // Auto restart, make sure the entire timer won't stop for any event
-        timer.shorts.write(|w| {
-            w.compare0_clear()
-                .enabled()
-                .compare0_stop()
-                .disabled()
-                .compare1_clear()
-                .enabled()
-                .compare1_stop()
-                .disabled()
-                .compare2_clear()
-                .enabled()
-                .compare2_stop()
-                .disabled()
-                .compare3_clear()
-                .enabled()
-                .compare3_stop()
-                .disabled()
-        });
-
-        // 1 MHz mode
-        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
-
-        // 32 bit mode
-        timer.bitmode.write(|w| w.bitmode()._32bit());
-
-        // Set compare value to max, not sure if this is needed
-        timer.cc[0].write(|w| unsafe { w.cc().bits(u32::MAX) });
+/// The LFCLK must be started before the timer is created.
+pub struct Rtc1 {
+    _rtc: pac::RTC1,
+}
 
-        // Clear the counter value
-        timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+impl Rtc1 {
+    pub fn new(rtc: pac::RTC1) -> Self {
+        // No prescaler, 32768 Hz
+        rtc.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
 
-        // Start the timer
-        timer.tasks_start.write(|w| unsafe { w.bits(1) });
+        // Interrupts on overflow and half period (the interrupt is unmasked
+        // by RTIC)
+        rtc.cc[HALF_PERIOD_CHANNEL].write(|w| unsafe { w.bits(0x80_0000) });
+        rtc.evtenset
+            .write(|w| w.ovrflw().set().compare3().set());
+        rtc.intenset
+            .write(|w| w.ovrflw().set().compare3().set());
 
-        // Throw away the timer, it is now setup and consumed
-        drop(timer);
+        // Start the timer (the counter is cleared by RTIC through `reset`)
+        rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        Rtc1 { _rtc: rtc }
     }
 }
 
-impl rtic::Monotonic for Tim1 {
+impl Monotonic for Rtc1 {
     type Instant = Instant;
+    type Duration = Duration;
 
-    /// The ratio between the system timer (SysTick) frequency and this clock
-    /// frequency, i.e. `Monotonic clock * Fraction = System clock`.
-    fn ratio() -> rtic::Fraction {
-        // The TIM1 timer is being initialized with 1 MHz. If we multiply by 64,
-        // we get the sys clock of 64 MHz.
-        rtic::Fraction {
-            numerator: 64,
-            denominator: 1,
-        }
-    }
+    // The overflows must be counted, even if no task is scheduled
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
 
-    fn now() -> Self::Instant {
+    fn now(&mut self) -> Self::Instant {
         Instant::now()
     }
 
-    unsafe fn reset() {
-        let timer = &*pac::TIMER1::ptr();
+    fn zero() -> Self::Instant {
+        Instant { inner: 0 }
+    }
 
+    unsafe fn reset(&mut self) {
         // Clear the counter value
-        timer.tasks_clear.write(|w| w.bits(1));
+        rtc().tasks_clear.write(|w| w.bits(1));
+        PERIOD.store(0, AtomicOrdering::Relaxed);
     }
 
-    fn zero() -> Self::Instant {
-        Instant { inner: 0 }
+    /// Let the compare event fire at the specified instant. If it is more
+    /// than 512 seconds in the future, the event fires early.
+    fn set_compare(&mut self, instant: Self::Instant) {
+        let rtc = rtc();
+        rtc.cc[QUEUE_CHANNEL].write(|w| unsafe { w.bits(instant.counts() & 0xff_ffff) });
+        rtc.evtenset.write(|w| w.compare0().set());
+        rtc.intenset.write(|w| w.compare0().set());
+
+        // The compare event is not generated if the compare value is less
+        // than two ticks in the future
+        if instant.inner.wrapping_sub(Instant::now().inner) < 2 {
+            NVIC::pend(pac::Interrupt::RTC1);
+        }
+    }
+
+    fn clear_compare_flag(&mut self) {
+        rtc().events_compare[QUEUE_CHANNEL].write(|w| unsafe { w.bits(0) });
+    }
+
+    /// Count the half periods.
+    fn on_interrupt(&mut self) {
+        let rtc = rtc();
+        if rtc.events_ovrflw.read().bits() != 0 {
+            rtc.events_ovrflw.write(|w| unsafe { w.bits(0) });
+            PERIOD.fetch_add(1, AtomicOrdering::Release);
+        }
+        if rtc.events_compare[HALF_PERIOD_CHANNEL].read().bits() != 0 {
+            rtc.events_compare[HALF_PERIOD_CHANNEL].write(|w| unsafe { w.bits(0) });
+            PERIOD.fetch_add(1, AtomicOrdering::Release);
+        }
     }
 }
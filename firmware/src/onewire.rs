@@ -0,0 +1,203 @@
+//! Bit-banged 1-Wire bus master.
+//!
+//! The bus pin is driven in open drain mode (the line is pulled high by an
+//! external 4.7 kΩ resistor). The time slots are generated with busy waiting,
+//! with interrupts disabled, which is accurate enough at 64 MHz.
+
+use cortex_m::asm::delay;
+
+use crate::hal::{
+    gpio::{Disconnected, Pin},
+    pac,
+};
+
+/// CPU cycles per µs
+const CYCLES_PER_US: u32 = 64;
+
+// ROM commands
+pub const CMD_SEARCH_ROM: u8 = 0xf0;
+pub const CMD_MATCH_ROM: u8 = 0x55;
+pub const CMD_SKIP_ROM: u8 = 0xcc;
+
+/// A 64 bit ROM code (family code, serial number, CRC).
+pub type Rom = [u8; 8];
+
+fn p0() -> &'static pac::p0::RegisterBlock {
+    unsafe { &*pac::P0::ptr() }
+}
+
+fn delay_us(us: u32) {
+    delay(us * CYCLES_PER_US);
+}
+
+/// Dallas/Maxim CRC-8. The CRC over some data including its CRC byte is 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+pub struct OneWire {
+    pin: u32,
+}
+
+impl OneWire {
+    pub fn new(pin: Pin<Disconnected>) -> Self {
+        let pin = u32::from(pin.pin());
+        let p0 = p0();
+        p0.outset.write(|w| unsafe { w.bits(1 << pin) });
+        p0.pin_cnf[pin as usize].write(|w| {
+            w.dir()
+                .output()
+                .input()
+                .connect()
+                .pull()
+                .pullup()
+                .drive()
+                .s0d1()
+        });
+        Self { pin }
+    }
+
+    fn pull_low(&self) {
+        p0().outclr.write(|w| unsafe { w.bits(1 << self.pin) });
+    }
+
+    fn release(&self) {
+        p0().outset.write(|w| unsafe { w.bits(1 << self.pin) });
+    }
+
+    fn is_high(&self) -> bool {
+        p0().in_.read().bits() & (1 << self.pin) != 0
+    }
+
+    /// Send a reset pulse. Return whether a device answered with a presence
+    /// pulse.
+    pub fn reset(&mut self) -> bool {
+        cortex_m::interrupt::free(|_| {
+            self.pull_low();
+            delay_us(480);
+            self.release();
+            delay_us(70);
+            let presence = !self.is_high();
+            delay_us(410);
+            presence
+        })
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        cortex_m::interrupt::free(|_| {
+            self.pull_low();
+            if bit {
+                delay_us(6);
+                self.release();
+                delay_us(64);
+            } else {
+                delay_us(60);
+                self.release();
+                delay_us(10);
+            }
+        })
+    }
+
+    pub fn read_bit(&mut self) -> bool {
+        cortex_m::interrupt::free(|_| {
+            self.pull_low();
+            delay_us(6);
+            self.release();
+            delay_us(9);
+            let bit = self.is_high();
+            delay_us(55);
+            bit
+        })
+    }
+
+    /// Write a byte (LSB first).
+    pub fn write_byte(&mut self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    /// Read a byte (LSB first).
+    pub fn read_byte(&mut self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | (u8::from(self.read_bit()) << i))
+    }
+
+    /// Reset the bus and select the device with the given ROM code. Return
+    /// false if there is no device on the bus.
+    pub fn select(&mut self, rom: &Rom) -> bool {
+        if !self.reset() {
+            return false;
+        }
+        self.write_byte(CMD_MATCH_ROM);
+        for byte in rom {
+            self.write_byte(*byte);
+        }
+        true
+    }
+
+    /// Find the ROM codes of the devices on the bus (up to `roms.len()`),
+    /// using the search algorithm from Maxim application note 187. Return the
+    /// number of devices found.
+    pub fn search(&mut self, roms: &mut [Rom]) -> usize {
+        let mut count = 0;
+        let mut rom: Rom = [0; 8];
+        // Bit position (1-64) of the last branch where 0 was taken
+        let mut last_discrepancy = 0;
+        while count < roms.len() {
+            if !self.reset() {
+                break;
+            }
+            self.write_byte(CMD_SEARCH_ROM);
+            let mut last_zero = 0;
+            for position in 1..=64 {
+                let (byte, mask) = ((position - 1) / 8, 1 << ((position - 1) % 8));
+                let bit = self.read_bit();
+                let complement = self.read_bit();
+                let direction = match (bit, complement) {
+                    // No device answered
+                    (true, true) => return count,
+                    (bit, complement) if bit != complement => bit,
+                    // Discrepancy: Devices with both values are present
+                    _ => {
+                        let direction = if position < last_discrepancy {
+                            rom[byte] & mask != 0
+                        } else {
+                            position == last_discrepancy
+                        };
+                        if !direction {
+                            last_zero = position;
+                        }
+                        direction
+                    }
+                };
+                if direction {
+                    rom[byte] |= mask;
+                } else {
+                    rom[byte] &= !mask;
+                }
+                self.write_bit(direction);
+            }
+            if crc8(&rom) == 0 {
+                roms[count] = rom;
+                count += 1;
+            }
+            last_discrepancy = last_zero;
+            if last_discrepancy == 0 {
+                break;
+            }
+        }
+        count
+    }
+}
@@ -0,0 +1,180 @@
+//! Oversampling of the sensor readings.
+//!
+//! With the `oversampling` feature, the SHTC3 and the VEML7700 are sampled
+//! several times in quick succession within a measurement cycle (the number
+//! of samples is configured per sensor in `src/board.rs`). The samples of a
+//! reading are reduced to a single value (the median or the mean), which
+//! reduces the noise of the reported values without sending more beacons.
+//!
+//! Only the temperature, humidity and lux readings are oversampled. The other
+//! readings of these sensors (e.g. the raw VEML7700 channel counts) are taken
+//! from the last sample.
+
+use crate::sensors::{Readings, SENSOR_HUMI, SENSOR_LUX, SENSOR_TEMP};
+
+/// Maximum number of samples of a reading per measurement.
+pub const MAX_SAMPLES: usize = 8;
+
+/// The oversampled readings, with the type of their value.
+const READINGS: [(u8, Kind); 3] = [
+    (SENSOR_TEMP, Kind::I32),
+    (SENSOR_HUMI, Kind::I32),
+    (SENSOR_LUX, Kind::F32),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    I32,
+    F32,
+}
+
+/// How the samples of a reading are reduced to a single value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reduction {
+    /// The middle sample (or the mean of the two middle samples), robust
+    /// against single outliers
+    Median,
+    /// The mean of the samples
+    Mean,
+}
+
+/// The oversampling of a sensor.
+#[derive(Debug, Clone, Copy)]
+pub struct Oversampling {
+    /// Samples per measurement (1 disables oversampling, at most
+    /// `MAX_SAMPLES`)
+    pub samples: u8,
+    pub reduction: Reduction,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Series {
+    reduction: Reduction,
+    values: [f32; MAX_SAMPLES],
+    len: usize,
+}
+
+impl Series {
+    const fn new() -> Self {
+        Self {
+            reduction: Reduction::Median,
+            values: [0.0; MAX_SAMPLES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, value: f32) {
+        if self.len < MAX_SAMPLES {
+            self.values[self.len] = value;
+            self.len += 1;
+        }
+    }
+
+    fn reduce(&mut self) -> f32 {
+        let values = &mut self.values[..self.len];
+        match self.reduction {
+            Reduction::Mean => values.iter().sum::<f32>() / values.len() as f32,
+            Reduction::Median => {
+                // Insertion sort, there are only a few samples
+                for i in 1..values.len() {
+                    let mut j = i;
+                    while j > 0 && values[j - 1] > values[j] {
+                        values.swap(j - 1, j);
+                        j -= 1;
+                    }
+                }
+                let middle = values.len() / 2;
+                if values.len() % 2 == 0 {
+                    (values[middle - 1] + values[middle]) / 2.0
+                } else {
+                    values[middle]
+                }
+            }
+        }
+    }
+}
+
+/// The samples of the current measurement.
+pub struct Samples {
+    /// Number of completed sampling rounds
+    round: u8,
+    series: [Series; READINGS.len()],
+}
+
+impl Samples {
+    pub const fn new() -> Self {
+        Self {
+            round: 0,
+            series: [Series::new(); READINGS.len()],
+        }
+    }
+
+    /// Drop the samples, before a new measurement.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Number of completed sampling rounds. A sensor with `n` samples is
+    /// sampled in the first `n - 1` rounds, its last sample is collected with
+    /// the measurement.
+    pub fn round(&self) -> u8 {
+        self.round
+    }
+
+    pub fn next_round(&mut self) {
+        self.round += 1;
+    }
+
+    /// Add the oversampled readings of a sample.
+    pub fn push(&mut self, readings: &Readings, reduction: Reduction) {
+        for ((sensor_type, kind), series) in READINGS.iter().zip(self.series.iter_mut()) {
+            if let Some(value) = readings.value(*sensor_type).and_then(|value| decode(*kind, value)) {
+                series.reduction = reduction;
+                series.push(value);
+            }
+        }
+    }
+
+    /// Replace the oversampled readings of the measurement with the reduced
+    /// value of all their samples (including the reading itself).
+    pub fn reduce(&mut self, readings: &mut Readings) {
+        for ((sensor_type, kind), series) in READINGS.iter().zip(self.series.iter_mut()) {
+            if series.len == 0 {
+                continue;
+            }
+            let value = match readings.value(*sensor_type).and_then(|value| decode(*kind, value)) {
+                Some(value) => value,
+                // The last sample failed, the reading is missing
+                None => continue,
+            };
+            series.push(value);
+            let value = series.reduce();
+            match kind {
+                Kind::I32 => readings.set(*sensor_type, &round(value).to_le_bytes()),
+                Kind::F32 => readings.set(*sensor_type, &value.to_le_bytes()),
+            }
+        }
+        self.clear();
+    }
+}
+
+fn decode(kind: Kind, value: &[u8]) -> Option<f32> {
+    if value.len() != 4 {
+        return None;
+    }
+    let bytes = [value[0], value[1], value[2], value[3]];
+    Some(match kind {
+        // Millidegrees and millipercent are exact in an f32
+        Kind::I32 => i32::from_le_bytes(bytes) as f32,
+        Kind::F32 => f32::from_le_bytes(bytes),
+    })
+}
+
+/// Round to the nearest integer (`f32::round` needs `std`).
+fn round(value: f32) -> i32 {
+    if value < 0.0 {
+        (value - 0.5) as i32
+    } else {
+        (value + 0.5) as i32
+    }
+}
@@ -0,0 +1,271 @@
+//! Beacon payload serialization.
+//!
+//! The payload is sent as manufacturer specific data in a BLE advertisement
+//! frame. It starts with the company identifier and the 16 bit counter,
+//! followed by a list of measurement entries (type flag + value).
+//!
+//! Every frame starts with a frame info entry, containing the protocol
+//! version as well as the frame index and frame count. If not all entries fit
+//! into a single advertisement frame, they are split across multiple frames
+//! with the same counter.
+//!
+//! If a key has been provisioned, every frame ends with a MAC entry, which
+//! authenticates the device address and the preceding payload. With the `crc`
+//! feature, it is followed by a CRC entry, which protects the payload against
+//! bit errors.
+//!
+//! With the `protocol-v2` feature, every entry has a length byte after the
+//! type flag (type + length + value), so that receivers can skip unknown types
+//! and values may have a variable length. The frame info entry then contains
+//! version 2. The frame info entry itself has no length byte, so that the
+//! version can be read first.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::advertiser::MAX_BEACON_DATA_LEN;
+use crate::key::Key;
+
+/// Company identifier used for the manufacturer specific data.
+const COMPANY_IDENTIFIER: [u8; 2] = [0xff, 0xff];
+
+/// Maximum length of the manufacturer specific data (including company
+/// identifier) if the advertisement also contains a complete local name of
+/// length `name_len`.
+///
+/// Every AD structure has a two byte overhead (length and type).
+pub const fn max_payload_len(name_len: usize) -> usize {
+    MAX_BEACON_DATA_LEN - (2 + name_len) - 2
+}
+
+/// Size of the payload buffer. This is the capacity that remains if the
+/// advertisement frame contains no other AD structures.
+const BUFFER_LEN: usize = MAX_BEACON_DATA_LEN - 2;
+
+/// Length of company identifier and counter.
+const HEADER_LEN: usize = 4;
+
+/// Entry type of the frame info.
+const SENSOR_FRAME_INFO: u8 = 0x00;
+
+/// Length of the frame info entry.
+const FRAME_INFO_LEN: usize = 3;
+
+/// Protocol version sent in the frame info entry.
+const PROTOCOL_VERSION: u8 = if cfg!(feature = "protocol-v2") { 2 } else { 1 };
+
+/// Length of an entry header (type flag, and length since protocol version 2).
+const ENTRY_HEADER_LEN: usize = if cfg!(feature = "protocol-v2") { 2 } else { 1 };
+
+/// Entry type of the MAC.
+const SENSOR_MAC: u8 = 0x0d;
+
+/// Length of the MAC (truncated HMAC-SHA256).
+const MAC_LEN: usize = 4;
+
+/// Length of the MAC entry.
+pub const MAC_ENTRY_LEN: usize = ENTRY_HEADER_LEN + MAC_LEN;
+
+/// Entry type of the CRC.
+const SENSOR_CRC: u8 = 0x11;
+
+/// Length of the CRC entry.
+pub const CRC_ENTRY_LEN: usize = ENTRY_HEADER_LEN + 1;
+
+/// CRC-8 with the polynomial 0x07 and the initial value 0 (CRC-8/SMBUS).
+fn crc8(chunks: &[&[u8]]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Header of an entry with a value of length `len`.
+fn entry_header(sensor_type: u8, len: usize) -> [u8; ENTRY_HEADER_LEN] {
+    let mut header = [sensor_type; ENTRY_HEADER_LEN];
+    if let Some(length) = header.get_mut(1) {
+        *length = len as u8;
+    }
+    header
+}
+
+/// Calculate the number of frames needed to send all entries, if every frame
+/// may contain up to `max_len` bytes of payload.
+pub fn frame_count(entries: &[Option<Entry>], max_len: usize) -> usize {
+    let capacity = max_len.saturating_sub(HEADER_LEN + FRAME_INFO_LEN);
+    let mut frames = 0;
+    let mut remaining = 0;
+    for entry in entries.iter().flatten() {
+        let len = entry.encoded_len();
+        if len > capacity {
+            continue;
+        }
+        if len > remaining {
+            frames += 1;
+            remaining = capacity;
+        }
+        remaining -= len;
+    }
+    frames.max(1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The entry does not fit into the payload.
+    Overflow,
+}
+
+/// A measurement entry in the payload.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub sensor_type: u8,
+    pub value: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(sensor_type: u8, value: &'a [u8]) -> Self {
+        Self { sensor_type, value }
+    }
+
+    /// Size of the encoded entry in bytes.
+    pub fn encoded_len(&self) -> usize {
+        ENTRY_HEADER_LEN + self.value.len()
+    }
+}
+
+/// Writer that appends measurement entries to a fixed size payload buffer.
+pub struct PayloadWriter {
+    buf: [u8; BUFFER_LEN],
+    len: usize,
+    max_len: usize,
+}
+
+impl PayloadWriter {
+    /// Create a new payload writer with the specified maximum length.
+    ///
+    /// The company identifier and the counter are written immediately.
+    pub fn new(max_len: usize, counter: u16) -> Self {
+        let mut writer = Self {
+            buf: [0; BUFFER_LEN],
+            len: 0,
+            max_len: core::cmp::min(max_len, BUFFER_LEN),
+        };
+        writer
+            .write_bytes(&COMPANY_IDENTIFIER)
+            .expect("Payload too small for company identifier");
+        writer
+            .write_bytes(&counter.to_le_bytes())
+            .expect("Payload too small for counter");
+        writer
+    }
+
+    /// Number of bytes that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.max_len - self.len
+    }
+
+    /// Append an entry. If it does not fit, the payload is left unmodified
+    /// and `Error::Overflow` is returned.
+    pub fn write(&mut self, entry: &Entry) -> Result<(), Error> {
+        if entry.encoded_len() > self.remaining() {
+            return Err(Error::Overflow);
+        }
+        self.write_bytes(&entry_header(entry.sensor_type, entry.value.len()))?;
+        self.write_bytes(entry.value)
+    }
+
+    /// Append the frame info entry (the first entry of every frame). It has
+    /// no length byte in any protocol version.
+    pub fn write_frame_info(&mut self, index: u8, count: u8) -> Result<(), Error> {
+        self.write_bytes(&[
+            SENSOR_FRAME_INFO,
+            PROTOCOL_VERSION,
+            (index << 4) | (count & 0x0f),
+        ])
+    }
+
+    /// Append entries in order, starting at index `start`, until an entry
+    /// does not fit. Entries that are too large to ever fit into a frame are
+    /// skipped.
+    ///
+    /// Return the index of the first entry that was not written.
+    pub fn write_entries(&mut self, entries: &[Option<Entry>], start: usize) -> usize {
+        let capacity = self.max_len.saturating_sub(HEADER_LEN + FRAME_INFO_LEN);
+        let mut i = start;
+        while i < entries.len() {
+            if let Some(ref entry) = entries[i] {
+                if self.write(entry).is_err() && entry.encoded_len() <= capacity {
+                    break;
+                }
+            }
+            i += 1;
+        }
+        i
+    }
+
+    /// Reserve space for the MAC entry at the end of the payload. Must be
+    /// called before writing any entries.
+    pub fn reserve_mac(&mut self) {
+        self.max_len -= MAC_ENTRY_LEN;
+    }
+
+    /// Append the MAC entry (the space must have been reserved with
+    /// `reserve_mac`). The MAC is the HMAC-SHA256 (truncated to 4 bytes) of
+    /// the device address from the FICR (most significant byte first, also
+    /// with private addresses), the payload after the company identifier,
+    /// and the MAC entry header.
+    pub fn write_mac(&mut self, key: &Key, address: &[u8; 6]) {
+        self.max_len += MAC_ENTRY_LEN;
+        let mut address = *address;
+        address.reverse();
+        let header = entry_header(SENSOR_MAC, MAC_LEN);
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("Invalid key length");
+        hmac.update(&address);
+        hmac.update(&self.buf[COMPANY_IDENTIFIER.len()..self.len]);
+        hmac.update(&header);
+        let tag = hmac.finalize().into_bytes();
+        self.write_bytes(&header)
+            .and_then(|_| self.write_bytes(&tag[..MAC_LEN]))
+            .expect("No space reserved for MAC");
+    }
+
+    /// Reserve space for the CRC entry at the end of the payload (after the
+    /// MAC). Must be called before writing any entries.
+    pub fn reserve_crc(&mut self) {
+        self.max_len -= CRC_ENTRY_LEN;
+    }
+
+    /// Append the CRC entry (the space must have been reserved with
+    /// `reserve_crc`). The CRC-8 covers the payload after the company
+    /// identifier (including the MAC entry) and the CRC entry header.
+    pub fn write_crc(&mut self) {
+        self.max_len += CRC_ENTRY_LEN;
+        let header = entry_header(SENSOR_CRC, 1);
+        let crc = crc8(&[&self.buf[COMPANY_IDENTIFIER.len()..self.len], &header]);
+        self.write_bytes(&header)
+            .and_then(|_| self.write_bytes(&[crc]))
+            .expect("No space reserved for CRC");
+    }
+
+    /// Return the payload bytes written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if bytes.len() > self.remaining() {
+            return Err(Error::Overflow);
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Power management.
+//!
+//! The external high frequency crystal oscillator (HFXO) is only needed while
+//! the radio is active. Between beacon bursts, it is stopped, so that the chip
+//! only consumes the System ON sleep current (with the RTC running from the
+//! LFCLK). With the `gatt` feature, the link layer uses the radio at any
+//! time, so the HFXO keeps running.
+
+use crate::hal::pac;
+
+fn clock() -> &'static pac::clock::RegisterBlock {
+    unsafe { &*pac::CLOCK::ptr() }
+}
+
+/// Start the HFXO and wait until it is running (takes about 360 µs).
+pub fn hfxo_start() {
+    let clock = clock();
+    let status = clock.hfclkstat.read();
+    if status.src().is_xtal() && status.state().is_running() {
+        return;
+    }
+    clock.events_hfclkstarted.write(|w| unsafe { w.bits(0) });
+    clock.tasks_hfclkstart.write(|w| unsafe { w.bits(1) });
+    while clock.events_hfclkstarted.read().bits() == 0 {}
+}
+
+/// Stop the HFXO. The HF clock falls back to the internal oscillator, which is
+/// only running while it is requested (e.g. by the CPU).
+pub fn hfxo_stop() {
+    #[cfg(not(feature = "gatt"))]
+    clock().tasks_hfclkstop.write(|w| unsafe { w.bits(1) });
+}
+
+/// Init-time power configuration (with the `low-power` feature):
+///
+/// - Enable the DC/DC regulator (requires the external LC filter, which is
+///   present on most nRF52 modules).
+/// - Disable peripherals that are not used, in case they were left enabled
+///   (e.g. by a bootloader). TWIM0 (which shares its instance with SPIM0) is
+///   used for the sensors. With the `i2c1` feature, TWIM1 (sharing its
+///   instance with SPIM1) is enabled again when the second bus is set up,
+///   and SPIM2 with the `max31855` feature. The peripherals that the nRF52810
+///   does not have are skipped there.
+/// - Keep all RAM sections powered in System ON (the whole RAM is used for
+///   the stack and the panic dump), but disable their retention in System
+///   OFF, which is never entered.
+#[cfg(feature = "low-power")]
+pub fn configure(power: &pac::POWER) {
+    power.dcdcen.write(|w| w.dcdcen().enabled());
+
+    unsafe {
+        (*pac::UARTE0::ptr()).enable.write(|w| w.bits(0));
+        #[cfg(not(feature = "nrf52810"))]
+        (*pac::SPIM1::ptr()).enable.write(|w| w.bits(0));
+        #[cfg(not(feature = "nrf52810"))]
+        (*pac::SPIM2::ptr()).enable.write(|w| w.bits(0));
+        (*pac::SAADC::ptr()).enable.write(|w| w.bits(0));
+        (*pac::PWM0::ptr()).enable.write(|w| w.bits(0));
+        #[cfg(not(feature = "nrf52810"))]
+        (*pac::PWM1::ptr()).enable.write(|w| w.bits(0));
+        #[cfg(not(feature = "nrf52810"))]
+        (*pac::PWM2::ptr()).enable.write(|w| w.bits(0));
+        #[cfg(not(feature = "nrf52810"))]
+        (*pac::I2S::ptr()).enable.write(|w| w.bits(0));
+        (*pac::QDEC::ptr()).enable.write(|w| w.bits(0));
+        (*pac::COMP::ptr()).enable.write(|w| w.bits(0));
+        #[cfg(not(feature = "nrf52810"))]
+        (*pac::LPCOMP::ptr()).enable.write(|w| w.bits(0));
+    }
+
+    let sections = [
+        &power.ram0,
+        &power.ram1,
+        &power.ram2,
+        &power.ram3,
+        &power.ram4,
+        &power.ram5,
+        &power.ram6,
+        &power.ram7,
+    ];
+    for ram in sections.iter() {
+        ram.power.write(|w| {
+            w.s0power()
+                .on()
+                .s1power()
+                .on()
+                .s0retention()
+                .off()
+                .s1retention()
+                .off()
+        });
+    }
+}
@@ -0,0 +1,68 @@
+//! Power profiling instrumentation.
+//!
+//! With the `power-profiling` feature, spare GPIOs are set high during the
+//! phases of a measurement cycle, so that the current consumption recorded by
+//! a power profiler (e.g. the Nordic PPK2 with its digital inputs) can be
+//! correlated with the activity of the firmware:
+//!
+//! | Pin   | Phase                        |
+//! |-------|------------------------------|
+//! | P0.11 | Sensor bus transfers         |
+//! | P0.12 | Radio TX (beacon broadcast)  |
+//! | P0.13 | Sleep (`WFI` in idle)        |
+//!
+//! Without the feature, all functions are no-ops.
+
+#[cfg(feature = "power-profiling")]
+use crate::hal::pac;
+
+/// A phase of the measurement cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Sensors,
+    RadioTx,
+    Sleep,
+}
+
+impl Phase {
+    #[cfg(feature = "power-profiling")]
+    fn pin(self) -> u32 {
+        match self {
+            Phase::Sensors => 11,
+            Phase::RadioTx => 12,
+            Phase::Sleep => 13,
+        }
+    }
+}
+
+/// Configure the profiling pins as outputs (low).
+#[inline(always)]
+pub fn init() {
+    #[cfg(feature = "power-profiling")]
+    {
+        let p0 = unsafe { &*pac::P0::ptr() };
+        for phase in &[Phase::Sensors, Phase::RadioTx, Phase::Sleep] {
+            let pin = phase.pin();
+            p0.outclr.write(|w| unsafe { w.bits(1 << pin) });
+            p0.pin_cnf[pin as usize].write(|w| w.dir().output().input().disconnect());
+        }
+    }
+}
+
+/// Mark the start of a phase.
+#[inline(always)]
+pub fn enter(_phase: Phase) {
+    #[cfg(feature = "power-profiling")]
+    unsafe { &*pac::P0::ptr() }
+        .outset
+        .write(|w| unsafe { w.bits(1 << _phase.pin()) });
+}
+
+/// Mark the end of a phase.
+#[inline(always)]
+pub fn exit(_phase: Phase) {
+    #[cfg(feature = "power-profiling")]
+    unsafe { &*pac::P0::ptr() }
+        .outclr
+        .write(|w| unsafe { w.bits(1 << _phase.pin()) });
+}
@@ -0,0 +1,141 @@
+//! Pulse counter (e.g. a reed switch rain gauge or an S0 power meter output).
+//!
+//! The pulses are counted in hardware, without waking up the CPU: A falling
+//! edge on the input pin generates a GPIOTE event, which is routed through
+//! PPI to the COUNT task of TIMER2 (in counter mode).
+//!
+//! Debouncing is done in hardware as well: The first edge also disables the
+//! counting PPI channel (through a channel group) and starts TIMER3. When
+//! TIMER3 reaches the debounce time, it stops and enables counting again. All
+//! edges within the debounce time (contact bounce) are ignored.
+//!
+//! Note: GPIOTE IN events need the HF clock, which increases the sleep
+//! current.
+
+use crate::console::rprintln;
+use crate::hal::{
+    gpio::{Input, Pin, PullUp},
+    pac,
+};
+use crate::sensors::{self, Readings, Sensor, SENSOR_PULSES};
+
+/// Edges within this time after a counted pulse are ignored.
+const DEBOUNCE_MS: u32 = 10;
+
+/// Frequency of the debounce timer (16 MHz / 2^8).
+const DEBOUNCE_TIMER_PRESCALER: u32 = 8;
+const DEBOUNCE_TIMER_HZ: u32 = 16_000_000 >> DEBOUNCE_TIMER_PRESCALER;
+
+// Resources used for the counter
+const GPIOTE_CHANNEL: usize = 0;
+const PPI_CHANNEL_COUNT: usize = 0;
+const PPI_CHANNEL_DEBOUNCE_START: usize = 1;
+const PPI_CHANNEL_DEBOUNCE_END: usize = 2;
+const PPI_GROUP: usize = 0;
+
+pub struct PulseCounter {
+    counter: pac::TIMER2,
+    _debounce: pac::TIMER3,
+    _pin: Pin<Input<PullUp>>,
+    previous: u32,
+}
+
+impl PulseCounter {
+    pub fn new(
+        pin: Pin<Input<PullUp>>,
+        gpiote: &pac::GPIOTE,
+        ppi: &pac::PPI,
+        counter: pac::TIMER2,
+        debounce: pac::TIMER3,
+    ) -> Self {
+        // Generate an event on falling edges
+        gpiote.config[GPIOTE_CHANNEL].write(|w| unsafe {
+            w.mode().event().psel().bits(pin.pin()).polarity().hi_to_lo()
+        });
+
+        // Count the events
+        counter.mode.write(|w| w.mode().low_power_counter());
+        counter.bitmode.write(|w| w.bitmode()._32bit());
+        counter.tasks_clear.write(|w| unsafe { w.bits(1) });
+        counter.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        // One-shot debounce timer
+        debounce.mode.write(|w| w.mode().timer());
+        debounce.bitmode.write(|w| w.bitmode()._16bit());
+        debounce
+            .prescaler
+            .write(|w| unsafe { w.prescaler().bits(DEBOUNCE_TIMER_PRESCALER as u8) });
+        debounce.cc[0].write(|w| unsafe { w.bits(DEBOUNCE_MS * DEBOUNCE_TIMER_HZ / 1000) });
+        debounce
+            .shorts
+            .write(|w| w.compare0_clear().enabled().compare0_stop().enabled());
+        debounce.tasks_clear.write(|w| unsafe { w.bits(1) });
+
+        // Wire everything up
+        let event_in = &gpiote.events_in[GPIOTE_CHANNEL] as *const _ as u32;
+        let channel = |index: usize, event: u32, task: u32| {
+            ppi.ch[index].eep.write(|w| unsafe { w.bits(event) });
+            ppi.ch[index].tep.write(|w| unsafe { w.bits(task) });
+        };
+        channel(
+            PPI_CHANNEL_COUNT,
+            event_in,
+            &counter.tasks_count as *const _ as u32,
+        );
+        ppi.fork[PPI_CHANNEL_COUNT]
+            .tep
+            .write(|w| unsafe { w.bits(&ppi.tasks_chg[PPI_GROUP].dis as *const _ as u32) });
+        channel(
+            PPI_CHANNEL_DEBOUNCE_START,
+            event_in,
+            &debounce.tasks_start as *const _ as u32,
+        );
+        channel(
+            PPI_CHANNEL_DEBOUNCE_END,
+            &debounce.events_compare[0] as *const _ as u32,
+            &ppi.tasks_chg[PPI_GROUP].en as *const _ as u32,
+        );
+        ppi.chg[PPI_GROUP].write(|w| unsafe { w.bits(1 << PPI_CHANNEL_COUNT) });
+        ppi.chenset.write(|w| unsafe {
+            w.bits(
+                1 << PPI_CHANNEL_COUNT
+                    | 1 << PPI_CHANNEL_DEBOUNCE_START
+                    | 1 << PPI_CHANNEL_DEBOUNCE_END,
+            )
+        });
+
+        Self {
+            counter,
+            _debounce: debounce,
+            _pin: pin,
+            previous: 0,
+        }
+    }
+
+    /// Number of pulses since startup (wraps around).
+    pub fn count(&mut self) -> u32 {
+        self.counter.tasks_capture[0].write(|w| unsafe { w.bits(1) });
+        self.counter.cc[0].read().bits()
+    }
+}
+
+impl Sensor for PulseCounter {
+    fn start(&mut self) -> Result<u32, sensors::Error> {
+        // Pulses are counted continuously
+        Ok(0)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), sensors::Error> {
+        let count = self.count();
+        let delta = count.wrapping_sub(self.previous).min(u32::from(u16::MAX)) as u16;
+        self.previous = count;
+        rprintln!("Pulse counter: {} pulses (+{})", count, delta);
+        let (count, delta) = (count.to_le_bytes(), delta.to_le_bytes());
+        // Count (u32 LE) + delta since the last measurement (u16 LE)
+        readings.push(
+            SENSOR_PULSES,
+            &[count[0], count[1], count[2], count[3], delta[0], delta[1]],
+        );
+        Ok(())
+    }
+}
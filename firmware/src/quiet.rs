@@ -0,0 +1,88 @@
+//! Quiet hours.
+//!
+//! During the quiet hours (e.g. at night in a bedroom, to reduce RF), the node
+//! lengthens its measurement interval or stops broadcasting altogether. The
+//! schedule is configured per node through the UICR register `CUSTOMER[13]`:
+//!
+//! - Bits 0-7: Start hour (0-23)
+//! - Bits 8-15: End hour (0-23, the quiet hours may span midnight)
+//! - Bits 16-23: Interval factor during the quiet hours (2-60), or 0 to stop
+//!   broadcasting
+//! - Bits 24-31: Must be `0x51` (marks the register as configured)
+//!
+//! The register is erased (`0xffffffff`) by default. Invalid values are
+//! ignored. The quiet hours only apply while the node knows the time of day
+//! (see the `clock` module).
+
+use crate::clock::SECONDS_PER_DAY;
+use crate::console::rprintln;
+use crate::hal::pac;
+
+/// Marker in the highest byte of the register.
+const MAGIC: u32 = 0x51;
+
+/// Upper bound of the interval factor (3 minutes at the default interval).
+const MAX_FACTOR: u8 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuietMode {
+    /// No measurements and no beacons
+    Stop,
+    /// Multiply the measurement interval by the factor
+    Lengthen(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start_hour: u8,
+    pub end_hour: u8,
+    pub mode: QuietMode,
+}
+
+impl QuietHours {
+    /// Decode the value of the UICR register.
+    fn decode(value: u32) -> Option<Self> {
+        if value >> 24 != MAGIC {
+            return None;
+        }
+        let start_hour = value as u8;
+        let end_hour = (value >> 8) as u8;
+        let mode = match (value >> 16) as u8 {
+            0 => QuietMode::Stop,
+            factor if (2..=MAX_FACTOR).contains(&factor) => QuietMode::Lengthen(factor),
+            _ => return None,
+        };
+        if start_hour > 23 || end_hour > 23 || start_hour == end_hour {
+            return None;
+        }
+        Some(QuietHours {
+            start_hour,
+            end_hour,
+            mode,
+        })
+    }
+
+    /// Read the schedule from the UICR, if configured and valid.
+    pub fn from_uicr() -> Option<Self> {
+        let value = unsafe { &*pac::UICR::ptr() }.customer[13].read().bits();
+        if value == 0xffff_ffff {
+            return None;
+        }
+        let quiet_hours = Self::decode(value);
+        if quiet_hours.is_none() {
+            rprintln!("Warning: Invalid quiet hours configuration {:#010x}", value);
+        }
+        quiet_hours
+    }
+
+    /// Whether the time of day (seconds since midnight) is within the quiet
+    /// hours.
+    pub fn contains(&self, second_of_day: u32) -> bool {
+        let hour = (second_of_day % SECONDS_PER_DAY / 3600) as u8;
+        if self.start_hour < self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! [`Advertiser`] implementation that drives the RADIO peripheral directly.
+//!
+//! The beacons are `ADV_NONCONN_IND` PDUs on the LE 1M PHY (Core spec Vol 6,
+//! Part B, 2.3). The radio adds the preamble, access address, whitening and
+//! CRC. With the `time-sync` feature, the time beacons of the gateway are
+//! received on the advertising channels as well.
+//!
+//! With the `ext-adv` feature, the Sensilo beacons are extended advertisements
+//! instead (Core spec Vol 6, Part B, 2.3.4): An `ADV_EXT_IND` on every primary
+//! advertising channel points to a single `AUX_ADV_IND` on a secondary channel
+//! (one of the data channels 0-36), which carries the advertisement data. The
+//! AuxPtr of an `ADV_EXT_IND` contains the time from its start until the start
+//! of the `AUX_ADV_IND`, so the transmissions are started by TIMER0 (through
+//! PPI) at fixed times, not by the CPU. The compatibility frames stay legacy
+//! advertisements.
+
+#[cfg(feature = "ext-adv")]
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+#[cfg(feature = "ext-adv")]
+use crate::advertiser::{
+    AD_TYPE_COMPLETE_LOCAL_NAME, AD_TYPE_MANUFACTURER_DATA, MAX_EXTENDED_ADVERTISEMENT_DATA_LEN,
+};
+use crate::advertiser::{Advertiser, DataTooLong, DeviceAddress, MAX_ADVERTISEMENT_DATA_LEN};
+use crate::hal::pac;
+#[cfg(feature = "time-sync")]
+use crate::monotonic_nrf52::{Duration, Instant};
+
+/// Access address of the advertising channels.
+const ACCESS_ADDRESS: u32 = 0x8e89_bed6;
+
+/// CRC polynomial (x^24 + x^10 + x^9 + x^6 + x^4 + x^3 + x + 1) and initial
+/// value of the advertising channels.
+const CRC_POLY: u32 = 0x0000_065b;
+const CRC_INIT: u32 = 0x0055_5555;
+
+/// PDU type of a non-connectable undirected advertisement.
+const ADV_NONCONN_IND: u8 = 0x02;
+
+/// TxAdd flag in the PDU header (random advertiser address).
+const TX_ADD: u8 = 1 << 6;
+
+/// PDU type of an extended advertisement (`ADV_EXT_IND` on the primary
+/// channels, `AUX_ADV_IND` on the secondary channels).
+#[cfg(feature = "ext-adv")]
+const ADV_EXT_IND: u8 = 0x07;
+
+// Fields of the extended header
+#[cfg(feature = "ext-adv")]
+const EXT_HEADER_ADV_A: u8 = 1 << 0;
+#[cfg(feature = "ext-adv")]
+const EXT_HEADER_ADI: u8 = 1 << 3;
+#[cfg(feature = "ext-adv")]
+const EXT_HEADER_AUX_PTR: u8 = 1 << 4;
+
+/// Extended header of an `ADV_EXT_IND`: Flags, ADI and AuxPtr.
+#[cfg(feature = "ext-adv")]
+const EXT_IND_HEADER_LEN: usize = 1 + 2 + 3;
+
+/// A complete `ADV_EXT_IND`: PDU header, extended header length and
+/// extended header (no advertisement data).
+#[cfg(feature = "ext-adv")]
+const EXT_IND_LEN: usize = 2 + 1 + EXT_IND_HEADER_LEN;
+
+/// Extended header of an `AUX_ADV_IND`: Flags, advertiser address and ADI.
+#[cfg(feature = "ext-adv")]
+const AUX_HEADER_LEN: usize = 1 + 6 + 2;
+
+/// Clock accuracy flag of the AuxPtr (0-50 ppm, the radio runs on the HFXO).
+#[cfg(feature = "ext-adv")]
+const AUX_PTR_CA: u32 = 1 << 6;
+
+/// Unit of the AuxPtr offset (with the Offset Units bit cleared).
+#[cfg(feature = "ext-adv")]
+const OFFSET_UNIT_US: u32 = 30;
+
+/// Timer value at which the first `ADV_EXT_IND` is triggered.
+#[cfg(feature = "ext-adv")]
+const FIRST_TXEN_US: u32 = 10;
+
+/// Time between the starts of two `ADV_EXT_IND`s: The radio ramp-up (140 µs)
+/// and the PDU (136 µs), plus time for the CPU to prepare the next one.
+#[cfg(feature = "ext-adv")]
+const PRIMARY_SPACING_US: u32 = 14 * OFFSET_UNIT_US;
+
+/// Time between the starts of the last `ADV_EXT_IND` and the `AUX_ADV_IND`:
+/// The PDU, plus the minimum gap between PDUs on different channels (T_MAFS,
+/// 300 µs).
+#[cfg(feature = "ext-adv")]
+const AUX_SPACING_US: u32 = 16 * OFFSET_UNIT_US;
+
+/// Secondary channels of consecutive broadcasts are this far apart (coprime
+/// to 37, so that all data channels are used in turns).
+#[cfg(feature = "ext-adv")]
+const AUX_CHANNEL_HOP: u8 = 7;
+
+/// PPI channel that starts a transmission at the compare event of TIMER0
+/// (channels 0-2 are used by the pulse counter).
+#[cfg(feature = "ext-adv")]
+const PPI_CHANNEL_TXEN: usize = 3;
+
+/// Data ID of the next extended advertisement. It changes with the data, so
+/// that scanners don't drop new data as duplicates.
+#[cfg(feature = "ext-adv")]
+static NEXT_DID: AtomicU16 = AtomicU16::new(0);
+
+/// PDU header (2 bytes), advertiser address (6 bytes) and advertisement data,
+/// or with the `ext-adv` feature the longest `AUX_ADV_IND`.
+#[cfg(not(feature = "ext-adv"))]
+const PDU_LEN: usize = 2 + 6 + MAX_ADVERTISEMENT_DATA_LEN;
+#[cfg(feature = "ext-adv")]
+const PDU_LEN: usize = 2 + 1 + AUX_HEADER_LEN + MAX_EXTENDED_ADVERTISEMENT_DATA_LEN;
+
+/// Advertising channels and their frequencies (offset from 2400 MHz).
+const CHANNELS: [(u8, u8); 3] = [(37, 2), (38, 26), (39, 80)];
+
+/// Frequency of a data channel (offset from 2400 MHz), between the
+/// advertising channels.
+#[cfg(feature = "ext-adv")]
+fn data_channel_frequency(channel: u8) -> u8 {
+    match channel {
+        0..=10 => 4 + 2 * channel,
+        _ => 6 + 2 * channel,
+    }
+}
+
+/// Write AD structures (type and data) into `buf`.
+fn write_structures(buf: &mut [u8], structures: &[(u8, &[u8])]) {
+    let mut i = 0;
+    for &(ty, data) in structures {
+        buf[i] = 1 + data.len() as u8;
+        buf[i + 1] = ty;
+        buf[i + 2..i + 2 + data.len()].copy_from_slice(data);
+        i += 2 + data.len();
+    }
+}
+
+/// A complete advertising PDU.
+pub struct Pdu {
+    bytes: [u8; PDU_LEN],
+    /// An `AUX_ADV_IND`, which is announced by `ADV_EXT_IND`s
+    #[cfg(feature = "ext-adv")]
+    extended: bool,
+}
+
+pub struct RawAdvertiser {
+    radio: pac::RADIO,
+    #[cfg(feature = "ext-adv")]
+    timer: pac::TIMER0,
+    /// Secondary channel of the next extended advertisement
+    #[cfg(feature = "ext-adv")]
+    aux_channel: u8,
+}
+
+/// Set up the radio for the advertising channels.
+fn configure(radio: &pac::RADIO) {
+    radio.mode.write(|w| w.mode().ble_1mbit());
+    // 0 dBm
+    radio.txpower.write(|w| unsafe { w.bits(0) });
+
+    // 8 bit length field, 1 byte S0 (the first header byte), no S1
+    radio
+        .pcnf0
+        .write(|w| unsafe { w.lflen().bits(8).s0len().bit(true).s1len().bits(0) });
+    // 3 byte base address (+ 1 byte prefix), little endian, whitening
+    radio.pcnf1.write(|w| unsafe {
+        w.maxlen()
+            .bits((PDU_LEN - 2) as u8)
+            .statlen()
+            .bits(0)
+            .balen()
+            .bits(3)
+            .endian()
+            .little()
+            .whiteen()
+            .enabled()
+    });
+    radio.base0.write(|w| unsafe { w.bits(ACCESS_ADDRESS << 8) });
+    radio
+        .prefix0
+        .write(|w| unsafe { w.ap0().bits((ACCESS_ADDRESS >> 24) as u8) });
+    radio.txaddress.write(|w| unsafe { w.txaddress().bits(0) });
+    radio.rxaddresses.write(|w| w.addr0().enabled());
+
+    // 3 byte CRC, not covering the access address
+    radio.crccnf.write(|w| w.len().three().skipaddr().skip());
+    radio.crcpoly.write(|w| unsafe { w.crcpoly().bits(CRC_POLY) });
+    radio.crcinit.write(|w| unsafe { w.crcinit().bits(CRC_INIT) });
+
+    // Start sending (or receiving) as soon as the radio is ready, disable
+    // it at the end
+    radio
+        .shorts
+        .write(|w| w.ready_start().enabled().end_disable().enabled());
+}
+
+impl RawAdvertiser {
+    #[cfg(not(feature = "ext-adv"))]
+    pub fn new(radio: pac::RADIO, _ficr: &pac::FICR) -> Self {
+        configure(&radio);
+        Self { radio }
+    }
+
+    /// With the `ext-adv` feature, TIMER0 and a PPI channel time the
+    /// transmissions of the extended advertisements.
+    #[cfg(feature = "ext-adv")]
+    pub fn with_extended(
+        radio: pac::RADIO,
+        _ficr: &pac::FICR,
+        timer: pac::TIMER0,
+        ppi: &pac::PPI,
+    ) -> Self {
+        configure(&radio);
+
+        // 1 MHz, started for every broadcast
+        timer.mode.write(|w| w.mode().timer());
+        timer.bitmode.write(|w| w.bitmode()._32bit());
+        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+
+        // Enable the transmitter at the compare event
+        ppi.ch[PPI_CHANNEL_TXEN]
+            .eep
+            .write(|w| unsafe { w.bits(&timer.events_compare[0] as *const _ as u32) });
+        ppi.ch[PPI_CHANNEL_TXEN]
+            .tep
+            .write(|w| unsafe { w.bits(&radio.tasks_txen as *const _ as u32) });
+        ppi.chenset
+            .write(|w| unsafe { w.bits(1 << PPI_CHANNEL_TXEN) });
+
+        Self {
+            radio,
+            timer,
+            aux_channel: 0,
+        }
+    }
+
+    /// Listen on advertising channel 37 until `accept` returns true for a
+    /// received PDU (header, advertiser address and data), or until the
+    /// timeout has passed. Return whether a PDU was accepted. The HFXO must
+    /// be running.
+    #[cfg(feature = "time-sync")]
+    pub fn listen(&mut self, timeout: Duration, mut accept: impl FnMut(&[u8]) -> bool) -> bool {
+        let radio = &self.radio;
+        let mut buffer = [0; PDU_LEN];
+        radio
+            .packetptr
+            .write(|w| unsafe { w.bits(buffer.as_mut_ptr() as u32) });
+        let (channel, frequency) = CHANNELS[0];
+        radio
+            .frequency
+            .write(|w| unsafe { w.frequency().bits(frequency) });
+        radio
+            .datawhiteiv
+            .write(|w| unsafe { w.datawhiteiv().bits(channel) });
+
+        let deadline = Instant::now() + timeout;
+        let mut accepted = false;
+        while !accepted && Instant::now() < deadline {
+            radio.events_disabled.write(|w| unsafe { w.bits(0) });
+            compiler_fence(Ordering::Release);
+            radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+            // The radio is disabled after a packet has been received
+            let mut timed_out = false;
+            while radio.events_disabled.read().bits() == 0 {
+                if !timed_out && Instant::now() >= deadline {
+                    radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+                    timed_out = true;
+                }
+            }
+            compiler_fence(Ordering::Acquire);
+            if !timed_out && radio.crcstatus.read().crcstatus().is_crcok() {
+                accepted = accept(&buffer);
+            }
+        }
+        radio.events_disabled.write(|w| unsafe { w.bits(0) });
+        accepted
+    }
+}
+
+#[cfg(feature = "ext-adv")]
+impl RawAdvertiser {
+    /// Broadcast an `AUX_ADV_IND`, announced by an `ADV_EXT_IND` on every
+    /// primary advertising channel. TIMER0 enables the transmitter at fixed
+    /// times, the radio starts sending after its (constant) ramp-up.
+    fn broadcast_extended(&mut self, aux: &Pdu) {
+        let aux_channel = self.aux_channel;
+        self.aux_channel = (aux_channel + AUX_CHANNEL_HOP) % 37;
+        let aux_txen = FIRST_TXEN_US + 2 * PRIMARY_SPACING_US + AUX_SPACING_US;
+        let adi = [aux.bytes[10], aux.bytes[11]];
+
+        self.timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+        for (i, &(channel, frequency)) in CHANNELS.iter().enumerate() {
+            let txen = FIRST_TXEN_US + i as u32 * PRIMARY_SPACING_US;
+            // The offset is the same from the start of the PDU, both are
+            // delayed by the ramp-up
+            let offset = (aux_txen - txen) / OFFSET_UNIT_US;
+            let aux_ptr = (u32::from(aux_channel) | AUX_PTR_CA | (offset << 8)).to_le_bytes();
+            let ext_ind: [u8; EXT_IND_LEN] = [
+                ADV_EXT_IND,
+                (1 + EXT_IND_HEADER_LEN) as u8,
+                // AdvMode 0: Non-connectable and non-scannable
+                EXT_IND_HEADER_LEN as u8,
+                EXT_HEADER_ADI | EXT_HEADER_AUX_PTR,
+                adi[0],
+                adi[1],
+                aux_ptr[0],
+                aux_ptr[1],
+                aux_ptr[2],
+            ];
+            self.transmit_at(txen, channel, frequency, &ext_ind);
+        }
+        self.transmit_at(
+            aux_txen,
+            aux_channel,
+            data_channel_frequency(aux_channel),
+            &aux.bytes,
+        );
+        self.timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.radio.events_disabled.write(|w| unsafe { w.bits(0) });
+    }
+
+    /// Send a PDU when the timer reaches `txen` (in µs), and wait until it
+    /// has been sent. The timer is started by the first transmission.
+    fn transmit_at(&self, txen: u32, channel: u8, frequency: u8, pdu: &[u8]) {
+        let radio = &self.radio;
+        radio
+            .packetptr
+            .write(|w| unsafe { w.bits(pdu.as_ptr() as u32) });
+        radio
+            .frequency
+            .write(|w| unsafe { w.frequency().bits(frequency) });
+        radio
+            .datawhiteiv
+            .write(|w| unsafe { w.datawhiteiv().bits(channel) });
+        radio.events_disabled.write(|w| unsafe { w.bits(0) });
+        self.timer.cc[0].write(|w| unsafe { w.bits(txen) });
+
+        // The PDU must be written before the DMA reads it
+        compiler_fence(Ordering::Release);
+        self.timer.tasks_start.write(|w| unsafe { w.bits(1) });
+        while radio.events_disabled.read().bits() == 0 {}
+        compiler_fence(Ordering::Acquire);
+    }
+}
+
+impl Advertiser for RawAdvertiser {
+    type Frame = Pdu;
+
+    /// With the `ext-adv` feature, the Sensilo beacons are `AUX_ADV_IND`s
+    /// with up to `MAX_EXTENDED_ADVERTISEMENT_DATA_LEN` bytes of data.
+    #[cfg(feature = "ext-adv")]
+    fn frame(address: DeviceAddress, name: &str, manufacturer_data: &[u8]) -> Result<Pdu, DataTooLong> {
+        let structures = [
+            (AD_TYPE_COMPLETE_LOCAL_NAME, name.as_bytes()),
+            (AD_TYPE_MANUFACTURER_DATA, manufacturer_data),
+        ];
+        let data_len: usize = structures.iter().map(|(_, data)| 2 + data.len()).sum();
+        if data_len > MAX_EXTENDED_ADVERTISEMENT_DATA_LEN {
+            return Err(DataTooLong);
+        }
+
+        let mut bytes = [0; PDU_LEN];
+        bytes[0] = ADV_EXT_IND | if address.is_random() { TX_ADD } else { 0 };
+        bytes[1] = (1 + AUX_HEADER_LEN + data_len) as u8;
+        // AdvMode 0: Non-connectable and non-scannable
+        bytes[2] = AUX_HEADER_LEN as u8;
+        bytes[3] = EXT_HEADER_ADV_A | EXT_HEADER_ADI;
+        bytes[4..10].copy_from_slice(address.raw());
+        // ADI: Data ID (12 bits) and advertising set ID 0
+        let did = NEXT_DID.fetch_add(1, Ordering::Relaxed) & 0x0fff;
+        bytes[10..12].copy_from_slice(&did.to_le_bytes());
+        write_structures(&mut bytes[12..], &structures);
+        Ok(Pdu {
+            bytes,
+            extended: true,
+        })
+    }
+
+    fn raw_frame(address: DeviceAddress, structures: &[(u8, &[u8])]) -> Result<Pdu, DataTooLong> {
+        let data_len: usize = structures.iter().map(|(_, data)| 2 + data.len()).sum();
+        if data_len > MAX_ADVERTISEMENT_DATA_LEN {
+            return Err(DataTooLong);
+        }
+
+        let mut bytes = [0; PDU_LEN];
+        bytes[0] = ADV_NONCONN_IND | if address.is_random() { TX_ADD } else { 0 };
+        bytes[1] = (6 + data_len) as u8;
+        bytes[2..8].copy_from_slice(address.raw());
+        write_structures(&mut bytes[8..], structures);
+        Ok(Pdu {
+            bytes,
+            #[cfg(feature = "ext-adv")]
+            extended: false,
+        })
+    }
+
+    fn broadcast(&mut self, frame: &Pdu) {
+        #[cfg(feature = "ext-adv")]
+        if frame.extended {
+            self.broadcast_extended(frame);
+            return;
+        }
+
+        let radio = &self.radio;
+        radio
+            .packetptr
+            .write(|w| unsafe { w.bits(frame.bytes.as_ptr() as u32) });
+        for &(channel, frequency) in CHANNELS.iter() {
+            radio
+                .frequency
+                .write(|w| unsafe { w.frequency().bits(frequency) });
+            radio
+                .datawhiteiv
+                .write(|w| unsafe { w.datawhiteiv().bits(channel) });
+
+            // The PDU must be written before the DMA reads it
+            compiler_fence(Ordering::Release);
+            radio.events_disabled.write(|w| unsafe { w.bits(0) });
+            radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+            while radio.events_disabled.read().bits() == 0 {}
+            compiler_fence(Ordering::Acquire);
+        }
+        radio.events_disabled.write(|w| unsafe { w.bits(0) });
+    }
+}
@@ -0,0 +1,122 @@
+//! Resolvable private addresses (with the `private-address` feature).
+//!
+//! Instead of the fixed device address from the FICR, the beacons are sent
+//! from a resolvable private address (Bluetooth Core Specification, Vol 6,
+//! Part B, 1.3.2.2), which changes regularly. Passive observers can't link
+//! the addresses, only the gateway can resolve them with the identity
+//! resolving key (IRK) of the node.
+//!
+//! An address consists of 24 random bits (`prand`, the two most significant
+//! bits are `0b01`) and the 24 bit hash `ah(IRK, prand)`, which is calculated
+//! with the ECB (AES-128) peripheral.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use crate::advertiser::DeviceAddress;
+use crate::entropy::Entropy;
+use crate::hal::pac;
+use crate::key::Key;
+
+/// The address is changed every 15 minutes (the interval recommended by the
+/// specification).
+pub const ROTATION_INTERVAL_MS: u32 = 15 * 60 * 1000;
+
+/// Memory layout expected by the ECB peripheral.
+#[repr(C)]
+struct EcbData {
+    key: [u8; 16],
+    cleartext: [u8; 16],
+    ciphertext: [u8; 16],
+}
+
+/// Encrypt a single block with AES-128. Like in the specification, the
+/// most significant byte comes first.
+fn aes128(key: &Key, cleartext: &[u8; 16]) -> [u8; 16] {
+    let ecb = unsafe { &*pac::ECB::ptr() };
+    let mut data = EcbData {
+        key: *key,
+        cleartext: *cleartext,
+        ciphertext: [0; 16],
+    };
+    ecb.ecbdataptr
+        .write(|w| unsafe { w.bits(&mut data as *mut EcbData as u32) });
+    compiler_fence(Ordering::SeqCst);
+    loop {
+        ecb.events_endecb.write(|w| unsafe { w.bits(0) });
+        ecb.events_errorecb.write(|w| unsafe { w.bits(0) });
+        ecb.tasks_startecb.write(|w| unsafe { w.bits(1) });
+        // The encryption is aborted if the AES core is needed by the CCM or
+        // AAR peripheral, in that case it is simply restarted.
+        loop {
+            if ecb.events_endecb.read().bits() != 0 {
+                compiler_fence(Ordering::SeqCst);
+                return data.ciphertext;
+            }
+            if ecb.events_errorecb.read().bits() != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// The random address hash function `ah` (Vol 3, Part H, 2.2.2).
+fn ah(irk: &Key, prand: u32) -> u32 {
+    let mut cleartext = [0; 16];
+    cleartext[13..].copy_from_slice(&prand.to_be_bytes()[1..]);
+    let ciphertext = aes128(irk, &cleartext);
+    u32::from_be_bytes([0, ciphertext[13], ciphertext[14], ciphertext[15]])
+}
+
+/// Generate a new resolvable private address.
+fn generate(irk: &Key, entropy: &mut Entropy) -> DeviceAddress {
+    // The random part must not be all zeros or all ones
+    let random = loop {
+        let random = entropy.next_u32() & 0x3f_ffff;
+        if random != 0 && random != 0x3f_ffff {
+            break random;
+        }
+    };
+    let prand = random | 0x40_0000;
+    let hash = ah(irk, prand);
+
+    // The address is sent least significant byte first: hash, then prand
+    let mut bytes = [0; 6];
+    bytes[..3].copy_from_slice(&hash.to_le_bytes()[..3]);
+    bytes[3..].copy_from_slice(&prand.to_le_bytes()[..3]);
+    DeviceAddress::random(bytes)
+}
+
+pub struct PrivateAddress {
+    irk: Key,
+    address: DeviceAddress,
+    /// Number of measurement cycles after which the address is changed
+    rotation_cycles: u32,
+    cycles: u32,
+}
+
+impl PrivateAddress {
+    /// Generate the first address. A new address is generated every
+    /// `rotation_cycles` measurement cycles.
+    pub fn new(irk: Key, rotation_cycles: u32, entropy: &mut Entropy) -> Self {
+        Self {
+            address: generate(&irk, entropy),
+            irk,
+            rotation_cycles,
+            cycles: 0,
+        }
+    }
+
+    pub fn current(&self) -> DeviceAddress {
+        self.address
+    }
+
+    /// Return the address for the beacons of the next measurement cycle.
+    pub fn next(&mut self, entropy: &mut Entropy) -> DeviceAddress {
+        self.cycles += 1;
+        if self.cycles >= self.rotation_cycles {
+            self.cycles = 0;
+            self.address = generate(&self.irk, entropy);
+        }
+        self.address
+    }
+}
@@ -0,0 +1,156 @@
+//! [`Advertiser`] implementation on top of the rubble BLE stack.
+//!
+//! With the `gatt` feature, the rubble link layer also advertises connectably
+//! and handles the connections of the GATT server (see the `gatt` module). It
+//! is driven by the RADIO and TIMER0 interrupts. The beacons are broadcast
+//! in between, and skipped while a client is connected.
+
+use rubble::{
+    beacon::Beacon,
+    link::{ad_structure::AdStructure, AddressKind, MIN_PDU_BUF},
+};
+#[cfg(feature = "gatt")]
+use rubble::{
+    l2cap::{BleChannelMap, L2CAPState},
+    link::{
+        ad_structure::ServiceUuids,
+        queue::{PacketQueue, SimpleQueue},
+        LinkLayer, Responder,
+    },
+    time::{Duration, Timer},
+    uuid::Uuid16,
+};
+
+use crate::advertiser::{Advertiser, DataTooLong, DeviceAddress};
+#[cfg(feature = "gatt")]
+use crate::gatt::{self, EssAttrs, GattConfig, GattResponder};
+use crate::hal::pac;
+#[cfg(feature = "gatt")]
+use crate::rubble_radio::BleTimer;
+use crate::rubble_radio::{BleRadio, PacketBuffer};
+
+pub struct RubbleAdvertiser {
+    radio: BleRadio,
+    #[cfg(feature = "gatt")]
+    link_layer: LinkLayer<GattConfig>,
+}
+
+fn rubble_address(address: DeviceAddress) -> rubble::link::DeviceAddress {
+    let kind = if address.is_random() {
+        AddressKind::Random
+    } else {
+        AddressKind::Public
+    };
+    rubble::link::DeviceAddress::new(*address.raw(), kind)
+}
+
+/// An AD structure with the type and data.
+fn ad<'a>(&(ty, data): &(u8, &'a [u8])) -> AdStructure<'a> {
+    AdStructure::Unknown { ty, data }
+}
+
+impl RubbleAdvertiser {
+    /// Can only be created once (the packet buffers are static).
+    #[cfg(not(feature = "gatt"))]
+    pub fn new(radio: pac::RADIO, ficr: &pac::FICR) -> Self {
+        let tx_buf = cortex_m::singleton!(: PacketBuffer = [0; MIN_PDU_BUF]).unwrap();
+        let rx_buf = cortex_m::singleton!(: PacketBuffer = [0; MIN_PDU_BUF]).unwrap();
+        Self {
+            radio: BleRadio::new(radio, ficr, tx_buf, rx_buf),
+        }
+    }
+
+    /// Create the advertiser and start advertising connectably with the name
+    /// and the service UUID of the GATT server. Return the responder, which
+    /// must be run when the link layer has queued work. Can only be called
+    /// once (the buffers are static).
+    #[cfg(feature = "gatt")]
+    pub fn with_gatt(
+        radio: pac::RADIO,
+        timer: pac::TIMER0,
+        ficr: &pac::FICR,
+        address: DeviceAddress,
+        name: &str,
+    ) -> (Self, GattResponder) {
+        let tx_buf = cortex_m::singleton!(: PacketBuffer = [0; MIN_PDU_BUF]).unwrap();
+        let rx_buf = cortex_m::singleton!(: PacketBuffer = [0; MIN_PDU_BUF]).unwrap();
+        let tx_queue = cortex_m::singleton!(: SimpleQueue = SimpleQueue::new()).unwrap();
+        let rx_queue = cortex_m::singleton!(: SimpleQueue = SimpleQueue::new()).unwrap();
+        let (tx, tx_cons) = tx_queue.split();
+        let (rx_prod, rx) = rx_queue.split();
+
+        let mut radio = BleRadio::new(radio, ficr, tx_buf, rx_buf);
+        let mut link_layer = LinkLayer::<GattConfig>::new(rubble_address(address), BleTimer::init(timer));
+        let responder = Responder::new(
+            tx,
+            rx,
+            L2CAPState::new(BleChannelMap::with_attributes(EssAttrs::new())),
+        );
+        let next_update = link_layer
+            .start_advertise(
+                Duration::from_millis(gatt::ADVERTISING_INTERVAL_MS),
+                &[
+                    AdStructure::CompleteLocalName(name),
+                    AdStructure::ServiceUuids16(ServiceUuids::from_uuids(true, &[Uuid16(gatt::ESS_UUID)])),
+                ],
+                &mut radio,
+                tx_cons,
+                rx_prod,
+            )
+            .expect("Could not start connectable advertising");
+        link_layer.timer().configure_interrupt(next_update);
+        (Self { radio, link_layer }, responder)
+    }
+
+    /// Handle a RADIO interrupt. Return whether the responder has work.
+    #[cfg(feature = "gatt")]
+    pub fn on_radio_interrupt(&mut self) -> bool {
+        let now = self.link_layer.timer().now();
+        match self.radio.recv_interrupt(now, &mut self.link_layer) {
+            Some(cmd) => {
+                self.radio.configure_receiver(cmd.radio);
+                self.link_layer.timer().configure_interrupt(cmd.next_update);
+                cmd.queued_work
+            }
+            None => false,
+        }
+    }
+
+    /// Handle a TIMER0 interrupt. Return whether the responder has work.
+    #[cfg(feature = "gatt")]
+    pub fn on_timer_interrupt(&mut self) -> bool {
+        let timer = self.link_layer.timer();
+        if !timer.is_interrupt_pending() {
+            return false;
+        }
+        timer.clear_interrupt();
+        let cmd = self.link_layer.update_timer(&mut self.radio);
+        self.radio.configure_receiver(cmd.radio);
+        self.link_layer.timer().configure_interrupt(cmd.next_update);
+        cmd.queued_work
+    }
+}
+
+impl Advertiser for RubbleAdvertiser {
+    type Frame = Beacon;
+
+    fn raw_frame(address: DeviceAddress, structures: &[(u8, &[u8])]) -> Result<Beacon, DataTooLong> {
+        let address = rubble_address(address);
+        let result = match structures {
+            [a] => Beacon::new(address, &[ad(a)]),
+            [a, b] => Beacon::new(address, &[ad(a), ad(b)]),
+            [a, b, c] => Beacon::new(address, &[ad(a), ad(b), ad(c)]),
+            _ => return Err(DataTooLong),
+        };
+        result.map_err(|_| DataTooLong)
+    }
+
+    fn broadcast(&mut self, frame: &Beacon) {
+        // The radio belongs to the connection
+        #[cfg(feature = "gatt")]
+        if self.link_layer.is_connected() {
+            return;
+        }
+        frame.broadcast(&mut self.radio);
+    }
+}
@@ -0,0 +1,341 @@
+//! rubble [`Transmitter`] and [`Timer`] for the RADIO and TIMER0 peripherals.
+//!
+//! Ported from rubble-nrf5x 0.0.4 (0BSD), which is only released for
+//! nrf-hal 0.12. Its PAC can't be linked next to the one of nrf-hal 0.14
+//! (both define the `DEVICE_PERIPHERALS` symbol). Only the nRF52 parts are
+//! kept.
+//!
+//! The radio adds the preamble, access address, whitening and CRC of the
+//! packets. In RAM, a packet is the S0 byte (the first byte of the PDU
+//! header), the length byte and the payload, the remaining two bits of the
+//! header (S1) are zero in all PDUs that rubble sends.
+
+use core::cmp;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use rubble::config::Config;
+use rubble::link::{advertising, data, Cmd, LinkLayer, NextUpdate, RadioCmd, Transmitter, CRC_POLY, MIN_PDU_BUF};
+use rubble::phy::{AdvertisingChannel, DataChannel};
+use rubble::time::{Duration, Instant, Timer};
+
+use crate::hal::pac::{self, radio::state::STATE_A};
+
+/// A packet buffer that can hold the header and the payload of any
+/// advertising or data channel packet.
+pub type PacketBuffer = [u8; MIN_PDU_BUF];
+
+/// The RADIO in BLE mode (LE 1M PHY).
+pub struct BleRadio {
+    /// Whether the radio is on an advertising channel (else a data channel).
+    advertising: bool,
+    radio: pac::RADIO,
+    tx_buf: &'static mut PacketBuffer,
+    /// Taken while the link layer processes a received packet (which needs
+    /// the radio as well).
+    rx_buf: Option<&'static mut PacketBuffer>,
+}
+
+impl BleRadio {
+    /// Put the radio into BLE mode. The FICR is unused (the nRF52 needs no
+    /// trim values), it's taken for the interface of rubble-nrf5x.
+    pub fn new(
+        radio: pac::RADIO,
+        _ficr: &pac::FICR,
+        tx_buf: &'static mut PacketBuffer,
+        rx_buf: &'static mut PacketBuffer,
+    ) -> Self {
+        assert!(radio.state.read().state().is_disabled());
+
+        radio.mode.write(|w| w.mode().ble_1mbit());
+        radio.txpower.write(|w| w.txpower().pos4d_bm());
+
+        let max_payload = rx_buf.len() - 2;
+        assert!(max_payload <= usize::from(u8::MAX));
+
+        unsafe {
+            radio.pcnf1.write(|w| {
+                // 3 byte base address and 1 byte prefix, whitening over the
+                // PDU and the CRC
+                w.maxlen().bits(max_payload as u8).balen().bits(3).whiteen().set_bit()
+            });
+            // The CRC (CRC24) covers the PDU only
+            radio.crccnf.write(|w| w.skipaddr().skip().len().three());
+            radio.crcpoly.write(|w| w.crcpoly().bits(CRC_POLY & 0x00ff_ffff));
+
+            // Logical address 0 is the access address of the advertising
+            // channels. BASE0 transmits its upper 24 bits as the lower 24
+            // bits of the access address, the prefix is the top byte.
+            radio.base0.write(|w| w.bits(advertising::ACCESS_ADDRESS << 8));
+            radio.prefix0.write(|w| w.ap0().bits((advertising::ACCESS_ADDRESS >> 24) as u8));
+        }
+
+        // Start sending or receiving after the ramp-up, disable the radio
+        // when done
+        radio.shorts.write(|w| w.ready_start().enabled().end_disable().enabled());
+
+        Self {
+            advertising: false,
+            radio,
+            tx_buf,
+            rx_buf: Some(rx_buf),
+        }
+    }
+
+    fn state(&self) -> STATE_A {
+        self.radio.state.read().state().variant().unwrap_or(STATE_A::DISABLED)
+    }
+
+    /// Configure the radio for (not) receiving according to `cmd`.
+    pub fn configure_receiver(&mut self, cmd: RadioCmd) {
+        // Wait for the end of an ongoing transmission (unless the last
+        // connection event was lost, then nothing is sent anyway)
+        if let RadioCmd::ListenData { timeout, .. } = cmd {
+            if !timeout {
+                while matches!(self.state(), STATE_A::TX | STATE_A::TXRU) {}
+            }
+        }
+        compiler_fence(Ordering::Acquire);
+
+        // Stop the reception
+        self.radio.intenclr.write(|w| w.disabled().clear());
+        self.radio.events_disabled.reset();
+        self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_disabled.read().bits() == 0 {}
+        self.radio.events_disabled.reset();
+
+        match cmd {
+            RadioCmd::Off => {}
+            RadioCmd::ListenAdvertising { channel } => {
+                self.prepare_txrx_advertising(channel);
+
+                let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
+                self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
+                // Interrupt when a packet has been received, on logical
+                // address 0
+                self.radio.intenset.write(|w| w.disabled().set());
+                self.radio.rxaddresses.write(|w| w.addr0().enabled());
+                // A connection changes the shortcuts
+                self.radio.shorts.write(|w| w.ready_start().enabled().end_disable().enabled());
+
+                compiler_fence(Ordering::Release);
+                self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+            }
+            RadioCmd::ListenData {
+                channel,
+                access_address,
+                crc_init,
+                ..
+            } => {
+                self.prepare_txrx_data(channel, access_address, crc_init);
+
+                // T_IFS in hardware
+                self.radio.tifs.write(|w| unsafe { w.bits(Duration::T_IFS.as_micros()) });
+
+                let rx_buf = (*self.rx_buf.as_mut().unwrap()) as *mut _ as u32;
+                self.radio.packetptr.write(|w| unsafe { w.bits(rx_buf) });
+                // Interrupt when a packet has been received, on logical
+                // address 1
+                self.radio.intenset.write(|w| w.disabled().set());
+                self.radio.rxaddresses.write(|w| w.addr1().enabled());
+
+                compiler_fence(Ordering::Release);
+                self.radio.tasks_rxen.write(|w| unsafe { w.bits(1) });
+
+                // Switch to TX after the reception, for the response (T_IFS)
+                self.radio.shorts.write(|w| {
+                    w.end_disable()
+                        .enabled()
+                        .disabled_txen()
+                        .enabled()
+                        .ready_start()
+                        .enabled()
+                });
+            }
+        }
+    }
+
+    /// Handle a RADIO interrupt. Return the command of the link layer, if a
+    /// packet has been received.
+    pub fn recv_interrupt<C: Config<Transmitter = Self>>(
+        &mut self,
+        timestamp: Instant,
+        ll: &mut LinkLayer<C>,
+    ) -> Option<Cmd> {
+        if self.radio.events_disabled.read().bits() == 0 {
+            return None;
+        }
+        compiler_fence(Ordering::Acquire);
+        self.radio.events_disabled.reset();
+
+        let crc_ok = self.radio.crcstatus.read().crcstatus().is_crcok();
+        let rx_buf = self.rx_buf.take().unwrap();
+        let cmd = if self.advertising {
+            assert!(self.state() == STATE_A::DISABLED);
+            let header = advertising::Header::parse(rx_buf);
+            let end = cmp::min(2 + usize::from(header.payload_length()), rx_buf.len());
+            ll.process_adv_packet(timestamp, self, header, &rx_buf[2..end], crc_ok)
+        } else {
+            // The ready-start shortcut must be off before TXREADY (~150 µs)
+            self.radio.shorts.modify(|_, w| w.ready_start().disabled());
+            assert!(self.state() != STATE_A::TX);
+            let header = data::Header::parse(rx_buf);
+            let end = cmp::min(2 + usize::from(header.payload_length()), rx_buf.len());
+            ll.process_data_packet(timestamp, self, header, &rx_buf[2..end], crc_ok)
+        };
+        self.rx_buf = Some(rx_buf);
+        Some(cmd)
+    }
+
+    /// Disable the radio and set the packet layout, the whitening and CRC
+    /// initial values and the frequency of an advertising channel.
+    fn prepare_txrx_advertising(&mut self, channel: AdvertisingChannel) {
+        self.advertising = true;
+
+        self.radio.events_disabled.reset();
+        if self.state() != STATE_A::DISABLED {
+            self.radio.tasks_disable.write(|w| unsafe { w.bits(1) });
+            while self.radio.events_disabled.read().bits() == 0 {}
+        }
+        assert!(self.state() == STATE_A::DISABLED);
+
+        unsafe {
+            self.radio.pcnf0.write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
+            self.radio.datawhiteiv.write(|w| w.datawhiteiv().bits(channel.whitening_iv()));
+            self.radio.crcinit.write(|w| w.crcinit().bits(advertising::CRC_PRESET));
+            self.radio.frequency.write(|w| w.frequency().bits((channel.freq() - 2400) as u8));
+        }
+    }
+
+    /// Set the packet layout, the whitening and CRC initial values, the
+    /// frequency and the access address (logical address 1) of a data
+    /// channel.
+    fn prepare_txrx_data(&mut self, channel: DataChannel, access_address: u32, crc_init: u32) {
+        self.advertising = false;
+
+        unsafe {
+            self.radio.pcnf0.write(|w| w.s0len().bit(true).lflen().bits(8).s1len().bits(0));
+            self.radio.datawhiteiv.write(|w| w.datawhiteiv().bits(channel.whitening_iv()));
+            self.radio.crcinit.write(|w| w.crcinit().bits(crc_init & 0x00ff_ffff));
+            self.radio.frequency.write(|w| w.frequency().bits((channel.freq() - 2400) as u8));
+            self.radio.base1.write(|w| w.bits(access_address << 8));
+            self.radio.prefix0.write(|w| w.ap1().bits((access_address >> 24) as u8));
+        }
+    }
+
+    /// Send the packet in the TX buffer and wait until it has been sent.
+    fn transmit(&mut self) {
+        assert!(self.state() == STATE_A::DISABLED);
+
+        // The pointer must be set before every start
+        self.radio.packetptr.write(|w| unsafe { w.bits(self.tx_buf as *const _ as u32) });
+        self.radio.events_disabled.reset();
+
+        compiler_fence(Ordering::Release);
+        self.radio.tasks_txen.write(|w| unsafe { w.bits(1) });
+        while self.radio.events_disabled.read().bits() == 0 {}
+        compiler_fence(Ordering::Acquire);
+    }
+}
+
+impl Transmitter for BleRadio {
+    fn tx_payload_buf(&mut self) -> &mut [u8] {
+        while self.state() == STATE_A::TX {}
+        compiler_fence(Ordering::Acquire);
+
+        // Leave 2 bytes for the PDU header
+        &mut self.tx_buf[2..]
+    }
+
+    fn transmit_advertising(&mut self, header: advertising::Header, channel: AdvertisingChannel) {
+        // S0 and the length (6 bits, followed by 2 zero bits)
+        self.tx_buf[0] = header.to_u16() as u8;
+        self.tx_buf[1] = header.payload_length();
+
+        self.prepare_txrx_advertising(channel);
+        self.radio.txaddress.write(|w| unsafe { w.txaddress().bits(0) });
+        self.transmit();
+    }
+
+    fn transmit_data(&mut self, _access_address: u32, _crc_iv: u32, header: data::Header, _channel: DataChannel) {
+        // S0 and the length. The radio is already configured, the
+        // transmission is started by the disabled-txen shortcut after the
+        // reception (see `configure_receiver`).
+        self.tx_buf[0] = header.to_u16() as u8;
+        self.tx_buf[1] = header.payload_length();
+
+        self.radio.txaddress.write(|w| unsafe { w.txaddress().bits(1) });
+        self.radio.packetptr.write(|w| unsafe { w.bits(self.tx_buf as *const _ as u32) });
+
+        compiler_fence(Ordering::Release);
+        self.radio.shorts.write(|w| w.ready_start().enabled().end_disable().disabled());
+    }
+}
+
+/// TIMER0 as the timer of the rubble link layer, counting microseconds. CC[0]
+/// captures the counter, CC[1] is the next update of the link layer.
+pub struct BleTimer {
+    timer: pac::TIMER0,
+    next: Instant,
+    interrupt_enabled: bool,
+}
+
+impl BleTimer {
+    pub fn init(timer: pac::TIMER0) -> Self {
+        timer.bitmode.write(|w| w.bitmode()._32bit());
+        // 16 MHz / 2^4 = 1 MHz
+        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+        timer.tasks_start.write(|w| unsafe { w.bits(1) });
+        Self {
+            timer,
+            next: Instant::from_raw_micros(0),
+            interrupt_enabled: false,
+        }
+    }
+
+    /// Configure the interrupt for the next update of the link layer.
+    pub fn configure_interrupt(&mut self, next: NextUpdate) {
+        match next {
+            NextUpdate::Keep => {
+                // Setting the interrupt again would reset the event (race)
+                if !self.interrupt_enabled {
+                    self.set_interrupt(self.next);
+                }
+            }
+            NextUpdate::Disable => {
+                self.clear_interrupt();
+            }
+            NextUpdate::At(instant) => {
+                self.set_interrupt(instant);
+            }
+        }
+    }
+
+    fn set_interrupt(&mut self, at: Instant) {
+        self.next = at;
+        self.timer.cc[1].write(|w| unsafe { w.bits(at.raw_micros()) });
+        self.timer.events_compare[1].reset();
+        self.timer.intenset.write(|w| w.compare1().set());
+        self.interrupt_enabled = true;
+    }
+
+    /// Whether the interrupt is due (the TIMER0 interrupt handler must check
+    /// this first, to ignore spurious interrupts).
+    pub fn is_interrupt_pending(&self) -> bool {
+        self.timer.events_compare[1].read().bits() == 1
+    }
+
+    /// Acknowledge the interrupt and disable further interrupts.
+    pub fn clear_interrupt(&mut self) {
+        self.timer.intenclr.write(|w| w.compare1().clear());
+        self.timer.events_compare[1].reset();
+        self.interrupt_enabled = false;
+    }
+}
+
+impl Timer for BleTimer {
+    fn now(&self) -> Instant {
+        self.timer.tasks_capture[0].write(|w| unsafe { w.bits(1) });
+        Instant::from_raw_micros(self.timer.cc[0].read().bits())
+    }
+}
@@ -0,0 +1,302 @@
+//! Sensor abstraction.
+//!
+//! Every sensor implements the [`Sensor`] trait, independently of the bus it
+//! is attached to (I²C, SPI, …). A measurement cycle first starts a
+//! measurement on all sensors, waits for the longest measurement duration and
+//! then collects the results into a list of [`Readings`], which are sent as
+//! payload entries. With the `oversampling` feature, some sensors are started
+//! and collected several times per cycle (see the `oversampling` module).
+
+use shared_bus_rtic::SharedBus;
+use shtcx::ShtC3;
+use veml6030::Veml6030;
+
+use crate::{board::AnyTwim, console::rprintln, payload::Entry, telemetry};
+
+// Sensor types
+pub const SENSOR_TEMP: u8 = 0x01;
+pub const SENSOR_HUMI: u8 = 0x02;
+pub const SENSOR_LUX: u8 = 0x04;
+#[cfg(feature = "veml-raw")]
+pub const SENSOR_LUX_RAW_ALS: u8 = 0x05;
+#[cfg(feature = "veml-raw")]
+pub const SENSOR_LUX_RAW_WHITE: u8 = 0x06;
+#[cfg(feature = "max31855")]
+pub const SENSOR_THERMOCOUPLE: u8 = 0x07;
+#[cfg(feature = "ds18b20")]
+pub const SENSOR_EXT_TEMP: u8 = 0x08;
+#[cfg(feature = "pulse-counter")]
+pub const SENSOR_PULSES: u8 = 0x09;
+#[cfg(feature = "analog")]
+pub const SENSOR_ANALOG: u8 = 0x0a;
+pub const SENSOR_CONTACT: u8 = 0x0b;
+pub const SENSOR_STATUS: u8 = 0x0c;
+pub const SENSOR_TELEMETRY: u8 = 0x0e;
+#[cfg(feature = "backlog")]
+pub const SENSOR_BACKLOG: u8 = 0x0f;
+#[cfg(feature = "delta")]
+pub const SENSOR_DELTA: u8 = 0x10;
+#[cfg(feature = "diagnostics")]
+pub const SENSOR_TIMING: u8 = 0x12;
+#[cfg(feature = "dual-sensor")]
+pub const SENSOR_SECONDARY_TEMP: u8 = 0x13;
+#[cfg(feature = "dual-sensor")]
+pub const SENSOR_SECONDARY_HUMI: u8 = 0x14;
+
+// Flags of the status entry
+pub const STATUS_BUZZER: u8 = 1 << 0;
+#[cfg(feature = "heater")]
+pub const STATUS_HEATED: u8 = 1 << 1;
+#[cfg(feature = "dual-sensor")]
+pub const STATUS_DISAGREEMENT: u8 = 1 << 2;
+
+/// Maximum number of readings per measurement cycle.
+pub const MAX_READINGS: usize = 16;
+
+/// Maximum size of a reading value in bytes.
+const MAX_VALUE_LEN: usize = 6;
+
+/// A sensor operation failed. The details are logged by the sensor.
+#[derive(Debug)]
+pub struct Error;
+
+pub trait Sensor {
+    /// Start a measurement. Return the time in µs until the result can be
+    /// collected.
+    fn start(&mut self) -> Result<u32, Error>;
+
+    /// Collect the result of the started measurement.
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    sensor_type: u8,
+    value: [u8; MAX_VALUE_LEN],
+    len: usize,
+}
+
+/// The readings collected during a measurement cycle.
+pub struct Readings {
+    readings: [Option<Reading>; MAX_READINGS],
+    len: usize,
+}
+
+impl Readings {
+    pub fn new() -> Self {
+        Self {
+            readings: [None; MAX_READINGS],
+            len: 0,
+        }
+    }
+
+    /// Add a reading. The value must not be larger than 6 bytes.
+    pub fn push(&mut self, sensor_type: u8, value: &[u8]) {
+        if self.len >= MAX_READINGS {
+            rprintln!("Warning: Too many readings, dropping type {:#04x}", sensor_type);
+            return;
+        }
+        let mut reading = Reading {
+            sensor_type,
+            value: [0; MAX_VALUE_LEN],
+            len: value.len(),
+        };
+        reading.value[..value.len()].copy_from_slice(value);
+        self.readings[self.len] = Some(reading);
+        self.len += 1;
+    }
+
+    /// The value of the first reading of a sensor type, if any.
+    #[cfg_attr(
+        not(any(
+            feature = "backlog",
+            feature = "gatt",
+            feature = "eddystone-tlm",
+            feature = "delta",
+            feature = "oversampling",
+            feature = "dual-sensor"
+        )),
+        allow(dead_code)
+    )]
+    pub fn value(&self, sensor_type: u8) -> Option<&[u8]> {
+        self.readings
+            .iter()
+            .flatten()
+            .find(|reading| reading.sensor_type == sensor_type)
+            .map(|reading| &reading.value[..reading.len])
+    }
+
+    /// The value of the first reading of a sensor type as i32 (e.g. the
+    /// temperature or humidity), if any.
+    #[cfg_attr(
+        not(any(
+            feature = "backlog",
+            feature = "gatt",
+            feature = "eddystone-tlm",
+            feature = "delta",
+            feature = "dual-sensor"
+        )),
+        allow(dead_code)
+    )]
+    pub fn i32_value(&self, sensor_type: u8) -> Option<i32> {
+        let value = self.value(sensor_type)?;
+        Some(i32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    /// Replace the value of the first reading of a sensor type (with a value
+    /// of the same size).
+    #[cfg_attr(not(feature = "oversampling"), allow(dead_code))]
+    pub fn set(&mut self, sensor_type: u8, value: &[u8]) {
+        let reading = self
+            .readings
+            .iter_mut()
+            .flatten()
+            .find(|reading| reading.sensor_type == sensor_type);
+        if let Some(reading) = reading {
+            reading.value[..value.len()].copy_from_slice(value);
+            reading.len = value.len();
+        }
+    }
+
+    /// Remove the readings of a sensor type.
+    #[cfg_attr(not(feature = "delta"), allow(dead_code))]
+    pub fn remove(&mut self, sensor_type: u8) {
+        let mut len = 0;
+        for i in 0..self.len {
+            let reading = self.readings[i].take();
+            if matches!(reading, Some(reading) if reading.sensor_type != sensor_type) {
+                self.readings[len] = reading;
+                len += 1;
+            }
+        }
+        self.len = len;
+    }
+
+    /// Return the readings as payload entries.
+    pub fn entries(&self) -> [Option<Entry>; MAX_READINGS] {
+        let mut entries = [None; MAX_READINGS];
+        for (entry, reading) in entries.iter_mut().zip(self.readings.iter()) {
+            *entry = reading
+                .as_ref()
+                .map(|reading| Entry::new(reading.sensor_type, &reading.value[..reading.len]));
+        }
+        entries
+    }
+}
+
+/// Whether the temperature or the humidity of the two sensors of a node with
+/// redundant sensors differ by more than the thresholds in `src/board.rs`
+/// (one of them has probably drifted). Nothing can be compared if one of the
+/// sensors failed.
+#[cfg(feature = "dual-sensor")]
+pub fn sensors_disagree(readings: &Readings) -> bool {
+    let differs = |primary, secondary, threshold: i32| {
+        match (readings.i32_value(primary), readings.i32_value(secondary)) {
+            (Some(a), Some(b)) => (a - b).abs() > threshold,
+            _ => false,
+        }
+    };
+    let temp = differs(
+        SENSOR_TEMP,
+        SENSOR_SECONDARY_TEMP,
+        crate::board::SHT_DISAGREEMENT_TEMP,
+    );
+    let humi = differs(
+        SENSOR_HUMI,
+        SENSOR_SECONDARY_HUMI,
+        crate::board::SHT_DISAGREEMENT_HUMI,
+    );
+    if temp || humi {
+        rprintln!("Warning: The temperature and humidity sensors disagree");
+    }
+    temp || humi
+}
+
+/// VEML sensor integration time
+pub const VEML_INTEGRATION_TIME: veml6030::IntegrationTime = veml6030::IntegrationTime::Ms25;
+
+/// VEML7700 measurement duration: After enabling the sensor, a startup time of
+/// 4 ms plus the integration time must be awaited.
+pub fn veml_measurement_duration_us() -> u32 {
+    VEML_INTEGRATION_TIME.as_us() + 4_000
+}
+
+impl Sensor for ShtC3<SharedBus<AnyTwim>> {
+    fn start(&mut self) -> Result<u32, Error> {
+        let power_mode = shtcx::PowerMode::NormalMode;
+        self.start_measurement(power_mode).map_err(|e| {
+            rprintln!("SHTC3: Could not start measurement: {:?}", e);
+            telemetry::i2c_error();
+            Error
+        })?;
+        Ok(shtcx::max_measurement_duration(self, power_mode) as u32)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), Error> {
+        let measurement = self.get_measurement_result().map_err(|e| {
+            rprintln!("SHTC3: Could not read measurement: {:?}", e);
+            telemetry::i2c_error();
+            Error
+        })?;
+        rprintln!(
+            "SHTC3 measurement: {}°C / {} %RH",
+            measurement.temperature.as_degrees_celsius(),
+            measurement.humidity.as_percent()
+        );
+        let temp = measurement.temperature.as_millidegrees_celsius();
+        let humi = measurement.humidity.as_millipercent();
+        readings.push(SENSOR_TEMP, &temp.to_le_bytes()); // i32 LE
+        readings.push(SENSOR_HUMI, &humi.to_le_bytes()); // i32 LE
+        Ok(())
+    }
+}
+
+impl Sensor for Veml6030<SharedBus<AnyTwim>> {
+    fn start(&mut self) -> Result<u32, Error> {
+        self.enable().map_err(|e| {
+            rprintln!("VEML7700: Could not enable sensor: {:?}", e);
+            telemetry::i2c_error();
+            Error
+        })?;
+        Ok(veml_measurement_duration_us())
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        match self.read_lux() {
+            Ok(lux) => {
+                rprintln!("VEML7700 measurement: {:.1} lx", lux);
+                readings.push(SENSOR_LUX, &lux.to_le_bytes()); // f32 LE
+            }
+            Err(e) => {
+                rprintln!("VEML7700: Could not measure lux: {:?}", e);
+                telemetry::i2c_error();
+                result = Err(Error);
+            }
+        }
+
+        // Collect raw channel counts
+        #[cfg(feature = "veml-raw")]
+        match (self.read_raw(), self.read_white()) {
+            (Ok(als), Ok(white)) => {
+                rprintln!("VEML7700 raw counts: ALS {} / WHITE {}", als, white);
+                readings.push(SENSOR_LUX_RAW_ALS, &als.to_le_bytes()); // u16 LE
+                readings.push(SENSOR_LUX_RAW_WHITE, &white.to_le_bytes()); // u16 LE
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                rprintln!("VEML7700: Could not read raw counts: {:?}", e);
+                telemetry::i2c_error();
+                result = Err(Error);
+            }
+        }
+
+        if let Err(e) = self.disable() {
+            rprintln!("VEML7700: Could not shut down: {:?}", e);
+            telemetry::i2c_error();
+            result = Err(Error);
+        }
+
+        result
+    }
+}
@@ -0,0 +1,210 @@
+//! Driver for the SHT4x temperature and humidity sensors (I²C, address 0x44).
+//!
+//! With the `sht4x` feature, an SHT40/41/45 replaces the SHTC3. It sends the
+//! same readings, but unlike the SHTC3 it has a heater: With the `heater`
+//! feature, a heater pulse is triggered every `HEATER_INTERVAL` measurements
+//! (see `src/board.rs`) after the readings were collected. It evaporates
+//! condensed water (e.g. in a bathroom or a greenhouse), which otherwise
+//! makes the sensor report 100 %RH for hours. The readings of the following
+//! `HEATER_COOLDOWN` measurements are still affected (too warm and too dry),
+//! and are flagged in the status entry.
+//!
+//! With the `dual-sensor` feature, a second SHT4x (at `SECONDARY_SHT_ADDRESS`,
+//! see `src/board.rs`) is read in addition to the primary sensor, and sends
+//! its readings as secondary temperature and humidity entries.
+//!
+//! Every measurement returns 6 bytes: The raw temperature and humidity (u16
+//! BE), each followed by a CRC-8 (polynomial 0x31, initial value 0xff).
+
+use cortex_m::asm::delay;
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use crate::console::rprintln;
+use crate::sensors::{self, Readings, Sensor, SENSOR_HUMI, SENSOR_TEMP};
+#[cfg(feature = "dual-sensor")]
+use crate::sensors::{SENSOR_SECONDARY_HUMI, SENSOR_SECONDARY_TEMP};
+use crate::telemetry;
+
+/// Address of the SHT40/41/45-AD1B. The -BD1B variants are at 0x45.
+const ADDRESS: u8 = 0x44;
+
+// Commands
+const CMD_MEASURE_HIGH_PRECISION: u8 = 0xfd;
+const CMD_READ_SERIAL: u8 = 0x89;
+
+/// Max duration of a high precision measurement (8.3 ms).
+pub const MEASUREMENT_DURATION_US: u32 = 8_300;
+
+/// A heater pulse: The heater power and duration. The sensor measures at the
+/// end of the pulse, and does not respond on the bus during the pulse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Heater {
+    Power200mW1s = 0x39,
+    Power200mW100ms = 0x32,
+    Power110mW1s = 0x2f,
+    Power110mW100ms = 0x24,
+    Power20mW1s = 0x1e,
+    Power20mW100ms = 0x15,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    Crc,
+}
+
+/// Sensirion CRC-8 of a 16 bit word.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xff;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Check the CRCs of a response and return its two words.
+fn decode(buf: &[u8; 6]) -> Option<(u16, u16)> {
+    if crc8(&buf[0..2]) != buf[2] || crc8(&buf[3..5]) != buf[5] {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([buf[0], buf[1]]),
+        u16::from_be_bytes([buf[3], buf[4]]),
+    ))
+}
+
+/// Temperature in m°C: -45 °C + 175 °C * raw / 65535
+fn temperature(raw: u16) -> i32 {
+    (-45_000 + 175_000 * i64::from(raw) / 65_535) as i32
+}
+
+/// Relative humidity in m%: -6 % + 125 % * raw / 65535, limited to the
+/// physical range
+fn humidity(raw: u16) -> i32 {
+    let humidity = -6_000 + 125_000 * i64::from(raw) / 65_535;
+    humidity.max(0).min(100_000) as i32
+}
+
+// The heater state is only used with the `heater` feature
+#[cfg_attr(not(feature = "heater"), allow(dead_code))]
+pub struct Sht4x<I2C> {
+    i2c: I2C,
+    address: u8,
+    /// Sensor types of the temperature and humidity readings
+    sensor_types: (u8, u8),
+    /// Measurements until the next heater pulse
+    until_heating: u16,
+    /// Measurements since the last heater pulse (if any)
+    since_heating: Option<u16>,
+}
+
+impl<I2C, E> Sht4x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            address: ADDRESS,
+            sensor_types: (SENSOR_TEMP, SENSOR_HUMI),
+            until_heating: crate::board::HEATER_INTERVAL,
+            since_heating: None,
+        }
+    }
+
+    /// The second sensor of a node with redundant sensors. It is never
+    /// heated.
+    #[cfg(feature = "dual-sensor")]
+    pub fn secondary(i2c: I2C) -> Self {
+        Self {
+            address: crate::board::SECONDARY_SHT_ADDRESS,
+            sensor_types: (SENSOR_SECONDARY_TEMP, SENSOR_SECONDARY_HUMI),
+            ..Self::new(i2c)
+        }
+    }
+
+    /// Read the 32 bit serial number (blocks for 1 ms).
+    pub fn serial_number(&mut self) -> Result<u32, Error<E>> {
+        self.i2c.write(self.address, &[CMD_READ_SERIAL]).map_err(Error::I2c)?;
+        // 1 ms at 64 MHz
+        delay(64_000);
+        let mut buf = [0; 6];
+        self.i2c.read(self.address, &mut buf).map_err(Error::I2c)?;
+        let (high, low) = decode(&buf).ok_or(Error::Crc)?;
+        Ok((u32::from(high) << 16) | u32::from(low))
+    }
+
+    /// Count a measurement, and trigger a heater pulse if it is due (after
+    /// the readings of the measurement were collected). Return whether the
+    /// readings of the measurement were taken shortly after a heater pulse.
+    #[cfg(feature = "heater")]
+    pub fn heater_cycle(&mut self) -> bool {
+        let heated = matches!(self.since_heating, Some(n) if n < crate::board::HEATER_COOLDOWN);
+        self.since_heating = self.since_heating.map(|n| n.saturating_add(1));
+        self.until_heating = self.until_heating.saturating_sub(1);
+        if self.until_heating == 0 {
+            self.until_heating = crate::board::HEATER_INTERVAL;
+            // The measurement at the end of the pulse is not read, the next
+            // measurement command replaces it
+            match self.i2c.write(self.address, &[crate::board::SHT_HEATER as u8]) {
+                Ok(()) => {
+                    rprintln!("SHT4x: Heater pulse");
+                    self.since_heating = Some(0);
+                }
+                Err(_) => {
+                    rprintln!("SHT4x: Could not start the heater");
+                    telemetry::i2c_error();
+                }
+            }
+        }
+        heated
+    }
+}
+
+impl<I2C, E> Sensor for Sht4x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+    E: core::fmt::Debug,
+{
+    fn start(&mut self) -> Result<u32, sensors::Error> {
+        // Fails during a heater pulse (the sensor does not acknowledge)
+        self.i2c
+            .write(self.address, &[CMD_MEASURE_HIGH_PRECISION])
+            .map_err(|e| {
+                rprintln!("SHT4x: Could not start measurement: {:?}", e);
+                telemetry::i2c_error();
+                sensors::Error
+            })?;
+        Ok(MEASUREMENT_DURATION_US)
+    }
+
+    fn collect(&mut self, readings: &mut Readings) -> Result<(), sensors::Error> {
+        let mut buf = [0; 6];
+        self.i2c.read(self.address, &mut buf).map_err(|e| {
+            rprintln!("SHT4x: Could not read measurement: {:?}", e);
+            telemetry::i2c_error();
+            sensors::Error
+        })?;
+        let (temp, humi) = decode(&buf).ok_or_else(|| {
+            rprintln!("SHT4x: Invalid CRC");
+            telemetry::i2c_error();
+            sensors::Error
+        })?;
+        let temp = temperature(temp);
+        let humi = humidity(humi);
+        rprintln!(
+            "SHT4x measurement ({:#04x}): {} m°C / {} m%RH",
+            self.address,
+            temp,
+            humi
+        );
+        let (temp_type, humi_type) = self.sensor_types;
+        readings.push(temp_type, &temp.to_le_bytes()); // i32 LE
+        readings.push(humi_type, &humi.to_le_bytes()); // i32 LE
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+//! Health counters of the node.
+//!
+//! The counters are incremented where the events occur (e.g. in the sensor
+//! drivers) and sent in the telemetry entry of every `TELEMETRY_INTERVAL`-th
+//! measurement. A node with failing hardware (e.g. a corroding sensor
+//! connector) shows rising error counters at the gateway long before it stops
+//! sending values. All counters wrap around.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// The telemetry entry is sent with every n-th measurement (including the
+/// first one after startup).
+pub const TELEMETRY_INTERVAL: u16 = 10;
+
+static TX_BEACONS: AtomicU16 = AtomicU16::new(0);
+static I2C_ERRORS: AtomicU16 = AtomicU16::new(0);
+static SENSOR_RETRIES: AtomicU16 = AtomicU16::new(0);
+
+/// Number of transmitted beacons (wraps around).
+#[cfg_attr(not(feature = "eddystone-tlm"), allow(dead_code))]
+pub fn tx_beacons() -> u16 {
+    TX_BEACONS.load(Ordering::Relaxed)
+}
+
+/// A beacon has been transmitted.
+pub fn beacon_sent() {
+    TX_BEACONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// An I²C transaction failed.
+pub fn i2c_error() {
+    I2C_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A failed sensor operation is retried.
+pub fn sensor_retry() {
+    SENSOR_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Value of the telemetry entry: Transmitted beacons, I²C errors and sensor
+/// retries (u16 LE each).
+pub fn entry_value() -> [u8; 6] {
+    let mut value = [0; 6];
+    value[0..2].copy_from_slice(&TX_BEACONS.load(Ordering::Relaxed).to_le_bytes());
+    value[2..4].copy_from_slice(&I2C_ERRORS.load(Ordering::Relaxed).to_le_bytes());
+    value[4..6].copy_from_slice(&SENSOR_RETRIES.load(Ordering::Relaxed).to_le_bytes());
+    value
+}
@@ -0,0 +1,76 @@
+//! Time sync: Learn the time from the time beacons of the gateway.
+//!
+//! With a `[time_sync]` section in its config, the gateway broadcasts
+//! non-connectable advertisements with the complete local name `SensiloTime`
+//! and manufacturer specific data (company identifier `0xffff`) containing
+//! the Unix time (u32 LE) and the UTC offset of the local time in minutes
+//! (i16 LE).
+//!
+//! After a beacon burst, the node listens on advertising channel 37 for up to
+//! `LISTEN_TIMEOUT_MS` (longer than the advertising interval of the gateway).
+//! Until the first time beacon has been received, it listens after every
+//! `RETRY_CYCLES`-th burst, afterwards after every `SYNC_CYCLES`-th burst.
+//!
+//! The time beacons are not authenticated.
+
+use crate::advertiser::{AD_TYPE_COMPLETE_LOCAL_NAME, AD_TYPE_MANUFACTURER_DATA};
+
+/// Listen for up to this time (the gateway advertises every 100 ms by
+/// default, plus a random delay of up to 10 ms).
+pub const LISTEN_TIMEOUT_MS: u32 = 120;
+
+/// Measurement cycles between two attempts while the time is unknown (about
+/// a minute).
+pub const RETRY_CYCLES: u16 = 20;
+
+/// Measurement cycles between two syncs (about an hour).
+pub const SYNC_CYCLES: u16 = 1200;
+
+/// Local name of the time beacons.
+const LOCAL_NAME: &[u8] = b"SensiloTime";
+
+/// Company identifier of the manufacturer specific data.
+const COMPANY_IDENTIFIER: [u8; 2] = [0xff, 0xff];
+
+/// PDU type of a non-connectable undirected advertisement.
+const ADV_NONCONN_IND: u8 = 0x02;
+
+/// The content of a time beacon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBeacon {
+    pub unix_time: u32,
+    pub utc_offset_min: i16,
+}
+
+/// Parse a received advertising PDU (header, advertiser address and
+/// advertisement data). Return `None` if it is not a time beacon.
+pub fn parse(pdu: &[u8]) -> Option<TimeBeacon> {
+    if pdu.len() < 8 || pdu[0] & 0x0f != ADV_NONCONN_IND {
+        return None;
+    }
+    let len = usize::from(pdu[1]);
+    let mut data = pdu.get(8..2 + len)?;
+    let mut name_matches = false;
+    let mut beacon = None;
+    while let [len, rest @ ..] = data {
+        let len = usize::from(*len);
+        if len == 0 || rest.len() < len {
+            break;
+        }
+        let (structure, remaining) = rest.split_at(len);
+        match structure {
+            [AD_TYPE_COMPLETE_LOCAL_NAME, name @ ..] => name_matches = name == LOCAL_NAME,
+            [AD_TYPE_MANUFACTURER_DATA, company @ .., t0, t1, t2, t3, o0, o1]
+                if company == COMPANY_IDENTIFIER =>
+            {
+                beacon = Some(TimeBeacon {
+                    unix_time: u32::from_le_bytes([*t0, *t1, *t2, *t3]),
+                    utc_offset_min: i16::from_le_bytes([*o0, *o1]),
+                });
+            }
+            _ => {}
+        }
+        data = remaining;
+    }
+    beacon.filter(|_| name_matches)
+}
@@ -0,0 +1,45 @@
+//! Timing diagnostics of the measurement cycle.
+//!
+//! With the `diagnostics` feature, every measurement contains a timing entry
+//! (type `0x12`) with the time from the start of `start_measurement` to the
+//! start of `collect_measurement`, and the scheduling latency of
+//! `start_measurement` (how much later than scheduled it ran, negative if it
+//! ran too early). A misconfigured monotonic timer, or sensors that are slower
+//! than expected, show up at the gateway without attaching a debugger.
+//!
+//! Entry value (6 bytes):
+//!
+//! - Measurement duration (u32 LE): µs
+//! - Start latency (i16 LE): µs, saturated at ±32767
+
+use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+
+use crate::monotonic_nrf52::{Duration, Instant};
+
+/// RTC counts when the measurement was started
+static START: AtomicU32 = AtomicU32::new(0);
+/// Start latency in RTC ticks
+static LATENCY: AtomicI32 = AtomicI32::new(0);
+
+/// The measurement scheduled at `scheduled` was started at `now`.
+pub fn measurement_started(scheduled: Instant, now: Instant) {
+    START.store(now.counts(), Ordering::Relaxed);
+    let latency = now.counts().wrapping_sub(scheduled.counts()) as i32;
+    LATENCY.store(latency, Ordering::Relaxed);
+}
+
+/// Value of the timing entry of the measurement collected at `now`.
+pub fn entry_value(now: Instant) -> [u8; 6] {
+    let ticks = now.counts().wrapping_sub(START.load(Ordering::Relaxed));
+    let duration_us = Duration::from_ticks(ticks).as_micros();
+    let latency = LATENCY.load(Ordering::Relaxed);
+    let latency_us = Duration::from_ticks(latency.unsigned_abs())
+        .as_micros()
+        .min(i16::MAX as u32) as i16;
+    let latency_us = if latency < 0 { -latency_us } else { latency_us };
+
+    let mut value = [0; 6];
+    value[0..4].copy_from_slice(&duration_us.to_le_bytes());
+    value[4..6].copy_from_slice(&latency_us.to_le_bytes());
+    value
+}
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use sensilo_gateway::hci;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = hci::parse_packet(data);
+});
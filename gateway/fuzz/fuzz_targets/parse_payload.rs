@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use sensilo_gateway::measurement::MeasurementBuilder;
+use sensilo_gateway::types::Address;
+
+fuzz_target!(|data: &[u8]| {
+    let mut builder = MeasurementBuilder::new(Address([0; 6]), 0);
+    builder.local_name("Sensilo");
+    if builder.parse_payload(data).is_ok() {
+        let _ = builder.build();
+    }
+});
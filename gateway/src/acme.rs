@@ -0,0 +1,1142 @@
+//! Certificates for the API from an ACME CA (e.g. Let's Encrypt).
+//!
+//! The certificate is obtained with the HTTP-01 challenge (RFC 8555): The CA
+//! fetches `/.well-known/acme-challenge/<token>` from port 80 of every domain,
+//! which is answered by a small HTTP server next to the API. The account key,
+//! the certificate and its key are kept in the ACME directory (`dir`), and
+//! the certificate is renewed in the background before it expires. Until the
+//! first certificate is issued, the API uses a temporary self-signed
+//! certificate.
+//!
+//! The client is implemented on the HTTP client of the gateway, since the
+//! ACME crates (e.g. instant-acme) build on tokio and hyper. The request
+//! signatures (JWS with ES256) are tested with the key of RFC 7515.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, ClientHello, PrivateKey, ResolvesServerCert};
+
+use crate::api;
+use crate::config;
+use crate::http;
+use crate::json;
+use crate::json::Value;
+use crate::retry::Timeouts;
+use crate::x509;
+
+/// Interval in which the expiry of the certificate is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Delay before retrying a failed order.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Interval and number of the polls of an authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Maximum number of concurrent connections to the challenge server (the CA
+/// validates from a few locations at once).
+const MAX_CHALLENGE_CONNECTIONS: usize = 8;
+
+/// The certificate of the server, replaced after every renewal.
+pub struct Resolver(RwLock<CertifiedKey>);
+
+impl Resolver {
+    fn set(&self, certificates: Vec<Certificate>, key: &PrivateKey) -> Result<()> {
+        let key = rustls::sign::any_supported_type(key)
+            .map_err(|_| anyhow!("Unsupported private key"))?;
+        *self.0.write().unwrap() = CertifiedKey::new(certificates, Arc::new(key));
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// The key authorizations of the pending challenges, by token.
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+/// The files in the ACME directory.
+struct Files {
+    account_key: PathBuf,
+    certificate: PathBuf,
+    private_key: PathBuf,
+}
+
+impl Files {
+    fn new(dir: &Path) -> Self {
+        Self {
+            account_key: dir.join("account.key"),
+            certificate: dir.join("certificate.pem"),
+            private_key: dir.join("private-key.pem"),
+        }
+    }
+}
+
+/// Load the stored certificate chain and key.
+fn load_certificate(files: &Files) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let read = |path: &Path| -> Result<Vec<u8>> {
+        fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+    };
+    let certificates: Vec<Certificate> = rustls_pemfile::certs(&mut &read(&files.certificate)?[..])
+        .ok()
+        .filter(|certificates| !certificates.is_empty())
+        .ok_or_else(|| anyhow!("Invalid certificate {}", files.certificate.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut &read(&files.private_key)?[..])
+        .ok()
+        .and_then(|keys| keys.into_iter().next())
+        .map(PrivateKey)
+        .ok_or_else(|| anyhow!("Invalid private key {}", files.private_key.display()))?;
+    if !key_matches(&certificates[0], &key) {
+        bail!(
+            "The private key {} doesn't match the certificate {}",
+            files.private_key.display(),
+            files.certificate.display()
+        );
+    }
+    Ok((certificates, key))
+}
+
+/// Whether the certificate contains the public key of the (P-256) private key.
+/// Other keys are not checked.
+fn key_matches(certificate: &Certificate, key: &PrivateKey) -> bool {
+    match EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.0) {
+        Ok(key_pair) => {
+            x509::public_key(certificate).as_deref() == Some(key_pair.public_key().as_ref())
+        }
+        Err(_) => true,
+    }
+}
+
+/// The temporary file that is renamed to `path`.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+/// Write and sync the temporary file of `path`, with the permissions of a new
+/// file (on unix). It is moved into place with `commit`.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn stage(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    let tmp = tmp_path(path);
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    options
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .with_context(|| format!("Could not write {}", tmp.display()))
+}
+
+/// Move the temporary file of `path` into place, and sync the directory so
+/// that the rename is durable.
+fn commit(path: &Path) -> Result<()> {
+    fs::rename(tmp_path(path), path)
+        .and_then(|()| match path.parent() {
+            #[cfg(unix)]
+            Some(dir) => File::open(dir)?.sync_all(),
+            _ => Ok(()),
+        })
+        .with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Write a file atomically.
+fn write(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    stage(path, contents, mode)?;
+    commit(path)
+}
+
+/// The time until the certificate has to be renewed, `None` if it has to be
+/// renewed now (or doesn't cover all domains).
+fn due_in(config: &config::Acme, certificate: &Certificate, now: SystemTime) -> Option<Duration> {
+    let (expiry, names) = x509::expiry_and_names(certificate)?;
+    let covered = config
+        .domains
+        .iter()
+        .all(|domain| names.iter().any(|name| name.eq_ignore_ascii_case(domain)));
+    if !covered {
+        return None;
+    }
+    let renewal = expiry - Duration::from_secs(config.renew_days * 86400);
+    renewal.duration_since(now).ok()
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// An account at the CA, which signs the requests (JWS with ES256).
+struct Account<'a> {
+    client: &'a http::Client,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    new_nonce: String,
+    nonce: Option<String>,
+    /// The URL of the account, after registering it
+    kid: Option<String>,
+}
+
+impl<'a> Account<'a> {
+    fn new(client: &'a http::Client, key: &[u8], new_nonce: String) -> Result<Self> {
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key)
+            .map_err(|_| anyhow!("Invalid account key"))?;
+        Ok(Self {
+            client,
+            key,
+            rng: SystemRandom::new(),
+            new_nonce,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    /// The public key as JWK (with the members in lexicographic order, for
+    /// the thumbprint).
+    fn jwk(&self) -> String {
+        // The uncompressed point (0x04, x, y)
+        let point = self.key.public_key().as_ref();
+        format!(
+            "{{\"crv\":\"P-256\",\"kty\":\"EC\",\"x\":\"{}\",\"y\":\"{}\"}}",
+            base64url(&point[1..33]),
+            base64url(&point[33..])
+        )
+    }
+
+    /// The key authorization of a challenge token (RFC 8555, section 8.1).
+    fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, self.jwk().as_bytes());
+        format!("{}.{}", token, base64url(thumbprint.as_ref()))
+    }
+
+    /// The flattened JWS of a request (RFC 8555, section 6.2), signed with
+    /// the account key.
+    fn jws(&self, url: &str, nonce: &str, payload: Option<&str>) -> Result<String> {
+        let key = match self.kid {
+            Some(ref kid) => format!("\"kid\":{}", json::string(kid)),
+            None => format!("\"jwk\":{}", self.jwk()),
+        };
+        let protected = base64url(
+            format!(
+                "{{\"alg\":\"ES256\",{},\"nonce\":{},\"url\":{}}}",
+                key,
+                json::string(nonce),
+                json::string(url)
+            )
+            .as_bytes(),
+        );
+        let payload = payload.map_or(String::new(), |payload| base64url(payload.as_bytes()));
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("Could not sign the request"))?;
+        Ok(format!(
+            "{{\"protected\":\"{}\",\"payload\":\"{}\",\"signature\":\"{}\"}}",
+            protected,
+            payload,
+            base64url(signature.as_ref())
+        ))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self.client.get(&self.new_nonce, &[]).await?;
+        response
+            .header("replay-nonce")
+            .map(String::from)
+            .ok_or_else(|| anyhow!("No nonce from {}", self.new_nonce))
+    }
+
+    /// Send a signed request (a POST-as-GET without payload). Fails unless the
+    /// status is successful.
+    async fn post(&mut self, url: &str, payload: Option<&str>) -> Result<http::Response> {
+        // A nonce can be rejected (e.g. after a restart of the CA), the error
+        // contains a fresh one
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.jws(url, &nonce, payload)?;
+            let headers = [("Content-Type", "application/jose+json")];
+            let response = self.client.post(url, &headers, body.into_bytes()).await?;
+            self.nonce = response.header("replay-nonce").map(String::from);
+            if (200..300).contains(&response.status) {
+                return Ok(response);
+            }
+            let problem = json::parse(&response.body).ok();
+            let field = |name| {
+                problem
+                    .as_ref()
+                    .and_then(|problem| problem.get(name))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            if field("type") == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "{} failed with status {}: {}",
+                url,
+                response.status_line(),
+                field("detail")
+            );
+        }
+    }
+
+    /// Poll an authorization or order until it is no longer pending (or
+    /// processing).
+    async fn poll(&mut self, url: &str) -> Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = parse(&self.post(url, None).await?)?;
+            match object.get("status").and_then(Value::as_str) {
+                Some("pending") | Some("processing") => smol::Timer::after(POLL_INTERVAL).await,
+                _ => return Ok(object),
+            };
+        }
+        bail!("{} is still pending", url)
+    }
+}
+
+fn parse(response: &http::Response) -> Result<Value> {
+    json::parse(&response.body).map_err(|e| anyhow!("Invalid response from the CA: {}", e))
+}
+
+fn member<'v>(value: &'v Value, name: &str) -> Result<&'v str> {
+    value
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Invalid response from the CA: No {}", name))
+}
+
+/// The error of a failed authorization.
+fn authorization_error(authorization: &Value) -> String {
+    authorization
+        .get("challenges")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|challenge| challenge.get("error")?.get("detail")?.as_str())
+        .next()
+        .unwrap_or("unknown error")
+        .to_string()
+}
+
+/// Order a certificate for the domains. Returns the PEM chain and the
+/// private key (PKCS#8).
+async fn order(
+    config: &config::Acme,
+    client: &http::Client,
+    account_key: &[u8],
+    challenges: &Challenges,
+) -> Result<(String, Vec<u8>)> {
+    let response = client.get(&config.directory, &[]).await?;
+    if response.status != 200 {
+        bail!(
+            "Could not fetch the ACME directory {}: {}",
+            config.directory,
+            response.status_line()
+        );
+    }
+    let directory = parse(&response)?;
+    let mut account = Account::new(
+        client,
+        account_key,
+        member(&directory, "newNonce")?.to_string(),
+    )?;
+
+    // Returns the existing account for a known key
+    let contact: Vec<String> = config.contact.iter().map(|url| json::string(url)).collect();
+    let new_account = format!(
+        "{{\"termsOfServiceAgreed\":true,\"contact\":[{}]}}",
+        contact.join(",")
+    );
+    let response = account
+        .post(member(&directory, "newAccount")?, Some(&new_account))
+        .await?;
+    account.kid = Some(
+        response
+            .header("location")
+            .ok_or_else(|| anyhow!("No account URL from the CA"))?
+            .to_string(),
+    );
+
+    let identifiers: Vec<String> = config
+        .domains
+        .iter()
+        .map(|domain| format!("{{\"type\":\"dns\",\"value\":{}}}", json::string(domain)))
+        .collect();
+    let new_order = format!("{{\"identifiers\":[{}]}}", identifiers.join(","));
+    let response = account
+        .post(member(&directory, "newOrder")?, Some(&new_order))
+        .await?;
+    let order_url = response
+        .header("location")
+        .ok_or_else(|| anyhow!("No order URL from the CA"))?
+        .to_string();
+    let order = parse(&response)?;
+
+    let authorizations = order
+        .get("authorizations")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    for url in authorizations.filter_map(Value::as_str) {
+        let authorization = parse(&account.post(url, None).await?)?;
+        let domain = authorization
+            .get("identifier")
+            .and_then(|identifier| identifier.get("value"))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        if authorization.get("status").and_then(Value::as_str) == Some("valid") {
+            continue;
+        }
+        let challenge = authorization
+            .get("challenges")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .find(|challenge| challenge.get("type").and_then(Value::as_str) == Some("http-01"))
+            .ok_or_else(|| anyhow!("No HTTP-01 challenge for {}", domain))?;
+        let token = member(challenge, "token")?.to_string();
+        challenges
+            .lock()
+            .unwrap()
+            .insert(token.clone(), account.key_authorization(&token));
+        log::info!("ACME: Validating {}", domain);
+        let result = match account.post(member(challenge, "url")?, Some("{}")).await {
+            Ok(_) => account.poll(url).await,
+            Err(e) => Err(e),
+        };
+        challenges.lock().unwrap().remove(&token);
+        let authorization = result?;
+        if authorization.get("status").and_then(Value::as_str) != Some("valid") {
+            bail!(
+                "Validation of {} failed: {}",
+                domain,
+                authorization_error(&authorization)
+            );
+        }
+    }
+
+    let (request, private_key) = x509::certificate_request(&config.domains)?;
+    let finalize = format!("{{\"csr\":\"{}\"}}", base64url(&request));
+    account
+        .post(member(&order, "finalize")?, Some(&finalize))
+        .await?;
+    let order = account.poll(&order_url).await?;
+    if order.get("status").and_then(Value::as_str) != Some("valid") {
+        bail!(
+            "The order failed: {}",
+            order
+                .get("error")
+                .and_then(|error| error.get("detail"))
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error")
+        );
+    }
+    let response = account.post(member(&order, "certificate")?, None).await?;
+    Ok((response.body, private_key))
+}
+
+/// Obtains and renews the certificate in the background.
+pub struct Manager {
+    config: config::Acme,
+    files: Files,
+    resolver: Arc<Resolver>,
+    challenges: Challenges,
+    /// The time until the current certificate has to be renewed
+    renew_in: Option<Duration>,
+}
+
+impl Manager {
+    /// Load the stored certificate (or create a temporary one) and serve the
+    /// challenges. The HTTP server is started here, as port 80 usually
+    /// requires root privileges.
+    pub fn new(config: &config::Acme) -> Result<(Self, Arc<Resolver>)> {
+        if config.domains.is_empty() {
+            bail!("ACME needs at least one domain");
+        }
+        if !config.accept_terms {
+            bail!("Agreeing to the terms of service of the CA is required (accept_terms)");
+        }
+        http::Client::check_url(&config.directory)?;
+        let files = Files::new(&config.dir);
+        let (certificates, key, renew_in) = match load_certificate(&files) {
+            Ok((certificates, key)) => {
+                let renew_in = due_in(config, &certificates[0], SystemTime::now());
+                (certificates, key, renew_in)
+            }
+            Err(e) => {
+                if files.certificate.exists() {
+                    log::warn!("ACME: {}", e);
+                }
+                log::info!("ACME: Using a self-signed certificate until the certificate is issued");
+                let (certificate, key) = x509::self_signed(&config.domains, SystemTime::now())?;
+                (vec![Certificate(certificate)], PrivateKey(key), None)
+            }
+        };
+        let key = rustls::sign::any_supported_type(&key)
+            .map_err(|_| anyhow!("Unsupported private key {}", files.private_key.display()))?;
+        let resolver = Arc::new(Resolver(RwLock::new(CertifiedKey::new(
+            certificates,
+            Arc::new(key),
+        ))));
+
+        let challenges = Challenges::default();
+        let listener = TcpListener::bind(&config.challenge_listen).with_context(|| {
+            format!(
+                "Could not listen on {} (for the ACME challenges)",
+                config.challenge_listen
+            )
+        })?;
+        let serving = Arc::clone(&challenges);
+        thread::Builder::new()
+            .name("acme-challenges".into())
+            .spawn(move || serve_challenges(listener, serving))?;
+
+        let manager = Self {
+            config: config.clone(),
+            files,
+            resolver: Arc::clone(&resolver),
+            challenges,
+            renew_in,
+        };
+        Ok((manager, resolver))
+    }
+
+    /// Obtain the certificate (if needed) and renew it in the background.
+    /// Started after dropping the privileges, so that the files are owned by
+    /// the daemon user.
+    pub fn start(self, http: &config::Http) -> Result<()> {
+        let timeout = Duration::from_secs(http.timeout_s);
+        let timeouts = Timeouts::new(&Default::default(), timeout);
+        let client = http::Client::new(http, timeouts)?;
+        thread::Builder::new()
+            .name("acme".into())
+            .spawn(move || smol::block_on(self.run(client)))?;
+        Ok(())
+    }
+
+    async fn run(mut self, client: http::Client) {
+        loop {
+            if let Some(renew_in) = self.renew_in {
+                smol::Timer::after(renew_in.min(CHECK_INTERVAL)).await;
+                let certificate = self.resolver.0.read().unwrap().cert[0].clone();
+                self.renew_in = due_in(&self.config, &certificate, SystemTime::now());
+                continue;
+            }
+            log::info!(
+                "ACME: Ordering a certificate for {}",
+                self.config.domains.join(", ")
+            );
+            match self.renew(&client).await {
+                Ok(renew_in) => {
+                    log::info!("ACME: Installed the new certificate");
+                    self.renew_in = Some(renew_in);
+                }
+                Err(e) => {
+                    log::error!("ACME: Could not obtain a certificate: {:#}", e);
+                    smol::Timer::after(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Order, store and install a new certificate.
+    async fn renew(&self, client: &http::Client) -> Result<Duration> {
+        fs::create_dir_all(&self.config.dir)
+            .with_context(|| format!("Could not create {}", self.config.dir.display()))?;
+        let account_key = match fs::read(&self.files.account_key) {
+            Ok(pem) => rustls_pemfile::pkcs8_private_keys(&mut &pem[..])
+                .ok()
+                .and_then(|keys| keys.into_iter().next())
+                .ok_or_else(|| {
+                    anyhow!("Invalid account key {}", self.files.account_key.display())
+                })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = EcdsaKeyPair::generate_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    &SystemRandom::new(),
+                )
+                .map_err(|_| anyhow!("Could not generate the account key"))?;
+                write(
+                    &self.files.account_key,
+                    &x509::pem("PRIVATE KEY", key.as_ref()),
+                    0o600,
+                )?;
+                key.as_ref().to_vec()
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Could not read {}", self.files.account_key.display())
+                })
+            }
+        };
+
+        let (chain, private_key) =
+            order(&self.config, client, &account_key, &self.challenges).await?;
+        let certificates: Vec<Certificate> = rustls_pemfile::certs(&mut chain.as_bytes())
+            .ok()
+            .filter(|certificates| !certificates.is_empty())
+            .ok_or_else(|| anyhow!("Invalid certificate from the CA"))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let renew_in = due_in(&self.config, &certificates[0], SystemTime::now())
+            .ok_or_else(|| anyhow!("The certificate from the CA is already due for renewal"))?;
+        // Both files are written before either is replaced, and the
+        // certificate last: After a crash in between, the new key doesn't
+        // match the old certificate, which is detected on startup (and a new
+        // certificate is ordered).
+        stage(
+            &self.files.private_key,
+            &x509::pem("PRIVATE KEY", &private_key),
+            0o600,
+        )?;
+        stage(&self.files.certificate, &chain, 0o644)?;
+        commit(&self.files.private_key)?;
+        commit(&self.files.certificate)?;
+        self.resolver.set(certificates, &PrivateKey(private_key))?;
+        Ok(renew_in)
+    }
+}
+
+/// Answer the challenges of the CA (and nothing else). Every connection is
+/// handled on its own thread, so that stalled clients can't block the
+/// validation, up to `MAX_CHALLENGE_CONNECTIONS`.
+fn serve_challenges(listener: TcpListener, challenges: Challenges) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("ACME: Could not accept a connection: {}", e);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CHALLENGE_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            log::warn!("ACME: Too many connections to the challenge server");
+            let _ = api::respond(
+                stream,
+                "503 Service Unavailable",
+                "text/plain",
+                "Too many connections\n",
+            );
+            continue;
+        }
+        let challenges = Arc::clone(&challenges);
+        let finished = Arc::clone(&connections);
+        let spawned = thread::Builder::new()
+            .name("acme-challenge".into())
+            .spawn(move || {
+                if let Err(e) = answer_challenge(stream, &challenges) {
+                    log::debug!("ACME: Challenge request failed: {}", e);
+                }
+                finished.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            log::warn!("ACME: Could not spawn a connection thread: {}", e);
+        }
+    }
+}
+
+fn answer_challenge(stream: TcpStream, challenges: &Challenges) -> std::io::Result<()> {
+    let mut stream = api::DeadlineStream::new(stream, api::READ_TIMEOUT);
+    let request = match api::read_request(&mut stream)? {
+        Some(request) => request,
+        None => return api::respond(stream, "400 Bad Request", "text/plain", "Bad request\n"),
+    };
+    let key_authorization = request
+        .path
+        .strip_prefix(CHALLENGE_PATH)
+        .filter(|_| request.method == "GET")
+        .and_then(|token| challenges.lock().unwrap().get(token).cloned());
+    match key_authorization {
+        Some(key_authorization) => {
+            log::debug!("ACME: Answered the challenge {}", request.path);
+            api::respond(
+                stream,
+                "200 OK",
+                "application/octet-stream",
+                &key_authorization,
+            )
+        }
+        None => api::respond(stream, "404 Not Found", "text/plain", "Not found\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read};
+    use std::time::UNIX_EPOCH;
+
+    fn acme_config(domains: &[&str]) -> config::Acme {
+        config::Acme {
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            contact: vec![],
+            directory: "https://acme.example.com/directory".into(),
+            accept_terms: true,
+            dir: "acme".into(),
+            challenge_listen: "127.0.0.1:0".into(),
+            renew_days: 30,
+        }
+    }
+
+    #[test]
+    fn renewal() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_607_500_000);
+        let (certificate, _) = x509::self_signed(&["gateway.example.com".into()], now).unwrap();
+        let certificate = Certificate(certificate);
+        let config = acme_config(&["Gateway.example.com"]);
+        // Valid for 10 years, renewed 30 days before
+        assert_eq!(
+            due_in(&config, &certificate, now),
+            Some(Duration::from_secs((3650 - 30) * 86400))
+        );
+        let later = now + Duration::from_secs(3630 * 86400);
+        assert_eq!(due_in(&config, &certificate, later), None);
+        // Renewed immediately if a domain is added
+        let config = acme_config(&["gateway.example.com", "sensilo.example.com"]);
+        assert_eq!(due_in(&config, &certificate, now), None);
+    }
+
+    /// The P-256 key of RFC 7515, appendix A.3 (PKCS#8).
+    const RFC7515_KEY: &str = "308187020100301306072a8648ce3d020106082a8648ce3d030107046d306b02\
+                               010104208e9b109e719098bf980487df1f5d77e9cb29606ebed2263b5f57c213\
+                               df84f4b2a144034200047fcdce2770f6c45d4183cbee6fdb4b7b580733357be9\
+                               ef13bacf6e3c7bd15445c7f144cd1bbd9b7e872cdfedb9eeb9f4b3695d6ea90b\
+                               24ad8a4623288588e5ad";
+
+    fn with_account(test: impl FnOnce(&Account)) {
+        let key = base16::decode(RFC7515_KEY.as_bytes()).unwrap();
+        let timeouts = Timeouts::new(&Default::default(), Duration::from_secs(5));
+        let client = http::Client::new(&Default::default(), timeouts).unwrap();
+        test(&Account::new(&client, &key, String::new()).unwrap());
+    }
+
+    fn verify(jwk: &Value, signing_input: &str, signature: &str) -> bool {
+        let coordinate = |name| {
+            let value = jwk.get(name).and_then(Value::as_str).unwrap();
+            base64::decode_config(value, base64::URL_SAFE_NO_PAD).unwrap()
+        };
+        let point = [&[4][..], &coordinate("x"), &coordinate("y")].concat();
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ECDSA_P256_SHA256_FIXED, point)
+            .verify(signing_input.as_bytes(), &signature)
+            .is_ok()
+    }
+
+    #[test]
+    fn encode_base64url() {
+        // RFC 4648, section 10 (without padding), and the URL safe alphabet
+        let vectors: [(&[u8], &str); 8] = [
+            (b"", ""),
+            (b"f", "Zg"),
+            (b"fo", "Zm8"),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg"),
+            (b"fooba", "Zm9vYmE"),
+            (b"foobar", "Zm9vYmFy"),
+            (&[0xfb, 0xff, 0xbf], "-_-_"),
+        ];
+        for (data, encoded) in &vectors {
+            assert_eq!(base64url(data), *encoded);
+        }
+    }
+
+    #[test]
+    fn jwk_thumbprint() {
+        with_account(|account| {
+            // The public key of RFC 7515, appendix A.3, in the member order
+            // of RFC 7638, section 3.2
+            assert_eq!(
+                account.jwk(),
+                "{\"crv\":\"P-256\",\"kty\":\"EC\",\
+                 \"x\":\"f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU\",\
+                 \"y\":\"x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0\"}"
+            );
+            // RFC 8555, section 8.1 (with a challenge token)
+            assert_eq!(
+                account.key_authorization("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"),
+                "LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0.\
+                 oKIywvGUpTVTyxMQ3bwIIeQUudfr_CkLMjCE19ECD-U"
+            );
+        });
+    }
+
+    #[test]
+    fn jws() {
+        with_account(|account| {
+            let jwk = json::parse(&account.jwk()).unwrap();
+            // The example of RFC 7515, appendix A.3 verifies with the key
+            // (ES256 signatures are R and S, not DER)
+            assert!(verify(
+                &jwk,
+                "eyJhbGciOiJFUzI1NiJ9.\
+                 eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ",
+                "DtEhU3ljbEg8L38VWAfUAqOyKAM6-Xx-F4GawxaepmXFCgfTjDxw5djxLa8ISlSApmWQxfKTUJqPP3-Kg6NU1Q"
+            ));
+
+            let url = "https://example.com/acme/new-account";
+            let body = account
+                .jws(
+                    url,
+                    "6S8IqOGY7eL2lsGoTZYifg",
+                    Some("{\"termsOfServiceAgreed\":true}"),
+                )
+                .unwrap();
+            let body = json::parse(&body).unwrap();
+            let member = |name| body.get(name).and_then(Value::as_str).unwrap();
+            let decode = |name| {
+                let value = base64::decode_config(member(name), base64::URL_SAFE_NO_PAD);
+                json::parse(std::str::from_utf8(&value.unwrap()).unwrap()).unwrap()
+            };
+            let protected = decode("protected");
+            assert_eq!(protected.get("alg").and_then(Value::as_str), Some("ES256"));
+            assert_eq!(protected.get("jwk"), Some(&jwk));
+            assert_eq!(
+                protected.get("nonce").and_then(Value::as_str),
+                Some("6S8IqOGY7eL2lsGoTZYifg")
+            );
+            assert_eq!(protected.get("url").and_then(Value::as_str), Some(url));
+            assert_eq!(
+                decode("payload").get("termsOfServiceAgreed"),
+                Some(&Value::Bool(true))
+            );
+            let signing_input = format!("{}.{}", member("protected"), member("payload"));
+            assert!(verify(&jwk, &signing_input, member("signature")));
+            assert!(!verify(
+                &jwk,
+                &signing_input.replace('.', ""),
+                member("signature")
+            ));
+
+            // POST-as-GET has an empty payload
+            let body = account.jws(url, "nonce", None).unwrap();
+            assert!(body.contains("\"payload\":\"\""));
+        });
+    }
+
+    #[test]
+    fn failed_authorization() {
+        // An authorization of RFC 8555, section 7.1.4, after a failed
+        // validation (section 8)
+        let authorization = json::parse(
+            r#"{
+                "status": "invalid",
+                "expires": "2016-01-02T14:09:30Z",
+                "identifier": {"type": "dns", "value": "www.example.org"},
+                "challenges": [
+                    {
+                        "type": "dns-01",
+                        "url": "https://example.com/acme/chall/Rg5dV14Gh1Q",
+                        "status": "pending",
+                        "token": "DGyRejmCefe7v4NfDGDKfA"
+                    },
+                    {
+                        "type": "http-01",
+                        "url": "https://example.com/acme/chall/prV_B7yEyA4",
+                        "status": "invalid",
+                        "token": "DGyRejmCefe7v4NfDGDKfA",
+                        "error": {
+                            "type": "urn:ietf:params:acme:error:connection",
+                            "detail": "Connection refused"
+                        }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(authorization_error(&authorization), "Connection refused");
+        let pending = json::parse(r#"{"status": "pending", "challenges": []}"#).unwrap();
+        assert_eq!(authorization_error(&pending), "unknown error");
+    }
+
+    #[test]
+    fn challenges() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let challenges = Challenges::default();
+        challenges
+            .lock()
+            .unwrap()
+            .insert("abc".into(), "abc.thumbprint".into());
+        let serving = Arc::clone(&challenges);
+        thread::spawn(move || serve_challenges(listener, serving));
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: gateway\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/.well-known/acme-challenge/abc");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nabc.thumbprint"));
+        let response = get("/.well-known/acme-challenge/other");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get("/api/latest");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn stored_certificate() {
+        let dir = std::env::temp_dir().join(format!("sensilo-acme-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = Files::new(&dir);
+        let now = SystemTime::now();
+        let (certificate, key) = x509::self_signed(&["gateway.example.com".into()], now).unwrap();
+        let (_, other_key) = x509::self_signed(&["gateway.example.com".into()], now).unwrap();
+        let certificate = x509::pem("CERTIFICATE", &certificate);
+        write(&files.certificate, &certificate, 0o644).unwrap();
+        write(&files.private_key, &x509::pem("PRIVATE KEY", &key), 0o600).unwrap();
+        assert!(load_certificate(&files).is_ok());
+        assert!(!tmp_path(&files.certificate).exists());
+
+        // E.g. after a crash between replacing the key and the certificate
+        stage(
+            &files.private_key,
+            &x509::pem("PRIVATE KEY", &other_key),
+            0o600,
+        )
+        .unwrap();
+        commit(&files.private_key).unwrap();
+        let error = load_certificate(&files).unwrap_err().to_string();
+        assert!(error.contains("doesn't match the certificate"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn challenge_connection_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_challenges(listener, Challenges::default()));
+        // Idle connections, which wait for the request
+        let idle: Vec<TcpStream> = (0..MAX_CHALLENGE_CONNECTIONS)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let mut response = String::new();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        drop(idle);
+    }
+
+    /// A minimal CA, which checks the pending challenge and issues the
+    /// certificate. Returns the requests (the path and the decoded protected
+    /// header and payload).
+    fn fake_ca(listener: TcpListener, challenges: Challenges) -> Vec<(String, String, String)> {
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let mut requests = vec![];
+        let mut validated = false;
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            loop {
+                let mut head = vec![];
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push(line.trim_end().to_lowercase());
+                }
+                if head.is_empty() {
+                    break;
+                }
+                let path = head[0].split(' ').nth(1).unwrap().to_string();
+                let len = head
+                    .iter()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |len| len.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                if len > 0 {
+                    let jws = json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+                    let decode = |name| {
+                        let value = jws.get(name).and_then(Value::as_str).unwrap();
+                        let value = base64::decode_config(value, base64::URL_SAFE_NO_PAD);
+                        String::from_utf8(value.unwrap()).unwrap()
+                    };
+                    requests.push((path.clone(), decode("protected"), decode("payload")));
+                }
+
+                let (status, location, body) = match path.as_str() {
+                    "/directory" => (
+                        "200 OK",
+                        None,
+                        format!(
+                            "{{\"newNonce\":\"{0}/nonce\",\"newAccount\":\"{0}/account\",\
+                             \"newOrder\":\"{0}/order\"}}",
+                            base
+                        ),
+                    ),
+                    "/nonce" => ("204 No Content", None, String::new()),
+                    "/account" => ("201 Created", Some("/account/1"), "{}".into()),
+                    "/order" => (
+                        "201 Created",
+                        Some("/order/1"),
+                        format!(
+                            "{{\"status\":\"pending\",\"authorizations\":[\"{0}/authz/1\"],\
+                             \"finalize\":\"{0}/finalize\"}}",
+                            base
+                        ),
+                    ),
+                    "/authz/1" => (
+                        "200 OK",
+                        None,
+                        format!(
+                            "{{\"status\":\"{}\",\"identifier\":{{\"type\":\"dns\",\
+                             \"value\":\"gateway.example.com\"}},\"challenges\":[\
+                             {{\"type\":\"dns-01\",\"url\":\"{1}/dns\",\"token\":\"dns\"}},\
+                             {{\"type\":\"http-01\",\"url\":\"{1}/challenge/1\",\"token\":\"t0k\"}}]}}",
+                            if validated { "valid" } else { "pending" },
+                            base
+                        ),
+                    ),
+                    "/challenge/1" => {
+                        validated = challenges.lock().unwrap().contains_key("t0k");
+                        ("200 OK", None, "{}".into())
+                    }
+                    "/finalize" => ("200 OK", None, "{\"status\":\"processing\"}".into()),
+                    "/order/1" => (
+                        "200 OK",
+                        None,
+                        format!("{{\"status\":\"valid\",\"certificate\":\"{}/cert\"}}", base),
+                    ),
+                    "/cert" => {
+                        let names = ["gateway.example.com".into()];
+                        let (certificate, _) = x509::self_signed(&names, SystemTime::now()).unwrap();
+                        let pem = x509::pem("CERTIFICATE", &certificate);
+                        write!(
+                            reader.get_mut(),
+                            "HTTP/1.1 200 OK\r\nReplay-Nonce: last\r\nContent-Length: {}\r\n\r\n{}",
+                            pem.len(),
+                            pem
+                        )
+                        .unwrap();
+                        return requests;
+                    }
+                    _ => ("404 Not Found", None, String::new()),
+                };
+                let location = location.map_or(String::new(), |location| {
+                    format!("Location: {}{}\r\n", base, location)
+                });
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {}\r\nReplay-Nonce: nonce-{}\r\n{}Content-Length: {}\r\n\r\n{}",
+                    status,
+                    requests.len(),
+                    location,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn order_certificate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let challenges = Challenges::default();
+        let serving = Arc::clone(&challenges);
+        let ca = thread::spawn(move || fake_ca(listener, serving));
+
+        let config = config::Acme {
+            directory: format!("{}/directory", base),
+            contact: vec!["mailto:admin@example.com".into()],
+            ..acme_config(&["gateway.example.com"])
+        };
+        let http = config::Http {
+            no_proxy: Some(vec!["127.0.0.1".into()]),
+            ..Default::default()
+        };
+        let timeouts = Timeouts::new(&Default::default(), Duration::from_secs(5));
+        let client = http::Client::new(&http, timeouts).unwrap();
+        let key =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        let (chain, private_key) =
+            smol::block_on(order(&config, &client, key.as_ref(), &challenges)).unwrap();
+        assert!(chain.starts_with("-----BEGIN CERTIFICATE-----\n"));
+        assert!(rustls::sign::any_supported_type(&PrivateKey(private_key)).is_ok());
+        assert!(challenges.lock().unwrap().is_empty());
+
+        let requests = ca.join().unwrap();
+        let paths: Vec<&str> = requests.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/account",
+                "/order",
+                "/authz/1",
+                "/challenge/1",
+                "/authz/1",
+                "/finalize",
+                "/order/1",
+                "/cert"
+            ]
+        );
+        // The account is registered with the key, then referenced by its URL
+        let (_, protected, payload) = &requests[0];
+        assert!(protected.starts_with("{\"alg\":\"ES256\",\"jwk\":{\"crv\":\"P-256\","));
+        assert!(protected.ends_with(&format!(
+            ",\"nonce\":\"nonce-0\",\"url\":\"{}/account\"}}",
+            base
+        )));
+        assert_eq!(
+            payload,
+            "{\"termsOfServiceAgreed\":true,\"contact\":[\"mailto:admin@example.com\"]}"
+        );
+        let (_, protected, payload) = &requests[1];
+        assert!(protected.contains(&format!(
+            "\"kid\":\"{}/account/1\",\"nonce\":\"nonce-1\"",
+            base
+        )));
+        assert_eq!(
+            payload,
+            "{\"identifiers\":[{\"type\":\"dns\",\"value\":\"gateway.example.com\"}]}"
+        );
+        // POST-as-GET
+        assert_eq!(requests[2].2, "");
+        assert_eq!(requests[3].2, "{}");
+        assert!(requests[5].2.starts_with("{\"csr\":\""));
+    }
+}
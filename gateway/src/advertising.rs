@@ -0,0 +1,342 @@
+//! Advertising reports and advertising data (AD) structures.
+//!
+//! Both legacy LE Advertising Report events and LE Extended Advertising Report
+//! events (sent by BLE 5 adapters in extended scanning mode) are parsed.
+use crate::types::Address;
+
+/// Subevent code of the LE Advertising Report event.
+pub const LE_ADVERTISING_REPORT: u8 = 0x02;
+
+/// Subevent code of the LE Extended Advertising Report event.
+pub const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0d;
+
+/// AD type of the complete local name.
+const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+
+/// AD type of the service data with a 16 bit UUID.
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+
+/// AD type of the manufacturer specific data.
+const AD_TYPE_MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+
+/// An advertising report, independent of the HCI event it was received in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdvertisingReport {
+    pub address: Address,
+    pub rssi: u8,
+    pub kind: ReportKind,
+    pub data: Vec<AdStructure>,
+}
+
+/// Whether a report is (or may be followed by) a scan response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    /// An advertisement that is never followed by a scan response
+    NonScannable,
+    /// A scannable advertisement (e.g. `ADV_IND` or `ADV_SCAN_IND`), which
+    /// is followed by a scan response if the scanner is active
+    Scannable,
+    /// A scan response (`SCAN_RSP`) to a scannable advertisement
+    ScanResponse,
+}
+
+impl ReportKind {
+    /// The kind of a report with the event type of an LE Advertising Report.
+    fn from_legacy_event_type(event_type: u8) -> Self {
+        match event_type {
+            // ADV_IND, ADV_SCAN_IND
+            0x00 | 0x02 => ReportKind::Scannable,
+            0x04 => ReportKind::ScanResponse,
+            _ => ReportKind::NonScannable,
+        }
+    }
+
+    /// The kind of a report with the event type of an LE Extended
+    /// Advertising Report.
+    fn from_extended_event_type(event_type: u16) -> Self {
+        if event_type & (1 << 3) != 0 {
+            ReportKind::ScanResponse
+        } else if event_type & (1 << 1) != 0 {
+            ReportKind::Scannable
+        } else {
+            ReportKind::NonScannable
+        }
+    }
+}
+
+/// An AD structure in the advertising data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdStructure {
+    CompleteLocalName(String),
+    ManufacturerSpecificData {
+        company_identifier: u16,
+        data: Vec<u8>,
+    },
+    ServiceData16 {
+        uuid: u16,
+        data: Vec<u8>,
+    },
+    Other {
+        ty: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// Parse the AD structures in the advertising data.
+pub fn parse_ad_structures(mut data: &[u8]) -> Result<Vec<AdStructure>, &'static str> {
+    let mut structures = vec![];
+    while let Some((&len, rest)) = data.split_first() {
+        // A zero length AD structure marks the end of the significant data
+        if len == 0 {
+            break;
+        }
+        let len = len as usize;
+        if rest.len() < len {
+            return Err("AD structure exceeds advertising data");
+        }
+        let (ty, value) = (rest[0], &rest[1..len]);
+        structures.push(match ty {
+            AD_TYPE_COMPLETE_LOCAL_NAME => AdStructure::CompleteLocalName(
+                std::str::from_utf8(value)
+                    .map_err(|_| "Invalid UTF-8 in local name")?
+                    .to_string(),
+            ),
+            AD_TYPE_MANUFACTURER_SPECIFIC_DATA if value.len() >= 2 => {
+                AdStructure::ManufacturerSpecificData {
+                    company_identifier: u16::from_le_bytes([value[0], value[1]]),
+                    data: value[2..].to_vec(),
+                }
+            }
+            AD_TYPE_SERVICE_DATA_16 if value.len() >= 2 => AdStructure::ServiceData16 {
+                uuid: u16::from_le_bytes([value[0], value[1]]),
+                data: value[2..].to_vec(),
+            },
+            _ => AdStructure::Other {
+                ty,
+                data: value.to_vec(),
+            },
+        });
+        data = &rest[len..];
+    }
+    Ok(structures)
+}
+
+/// Parse the parameters of an LE Advertising Report event (starting with the
+/// subevent code).
+///
+/// The reports are parsed one after the other (event type, address type,
+/// address, data length, data, RSSI), like BlueZ does. Controllers virtually
+/// always send a single report per event.
+pub fn parse_advertising_report(params: &[u8]) -> Result<Vec<AdvertisingReport>, &'static str> {
+    /// Length of a report without the advertising data and the RSSI
+    const REPORT_HEADER_LEN: usize = 9;
+
+    match params.first() {
+        Some(&LE_ADVERTISING_REPORT) => {}
+        _ => return Err("Not an LE Advertising Report"),
+    }
+    let num_reports = *params.get(1).ok_or("Missing number of reports")?;
+
+    let mut reports = vec![];
+    let mut rest = &params[2..];
+    for _ in 0..num_reports {
+        if rest.len() < REPORT_HEADER_LEN {
+            return Err("Advertising report too short");
+        }
+        let kind = ReportKind::from_legacy_event_type(rest[0]);
+        let address = Address::from_inverted_slice(&rest[2..8]);
+        let data_len = rest[8] as usize;
+        let data = rest
+            .get(REPORT_HEADER_LEN..REPORT_HEADER_LEN + data_len)
+            .ok_or("Advertising data exceeds event")?;
+        let rssi = *rest
+            .get(REPORT_HEADER_LEN + data_len)
+            .ok_or("Missing RSSI")?;
+        rest = &rest[REPORT_HEADER_LEN + data_len + 1..];
+
+        reports.push(AdvertisingReport {
+            address,
+            rssi,
+            kind,
+            data: parse_ad_structures(data)?,
+        });
+    }
+    Ok(reports)
+}
+
+/// Parse the parameters of an LE Extended Advertising Report event (starting
+/// with the subevent code).
+///
+/// Reports with incomplete or truncated advertising data are skipped.
+pub fn parse_extended_advertising_report(
+    params: &[u8],
+) -> Result<Vec<AdvertisingReport>, &'static str> {
+    /// Length of a report without the advertising data
+    const REPORT_HEADER_LEN: usize = 24;
+
+    match params.first() {
+        Some(&LE_EXTENDED_ADVERTISING_REPORT) => {}
+        _ => return Err("Not an LE Extended Advertising Report"),
+    }
+    let num_reports = *params.get(1).ok_or("Missing number of reports")?;
+
+    let mut reports = vec![];
+    let mut rest = &params[2..];
+    for _ in 0..num_reports {
+        if rest.len() < REPORT_HEADER_LEN {
+            return Err("Extended advertising report too short");
+        }
+        let event_type = u16::from_le_bytes([rest[0], rest[1]]);
+        let address = Address::from_inverted_slice(&rest[3..9]);
+        let rssi = rest[13];
+        let data_len = rest[23] as usize;
+        let data = rest
+            .get(REPORT_HEADER_LEN..REPORT_HEADER_LEN + data_len)
+            .ok_or("Extended advertising data exceeds event")?;
+        rest = &rest[REPORT_HEADER_LEN + data_len..];
+
+        // Bits 5-6 of the event type contain the data status
+        let data_status = (event_type >> 5) & 0b11;
+        if data_status != 0 {
+            log::debug!(
+                "Ignoring extended advertising report with incomplete data from {}",
+                address
+            );
+            continue;
+        }
+
+        reports.push(AdvertisingReport {
+            address,
+            rssi,
+            kind: ReportKind::from_extended_event_type(event_type),
+            data: parse_ad_structures(data)?,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    const AD_DATA: [u8; 15] = [
+        // Complete local name
+        8, 0x09, b'S', b'e', b'n', b's', b'i', b'l', b'o',
+        // Manufacturer specific data
+        5, 0xff, 0xff, 0xff, 52, 4,
+    ];
+
+    #[test]
+    fn test_parse_ad_structures() {
+        let structures = parse_ad_structures(&AD_DATA).unwrap();
+        assert_eq!(
+            structures,
+            vec![
+                AdStructure::CompleteLocalName("Sensilo".into()),
+                AdStructure::ManufacturerSpecificData {
+                    company_identifier: 0xffff,
+                    data: vec![52, 4],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_service_data() {
+        let structures = parse_ad_structures(&[6, 0x16, 0xd2, 0xfc, 0x40, 0x00, 0x01]).unwrap();
+        assert_eq!(
+            structures,
+            vec![AdStructure::ServiceData16 {
+                uuid: 0xfcd2,
+                data: vec![0x40, 0x00, 0x01],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_ad_structures_truncated() {
+        assert!(parse_ad_structures(&AD_DATA[..12]).is_err());
+    }
+
+    #[test]
+    fn test_parse_advertising_report() {
+        #[rustfmt::skip]
+        let mut params = vec![
+            // Subevent code, number of reports
+            0x02, 2,
+            // Event type (ADV_NONCONN_IND), address type, address
+            0x03, 0x01, 0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86,
+            // Data length
+            15,
+        ];
+        params.extend_from_slice(&AD_DATA);
+        #[rustfmt::skip]
+        params.extend_from_slice(&[
+            // RSSI
+            0xc4,
+            // Second report without data
+            0x03, 0x01, 0x7b, 0x99, 0x67, 0xe0, 0x4f, 0x86, 0, 0xb0,
+        ]);
+        let reports = parse_advertising_report(&params).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].address, Address::from_hex("864fe067997a"));
+        assert_eq!(reports[0].rssi, 0xc4);
+        assert_eq!(reports[0].kind, ReportKind::NonScannable);
+        assert_eq!(reports[0].data.len(), 2);
+        assert_eq!(reports[1].address, Address::from_hex("864fe067997b"));
+        assert_eq!(reports[1].rssi, 0xb0);
+        assert!(reports[1].data.is_empty());
+
+        // Truncated event
+        for len in 0..params.len() - 10 {
+            assert!(parse_advertising_report(&params[..len]).is_err());
+        }
+
+        // SCAN_RSP
+        params[2] = 0x04;
+        let reports = parse_advertising_report(&params).unwrap();
+        assert_eq!(reports[0].kind, ReportKind::ScanResponse);
+    }
+
+    #[test]
+    fn test_parse_extended_advertising_report() {
+        #[rustfmt::skip]
+        let mut params = vec![
+            // Subevent code, number of reports
+            0x0d, 1,
+            // Event type (legacy, complete), address type, address
+            0x10, 0x00, 0x01, 0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86,
+            // Primary PHY, secondary PHY, SID, TX power, RSSI
+            0x01, 0x00, 0xff, 0x7f, 0xc4,
+            // Periodic advertising interval, direct address type, direct address
+            0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0,
+            // Data length
+            15,
+        ];
+        params.extend_from_slice(&AD_DATA);
+        let reports = parse_extended_advertising_report(&params).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, Address::from_hex("864fe067997a"));
+        assert_eq!(reports[0].rssi, 0xc4);
+        assert_eq!(reports[0].kind, ReportKind::NonScannable);
+        assert_eq!(reports[0].data.len(), 2);
+
+        // Legacy scan response to an ADV_SCAN_IND
+        params[2] = 0x1a;
+        let reports = parse_extended_advertising_report(&params).unwrap();
+        assert_eq!(reports[0].kind, ReportKind::ScanResponse);
+        params[2] = 0x12;
+        let reports = parse_extended_advertising_report(&params).unwrap();
+        assert_eq!(reports[0].kind, ReportKind::Scannable);
+
+        // Incomplete data is skipped
+        params[2] = 0x30;
+        assert!(parse_extended_advertising_report(&params)
+            .unwrap()
+            .is_empty());
+
+        // Truncated event
+        assert!(parse_extended_advertising_report(&params[..30]).is_err());
+    }
+}
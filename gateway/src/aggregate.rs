@@ -0,0 +1,375 @@
+//! Aggregate the measurements of a device over a time window.
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::config::{self, AggregationFunction};
+use crate::measurement::{
+    AmbientLight, AnalogInput, Humidity, LightCounts, Measurement, Pulses, Status, Temperature,
+};
+use crate::types::Address;
+
+struct Bucket {
+    start: Instant,
+    measurements: Vec<Measurement>,
+}
+
+/// Collects measurements and combines them into one measurement per device
+/// and window.
+///
+/// If aggregation is disabled, all measurements are passed through.
+pub struct Aggregator {
+    window: Option<Duration>,
+    function: AggregationFunction,
+    buckets: HashMap<Address, Bucket>,
+}
+
+impl Aggregator {
+    pub fn new(config: Option<&config::Aggregation>) -> Self {
+        Self {
+            window: config.map(|c| Duration::from_secs(c.window_s)),
+            function: config.map_or(AggregationFunction::Last, |c| c.function),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Add a measurement.
+    ///
+    /// Return the aggregated measurement of the previous window, if the
+    /// measurement does not belong to the current window of the device.
+    pub fn add(&mut self, measurement: Measurement, now: Instant) -> Vec<Measurement> {
+        let window = match self.window {
+            Some(window) => window,
+            None => return vec![measurement],
+        };
+        let mut ready = vec![];
+        if let Some(bucket) = self.buckets.get(&measurement.address) {
+            if now.duration_since(bucket.start) >= window {
+                let bucket = self.buckets.remove(&measurement.address).unwrap();
+                ready.extend(aggregate(bucket.measurements, self.function));
+            }
+        }
+        self.buckets
+            .entry(measurement.address)
+            .or_insert_with(|| Bucket {
+                start: now,
+                measurements: vec![],
+            })
+            .measurements
+            .push(measurement);
+        ready
+    }
+
+    /// Remove and return the aggregated measurements of all windows that
+    /// have ended.
+    pub fn expire(&mut self, now: Instant) -> Vec<Measurement> {
+        let window = match self.window {
+            Some(window) => window,
+            None => return vec![],
+        };
+        let expired: Vec<Address> = self
+            .buckets
+            .iter()
+            .filter(|(_, bucket)| now.duration_since(bucket.start) >= window)
+            .map(|(address, _)| *address)
+            .collect();
+        let function = self.function;
+        expired
+            .into_iter()
+            .filter_map(|address| self.buckets.remove(&address))
+            .filter_map(|bucket| aggregate(bucket.measurements, function))
+            .collect()
+    }
+
+    /// Remove and return the aggregated measurements of all windows.
+    pub fn drain(&mut self) -> Vec<Measurement> {
+        let function = self.function;
+        self.buckets
+            .drain()
+            .filter_map(|(_, bucket)| aggregate(bucket.measurements, function))
+            .collect()
+    }
+}
+
+/// Combine the values of a single field.
+fn combine(values: impl Iterator<Item = f64>, function: AggregationFunction) -> Option<f64> {
+    let mut count = 0;
+    let mut result: Option<f64> = None;
+    for value in values {
+        count += 1;
+        result = Some(match (result, function) {
+            (None, _) | (_, AggregationFunction::Last) => value,
+            (Some(acc), AggregationFunction::Mean) => acc + value,
+            (Some(acc), AggregationFunction::Min) => acc.min(value),
+            (Some(acc), AggregationFunction::Max) => acc.max(value),
+        });
+    }
+    match function {
+        AggregationFunction::Mean => result.map(|sum| sum / f64::from(count)),
+        _ => result,
+    }
+}
+
+/// Combine measurements into a single one. Counter and local name are taken
+/// from the last measurement.
+fn aggregate(measurements: Vec<Measurement>, function: AggregationFunction) -> Option<Measurement> {
+    macro_rules! field {
+        ($field:ident, $get:ident) => {
+            field!(measurements.iter(), $field, $get)
+        };
+        ($measurements:expr, $field:ident, $get:ident) => {
+            combine(
+                $measurements
+                    .filter_map(|m| m.$field.as_ref())
+                    .map(|v| f64::from(v.$get())),
+                function,
+            )
+        };
+    }
+
+    // The temperature and humidity measured shortly after a heater pulse are
+    // off, they are only used if there are no others
+    let heated = |m: &&Measurement| m.status.is_some_and(Status::heated);
+    let climate: Vec<&Measurement> = if measurements.iter().all(|m| heated(&m)) {
+        measurements.iter().collect()
+    } else {
+        measurements.iter().filter(|m| !heated(m)).collect()
+    };
+    let temperature = field!(
+        climate.iter().copied(),
+        temperature,
+        as_millidegrees_celsius
+    )
+    .map(|v| Temperature::from_millidegrees_celsius(v.round() as i32));
+    let humidity = field!(climate.iter().copied(), humidity, as_millipercent)
+        .map(|v| Humidity::from_millipercent(v.round() as i32));
+    let ambient_light = field!(ambient_light, as_lux).map(|v| AmbientLight::from_lux(v as f32));
+    let ambient_light_als =
+        field!(ambient_light_als, as_counts).map(|v| LightCounts::from_counts(v.round() as u16));
+    let ambient_light_white =
+        field!(ambient_light_white, as_counts).map(|v| LightCounts::from_counts(v.round() as u16));
+    let thermocouple_temperature = field!(thermocouple_temperature, as_millidegrees_celsius)
+        .map(|v| Temperature::from_millidegrees_celsius(v.round() as i32));
+    let secondary_temperature = field!(secondary_temperature, as_millidegrees_celsius)
+        .map(|v| Temperature::from_millidegrees_celsius(v.round() as i32));
+    let secondary_humidity = field!(secondary_humidity, as_millipercent)
+        .map(|v| Humidity::from_millipercent(v.round() as i32));
+    let mut external_temperatures = BTreeMap::new();
+    let indices: Vec<u8> = measurements
+        .iter()
+        .flat_map(|m| m.external_temperatures.keys().copied())
+        .collect();
+    for index in indices {
+        if external_temperatures.contains_key(&index) {
+            continue;
+        }
+        let values = measurements
+            .iter()
+            .filter_map(|m| m.external_temperatures.get(&index))
+            .map(|v| f64::from(v.as_millidegrees_celsius()));
+        if let Some(v) = combine(values, function) {
+            let temperature = Temperature::from_millidegrees_celsius(v.round() as i32);
+            external_temperatures.insert(index, temperature);
+        }
+    }
+    let mut analog = BTreeMap::new();
+    for mmt in measurements.iter().rev() {
+        for (channel, input) in &mmt.analog {
+            if analog.contains_key(channel) {
+                continue;
+            }
+            let values = measurements
+                .iter()
+                .filter_map(|m| m.analog.get(channel))
+                .map(|input| f64::from(input.millivolts));
+            if let Some(v) = combine(values, function) {
+                let input = AnalogInput {
+                    millivolts: v.round() as u16,
+                    name: input.name.clone(),
+                };
+                analog.insert(*channel, input);
+            }
+        }
+    }
+    // The latest count, with the pulses of all measurements in the window
+    let pulses =
+        measurements
+            .iter()
+            .filter_map(|m| m.pulses)
+            .fold(None, |acc: Option<Pulses>, pulses| {
+                Some(Pulses {
+                    count: pulses.count,
+                    delta: acc.map_or(0, |acc| acc.delta).saturating_add(pulses.delta),
+                })
+            });
+    let pulse_rate = combine(measurements.iter().filter_map(|m| m.pulse_rate), function);
+    // The RSSI is a signed value
+    let rssi = combine(
+        measurements.iter().map(|m| f64::from(m.rssi as i8)),
+        function,
+    )
+    .map(|v| v.round() as i8 as u8);
+
+    let last = measurements.into_iter().last()?;
+    Some(Measurement {
+        rssi: rssi.unwrap_or(last.rssi),
+        frame: None,
+        temperature,
+        humidity,
+        ambient_light,
+        ambient_light_als,
+        ambient_light_white,
+        thermocouple_temperature,
+        secondary_temperature,
+        secondary_humidity,
+        external_temperatures,
+        pulses,
+        pulse_rate,
+        analog,
+        raw: None,
+        ..last
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::MeasurementBuilder;
+
+    fn measurement(counter: u16, rssi: u8, temperature: i32) -> Measurement {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), rssi);
+        builder
+            .local_name("Sensilo")
+            .counter(counter)
+            .temperature(Temperature::from_millidegrees_celsius(temperature));
+        builder.build().unwrap()
+    }
+
+    fn aggregator(function: AggregationFunction) -> Aggregator {
+        Aggregator::new(Some(&config::Aggregation {
+            window_s: 60,
+            function,
+        }))
+    }
+
+    #[test]
+    fn disabled() {
+        let mut aggregator = Aggregator::new(None);
+        let ready = aggregator.add(measurement(1, 0xc4, 20000), Instant::now());
+        assert_eq!(ready.len(), 1);
+        assert!(aggregator.drain().is_empty());
+    }
+
+    #[test]
+    fn mean() {
+        let mut aggregator = aggregator(AggregationFunction::Mean);
+        let now = Instant::now();
+        assert!(aggregator.add(measurement(1, 0xc4, 20000), now).is_empty());
+        assert!(aggregator
+            .add(measurement(2, 0xc2, 21001), now + Duration::from_secs(3))
+            .is_empty());
+        assert!(aggregator.expire(now + Duration::from_secs(59)).is_empty());
+
+        let ready = aggregator.add(measurement(3, 0xc4, 0), now + Duration::from_secs(60));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].counter, 2);
+        assert_eq!(ready[0].rssi, 0xc3);
+        assert_eq!(
+            ready[0].temperature,
+            Some(Temperature::from_millidegrees_celsius(20501))
+        );
+        assert!(ready[0].humidity.is_none());
+
+        let ready = aggregator.drain();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].counter, 3);
+    }
+
+    #[test]
+    fn expire_per_device() {
+        let mut aggregator = aggregator(AggregationFunction::Last);
+        let now = Instant::now();
+        let mut other = measurement(1, 0xc4, 19000);
+        other.address = Address([6, 5, 4, 3, 2, 1]);
+        aggregator.add(measurement(1, 0xc4, 20000), now);
+        aggregator.add(other, now + Duration::from_secs(30));
+
+        // Only the window of the first device has ended, without a further
+        // measurement
+        let ready = aggregator.expire(now + Duration::from_secs(60));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].address, Address([1, 2, 3, 4, 5, 6]));
+        assert!(aggregator.expire(now + Duration::from_secs(61)).is_empty());
+        let ready = aggregator.expire(now + Duration::from_secs(90));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].address, Address([6, 5, 4, 3, 2, 1]));
+        assert!(aggregator.drain().is_empty());
+    }
+
+    #[test]
+    fn heated() {
+        let mut aggregator = aggregator(AggregationFunction::Mean);
+        let now = Instant::now();
+        let mut heated = measurement(1, 0xc4, 30000);
+        heated.status = Some(Status { flags: 0b10 });
+        aggregator.add(heated.clone(), now);
+        aggregator.add(measurement(2, 0xc4, 20000), now + Duration::from_secs(3));
+        let ready = aggregator.drain();
+        assert_eq!(
+            ready[0].temperature,
+            Some(Temperature::from_millidegrees_celsius(20000))
+        );
+
+        // Only heated measurements
+        aggregator.add(heated, now);
+        let ready = aggregator.drain();
+        assert_eq!(
+            ready[0].temperature,
+            Some(Temperature::from_millidegrees_celsius(30000))
+        );
+    }
+
+    #[test]
+    fn pulses() {
+        let mut aggregator = aggregator(AggregationFunction::Mean);
+        let now = Instant::now();
+        for (i, (delta, rate)) in [(3, 1.0), (9, 3.0)].iter().enumerate() {
+            let mut mmt = measurement(i as u16, 0xc4, 20000);
+            mmt.pulses = Some(Pulses {
+                count: 100 + i as u32 * 9,
+                delta: *delta,
+            });
+            mmt.pulse_rate = Some(*rate);
+            aggregator.add(mmt, now);
+        }
+        let ready = aggregator.expire(now + Duration::from_secs(60));
+        assert_eq!(
+            ready[0].pulses,
+            Some(Pulses {
+                count: 109,
+                delta: 12
+            })
+        );
+        assert_eq!(ready[0].pulse_rate, Some(2.0));
+    }
+
+    #[test]
+    fn min_max_last() {
+        for (function, expected) in &[
+            (AggregationFunction::Min, 19000),
+            (AggregationFunction::Max, 22000),
+            (AggregationFunction::Last, 20000),
+        ] {
+            let mut aggregator = aggregator(*function);
+            let now = Instant::now();
+            for (i, temperature) in [19000, 22000, 20000].iter().enumerate() {
+                aggregator.add(measurement(i as u16, 0xc4, *temperature), now);
+            }
+            let ready = aggregator.expire(now + Duration::from_secs(60));
+            assert_eq!(
+                ready[0].temperature,
+                Some(Temperature::from_millidegrees_celsius(*expected))
+            );
+        }
+    }
+}
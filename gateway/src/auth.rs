@@ -0,0 +1,149 @@
+//! Verification of authenticated Sensilo payloads.
+//!
+//! If a key is provisioned on a device, every frame ends with a MAC entry
+//! (type `0x0d`). The MAC is the HMAC-SHA256 (truncated to 4 bytes) of the
+//! device address, the payload after the company identifier (up to the MAC
+//! value) and the MAC entry type (and length, since protocol version 2).
+//!
+//! The MAC is verified before any other field of the payload is parsed, and
+//! the decoder rejects payloads whose counter is older than the last accepted
+//! one (see `decoder`), so recorded frames can't be replayed later.
+//!
+//! A 4-byte tag is weak: A forgery succeeds with a probability of 2^-32 per
+//! attempt, i.e. an attacker who can send frames for long enough will get one
+//! accepted eventually. It only protects against casual forgeries, and
+//! increasing `verification_failures` should be treated as an attack.
+use anyhow::{bail, Result};
+use ring::hmac;
+
+use crate::measurement::entry_header_len;
+use crate::types::Address;
+
+/// Entry type of the MAC.
+const SENSOR_MAC: u8 = 0x0d;
+
+/// Length of the (truncated) MAC.
+const MAC_LEN: usize = 4;
+
+/// Length of a device key in bytes.
+const KEY_LEN: usize = 16;
+
+/// Key of a device, for the payload verification.
+pub struct PayloadKey(hmac::Key);
+
+impl PayloadKey {
+    /// Parse a key from 32 hex digits.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        match base16::decode(hex) {
+            Ok(key) if key.len() == KEY_LEN => Ok(Self(hmac::Key::new(hmac::HMAC_SHA256, &key))),
+            _ => bail!("Invalid key (expected {} hex digits)", 2 * KEY_LEN),
+        }
+    }
+
+    fn mac(&self, address: Address, data: &[u8]) -> [u8; MAC_LEN] {
+        let mut context = hmac::Context::with_key(&self.0);
+        context.update(&address.0);
+        context.update(data);
+        let tag = context.sign();
+        let mut mac = [0; MAC_LEN];
+        mac.copy_from_slice(&tag.as_ref()[..MAC_LEN]);
+        mac
+    }
+
+    /// Verify the MAC at the end of a payload (without company identifier).
+    /// On success, return the payload without the MAC entry.
+    pub fn verify<'a>(
+        &self,
+        address: Address,
+        payload: &'a [u8],
+    ) -> Result<&'a [u8], &'static str> {
+        // At least the counter and the MAC entry header (of either protocol
+        // version)
+        let split = payload
+            .len()
+            .checked_sub(MAC_LEN)
+            .filter(|&split| split >= 3)
+            .ok_or("Missing MAC")?;
+        let (data, mac) = payload.split_at(split);
+        let header: &[u8] = match entry_header_len(data) {
+            1 => &[SENSOR_MAC],
+            _ => &[SENSOR_MAC, MAC_LEN as u8],
+        };
+        let has_header = data.len() >= 2 + header.len() && data.ends_with(header);
+        // Truncated tags are compared with `verify_slices_are_equal` to avoid
+        // timing side channels. Nothing in the payload is trusted before, the
+        // header only tells unauthenticated payloads apart from forged ones.
+        match ring::constant_time::verify_slices_are_equal(&self.mac(address, data), mac) {
+            Ok(()) if has_header => {}
+            Err(_) if has_header => return Err("Invalid MAC"),
+            _ => return Err("Missing MAC"),
+        }
+        Ok(&data[..data.len() - header.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "00112233445566778899aabbccddeeff";
+
+    /// Payload with a temperature entry, as written by the firmware.
+    #[rustfmt::skip]
+    const PAYLOAD: [u8; 12] = [
+        0x34, 0x12,
+        0x01, 0xde, 0x58, 0x00, 0x00,
+        0x0d, 0x25, 0x4a, 0x0e, 0x49,
+    ];
+
+    #[test]
+    fn verify() {
+        let key = PayloadKey::from_hex(KEY).unwrap();
+        let address = Address::from_hex("864fe067997a");
+        assert_eq!(key.verify(address, &PAYLOAD), Ok(&PAYLOAD[..7]));
+
+        // The address is authenticated
+        let other = Address::from_hex("864fe067997b");
+        assert_eq!(key.verify(other, &PAYLOAD), Err("Invalid MAC"));
+
+        // Every byte is authenticated
+        for i in 0..PAYLOAD.len() {
+            let mut payload = PAYLOAD;
+            payload[i] ^= 1;
+            assert!(key.verify(address, &payload).is_err(), "Byte {}", i);
+        }
+
+        // Unauthenticated payloads
+        assert_eq!(key.verify(address, &PAYLOAD[..7]), Err("Missing MAC"));
+        assert_eq!(key.verify(address, &PAYLOAD[7..]), Err("Missing MAC"));
+        assert_eq!(key.verify(address, &[]), Err("Missing MAC"));
+    }
+
+    #[test]
+    fn verify_v2() {
+        let key = PayloadKey::from_hex(KEY).unwrap();
+        let address = Address::from_hex("864fe067997a");
+        #[rustfmt::skip]
+        let mut payload = vec![
+            0x34, 0x12,
+            0x00, 0x02, 0x01,
+            0x01, 0x04, 0xde, 0x58, 0x00, 0x00,
+            0x0d, 0x04,
+        ];
+        let mac = key.mac(address, &payload);
+        payload.extend_from_slice(&mac);
+        assert_eq!(key.verify(address, &payload), Ok(&payload[..11]));
+
+        // The MAC entry has a length in protocol version 2
+        payload.remove(12);
+        assert_eq!(key.verify(address, &payload), Err("Missing MAC"));
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(PayloadKey::from_hex("").is_err());
+        assert!(PayloadKey::from_hex(&KEY[..30]).is_err());
+        assert!(PayloadKey::from_hex(&format!("{}00", KEY)).is_err());
+        assert!(PayloadKey::from_hex(&KEY.replace('0', "x")).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Availability of the devices, based on the age of their last measurement.
+//!
+//! A device is online from its first measurement until no measurement was
+//! received within its timeout. Consumers like Home Assistant then mark the
+//! values of the device as unavailable, instead of showing stale values as
+//! current.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::types::Address;
+
+/// Timeout of devices without a configured interval.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Number of measurement intervals after which a device is offline.
+const MISSED_INTERVALS: u32 = 3;
+
+struct Device {
+    local_name: String,
+    last_seen: Instant,
+    online: bool,
+}
+
+pub struct Availability {
+    timeouts: HashMap<Address, Duration>,
+    devices: HashMap<Address, Device>,
+}
+
+impl Availability {
+    /// Devices are offline after `timeout`, or (by default) after three
+    /// missed measurements (with the configured interval). The addresses must
+    /// be in the same order as the devices in the config.
+    pub fn new(
+        devices: &[config::Device],
+        addresses: &[Address],
+        timeout: Option<Duration>,
+    ) -> Self {
+        let timeouts = devices
+            .iter()
+            .zip(addresses)
+            .map(|(device, address)| {
+                let interval = device.interval_s.map(Duration::from_secs);
+                let timeout = timeout
+                    .or_else(|| interval.map(|interval| interval * MISSED_INTERVALS))
+                    .unwrap_or(DEFAULT_TIMEOUT);
+                (*address, timeout)
+            })
+            .collect();
+        Self {
+            timeouts,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Record a measurement of a device. Return `true` if the device came
+    /// online.
+    pub fn seen(&mut self, address: Address, local_name: &str, now: Instant) -> bool {
+        let device = self.devices.entry(address).or_insert_with(|| Device {
+            local_name: local_name.to_string(),
+            last_seen: now,
+            online: false,
+        });
+        device.local_name = local_name.to_string();
+        device.last_seen = device.last_seen.max(now);
+        !std::mem::replace(&mut device.online, true)
+    }
+
+    /// The devices (with their local name) that went offline.
+    pub fn expire(&mut self, now: Instant) -> Vec<(Address, String)> {
+        let timeouts = &self.timeouts;
+        self.devices
+            .iter_mut()
+            .filter(|(address, device)| {
+                let timeout = timeouts.get(address).copied().unwrap_or(DEFAULT_TIMEOUT);
+                device.online && now.saturating_duration_since(device.last_seen) >= timeout
+            })
+            .map(|(address, device)| {
+                device.online = false;
+                (*address, device.local_name.clone())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn device(interval_s: Option<u64>) -> config::Device {
+        config::Device {
+            name: "Sensilo".into(),
+            hex_addr: "010203040506".into(),
+            location: None,
+            site: None,
+            interval_s,
+            protocol: config::Protocol::Sensilo,
+            analog: vec![],
+            expects: vec![],
+            key: None,
+            crc: false,
+            irk: None,
+            min_rssi: None,
+        }
+    }
+
+    #[test]
+    fn online_offline() {
+        let mut availability = Availability::new(&[device(Some(10))], &[ADDR], None);
+        let now = Instant::now();
+        assert!(availability.seen(ADDR, "Sensilo", now));
+        assert!(!availability.seen(ADDR, "Sensilo", now + Duration::from_secs(10)));
+        assert!(availability
+            .expire(now + Duration::from_secs(39))
+            .is_empty());
+        assert_eq!(
+            availability.expire(now + Duration::from_secs(40)),
+            vec![(ADDR, "Sensilo".to_string())]
+        );
+        // Only reported once
+        assert!(availability
+            .expire(now + Duration::from_secs(50))
+            .is_empty());
+        assert!(availability.seen(ADDR, "Sensilo", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut availability = Availability::new(&[device(None)], &[ADDR], None);
+        let now = Instant::now();
+        availability.seen(ADDR, "Sensilo", now);
+        assert!(availability
+            .expire(now + Duration::from_secs(299))
+            .is_empty());
+        assert_eq!(availability.expire(now + DEFAULT_TIMEOUT).len(), 1);
+
+        // The configured timeout takes precedence
+        let timeout = Some(Duration::from_secs(5));
+        let mut availability = Availability::new(&[device(Some(10))], &[ADDR], timeout);
+        availability.seen(ADDR, "Sensilo", now);
+        assert_eq!(availability.expire(now + Duration::from_secs(5)).len(), 1);
+    }
+}
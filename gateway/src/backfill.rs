@@ -0,0 +1,155 @@
+//! Reconstruct missed measurements from the backlog samples of the devices.
+//!
+//! Nodes with a backlog send one of their past measurements along with every
+//! measurement. When the gateway missed a measurement (e.g. during a brief
+//! outage of the gateway or its Bluetooth adapter), the backlog sample with
+//! its counter is passed to the sinks later. Its timestamp is interpolated
+//! between the measurements received before and after the gap (it's
+//! calculated back from the latter with the monotonic clock, so it's
+//! approximate if the host clock wasn't synchronized when the gap was
+//! detected).
+use std::collections::{HashMap, VecDeque};
+use std::time::SystemTime;
+
+use crate::gaps::Gap;
+use crate::measurement::{Measurement, MeasurementBuilder};
+use crate::types::Address;
+
+/// Maximum number of missed measurements remembered per device (the oldest
+/// are dropped).
+const MAX_MISSED: usize = 2000;
+
+/// Remembers the missed measurements of every device.
+pub struct Backfill {
+    /// Counter, estimated timestamp and whether that timestamp is
+    /// approximate, of the missed measurements, oldest first
+    missed: HashMap<Address, VecDeque<(u16, SystemTime, bool)>>,
+}
+
+impl Backfill {
+    pub fn new() -> Self {
+        Self {
+            missed: HashMap::new(),
+        }
+    }
+
+    /// Remember the measurements missed before a received measurement.
+    pub fn add_gap(&mut self, mmt: &Measurement, gap: &Gap) {
+        let step = gap.elapsed / (u32::from(gap.missed) + 1);
+        let missed = self.missed.entry(mmt.address).or_default();
+        for age in (1..=gap.missed).rev() {
+            let timestamp = mmt
+                .timestamp
+                .checked_sub(step * u32::from(age))
+                .unwrap_or(mmt.timestamp);
+            missed.push_back((mmt.counter.wrapping_sub(age), timestamp, mmt.approx_time));
+        }
+        while missed.len() > MAX_MISSED {
+            missed.pop_front();
+        }
+    }
+
+    /// Return the measurement reconstructed from the backlog sample of a
+    /// measurement, if that measurement was missed (only once).
+    pub fn take(&mut self, mmt: &Measurement) -> Option<Measurement> {
+        let sample = mmt.backlog.as_ref()?;
+        let counter = mmt.counter.wrapping_sub(sample.age);
+        let missed = self.missed.get_mut(&mmt.address)?;
+        let index = missed.iter().position(|(c, _, _)| *c == counter)?;
+        let (_, timestamp, approx_time) = missed.remove(index)?;
+
+        let mut builder = MeasurementBuilder::new(mmt.address, mmt.rssi);
+        builder
+            .timestamp(timestamp)
+            .local_name(&mmt.local_name)
+            .counter(counter)
+            .temperature(sample.temperature.clone())
+            .humidity(sample.humidity.clone());
+        let mut backfilled = builder.build().ok()?;
+        backfilled.backfilled = true;
+        backfilled.approx_time = approx_time;
+        Some(backfilled)
+    }
+
+    /// Forget the missed measurements of a device (e.g. after a reboot, when
+    /// the counters restart).
+    pub fn reset(&mut self, address: Address) {
+        self.missed.remove(&address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::measurement::{BacklogSample, Humidity, Temperature};
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn measurement(counter: u16, secs: u64, backlog_age: Option<u16>) -> Measurement {
+        let mut builder = MeasurementBuilder::new(ADDR, 200);
+        builder
+            .timestamp(UNIX_EPOCH + Duration::from_secs(secs))
+            .local_name("Sensilo")
+            .counter(counter)
+            .temperature(Temperature::from_millidegrees_celsius(21000));
+        if let Some(age) = backlog_age {
+            builder.backlog(BacklogSample {
+                age,
+                temperature: Temperature::from_millidegrees_celsius(19500),
+                humidity: Humidity::from_millipercent(40000),
+            });
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn backfill() {
+        let mut backfill = Backfill::new();
+        // Measurements 11-14 were missed, 3 s apart
+        let gap = Gap {
+            missed: 4,
+            elapsed: Duration::from_secs(15),
+            duration: None,
+        };
+        backfill.add_gap(&measurement(15, 1000, None), &gap);
+
+        // Received before the gap
+        assert!(backfill.take(&measurement(16, 1003, Some(6))).is_none());
+        let mmt = backfill.take(&measurement(16, 1003, Some(4))).unwrap();
+        assert!(mmt.backfilled);
+        assert_eq!(mmt.counter, 12);
+        assert_eq!(mmt.timestamp, UNIX_EPOCH + Duration::from_secs(991));
+        assert_eq!(mmt.temperature.unwrap().as_millidegrees_celsius(), 19500);
+        assert_eq!(mmt.humidity.unwrap().as_millipercent(), 40000);
+        assert!(mmt.backlog.is_none());
+        // Only once
+        assert!(backfill.take(&measurement(17, 1006, Some(5))).is_none());
+
+        backfill.reset(ADDR);
+        assert!(backfill.take(&measurement(17, 1006, Some(6))).is_none());
+    }
+
+    #[test]
+    fn approx_time() {
+        let mut backfill = Backfill::new();
+        let gap = Gap {
+            missed: 1,
+            elapsed: Duration::from_secs(6),
+            duration: None,
+        };
+        let mut after_gap = measurement(15, 1000, None);
+        after_gap.approx_time = true;
+        backfill.add_gap(&after_gap, &gap);
+
+        // The state when the gap was detected counts, not the current one
+        let mmt = backfill.take(&measurement(16, 1003, Some(2))).unwrap();
+        assert!(mmt.approx_time);
+        backfill.add_gap(&measurement(17, 1009, None), &gap);
+        let mut current = measurement(18, 1012, Some(2));
+        current.approx_time = true;
+        assert!(!backfill.take(&current).unwrap().approx_time);
+    }
+}
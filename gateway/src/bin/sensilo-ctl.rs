@@ -0,0 +1,475 @@
+//! Command line client for the API of the Sensilo Gateway.
+//!
+//! Talks HTTP/1.1 to the gateway (see the `[api]` section of the gateway
+//! config), without any dependencies besides serde_json (and rustls for
+//! HTTPS).
+#[cfg(feature = "api-tls")]
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+#[cfg(feature = "api-tls")]
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(feature = "api-tls")]
+use rustls::{ClientConfig, ClientSession, StreamOwned};
+
+use serde_json::Value;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8080";
+
+fn print_usage(program: &str) {
+    println!("Sensilo Gateway Control\n");
+    println!(
+        "Usage: {} [--url URL] [--token TOKEN] [--cacert FILE] COMMAND",
+        program
+    );
+    println!();
+    println!("Commands:");
+    println!("  devices  List the configured devices");
+    println!("  latest   Show the last measurement of every device");
+    println!("  tail     Print the measurements as they arrive (as JSON lines)");
+    println!("  reload   Reload the config of the gateway");
+    println!("  frames   Dump the retained raw frames and parse errors");
+    println!();
+    println!(
+        "The URL of the gateway defaults to $SENSILO_URL, or {}.",
+        DEFAULT_URL
+    );
+    println!("The token defaults to $SENSILO_TOKEN.");
+    println!(
+        "The CA certificate of an https:// URL (e.g. the self-signed certificate \
+         of the gateway) defaults to $SENSILO_CACERT, or the Mozilla root certificates."
+    );
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    https: bool,
+    host: String,
+    port: u16,
+}
+
+/// Parse an `http://` or `https://` URL (a path is ignored).
+fn parse_url(url: &str) -> Result<Url> {
+    let (https, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(rest), _) => (false, rest),
+        (_, Some(rest)) => (true, rest),
+        _ => bail!("Unsupported URL (expected http:// or https://): {}", url),
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) if !authority.ends_with(']') => {
+            let port = authority[colon + 1..]
+                .parse()
+                .with_context(|| format!("Invalid port in {}", url))?;
+            (&authority[..colon], port)
+        }
+        _ if authority.is_empty() => bail!("Missing host in {}", url),
+        _ => (authority, if https { 443 } else { 80 }),
+    };
+    Ok(Url {
+        https,
+        host: host.to_string(),
+        port,
+    })
+}
+
+/// The TLS config of the client, trusting the CA certificates of the file
+/// (or the Mozilla root certificates).
+#[cfg(feature = "api-tls")]
+fn tls_config(cacert: Option<&str>) -> Result<Arc<ClientConfig>> {
+    let mut tls = ClientConfig::new();
+    match cacert {
+        Some(path) => {
+            let file = File::open(path).with_context(|| format!("Could not open {}", path))?;
+            let (valid, _) = tls
+                .root_store
+                .add_pem_file(&mut BufReader::new(file))
+                .map_err(|_| anyhow!("Invalid CA file {}", path))?;
+            if valid == 0 {
+                bail!("No valid CA certificates in {}", path);
+            }
+        }
+        None => tls
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    Ok(Arc::new(tls))
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+/// A TLS stream that ends with the `close_notify` of the gateway, which
+/// rustls reports as error.
+#[cfg(feature = "api-tls")]
+struct TlsStream(StreamOwned<ClientSession, TcpStream>);
+
+#[cfg(feature = "api-tls")]
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => Ok(0),
+            result => result,
+        }
+    }
+}
+
+#[cfg(feature = "api-tls")]
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+struct Client {
+    host: String,
+    port: u16,
+    /// Token for the API (if it is protected)
+    token: Option<String>,
+    #[cfg(feature = "api-tls")]
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl Client {
+    #[cfg_attr(not(feature = "api-tls"), allow(unused_variables))]
+    fn new(url: &Url, token: Option<String>, cacert: Option<&str>) -> Result<Self> {
+        #[cfg(feature = "api-tls")]
+        let tls = if url.https {
+            Some(tls_config(cacert)?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "api-tls"))]
+        if url.https {
+            bail!("HTTPS is not included in this build (feature api-tls)");
+        }
+        Ok(Self {
+            host: url.host.clone(),
+            port: url.port,
+            token,
+            #[cfg(feature = "api-tls")]
+            tls,
+        })
+    }
+
+    fn connect(&self) -> Result<Box<dyn ReadWrite>> {
+        let ip_literal = self.host.trim_start_matches('[').trim_end_matches(']');
+        let tcp = TcpStream::connect((ip_literal, self.port))
+            .with_context(|| format!("Could not connect to {}:{}", self.host, self.port))?;
+        #[cfg(feature = "api-tls")]
+        if let Some(ref tls) = self.tls {
+            let hostname = webpki::DNSNameRef::try_from_ascii_str(ip_literal)
+                .map_err(|_| anyhow!("Invalid hostname (HTTPS needs a DNS name): {}", self.host))?;
+            let session = ClientSession::new(tls, hostname);
+            return Ok(Box::new(TlsStream(StreamOwned::new(session, tcp))));
+        }
+        Ok(Box::new(tcp))
+    }
+
+    /// Send a request and return the reader, positioned at the body, if the
+    /// status is successful.
+    fn request(&self, method: &str, path: &str) -> Result<BufReader<Box<dyn ReadWrite>>> {
+        let mut stream = self.connect()?;
+        let authorization = match self.token {
+            Some(ref token) => format!("Authorization: Bearer {}\r\n", token),
+            None => String::new(),
+        };
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\n{}Connection: close\r\nContent-Length: 0\r\n\r\n",
+            method, path, self.host, authorization
+        )?;
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line == "\r\n" {
+                break;
+            }
+        }
+        let status = status.trim_end();
+        let code = status.split(' ').nth(1).unwrap_or_default();
+        if !code.starts_with('2') {
+            let mut body = String::new();
+            let _ = reader.read_to_string(&mut body);
+            bail!("{} {}: {} {}", method, path, status, body.trim_end());
+        }
+        Ok(reader)
+    }
+
+    /// Send a request and return the body.
+    fn body(&self, method: &str, path: &str) -> Result<String> {
+        let mut body = String::new();
+        self.request(method, path)?.read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    fn json(&self, path: &str) -> Result<Vec<Value>> {
+        let body = self.body("GET", path)?;
+        let value = serde_json::from_str(&body).map_err(|e| anyhow!("Invalid response: {}", e))?;
+        match value {
+            Value::Array(values) => Ok(values),
+            _ => bail!("Invalid response: Not an array"),
+        }
+    }
+}
+
+/// Format a value for a table.
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "-".into(),
+        Value::Bool(value) => value.to_string(),
+        Value::Number(number) => number.to_string(),
+        Value::String(string) => string.clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(format_value)
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::Object(members) => members
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, format_value(value)))
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+/// The time since a timestamp (in milliseconds since the unix epoch).
+fn format_age(timestamp: Option<u64>, now: SystemTime) -> String {
+    let timestamp = match timestamp {
+        Some(timestamp) => UNIX_EPOCH + Duration::from_millis(timestamp),
+        None => return "never".into(),
+    };
+    let seconds = now.duration_since(timestamp).unwrap_or_default().as_secs();
+    match seconds {
+        0..=119 => format!("{}s ago", seconds),
+        120..=7199 => format!("{}m ago", seconds / 60),
+        7200..=172_799 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Print rows as table, with aligned columns (except for the last column).
+fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in rows {
+        let mut line = String::new();
+        for (column, cell) in row.iter().enumerate() {
+            if column + 1 == row.len() {
+                line.push_str(cell);
+            } else {
+                let padding = widths[column] - cell.chars().count();
+                line.push_str(&format!("{}{}  ", cell, " ".repeat(padding)));
+            }
+        }
+        println!("{}", line.trim_end());
+    }
+}
+
+fn devices(client: &Client) -> Result<()> {
+    let now = SystemTime::now();
+    let mut rows = vec![vec![
+        "NAME".into(),
+        "ADDRESS".into(),
+        "LOCATION".into(),
+        "SITE".into(),
+        "LAST SEEN".into(),
+    ]];
+    for device in client.json("/api/devices")? {
+        let field = |name| device.get(name).map_or("-".into(), format_value);
+        rows.push(vec![
+            field("name"),
+            field("address"),
+            field("location"),
+            field("site"),
+            format_age(device.get("last_seen").and_then(Value::as_u64), now),
+        ]);
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+/// The values of a measurement, without the fields shown in separate
+/// columns.
+fn values(measurement: &Value) -> String {
+    measurement
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| !["timestamp", "address", "local_name"].contains(&key.as_str()))
+        .map(|(key, value)| match value {
+            Value::Object(_) => format!("{}[{}]", key, format_value(value)),
+            _ => format!("{}={}", key, format_value(value)),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn latest(client: &Client) -> Result<()> {
+    let devices = client.json("/api/devices")?;
+    let name = |address: &str| {
+        devices
+            .iter()
+            .find(|device| device.get("address").and_then(Value::as_str) == Some(address))
+            .and_then(|device| device.get("name"))
+            .and_then(Value::as_str)
+            .map(String::from)
+    };
+    let now = SystemTime::now();
+    let mut rows = vec![vec![
+        "NAME".into(),
+        "ADDRESS".into(),
+        "RECEIVED".into(),
+        "VALUES".into(),
+    ]];
+    for measurement in client.json("/api/latest")? {
+        let address = measurement
+            .get("address")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let local_name = measurement.get("local_name").map(format_value);
+        rows.push(vec![
+            name(address).or(local_name).unwrap_or_default(),
+            address.into(),
+            format_age(measurement.get("timestamp").and_then(Value::as_u64), now),
+            values(&measurement),
+        ]);
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+fn tail(client: &Client) -> Result<()> {
+    let reader = client.request("GET", "/api/stream")?;
+    let stdout = io::stdout();
+    for line in reader.lines() {
+        if let Some(data) = line?.strip_prefix("data: ") {
+            let mut stdout = stdout.lock();
+            writeln!(stdout, "{}", data)?;
+            stdout.flush()?;
+        }
+    }
+    bail!("The gateway closed the stream")
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print_usage(&args[0]);
+        return Ok(());
+    }
+    let mut url = std::env::var("SENSILO_URL").unwrap_or_else(|_| DEFAULT_URL.into());
+    let mut token = std::env::var("SENSILO_TOKEN").ok();
+    let mut cacert = std::env::var("SENSILO_CACERT").ok();
+    let mut rest = &args[1..];
+    let command = loop {
+        match rest {
+            [option, value, tail @ ..] if option == "--url" => {
+                url = value.clone();
+                rest = tail;
+            }
+            [option, value, tail @ ..] if option == "--token" => {
+                token = Some(value.clone());
+                rest = tail;
+            }
+            [option, value, tail @ ..] if option == "--cacert" => {
+                cacert = Some(value.clone());
+                rest = tail;
+            }
+            [command] if !command.starts_with('-') => break command.as_str(),
+            _ => {
+                print_usage(&args[0]);
+                std::process::exit(1);
+            }
+        }
+    };
+    let client = Client::new(&parse_url(&url)?, token, cacert.as_deref())?;
+    match command {
+        "devices" => devices(&client),
+        "latest" => latest(&client),
+        "tail" => tail(&client),
+        "reload" => {
+            print!("{}", client.body("POST", "/api/reload")?);
+            Ok(())
+        }
+        "frames" => {
+            print!("{}", client.body("GET", "/api/frames")?);
+            Ok(())
+        }
+        _ => {
+            print_usage(&args[0]);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url() {
+        let url = |https, host: &str, port| Url {
+            https,
+            host: host.into(),
+            port,
+        };
+        assert_eq!(
+            parse_url("http://gateway:8080").unwrap(),
+            url(false, "gateway", 8080)
+        );
+        assert_eq!(
+            parse_url("http://[::1]:8080/api").unwrap(),
+            url(false, "[::1]", 8080)
+        );
+        assert_eq!(
+            parse_url("http://gateway/").unwrap(),
+            url(false, "gateway", 80)
+        );
+        assert_eq!(
+            parse_url("https://gateway.local").unwrap(),
+            url(true, "gateway.local", 443)
+        );
+        assert!(parse_url("gateway:8080").is_err());
+        assert!(parse_url("http://gateway:http").is_err());
+        assert!(parse_url("http://").is_err());
+    }
+
+    #[test]
+    fn format() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_607_500_000);
+        let age = |seconds: u64| format_age(Some((1_607_500_000 - seconds) * 1000), now);
+        assert_eq!(age(5), "5s ago");
+        assert_eq!(age(600), "10m ago");
+        assert_eq!(age(3 * 3600), "3h ago");
+        assert_eq!(age(5 * 86400), "5d ago");
+        assert_eq!(format_age(None, now), "never");
+
+        let measurement = serde_json::from_str(
+            r#"{"timestamp":1607500000000,"address":"123456","local_name":"Sensilo",
+                "counter":42,"temperature":21.5,"buzzer":false,"analog":{"soil":1234}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            values(&measurement),
+            "counter=42 temperature=21.5 buzzer=false analog[soil=1234]"
+        );
+    }
+}
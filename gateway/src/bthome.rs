@@ -0,0 +1,216 @@
+//! Decoder for the BTHome (v2) advertisement format, used by third-party
+//! sensors like the Shelly H&T or b-parasite.
+//!
+//! See <https://bthome.io/format/> for the format specification.
+use crate::advertising::AdStructure;
+use crate::decoder::Decoder;
+use crate::measurement::{AmbientLight, Humidity, MeasurementBuilder, Temperature};
+
+/// 16 bit UUID of the BTHome service data.
+const BTHOME_UUID: u16 = 0xfcd2;
+
+/// Device information flags
+const FLAG_ENCRYPTED: u8 = 0x01;
+const VERSION_SHIFT: u8 = 5;
+
+/// Length of the value of an object (excluding the object ID). Objects with a
+/// variable length are prefixed with their length.
+fn object_len(id: u8) -> Option<usize> {
+    Some(match id {
+        0x00 | 0x01 | 0x09 | 0x0f..=0x11 | 0x15..=0x2f | 0x3a | 0x46 => 1,
+        0x02 | 0x03 | 0x06..=0x08 | 0x0c..=0x0e | 0x12..=0x14 | 0x3c | 0x3d | 0x3f..=0x41 => 2,
+        0x43..=0x45 | 0x47..=0x4a | 0x51 | 0x52 | 0xf0 => 2,
+        0x04 | 0x05 | 0x0a | 0x0b | 0x42 | 0x4b | 0xf2 => 3,
+        0x3e | 0x4c..=0x50 | 0x55 | 0xf1 => 4,
+        _ => return None,
+    })
+}
+
+/// The values of a BTHome payload that are supported by the gateway.
+#[derive(Debug, Default)]
+struct Payload {
+    packet_id: Option<u8>,
+    temperature: Option<Temperature>,
+    humidity: Option<Humidity>,
+    illuminance: Option<AmbientLight>,
+}
+
+fn parse(data: &[u8]) -> Result<Payload, &'static str> {
+    let (&info, mut objects) = data.split_first().ok_or("Missing device information")?;
+    if info & FLAG_ENCRYPTED != 0 {
+        return Err("Encrypted BTHome payloads are not supported");
+    }
+    if info >> VERSION_SHIFT != 2 {
+        return Err("Unsupported BTHome version");
+    }
+
+    let mut payload = Payload::default();
+    while let Some((&id, rest)) = objects.split_first() {
+        let (value, rest) = match object_len(id) {
+            Some(len) if rest.len() >= len => rest.split_at(len),
+            Some(_) => return Err("Truncated BTHome object"),
+            // Text and raw objects
+            None if id == 0x53 || id == 0x54 => {
+                let (&len, rest) = rest.split_first().ok_or("Truncated BTHome object")?;
+                if rest.len() < len as usize {
+                    return Err("Truncated BTHome object");
+                }
+                rest.split_at(len as usize)
+            }
+            None => {
+                // The length of the remaining objects is unknown
+                log::debug!("Unknown BTHome object ID 0x{:02x}", id);
+                break;
+            }
+        };
+        let unsigned = value
+            .iter()
+            .rev()
+            .fold(0u32, |acc, byte| (acc << 8) | u32::from(*byte));
+        match id {
+            0x00 => payload.packet_id = Some(value[0]),
+            0x02 => {
+                let raw = i16::from_le_bytes([value[0], value[1]]);
+                payload.temperature =
+                    Some(Temperature::from_millidegrees_celsius(i32::from(raw) * 10));
+            }
+            0x45 => {
+                let raw = i16::from_le_bytes([value[0], value[1]]);
+                payload.temperature =
+                    Some(Temperature::from_millidegrees_celsius(i32::from(raw) * 100));
+            }
+            0x03 => payload.humidity = Some(Humidity::from_millipercent(unsigned as i32 * 10)),
+            0x2e => payload.humidity = Some(Humidity::from_millipercent(unsigned as i32 * 1000)),
+            0x05 => payload.illuminance = Some(AmbientLight::from_lux(unsigned as f32 / 100.0)),
+            other => log::trace!("Ignoring BTHome object ID 0x{:02x}", other),
+        }
+        objects = rest;
+    }
+    Ok(payload)
+}
+
+/// Decodes BTHome payloads of a device into measurements.
+///
+/// The 8 bit packet ID is extended to a 16 bit counter, so that deduplication
+/// and gap detection work the same way as for Sensilo devices.
+#[derive(Default)]
+pub struct BthomeDecoder {
+    counter: Option<u16>,
+}
+
+impl BthomeDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Decoder for BthomeDecoder {
+    fn payload<'a>(&self, data: &'a [AdStructure]) -> Option<&'a [u8]> {
+        data.iter().find_map(|datum| match datum {
+            AdStructure::ServiceData16 {
+                uuid: BTHOME_UUID,
+                data,
+            } => Some(&data[..]),
+            _ => None,
+        })
+    }
+
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        builder: &mut MeasurementBuilder<'_>,
+    ) -> Result<(), &'static str> {
+        // The local name is optional in BTHome advertisements
+        builder.local_name("");
+
+        let payload = parse(payload)?;
+        let counter = match (self.counter, payload.packet_id) {
+            (Some(previous), Some(id)) => {
+                previous.wrapping_add(u16::from(id.wrapping_sub(previous as u8)))
+            }
+            (None, Some(id)) => u16::from(id),
+            // Without a packet ID, every received frame is counted
+            (Some(previous), None) => previous.wrapping_add(1),
+            (None, None) => 0,
+        };
+        self.counter = Some(counter);
+
+        builder.counter(counter);
+        if let Some(temperature) = payload.temperature {
+            builder.temperature(temperature);
+        }
+        if let Some(humidity) = payload.humidity {
+            builder.humidity(humidity);
+        }
+        if let Some(illuminance) = payload.illuminance {
+            builder.ambient_light(illuminance);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::types::Address;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    #[rustfmt::skip]
+    const DATA: [u8; 13] = [
+        // Device information (version 2, not encrypted)
+        0x40,
+        // Packet ID
+        0x00, 0x2a,
+        // Battery
+        0x01, 0x5d,
+        // Temperature (0.01 °C)
+        0x02, 0xca, 0x09,
+        // Humidity (0.01 %)
+        0x03, 0xbf, 0x13,
+        // Button (ignored)
+        0x3a, 0x01,
+    ];
+
+    #[test]
+    fn parse_payload() {
+        let payload = parse(&DATA).unwrap();
+        assert_eq!(payload.packet_id, Some(42));
+        assert_eq!(
+            payload.temperature.unwrap().as_millidegrees_celsius(),
+            25060
+        );
+        assert_eq!(payload.humidity.unwrap().as_millipercent(), 50550);
+        assert!(payload.illuminance.is_none());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        // Encrypted
+        assert!(parse(&[0x41, 0x00, 0x01]).is_err());
+        // Version 1
+        assert!(parse(&[0x20, 0x00, 0x01]).is_err());
+        // Truncated temperature
+        assert!(parse(&[0x40, 0x02, 0xca]).is_err());
+        // Unknown object IDs end the parsing
+        assert_eq!(
+            parse(&[0x40, 0x00, 0x01, 0xee, 0x00]).unwrap().packet_id,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn extend_packet_id() {
+        let mut decoder = BthomeDecoder::new();
+        let mut counter = |id: u8| {
+            let mut builder = MeasurementBuilder::new(ADDR, 200);
+            decoder.decode(&[0x40, 0x00, id], &mut builder).unwrap();
+            builder.build().unwrap().counter
+        };
+        assert_eq!(counter(254), 254);
+        assert_eq!(counter(255), 255);
+        assert_eq!(counter(1), 257);
+        assert_eq!(counter(1), 257);
+    }
+}
@@ -0,0 +1,108 @@
+//! Capture backends that provide raw HCI packets.
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use futures::stream::LocalBoxStream;
+
+use crate::config;
+use crate::types::Address;
+
+mod btsnoop;
+#[cfg(feature = "capture-hci")]
+mod hci_socket;
+mod nrf_sniffer;
+#[cfg(feature = "capture-pcap")]
+mod pcap;
+mod pcap_file;
+
+/// A captured HCI packet in H4 format (starting with the packet type
+/// indicator, e.g. `0x04` for HCI events).
+#[derive(Debug, Clone)]
+pub struct HciPacket {
+    /// When the packet was captured
+    pub timestamp: SystemTime,
+    /// The advertising channel the packet was received on (if known)
+    pub channel: Option<u8>,
+    /// The raw packet data
+    pub data: Vec<u8>,
+}
+
+/// A stream of batches of captured HCI packets.
+pub type PacketStream = LocalBoxStream<'static, Vec<HciPacket>>;
+
+/// The addresses to filter by (in the kernel or in the adapter), if the
+/// filter is enabled. Devices with private addresses can't be filtered by
+/// address, so all reports are passed if there are any.
+#[cfg_attr(
+    not(any(feature = "capture-pcap", feature = "capture-hci")),
+    allow(dead_code)
+)]
+fn filter_addresses<'a>(
+    enabled: bool,
+    devices: &[config::Device],
+    addresses: &'a [Address],
+) -> Option<&'a [Address]> {
+    if !enabled {
+        return None;
+    }
+    match devices.iter().find(|device| device.irk.is_some()) {
+        Some(device) => {
+            status!(
+                "Warning: Not filtering by address, device {} uses private addresses",
+                device.name
+            );
+            None
+        }
+        None => Some(addresses),
+    }
+}
+
+/// Open the configured capture backend. The addresses must be in the same
+/// order as the devices.
+#[cfg_attr(
+    not(any(feature = "capture-pcap", feature = "capture-hci")),
+    allow(unused_variables)
+)]
+pub fn open(
+    config: &config::Capture,
+    devices: &[config::Device],
+    addresses: &[Address],
+) -> Result<PacketStream> {
+    match config {
+        #[cfg(feature = "capture-pcap")]
+        config::Capture::Pcap {
+            interface,
+            filter,
+            filter_addresses: enabled,
+        } => match filter_addresses(*enabled, devices, addresses) {
+            Some(addresses) => pcap::open(interface, &pcap::address_filter(filter, addresses)),
+            None => pcap::open(interface, filter),
+        },
+        #[cfg(not(feature = "capture-pcap"))]
+        config::Capture::Pcap { .. } => anyhow::bail!(
+            "The pcap capture backend is not included in this build \
+             (feature capture-pcap), use e.g. the hci backend"
+        ),
+        #[cfg(feature = "capture-hci")]
+        config::Capture::Hci { device, scan } => {
+            let accept_list = scan
+                .as_ref()
+                .and_then(|scan| filter_addresses(scan.accept_list, devices, addresses));
+            hci_socket::open(*device, scan.as_ref(), accept_list)
+        }
+        #[cfg(not(feature = "capture-hci"))]
+        config::Capture::Hci { .. } => {
+            anyhow::bail!(
+                "The hci capture backend is not included in this build (feature capture-hci)"
+            )
+        }
+        config::Capture::Btsnoop { path, follow } => btsnoop::open(path, *follow),
+        config::Capture::NrfSniffer { port, baud_rate } => nrf_sniffer::open(port, *baud_rate),
+    }
+}
+
+/// Read all HCI packets of a capture file (in the pcap or pcapng format).
+pub fn read_file(path: &Path) -> Result<Vec<HciPacket>> {
+    pcap_file::read(path)
+}
@@ -0,0 +1,252 @@
+//! Read HCI packets from btsnoop log files.
+//!
+//! The btsnoop format is written by many HCI logging tools (e.g. `btmon -w`
+//! or the Android Bluetooth HCI snoop log) and is platform independent. Both
+//! complete log files and log files that are still being written (with
+//! `follow` enabled) are supported.
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use futures::{AsyncReadExt, StreamExt};
+
+use super::{HciPacket, PacketStream};
+
+const MAGIC: &[u8; 8] = b"btsnoop\0";
+const FILE_HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 24;
+
+/// Supported datalink types
+const DATALINK_HCI_UNENCAPSULATED: u32 = 1001;
+const DATALINK_HCI_UART: u32 = 1002;
+const DATALINK_LINUX_MONITOR: u32 = 2001;
+
+/// H4 packet type indicators
+const H4_COMMAND: u8 = 0x01;
+const H4_ACL_DATA: u8 = 0x02;
+const H4_SCO_DATA: u8 = 0x03;
+const H4_EVENT: u8 = 0x04;
+
+/// Timestamps are microseconds since 0000-01-01. This is the offset to the
+/// unix epoch.
+const EPOCH_OFFSET_US: i64 = 0x00dc_ddb3_0f2f_8000;
+
+/// How long to wait for new data when following a log file.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Incremental btsnoop parser.
+#[derive(Default)]
+pub struct Parser {
+    datalink: Option<u32>,
+    buf: Vec<u8>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add data and return all HCI packets contained in complete records.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<HciPacket>> {
+        self.buf.extend_from_slice(data);
+
+        // Parse file header
+        let datalink = match self.datalink {
+            Some(datalink) => datalink,
+            None if self.buf.len() < FILE_HEADER_LEN => return Ok(vec![]),
+            None => {
+                if &self.buf[..8] != MAGIC {
+                    bail!("Not a btsnoop file");
+                }
+                let datalink = read_u32(&self.buf[12..16]);
+                match datalink {
+                    DATALINK_HCI_UNENCAPSULATED | DATALINK_HCI_UART | DATALINK_LINUX_MONITOR => {}
+                    other => bail!("Unsupported btsnoop datalink type: {}", other),
+                }
+                self.buf.drain(..FILE_HEADER_LEN);
+                self.datalink = Some(datalink);
+                datalink
+            }
+        };
+
+        // Parse records
+        let mut packets = vec![];
+        let mut offset = 0;
+        while self.buf.len() - offset >= RECORD_HEADER_LEN {
+            let header = &self.buf[offset..offset + RECORD_HEADER_LEN];
+            let included_len = read_u32(&header[4..8]) as usize;
+            let flags = read_u32(&header[8..12]);
+            let timestamp = i64::from_be_bytes(header[16..24].try_into().unwrap());
+            let end = offset + RECORD_HEADER_LEN + included_len;
+            if self.buf.len() < end {
+                break;
+            }
+            let data = &self.buf[offset + RECORD_HEADER_LEN..end];
+            if let Some(packet_type) = packet_type(datalink, flags, data) {
+                let mut packet = Vec::with_capacity(data.len() + 1);
+                if datalink != DATALINK_HCI_UART {
+                    packet.push(packet_type);
+                }
+                packet.extend_from_slice(data);
+                packets.push(HciPacket {
+                    timestamp: to_system_time(timestamp),
+                    channel: None,
+                    data: packet,
+                });
+            }
+            offset = end;
+        }
+        self.buf.drain(..offset);
+
+        Ok(packets)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+/// Determine the H4 packet type of a record.
+fn packet_type(datalink: u32, flags: u32, data: &[u8]) -> Option<u8> {
+    match datalink {
+        // The packet type is already part of the data
+        DATALINK_HCI_UART => data.first().copied(),
+        // Bit 0: Direction (1 = received), bit 1: Command/event (1) or data (0)
+        DATALINK_HCI_UNENCAPSULATED => match flags & 0b11 {
+            0b00 | 0b01 => Some(H4_ACL_DATA),
+            0b10 => Some(H4_COMMAND),
+            _ => Some(H4_EVENT),
+        },
+        // The lower 16 bits contain the monitor opcode
+        DATALINK_LINUX_MONITOR => match flags & 0xffff {
+            2 => Some(H4_COMMAND),
+            3 => Some(H4_EVENT),
+            4 | 5 => Some(H4_ACL_DATA),
+            6 | 7 => Some(H4_SCO_DATA),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn to_system_time(timestamp: i64) -> SystemTime {
+    let unix_us = timestamp - EPOCH_OFFSET_US;
+    if unix_us >= 0 {
+        UNIX_EPOCH + Duration::from_micros(unix_us as u64)
+    } else {
+        UNIX_EPOCH
+    }
+}
+
+pub fn open(path: &str, follow: bool) -> Result<PacketStream> {
+    status!("Opening btsnoop log {}...", path);
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Could not open btsnoop log {}", path))?;
+    let file = smol::Unblock::new(file);
+
+    let stream = futures::stream::unfold(
+        (file, Parser::new(), vec![0; 64 * 1024]),
+        move |(mut file, mut parser, mut buf)| async move {
+            loop {
+                let len = match file.read(&mut buf).await {
+                    Ok(0) if follow => {
+                        smol::Timer::after(FOLLOW_INTERVAL).await;
+                        continue;
+                    }
+                    Ok(0) => {
+                        status!("End of btsnoop log reached");
+                        return None;
+                    }
+                    Ok(len) => len,
+                    Err(e) => {
+                        status!("Error: Could not read btsnoop log: {}", e);
+                        return None;
+                    }
+                };
+                match parser.push(&buf[..len]) {
+                    Ok(packets) => return Some((packets, (file, parser, buf))),
+                    Err(e) => {
+                        status!("Error: {:#}", e);
+                        return None;
+                    }
+                }
+            }
+        },
+    );
+    Ok(stream.boxed_local())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_header(datalink: u32) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(&datalink.to_be_bytes());
+        header
+    }
+
+    fn record(flags: u32, timestamp: i64, data: &[u8]) -> Vec<u8> {
+        let mut record = vec![];
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(&flags.to_be_bytes());
+        record.extend_from_slice(&0u32.to_be_bytes());
+        record.extend_from_slice(&timestamp.to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn parse_hci_uart() {
+        let mut data = file_header(DATALINK_HCI_UART);
+        data.extend(record(
+            3,
+            EPOCH_OFFSET_US + 1_000_000,
+            &[0x04, 0x3e, 0x01, 0x02],
+        ));
+        let packets = Parser::new().push(&data).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, vec![0x04, 0x3e, 0x01, 0x02]);
+        assert_eq!(packets[0].timestamp, UNIX_EPOCH + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parse_unencapsulated() {
+        let mut data = file_header(DATALINK_HCI_UNENCAPSULATED);
+        data.extend(record(3, EPOCH_OFFSET_US, &[0x3e, 0x01, 0x02]));
+        let packets = Parser::new().push(&data).unwrap();
+        assert_eq!(packets[0].data, vec![0x04, 0x3e, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn parse_monitor() {
+        let mut data = file_header(DATALINK_LINUX_MONITOR);
+        // New index (ignored), event
+        data.extend(record(0, EPOCH_OFFSET_US, &[0; 16]));
+        data.extend(record(3, EPOCH_OFFSET_US, &[0x3e, 0x01, 0x02]));
+        let packets = Parser::new().push(&data).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, vec![0x04, 0x3e, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn parse_incremental() {
+        let mut data = file_header(DATALINK_HCI_UART);
+        data.extend(record(3, EPOCH_OFFSET_US, &[0x04, 0x3e, 0x01, 0x02]));
+        data.extend(record(3, EPOCH_OFFSET_US, &[0x04, 0x3e, 0x01, 0x03]));
+        let mut parser = Parser::new();
+        let mut packets = vec![];
+        for chunk in data.chunks(7) {
+            packets.extend(parser.push(chunk).unwrap());
+        }
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].data, vec![0x04, 0x3e, 0x01, 0x03]);
+    }
+
+    #[test]
+    fn invalid_magic() {
+        assert!(Parser::new().push(&[0; 16]).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+//! Live capture of HCI events through a raw Bluetooth HCI socket (Linux
+//! only).
+//!
+//! Unlike the pcap backend, this doesn't need libpcap. The socket receives a
+//! copy of the events of the adapter, which are filtered in the kernel so that
+//! only LE meta events (containing the advertising reports) reach the gateway.
+//! Scanning must be enabled separately (e.g. with `bluetoothctl scan on`),
+//! unless scan parameters are configured. Then the gateway sets them and
+//! enables scanning itself, optionally with the configured devices in the
+//! accept list of the adapter (so that the adapter drops all other
+//! advertisements). With `extended = true`, the BLE 5 extended scanning
+//! commands are used instead, so that the adapter also reports extended
+//! advertisements.
+use anyhow::Result;
+
+use super::PacketStream;
+use crate::config::{Scan, ScanType};
+use crate::types::Address;
+
+/// Scan interval and window are in units of 0.625 ms.
+const SCAN_TIME_UNIT_MS: f64 = 0.625;
+
+/// The parameters of the LE Set Scan Parameters command: Scan type,
+/// interval, window, own address type (public) and filter policy (all
+/// advertisements, or only those of the devices in the accept list).
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn scan_parameters(scan: &Scan, accept_list: bool) -> Result<[u8; 7]> {
+    let units = |ms: f64| (ms / SCAN_TIME_UNIT_MS).round();
+    let interval = units(scan.interval_ms);
+    let window = units(scan.window_ms.unwrap_or(scan.interval_ms));
+    if !(4.0..=16384.0).contains(&interval) {
+        anyhow::bail!("The scan interval must be between 2.5 ms and 10240 ms");
+    }
+    if !(4.0..=interval).contains(&window) {
+        anyhow::bail!("The scan window must be between 2.5 ms and the scan interval");
+    }
+    let [interval_lo, interval_hi] = (interval as u16).to_le_bytes();
+    let [window_lo, window_hi] = (window as u16).to_le_bytes();
+    let scan_type = match scan.scan_type {
+        ScanType::Passive => 0x00,
+        ScanType::Active => 0x01,
+    };
+    Ok([
+        scan_type,
+        interval_lo,
+        interval_hi,
+        window_lo,
+        window_hi,
+        0x00,
+        accept_list as u8,
+    ])
+}
+
+/// The parameters of the LE Set Extended Scan Parameters command: Own address
+/// type, filter policy, scanning PHYs (only the 1M PHY) and the scan type,
+/// interval and window for that PHY.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn extended_scan_parameters(scan: &Scan, accept_list: bool) -> Result<[u8; 8]> {
+    let [scan_type, interval_lo, interval_hi, window_lo, window_hi, own_address_type, filter_policy] =
+        scan_parameters(scan, accept_list)?;
+    Ok([
+        own_address_type,
+        filter_policy,
+        0x01,
+        scan_type,
+        interval_lo,
+        interval_hi,
+        window_lo,
+        window_hi,
+    ])
+}
+
+/// The parameters of the LE Add Device To Accept List commands of an address
+/// (the address type is unknown, so it is added as public and as random
+/// address).
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn accept_list_entries(address: Address) -> [[u8; 7]; 2] {
+    let mut entry = [0; 7];
+    entry[1..].copy_from_slice(&address.0);
+    entry[1..].reverse();
+    let mut random = entry;
+    random[0] = 0x01;
+    [entry, random]
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::time::SystemTime;
+
+    use anyhow::{Context, Result};
+    use futures::StreamExt;
+
+    use super::super::{HciPacket, PacketStream};
+    use crate::config::{Scan, ScanType};
+    use crate::types::Address;
+
+    const BTPROTO_HCI: libc::c_int = 1;
+    const SOL_HCI: libc::c_int = 0;
+    const HCI_FILTER: libc::c_int = 2;
+    const HCI_CHANNEL_RAW: u16 = 0;
+
+    /// H4 packet type of HCI commands.
+    const HCI_COMMAND_PKT: u8 = 0x01;
+
+    /// H4 packet type of HCI events.
+    const HCI_EVENT_PKT: u32 = 0x04;
+
+    // Event codes
+    const EVT_CMD_COMPLETE: u32 = 0x0e;
+    const EVT_CMD_STATUS: u32 = 0x0f;
+    const EVT_LE_META_EVENT: u32 = 0x3e;
+
+    // Opcodes of the LE controller commands (OGF 0x08)
+    const LE_SET_SCAN_PARAMETERS: u16 = 0x200b;
+    const LE_SET_SCAN_ENABLE: u16 = 0x200c;
+    const LE_CLEAR_ACCEPT_LIST: u16 = 0x2010;
+    const LE_ADD_DEVICE_TO_ACCEPT_LIST: u16 = 0x2011;
+    const LE_SET_EXTENDED_SCAN_PARAMETERS: u16 = 0x2041;
+    const LE_SET_EXTENDED_SCAN_ENABLE: u16 = 0x2042;
+
+    /// Error code of commands that are not allowed in the current state.
+    const COMMAND_DISALLOWED: u8 = 0x0c;
+
+    /// How long to wait for the completion of a command.
+    const COMMAND_TIMEOUT_S: libc::time_t = 2;
+
+    #[repr(C)]
+    struct SockaddrHci {
+        hci_family: libc::sa_family_t,
+        hci_dev: u16,
+        hci_channel: u16,
+    }
+
+    #[repr(C)]
+    struct HciFilter {
+        type_mask: u32,
+        event_mask: [u32; 2],
+        opcode: u16,
+    }
+
+    /// Open a raw HCI socket on the adapter `hciN`, receiving the events
+    /// that pass the filter.
+    fn open_socket(device: u16, filter: HciFilter) -> io::Result<File> {
+        // Safety: The file descriptor is owned by the returned file (and
+        // closed by it on errors). The structs match the kernel ABI.
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                BTPROTO_HCI,
+            );
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let file = File::from_raw_fd(fd);
+
+            let result = libc::setsockopt(
+                fd,
+                SOL_HCI,
+                HCI_FILTER,
+                &filter as *const HciFilter as *const libc::c_void,
+                mem::size_of::<HciFilter>() as libc::socklen_t,
+            );
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let address = SockaddrHci {
+                hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+                hci_dev: device,
+                hci_channel: HCI_CHANNEL_RAW,
+            };
+            let result = libc::bind(
+                fd,
+                &address as *const SockaddrHci as *const libc::sockaddr,
+                mem::size_of::<SockaddrHci>() as libc::socklen_t,
+            );
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(file)
+        }
+    }
+
+    /// Send an HCI command and wait for its completion. Return the status
+    /// code of the controller (0 on success).
+    fn send_command(device: u16, opcode: u16, params: &[u8]) -> io::Result<u8> {
+        let filter = HciFilter {
+            type_mask: 1 << HCI_EVENT_PKT,
+            event_mask: [1 << EVT_CMD_COMPLETE | 1 << EVT_CMD_STATUS, 0],
+            opcode,
+        };
+        let mut socket = open_socket(device, filter)?;
+        let timeout = libc::timeval {
+            tv_sec: COMMAND_TIMEOUT_S,
+            tv_usec: 0,
+        };
+        // Safety: The socket is open, the struct matches the kernel ABI
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let [opcode_lo, opcode_hi] = opcode.to_le_bytes();
+        let mut command = vec![HCI_COMMAND_PKT, opcode_lo, opcode_hi, params.len() as u8];
+        command.extend_from_slice(params);
+        socket.write_all(&command)?;
+
+        let mut buf = [0; 260];
+        loop {
+            let len = socket.read(&mut buf)?;
+            if len < 3 {
+                continue;
+            }
+            // Packet type, event code, parameter length and parameters
+            match (buf[1] as u32, &buf[3..len]) {
+                (EVT_CMD_COMPLETE, [_, lo, hi, status, ..])
+                | (EVT_CMD_STATUS, [status, _, lo, hi, ..])
+                    if u16::from_le_bytes([*lo, *hi]) == opcode =>
+                {
+                    return Ok(*status)
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Set the scan parameters (and the accept list) and (re)enable
+    /// scanning.
+    fn enable_scanning(device: u16, scan: &Scan, accept_list: Option<&[Address]>) -> Result<()> {
+        let command = |name, opcode, params: &[u8]| match send_command(device, opcode, params) {
+            Ok(0) => Ok(()),
+            Ok(status) => anyhow::bail!("{} failed with status {:#04x}", name, status),
+            Err(e) => Err(e).with_context(|| format!("Could not send {}", name)),
+        };
+        // Scanning must be disabled while the parameters are changed (which
+        // is disallowed if it is disabled already)
+        let disable = if scan.extended {
+            send_command(device, LE_SET_EXTENDED_SCAN_ENABLE, &[0x00; 6])
+        } else {
+            send_command(device, LE_SET_SCAN_ENABLE, &[0x00, 0x00])
+        };
+        match disable {
+            Ok(0 | COMMAND_DISALLOWED) => {}
+            Ok(status) => anyhow::bail!("Disabling scanning failed with status {:#04x}", status),
+            Err(e) => return Err(e).context("Could not disable scanning"),
+        }
+        if let Some(addresses) = accept_list {
+            command("LE Clear Accept List", LE_CLEAR_ACCEPT_LIST, &[])?;
+            for &address in addresses {
+                for entry in &super::accept_list_entries(address) {
+                    command(
+                        "LE Add Device To Accept List",
+                        LE_ADD_DEVICE_TO_ACCEPT_LIST,
+                        entry,
+                    )
+                    .with_context(|| format!("Could not add {} to the accept list", address))?;
+                }
+            }
+            status!("Added {} device(s) to the accept list", addresses.len());
+        }
+        if scan.extended {
+            command(
+                "LE Set Extended Scan Parameters",
+                LE_SET_EXTENDED_SCAN_PARAMETERS,
+                &super::extended_scan_parameters(scan, accept_list.is_some())?,
+            )?;
+            // Enable, filter duplicates, no duration and period (scan until
+            // disabled)
+            command(
+                "LE Set Extended Scan Enable",
+                LE_SET_EXTENDED_SCAN_ENABLE,
+                &[0x01, scan.filter_duplicates as u8, 0x00, 0x00, 0x00, 0x00],
+            )?;
+        } else {
+            command(
+                "LE Set Scan Parameters",
+                LE_SET_SCAN_PARAMETERS,
+                &super::scan_parameters(scan, accept_list.is_some())?,
+            )?;
+            command(
+                "LE Set Scan Enable",
+                LE_SET_SCAN_ENABLE,
+                &[0x01, scan.filter_duplicates as u8],
+            )?;
+        }
+        status!(
+            "Enabled {}{} scanning on hci{} (interval {} ms, window {} ms)",
+            match scan.scan_type {
+                ScanType::Passive => "passive",
+                ScanType::Active => "active",
+            },
+            if scan.extended { " extended" } else { "" },
+            device,
+            scan.interval_ms,
+            scan.window_ms.unwrap_or(scan.interval_ms)
+        );
+        Ok(())
+    }
+
+    pub fn open(
+        device: u16,
+        scan: Option<&Scan>,
+        accept_list: Option<&[Address]>,
+    ) -> Result<PacketStream> {
+        status!("Opening HCI socket on hci{}...", device);
+        let filter = HciFilter {
+            type_mask: 1 << HCI_EVENT_PKT,
+            event_mask: [0, 1 << (EVT_LE_META_EVENT - 32)],
+            opcode: 0,
+        };
+        let mut socket = open_socket(device, filter)
+            .with_context(|| format!("Could not open HCI socket on hci{}", device))?;
+        if let Some(scan) = scan {
+            enable_scanning(device, scan, accept_list)
+                .with_context(|| format!("Could not enable scanning on hci{}", device))?;
+        }
+
+        // Every read returns a single packet. They are read on a separate
+        // thread, to keep the packet boundaries and the reception time.
+        let (sender, receiver) = smol::channel::unbounded();
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                let len = match socket.read(&mut buf) {
+                    Ok(0) => {
+                        status!("HCI socket closed");
+                        return;
+                    }
+                    Ok(len) => len,
+                    Err(e) => {
+                        status!("Error: Could not read from HCI socket: {}", e);
+                        return;
+                    }
+                };
+                let packet = HciPacket {
+                    timestamp: SystemTime::now(),
+                    channel: None,
+                    data: buf[..len].to_vec(),
+                };
+                if sender.try_send(vec![packet]).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(receiver.boxed_local())
+    }
+}
+
+/// Open the socket. If scan parameters are given, enable scanning (only for
+/// the advertisements of the addresses in the accept list, if any).
+#[cfg(target_os = "linux")]
+pub fn open(
+    device: u16,
+    scan: Option<&Scan>,
+    accept_list: Option<&[Address]>,
+) -> Result<PacketStream> {
+    linux::open(device, scan, accept_list)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open(
+    _device: u16,
+    _scan: Option<&Scan>,
+    _accept_list: Option<&[Address]>,
+) -> Result<PacketStream> {
+    anyhow::bail!("The hci capture backend is only available on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameters() {
+        let mut scan = Scan {
+            scan_type: ScanType::Active,
+            interval_ms: 100.0,
+            window_ms: Some(50.0),
+            filter_duplicates: false,
+            accept_list: false,
+            extended: false,
+        };
+        assert_eq!(
+            scan_parameters(&scan, false).unwrap(),
+            [0x01, 0xa0, 0x00, 0x50, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(scan_parameters(&scan, true).unwrap()[6], 0x01);
+
+        // The window defaults to the interval
+        scan.scan_type = ScanType::Passive;
+        scan.window_ms = None;
+        assert_eq!(
+            scan_parameters(&scan, false).unwrap(),
+            [0x00, 0xa0, 0x00, 0xa0, 0x00, 0x00, 0x00]
+        );
+
+        assert_eq!(
+            extended_scan_parameters(&scan, true).unwrap(),
+            [0x00, 0x01, 0x01, 0x00, 0xa0, 0x00, 0xa0, 0x00]
+        );
+
+        scan.window_ms = Some(150.0);
+        assert!(scan_parameters(&scan, false).is_err());
+        assert!(extended_scan_parameters(&scan, false).is_err());
+        scan.interval_ms = 1.0;
+        assert!(scan_parameters(&scan, false).is_err());
+    }
+
+    #[test]
+    fn accept_list() {
+        assert_eq!(
+            accept_list_entries(Address::from_hex("864fe067997a")),
+            [
+                [0x00, 0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86],
+                [0x01, 0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86],
+            ]
+        );
+    }
+}
@@ -0,0 +1,512 @@
+//! Capture advertising packets with an nRF52 running the Nordic nRF Sniffer
+//! for Bluetooth LE firmware (protocol version 2 and 3), attached through a
+//! serial port.
+//!
+//! The sniffer reports raw link layer packets. Valid advertising PDUs are
+//! converted to LE Advertising Report HCI events, so that they can be
+//! processed just like packets from a host Bluetooth adapter. Extended
+//! advertisements (`AUX_ADV_IND`, if the sniffer follows the auxiliary
+//! pointers) are converted to LE Extended Advertising Report events.
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use futures::{AsyncReadExt, StreamExt};
+
+use super::{HciPacket, PacketStream};
+
+/// SLIP framing bytes used by the sniffer
+const SLIP_START: u8 = 0xab;
+const SLIP_END: u8 = 0xbc;
+const SLIP_ESC: u8 = 0xcd;
+const SLIP_ESC_START: u8 = SLIP_START + 1;
+const SLIP_ESC_END: u8 = SLIP_END + 1;
+const SLIP_ESC_ESC: u8 = SLIP_ESC + 1;
+
+/// Length of the sniffer packet header
+const HEADER_LEN: usize = 6;
+
+/// Sniffer packet IDs
+const EVENT_PACKET_ADV_PDU: u8 = 0x02;
+const EVENT_PACKET_DATA_PDU: u8 = 0x06;
+const REQ_SCAN_CONT: u8 = 0x07;
+
+/// Protocol version used for requests
+const REQUEST_PROTOCOL_VERSION: u8 = 1;
+
+/// Flag bits in the BLE packet metadata
+const FLAG_CRC_OK: u8 = 1 << 0;
+const FLAG_PHY_MASK: u8 = 0b0111_0000;
+
+/// Length of the BLE packet metadata (header length, flags, channel, RSSI,
+/// event counter, timestamp)
+const BLE_METADATA_LEN: usize = 10;
+
+/// Length of the access address
+const ACCESS_ADDRESS_LEN: usize = 4;
+
+/// Maximum length of the data of a legacy advertisement
+const MAX_ADV_DATA_LEN: usize = 31;
+
+/// Maximum length of the data of an extended advertisement that fits into a
+/// single LE Extended Advertising Report event
+const MAX_EXT_ADV_DATA_LEN: usize = 255 - 2 - 24;
+
+/// Flags of the fields in the extended header of an `AUX_ADV_IND`, in the
+/// order of the fields, with their lengths
+const EXT_HEADER_FIELDS: [(u8, usize); 7] = [
+    (1 << 0, 6),  // AdvA
+    (1 << 1, 6),  // TargetA
+    (1 << 2, 1),  // CTEInfo
+    (1 << 3, 2),  // ADI
+    (1 << 4, 3),  // AuxPtr
+    (1 << 5, 18), // SyncInfo
+    (1 << 6, 1),  // TxPower
+];
+const EXT_HEADER_ADV_A: u8 = 1 << 0;
+const EXT_HEADER_ADI: u8 = 1 << 3;
+const EXT_HEADER_AUX_PTR: u8 = 1 << 4;
+
+/// Incremental decoder for SLIP framed sniffer packets.
+#[derive(Default)]
+pub struct SlipDecoder {
+    buf: Vec<u8>,
+    in_frame: bool,
+    escaped: bool,
+}
+
+impl SlipDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add data and return all complete frames.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        for &byte in data {
+            if byte == SLIP_START {
+                self.buf.clear();
+                self.in_frame = true;
+                self.escaped = false;
+                continue;
+            }
+            if !self.in_frame {
+                continue;
+            }
+            if self.escaped {
+                self.escaped = false;
+                match byte {
+                    SLIP_ESC_START => self.buf.push(SLIP_START),
+                    SLIP_ESC_END => self.buf.push(SLIP_END),
+                    SLIP_ESC_ESC => self.buf.push(SLIP_ESC),
+                    _ => {
+                        log::debug!("Invalid SLIP escape sequence");
+                        self.in_frame = false;
+                    }
+                }
+                continue;
+            }
+            match byte {
+                SLIP_END => {
+                    frames.push(std::mem::take(&mut self.buf));
+                    self.in_frame = false;
+                }
+                SLIP_ESC => self.escaped = true,
+                _ => self.buf.push(byte),
+            }
+        }
+        frames
+    }
+}
+
+/// SLIP encode a frame.
+fn slip_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![SLIP_START];
+    for &byte in data {
+        match byte {
+            SLIP_START => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_START]),
+            SLIP_END => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            other => encoded.push(other),
+        }
+    }
+    encoded.push(SLIP_END);
+    encoded
+}
+
+/// Build a request to the sniffer.
+fn request(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![
+        HEADER_LEN as u8,
+        payload.len() as u8,
+        REQUEST_PROTOCOL_VERSION,
+        0,
+        0,
+        id,
+    ];
+    packet.extend_from_slice(payload);
+    slip_encode(&packet)
+}
+
+/// A link layer packet reported by the sniffer.
+#[derive(Debug, PartialEq)]
+pub struct SnifferPacket<'a> {
+    pub channel: u8,
+    pub rssi: i8,
+    pub crc_ok: bool,
+    /// The PDU (header and payload, without access address and CRC)
+    pub pdu: &'a [u8],
+}
+
+/// Parse a (decoded) sniffer frame.
+pub fn parse_frame(frame: &[u8]) -> Result<Option<SnifferPacket<'_>>, &'static str> {
+    if frame.len() < HEADER_LEN {
+        return Err("Sniffer packet too short");
+    }
+    let protocol_version = frame[2];
+    if !(2..=3).contains(&protocol_version) {
+        return Err("Unsupported sniffer protocol version");
+    }
+    let payload_len = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+    let payload = frame
+        .get(HEADER_LEN..HEADER_LEN + payload_len)
+        .ok_or("Sniffer packet payload truncated")?;
+    match frame[5] {
+        EVENT_PACKET_ADV_PDU | EVENT_PACKET_DATA_PDU => {}
+        _ => return Ok(None),
+    }
+
+    // BLE packet metadata
+    let metadata_len = *payload.first().ok_or("BLE packet metadata truncated")? as usize;
+    if metadata_len < BLE_METADATA_LEN || payload.len() < metadata_len {
+        return Err("BLE packet metadata truncated");
+    }
+    let flags = payload[1];
+    if flags & FLAG_PHY_MASK != 0 {
+        // Only the 1M PHY has the same packet layout as legacy advertisements
+        return Ok(None);
+    }
+    let ble_packet = &payload[metadata_len..];
+
+    // Access address, PDU header (2 bytes) and payload, CRC (3 bytes)
+    if ble_packet.len() < ACCESS_ADDRESS_LEN + 2 {
+        return Err("BLE packet truncated");
+    }
+    let pdu_len = 2 + ble_packet[ACCESS_ADDRESS_LEN + 1] as usize;
+    let pdu = ble_packet
+        .get(ACCESS_ADDRESS_LEN..ACCESS_ADDRESS_LEN + pdu_len)
+        .ok_or("BLE PDU truncated")?;
+
+    Ok(Some(SnifferPacket {
+        channel: payload[2],
+        rssi: (payload[3] as i8).wrapping_neg(),
+        crc_ok: flags & FLAG_CRC_OK != 0,
+        pdu,
+    }))
+}
+
+/// Convert an advertising PDU into an HCI LE Advertising Report event.
+///
+/// Return `None` for PDUs that aren't advertisements with advertising data.
+pub fn to_advertising_report_event(pdu: &[u8], rssi: i8) -> Option<Vec<u8>> {
+    let pdu_type = pdu[0] & 0x0f;
+    let address_type = (pdu[0] >> 6) & 1;
+
+    // Map PDU type to HCI advertising report event type
+    let event_type = match pdu_type {
+        0b0000 => 0x00, // ADV_IND
+        0b0010 => 0x03, // ADV_NONCONN_IND
+        0b0100 => 0x04, // SCAN_RSP
+        0b0110 => 0x02, // ADV_SCAN_IND
+        0b0111 => return to_extended_advertising_report_event(pdu, rssi),
+        _ => return None,
+    };
+
+    // Payload: Advertiser address, followed by advertising data
+    let payload = &pdu[2..];
+    if payload.len() < 6 {
+        return None;
+    }
+    let (address, data) = payload.split_at(6);
+    if data.len() > MAX_ADV_DATA_LEN {
+        // Not a valid legacy advertisement, it would not fit into the event
+        return None;
+    }
+
+    let mut params = vec![0x02, 1, event_type, address_type];
+    params.extend_from_slice(address);
+    params.push(data.len() as u8);
+    params.extend_from_slice(data);
+    params.push(rssi as u8);
+
+    let mut event = vec![0x04, 0x3e, params.len() as u8];
+    event.extend(params);
+    Some(event)
+}
+
+/// Convert an `AUX_ADV_IND` into an HCI LE Extended Advertising Report event.
+///
+/// Only complete advertisements are converted: No `ADV_EXT_IND` (which
+/// doesn't contain the data) and no `AUX_ADV_IND` that is continued in an
+/// `AUX_CHAIN_IND`.
+fn to_extended_advertising_report_event(pdu: &[u8], rssi: i8) -> Option<Vec<u8>> {
+    let address_type = (pdu[0] >> 6) & 1;
+
+    // Payload: Extended header length and advertising mode, extended header
+    // (flags and fields), followed by advertising data
+    let payload = &pdu[2..];
+    let header_len = (*payload.first()? & 0x3f) as usize;
+    let adv_mode = payload[0] >> 6;
+    let header = payload.get(1..1 + header_len)?;
+    let data = &payload[1 + header_len..];
+    let flags = *header.first()?;
+    if flags & EXT_HEADER_ADV_A == 0 || flags & EXT_HEADER_AUX_PTR != 0 {
+        return None;
+    }
+    if data.len() > MAX_EXT_ADV_DATA_LEN {
+        return None;
+    }
+    let mut address = None;
+    let mut sid = 0xff;
+    let mut fields = &header[1..];
+    for &(flag, len) in &EXT_HEADER_FIELDS {
+        if flags & flag == 0 {
+            continue;
+        }
+        let field = fields.get(..len)?;
+        match flag {
+            EXT_HEADER_ADV_A => address = Some(field),
+            EXT_HEADER_ADI => sid = field[1] >> 4,
+            _ => {}
+        }
+        fields = &fields[len..];
+    }
+
+    // Event type (connectable and scannable bits as in the advertising mode,
+    // data complete), address, primary and secondary PHY (1M), advertising
+    // SID, TX power (not available), RSSI, periodic advertising interval
+    // (none), direct address type and address (none)
+    let mut params = vec![0x0d, 1, adv_mode & 0b11, 0x00, address_type];
+    params.extend_from_slice(address?);
+    params.extend_from_slice(&[0x01, 0x01, sid, 0x7f, rssi as u8, 0x00, 0x00]);
+    params.extend_from_slice(&[0x00; 7]);
+    params.push(data.len() as u8);
+    params.extend_from_slice(data);
+
+    let mut event = vec![0x04, 0x3e, params.len() as u8];
+    event.extend(params);
+    Some(event)
+}
+
+#[cfg(unix)]
+fn configure_port(port: &File, baud_rate: u32) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let speed = match baud_rate {
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        #[cfg(target_os = "linux")]
+        460_800 => libc::B460800,
+        #[cfg(target_os = "linux")]
+        1_000_000 => libc::B1000000,
+        other => anyhow::bail!("Unsupported baud rate: {}", other),
+    };
+
+    let fd = port.as_raw_fd();
+    // Safety: The termios struct is initialized by tcgetattr and the file
+    // descriptor is valid for the lifetime of `port`.
+    unsafe {
+        let mut tio: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut tio) != 0 {
+            return Err(std::io::Error::last_os_error()).context("tcgetattr failed");
+        }
+        libc::cfmakeraw(&mut tio);
+        tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tio.c_cc[libc::VMIN] = 1;
+        tio.c_cc[libc::VTIME] = 0;
+        libc::cfsetispeed(&mut tio, speed);
+        libc::cfsetospeed(&mut tio, speed);
+        if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+            return Err(std::io::Error::last_os_error()).context("tcsetattr failed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn configure_port(_port: &File, _baud_rate: u32) -> Result<()> {
+    // The port settings cannot be changed. This works fine for the USB
+    // dongles (CDC ACM) which ignore the baud rate anyway.
+    Ok(())
+}
+
+pub fn open(port: &str, baud_rate: u32) -> Result<PacketStream> {
+    status!("Opening nRF Sniffer on {}...", port);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(port)
+        .with_context(|| format!("Could not open serial port {}", port))?;
+    configure_port(&file, baud_rate)
+        .with_context(|| format!("Could not configure serial port {}", port))?;
+
+    // Scan continuously on all advertising channels
+    file.write_all(&request(REQ_SCAN_CONT, &[0]))
+        .context("Could not send scan request to sniffer")?;
+
+    let file = smol::Unblock::new(file);
+    let stream = futures::stream::unfold(
+        (file, SlipDecoder::new(), vec![0; 4096]),
+        |(mut file, mut decoder, mut buf)| async move {
+            let len = match file.read(&mut buf).await {
+                Ok(0) => {
+                    status!("Serial port closed");
+                    return None;
+                }
+                Ok(len) => len,
+                Err(e) => {
+                    status!("Error: Could not read from serial port: {}", e);
+                    return None;
+                }
+            };
+            let timestamp = SystemTime::now();
+            let packets = decoder
+                .push(&buf[..len])
+                .iter()
+                .filter_map(|frame| match parse_frame(frame) {
+                    Ok(Some(packet)) if !packet.crc_ok => {
+                        log::debug!("Ignoring packet with invalid CRC");
+                        None
+                    }
+                    Ok(Some(packet)) => {
+                        let data = to_advertising_report_event(packet.pdu, packet.rssi)?;
+                        Some(HciPacket {
+                            timestamp,
+                            channel: Some(packet.channel),
+                            data,
+                        })
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        log::debug!("Could not parse sniffer packet: {}", e);
+                        None
+                    }
+                })
+                .collect();
+            Some((packets, (file, decoder, buf)))
+        },
+    );
+    Ok(stream.boxed_local())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    const FRAME: [u8; 34] = [
+        // Header: payload length, protocol version, packet counter, packet ID
+        28, 0, 3, 0x01, 0x00, EVENT_PACKET_ADV_PDU,
+        // BLE metadata: header length, flags, channel, RSSI, event counter, timestamp
+        10, 0x01, 38, 60, 0, 0, 0, 0, 0, 0,
+        // Access address
+        0xd6, 0xbe, 0x89, 0x8e,
+        // PDU header: ADV_NONCONN_IND with random address, length
+        0x42, 9,
+        // Advertiser address
+        0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86,
+        // Advertising data
+        2, 0x01, 0x06,
+        // CRC
+        0x11, 0x22, 0x33,
+    ];
+
+    #[test]
+    fn slip_roundtrip() {
+        let data = [1, SLIP_START, 2, SLIP_END, 3, SLIP_ESC];
+        let encoded = slip_encode(&data);
+        let mut decoder = SlipDecoder::new();
+        let mut frames = decoder.push(&[0x00, 0x01]);
+        frames.extend(decoder.push(&encoded[..3]));
+        frames.extend(decoder.push(&encoded[3..]));
+        assert_eq!(frames, vec![data.to_vec()]);
+    }
+
+    #[test]
+    fn parse_adv_frame() {
+        let packet = parse_frame(&FRAME).unwrap().unwrap();
+        assert_eq!(packet.channel, 38);
+        assert_eq!(packet.rssi, -60);
+        assert!(packet.crc_ok);
+        assert_eq!(packet.pdu.len(), 11);
+    }
+
+    #[test]
+    fn parse_truncated_frame() {
+        assert!(parse_frame(&FRAME[..20]).is_err());
+    }
+
+    #[test]
+    fn parse_invalid_metadata_len() {
+        let mut frame = FRAME;
+        frame[HEADER_LEN] = 200;
+        assert!(parse_frame(&frame).is_err());
+    }
+
+    #[test]
+    fn convert_oversized_pdu() {
+        let mut pdu = vec![0x02, 6 + 40];
+        pdu.extend_from_slice(&[0; 6 + 40]);
+        assert_eq!(to_advertising_report_event(&pdu, -60), None);
+    }
+
+    #[test]
+    fn convert_extended_advertisement() {
+        #[rustfmt::skip]
+        let mut pdu = vec![
+            // PDU header: AUX_ADV_IND with random address, length
+            0x47, 13,
+            // Extended header length (AdvA, ADI), non-connectable and non-scannable
+            9,
+            // Extended header flags, AdvA, ADI (DID 0x123, SID 2)
+            0x09, 0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86, 0x23, 0x21,
+            // Advertising data
+            2, 0x01, 0x06,
+        ];
+        let event = to_advertising_report_event(&pdu, -60).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(event, vec![
+            0x04, 0x3e, 29,
+            0x0d, 1, 0x00, 0x00, 1,
+            0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86,
+            0x01, 0x01, 2, 0x7f, (-60i8) as u8, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            3, 2, 0x01, 0x06,
+        ]);
+        let params = &event[3..];
+        let reports = crate::advertising::parse_extended_advertising_report(params).unwrap();
+        assert_eq!(reports.len(), 1);
+
+        // An ADV_EXT_IND (with AuxPtr, without AdvA) isn't converted
+        pdu[2] = 6;
+        pdu[3] = 0x18;
+        pdu.truncate(2 + 1 + 6);
+        pdu[1] = 7;
+        assert_eq!(to_advertising_report_event(&pdu, -60), None);
+    }
+
+    #[test]
+    fn convert_to_hci_event() {
+        let packet = parse_frame(&FRAME).unwrap().unwrap();
+        let event = to_advertising_report_event(packet.pdu, packet.rssi).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(event, vec![
+            0x04, 0x3e, 15,
+            0x02, 1, 0x03, 1,
+            0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86,
+            3, 2, 0x01, 0x06,
+            (-60i8) as u8,
+        ]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Live capture of HCI packets through libpcap (Linux only).
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use pcap_async::{Config, Handle, Packet};
+
+use super::{HciPacket, PacketStream};
+use crate::types::Address;
+
+/// Length of the pseudo header (direction) in front of the H4 packet
+/// (link type `DLT_BLUETOOTH_HCI_H4_WITH_PHDR`).
+const PSEUDO_HEADER_LEN: usize = 4;
+
+/// Extend a capture filter, so that only the advertising reports of the
+/// addresses pass. Only the first report of every event is checked (the
+/// controllers virtually always send a single report per event).
+pub fn address_filter(filter: &str, addresses: &[Address]) -> String {
+    if addresses.is_empty() {
+        return filter.into();
+    }
+    // The address of the first report (least significant byte first) starts
+    // at offset 7 of an H4 packet with an LE Advertising Report, and at
+    // offset 8 with an LE Extended Advertising Report
+    let matches = |offset: usize| {
+        addresses
+            .iter()
+            .map(|Address(a)| {
+                format!(
+                    "(link[{}:4] == 0x{:02x}{:02x}{:02x}{:02x} and link[{}:2] == 0x{:02x}{:02x})",
+                    offset,
+                    a[5],
+                    a[4],
+                    a[3],
+                    a[2],
+                    offset + 4,
+                    a[1],
+                    a[0]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" or ")
+    };
+    let reports = format!(
+        "(link[7] == 0x02 and ({})) or (link[7] == 0x0d and ({}))",
+        matches(PSEUDO_HEADER_LEN + 7),
+        matches(PSEUDO_HEADER_LEN + 8)
+    );
+    if filter.is_empty() {
+        reports
+    } else {
+        format!("({}) and ({})", filter, reports)
+    }
+}
+
+pub fn open(interface: &str, filter: &str) -> Result<PacketStream> {
+    status!("Available bluetooth capture interfaces:");
+    for iface in pcap_async::Info::all().context("Could not get list of interfaces")? {
+        if iface.name.contains("blue") || iface.name.contains("ble") {
+            status!("  - {}", iface.name);
+            for ip in iface.ips {
+                status!("    - {}", ip);
+            }
+        }
+    }
+
+    status!("Opening device {}...", interface);
+    let handle = Handle::live_capture(interface).context("No handle created")?;
+
+    let mut pcap_config = Config::default();
+    pcap_config.with_blocking(true);
+    if !filter.is_empty() {
+        // Drop packets that are not needed before they are copied to userspace
+        log::debug!("Capture filter: {}", filter);
+        pcap_config.with_bpf(filter.into());
+    }
+
+    let stream = pcap_async::PacketStream::new(pcap_config, Arc::clone(&handle))
+        .context("Failed to build packet stream")?;
+    Ok(stream
+        .filter_map(|packets_result| async move {
+            match packets_result {
+                Ok(packets) => Some(packets.into_iter().filter_map(to_hci_packet).collect()),
+                Err(e) => {
+                    status!("Error: {:?}", e);
+                    None
+                }
+            }
+        })
+        .boxed_local())
+}
+
+fn to_hci_packet(packet: Packet) -> Option<HciPacket> {
+    log::trace!("{:?}", packet);
+
+    // Validate length
+    if packet.original_length() != packet.actual_length() {
+        log::debug!(
+            "Invalid packet length: {} != {}",
+            packet.original_length(),
+            packet.actual_length()
+        );
+        return None;
+    }
+    if packet.data().len() < PSEUDO_HEADER_LEN {
+        log::debug!("Packet too short: {} bytes", packet.data().len());
+        return None;
+    }
+
+    Some(HciPacket {
+        timestamp: *packet.timestamp(),
+        channel: None,
+        data: packet.data()[PSEUDO_HEADER_LEN..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let addresses = [Address::from_hex("864fe067997a")];
+        assert_eq!(
+            address_filter("link[4] == 0x04", &addresses),
+            "(link[4] == 0x04) and ((link[7] == 0x02 and \
+             ((link[11:4] == 0x7a9967e0 and link[15:2] == 0x4f86))) or \
+             (link[7] == 0x0d and ((link[12:4] == 0x7a9967e0 and link[16:2] == 0x4f86))))"
+        );
+        assert_eq!(address_filter("link[4] == 0x04", &[]), "link[4] == 0x04");
+    }
+}
@@ -0,0 +1,434 @@
+//! Read HCI packets from capture files written by Wireshark or tcpdump.
+//!
+//! Both the classic pcap format (with microsecond or nanosecond timestamps,
+//! in either byte order) and the pcapng format are supported. The packets
+//! must have one of the Bluetooth HCI link types.
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+
+use super::HciPacket;
+
+/// Magic numbers of the classic pcap format
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Block types and byte order magic of the pcapng format
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Interface description option with the timestamp resolution
+const OPTION_IF_TSRESOL: u16 = 9;
+
+/// Supported link types
+const LINKTYPE_BLUETOOTH_HCI_H4: u32 = 187;
+const LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR: u32 = 201;
+const LINKTYPE_BLUETOOTH_LINUX_MONITOR: u32 = 254;
+
+/// Packet type indicator of HCI events
+const H4_EVENT: u8 = 0x04;
+
+/// Monitor opcode of HCI events (link type `LINUX_MONITOR`)
+const MONITOR_EVENT_PKT: u16 = 3;
+
+#[derive(Clone, Copy)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = bytes[..2].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+/// Read all HCI packets of a capture file.
+pub fn read(path: &Path) -> Result<Vec<HciPacket>> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Could not read capture file {}", path.display()))?;
+    parse(&data).with_context(|| format!("Invalid capture file {}", path.display()))
+}
+
+/// Parse a capture file (in the pcap or pcapng format).
+pub fn parse(data: &[u8]) -> Result<Vec<HciPacket>> {
+    if data.len() < 4 {
+        bail!("File too short");
+    }
+    let magic = ByteOrder::Little.u32(data);
+    if magic == BLOCK_SECTION_HEADER {
+        parse_pcapng(data)
+    } else {
+        parse_pcap(data)
+    }
+}
+
+fn parse_pcap(data: &[u8]) -> Result<Vec<HciPacket>> {
+    if data.len() < FILE_HEADER_LEN {
+        bail!("File header truncated");
+    }
+    let (order, nanos) = match ByteOrder::Little.u32(data) {
+        MAGIC_MICROS => (ByteOrder::Little, false),
+        MAGIC_NANOS => (ByteOrder::Little, true),
+        _ => match ByteOrder::Big.u32(data) {
+            MAGIC_MICROS => (ByteOrder::Big, false),
+            MAGIC_NANOS => (ByteOrder::Big, true),
+            _ => bail!("Not a pcap or pcapng file"),
+        },
+    };
+    let linktype = order.u32(&data[20..24]) & 0xffff;
+    check_linktype(linktype)?;
+
+    let mut packets = vec![];
+    let mut offset = FILE_HEADER_LEN;
+    while offset < data.len() {
+        let header = data
+            .get(offset..offset + RECORD_HEADER_LEN)
+            .context("Record header truncated")?;
+        let seconds = order.u32(&header[0..4]);
+        let fraction = order.u32(&header[4..8]);
+        let included_len = order.u32(&header[8..12]) as usize;
+        let start = offset + RECORD_HEADER_LEN;
+        let record = data
+            .get(start..start + included_len)
+            .context("Record truncated")?;
+        let timestamp = UNIX_EPOCH
+            + Duration::from_secs(u64::from(seconds))
+            + if nanos {
+                Duration::from_nanos(u64::from(fraction))
+            } else {
+                Duration::from_micros(u64::from(fraction))
+            };
+        packets.extend(to_hci_packet(linktype, timestamp, record));
+        offset = start + included_len;
+    }
+    Ok(packets)
+}
+
+/// A pcapng interface, with the link type and the timestamp resolution (in
+/// units per second).
+struct Interface {
+    linktype: u32,
+    units_per_second: u64,
+}
+
+fn parse_pcapng(data: &[u8]) -> Result<Vec<HciPacket>> {
+    let mut packets = vec![];
+    let mut order = ByteOrder::Little;
+    let mut interfaces: Vec<Interface> = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data.get(offset..offset + 12).context("Block truncated")?;
+        let block_type = order.u32(&header[0..4]);
+        if block_type == BLOCK_SECTION_HEADER {
+            // Every section may have a different byte order, and has its own
+            // interfaces
+            order = match ByteOrder::Little.u32(&header[8..12]) {
+                BYTE_ORDER_MAGIC => ByteOrder::Little,
+                _ if ByteOrder::Big.u32(&header[8..12]) == BYTE_ORDER_MAGIC => ByteOrder::Big,
+                _ => bail!("Invalid byte order magic"),
+            };
+            interfaces.clear();
+        }
+        let block_len = order.u32(&header[4..8]) as usize;
+        if block_len < 12 || !block_len.is_multiple_of(4) {
+            bail!("Invalid block length: {}", block_len);
+        }
+        let block = data
+            .get(offset..offset + block_len)
+            .context("Block truncated")?;
+        let body = &block[8..block_len - 4];
+
+        match block_type {
+            BLOCK_INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    bail!("Interface description block truncated");
+                }
+                let linktype = u32::from(order.u16(&body[0..2]));
+                check_linktype(linktype)?;
+                interfaces.push(Interface {
+                    linktype,
+                    units_per_second: timestamp_resolution(order, &body[8..])?,
+                });
+            }
+            BLOCK_ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    bail!("Enhanced packet block truncated");
+                }
+                let interface = interfaces
+                    .get(order.u32(&body[0..4]) as usize)
+                    .context("Packet of an unknown interface")?;
+                let units =
+                    u64::from(order.u32(&body[4..8])) << 32 | u64::from(order.u32(&body[8..12]));
+                let captured_len = order.u32(&body[12..16]) as usize;
+                let record = body
+                    .get(20..20 + captured_len)
+                    .context("Enhanced packet block truncated")?;
+                let per_second = interface.units_per_second;
+                let timestamp = UNIX_EPOCH
+                    + Duration::from_secs(units / per_second)
+                    + Duration::from_nanos(
+                        ((units % per_second) as u128 * 1_000_000_000 / per_second as u128) as u64,
+                    );
+                packets.extend(to_hci_packet(interface.linktype, timestamp, record));
+            }
+            // Other blocks (e.g. statistics) are not needed
+            _ => {}
+        }
+        offset += block_len;
+    }
+    Ok(packets)
+}
+
+/// Parse the options of an interface description block. Return the
+/// timestamp resolution (in units per second, microseconds by default).
+fn timestamp_resolution(order: ByteOrder, mut options: &[u8]) -> Result<u64> {
+    while options.len() >= 4 {
+        let code = order.u16(&options[0..2]);
+        let len = order.u16(&options[2..4]) as usize;
+        let value = options.get(4..4 + len).context("Option truncated")?;
+        if code == OPTION_IF_TSRESOL {
+            let resolution = *value.first().context("Option truncated")?;
+            // The MSB selects the base (2 or 10), the other bits are the
+            // negative exponent
+            let exponent = u32::from(resolution & 0x7f);
+            let units = if resolution & 0x80 == 0 {
+                10u64.checked_pow(exponent)
+            } else {
+                2u64.checked_pow(exponent)
+            };
+            return units
+                .filter(|&units| units > 0)
+                .with_context(|| format!("Unsupported timestamp resolution: {}", resolution));
+        }
+        // Options are padded to 32 bits
+        let padded = (4 + len + 3) & !3;
+        options = options.get(padded..).unwrap_or(&[]);
+    }
+    Ok(1_000_000)
+}
+
+fn check_linktype(linktype: u32) -> Result<()> {
+    match linktype {
+        LINKTYPE_BLUETOOTH_HCI_H4
+        | LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR
+        | LINKTYPE_BLUETOOTH_LINUX_MONITOR => Ok(()),
+        other => bail!("Unsupported link type: {}", other),
+    }
+}
+
+/// Convert a record to an H4 packet. Return `None` for records that can't
+/// contain HCI events.
+fn to_hci_packet(linktype: u32, timestamp: SystemTime, record: &[u8]) -> Option<HciPacket> {
+    let data = match linktype {
+        LINKTYPE_BLUETOOTH_HCI_H4 => record.to_vec(),
+        // Direction (u32)
+        LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR => record.get(4..)?.to_vec(),
+        // Adapter index (u16 BE), opcode (u16 BE)
+        LINKTYPE_BLUETOOTH_LINUX_MONITOR => {
+            let opcode = u16::from_be_bytes(record.get(2..4)?.try_into().unwrap());
+            if opcode != MONITOR_EVENT_PKT {
+                return None;
+            }
+            let mut data = vec![H4_EVENT];
+            data.extend_from_slice(&record[4..]);
+            data
+        }
+        _ => return None,
+    };
+    Some(HciPacket {
+        timestamp,
+        channel: None,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT: [u8; 4] = [0x04, 0x3e, 0x01, 0x02];
+
+    fn pcap(magic: u32, big_endian: bool, linktype: u32, records: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut data = u32_bytes(magic).to_vec();
+        data.extend_from_slice(&u16_bytes(2));
+        data.extend_from_slice(&u16_bytes(4));
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&u32_bytes(65535));
+        data.extend_from_slice(&u32_bytes(linktype));
+        for (seconds, fraction, record) in records {
+            data.extend_from_slice(&u32_bytes(*seconds));
+            data.extend_from_slice(&u32_bytes(*fraction));
+            data.extend_from_slice(&u32_bytes(record.len() as u32));
+            data.extend_from_slice(&u32_bytes(record.len() as u32));
+            data.extend_from_slice(record);
+        }
+        data
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        while !body.len().is_multiple_of(4) {
+            body.push(0);
+        }
+        let len = (body.len() as u32 + 12).to_le_bytes();
+        let mut block = block_type.to_le_bytes().to_vec();
+        block.extend_from_slice(&len);
+        block.extend(body);
+        block.extend_from_slice(&len);
+        block
+    }
+
+    fn section_header() -> Vec<u8> {
+        let mut body = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        body.extend_from_slice(&[1, 0, 0, 0]);
+        body.extend_from_slice(&u64::MAX.to_le_bytes());
+        block(BLOCK_SECTION_HEADER, &body)
+    }
+
+    fn interface(linktype: u16, tsresol: Option<u8>) -> Vec<u8> {
+        let mut body = linktype.to_le_bytes().to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        if let Some(tsresol) = tsresol {
+            body.extend_from_slice(&OPTION_IF_TSRESOL.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&[tsresol, 0, 0, 0]);
+            // End of options
+            body.extend_from_slice(&[0; 4]);
+        }
+        block(BLOCK_INTERFACE_DESCRIPTION, &body)
+    }
+
+    fn enhanced_packet(interface: u32, units: u64, record: &[u8]) -> Vec<u8> {
+        let mut body = interface.to_le_bytes().to_vec();
+        body.extend_from_slice(&((units >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(units as u32).to_le_bytes());
+        body.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        body.extend_from_slice(record);
+        block(BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    #[test]
+    fn pcap_byte_orders() {
+        let mut record = vec![0, 0, 0, 1];
+        record.extend_from_slice(&EVENT);
+        for &big_endian in &[false, true] {
+            let data = pcap(
+                MAGIC_MICROS,
+                big_endian,
+                LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR,
+                &[(1_607_500_000, 250_000, &record)],
+            );
+            let packets = parse(&data).unwrap();
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].data, EVENT);
+            assert_eq!(
+                packets[0].timestamp,
+                UNIX_EPOCH + Duration::from_millis(1_607_500_000_250)
+            );
+        }
+    }
+
+    #[test]
+    fn pcap_nanoseconds() {
+        let data = pcap(
+            MAGIC_NANOS,
+            false,
+            LINKTYPE_BLUETOOTH_HCI_H4,
+            &[(1, 500, &EVENT), (2, 0, &EVENT)],
+        );
+        let packets = parse(&data).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].timestamp, UNIX_EPOCH + Duration::new(1, 500));
+        assert_eq!(packets[1].timestamp, UNIX_EPOCH + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn pcap_monitor() {
+        // New index (ignored), event
+        let data = pcap(
+            MAGIC_MICROS,
+            false,
+            LINKTYPE_BLUETOOTH_LINUX_MONITOR,
+            &[
+                (0, 0, &[0, 0, 0, 0, 1, 2]),
+                (0, 0, &[0, 0, 0, 3, 0x3e, 0x01, 0x02]),
+            ],
+        );
+        let packets = parse(&data).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, EVENT);
+    }
+
+    #[test]
+    fn pcapng() {
+        let mut data = section_header();
+        data.extend(interface(LINKTYPE_BLUETOOTH_HCI_H4 as u16, None));
+        // Nanosecond resolution
+        data.extend(interface(LINKTYPE_BLUETOOTH_HCI_H4 as u16, Some(9)));
+        data.extend(enhanced_packet(0, 1_500_000, &EVENT));
+        data.extend(enhanced_packet(1, 2_000_000_001, &EVENT[..3]));
+        let packets = parse(&data).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data, EVENT);
+        assert_eq!(
+            packets[0].timestamp,
+            UNIX_EPOCH + Duration::from_millis(1500)
+        );
+        assert_eq!(packets[1].data, &EVENT[..3]);
+        assert_eq!(packets[1].timestamp, UNIX_EPOCH + Duration::new(2, 1));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&[0; 24]).is_err());
+        // Ethernet
+        assert!(parse(&pcap(MAGIC_MICROS, false, 1, &[])).is_err());
+        // Truncated record
+        let data = pcap(
+            MAGIC_MICROS,
+            false,
+            LINKTYPE_BLUETOOTH_HCI_H4,
+            &[(0, 0, &EVENT)],
+        );
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        // Packet without interface
+        let mut data = section_header();
+        data.extend(enhanced_packet(0, 0, &EVENT));
+        assert!(parse(&data).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+//! Per-channel reception statistics, to find out whether frames are lost on
+//! a specific advertising channel (e.g. because of WiFi interference on
+//! channel 38).
+//!
+//! Only capture backends that report the channel a packet was received on
+//! (currently the nRF Sniffer) provide these statistics.
+use std::collections::HashMap;
+
+use crate::types::Address;
+
+/// The primary advertising channels.
+pub const ADVERTISING_CHANNELS: [u8; 3] = [37, 38, 39];
+
+/// Number of frames received per advertising channel (since the gateway
+/// started), including duplicates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelCounts {
+    frames: [u64; 3],
+}
+
+impl ChannelCounts {
+    /// The number of frames of every channel.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        ADVERTISING_CHANNELS
+            .iter()
+            .copied()
+            .zip(self.frames.iter().copied())
+    }
+}
+
+#[derive(Default)]
+pub struct ChannelStats {
+    counts: HashMap<Address, ChannelCounts>,
+}
+
+impl ChannelStats {
+    /// Count a frame of a device received on the channel. Frames of other
+    /// channels than the advertising channels are ignored.
+    pub fn record(&mut self, address: Address, channel: u8) {
+        match ADVERTISING_CHANNELS.iter().position(|&c| c == channel) {
+            Some(index) => self.counts.entry(address).or_default().frames[index] += 1,
+            None => log::debug!("Ignoring frame on channel {} for the statistics", channel),
+        }
+    }
+
+    /// The counts of a device, if any frame with a known channel was received.
+    pub fn get(&self, address: Address) -> Option<ChannelCounts> {
+        self.counts.get(&address).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut stats = ChannelStats::default();
+        assert_eq!(stats.get(address), None);
+
+        for &channel in &[37, 39, 39, 12] {
+            stats.record(address, channel);
+        }
+        let counts: Vec<(u8, u64)> = stats.get(address).unwrap().iter().collect();
+        assert_eq!(counts, vec![(37, 1), (38, 0), (39, 2)]);
+        assert_eq!(stats.get(Address([6, 5, 4, 3, 2, 1])), None);
+    }
+}
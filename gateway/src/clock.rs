@@ -0,0 +1,112 @@
+//! Synchronization state of the host clock.
+//!
+//! The timestamps of the measurements are taken from the host clock. On
+//! boards without a battery backed RTC (e.g. a Raspberry Pi), the clock may be
+//! far off after a boot until NTP has synchronized it. The state is read from
+//! the kernel (`adjtimex`, on Linux with glibc) or from `chronyc tracking`.
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Interval in which the state is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether the host clock is synchronized, `None` if unknown. Blocks while
+/// `chronyc` runs.
+pub fn synchronized() -> Option<bool> {
+    kernel_synchronized().or_else(chrony_synchronized)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn kernel_synchronized() -> Option<bool> {
+    // Safety: Only reads the state (the modes are zero)
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    if state == -1 {
+        return None;
+    }
+    Some(state != libc::TIME_ERROR && timex.status & libc::STA_UNSYNC == 0)
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn kernel_synchronized() -> Option<bool> {
+    None
+}
+
+fn chrony_synchronized() -> Option<bool> {
+    let output = Command::new("chronyc")
+        .args(["-c", "tracking"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_tracking(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the CSV output of `chronyc -c tracking`, the last field is the leap
+/// status.
+fn parse_tracking(output: &str) -> Option<bool> {
+    let fields: Vec<&str> = output.trim().split(',').collect();
+    if fields.len() < 14 {
+        return None;
+    }
+    Some(fields[fields.len() - 1] != "Not synchronised")
+}
+
+/// Checks the state periodically and logs changes.
+pub struct SyncMonitor {
+    last_check: Option<Instant>,
+    synchronized: Option<bool>,
+}
+
+impl SyncMonitor {
+    pub fn new() -> Self {
+        Self {
+            last_check: None,
+            synchronized: None,
+        }
+    }
+
+    /// Whether the timestamps taken now are approximate (the clock is known
+    /// not to be synchronized). The state is read on a blocking thread.
+    pub async fn is_approximate(&mut self, now: Instant) -> bool {
+        let due = match self.last_check {
+            Some(last) => now.duration_since(last) >= CHECK_INTERVAL,
+            None => true,
+        };
+        if due {
+            self.last_check = Some(now);
+            self.update(smol::unblock(synchronized).await);
+        }
+        self.synchronized == Some(false)
+    }
+
+    fn update(&mut self, synchronized: Option<bool>) {
+        match (self.synchronized, synchronized) {
+            (Some(true), Some(false)) | (None, Some(false)) => {
+                log::warn!("The host clock is not synchronized, timestamps are approximate")
+            }
+            (Some(false), Some(true)) => log::info!("The host clock is synchronized"),
+            _ => {}
+        }
+        self.synchronized = synchronized;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracking() {
+        let synchronized = "A29FC87B,162.159.200.123,3,1615390217.657160767,-0.000005185,\
+                            0.000012491,0.000018659,-8.247,-0.001,0.047,0.010929429,\
+                            0.000467033,64.3,Normal\n";
+        assert_eq!(parse_tracking(synchronized), Some(true));
+        let unsynchronized = "7F7F0101,,10,0.000000000,0.000000000,0.000000000,\
+                              0.000000000,0.000,0.000,0.000,1.000000000,1.000000000,\
+                              0.0,Not synchronised\n";
+        assert_eq!(parse_tracking(unsynchronized), Some(false));
+        assert_eq!(parse_tracking("506 Cannot talk to daemon"), None);
+    }
+}
@@ -1,22 +1,1426 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
 use serde::Deserialize;
 
+/// Prefix of the environment variables that override config values.
+const ENV_PREFIX: &str = "SENSILO__";
+
+/// Version of the config layout. Files without `version` are version 1.
+pub const VERSION: u64 = 2;
+
+/// Migrations of the layouts, by the version they migrate from.
+const MIGRATIONS: [fn(&mut toml::value::Table); VERSION as usize - 1] = [migrate_v1];
+
+/// Load the config file and apply the overrides from the environment (see
+/// `apply_env`). If the file is not `required`, a missing file is treated as
+/// empty, so that the config can be passed entirely through the environment.
+pub fn load(path: &Path, required: bool) -> Result<Config> {
+    let mut value = match std::fs::read_to_string(path) {
+        Ok(content) => toml::from_str(&content)
+            .with_context(|| format!("Could not parse config file {}", path.display()))?,
+        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+            toml::Value::Table(Default::default())
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Could not read config file {}", path.display()))
+        }
+    };
+    let vars = std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+    apply_env(&mut value, vars)?;
+    migrate(&mut value)?;
+    let mut config: Config = value
+        .try_into()
+        .map_err(|e| anyhow!(with_suggestion(e.to_string())))
+        .context("Invalid config")?;
+    config.flatten_sites();
+    Ok(config)
+}
+
+/// Override config values with the environment variables starting with
+/// `SENSILO__`. The rest of the name is the path of the value, with the
+/// sections separated by `__` (e.g. `SENSILO__INFLUXDB__PASSWORD` sets `password` in
+/// the `[influxdb]` section). The names are case insensitive.
+///
+/// Values are parsed as TOML values (e.g. numbers, booleans, arrays or inline
+/// tables). Anything else is used as string.
+fn apply_env(config: &mut toml::Value, vars: impl Iterator<Item = (String, String)>) -> Result<()> {
+    for (key, raw) in vars {
+        let path = match key.get(..ENV_PREFIX.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(ENV_PREFIX) => {
+                key[ENV_PREFIX.len()..].to_lowercase()
+            }
+            _ => continue,
+        };
+        log::debug!("Config value {} set from the environment", path);
+        let mut keys: Vec<&str> = path.split("__").collect();
+        let last = keys.pop().unwrap();
+        let mut table = config.as_table_mut().unwrap();
+        for section in keys {
+            table = match table
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+            {
+                toml::Value::Table(table) => table,
+                _ => bail!("Cannot set {}: {} is not a section", key, section),
+            };
+        }
+        table.insert(last.into(), parse_env_value(&raw));
+    }
+    Ok(())
+}
+
+/// Migrate the config to the current layout (see `VERSION`) and remove the
+/// version. Configs of newer versions are rejected.
+fn migrate(config: &mut toml::Value) -> Result<()> {
+    let table = config.as_table_mut().unwrap();
+    let version = match table.remove("version") {
+        None => 1,
+        Some(toml::Value::Integer(version)) if version >= 1 => version as u64,
+        Some(value) => bail!("Invalid config version {}", value),
+    };
+    if version > VERSION {
+        bail!(
+            "Config version {} is not supported by this gateway (at most version {}), \
+             please update the gateway",
+            version,
+            VERSION
+        );
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        log::info!("Migrating config from version {} to {}", from + 1, from + 2);
+        migration(table);
+    }
+    if version < VERSION {
+        log::warn!(
+            "Config version {} is outdated, please update it to version {}",
+            version,
+            VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Version 2: `pass` and `db` in `[influxdb]` are renamed to `password` and
+/// `database` (as in `[postgres]`).
+fn migrate_v1(config: &mut toml::value::Table) {
+    if let Some(toml::Value::Table(influxdb)) = config.get_mut("influxdb") {
+        rename(influxdb, "pass", "password");
+        rename(influxdb, "db", "database");
+    }
+}
+
+/// Rename a key. If the new key exists already (e.g. set from the
+/// environment), it takes precedence.
+fn rename(table: &mut toml::value::Table, from: &str, to: &str) {
+    if let Some(value) = table.remove(from) {
+        table.entry(to).or_insert(value);
+    }
+}
+
+/// Append the most similar name to the error of an unknown key or value (e.g.
+/// "did you mean `connection_string`?" for `connection_str`).
+fn with_suggestion(message: String) -> String {
+    match suggestion(&message) {
+        Some(name) => format!("{} (did you mean `{}`?)", message, name),
+        None => message,
+    }
+}
+
+/// The expected name that is most similar to the unknown one in a serde error
+/// (`unknown field `x`, expected one of `a`, `b``).
+fn suggestion(message: &str) -> Option<&str> {
+    let start = message
+        .find("unknown field `")
+        .or_else(|| message.find("unknown variant `"))?;
+    let rest = &message[start..];
+    let rest = &rest[rest.find('`')? + 1..];
+    let end = rest.find("`, expected ")?;
+    let unknown = &rest[..end];
+    let expected = &rest[end + "`, expected ".len()..];
+    let expected = &expected[..expected.find(" for key ").unwrap_or(expected.len())];
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|name| (edit_distance(unknown, name), name))
+        .filter(|(distance, _)| *distance <= (unknown.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+/// Edit distance of two strings (insertions, deletions, substitutions and
+/// transpositions of adjacent characters).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+fn parse_env_value(raw: &str) -> toml::Value {
+    match toml::from_str::<toml::Value>(&format!("value = {}", raw)) {
+        Ok(toml::Value::Table(mut table)) => table
+            .remove("value")
+            .unwrap_or_else(|| toml::Value::String(raw.into())),
+        _ => toml::Value::String(raw.into()),
+    }
+}
+
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
     pub devices: Vec<Device>,
-    pub influxdb: InfluxDb,
+    /// Groups of devices (e.g. apartments), moved to `devices` after loading
+    #[serde(default)]
+    pub sites: Vec<Site>,
+    pub influxdb: Option<InfluxDb>,
+    #[serde(default)]
+    pub capture: Capture,
+    #[serde(default)]
+    pub stats: Stats,
+    #[serde(default)]
+    pub dedup: Dedup,
+    #[serde(default)]
+    pub state: State,
+    #[serde(default)]
+    pub daemon: Daemon,
+    pub aggregation: Option<Aggregation>,
+    pub smoothing: Option<Smoothing>,
+    pub summary: Option<Summary>,
+    pub time_sync: Option<TimeSync>,
+    #[serde(default)]
+    pub units: Units,
+    #[serde(default)]
+    pub display: Display,
+    pub exec: Option<Exec>,
+    pub postgres: Option<Postgres>,
+    pub graphite: Option<Graphite>,
+    pub mqtt: Option<Mqtt>,
+    pub api: Option<Api>,
+    #[serde(default)]
+    #[cfg_attr(
+        not(any(feature = "sink-influxdb", feature = "acme")),
+        allow(dead_code)
+    )]
+    pub http: Http,
+    #[serde(default)]
+    pub filter: Filter,
+    #[serde(default)]
+    pub payload: Payload,
+    #[serde(default)]
+    pub debug: Debug,
+}
+
+impl Config {
+    /// Move the devices of the sites to `devices`, with the site set.
+    fn flatten_sites(&mut self) {
+        for site in self.sites.drain(..) {
+            for mut device in site.devices {
+                device.site = Some(site.name.clone());
+                self.devices.push(device);
+            }
+        }
+    }
 }
 
+/// A named group of devices.
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    pub name: String,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Device {
     pub name: String,
     pub hex_addr: String,
     pub location: Option<String>,
+    /// Site (group) of the device
+    pub site: Option<String>,
+    /// Expected measurement interval in seconds
+    pub interval_s: Option<u64>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Metric names of the analog inputs
+    #[serde(default)]
+    pub analog: Vec<AnalogChannel>,
+    /// Metrics that every measurement of the device should contain
+    #[serde(default)]
+    pub expects: Vec<Metric>,
+    /// Key for the payload authentication (32 hex digits). If set, only
+    /// authenticated payloads are accepted.
+    pub key: Option<String>,
+    /// Whether the frames end with a CRC (firmware feature `crc`). If set,
+    /// frames without a valid CRC are dropped.
+    #[serde(default)]
+    pub crc: bool,
+    /// Identity resolving key (32 hex digits), if the device uses resolvable
+    /// private addresses
+    pub irk: Option<String>,
+    /// Ignore frames with a lower RSSI (in dBm, overrides the global
+    /// threshold)
+    pub min_rssi: Option<i8>,
+}
+
+/// A metric that a device is expected to send.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Temperature,
+    Humidity,
+    AmbientLight,
+    ThermocoupleTemperature,
+    ExternalTemperature,
+    Pulses,
+    Analog,
+    Contact,
 }
 
+impl Metric {
+    pub const ALL: [Metric; 8] = [
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::AmbientLight,
+        Metric::ThermocoupleTemperature,
+        Metric::ExternalTemperature,
+        Metric::Pulses,
+        Metric::Analog,
+        Metric::Contact,
+    ];
+
+    /// Look up a metric by its name.
+    pub fn from_name(name: &str) -> Option<Metric> {
+        Metric::ALL
+            .iter()
+            .copied()
+            .find(|metric| metric.name() == name)
+    }
+
+    /// Name of the metric, as used in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::AmbientLight => "ambient_light",
+            Metric::ThermocoupleTemperature => "thermocouple_temperature",
+            Metric::ExternalTemperature => "external_temperature",
+            Metric::Pulses => "pulses",
+            Metric::Analog => "analog",
+            Metric::Contact => "contact",
+        }
+    }
+}
+
+/// Name of an analog input of a device.
 #[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AnalogChannel {
+    /// Number of the analog input (e.g. 4 for AIN4)
+    pub channel: u8,
+    /// Metric name
+    pub name: String,
+}
+
+/// Advertisement format of a device.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Sensilo payload in the manufacturer specific data
+    #[default]
+    Sensilo,
+    /// BTHome (v2) service data
+    Bthome,
+    /// Eddystone-TLM service data
+    #[serde(rename = "eddystone-tlm")]
+    EddystoneTlm,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sink-influxdb"), allow(dead_code))]
 pub struct InfluxDb {
     pub connection_string: String,
+    /// InfluxDB 1.x: user, password and database
+    #[serde(default)]
     pub user: String,
-    pub pass: String,
-    pub db: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub database: String,
+    /// InfluxDB 2.x: organization, bucket and API token (instead of the user,
+    /// password and database)
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+    /// Send at most one measurement per device within this interval (in
+    /// seconds)
+    pub min_interval_s: Option<u64>,
+    #[serde(default)]
+    pub schema: Schema,
+    /// Tag the points with `approx_time=true` while the host clock is not
+    /// synchronized
+    #[serde(default = "default_tag_approx_time")]
+    pub tag_approx_time: bool,
+    /// Create the database (or bucket) if it doesn't exist (requires admin
+    /// privileges, or a token that may create buckets)
+    #[serde(default)]
+    pub create_database: bool,
+    /// Compress the request bodies with gzip
+    #[serde(default)]
+    pub gzip: bool,
+    /// The timeouts default to the timeout in `[http]`
+    #[serde(default)]
+    pub connection: Connection,
+}
+
+fn default_tag_approx_time() -> bool {
+    true
+}
+
+/// Templates for the measurement names, tags and field names.
+///
+/// Available placeholders: `{metric}`, `{address}`, `{local_name}`, `{name}`,
+/// `{location}` and `{site}`. Field names may also use `{field}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sink-influxdb"), allow(dead_code))]
+pub struct Schema {
+    #[serde(default = "default_schema_measurement")]
+    pub measurement: String,
+    #[serde(default = "default_schema_field")]
+    pub field: String,
+    #[serde(default = "default_schema_tags")]
+    pub tags: BTreeMap<String, String>,
+}
+
+fn default_schema_measurement() -> String {
+    "{metric}".into()
+}
+
+fn default_schema_field() -> String {
+    "{field}".into()
+}
+
+fn default_schema_tags() -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    tags.insert("address".into(), "{address}".into());
+    tags.insert("local_name".into(), "{local_name}".into());
+    tags.insert("site".into(), "{site}".into());
+    tags
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema {
+            measurement: default_schema_measurement(),
+            field: default_schema_field(),
+            tags: default_schema_tags(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Stats {
+    /// Rolling windows (in seconds) over which the packet loss is calculated
+    #[serde(default = "default_loss_windows")]
+    pub loss_windows_s: Vec<u64>,
+}
+
+fn default_loss_windows() -> Vec<u64> {
+    vec![300, 3600]
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            loss_windows_s: default_loss_windows(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Daemon {
+    /// File where the PID is written to (with `--daemonize`)
+    pub pid_file: Option<PathBuf>,
+    /// File where the output is appended to (with `--daemonize`)
+    pub log_file: Option<PathBuf>,
+    /// Directory to change to (with `--daemonize`)
+    pub working_directory: Option<PathBuf>,
+    /// User to switch to after opening the capture device
+    pub user: Option<String>,
+    /// Group to switch to (default: the primary group of the user)
+    pub group: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Dedup {
+    /// Frames with a counter received within this window (in seconds) are
+    /// duplicates
+    #[serde(default = "default_dedup_window")]
+    pub window_s: u64,
+}
+
+fn default_dedup_window() -> u64 {
+    30
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Dedup {
+            window_s: default_dedup_window(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    /// File where the device state (last received counters, contacts, pulse
+    /// counts, missing metrics and deduplication windows) is kept across
+    /// restarts
+    pub file: Option<PathBuf>,
+    /// Write the file at most once within this interval (in seconds)
+    #[serde(default = "default_save_interval")]
+    pub save_interval_s: u64,
+}
+
+fn default_save_interval() -> u64 {
+    30
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            file: None,
+            save_interval_s: default_save_interval(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Exec {
+    /// Program and arguments
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub mode: ExecMode,
+    /// Send at most one measurement per device within this interval (in
+    /// seconds)
+    pub min_interval_s: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecMode {
+    /// Spawn the command once for every batch of measurements
+    Batch,
+    /// Spawn a long-running command and stream measurements to it
+    #[default]
+    Stream,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Api {
+    /// Address and port of the HTTP server
+    #[serde(default = "default_api_listen")]
+    pub listen: String,
+    /// Announce the API via mDNS (as `_sensilo._tcp`)
+    #[serde(default)]
+    pub mdns: bool,
+    /// The mDNS instance name, defaults to `Sensilo Gateway on <hostname>`
+    pub mdns_name: Option<String>,
+    /// Tokens for the read-only endpoints. Without any tokens, the API is
+    /// not protected.
+    #[serde(default)]
+    pub read_tokens: Vec<String>,
+    /// Tokens for all endpoints (including the reload)
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+    /// Number of measurements kept in memory per device for the CSV export
+    /// (0 disables it). The history is lost on restart.
+    #[serde(default = "default_api_history")]
+    pub history: usize,
+    /// Serve the API over HTTPS
+    pub tls: Option<ApiTls>,
+    /// Serve the API over HTTPS, with a certificate from an ACME CA
+    pub acme: Option<Acme>,
+}
+
+fn default_api_listen() -> String {
+    "127.0.0.1:8080".into()
+}
+
+fn default_api_history() -> usize {
+    1440
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Api {
+            listen: default_api_listen(),
+            mdns: false,
+            mdns_name: None,
+            read_tokens: vec![],
+            admin_tokens: vec![],
+            history: default_api_history(),
+            tls: None,
+            acme: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "api-tls"), allow(dead_code))]
+pub struct ApiTls {
+    /// PEM file with the certificate chain
+    pub certificate: String,
+    /// PEM file with the private key (PKCS#8 or RSA)
+    pub private_key: String,
+    /// Generate a self-signed certificate (and key) if the files don't exist
+    #[serde(default)]
+    pub self_signed: bool,
+    /// Host names or IP addresses of the self-signed certificate
+    #[serde(default = "default_api_tls_names")]
+    pub names: Vec<String>,
+}
+
+fn default_api_tls_names() -> Vec<String> {
+    vec!["localhost".into()]
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "acme"), allow(dead_code))]
+pub struct Acme {
+    /// Public host names of the gateway (the first is the common name)
+    pub domains: Vec<String>,
+    /// Contact URLs of the account, e.g. `mailto:admin@example.com`
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory URL of the CA (defaults to Let's Encrypt)
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    /// Agree to the terms of service of the CA (required)
+    #[serde(default)]
+    pub accept_terms: bool,
+    /// Directory for the account key and the certificate
+    #[serde(default = "default_acme_dir")]
+    pub dir: PathBuf,
+    /// Address and port of the HTTP server for the challenges
+    #[serde(default = "default_acme_challenge_listen")]
+    pub challenge_listen: String,
+    /// Renew the certificate this many days before it expires
+    #[serde(default = "default_acme_renew_days")]
+    pub renew_days: u64,
+}
+
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".into()
+}
+
+fn default_acme_dir() -> PathBuf {
+    "acme".into()
+}
+
+fn default_acme_challenge_listen() -> String {
+    "0.0.0.0:80".into()
+}
+
+fn default_acme_renew_days() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Postgres {
+    pub host: String,
+    #[serde(default = "default_postgres_port")]
+    pub port: u16,
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+    pub database: String,
+    #[serde(default = "default_postgres_table")]
+    pub table: String,
+    /// Convert the table to a TimescaleDB hypertable
+    #[serde(default)]
+    pub timescale: bool,
+    /// Send at most one measurement per device within this interval (in
+    /// seconds)
+    pub min_interval_s: Option<u64>,
+    /// Delete measurements that are older than this (in days)
+    pub retention_days: Option<u32>,
+    /// Keep hourly aggregates of the deleted measurements (in the table
+    /// `<table>_hourly`)
+    #[serde(default)]
+    pub compact: bool,
+    #[serde(default)]
+    pub connection: Connection,
+}
+
+fn default_postgres_port() -> u16 {
+    5432
+}
+
+fn default_postgres_table() -> String {
+    "sensilo_measurements".into()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Graphite {
+    #[serde(default)]
+    pub protocol: GraphiteProtocol,
+    pub host: String,
+    /// Defaults to 2003 (Graphite) or 8125 (StatsD)
+    pub port: Option<u16>,
+    /// Template for the metric path, with the same placeholders as the
+    /// InfluxDB schema
+    #[serde(default = "default_graphite_template")]
+    pub template: String,
+    /// Send at most one measurement per device within this interval (in
+    /// seconds)
+    pub min_interval_s: Option<u64>,
+    #[serde(default)]
+    pub connection: Connection,
+}
+
+fn default_graphite_template() -> String {
+    "sensilo.{name}.{metric}".into()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphiteProtocol {
+    /// Graphite plaintext protocol (TCP)
+    #[default]
+    Graphite,
+    /// StatsD gauges (UDP)
+    Statsd,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sink-mqtt"), allow(dead_code))]
+pub struct Mqtt {
+    pub host: String,
+    /// Defaults to 1883 (or 8883 with TLS)
+    pub port: Option<u16>,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Template for the topic, with the same placeholders as the InfluxDB
+    /// schema
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    #[serde(default)]
+    pub retain: bool,
+    pub tls: Option<MqttTls>,
+    /// Send at most one measurement per device within this interval (in
+    /// seconds)
+    pub min_interval_s: Option<u64>,
+    /// Template for the availability topic of every device (`online` or
+    /// `offline`, retained), e.g. `sensilo/{name}/availability`
+    pub availability_topic: Option<String>,
+    /// Devices are offline if no measurement was received within this time
+    /// (in seconds, default: three measurement intervals, or 300 s)
+    pub offline_after_s: Option<u64>,
+    /// Template for the topic of the device events (e.g. reboots), e.g.
+    /// `sensilo/{name}/event`
+    pub event_topic: Option<String>,
+    #[serde(default)]
+    pub connection: Connection,
+}
+
+fn default_mqtt_client_id() -> String {
+    "sensilo-gateway".into()
+}
+
+fn default_mqtt_topic() -> String {
+    "sensilo/{address}".into()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sink-mqtt"), allow(dead_code))]
+pub struct MqttTls {
+    /// PEM file with the CA certificates (defaults to the Mozilla root
+    /// certificates)
+    pub ca_file: Option<String>,
+    /// PEM file with the client certificate chain
+    pub client_cert: Option<String>,
+    /// PEM file with the client private key (PKCS#8 or RSA)
+    pub client_key: Option<String>,
+    /// ALPN protocols (e.g. `x-amzn-mqtt-ca` for AWS IoT on port 443)
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+/// Timeouts and retries of the submissions to a sink.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Connection {
+    /// Timeout for connecting (in seconds, default: 10 s)
+    pub connect_timeout_s: Option<u64>,
+    /// Timeout for every read, e.g. of the response (in seconds, default:
+    /// 10 s)
+    pub read_timeout_s: Option<u64>,
+    /// Timeout for every write (in seconds, default: 10 s)
+    pub write_timeout_s: Option<u64>,
+    /// Number of retries of a failed submission
+    #[serde(default)]
+    pub retries: u32,
+    /// Delay before the first retry (in milliseconds), doubled for every
+    /// further retry
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Maximum delay before a retry (in milliseconds)
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Connection {
+            connect_timeout_s: None,
+            read_timeout_s: None,
+            write_timeout_s: None,
+            retries: 0,
+            backoff_ms: default_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+/// Settings of the HTTP client (used by the InfluxDB sink and ACME).
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(
+    not(any(feature = "sink-influxdb", feature = "acme")),
+    allow(dead_code)
+)]
+pub struct Http {
+    /// Timeout for connecting, and for every read and write (in seconds)
+    #[serde(default = "default_http_timeout")]
+    pub timeout_s: u64,
+    /// Proxy for all requests (default: from `http_proxy` / `https_proxy`)
+    pub proxy: Option<String>,
+    /// Hosts that are accessed without the proxy (default: from `no_proxy`)
+    pub no_proxy: Option<Vec<String>>,
+}
+
+fn default_http_timeout() -> u64 {
+    5
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            timeout_s: default_http_timeout(),
+            proxy: None,
+            no_proxy: None,
+        }
+    }
+}
+
+/// Filtering of the received frames.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// Ignore frames with a lower RSSI (in dBm), e.g. of the same devices in
+    /// a neighboring deployment
+    pub min_rssi: Option<i8>,
+}
+
+/// Decoding of the Sensilo payloads.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Payload {
+    #[serde(default)]
+    pub mode: ParseMode,
+    /// Forward the entries with unknown types hex-encoded to the sinks (as
+    /// `raw_0x..` fields)
+    #[serde(default)]
+    pub forward_unknown: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseMode {
+    /// Skip the entries with unknown types
+    #[default]
+    Lenient,
+    /// Reject payloads with unknown entry types or trailing bytes
+    Strict,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Debug {
+    /// Number of raw frames retained per device
+    #[serde(default = "default_raw_frames")]
+    pub raw_frames: usize,
+    /// Number of parse errors retained per device
+    #[serde(default = "default_parse_errors")]
+    pub parse_errors: usize,
+    /// Print an annotated hex dump of every accepted payload
+    #[serde(default)]
+    pub hexdump: bool,
+}
+
+fn default_raw_frames() -> usize {
+    16
+}
+
+fn default_parse_errors() -> usize {
+    16
+}
+
+impl Default for Debug {
+    fn default() -> Self {
+        Debug {
+            raw_frames: default_raw_frames(),
+            parse_errors: default_parse_errors(),
+            hexdump: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Units {
+    #[serde(default)]
+    pub temperature: TemperatureUnit,
+    /// Export temperature and humidity as integer thousandths (e.g.
+    /// millidegrees) instead of floats
+    #[serde(default = "default_milli")]
+    pub milli: bool,
+}
+
+fn default_milli() -> bool {
+    true
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units {
+            temperature: TemperatureUnit::default(),
+            milli: default_milli(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+/// Units and number format of the console output.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Display {
+    /// Defaults to the unit in `[units]`
+    pub temperature: Option<TemperatureUnit>,
+    #[serde(default)]
+    pub light: LightUnit,
+    #[serde(default)]
+    pub decimal_comma: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LightUnit {
+    #[default]
+    Lux,
+    Footcandles,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Aggregation {
+    /// Length of the aggregation window in seconds
+    pub window_s: u64,
+    #[serde(default)]
+    pub function: AggregationFunction,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AggregationFunction {
+    #[default]
+    Mean,
+    Min,
+    Max,
+    Last,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Summary {
+    /// Length of the summary period in seconds (the periods start at
+    /// multiples of the length since the Unix epoch, i.e. midnight UTC for
+    /// daily summaries)
+    #[serde(default = "default_summary_period")]
+    pub period_s: u64,
+    /// Metric name of the summary points
+    #[serde(default = "default_summary_metric")]
+    pub metric: String,
+}
+
+fn default_summary_period() -> u64 {
+    86400
+}
+
+fn default_summary_metric() -> String {
+    "summary".into()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TimeSync {
+    /// Number of the adapter that broadcasts the time beacons (`hciN`)
+    #[serde(default)]
+    pub device: u16,
+    /// Advertising interval in milliseconds
+    #[serde(default = "default_time_sync_interval")]
+    pub interval_ms: u16,
+}
+
+fn default_time_sync_interval() -> u16 {
+    100
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Smoothing {
+    /// Keep the raw values of the smoothed metrics (as `raw` field)
+    #[serde(default)]
+    pub keep_raw: bool,
+    /// Filter by metric name
+    pub metrics: BTreeMap<String, SmoothingFilter>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "filter", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SmoothingFilter {
+    /// Median of the last `n` values
+    Median { n: usize },
+    /// Exponentially weighted moving average, the weight of the newest value
+    /// is `alpha`
+    Ewma { alpha: f64 },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Capture {
+    /// Live capture through libpcap (Linux only)
+    #[cfg_attr(not(feature = "capture-pcap"), allow(dead_code))]
+    Pcap {
+        #[serde(default = "default_pcap_interface")]
+        interface: String,
+        /// Capture filter, evaluated in the kernel (empty: no filter)
+        #[serde(default = "default_pcap_filter")]
+        filter: String,
+        /// Only pass the advertising reports of the configured devices
+        #[serde(default)]
+        filter_addresses: bool,
+    },
+    /// Live capture through a raw HCI socket (Linux only)
+    #[cfg_attr(not(feature = "capture-hci"), allow(dead_code))]
+    Hci {
+        /// Number of the adapter (`hciN`)
+        #[serde(default)]
+        device: u16,
+        /// Scan parameters, if the gateway should enable scanning itself
+        scan: Option<Scan>,
+    },
+    /// Read a btsnoop HCI log file
+    Btsnoop {
+        path: String,
+        /// Wait for new data at the end of the file
+        #[serde(default)]
+        follow: bool,
+    },
+    /// nRF52 with the Nordic nRF Sniffer firmware on a serial port
+    #[serde(rename = "nrf-sniffer")]
+    NrfSniffer {
+        port: String,
+        #[serde(default = "default_sniffer_baud_rate")]
+        baud_rate: u32,
+    },
+}
+
+/// Scan parameters of the adapter (with the `hci` capture backend).
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Scan {
+    /// Active scanning requests the scan responses of scannable
+    /// advertisements
+    #[serde(rename = "type", default)]
+    pub scan_type: ScanType,
+    /// Time between the starts of two scan windows (2.5 ms to 10.24 s)
+    #[serde(default = "default_scan_interval_ms")]
+    pub interval_ms: f64,
+    /// Duration of a scan window (at most the interval)
+    pub window_ms: Option<f64>,
+    /// Let the controller drop duplicate advertisements (before they reach
+    /// the gateway)
+    #[serde(default)]
+    pub filter_duplicates: bool,
+    /// Only report the advertisements of the configured devices (through
+    /// the accept list of the adapter)
+    #[serde(default)]
+    pub accept_list: bool,
+    /// Use the BLE 5 extended scanning commands, so that the adapter also
+    /// reports extended advertisements (e.g. of the `ext-adv` firmware)
+    #[serde(default)]
+    pub extended: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanType {
+    #[default]
+    Passive,
+    Active,
+}
+
+fn default_scan_interval_ms() -> f64 {
+    100.0
+}
+
+fn default_pcap_interface() -> String {
+    "bluetooth0".into()
+}
+
+/// Only pass HCI LE meta events (H4 packet type `0x04`, event code `0x3e`,
+/// after the 4 byte direction pseudo header), no ACL, SCO or ISO data.
+fn default_pcap_filter() -> String {
+    "link[4] == 0x04 and link[5] == 0x3e".into()
+}
+
+fn default_sniffer_baud_rate() -> u32 {
+    1_000_000
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Capture::Pcap {
+            interface: default_pcap_interface(),
+            filter: default_pcap_filter(),
+            filter_addresses: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn env_only() {
+        let mut value = toml::Value::Table(Default::default());
+        apply_env(
+            &mut value,
+            vars(&[
+                (
+                    "SENSILO__INFLUXDB__CONNECTION_STRING",
+                    "https://influxdb.example.com",
+                ),
+                ("SENSILO__INFLUXDB__USER", "influxuser"),
+                ("SENSILO__INFLUXDB__PASSWORD", "\"1234\""),
+                ("SENSILO__INFLUXDB__DATABASE", "sensilo"),
+                (
+                    "SENSILO__DEVICES",
+                    r#"[{ name = "Sensilo1", hex_addr = "864fe067997a", interval_s = 3 }]"#,
+                ),
+                ("SENSILO__DEBUG__HEXDUMP", "true"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        let config: Config = value.try_into().unwrap();
+        let influxdb = config.influxdb.unwrap();
+        assert_eq!(influxdb.connection_string, "https://influxdb.example.com");
+        assert_eq!(influxdb.password, "1234");
+        assert_eq!(config.devices.len(), 1);
+        assert_eq!(config.devices[0].hex_addr, "864fe067997a");
+        assert_eq!(config.devices[0].interval_s, Some(3));
+        assert!(config.debug.hexdump);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [capture]
+            backend = "btsnoop"
+            path = "hci.log"
+
+            [dedup]
+            window_s = 60
+            "#,
+        )
+        .unwrap();
+        apply_env(
+            &mut value,
+            vars(&[
+                ("SENSILO__CAPTURE__PATH", "/data/hci.log"),
+                ("sensilo__dedup__window_s", "120"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(value["capture"]["backend"].as_str(), Some("btsnoop"));
+        assert_eq!(value["capture"]["path"].as_str(), Some("/data/hci.log"));
+        assert_eq!(value["dedup"]["window_s"].as_integer(), Some(120));
+
+        // A value can't be replaced by a section
+        let err = apply_env(&mut value, vars(&[("SENSILO__DEDUP__WINDOW_S__X", "1")]));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn env_values() {
+        assert_eq!(parse_env_value("42"), toml::Value::Integer(42));
+        assert_eq!(parse_env_value("false"), toml::Value::Boolean(false));
+        assert_eq!(
+            parse_env_value("bluetooth0"),
+            toml::Value::String("bluetooth0".into())
+        );
+        assert_eq!(
+            parse_env_value("https://x.example.com"),
+            toml::Value::String("https://x.example.com".into())
+        );
+        assert_eq!(parse_env_value("\"42\""), toml::Value::String("42".into()));
+        // Only a single value is accepted
+        assert_eq!(parse_env_value("1\nother = 2"), toml::Value::Integer(1),);
+    }
+
+    #[test]
+    fn migration() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [influxdb]
+            connection_string = "https://influxdb.example.com"
+            user = "influxuser"
+            pass = "influxpass"
+            db = "sensilo"
+            "#,
+        )
+        .unwrap();
+        apply_env(
+            &mut value,
+            vars(&[("SENSILO__INFLUXDB__DATABASE", "other")]),
+        )
+        .unwrap();
+        migrate(&mut value).unwrap();
+        let config: Config = value.try_into().unwrap();
+        let influxdb = config.influxdb.unwrap();
+        assert_eq!(influxdb.password, "influxpass");
+        // The environment takes precedence
+        assert_eq!(influxdb.database, "other");
+
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            version = 2
+
+            [influxdb]
+            pass = "influxpass"
+            "#,
+        )
+        .unwrap();
+        migrate(&mut value).unwrap();
+        assert!(value["influxdb"].get("pass").is_some());
+        assert!(value.get("version").is_none());
+    }
+
+    #[test]
+    fn newer_version() {
+        let mut value: toml::Value = toml::from_str("version = 3").unwrap();
+        let err = migrate(&mut value).unwrap_err();
+        assert!(err.to_string().contains("at most version 2"));
+        let mut value: toml::Value = toml::from_str("version = \"2\"").unwrap();
+        assert!(migrate(&mut value).is_err());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let result = toml::from_str::<Config>(
+            r#"
+            [influxdb]
+            connection_str = "https://influxdb.example.com"
+            "#,
+        );
+        let message = with_suggestion(result.unwrap_err().to_string());
+        assert!(message.contains("(did you mean `connection_string`?)"));
+
+        let result = toml::from_str::<Config>("[capture]\nbackend = \"hic\"");
+        let message = with_suggestion(result.unwrap_err().to_string());
+        assert!(message.contains("(did you mean `hci`?)"));
+
+        let result = toml::from_str::<Config>("[capture]\nbackend = \"hci\"\ndevic = 1");
+        let message = with_suggestion(result.unwrap_err().to_string());
+        assert!(message.contains("(did you mean `device`?)"));
+
+        // No suggestion for unrelated names
+        let result = toml::from_str::<Config>("[dedup]\nfoo = 1");
+        let message = with_suggestion(result.unwrap_err().to_string());
+        assert!(!message.contains("did you mean"));
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("connection_str", "connection_string"), 3);
+        assert_eq!(edit_distance("hic", "hci"), 1);
+        assert_eq!(edit_distance("", "db"), 2);
+        assert_eq!(edit_distance("pass", "pass"), 0);
+    }
+
+    #[test]
+    fn scan() {
+        let config: Config = toml::from_str(
+            r#"
+            devices = []
+
+            [capture]
+            backend = "hci"
+            device = 1
+
+            [capture.scan]
+            type = "active"
+            window_ms = 50
+            "#,
+        )
+        .unwrap();
+        match config.capture {
+            Capture::Hci { device, scan } => {
+                assert_eq!(device, 1);
+                assert_eq!(
+                    scan,
+                    Some(Scan {
+                        scan_type: ScanType::Active,
+                        interval_ms: 100.0,
+                        window_ms: Some(50.0),
+                        filter_duplicates: false,
+                        accept_list: false,
+                        extended: false,
+                    })
+                );
+            }
+            other => panic!("Unexpected capture backend: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn smoothing() {
+        let config: Config = toml::from_str(
+            r#"
+            devices = []
+
+            [smoothing]
+            keep_raw = true
+
+            [smoothing.metrics]
+            temperature = { filter = "median", n = 5 }
+            humidity = { filter = "ewma", alpha = 0.3 }
+            "#,
+        )
+        .unwrap();
+        let smoothing = config.smoothing.unwrap();
+        assert!(smoothing.keep_raw);
+        assert_eq!(
+            smoothing.metrics["temperature"],
+            SmoothingFilter::Median { n: 5 }
+        );
+        assert_eq!(
+            smoothing.metrics["humidity"],
+            SmoothingFilter::Ewma { alpha: 0.3 }
+        );
+    }
+
+    #[test]
+    fn sites() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[devices]]
+            name = "Garden"
+            hex_addr = "864fe0679970"
+
+            [[sites]]
+            name = "Apartment 1"
+
+            [[sites.devices]]
+            name = "Kitchen"
+            hex_addr = "864fe0679971"
+
+            [[sites]]
+            name = "Apartment 2"
+            devices = [
+                { name = "Kitchen", hex_addr = "864fe0679972", site = "Other" },
+            ]
+            "#,
+        )
+        .unwrap();
+        config.flatten_sites();
+        assert!(config.sites.is_empty());
+        let devices: Vec<(&str, Option<&str>)> = config
+            .devices
+            .iter()
+            .map(|dev| (dev.hex_addr.as_str(), dev.site.as_deref()))
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                ("864fe0679970", None),
+                ("864fe0679971", Some("Apartment 1")),
+                ("864fe0679972", Some("Apartment 2")),
+            ]
+        );
+    }
 }
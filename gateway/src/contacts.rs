@@ -0,0 +1,63 @@
+//! Detect state changes of door/window contacts.
+use std::collections::HashMap;
+
+use crate::measurement::Contact;
+use crate::types::Address;
+
+/// Tracks the event counter of the contact of every device.
+///
+/// The device increments the counter for every state change. A change of the
+/// counter (not of the state) is detected, so that a quick open/close sequence
+/// between two beacons is still noticed.
+pub struct ContactTracker {
+    events: HashMap<Address, u16>,
+}
+
+impl ContactTracker {
+    pub fn new() -> Self {
+        Self {
+            events: HashMap::new(),
+        }
+    }
+
+    /// Return the last event counter of every device.
+    pub fn events(&self) -> impl Iterator<Item = (Address, u16)> + '_ {
+        self.events
+            .iter()
+            .map(|(address, events)| (*address, *events))
+    }
+
+    /// Restore the last event counter of a device.
+    pub fn restore(&mut self, address: Address, events: u16) {
+        self.events.insert(address, events);
+    }
+
+    /// Update the contact of a device. Return whether the state changed
+    /// since the previous measurement. The first measurement of a device is
+    /// not a change.
+    pub fn update(&mut self, address: Address, contact: &Contact) -> bool {
+        match self.events.insert(address, contact.events) {
+            Some(events) => events != contact.events,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    #[test]
+    fn change() {
+        let mut tracker = ContactTracker::new();
+        let contact = |open, events| Contact { open, events };
+        assert!(!tracker.update(ADDR, &contact(false, 4)));
+        assert!(!tracker.update(ADDR, &contact(false, 4)));
+        assert!(tracker.update(ADDR, &contact(true, 5)));
+        // Opened and closed again between two beacons
+        assert!(tracker.update(ADDR, &contact(true, 7)));
+        assert!(!tracker.update(ADDR, &contact(true, 7)));
+    }
+}
@@ -0,0 +1,90 @@
+//! Verification of the CRC of Sensilo payloads.
+//!
+//! With the `crc` firmware feature, every frame ends with a CRC entry (type
+//! `0x11`, after the MAC entry if the payload is authenticated). The CRC-8
+//! (polynomial `0x07`, initial value `0`) covers the payload after the company
+//! identifier (up to the CRC value) and the CRC entry type (and length, since
+//! protocol version 2). It catches frames with bit errors that some Bluetooth
+//! adapters pass on despite the BLE CRC.
+use crate::measurement::entry_header_len;
+
+/// Entry type of the CRC.
+const SENSOR_CRC: u8 = 0x11;
+
+/// CRC-8 with the polynomial 0x07 and the initial value 0 (CRC-8/SMBUS).
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Verify the CRC at the end of a payload (without company identifier). On
+/// success, return the payload without the CRC entry.
+pub fn verify(payload: &[u8]) -> Result<&[u8], &'static str> {
+    let header: &[u8] = match entry_header_len(payload) {
+        1 => &[SENSOR_CRC],
+        _ => &[SENSOR_CRC, 1],
+    };
+    let split = payload
+        .len()
+        .checked_sub(1)
+        // At least the counter and the CRC entry header
+        .filter(|&split| split >= 2 + header.len() && payload[..split].ends_with(header))
+        .ok_or("Missing CRC")?;
+    let (data, crc) = payload.split_at(split);
+    if crc8(data) != crc[0] {
+        return Err("Invalid CRC");
+    }
+    Ok(&data[..data.len() - header.len()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payload with a humidity entry, as written by the firmware.
+    #[rustfmt::skip]
+    const PAYLOAD: [u8; 9] = [
+        0x34, 0x12,
+        0x02, 0x40, 0x9c, 0x00, 0x00,
+        0x11, 0x2c,
+    ];
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc8(b"123456789"), 0xf4);
+    }
+
+    #[test]
+    fn verify_payload() {
+        assert_eq!(verify(&PAYLOAD), Ok(&PAYLOAD[..7]));
+
+        // A single bit error
+        let mut payload = PAYLOAD;
+        payload[4] ^= 0x10;
+        assert_eq!(verify(&payload), Err("Invalid CRC"));
+
+        assert_eq!(verify(&PAYLOAD[..7]), Err("Missing CRC"));
+        assert_eq!(verify(&PAYLOAD[7..]), Err("Missing CRC"));
+        assert_eq!(verify(&[]), Err("Missing CRC"));
+    }
+
+    #[test]
+    fn verify_payload_v2() {
+        #[rustfmt::skip]
+        let payload = [
+            0x34, 0x12,
+            0x00, 0x02, 0x01,
+            0x02, 0x04, 0x40, 0x9c, 0x00, 0x00,
+            0x11, 0x01, 0x54,
+        ];
+        assert_eq!(verify(&payload), Ok(&payload[..11]));
+        assert_eq!(verify(&payload[..12]), Err("Missing CRC"));
+    }
+}
@@ -0,0 +1,83 @@
+//! CSV encoding of measurements, for the export of the API. The columns are
+//! fixed (so that the rows of all devices fit under the same header), values
+//! that a measurement doesn't contain are empty.
+use std::time::UNIX_EPOCH;
+
+use crate::config::Units;
+use crate::measurement::Measurement;
+use crate::units;
+
+/// The header line (with line break).
+pub const HEADER: &str = "timestamp,address,local_name,counter,rssi,temperature,humidity,\
+                          ambient_light,pulse_count,contact_open,battery_voltage,backfilled\r\n";
+
+/// Quote a field if it contains a separator, a quote or a line break.
+fn field(value: &str) -> String {
+    if value.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Encode a measurement as CSV row (with line break), with the temperature
+/// and humidity converted to the configured units.
+pub fn row(mmt: &Measurement, units: &Units) -> String {
+    let timestamp = mmt
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let fields = [
+        timestamp.to_string(),
+        mmt.address.to_string(),
+        field(&mmt.local_name),
+        mmt.counter.to_string(),
+        mmt.rssi.to_string(),
+        mmt.temperature
+            .as_ref()
+            .map_or_else(String::new, |temp| units::temperature(temp, units)),
+        mmt.humidity
+            .as_ref()
+            .map_or_else(String::new, |humi| units::humidity(humi, units)),
+        mmt.ambient_light
+            .as_ref()
+            .filter(|lux| lux.as_lux().is_finite())
+            .map_or_else(String::new, |lux| format!("{:.2}", lux.as_lux())),
+        mmt.pulses
+            .map_or_else(String::new, |pulses| pulses.count.to_string()),
+        mmt.contact
+            .map_or_else(String::new, |contact| contact.open.to_string()),
+        mmt.battery
+            .map_or_else(String::new, |battery| battery.millivolts.to_string()),
+        mmt.backfilled.to_string(),
+    ];
+    format!("{}\r\n", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::measurement::{Humidity, MeasurementBuilder, Temperature};
+    use crate::types::Address;
+
+    #[test]
+    fn encode() {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .local_name("Living room, \"north\"")
+            .counter(7)
+            .temperature(Temperature::from_millidegrees_celsius(21_500))
+            .humidity(Humidity::from_millipercent(45_250))
+            .timestamp(UNIX_EPOCH + Duration::from_millis(1_607_500_000_000));
+        let mmt = builder.build().unwrap();
+        assert_eq!(HEADER.split(',').count(), 12);
+        assert_eq!(
+            row(&mmt, &Default::default()),
+            "1607500000000,123456,\"Living room, \"\"north\"\"\",7,200,21500,45250,,,,,false\r\n"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Run the gateway in the background, drop root privileges once the capture
+//! device is open, and restart the gateway to reload the config.
+use crate::config;
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CString;
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    use anyhow::{bail, Context, Result};
+
+    use crate::config;
+
+    /// Fork. The parent exits, the child returns.
+    ///
+    /// Safety: Must only be called while the process is single threaded.
+    unsafe fn fork_and_exit_parent() -> Result<()> {
+        match libc::fork() {
+            -1 => Err(io::Error::last_os_error()).context("fork failed"),
+            0 => Ok(()),
+            _ => libc::_exit(0),
+        }
+    }
+
+    pub fn daemonize(config: &config::Daemon) -> Result<()> {
+        // Detach from the terminal. The second fork ensures that the daemon
+        // is not a session leader, so it can never acquire a terminal again.
+        //
+        // Safety: This is called at startup, before any threads are spawned.
+        unsafe {
+            fork_and_exit_parent()?;
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error()).context("setsid failed");
+            }
+            fork_and_exit_parent()?;
+            libc::umask(0o027);
+        }
+
+        if let Some(ref pid_file) = config.pid_file {
+            fs::write(pid_file, format!("{}\n", std::process::id()))
+                .with_context(|| format!("Could not write PID file {}", pid_file.display()))?;
+        }
+
+        // Output goes to the log file (if any), input is not available
+        let output = match config.log_file {
+            Some(ref log_file) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)
+                .with_context(|| format!("Could not open log file {}", log_file.display()))?,
+            None => OpenOptions::new().write(true).open("/dev/null")?,
+        };
+        let input = File::open("/dev/null")?;
+
+        // Keep the daemon out of the directory it was started in. Relative
+        // paths in the config are relative to this directory from now on.
+        if let Some(ref dir) = config.working_directory {
+            std::env::set_current_dir(dir)
+                .with_context(|| format!("Could not change directory to {}", dir.display()))?;
+        }
+
+        // Safety: The file descriptors are valid, the standard streams are
+        // replaced atomically.
+        unsafe {
+            if libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO) == -1
+                || libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO) == -1
+                || libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO) == -1
+            {
+                return Err(io::Error::last_os_error()).context("dup2 failed");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn drop_privileges(config: &config::Daemon) -> Result<()> {
+        let user = match config.user {
+            Some(ref user) => user,
+            None => return Ok(()),
+        };
+        // Safety: geteuid is always successful
+        if unsafe { libc::geteuid() } != 0 {
+            log::info!("Not running as root, not dropping privileges");
+            return Ok(());
+        }
+
+        // Safety: The returned entries are copied before any other call to
+        // getpwnam / getgrnam.
+        let name = CString::new(user.as_str())?;
+        let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+        if passwd.is_null() {
+            bail!("Unknown user: {}", user);
+        }
+        let (uid, mut gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+        if let Some(ref group) = config.group {
+            let name = CString::new(group.as_str())?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                bail!("Unknown group: {}", group);
+            }
+            gid = unsafe { (*entry).gr_gid };
+        }
+
+        // The group must be changed first, this is not possible anymore after
+        // changing the user.
+        //
+        // Safety: The group list consists of a single valid gid.
+        unsafe {
+            if libc::setgroups(1, &gid) != 0 {
+                return Err(io::Error::last_os_error()).context("setgroups failed");
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(io::Error::last_os_error()).context("setgid failed");
+            }
+            if libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error()).context("setuid failed");
+            }
+        }
+        status!("Dropped privileges (uid {}, gid {})", uid, gid);
+        Ok(())
+    }
+
+    /// Replace the process with a new instance of the gateway, with the same
+    /// arguments. Only returns on errors.
+    pub fn restart() -> Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let program = std::env::current_exe().context("Could not find the executable")?;
+        let error = std::process::Command::new(&program)
+            .args(std::env::args_os().skip(1))
+            .exec();
+        Err(error).with_context(|| format!("Could not restart {}", program.display()))
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{daemonize, drop_privileges, restart};
+
+#[cfg(not(unix))]
+pub fn daemonize(_config: &config::Daemon) -> anyhow::Result<()> {
+    anyhow::bail!("Daemon mode is only supported on Unix");
+}
+
+#[cfg(not(unix))]
+pub fn restart() -> anyhow::Result<()> {
+    anyhow::bail!("Reloading the config is only supported on Unix");
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(config: &config::Daemon) -> anyhow::Result<()> {
+    if config.user.is_some() {
+        anyhow::bail!("Dropping privileges is only supported on Unix");
+    }
+    Ok(())
+}
+
+/// Remove the PID file (if any) when the gateway stops. This may fail after
+/// dropping the privileges, which is not an error.
+pub fn remove_pid_file(config: &config::Daemon) {
+    if let Some(ref pid_file) = config.pid_file {
+        if let Err(e) = std::fs::remove_file(pid_file) {
+            log::debug!("Could not remove PID file {}: {}", pid_file.display(), e);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Decoding of advertisement payloads, with a decoder per device depending on
+//! the configured protocol.
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::advertising::AdStructure;
+use crate::auth::PayloadKey;
+use crate::bthome::BthomeDecoder;
+use crate::config::{self, Protocol};
+use crate::crc;
+use crate::eddystone::EddystoneTlmDecoder;
+use crate::hexdump;
+use crate::measurement::MeasurementBuilder;
+use crate::types::Address;
+
+/// Company identifier of the Sensilo manufacturer specific data.
+const SENSILO_COMPANY_IDENTIFIER: u16 = 0xffff;
+
+/// A decoder for an advertisement format.
+pub trait Decoder {
+    /// Find the payload in the AD structures of an advertising report.
+    fn payload<'a>(&self, data: &'a [AdStructure]) -> Option<&'a [u8]>;
+
+    /// Decode a payload and add the values to the builder.
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        builder: &mut MeasurementBuilder<'_>,
+    ) -> Result<(), &'static str>;
+
+    /// Number of payloads that failed the authentication, if the payloads
+    /// are authenticated.
+    fn verification_failures(&self) -> Option<u64> {
+        None
+    }
+
+    /// The counter of the last accepted payload, if the payloads are
+    /// authenticated (for the replay protection).
+    fn replay_counter(&self) -> Option<u16> {
+        None
+    }
+
+    /// Restore the counter of the last accepted payload (e.g. from a
+    /// snapshot), so that payloads recorded before a restart are still
+    /// rejected.
+    fn restore_replay_counter(&mut self, _counter: u16) {}
+
+    /// Render an annotated hex dump of a payload, if supported by the format.
+    fn annotate(&self, _address: Address, _rssi: u8, _payload: &[u8]) -> Option<String> {
+        None
+    }
+}
+
+/// Decoder for the Sensilo payload in the manufacturer specific data.
+#[derive(Default)]
+pub struct SensiloDecoder {
+    /// Key of the device, if the payloads must be authenticated
+    key: Option<PayloadKey>,
+    /// Whether the payloads end with a CRC
+    crc: bool,
+    verification_failures: u64,
+    /// Counter of the last authenticated payload
+    last_counter: Option<u16>,
+}
+
+impl SensiloDecoder {
+    /// Create a decoder that only accepts payloads authenticated with the key.
+    pub fn with_key(key: PayloadKey) -> Self {
+        Self {
+            key: Some(key),
+            crc: false,
+            verification_failures: 0,
+            last_counter: None,
+        }
+    }
+
+    /// Only accept payloads with a valid CRC (which is checked before the
+    /// authentication).
+    pub fn with_crc(self) -> Self {
+        Self { crc: true, ..self }
+    }
+}
+
+impl Decoder for SensiloDecoder {
+    fn payload<'a>(&self, data: &'a [AdStructure]) -> Option<&'a [u8]> {
+        data.iter().find_map(|datum| match datum {
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: SENSILO_COMPANY_IDENTIFIER,
+                data,
+            } => Some(&data[..]),
+            _ => None,
+        })
+    }
+
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        builder: &mut MeasurementBuilder<'_>,
+    ) -> Result<(), &'static str> {
+        let payload = if self.crc {
+            crc::verify(payload)?
+        } else {
+            payload
+        };
+        let payload = match self.key {
+            Some(ref key) => match key.verify(builder.address(), payload) {
+                Ok(payload) => {
+                    // Authenticated payloads start with the counter
+                    let counter = u16::from_le_bytes([payload[0], payload[1]]);
+                    if matches!(self.last_counter, Some(last) if !is_current(counter, last)) {
+                        self.verification_failures += 1;
+                        return Err("Replayed counter");
+                    }
+                    self.last_counter = Some(counter);
+                    payload
+                }
+                Err(e) => {
+                    self.verification_failures += 1;
+                    return Err(e);
+                }
+            },
+            None => payload,
+        };
+        builder.parse_payload(payload).map(|_| ())
+    }
+
+    fn verification_failures(&self) -> Option<u64> {
+        self.key.as_ref().map(|_| self.verification_failures)
+    }
+
+    fn replay_counter(&self) -> Option<u16> {
+        self.key.as_ref().and(self.last_counter)
+    }
+
+    fn restore_replay_counter(&mut self, counter: u16) {
+        if self.key.is_some() {
+            self.last_counter = Some(counter);
+        }
+    }
+
+    fn annotate(&self, address: Address, rssi: u8, payload: &[u8]) -> Option<String> {
+        Some(hexdump::annotate(address, rssi, payload))
+    }
+}
+
+/// Whether a counter is the last accepted one or newer (the counter wraps
+/// around, the newer half of the range is accepted). The frames of a
+/// measurement and their retransmissions share the counter, repeated frames
+/// are dropped by the deduplication.
+fn is_current(counter: u16, last: u16) -> bool {
+    counter.wrapping_sub(last) < 0x8000
+}
+
+/// Create a decoder for every configured device. The addresses must be in
+/// the same order as the devices in the config.
+pub fn for_devices(
+    devices: &[config::Device],
+    addresses: &[Address],
+) -> Result<HashMap<Address, Box<dyn Decoder>>> {
+    devices
+        .iter()
+        .zip(addresses)
+        .map(|(device, address)| {
+            let key = match device.key {
+                Some(ref key) => Some(
+                    PayloadKey::from_hex(key)
+                        .with_context(|| format!("Invalid config of device {}", device.name))?,
+                ),
+                None => None,
+            };
+            if device.crc && device.protocol != Protocol::Sensilo {
+                anyhow::bail!(
+                    "Invalid config of device {}: CRCs are only supported for Sensilo devices",
+                    device.name
+                );
+            }
+            let decoder: Box<dyn Decoder> = match (device.protocol, key) {
+                (Protocol::Sensilo, key) => {
+                    let decoder = match key {
+                        Some(key) => SensiloDecoder::with_key(key),
+                        None => SensiloDecoder::default(),
+                    };
+                    Box::new(if device.crc {
+                        decoder.with_crc()
+                    } else {
+                        decoder
+                    })
+                }
+                (Protocol::Bthome, None) => Box::new(BthomeDecoder::new()),
+                (Protocol::EddystoneTlm, None) => Box::new(EddystoneTlmDecoder::new()),
+                (Protocol::Bthome | Protocol::EddystoneTlm, Some(_)) => anyhow::bail!(
+                    "Invalid config of device {}: Keys are only supported for Sensilo devices",
+                    device.name
+                ),
+            };
+            Ok((*address, decoder))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensilo_payload() {
+        let data = vec![
+            AdStructure::CompleteLocalName("Sensilo".into()),
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: 0x0059,
+                data: vec![1, 2],
+            },
+            AdStructure::ManufacturerSpecificData {
+                company_identifier: 0xffff,
+                data: vec![3, 4],
+            },
+        ];
+        let decoder = SensiloDecoder::default();
+        assert_eq!(decoder.payload(&data), Some(&[3, 4][..]));
+        assert_eq!(decoder.payload(&data[..2]), None);
+    }
+
+    #[test]
+    fn compat_frames_ignored() {
+        // Eddystone-TLM and iBeacon frames of a node
+        let eddystone = vec![AdStructure::ServiceData16 {
+            uuid: 0xfeaa,
+            data: vec![0x20, 0x00, 0x0b, 0xb8, 0x15, 0x80, 0, 0, 0, 1, 0, 0, 0, 2],
+        }];
+        let ibeacon = vec![AdStructure::ManufacturerSpecificData {
+            company_identifier: 0x004c,
+            data: vec![0x02, 0x15, 0, 0, 0, 0],
+        }];
+        let decoder = SensiloDecoder::default();
+        assert_eq!(decoder.payload(&eddystone), None);
+        assert_eq!(decoder.payload(&ibeacon), None);
+    }
+
+    #[test]
+    fn sensilo_verification() {
+        let key = PayloadKey::from_hex("00112233445566778899aabbccddeeff").unwrap();
+        let mut decoder = SensiloDecoder::with_key(key);
+        #[rustfmt::skip]
+        let payload = [
+            0x34, 0x12,
+            0x01, 0xde, 0x58, 0x00, 0x00,
+            0x0d, 0x25, 0x4a, 0x0e, 0x49,
+        ];
+
+        let mut builder = MeasurementBuilder::new(Address::from_hex("864fe067997a"), 200);
+        assert_eq!(decoder.decode(&payload, &mut builder), Ok(()));
+        builder.local_name("Sensilo");
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.counter, 0x1234);
+        assert_eq!(decoder.verification_failures(), Some(0));
+
+        let mut builder = MeasurementBuilder::new(Address::from_hex("864fe067997b"), 200);
+        assert_eq!(decoder.decode(&payload, &mut builder), Err("Invalid MAC"));
+        assert_eq!(
+            decoder.decode(&payload[..7], &mut builder),
+            Err("Missing MAC")
+        );
+        assert_eq!(decoder.verification_failures(), Some(2));
+
+        assert_eq!(SensiloDecoder::default().verification_failures(), None);
+    }
+
+    #[test]
+    fn sensilo_replay() {
+        let key = PayloadKey::from_hex("00112233445566778899aabbccddeeff").unwrap();
+        let mut decoder = SensiloDecoder::with_key(key);
+        #[rustfmt::skip]
+        let payload = [
+            0x34, 0x12,
+            0x01, 0xde, 0x58, 0x00, 0x00,
+            0x0d, 0x25, 0x4a, 0x0e, 0x49,
+        ];
+        let address = Address::from_hex("864fe067997a");
+        let mut builder = MeasurementBuilder::new(address, 200);
+        assert_eq!(decoder.decode(&payload, &mut builder), Ok(()));
+        assert_eq!(decoder.replay_counter(), Some(0x1234));
+        // Frames of the same measurement have the same counter
+        let mut builder = MeasurementBuilder::new(address, 200);
+        assert_eq!(decoder.decode(&payload, &mut builder), Ok(()));
+
+        decoder.restore_replay_counter(0x1235);
+        let mut builder = MeasurementBuilder::new(address, 200);
+        assert_eq!(
+            decoder.decode(&payload, &mut builder),
+            Err("Replayed counter")
+        );
+        assert_eq!(decoder.verification_failures(), Some(1));
+        assert_eq!(decoder.replay_counter(), Some(0x1235));
+
+        // Unauthenticated payloads have no replay protection
+        let mut decoder = SensiloDecoder::default();
+        decoder.restore_replay_counter(1);
+        assert_eq!(decoder.replay_counter(), None);
+    }
+
+    #[test]
+    fn counter_wraparound() {
+        assert!(is_current(5, 5));
+        assert!(is_current(6, 5));
+        assert!(is_current(0x8004, 5));
+        assert!(!is_current(4, 5));
+        assert!(!is_current(0x8005, 5));
+        assert!(is_current(2, 0xfffe));
+        assert!(!is_current(0xfffd, 0xfffe));
+    }
+
+    #[test]
+    fn sensilo_crc() {
+        let mut decoder = SensiloDecoder::default().with_crc();
+        // Humidity entry and CRC
+        let mut payload = [0x34, 0x12, 0x02, 0x40, 0x9c, 0x00, 0x00, 0x11, 0x2c];
+
+        let mut builder = MeasurementBuilder::new(Address::from_hex("864fe067997a"), 200);
+        assert_eq!(decoder.decode(&payload, &mut builder), Ok(()));
+        builder.local_name("Sensilo");
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.humidity.unwrap().as_millipercent(), 40000);
+
+        payload[5] = 0x01;
+        let mut builder = MeasurementBuilder::new(Address::from_hex("864fe067997a"), 200);
+        assert_eq!(decoder.decode(&payload, &mut builder), Err("Invalid CRC"));
+        builder.local_name("Sensilo");
+        assert!(builder.build().is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Deduplicate beacons (every measurement is sent multiple times), also
+//! across gateway restarts (the windows are kept in the state snapshot).
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::state::{Clock, Snapshot};
+use crate::types::Address;
+
+/// A (counter, frame index) pair that was received at a specific time.
+struct Seen {
+    counter: u16,
+    frame_index: u8,
+    instant: Instant,
+}
+
+/// Deduplication statistics of a device (since the gateway started).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Frames that were passed on
+    pub accepted: u64,
+    /// Frames that were ignored as duplicates
+    pub duplicates: u64,
+}
+
+/// Remembers the (counter, frame index) pairs of every device that were
+/// received within a sliding time window. A frame with a pair contained in
+/// the window is a duplicate.
+///
+/// In contrast to a fixed number of entries, this handles long bursts and
+/// frames that are received through multiple capture devices with a delay.
+/// After a counter wraparound (or a device reboot), the old pairs have long
+/// left the window, so new frames are not mistaken for duplicates.
+pub struct Deduplicator {
+    window: Duration,
+    seen: HashMap<Address, VecDeque<Seen>>,
+    stats: HashMap<Address, DedupStats>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Return whether a frame was already seen within the window, and
+    /// remember it otherwise.
+    pub fn is_duplicate(
+        &mut self,
+        address: Address,
+        counter: u16,
+        frame_index: u8,
+        now: Instant,
+    ) -> bool {
+        let window = self.window;
+        let seen = self.seen.entry(address).or_default();
+        while seen
+            .front()
+            .is_some_and(|entry| now.saturating_duration_since(entry.instant) > window)
+        {
+            seen.pop_front();
+        }
+        let stats = self.stats.entry(address).or_default();
+        if seen
+            .iter()
+            .any(|entry| entry.counter == counter && entry.frame_index == frame_index)
+        {
+            stats.duplicates += 1;
+            return true;
+        }
+        stats.accepted += 1;
+        seen.push_back(Seen {
+            counter,
+            frame_index,
+            instant: now,
+        });
+        false
+    }
+
+    /// Add the window of every device to a snapshot.
+    pub fn snapshot(&self, snapshot: &mut Snapshot, clock: &Clock) {
+        for (address, seen) in &self.seen {
+            if seen.is_empty() {
+                continue;
+            }
+            let state = snapshot.devices.entry(*address).or_default();
+            state.dedup = seen
+                .iter()
+                .map(|entry| {
+                    let received = clock.system_time(entry.instant);
+                    (entry.counter, entry.frame_index, received)
+                })
+                .collect();
+        }
+    }
+
+    /// Restore the windows from a snapshot. Entries that have left the window
+    /// in the meantime are dropped.
+    pub fn restore(&mut self, snapshot: &Snapshot, clock: &Clock) {
+        for (address, state) in &snapshot.devices {
+            let seen: VecDeque<Seen> = state
+                .dedup
+                .iter()
+                .filter_map(|&(counter, frame_index, received)| {
+                    let instant = clock.instant(received)?;
+                    if clock.elapsed(instant) > self.window {
+                        return None;
+                    }
+                    Some(Seen {
+                        counter,
+                        frame_index,
+                        instant,
+                    })
+                })
+                .collect();
+            if !seen.is_empty() {
+                self.seen.insert(*address, seen);
+            }
+        }
+    }
+
+    /// Return the statistics of a device.
+    pub fn stats(&self, address: Address) -> DedupStats {
+        self.stats.get(&address).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Address = Address([0x86, 0x4f, 0xe0, 0x67, 0x99, 0x7a]);
+    const WINDOW: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn duplicate() {
+        let mut dedup = Deduplicator::new(WINDOW);
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate(ADDR, 1, 0, now));
+        assert!(dedup.is_duplicate(ADDR, 1, 0, now));
+        assert!(!dedup.is_duplicate(ADDR, 1, 1, now));
+        assert!(!dedup.is_duplicate(Address([0; 6]), 1, 0, now));
+        assert_eq!(
+            dedup.stats(ADDR),
+            DedupStats {
+                accepted: 2,
+                duplicates: 1
+            }
+        );
+    }
+
+    #[test]
+    fn long_burst() {
+        // A fixed size cache forgets the first frames of a burst of many
+        // multi-frame measurements
+        let mut dedup = Deduplicator::new(WINDOW);
+        let now = Instant::now();
+        for index in 0..10 {
+            assert!(!dedup.is_duplicate(ADDR, 1, index, now));
+        }
+        for index in 0..10 {
+            assert!(dedup.is_duplicate(ADDR, 1, index, now + Duration::from_secs(1)));
+        }
+    }
+
+    #[test]
+    fn window_expiry() {
+        // After a counter wraparound, the same counter is not a duplicate
+        let mut dedup = Deduplicator::new(WINDOW);
+        let now = Instant::now();
+        assert!(!dedup.is_duplicate(ADDR, 42, 0, now));
+        assert!(dedup.is_duplicate(ADDR, 42, 0, now + Duration::from_secs(29)));
+        assert!(!dedup.is_duplicate(ADDR, 42, 0, now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let clock = Clock::now();
+        let mut dedup = Deduplicator::new(WINDOW);
+        let now = Instant::now();
+        dedup.is_duplicate(ADDR, 1, 0, now);
+        dedup.is_duplicate(ADDR, 2, 1, now);
+        let mut snapshot = Snapshot::default();
+        dedup.snapshot(&mut snapshot, &clock);
+        assert_eq!(snapshot.devices[&ADDR].dedup.len(), 2);
+
+        let mut restored = Deduplicator::new(WINDOW);
+        restored.restore(&snapshot, &Clock::now());
+        assert!(restored.is_duplicate(ADDR, 2, 1, Instant::now()));
+        assert!(!restored.is_duplicate(ADDR, 2, 0, Instant::now()));
+    }
+}
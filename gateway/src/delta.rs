@@ -0,0 +1,127 @@
+//! Reconstruct the absolute values of delta-encoded measurements.
+//!
+//! Nodes with delta encoding send the temperature and humidity only in every
+//! few measurements (the full measurements). In between, they only send the
+//! differences to the last full measurement, together with its age. The
+//! gateway remembers the last full measurement of every device and adds the
+//! differences to it. If it missed that full measurement, the temperature and
+//! humidity of the measurements up to the next one are dropped.
+use std::collections::HashMap;
+
+use crate::measurement::{Humidity, Measurement, Temperature};
+use crate::types::Address;
+
+struct Reference {
+    counter: u16,
+    temperature: Option<Temperature>,
+    humidity: Option<Humidity>,
+}
+
+/// Remembers the last full measurement of every device.
+pub struct DeltaDecoder {
+    references: HashMap<Address, Reference>,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self {
+            references: HashMap::new(),
+        }
+    }
+
+    /// Replace the delta of a measurement with the absolute values. Return
+    /// `false` if the full measurement it refers to was missed. Measurements
+    /// without a delta are remembered as full measurements.
+    pub fn apply(&mut self, mmt: &mut Measurement) -> bool {
+        let delta = match mmt.delta.take() {
+            Some(delta) => delta,
+            None => {
+                if mmt.temperature.is_some() || mmt.humidity.is_some() {
+                    let reference = Reference {
+                        counter: mmt.counter,
+                        temperature: mmt.temperature.clone(),
+                        humidity: mmt.humidity.clone(),
+                    };
+                    self.references.insert(mmt.address, reference);
+                }
+                return true;
+            }
+        };
+        let counter = mmt.counter.wrapping_sub(u16::from(delta.reference_age));
+        let reference = match self.references.get(&mmt.address) {
+            Some(reference) if reference.counter == counter => reference,
+            _ => return false,
+        };
+        if let (Some(reference), Some(centidegrees)) = (&reference.temperature, delta.temperature) {
+            mmt.temperature = Some(Temperature::from_millidegrees_celsius(
+                reference.as_millidegrees_celsius() + i32::from(centidegrees) * 10,
+            ));
+        }
+        if let (Some(reference), Some(centipercent)) = (&reference.humidity, delta.humidity) {
+            mmt.humidity = Some(Humidity::from_millipercent(
+                reference.as_millipercent() + i32::from(centipercent) * 10,
+            ));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::{Delta, MeasurementBuilder};
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn full(counter: u16) -> Measurement {
+        let mut builder = MeasurementBuilder::new(ADDR, 200);
+        builder
+            .local_name("Sensilo")
+            .counter(counter)
+            .temperature(Temperature::from_millidegrees_celsius(21004))
+            .humidity(Humidity::from_millipercent(40000));
+        builder.build().unwrap()
+    }
+
+    fn delta(counter: u16, reference_age: u8) -> Measurement {
+        let mut builder = MeasurementBuilder::new(ADDR, 200);
+        builder.local_name("Sensilo").counter(counter).delta(Delta {
+            reference_age,
+            temperature: Some(-150),
+            humidity: None,
+        });
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn reconstruct() {
+        let mut decoder = DeltaDecoder::new();
+        // Before the first full measurement
+        let mut mmt = delta(9, 1);
+        assert!(!decoder.apply(&mut mmt));
+        assert!(mmt.temperature.is_none());
+        assert!(mmt.delta.is_none());
+
+        assert!(decoder.apply(&mut full(10)));
+        let mut mmt = delta(12, 2);
+        assert!(decoder.apply(&mut mmt));
+        assert_eq!(mmt.temperature.unwrap().as_millidegrees_celsius(), 19504);
+        // The humidity sensor failed
+        assert!(mmt.humidity.is_none());
+
+        // The full measurement 20 was missed
+        let mut mmt = delta(21, 1);
+        assert!(!decoder.apply(&mut mmt));
+        assert!(mmt.temperature.is_none());
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut decoder = DeltaDecoder::new();
+        assert!(decoder.apply(&mut full(65535)));
+        let mut mmt = delta(1, 2);
+        assert!(decoder.apply(&mut mmt));
+        assert_eq!(mmt.temperature.unwrap().as_millidegrees_celsius(), 19504);
+    }
+}
@@ -0,0 +1,168 @@
+//! Counters and recent errors of the payloads that could not be decoded, for
+//! troubleshooting.
+//!
+//! Without trace-level logs, it is hard to tell why a device is missing a
+//! value. The gateway counts the failed payloads of every device by kind,
+//! and keeps the last errors with their payloads. They are dumped on
+//! `SIGUSR1`, together with the raw frames.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::types::Address;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// The payload could not be parsed (e.g. truncated)
+    Malformed,
+    /// Missing or invalid CRC
+    Crc,
+    /// Missing or invalid MAC, or a replayed counter
+    Mac,
+    /// An entry type that the gateway doesn't know
+    UnknownType,
+}
+
+impl ErrorKind {
+    /// The kind of a decoder error.
+    pub fn of(error: &str) -> Self {
+        match error {
+            "Missing CRC" | "Invalid CRC" => ErrorKind::Crc,
+            "Missing MAC" | "Invalid MAC" | "Replayed counter" => ErrorKind::Mac,
+            "Unknown payload type" => ErrorKind::UnknownType,
+            _ => ErrorKind::Malformed,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::Malformed => "malformed",
+            ErrorKind::Crc => "crc",
+            ErrorKind::Mac => "mac",
+            ErrorKind::UnknownType => "unknown_type",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub timestamp: SystemTime,
+    pub kind: ErrorKind,
+    pub message: String,
+    pub payload: Vec<u8>,
+}
+
+/// Error counters and ring buffers with the last errors of every device.
+pub struct ParseErrors {
+    capacity: usize,
+    counts: HashMap<Address, BTreeMap<ErrorKind, u64>>,
+    recent: HashMap<Address, VecDeque<ParseError>>,
+}
+
+impl ParseErrors {
+    /// Keep the last `capacity` errors per device (they are always counted).
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, address: Address, error: ParseError) {
+        *self
+            .counts
+            .entry(address)
+            .or_default()
+            .entry(error.kind)
+            .or_default() += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        let recent = self.recent.entry(address).or_default();
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(error);
+    }
+
+    /// The number of errors of a device, by kind.
+    pub fn counts(&self, address: Address) -> impl Iterator<Item = (ErrorKind, u64)> + '_ {
+        self.counts
+            .get(&address)
+            .into_iter()
+            .flat_map(|counts| counts.iter().map(|(kind, count)| (*kind, *count)))
+    }
+
+    /// Dump the counters and the recent errors of all devices, one error per
+    /// line (with the timestamp in milliseconds and the payload hex-encoded).
+    pub fn dump(&self) -> String {
+        let mut addresses: Vec<&Address> = self.counts.keys().collect();
+        addresses.sort_by_key(|address| address.0);
+        let mut dump = String::new();
+        for address in addresses {
+            let counts: Vec<String> = self
+                .counts(*address)
+                .map(|(kind, count)| format!("{} {}", count, kind.name()))
+                .collect();
+            writeln!(dump, "{} errors: {}", address, counts.join(", ")).unwrap();
+            for error in self.recent.get(address).into_iter().flatten() {
+                let timestamp = error
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                writeln!(
+                    dump,
+                    "  {} {}: {} ({})",
+                    timestamp,
+                    error.kind.name(),
+                    error.message,
+                    base16::encode_lower(&error.payload)
+                )
+                .unwrap();
+            }
+        }
+        dump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn error(kind: ErrorKind, payload: &[u8]) -> ParseError {
+        ParseError {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1607500000123),
+            kind,
+            message: "Invalid CRC".into(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(ErrorKind::of("Invalid CRC"), ErrorKind::Crc);
+        assert_eq!(ErrorKind::of("Missing MAC"), ErrorKind::Mac);
+        assert_eq!(ErrorKind::of("Malformed payload"), ErrorKind::Malformed);
+    }
+
+    #[test]
+    fn counts_and_recent() {
+        let mut errors = ParseErrors::new(1);
+        errors.push(ADDR, error(ErrorKind::Crc, &[1]));
+        errors.push(ADDR, error(ErrorKind::Crc, &[2]));
+        errors.push(ADDR, error(ErrorKind::Malformed, &[0x01, 0x2a]));
+        let counts: Vec<_> = errors.counts(ADDR).collect();
+        assert_eq!(counts, vec![(ErrorKind::Malformed, 1), (ErrorKind::Crc, 2)]);
+        assert_eq!(
+            errors.dump(),
+            "123456 errors: 1 malformed, 2 crc\n  \
+             1607500000123 malformed: Invalid CRC (012a)\n"
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Formatting of the values in the console output.
+//!
+//! The display units only affect the console, the sinks always receive the
+//! values in the units configured in the `[units]` section.
+use crate::config::{self, LightUnit, TemperatureUnit};
+use crate::measurement::Measurement;
+
+/// Lux per foot-candle.
+const LUX_PER_FOOTCANDLE: f32 = 10.763_91;
+
+/// Format a number, with a decimal comma if configured.
+fn number(value: f32, config: &config::Display) -> String {
+    let formatted = value.to_string();
+    if config.decimal_comma {
+        formatted.replace('.', ",")
+    } else {
+        formatted
+    }
+}
+
+/// The status line of a received measurement, e.g.
+/// `Kitchen (200 RSSI): [42] 21.5 °C | 45 %RH | 120 Lux`. Missing values are
+/// shown as -1.
+pub fn measurement(mmt: &Measurement, config: &config::Display, units: &config::Units) -> String {
+    let (temperature, temperature_unit) = match config.temperature.unwrap_or(units.temperature) {
+        TemperatureUnit::Celsius => (
+            mmt.temperature.as_ref().map(|t| t.as_degrees_celsius()),
+            "°C",
+        ),
+        TemperatureUnit::Fahrenheit => (
+            mmt.temperature.as_ref().map(|t| t.as_degrees_fahrenheit()),
+            "°F",
+        ),
+    };
+    let (light, light_unit) = match config.light {
+        LightUnit::Lux => (mmt.ambient_light.as_ref().map(|l| l.as_lux()), "Lux"),
+        LightUnit::Footcandles => (
+            mmt.ambient_light
+                .as_ref()
+                .map(|l| (l.as_lux() / LUX_PER_FOOTCANDLE * 100.0).round() / 100.0),
+            "fc",
+        ),
+    };
+    format!(
+        "{} ({} RSSI): [{}] {} {} | {} %RH | {} {}",
+        mmt.local_name,
+        mmt.rssi,
+        mmt.counter,
+        number(temperature.unwrap_or(-1.0), config),
+        temperature_unit,
+        number(
+            mmt.humidity
+                .as_ref()
+                .map(|h| h.as_percent())
+                .unwrap_or(-1.0),
+            config
+        ),
+        number(light.unwrap_or(-1.0), config),
+        light_unit,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::{AmbientLight, Humidity, MeasurementBuilder, Temperature};
+    use crate::types::Address;
+
+    fn measurement_with(temperature: i32, lux: Option<f32>) -> Measurement {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .local_name("Sensilo")
+            .counter(42)
+            .temperature(Temperature::from_millidegrees_celsius(temperature))
+            .humidity(Humidity::from_millipercent(45500));
+        if let Some(lux) = lux {
+            builder.ambient_light(AmbientLight::from_lux(lux));
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn default() {
+        let mmt = measurement_with(21500, Some(120.0));
+        assert_eq!(
+            measurement(&mmt, &config::Display::default(), &config::Units::default()),
+            "Sensilo (200 RSSI): [42] 21.5 °C | 45.5 %RH | 120 Lux"
+        );
+    }
+
+    #[test]
+    fn localized() {
+        let config = config::Display {
+            temperature: Some(TemperatureUnit::Fahrenheit),
+            light: LightUnit::Footcandles,
+            decimal_comma: true,
+        };
+        let mmt = measurement_with(21500, Some(107.6391));
+        assert_eq!(
+            measurement(&mmt, &config, &config::Units::default()),
+            "Sensilo (200 RSSI): [42] 70,7 °F | 45,5 %RH | 10 fc"
+        );
+        let mmt = measurement_with(21500, None);
+        assert_eq!(
+            measurement(&mmt, &config, &config::Units::default()),
+            "Sensilo (200 RSSI): [42] 70,7 °F | 45,5 %RH | -1 fc"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Decoder for unencrypted Eddystone-TLM (telemetry) frames, sent by many
+//! third-party beacons and by Sensilo nodes with the `eddystone-tlm` firmware
+//! feature.
+//!
+//! See <https://github.com/google/eddystone/blob/master/eddystone-tlm/tlm-plain.md>
+//! for the format specification.
+use crate::advertising::AdStructure;
+use crate::decoder::Decoder;
+use crate::measurement::{Battery, MeasurementBuilder, Temperature};
+
+/// 16 bit UUID of the Eddystone service data.
+const EDDYSTONE_UUID: u16 = 0xfeaa;
+
+/// Frame type of TLM frames (the other Eddystone frames are ignored).
+const FRAME_TYPE_TLM: u8 = 0x20;
+
+/// Version of unencrypted TLM frames.
+const VERSION_PLAIN: u8 = 0x00;
+
+/// The values of a TLM frame.
+#[derive(Debug, PartialEq)]
+struct Tlm {
+    /// `None` if not supported by the beacon (0)
+    battery: Option<Battery>,
+    /// `None` if not supported by the beacon (-128 °C)
+    temperature: Option<Temperature>,
+    advertisements: u32,
+    /// Tenths of seconds since the beacon started
+    uptime_ds: u32,
+}
+
+fn parse(data: &[u8]) -> Result<Tlm, &'static str> {
+    let values = match data {
+        [FRAME_TYPE_TLM, VERSION_PLAIN, values @ ..] => values,
+        [FRAME_TYPE_TLM, ..] => return Err("Encrypted Eddystone-TLM frames are not supported"),
+        _ => return Err("Not an Eddystone-TLM frame"),
+    };
+    if values.len() < 12 {
+        return Err("Truncated Eddystone-TLM frame");
+    }
+    let millivolts = u16::from_be_bytes([values[0], values[1]]);
+    // Signed 8.8 fixed point
+    let temperature = i16::from_be_bytes([values[2], values[3]]);
+    Ok(Tlm {
+        battery: Some(Battery { millivolts }).filter(|_| millivolts != 0),
+        temperature: Some(temperature)
+            .filter(|&raw| raw != i16::MIN)
+            .map(|raw| Temperature::from_millidegrees_celsius(i32::from(raw) * 1000 / 256)),
+        advertisements: u32::from_be_bytes([values[4], values[5], values[6], values[7]]),
+        uptime_ds: u32::from_be_bytes([values[8], values[9], values[10], values[11]]),
+    })
+}
+
+/// Decodes the Eddystone-TLM frames of a device into measurements.
+///
+/// TLM frames have no sequence number. The counter is incremented whenever
+/// the advertisement count changes, so that repeated receptions of a frame
+/// are deduplicated. Missed frames are not detected.
+#[derive(Default)]
+pub struct EddystoneTlmDecoder {
+    /// Counter and advertisement count of the previous frame
+    previous: Option<(u16, u32)>,
+}
+
+impl EddystoneTlmDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Decoder for EddystoneTlmDecoder {
+    fn payload<'a>(&self, data: &'a [AdStructure]) -> Option<&'a [u8]> {
+        data.iter().find_map(|datum| match datum {
+            AdStructure::ServiceData16 {
+                uuid: EDDYSTONE_UUID,
+                data,
+            } if data.first() == Some(&FRAME_TYPE_TLM) => Some(&data[..]),
+            _ => None,
+        })
+    }
+
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        builder: &mut MeasurementBuilder<'_>,
+    ) -> Result<(), &'static str> {
+        // Eddystone beacons usually don't send a local name
+        builder.local_name("");
+
+        let tlm = parse(payload)?;
+        log::trace!(
+            "Eddystone-TLM uptime: {:.1} s",
+            f64::from(tlm.uptime_ds) / 10.0
+        );
+        let counter = match self.previous {
+            Some((counter, advertisements)) if advertisements == tlm.advertisements => counter,
+            Some((counter, _)) => counter.wrapping_add(1),
+            None => 0,
+        };
+        self.previous = Some((counter, tlm.advertisements));
+
+        builder.counter(counter).advertisements(tlm.advertisements);
+        if let Some(battery) = tlm.battery {
+            builder.battery(battery);
+        }
+        if let Some(temperature) = tlm.temperature {
+            builder.temperature(temperature);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::types::Address;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    #[rustfmt::skip]
+    const DATA: [u8; 14] = [
+        // Frame type and version
+        0x20, 0x00,
+        // Battery voltage (3000 mV)
+        0x0b, 0xb8,
+        // Temperature (21.5 °C)
+        0x15, 0x80,
+        // Advertisement count
+        0x00, 0x00, 0x01, 0x00,
+        // Uptime (0.1 s)
+        0x00, 0x00, 0x00, 0x64,
+    ];
+
+    #[test]
+    fn parse_frame() {
+        let tlm = parse(&DATA).unwrap();
+        assert_eq!(tlm.battery, Some(Battery { millivolts: 3000 }));
+        assert_eq!(tlm.temperature.unwrap().as_millidegrees_celsius(), 21500);
+        assert_eq!(tlm.advertisements, 256);
+        assert_eq!(tlm.uptime_ds, 100);
+
+        // Battery voltage and temperature not supported
+        let mut data = DATA;
+        data[2..6].copy_from_slice(&[0x00, 0x00, 0x80, 0x00]);
+        let tlm = parse(&data).unwrap();
+        assert!(tlm.battery.is_none());
+        assert!(tlm.temperature.is_none());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        // Encrypted
+        assert!(parse(&[0x20, 0x01, 0x00]).is_err());
+        // UID frame
+        assert!(parse(&[0x00, 0x00, 0x00]).is_err());
+        assert!(parse(&DATA[..13]).is_err());
+    }
+
+    #[test]
+    fn payload() {
+        let decoder = EddystoneTlmDecoder::new();
+        let tlm = vec![AdStructure::ServiceData16 {
+            uuid: 0xfeaa,
+            data: DATA.to_vec(),
+        }];
+        assert_eq!(decoder.payload(&tlm), Some(&DATA[..]));
+        // URL frame
+        let url = vec![AdStructure::ServiceData16 {
+            uuid: 0xfeaa,
+            data: vec![0x10, 0xeb, 0x03],
+        }];
+        assert_eq!(decoder.payload(&url), None);
+    }
+
+    #[test]
+    fn counter() {
+        let mut decoder = EddystoneTlmDecoder::new();
+        let mut counters = vec![];
+        for advertisements in [256u32, 256, 260, 270] {
+            let mut data = DATA;
+            data[6..10].copy_from_slice(&advertisements.to_be_bytes());
+            let mut builder = MeasurementBuilder::new(ADDR, 200);
+            decoder.decode(&data, &mut builder).unwrap();
+            let measurement = builder.build().unwrap();
+            assert_eq!(measurement.advertisements, Some(advertisements));
+            assert_eq!(measurement.battery, Some(Battery { millivolts: 3000 }));
+            counters.push(measurement.counter);
+        }
+        assert_eq!(counters, vec![0, 0, 1, 2]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Pipe measurements as JSON to an external command.
+use anyhow::{bail, Context, Result};
+use futures::AsyncWriteExt;
+use smol::process::{Child, Command, Stdio};
+
+use crate::config::{self, ExecMode};
+
+pub struct ExecSink {
+    config: config::Exec,
+    /// Long-running child process (in stream mode)
+    child: Option<Child>,
+}
+
+impl ExecSink {
+    pub fn new(config: &config::Exec) -> Result<Self> {
+        if config.command.is_empty() {
+            bail!("Exec sink command must not be empty");
+        }
+        Ok(Self {
+            config: config.clone(),
+            child: None,
+        })
+    }
+
+    fn spawn(&self) -> Result<Child> {
+        log::debug!("Spawning exec sink command {:?}", self.config.command);
+        Command::new(&self.config.command[0])
+            .args(&self.config.command[1..])
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Could not spawn {:?}", self.config.command))
+    }
+
+    /// Start the command in stream mode (in batch mode, the command is only
+    /// run with measurements).
+    pub async fn check(&mut self) -> Result<()> {
+        if self.config.mode == ExecMode::Stream && self.child.is_none() {
+            self.child = Some(self.spawn()?);
+        }
+        Ok(())
+    }
+
+    /// Send a batch of JSON encoded measurements (one per line).
+    pub async fn submit(&mut self, lines: &[String]) -> Result<()> {
+        let mut data = lines.join("\n");
+        data.push('\n');
+        match self.config.mode {
+            ExecMode::Batch => {
+                let mut child = self.spawn()?;
+                let mut stdin = child.stdin.take().unwrap();
+                stdin.write_all(data.as_bytes()).await?;
+                drop(stdin);
+                let status = child.status().await?;
+                if !status.success() {
+                    bail!("Command {:?} failed: {}", self.config.command, status);
+                }
+            }
+            ExecMode::Stream => {
+                // (Re)start the command if necessary
+                if let Some(ref mut child) = self.child {
+                    if let Some(status) = child.try_status()? {
+                        status!("Warning: Exec sink command exited ({}), restarting", status);
+                        self.child = None;
+                    }
+                }
+                if self.child.is_none() {
+                    self.child = Some(self.spawn()?);
+                }
+
+                let child = self.child.as_mut().unwrap();
+                let stdin = child.stdin.as_mut().unwrap();
+                let result = async {
+                    stdin.write_all(data.as_bytes()).await?;
+                    stdin.flush().await
+                }
+                .await;
+                if let Err(e) = result {
+                    // Restart the command on the next submission
+                    self.child = None;
+                    bail!("Could not write to exec sink command: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sink(command: &[&str], mode: ExecMode) -> ExecSink {
+        ExecSink::new(&config::Exec {
+            command: command.iter().map(|s| s.to_string()).collect(),
+            mode,
+            min_interval_s: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn batch() {
+        let lines = vec!["{}".to_string()];
+        smol::block_on(async {
+            let mut ok = sink(&["sh", "-c", "grep -q '^{}$'"], ExecMode::Batch);
+            assert!(ok.submit(&lines).await.is_ok());
+            let mut failing = sink(&["false"], ExecMode::Batch);
+            assert!(failing.submit(&lines).await.is_err());
+        });
+    }
+
+    #[test]
+    fn stream() {
+        let lines = vec!["{}".to_string()];
+        smol::block_on(async {
+            let mut sink = sink(&["sh", "-c", "cat > /dev/null"], ExecMode::Stream);
+            assert!(sink.submit(&lines).await.is_ok());
+            assert!(sink.submit(&lines).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn empty_command() {
+        assert!(ExecSink::new(&config::Exec {
+            command: vec![],
+            mode: ExecMode::Batch,
+            min_interval_s: None,
+        })
+        .is_err());
+    }
+}
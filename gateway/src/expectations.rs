@@ -0,0 +1,135 @@
+//! Detect expected metrics that are missing in the measurements of a device
+//! (e.g. because of a failed sensor).
+use std::collections::{BTreeSet, HashMap};
+
+use crate::config::Metric;
+use crate::measurement::Measurement;
+use crate::types::Address;
+
+/// A change of the availability of an expected metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expectation {
+    /// The metric is missing (for the first time since it was last received)
+    Missing(Metric),
+    /// The metric is received again
+    Restored(Metric),
+}
+
+/// Whether a measurement contains a metric.
+pub fn contains(mmt: &Measurement, metric: Metric) -> bool {
+    match metric {
+        Metric::Temperature => mmt.temperature.is_some(),
+        Metric::Humidity => mmt.humidity.is_some(),
+        Metric::AmbientLight => mmt.ambient_light.is_some(),
+        Metric::ThermocoupleTemperature => mmt.thermocouple_temperature.is_some(),
+        Metric::ExternalTemperature => !mmt.external_temperatures.is_empty(),
+        Metric::Pulses => mmt.pulses.is_some(),
+        Metric::Analog => !mmt.analog.is_empty(),
+        Metric::Contact => mmt.contact.is_some(),
+    }
+}
+
+/// Tracks the missing expected metrics of every device.
+///
+/// Only changes are reported, so that a failed sensor results in a single
+/// alert (and a single recovery), not in one alert per measurement.
+pub struct Expectations {
+    expected: HashMap<Address, Vec<Metric>>,
+    missing: HashMap<Address, BTreeSet<Metric>>,
+}
+
+impl Expectations {
+    pub fn new() -> Self {
+        Self {
+            expected: HashMap::new(),
+            missing: HashMap::new(),
+        }
+    }
+
+    /// Set the expected metrics of a device.
+    pub fn set_expected(&mut self, address: Address, metrics: Vec<Metric>) {
+        self.expected.insert(address, metrics);
+    }
+
+    /// Return the missing expected metrics of every device.
+    pub fn missing(&self) -> impl Iterator<Item = (Address, &BTreeSet<Metric>)> {
+        self.missing
+            .iter()
+            .map(|(address, missing)| (*address, missing))
+    }
+
+    /// Restore the missing expected metrics of a device, so that they are not
+    /// reported again.
+    pub fn restore(&mut self, address: Address, missing: impl IntoIterator<Item = Metric>) {
+        self.missing.insert(address, missing.into_iter().collect());
+    }
+
+    /// Check a measurement. Return the metrics that went missing or were
+    /// restored since the previous measurement of the device.
+    pub fn update(&mut self, mmt: &Measurement) -> Vec<Expectation> {
+        let expected = match self.expected.get(&mmt.address) {
+            Some(expected) => expected,
+            None => return vec![],
+        };
+        let missing = self.missing.entry(mmt.address).or_default();
+        let mut changes = vec![];
+        for &metric in expected {
+            if contains(mmt, metric) {
+                if missing.remove(&metric) {
+                    changes.push(Expectation::Restored(metric));
+                }
+            } else if missing.insert(metric) {
+                changes.push(Expectation::Missing(metric));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::{MeasurementBuilder, Temperature};
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn measurement(address: Address, temperature: Option<i32>) -> Measurement {
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo").counter(1);
+        if let Some(temperature) = temperature {
+            builder.temperature(Temperature::from_millidegrees_celsius(temperature));
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn missing_and_restored() {
+        let mut expectations = Expectations::new();
+        expectations.set_expected(ADDR, vec![Metric::Temperature, Metric::Humidity]);
+        assert_eq!(
+            expectations.update(&measurement(ADDR, Some(20000))),
+            vec![Expectation::Missing(Metric::Humidity)]
+        );
+        // Only reported once
+        assert_eq!(expectations.update(&measurement(ADDR, Some(20000))), vec![]);
+        assert_eq!(
+            expectations.update(&measurement(ADDR, None)),
+            vec![Expectation::Missing(Metric::Temperature)]
+        );
+        assert_eq!(
+            expectations.update(&measurement(ADDR, Some(20000))),
+            vec![Expectation::Restored(Metric::Temperature)]
+        );
+    }
+
+    #[test]
+    fn no_expectations() {
+        let mut expectations = Expectations::new();
+        expectations.set_expected(ADDR, vec![Metric::Temperature]);
+        assert_eq!(
+            expectations.update(&measurement(Address([0; 6]), None)),
+            vec![]
+        );
+    }
+}
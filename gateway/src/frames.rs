@@ -0,0 +1,123 @@
+//! Retention of the last raw payloads per device, for debugging.
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::types::Address;
+
+/// A raw payload (manufacturer specific data) as it was received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawFrame {
+    pub timestamp: SystemTime,
+    pub rssi: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Ring buffers with the last raw frames of every device.
+pub struct RawFrames {
+    capacity: usize,
+    frames: HashMap<Address, VecDeque<RawFrame>>,
+}
+
+impl RawFrames {
+    /// Keep the last `capacity` frames per device. With a capacity of 0,
+    /// no frames are kept.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, address: Address, frame: RawFrame) {
+        if self.capacity == 0 {
+            return;
+        }
+        let capacity = self.capacity;
+        let frames = self
+            .frames
+            .entry(address)
+            .or_insert_with(|| VecDeque::with_capacity(capacity));
+        if frames.len() == capacity {
+            frames.pop_front();
+        }
+        frames.push_back(frame);
+    }
+
+    /// The retained frames of a device (oldest first).
+    pub fn get(&self, address: Address) -> impl Iterator<Item = &RawFrame> {
+        self.frames.get(&address).into_iter().flatten()
+    }
+
+    /// Dump the retained frames of all devices hex-encoded, one frame per
+    /// line (with the timestamp in milliseconds and the RSSI).
+    pub fn dump(&self) -> String {
+        let mut addresses: Vec<&Address> = self.frames.keys().collect();
+        addresses.sort_by_key(|address| address.0);
+        let mut dump = String::new();
+        for address in addresses {
+            writeln!(dump, "{}:", address).unwrap();
+            for frame in self.get(*address) {
+                let timestamp = frame
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                writeln!(
+                    dump,
+                    "  {} ({} RSSI): {}",
+                    timestamp,
+                    frame.rssi,
+                    base16::encode_lower(&frame.payload)
+                )
+                .unwrap();
+            }
+        }
+        dump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn frame(payload: &[u8]) -> RawFrame {
+        RawFrame {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1607500000123),
+            rssi: 200,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut frames = RawFrames::new(2);
+        frames.push(ADDR, frame(&[1]));
+        frames.push(ADDR, frame(&[2]));
+        frames.push(ADDR, frame(&[3]));
+        let payloads: Vec<&[u8]> = frames.get(ADDR).map(|f| &f.payload[..]).collect();
+        assert_eq!(payloads, vec![&[2][..], &[3][..]]);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut frames = RawFrames::new(0);
+        frames.push(ADDR, frame(&[1]));
+        assert_eq!(frames.get(ADDR).count(), 0);
+        assert_eq!(frames.dump(), "");
+    }
+
+    #[test]
+    fn dump() {
+        let mut frames = RawFrames::new(4);
+        frames.push(ADDR, frame(&[0x01, 0x2a, 0xff]));
+        assert_eq!(
+            frames.dump(),
+            "123456:\n  1607500000123 (200 RSSI): 012aff\n"
+        );
+    }
+}
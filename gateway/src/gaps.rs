@@ -0,0 +1,300 @@
+//! Detect missed beacons and device reboots based on the measurement counter.
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::types::Address;
+
+/// Counter differences larger than this are treated as a counter reset
+/// (or reordered measurements) instead of missed beacons.
+const MAX_COUNTER_DELTA: u16 = 0x8000;
+
+/// If the counter jumps backwards to a value below this threshold, the device
+/// is assumed to have rebooted (the counter starts at 0 after a reboot).
+const REBOOT_COUNTER_THRESHOLD: u16 = 100;
+
+/// A gap between two consecutively received measurements of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// Number of measurements that were not received
+    pub missed: u16,
+    /// Time between the two received measurements
+    pub elapsed: Duration,
+    /// Time exceeding the expected measurement interval (only known if an
+    /// interval is configured for the device)
+    pub duration: Option<Duration>,
+}
+
+/// A discontinuity of the measurement counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterEvent {
+    /// One or more measurements were missed
+    Gap(Gap),
+    /// The device rebooted
+    Reboot {
+        /// Counter of the last measurement received before the reboot
+        previous_counter: u16,
+        /// Time since the last measurement received before the reboot
+        elapsed: Duration,
+    },
+}
+
+/// Packet loss of a device within a rolling window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loss {
+    pub window: Duration,
+    pub received: u32,
+    pub missed: u32,
+}
+
+impl Loss {
+    /// Return the loss in percent of all sent measurements.
+    pub fn as_percent(&self) -> f32 {
+        let total = self.received + self.missed;
+        if total == 0 {
+            0.0
+        } else {
+            self.missed as f32 / total as f32 * 100.0
+        }
+    }
+}
+
+struct LastSeen {
+    counter: u16,
+    instant: Instant,
+}
+
+/// A received measurement, and the number of measurements that were missed
+/// before it.
+struct Sample {
+    instant: Instant,
+    missed: u16,
+}
+
+/// Tracks the last received counter of every device.
+pub struct GapDetector {
+    last_seen: HashMap<Address, LastSeen>,
+    intervals: HashMap<Address, Duration>,
+    samples: HashMap<Address, VecDeque<Sample>>,
+    windows: Vec<Duration>,
+}
+
+impl GapDetector {
+    /// Create a new gap detector that calculates the packet loss over the
+    /// specified rolling windows.
+    pub fn new(windows: Vec<Duration>) -> Self {
+        Self {
+            last_seen: HashMap::new(),
+            intervals: HashMap::new(),
+            samples: HashMap::new(),
+            windows,
+        }
+    }
+
+    /// Set the expected measurement interval of a device.
+    pub fn set_interval(&mut self, address: Address, interval: Duration) {
+        self.intervals.insert(address, interval);
+    }
+
+    /// Return the last received counter of every device, and when it was
+    /// received.
+    pub fn last_seen(&self) -> impl Iterator<Item = (Address, u16, Instant)> + '_ {
+        self.last_seen
+            .iter()
+            .map(|(address, last)| (*address, last.counter, last.instant))
+    }
+
+    /// Restore the last received counter of a device (e.g. from a snapshot
+    /// taken before a restart).
+    pub fn restore(&mut self, address: Address, counter: u16, instant: Instant) {
+        self.last_seen
+            .insert(address, LastSeen { counter, instant });
+    }
+
+    /// Register a received measurement and return the counter discontinuity
+    /// to the previously received measurement, if any.
+    pub fn update(&mut self, address: Address, counter: u16, now: Instant) -> Option<CounterEvent> {
+        let previous = self.last_seen.insert(
+            address,
+            LastSeen {
+                counter,
+                instant: now,
+            },
+        );
+        let event = previous.and_then(|previous| self.classify(address, &previous, counter, now));
+
+        // Record sample for the loss statistics
+        let missed = match event {
+            Some(CounterEvent::Gap(gap)) => gap.missed,
+            // Measurements sent between the reboot and this one were missed
+            Some(CounterEvent::Reboot { .. }) => counter,
+            None => 0,
+        };
+        let max_window = self.windows.iter().max().copied().unwrap_or_default();
+        let samples = self.samples.entry(address).or_default();
+        samples.push_back(Sample {
+            instant: now,
+            missed,
+        });
+        while samples
+            .front()
+            .is_some_and(|sample| now.duration_since(sample.instant) > max_window)
+        {
+            samples.pop_front();
+        }
+
+        event
+    }
+
+    fn classify(
+        &self,
+        address: Address,
+        previous: &LastSeen,
+        counter: u16,
+        now: Instant,
+    ) -> Option<CounterEvent> {
+        let delta = counter.wrapping_sub(previous.counter);
+        if delta <= 1 {
+            return None;
+        }
+        if delta >= MAX_COUNTER_DELTA {
+            if counter < REBOOT_COUNTER_THRESHOLD {
+                return Some(CounterEvent::Reboot {
+                    previous_counter: previous.counter,
+                    elapsed: now.duration_since(previous.instant),
+                });
+            }
+            log::debug!(
+                "Counter of {} jumped from {} to {}",
+                address,
+                previous.counter,
+                counter
+            );
+            return None;
+        }
+        let elapsed = now.duration_since(previous.instant);
+        let duration = self
+            .intervals
+            .get(&address)
+            .map(|interval| elapsed.checked_sub(*interval).unwrap_or_default());
+        Some(CounterEvent::Gap(Gap {
+            missed: delta - 1,
+            elapsed,
+            duration,
+        }))
+    }
+
+    /// Return the packet loss of a device for every configured window.
+    pub fn loss(&self, address: Address, now: Instant) -> Vec<Loss> {
+        let samples = match self.samples.get(&address) {
+            Some(samples) => samples,
+            None => return vec![],
+        };
+        self.windows
+            .iter()
+            .map(|window| {
+                let mut loss = Loss {
+                    window: *window,
+                    received: 0,
+                    missed: 0,
+                };
+                for sample in samples
+                    .iter()
+                    .filter(|sample| now.duration_since(sample.instant) <= *window)
+                {
+                    loss.received += 1;
+                    loss.missed += u32::from(sample.missed);
+                }
+                loss
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn detector() -> GapDetector {
+        GapDetector::new(vec![Duration::from_secs(60), Duration::from_secs(600)])
+    }
+
+    #[test]
+    fn no_gap() {
+        let mut detector = detector();
+        let now = Instant::now();
+        assert_eq!(detector.update(ADDR, 1, now), None);
+        assert_eq!(detector.update(ADDR, 2, now + Duration::from_secs(3)), None);
+        assert_eq!(detector.update(ADDR, 2, now + Duration::from_secs(4)), None);
+    }
+
+    #[test]
+    fn gap() {
+        let mut detector = detector();
+        detector.set_interval(ADDR, Duration::from_secs(3));
+        let now = Instant::now();
+        detector.update(ADDR, 10, now);
+        let event = detector.update(ADDR, 13, now + Duration::from_secs(9));
+        assert_eq!(
+            event,
+            Some(CounterEvent::Gap(Gap {
+                missed: 2,
+                elapsed: Duration::from_secs(9),
+                duration: Some(Duration::from_secs(6)),
+            }))
+        );
+    }
+
+    #[test]
+    fn gap_without_interval() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detector.update(ADDR, 0xffff, now);
+        match detector.update(ADDR, 1, now) {
+            Some(CounterEvent::Gap(gap)) => {
+                assert_eq!(gap.missed, 1);
+                assert_eq!(gap.duration, None);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reboot() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detector.update(ADDR, 1000, now);
+        assert_eq!(
+            detector.update(ADDR, 0, now + Duration::from_secs(5)),
+            Some(CounterEvent::Reboot {
+                previous_counter: 1000,
+                elapsed: Duration::from_secs(5),
+            })
+        );
+        assert_eq!(detector.update(ADDR, 1, now), None);
+    }
+
+    #[test]
+    fn counter_jump() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detector.update(ADDR, 1000, now);
+        assert_eq!(detector.update(ADDR, 500, now), None);
+    }
+
+    #[test]
+    fn loss() {
+        let mut detector = detector();
+        let now = Instant::now();
+        detector.update(ADDR, 0, now);
+        detector.update(ADDR, 2, now + Duration::from_secs(6));
+        detector.update(ADDR, 3, now + Duration::from_secs(100));
+        let loss = detector.loss(ADDR, now + Duration::from_secs(100));
+        assert_eq!(loss.len(), 2);
+        assert_eq!((loss[0].received, loss[0].missed), (1, 0));
+        assert_eq!((loss[1].received, loss[1].missed), (3, 1));
+        assert_eq!(loss[1].as_percent(), 25.0);
+        assert!(detector.loss(Address([0; 6]), now).is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+//! Send metrics to Graphite (plaintext protocol) or StatsD (gauges).
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use futures::AsyncWriteExt;
+use smol::net::{TcpStream, UdpSocket};
+
+use crate::config::{self, GraphiteProtocol};
+use crate::influxdb::Point;
+use crate::retry::{timeout, Timeouts};
+use crate::template::{expand, Devices};
+use crate::types::Address;
+
+/// Maximum payload size of a StatsD datagram (to avoid fragmentation).
+const MAX_DATAGRAM_LEN: usize = 1432;
+
+/// Replace characters that have a special meaning in metric paths.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// A rendered metric: Path, value and timestamp (in seconds since the Unix
+/// epoch).
+type Metric = (String, String, u64);
+
+/// Render points as metric paths and values.
+fn render(template: &str, devices: &Devices, points: &[Point]) -> Vec<Metric> {
+    let mut metrics = vec![];
+    for point in points {
+        let address = point.address.to_string();
+        let vars: Vec<(&str, String)> = devices
+            .vars(&point.metric, &address, &point.local_name, point.address)
+            .into_iter()
+            .map(|(name, value)| (name, sanitize(value)))
+            .collect();
+        let vars: Vec<(&str, &str)> = vars.iter().map(|(n, v)| (*n, v.as_str())).collect();
+        let mut path = expand(template, &vars);
+        for (_, value) in &point.tags {
+            path.push('.');
+            path.push_str(&sanitize(value));
+        }
+        let timestamp = point
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for (field, value) in &point.fields {
+            if point.fields.len() > 1 {
+                metrics.push((format!("{}.{}", path, field), value.clone(), timestamp));
+            } else {
+                metrics.push((path.clone(), value.clone(), timestamp));
+            }
+        }
+    }
+    metrics
+}
+
+/// Lines in the Graphite plaintext protocol.
+fn graphite_lines(metrics: &[Metric]) -> String {
+    metrics
+        .iter()
+        .map(|(path, value, timestamp)| format!("{} {} {}\n", path, value, timestamp))
+        .collect()
+}
+
+/// StatsD gauge datagrams, each containing as many metrics as possible. The
+/// timestamps are ignored, StatsD does not support them.
+fn statsd_datagrams(metrics: &[Metric]) -> Vec<String> {
+    let mut datagrams: Vec<String> = vec![];
+    for (path, value, _) in metrics {
+        // Signed gauge values modify the current value, so negative values
+        // must be sent as difference to zero
+        let metric = if value.starts_with('-') {
+            format!("{}:0|g\n{}:{}|g", path, path, value)
+        } else {
+            format!("{}:{}|g", path, value)
+        };
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + metric.len() <= MAX_DATAGRAM_LEN => {
+                datagram.push('\n');
+                datagram.push_str(&metric);
+            }
+            _ => datagrams.push(metric),
+        }
+    }
+    datagrams
+}
+
+/// Default timeout for connecting and for every write.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct GraphiteSink {
+    config: config::Graphite,
+    devices: Devices,
+    /// Graphite connection
+    stream: Option<TcpStream>,
+    timeouts: Timeouts,
+}
+
+impl GraphiteSink {
+    /// The addresses must be in the same order as the devices in the config.
+    pub fn new(
+        config: &config::Graphite,
+        devices: &[config::Device],
+        addresses: &[Address],
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            devices: Devices::new(devices, addresses),
+            stream: None,
+            timeouts: Timeouts::new(&config.connection, TIMEOUT),
+        }
+    }
+
+    fn port(&self) -> u16 {
+        self.config.port.unwrap_or(match self.config.protocol {
+            GraphiteProtocol::Graphite => 2003,
+            GraphiteProtocol::Statsd => 8125,
+        })
+    }
+
+    /// Connect (if not connected yet). With StatsD, only the address is
+    /// resolved.
+    pub async fn check(&mut self) -> Result<()> {
+        let addr = (self.config.host.as_str(), self.port());
+        match self.config.protocol {
+            GraphiteProtocol::Graphite if self.stream.is_none() => {
+                let connect = async { Ok(TcpStream::connect(addr).await?) };
+                let stream = timeout(self.timeouts.connect, "connecting", connect)
+                    .await
+                    .with_context(|| format!("Could not connect to {}:{}", addr.0, addr.1))?;
+                self.stream = Some(stream);
+            }
+            GraphiteProtocol::Graphite => {}
+            GraphiteProtocol::Statsd => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+                socket
+                    .connect(addr)
+                    .await
+                    .with_context(|| format!("Could not resolve {}:{}", addr.0, addr.1))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn submit(&mut self, points: &[Point]) -> Result<()> {
+        let metrics = render(&self.config.template, &self.devices, points);
+        let addr = (self.config.host.as_str(), self.port());
+        match self.config.protocol {
+            GraphiteProtocol::Graphite => {
+                self.check().await?;
+                let stream = self.stream.as_mut().unwrap();
+                let write = async {
+                    stream
+                        .write_all(graphite_lines(&metrics).as_bytes())
+                        .await?;
+                    stream.flush().await?;
+                    Ok(())
+                };
+                let result = timeout(self.timeouts.write, "writing", write).await;
+                if result.is_err() {
+                    // Reconnect on the next submission
+                    self.stream = None;
+                }
+                result?;
+            }
+            GraphiteProtocol::Statsd => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+                socket.connect(addr).await?;
+                for datagram in statsd_datagrams(&metrics) {
+                    socket.send(datagram.as_bytes()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn devices() -> Devices {
+        let device = config::Device {
+            name: "Living room.1".into(),
+            hex_addr: "010203040506".into(),
+            location: None,
+            site: None,
+            interval_s: None,
+            protocol: config::Protocol::Sensilo,
+            analog: vec![],
+            expects: vec![],
+            key: None,
+            crc: false,
+            irk: None,
+            min_rssi: None,
+        };
+        Devices::new(&[device], &[ADDR])
+    }
+
+    fn point(metric: &'static str, value: &str) -> Point {
+        Point {
+            metric: metric.into(),
+            address: ADDR,
+            local_name: "Sensilo".into(),
+            tags: vec![],
+            fields: vec![("value", value.into())],
+            timestamp: UNIX_EPOCH + Duration::from_secs(1607500000),
+        }
+    }
+
+    #[test]
+    fn render_metrics() {
+        let mut loss = point("packet_loss", "1.50");
+        loss.tags.push(("window", "300s".into()));
+        let mut reboot = point("reboot", "");
+        reboot.fields = vec![("previous_counter", "12".into()), ("counter", "0".into())];
+        let metrics = render(
+            "sensilo.{name}.{metric}",
+            &devices(),
+            &[point("temperature", "21500"), loss, reboot],
+        );
+        assert_eq!(
+            metrics,
+            vec![
+                (
+                    "sensilo.Living_room_1.temperature".into(),
+                    "21500".into(),
+                    1607500000
+                ),
+                (
+                    "sensilo.Living_room_1.packet_loss.300s".into(),
+                    "1.50".into(),
+                    1607500000
+                ),
+                (
+                    "sensilo.Living_room_1.reboot.previous_counter".into(),
+                    "12".into(),
+                    1607500000
+                ),
+                (
+                    "sensilo.Living_room_1.reboot.counter".into(),
+                    "0".into(),
+                    1607500000
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn graphite() {
+        let metrics = vec![("a.b".to_string(), "1".to_string(), 1607500000)];
+        assert_eq!(graphite_lines(&metrics), "a.b 1 1607500000\n");
+    }
+
+    #[test]
+    fn statsd() {
+        let metrics = vec![
+            ("a.b".to_string(), "1".to_string(), 0),
+            ("a.c".to_string(), "2.5".to_string(), 0),
+        ];
+        assert_eq!(statsd_datagrams(&metrics), vec!["a.b:1|g\na.c:2.5|g"]);
+
+        let negative = vec![("a.b".to_string(), "-1".to_string(), 0)];
+        assert_eq!(statsd_datagrams(&negative), vec!["a.b:0|g\na.b:-1|g"]);
+
+        let long = vec![("x".repeat(1000), "1".to_string(), 0); 2];
+        assert_eq!(statsd_datagrams(&long).len(), 2);
+    }
+}
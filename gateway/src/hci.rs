@@ -0,0 +1,145 @@
+//! Minimal parser for the HCI events that contain advertising reports.
+//!
+//! Only LE meta events with LE Advertising Reports or LE Extended Advertising
+//! Reports are parsed, all other packets are ignored.
+use crate::advertising::{
+    parse_advertising_report, parse_extended_advertising_report, AdvertisingReport,
+    LE_ADVERTISING_REPORT, LE_EXTENDED_ADVERTISING_REPORT,
+};
+
+/// HCI packet type indicator of HCI events.
+const HCI_EVENT_PACKET: u8 = 0x04;
+
+/// Event code of HCI LE meta events.
+const HCI_LE_META_EVENT: u8 = 0x3e;
+
+/// Parse an HCI packet in H4 format (starting with the packet type
+/// indicator). Return the advertising reports, or `None` if the packet is not
+/// an event with advertising reports.
+pub fn parse_packet(data: &[u8]) -> Result<Option<Vec<AdvertisingReport>>, &'static str> {
+    let (event_code, params) = match data {
+        [HCI_EVENT_PACKET, event_code, len, rest @ ..] => {
+            let params = rest.get(..*len as usize).ok_or("HCI event truncated")?;
+            if rest.len() > params.len() {
+                // Some adapters pad the packets, this is not an error
+                log::trace!(
+                    "Ignoring {} bytes after HCI event",
+                    rest.len() - params.len()
+                );
+            }
+            (*event_code, params)
+        }
+        [HCI_EVENT_PACKET, ..] => return Err("HCI event header truncated"),
+        _ => return Ok(None),
+    };
+    if event_code != HCI_LE_META_EVENT {
+        return Ok(None);
+    }
+    match params.first() {
+        Some(&LE_ADVERTISING_REPORT) => parse_advertising_report(params).map(Some),
+        Some(&LE_EXTENDED_ADVERTISING_REPORT) => {
+            parse_extended_advertising_report(params).map(Some)
+        }
+        Some(_) => Ok(None),
+        None => Err("Missing LE meta event subevent code"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::advertising::AdStructure;
+    use crate::test_util::Xorshift;
+    use crate::types::Address;
+
+    #[rustfmt::skip]
+    const ADVERTISING_REPORT: [u8; 32] = [
+        // HCI event packet, LE meta event, parameter length
+        0x04, 0x3e, 29,
+        // Subevent code, number of reports
+        0x02, 1,
+        // Event type, address type, address
+        0x03, 0x01, 0x7a, 0x99, 0x67, 0xe0, 0x4f, 0x86,
+        // Data length, data (manufacturer specific data, complete local name)
+        17,
+        5, 0xff, 0xff, 0xff, 52, 4,
+        10, 0x09, b'S', b'e', b'n', b's', b'i', b'l', b'o', b'1', b'2',
+        // RSSI
+        0xc4,
+    ];
+
+    #[test]
+    fn advertising_report() {
+        let reports = parse_packet(&ADVERTISING_REPORT).unwrap().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].address, Address::from_hex("864fe067997a"));
+        assert_eq!(reports[0].rssi, 0xc4);
+        assert_eq!(
+            reports[0].data,
+            vec![
+                AdStructure::ManufacturerSpecificData {
+                    company_identifier: 0xffff,
+                    data: vec![52, 4],
+                },
+                AdStructure::CompleteLocalName("Sensilo12".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn padding() {
+        let mut packet = ADVERTISING_REPORT.to_vec();
+        packet.extend_from_slice(&[0, 0]);
+        assert_eq!(parse_packet(&packet).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn ignored_packets() {
+        // ACL data
+        assert_eq!(parse_packet(&[0x02, 0x01, 0x20, 0x00]), Ok(None));
+        // Command complete event
+        assert_eq!(
+            parse_packet(&[0x04, 0x0e, 0x04, 1, 0x0c, 0x20, 0]),
+            Ok(None)
+        );
+        // LE connection complete event
+        assert_eq!(parse_packet(&[0x04, 0x3e, 0x01, 0x01]), Ok(None));
+        assert_eq!(parse_packet(&[]), Ok(None));
+    }
+
+    #[test]
+    fn truncated() {
+        for len in 1..ADVERTISING_REPORT.len() {
+            assert!(
+                parse_packet(&ADVERTISING_REPORT[..len]).is_err(),
+                "Length {}",
+                len
+            );
+        }
+    }
+
+    /// Feed random (and randomly mutated) packets to the parser, which must
+    /// never panic.
+    #[test]
+    fn fuzz() {
+        let mut rng = Xorshift::new(0x1234_5678);
+        let mut random = move || rng.next_u32();
+        for _ in 0..10_000 {
+            let mut packet = ADVERTISING_REPORT.to_vec();
+            packet.truncate(random() as usize % (packet.len() + 1));
+            for _ in 0..random() % 4 {
+                if packet.is_empty() {
+                    break;
+                }
+                let index = random() as usize % packet.len();
+                packet[index] = random() as u8;
+            }
+            let _ = parse_packet(&packet);
+
+            let mut packet = vec![0x04, 0x3e];
+            packet.extend((0..random() % 64).map(|_| random() as u8));
+            let _ = parse_packet(&packet);
+        }
+    }
+}
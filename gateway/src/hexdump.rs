@@ -0,0 +1,269 @@
+//! Annotated hex dumps of Sensilo payloads, for protocol debugging.
+use std::fmt::Write;
+
+use crate::measurement::{protocol_version, AmbientLight, Humidity, LightCounts, Temperature};
+use crate::protocol;
+use crate::types::Address;
+
+/// A field in the payload.
+#[derive(Debug, PartialEq)]
+struct Field<'a> {
+    offset: usize,
+    bytes: &'a [u8],
+    name: String,
+    value: String,
+}
+
+/// Split the payload into its fields, decoding them the same way as
+/// `MeasurementBuilder::parse_payload`.
+fn fields(payload: &[u8]) -> Vec<Field<'_>> {
+    let mut fields = vec![];
+    let mut offset = 0;
+    macro_rules! push {
+        ($len:expr, $name:expr, $value:expr) => {{
+            let len = $len;
+            fields.push(Field {
+                offset,
+                bytes: &payload[offset..offset + len],
+                name: $name.to_string(),
+                value: $value,
+            });
+            offset += len;
+        }};
+    }
+
+    if payload.len() < 2 {
+        return vec![Field {
+            offset,
+            bytes: payload,
+            name: "counter".into(),
+            value: "[truncated]".into(),
+        }];
+    }
+    let counter = u16::from_le_bytes([payload[0], payload[1]]);
+    push!(2, "counter", counter.to_string());
+
+    let v2 = protocol_version(payload) >= 2;
+    while offset < payload.len() {
+        let ty = payload[offset];
+        // Since protocol version 2, the entries (except for the leading frame
+        // info) have a length
+        let header_len = if v2 && offset > 2 { 2 } else { 1 };
+        let declared_len = match payload.get(offset + 1) {
+            Some(&len) if header_len == 2 => Some(usize::from(len)),
+            None if header_len == 2 => {
+                push!(1, "entry header", "[truncated]".into());
+                continue;
+            }
+            _ => None,
+        };
+        let (name, len) = match protocol::entry_type(ty) {
+            Some(entry) => (entry.name, entry.value_len()),
+            None => {
+                // Without length, only the type can be skipped
+                let len = declared_len.map_or(0, |len| len.min(payload.len() - offset - 2));
+                push!(header_len + len, "unknown type", format!("0x{:02x}", ty));
+                continue;
+            }
+        };
+        let mut data = &payload[offset + header_len..];
+        if let Some(declared_len) = declared_len {
+            data = &data[..declared_len.min(data.len())];
+        }
+        if data.len() < len {
+            // The remaining bytes are consumed, which ends the loop
+            push!(header_len + data.len(), name, "[truncated]".into());
+            continue;
+        }
+        // Additional bytes of the value are part of the field
+        let len = declared_len.unwrap_or(len);
+        let value = match ty {
+            0x00 => format!(
+                "version {}, frame {} of {}",
+                data[0],
+                data[1] >> 4,
+                data[1] & 0x0f
+            ),
+            0x01 | 0x07 | 0x13 => format!(
+                "{:.3} °C",
+                Temperature::from_le_bytes([data[0], data[1], data[2], data[3]])
+                    .as_degrees_celsius()
+            ),
+            0x02 | 0x14 => format!(
+                "{:.3} %RH",
+                Humidity::from_le_bytes([data[0], data[1], data[2], data[3]]).as_percent()
+            ),
+            0x08 => format!(
+                "probe {}: {:.3} °C",
+                data[0],
+                Temperature::from_le_bytes([data[1], data[2], data[3], data[4]])
+                    .as_degrees_celsius()
+            ),
+            0x09 => format!(
+                "{} pulses (+{})",
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                u16::from_le_bytes([data[4], data[5]])
+            ),
+            0x0a => format!(
+                "AIN{}: {} mV",
+                data[0],
+                u16::from_le_bytes([data[1], data[2]])
+            ),
+            0x0b => format!(
+                "{}, event {}",
+                if data[0] != 0 { "open" } else { "closed" },
+                u16::from_le_bytes([data[1], data[2]])
+            ),
+            0x0c => format!(
+                "flags 0x{:02x}{}{}{}",
+                data[0],
+                if data[0] & 1 != 0 { " (buzzer)" } else { "" },
+                if data[0] & 2 != 0 { " (heated)" } else { "" },
+                if data[0] & 4 != 0 {
+                    " (disagreement)"
+                } else {
+                    ""
+                }
+            ),
+            0x0d => base16::encode_lower(&data[..4]),
+            0x11 => format!("0x{:02x}", data[0]),
+            0x0e => format!(
+                "{} beacons, {} I²C errors, {} retries",
+                u16::from_le_bytes([data[0], data[1]]),
+                u16::from_le_bytes([data[2], data[3]]),
+                u16::from_le_bytes([data[4], data[5]])
+            ),
+            0x12 => format!(
+                "{} µs, started {} µs late",
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                i16::from_le_bytes([data[4], data[5]])
+            ),
+            0x0f => format!(
+                "{} measurements ago: {:.2} °C, {:.2} %RH",
+                u16::from_le_bytes([data[0], data[1]]),
+                f32::from(i16::from_le_bytes([data[2], data[3]])) / 100.0,
+                f32::from(u16::from_le_bytes([data[4], data[5]])) / 100.0
+            ),
+            0x10 => {
+                let centi = |lo, hi| match i16::from_le_bytes([lo, hi]) {
+                    i16::MIN => "failed".to_string(),
+                    value => format!("{:+.2}", f32::from(value) / 100.0),
+                };
+                format!(
+                    "{} measurements after the full one: {} °C, {} %RH",
+                    data[0],
+                    centi(data[1], data[2]),
+                    centi(data[3], data[4])
+                )
+            }
+            0x04 => format!(
+                "{:.2} Lux",
+                AmbientLight::from_le_bytes([data[0], data[1], data[2], data[3]]).as_lux()
+            ),
+            _ => {
+                let counts = LightCounts::from_le_bytes([data[0], data[1]]);
+                if counts.is_saturated() {
+                    format!("{} counts (saturated)", counts.as_counts())
+                } else {
+                    format!("{} counts", counts.as_counts())
+                }
+            }
+        };
+        push!(header_len + len, name, value);
+    }
+    fields
+}
+
+/// Render an annotated hex dump of a payload, one field per line.
+pub fn annotate(address: Address, rssi: u8, payload: &[u8]) -> String {
+    let mut dump = String::new();
+    writeln!(
+        dump,
+        "Payload from {} ({} RSSI, {} bytes): {}",
+        address,
+        rssi,
+        payload.len(),
+        base16::encode_lower(payload)
+    )
+    .unwrap();
+    for field in fields(payload) {
+        let bytes: Vec<String> = field.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(
+            dump,
+            "  {:04x}  {:<15} {:<20} {}",
+            field.offset,
+            bytes.join(" "),
+            field.name,
+            field.value
+        )
+        .unwrap();
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_of_payload() {
+        let payload = [
+            0x2a, 0x00, // counter
+            0x00, 0x01, 0x12, // frame info
+            0x01, 0xfc, 0x53, 0x00, 0x00, // temperature
+            0xee, // unknown
+            0x02, 0x01, // truncated humidity
+        ];
+        let fields: Vec<(usize, String, String)> = fields(&payload)
+            .into_iter()
+            .map(|f| (f.offset, f.name, f.value))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (0, "counter".into(), "42".into()),
+                (2, "frame info".into(), "version 1, frame 1 of 2".into()),
+                (5, "temperature".into(), "21.500 °C".into()),
+                (10, "unknown type".into(), "0xee".into()),
+                (11, "humidity".into(), "[truncated]".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_of_payload_v2() {
+        let payload = [
+            0x2a, 0x00, // counter
+            0x00, 0x02, 0x01, // frame info
+            0x01, 0x04, 0xfc, 0x53, 0x00, 0x00, // temperature
+            0xee, 0x01, 0xff, // unknown
+            0x0c, 0x02, 0x01, 0x00, // status with an additional byte
+            0x02, 0x04, 0x01, // truncated humidity
+        ];
+        let fields: Vec<(usize, String, String)> = fields(&payload)
+            .into_iter()
+            .map(|f| (f.offset, f.name, f.value))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (0, "counter".into(), "42".into()),
+                (2, "frame info".into(), "version 2, frame 0 of 1".into()),
+                (5, "temperature".into(), "21.500 °C".into()),
+                (11, "unknown type".into(), "0xee".into()),
+                (14, "status".into(), "flags 0x01 (buzzer)".into()),
+                (18, "humidity".into(), "[truncated]".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn annotate_payload() {
+        let dump = annotate(Address([1, 2, 3, 4, 5, 6]), 200, &[0x2a, 0x00]);
+        assert_eq!(
+            dump,
+            "Payload from 123456 (200 RSSI, 2 bytes): 2a00\n  \
+             0000  2a 00           counter              42\n"
+        );
+    }
+}
@@ -1,93 +1,1151 @@
-//! Send stats to InfluxDB with async-h1.
-use std::time::Duration;
+//! Points of the measurements and events, and the InfluxDB sink.
+use std::borrow::Cow;
+#[cfg(feature = "sink-influxdb")]
+use std::collections::hash_map::{Entry, HashMap};
+#[cfg(feature = "sink-influxdb")]
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{bail, Result};
-use ureq::Agent;
+#[cfg(feature = "sink-influxdb")]
+use anyhow::{anyhow, bail, Result};
+#[cfg(feature = "sink-influxdb")]
+use flate2::{write::GzEncoder, Compression};
 
+use crate::channels::ChannelCounts;
 use crate::config;
-use crate::measurement::Measurement;
-
-/// Create an ureq agent.
-pub fn make_ureq_agent() -> Agent {
-    ureq::AgentBuilder::new()
-        .timeout_read(Duration::from_secs(5))
-        .timeout_write(Duration::from_secs(5))
-        .build()
-}
-
-pub async fn submit_measurement(
-    agent: Agent,
-    config: &config::InfluxDb,
-    mmt: &Measurement<'_>,
-) -> Result<()> {
-    // Prepare payloads
-    let mut payloads = vec![];
-    let tags = format!("address={},local_name={}", mmt.address, mmt.local_name);
-    payloads.push(format!("rssi,{} value={}", tags, mmt.rssi));
-    payloads.push(format!("counter,{} value={}", tags, mmt.counter));
+use crate::dedup::DedupStats;
+use crate::expectations::Expectation;
+use crate::gaps::{Gap, Loss};
+#[cfg(feature = "sink-influxdb")]
+use crate::http;
+#[cfg(feature = "sink-influxdb")]
+use crate::json;
+use crate::measurement::{Contact, Measurement};
+#[cfg(feature = "sink-influxdb")]
+use crate::template::{expand, Devices};
+use crate::types::Address;
+use crate::units;
+
+/// A point before it is rendered according to the schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub metric: Cow<'static, str>,
+    pub address: Address,
+    pub local_name: String,
+    /// Additional tags (not configurable through the schema)
+    pub tags: Vec<(&'static str, String)>,
+    pub fields: Vec<(&'static str, String)>,
+    /// Reception time of the measurement
+    pub timestamp: SystemTime,
+}
+
+impl Point {
+    pub fn new(
+        metric: impl Into<Cow<'static, str>>,
+        mmt: &Measurement,
+        value: impl ToString,
+    ) -> Self {
+        Self {
+            metric: metric.into(),
+            address: mmt.address,
+            local_name: mmt.local_name.clone(),
+            tags: vec![],
+            fields: vec![("value", value.to_string())],
+            timestamp: mmt.timestamp,
+        }
+    }
+}
+
+/// Points of a measurement, with the temperature and humidity converted to
+/// the configured units. The raw values of smoothed metrics are added as
+/// `raw` field.
+pub fn measurement_points(mmt: &Measurement, units: &config::Units) -> Vec<Point> {
+    let mut points = vec![
+        Point::new("rssi", mmt, mmt.rssi),
+        Point::new("counter", mmt, mmt.counter),
+    ];
+    if let Some(telemetry) = mmt.telemetry {
+        let mut point = Point::new("telemetry", mmt, "");
+        point.fields = vec![
+            ("tx_beacons", telemetry.tx_beacons.to_string()),
+            ("i2c_errors", telemetry.i2c_errors.to_string()),
+            ("sensor_retries", telemetry.sensor_retries.to_string()),
+        ];
+        points.push(point);
+    }
+    if let Some(timing) = mmt.timing {
+        let mut point = Point::new("timing", mmt, "");
+        point.fields = vec![
+            ("measurement_duration_us", timing.duration_us.to_string()),
+            ("start_latency_us", timing.start_latency_us.to_string()),
+        ];
+        points.push(point);
+    }
+    points.extend(value_points(mmt, units));
+    if let Some(ref raw) = mmt.raw {
+        for raw_point in value_points(raw, units) {
+            let point = points
+                .iter_mut()
+                .find(|point| point.metric == raw_point.metric && point.tags == raw_point.tags);
+            if let (Some(point), Some((_, value))) = (point, raw_point.fields.into_iter().next()) {
+                point.fields.push(("raw", value));
+            }
+        }
+    }
+    points
+}
+
+/// Points of the sensor values of a measurement.
+pub fn value_points(mmt: &Measurement, units: &config::Units) -> Vec<Point> {
+    let mut points = vec![];
     if let Some(ref temp) = mmt.temperature {
-        payloads.push(format!(
-            "temperature,{} value={}",
-            tags,
-            temp.as_millidegrees_celsius()
-        ));
+        let value = units::temperature(temp, units);
+        points.push(Point::new("temperature", mmt, value));
     }
     if let Some(ref humi) = mmt.humidity {
-        payloads.push(format!(
-            "humidity,{} value={}",
-            tags,
-            humi.as_millipercent()
-        ));
+        let value = units::humidity(humi, units);
+        points.push(Point::new("humidity", mmt, value));
     }
     if let Some(ref lux) = mmt.ambient_light {
-        payloads.push(format!("ambient_light,{} value={:.2}", tags, lux.as_lux()));
-    }
-    let payload = payloads.join("\n");
-
-    // Create basic auth header
-    let auth = format!(
-        "Basic {}",
-        base64::encode(format!("{}:{}", &config.user, &config.pass))
-    );
-
-    // Create request
-    let url = format!("{}/write?db={}", config.connection_string, config.db);
-
-    // Send request to server
-    let resp: ureq::Response = smol::unblock(move || {
-        agent
-            .post(&url)
-            .set("authorization", &auth)
-            .error_on_non_2xx(false)
-            .send_string(&payload)
-    })
-    .await?;
-
-    // Handle response
-    match resp.status() {
-        // No content
-        204 => {}
-        // Not found
-        404 => {
-            log::warn!("InfluxDB database {} not found", config.db);
-            bail!("InfluxDB database {} not found", config.db);
-        }
-        // Bad request, permission denied
-        400 | 401 => {
-            let status = format!("{} ({})", resp.status(), resp.status_text());
-            let body = resp
-                .into_string()
-                .unwrap_or_else(|e| format!("[response decode error: {}]", e));
-            log::debug!(
-                "Could not send data to InfluxDB: Bad request: {}",
-                body.trim()
+        points.push(Point::new(
+            "ambient_light",
+            mmt,
+            format!("{:.2}", lux.as_lux()),
+        ));
+    }
+    if let Some(ref als) = mmt.ambient_light_als {
+        points.push(Point::new("ambient_light_als", mmt, als.as_counts()));
+    }
+    if let Some(ref white) = mmt.ambient_light_white {
+        points.push(Point::new("ambient_light_white", mmt, white.as_counts()));
+    }
+    if let Some(ref temp) = mmt.thermocouple_temperature {
+        let value = units::temperature(temp, units);
+        points.push(Point::new("thermocouple_temperature", mmt, value));
+    }
+    if let Some(ref temp) = mmt.secondary_temperature {
+        let value = units::temperature(temp, units);
+        points.push(Point::new("secondary_temperature", mmt, value));
+    }
+    if let Some(ref humi) = mmt.secondary_humidity {
+        let value = units::humidity(humi, units);
+        points.push(Point::new("secondary_humidity", mmt, value));
+    }
+    if let Some(ref pulses) = mmt.pulses {
+        points.push(Point::new("pulse_count", mmt, pulses.count));
+        points.push(Point::new("pulse_delta", mmt, pulses.delta));
+    }
+    if let Some(rate) = mmt.pulse_rate {
+        points.push(Point::new("pulse_rate", mmt, format!("{:.3}", rate)));
+    }
+    if let Some(ref contact) = mmt.contact {
+        points.push(Point::new("contact_open", mmt, u8::from(contact.open)));
+        points.push(Point::new("contact_events", mmt, contact.events));
+    }
+    if let Some(battery) = mmt.battery {
+        points.push(Point::new("battery_voltage", mmt, battery.millivolts));
+    }
+    if let Some(advertisements) = mmt.advertisements {
+        points.push(Point::new("advertisements", mmt, advertisements));
+    }
+    for (channel, input) in &mmt.analog {
+        points.push(Point::new(input.metric(*channel), mmt, input.millivolts));
+    }
+    for (index, temp) in &mmt.external_temperatures {
+        let value = units::temperature(temp, units);
+        let mut point = Point::new("external_temperature", mmt, value);
+        point.tags.push(("probe", index.to_string()));
+        points.push(point);
+    }
+    points
+}
+
+/// Point of a contact state change. The measurement is the first one
+/// received after the change.
+pub fn contact_points(mmt: &Measurement, contact: &Contact) -> Vec<Point> {
+    let mut point = Point::new("contact_change", mmt, "");
+    point.fields = vec![
+        ("open", u8::from(contact.open).to_string()),
+        ("events", contact.events.to_string()),
+    ];
+    vec![point]
+}
+
+/// Point of an expected metric that went missing (value 1) or was restored
+/// (value 0).
+pub fn expectation_points(mmt: &Measurement, expectation: &Expectation) -> Vec<Point> {
+    let (metric, missing) = match *expectation {
+        Expectation::Missing(metric) => (metric, 1),
+        Expectation::Restored(metric) => (metric, 0),
+    };
+    let mut point = Point::new("metric_missing", mmt, missing);
+    point.tags.push(("metric", metric.name().to_string()));
+    vec![point]
+}
+
+/// Points of a gap between two received measurements. The measurement is the
+/// first one received after the gap.
+pub fn gap_points(mmt: &Measurement, gap: &Gap) -> Vec<Point> {
+    let mut points = vec![Point::new("missed_beacons", mmt, gap.missed)];
+    if let Some(duration) = gap.duration {
+        points.push(Point::new("gap_duration", mmt, duration.as_millis()));
+    }
+    points
+}
+
+/// Point of a device reboot. The measurement is the first one received after
+/// the reboot, `elapsed` is the time since the last measurement before the
+/// reboot.
+pub fn reboot_points(mmt: &Measurement, previous_counter: u16, elapsed: Duration) -> Vec<Point> {
+    let previous_timestamp = mmt
+        .timestamp
+        .checked_sub(elapsed)
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let mut point = Point::new("reboot", mmt, "");
+    point.fields = vec![
+        ("previous_counter", previous_counter.to_string()),
+        (
+            "previous_timestamp",
+            previous_timestamp.as_millis().to_string(),
+        ),
+        ("counter", mmt.counter.to_string()),
+    ];
+    vec![point]
+}
+
+/// Points of the packet loss of a device (in percent) per window.
+pub fn loss_points(mmt: &Measurement, losses: &[Loss]) -> Vec<Point> {
+    losses
+        .iter()
+        .map(|loss| {
+            let mut point = Point::new("packet_loss", mmt, format!("{:.2}", loss.as_percent()));
+            point
+                .tags
+                .push(("window", format!("{}s", loss.window.as_secs())));
+            point
+        })
+        .collect()
+}
+
+/// Points of the deduplication statistics of a device (cumulative counters).
+pub fn dedup_points(mmt: &Measurement, stats: &DedupStats) -> Vec<Point> {
+    vec![
+        Point::new("dedup_accepted", mmt, stats.accepted),
+        Point::new("dedup_duplicates", mmt, stats.duplicates),
+    ]
+}
+
+/// Number of payloads of a device that failed the authentication. An
+/// increasing count may indicate tampering (or a wrong key).
+pub fn verification_points(mmt: &Measurement, failures: u64) -> Vec<Point> {
+    vec![Point::new("verification_failures", mmt, failures)]
+}
+
+/// Number of frames of a device that were ignored because of their low RSSI
+/// (cumulative counter).
+pub fn rssi_filter_points(mmt: &Measurement, filtered: u64) -> Vec<Point> {
+    vec![Point::new("rssi_filtered", mmt, filtered)]
+}
+
+/// Points of the number of frames of a device per advertising channel
+/// (cumulative counters), tagged with the `channel`.
+pub fn channel_points(mmt: &Measurement, counts: &ChannelCounts) -> Vec<Point> {
+    counts
+        .iter()
+        .map(|(channel, frames)| {
+            let mut point = Point::new("channel_frames", mmt, frames);
+            point.tags.push(("channel", channel.to_string()));
+            point
+        })
+        .collect()
+}
+
+/// Points of the entries with unknown types, with the value as hex-encoded
+/// string field (not supported by Graphite, which only takes numbers).
+#[cfg_attr(not(feature = "sink-influxdb"), allow(dead_code))]
+pub fn unknown_points(mmt: &Measurement) -> Vec<Point> {
+    mmt.unknown_entries
+        .iter()
+        .map(|(payload_type, value)| {
+            let value = format!("\"{}\"", base16::encode_lower(value));
+            Point::new(format!("raw_0x{:02x}", payload_type), mmt, value)
+        })
+        .collect()
+}
+
+/// Tag points with `approx_time=true`, for measurements received while the
+/// host clock was not synchronized.
+#[cfg(feature = "sink-influxdb")]
+pub fn tag_approx_time(points: &mut [Point]) {
+    for point in points {
+        point.tags.push(("approx_time", "true".into()));
+    }
+}
+
+/// Renders points in the line protocol format, using the configured
+/// measurement name, tag and field templates.
+#[cfg(feature = "sink-influxdb")]
+pub struct Schema {
+    config: config::Schema,
+    devices: Devices,
+}
+
+#[cfg(feature = "sink-influxdb")]
+impl Schema {
+    /// Create a new schema. The addresses must be in the same order as the
+    /// devices in the config.
+    pub fn new(config: &config::Schema, devices: &[config::Device], addresses: &[Address]) -> Self {
+        Self {
+            config: config.clone(),
+            devices: Devices::new(devices, addresses),
+        }
+    }
+
+    /// Render points as lines (with a timestamp in milliseconds). Points with
+    /// the same measurement name, tags and timestamp are combined into a
+    /// single line.
+    pub fn render(&self, points: &[Point]) -> Vec<String> {
+        let mut lines: Vec<(String, u128, Vec<String>)> = vec![];
+        // Index of the line with the same measurement name, tags and timestamp
+        let mut index: HashMap<(String, u128), usize> = HashMap::new();
+        for point in points {
+            let address = point.address.to_string();
+            let vars = self
+                .devices
+                .vars(&point.metric, &address, &point.local_name, point.address);
+
+            // Measurement and tags
+            let mut key = escape(&expand(&self.config.measurement, &vars), &[',', ' ']);
+            let tags = self
+                .config
+                .tags
+                .iter()
+                .map(|(key, template)| (key.as_str(), expand(template, &vars)))
+                .chain(point.tags.iter().map(|(key, value)| (*key, value.clone())));
+            for (tag_key, tag_value) in tags {
+                // Empty tag values are not allowed
+                if tag_value.is_empty() {
+                    continue;
+                }
+                key.push_str(&format!(
+                    ",{}={}",
+                    escape(tag_key, &[',', '=', ' ']),
+                    escape(&tag_value, &[',', '=', ' '])
+                ));
+            }
+
+            // Fields
+            let fields = point.fields.iter().map(|(field, value)| {
+                let mut vars = vars.clone();
+                vars.push(("field", field));
+                let mut name = expand(&self.config.field, &vars);
+                // Make sure that field names of points with multiple fields
+                // are unique
+                if point.fields.len() > 1 && !self.config.field.contains("{field}") {
+                    name = format!("{}_{}", name, field);
+                }
+                format!("{}={}", escape(&name, &[',', '=', ' ']), value)
+            });
+            let timestamp = point
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            match index.entry((key, timestamp)) {
+                Entry::Occupied(entry) => lines[*entry.get()].2.extend(fields),
+                Entry::Vacant(entry) => {
+                    let (key, timestamp) = entry.key().clone();
+                    entry.insert(lines.len());
+                    lines.push((key, timestamp, fields.collect()));
+                }
+            }
+        }
+        lines
+            .into_iter()
+            .map(|(key, timestamp, fields)| format!("{} {} {}", key, fields.join(","), timestamp))
+            .collect()
+    }
+}
+
+/// Escape special characters (and backslashes) in the line protocol.
+#[cfg(feature = "sink-influxdb")]
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The API of the server.
+#[cfg(feature = "sink-influxdb")]
+#[derive(Debug, Clone, PartialEq)]
+enum Api {
+    /// InfluxDB 1.x: a database, with basic auth
+    V1,
+    /// InfluxDB 2.x: a bucket of an organization, with an API token
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+#[cfg(feature = "sink-influxdb")]
+impl Api {
+    fn new(config: &config::InfluxDb) -> Result<Self> {
+        match (&config.org, &config.bucket, &config.token) {
+            (None, None, None) => {
+                if config.database.is_empty() {
+                    bail!("InfluxDB: Either the database or the org, bucket and token must be set");
+                }
+                Ok(Api::V1)
+            }
+            (Some(org), Some(bucket), Some(token)) => {
+                if !config.database.is_empty() || !config.user.is_empty() {
+                    bail!("InfluxDB: The database and user cannot be combined with a bucket");
+                }
+                Ok(Api::V2 {
+                    org: org.clone(),
+                    bucket: bucket.clone(),
+                    token: token.clone(),
+                })
+            }
+            _ => bail!("InfluxDB: The org, bucket and token must be set together"),
+        }
+    }
+}
+
+/// Sends points to an InfluxDB server.
+#[cfg(feature = "sink-influxdb")]
+pub struct InfluxDbSink {
+    client: http::Client,
+    config: config::InfluxDb,
+    api: Api,
+    schema: Schema,
+}
+
+#[cfg(feature = "sink-influxdb")]
+impl InfluxDbSink {
+    /// Create a new sink. The addresses must be in the same order as the
+    /// devices in the config.
+    pub fn new(
+        config: &config::InfluxDb,
+        client: http::Client,
+        devices: &[config::Device],
+        addresses: &[Address],
+    ) -> Result<Self> {
+        http::Client::check_url(&config.connection_string)?;
+        Ok(Self {
+            client,
+            config: config.clone(),
+            api: Api::new(config)?,
+            schema: Schema::new(&config.schema, devices, addresses),
+        })
+    }
+
+    /// Render the points according to the schema and write them (in the line
+    /// protocol format).
+    pub async fn submit(&mut self, points: &[Point]) -> Result<()> {
+        let payload = self.schema.render(points).join("\n");
+        self.write(payload).await
+    }
+
+    /// Check that the server is reachable and that the database accepts
+    /// writes (with an empty write), or that the bucket exists.
+    pub async fn check(&mut self) -> Result<()> {
+        let url = format!("{}/ping", self.config.connection_string);
+        let resp = self.client.get(&url, &[]).await?;
+        if resp.status != 204 {
+            bail!("Unexpected response to ping: {}", resp.status_line());
+        }
+        match self.api {
+            Api::V1 => self.write(String::new()).await,
+            Api::V2 { .. } => self.check_bucket().await,
+        }
+    }
+
+    fn auth(&self) -> String {
+        match self.api {
+            Api::V1 => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", &self.config.user, &self.config.password))
+            ),
+            Api::V2 { ref token, .. } => format!("Token {}", token),
+        }
+    }
+
+    /// Create the database (a no-op if it exists).
+    async fn create_database(&self) -> Result<()> {
+        let auth = self.auth();
+        let url = format!("{}/query", self.config.connection_string);
+        let query = format!(
+            "CREATE DATABASE \"{}\"",
+            self.config.database.replace('"', "\\\"")
+        );
+        let resp = self
+            .client
+            .post(
+                &url,
+                &[
+                    ("authorization", &auth),
+                    ("content-type", "application/x-www-form-urlencoded"),
+                ],
+                format!("q={}", url_encode(&query)).into_bytes(),
+            )
+            .await?;
+        // Errors of the statement are returned in the body
+        if resp.status != 200 || resp.body.contains("\"error\"") {
+            log::debug!("Could not create InfluxDB database: {}", resp.body.trim());
+            bail!(
+                "Could not create InfluxDB database {}: {}",
+                self.config.database,
+                resp.status_line()
             );
-            bail!("Could not send data to InfluxDB: {}", status)
         }
-        _ => {
-            let status = format!("{} ({})", resp.status(), resp.status_text());
-            bail!("Invalid status code: {}", status);
+        status!("Created InfluxDB database {}", self.config.database);
+        Ok(())
+    }
+
+    /// Check that the bucket exists (and create it with `create_database`).
+    async fn check_bucket(&self) -> Result<()> {
+        let (org, bucket) = match self.api {
+            Api::V2 {
+                ref org,
+                ref bucket,
+                ..
+            } => (org, bucket),
+            Api::V1 => return Ok(()),
+        };
+        let auth = self.auth();
+        let url = format!(
+            "{}/api/v2/buckets?org={}&name={}",
+            self.config.connection_string,
+            url_encode(org),
+            url_encode(bucket)
+        );
+        let resp = self.client.get(&url, &[("authorization", &auth)]).await?;
+        let found = match resp.status {
+            200 => json::parse(&resp.body)
+                .ok()
+                .and_then(|value| {
+                    value
+                        .get("buckets")
+                        .and_then(|b| b.as_array())
+                        .map(|b| !b.is_empty())
+                })
+                .ok_or_else(|| anyhow!("Invalid response from InfluxDB: {}", resp.body.trim()))?,
+            // Not found (the bucket or organization)
+            404 => false,
+            _ => {
+                log::debug!("Could not list InfluxDB buckets: {}", resp.body.trim());
+                bail!("Could not list InfluxDB buckets: {}", resp.status_line())
+            }
+        };
+        if found {
+            Ok(())
+        } else if self.config.create_database {
+            self.create_bucket().await
+        } else {
+            bail!(
+                "InfluxDB bucket {} not found (create it with `influx bucket create --org {} --name {}`)",
+                bucket,
+                org,
+                bucket
+            )
+        }
+    }
+
+    /// Create the bucket (with infinite retention).
+    async fn create_bucket(&self) -> Result<()> {
+        let (org, bucket) = match self.api {
+            Api::V2 {
+                ref org,
+                ref bucket,
+                ..
+            } => (org, bucket),
+            Api::V1 => return Ok(()),
+        };
+        let auth = self.auth();
+
+        // The bucket is created with the ID of the organization
+        let url = format!(
+            "{}/api/v2/orgs?org={}",
+            self.config.connection_string,
+            url_encode(org)
+        );
+        let resp = self.client.get(&url, &[("authorization", &auth)]).await?;
+        if resp.status != 200 {
+            bail!(
+                "InfluxDB organization {} not found: {}",
+                org,
+                resp.status_line()
+            );
+        }
+        let org_id = json::parse(&resp.body)
+            .ok()
+            .and_then(|value| {
+                let orgs = value.get("orgs")?.as_array()?;
+                Some(orgs.first()?.get("id")?.as_str()?.to_string())
+            })
+            .ok_or_else(|| anyhow!("InfluxDB organization {} not found", org))?;
+
+        let url = format!("{}/api/v2/buckets", self.config.connection_string);
+        let body = format!(
+            "{{\"orgID\":{},\"name\":{},\"retentionRules\":[]}}",
+            json::string(&org_id),
+            json::string(bucket)
+        );
+        let resp = self
+            .client
+            .post(
+                &url,
+                &[
+                    ("authorization", &auth),
+                    ("content-type", "application/json"),
+                ],
+                body.into_bytes(),
+            )
+            .await?;
+        // Conflict: The bucket was created in the meantime
+        if resp.status != 201 && resp.status != 422 {
+            log::debug!("Could not create InfluxDB bucket: {}", resp.body.trim());
+            bail!(
+                "Could not create InfluxDB bucket {}: {}",
+                bucket,
+                resp.status_line()
+            );
+        }
+        status!("Created InfluxDB bucket {}", bucket);
+        Ok(())
+    }
+
+    async fn write(&mut self, payload: String) -> Result<()> {
+        let auth = self.auth();
+
+        // Send request to server
+        let url = match self.api {
+            Api::V1 => format!(
+                "{}/write?db={}&precision=ms",
+                self.config.connection_string,
+                url_encode(&self.config.database)
+            ),
+            Api::V2 {
+                ref org,
+                ref bucket,
+                ..
+            } => format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=ms",
+                self.config.connection_string,
+                url_encode(org),
+                url_encode(bucket)
+            ),
+        };
+        let mut headers = vec![("authorization", auth.as_str())];
+        let body = if self.config.gzip {
+            headers.push(("content-encoding", "gzip"));
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(payload.as_bytes())?;
+            encoder.finish()?
+        } else {
+            payload.into_bytes()
+        };
+        let mut resp = self.client.post(&url, &headers, body.clone()).await?;
+        if resp.status == 404 && self.config.create_database {
+            match self.api {
+                Api::V1 => self.create_database().await?,
+                Api::V2 { .. } => self.create_bucket().await?,
+            }
+            resp = self.client.post(&url, &headers, body).await?;
+        }
+
+        // Handle response
+        match (resp.status, &self.api) {
+            // No content
+            (204, _) => {}
+            // Not found
+            (404, Api::V1) => {
+                log::warn!("InfluxDB database {} not found", self.config.database);
+                bail!(
+                    "InfluxDB database {} not found (create it with `CREATE DATABASE {}`)",
+                    self.config.database,
+                    self.config.database
+                );
+            }
+            (404, Api::V2 { bucket, .. }) => {
+                log::warn!("InfluxDB bucket {} not found", bucket);
+                bail!("InfluxDB bucket {} not found", bucket);
+            }
+            // Bad request, permission denied
+            (400, _) | (401, _) => {
+                log::debug!(
+                    "Could not send data to InfluxDB: Bad request: {}",
+                    resp.body.trim()
+                );
+                bail!("Could not send data to InfluxDB: {}", resp.status_line())
+            }
+            _ => bail!("Invalid status code: {}", resp.status_line()),
+        }
+        Ok(())
+    }
+}
+
+/// Percent-encode a query parameter.
+#[cfg(feature = "sink-influxdb")]
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "sink-influxdb"))]
+mod tests {
+    use super::*;
+
+    use crate::config::TemperatureUnit;
+    use crate::measurement::{MeasurementBuilder, Telemetry, Temperature, Timing};
+    use crate::retry::Timeouts;
+
+    fn measurement() -> Measurement {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .timestamp(UNIX_EPOCH + Duration::from_millis(1_607_500_000_123))
+            .local_name("Sensilo")
+            .counter(42)
+            .temperature(Temperature::from_millidegrees_celsius(21500));
+        builder.build().unwrap()
+    }
+
+    fn schema(config: config::Schema) -> Schema {
+        let device = config::Device {
+            name: "Sensilo 1".into(),
+            hex_addr: "010203040506".into(),
+            location: Some("Living room".into()),
+            site: None,
+            interval_s: None,
+            protocol: config::Protocol::Sensilo,
+            analog: vec![],
+            expects: vec![],
+            key: None,
+            crc: false,
+            irk: None,
+            min_rssi: None,
+        };
+        Schema::new(&config, &[device], &[Address([1, 2, 3, 4, 5, 6])])
+    }
+
+    #[test]
+    fn render_default() {
+        let schema = schema(config::Schema::default());
+        let lines = schema.render(&measurement_points(
+            &measurement(),
+            &config::Units::default(),
+        ));
+        assert_eq!(
+            lines,
+            vec![
+                "rssi,address=123456,local_name=Sensilo value=200 1607500000123",
+                "counter,address=123456,local_name=Sensilo value=42 1607500000123",
+                "temperature,address=123456,local_name=Sensilo value=21500 1607500000123",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_external_temperatures() {
+        let schema = schema(config::Schema::default());
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .timestamp(UNIX_EPOCH)
+            .local_name("Sensilo")
+            .counter(42)
+            .external_temperature(0, Temperature::from_millidegrees_celsius(4500))
+            .external_temperature(1, Temperature::from_millidegrees_celsius(18000));
+        let lines = schema.render(&measurement_points(
+            &builder.build().unwrap(),
+            &config::Units::default(),
+        ));
+        assert_eq!(
+            &lines[2..],
+            &[
+                "external_temperature,address=123456,local_name=Sensilo,probe=0 value=4500 0",
+                "external_temperature,address=123456,local_name=Sensilo,probe=1 value=18000 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_unknown() {
+        let schema = schema(config::Schema::default());
+        let mut mmt = measurement();
+        mmt.unknown_entries.insert(0x07, vec![0x12, 0xab]);
+        assert_eq!(
+            schema.render(&unknown_points(&mmt)),
+            vec!["raw_0x07,address=123456,local_name=Sensilo value=\"12ab\" 1607500000123"]
+        );
+    }
+
+    #[test]
+    fn render_approx_time() {
+        let schema = schema(config::Schema::default());
+        let mut points = unknown_points(&{
+            let mut mmt = measurement();
+            mmt.unknown_entries.insert(0x07, vec![0x12]);
+            mmt
+        });
+        tag_approx_time(&mut points);
+        assert_eq!(
+            schema.render(&points),
+            vec!["raw_0x07,address=123456,local_name=Sensilo,approx_time=true value=\"12\" 1607500000123"]
+        );
+    }
+
+    #[test]
+    fn render_telemetry() {
+        let schema = schema(config::Schema::default());
+        let mut mmt = measurement();
+        mmt.telemetry = Some(Telemetry {
+            tx_beacons: 1000,
+            i2c_errors: 2,
+            sensor_retries: 1,
+        });
+        let lines = schema.render(&measurement_points(&mmt, &config::Units::default()));
+        assert_eq!(
+            lines[2],
+            "telemetry,address=123456,local_name=Sensilo \
+             tx_beacons=1000,i2c_errors=2,sensor_retries=1 1607500000123"
+        );
+
+        mmt.timing = Some(Timing {
+            duration_us: 12_500,
+            start_latency_us: 30,
+        });
+        let lines = schema.render(&measurement_points(&mmt, &config::Units::default()));
+        assert_eq!(
+            lines[3],
+            "timing,address=123456,local_name=Sensilo \
+             measurement_duration_us=12500,start_latency_us=30 1607500000123"
+        );
+    }
+
+    #[test]
+    fn units() {
+        let schema = schema(config::Schema::default());
+        let units = config::Units {
+            temperature: TemperatureUnit::Fahrenheit,
+            milli: false,
+        };
+        let lines = schema.render(&measurement_points(&measurement(), &units));
+        assert_eq!(
+            lines[2],
+            "temperature,address=123456,local_name=Sensilo value=70.700 1607500000123"
+        );
+    }
+
+    #[test]
+    fn render_raw_values() {
+        let schema = schema(config::Schema::default());
+        let mut mmt = measurement();
+        let mut raw = mmt.clone();
+        raw.temperature = Some(Temperature::from_millidegrees_celsius(23000));
+        mmt.raw = Some(Box::new(raw));
+        let lines = schema.render(&measurement_points(&mmt, &config::Units::default()));
+        assert_eq!(
+            lines[2],
+            "temperature,address=123456,local_name=Sensilo value=21500,raw=23000 1607500000123"
+        );
+    }
+
+    #[test]
+    fn render_single_measurement() {
+        let mut config = config::Schema {
+            measurement: "environment".into(),
+            field: "{metric}".into(),
+            ..Default::default()
+        };
+        config.tags.remove("local_name");
+        config.tags.insert("room".into(), "{location}".into());
+        let schema = schema(config);
+        let mmt = measurement();
+        let mut points = measurement_points(&mmt, &config::Units::default());
+        points.extend(reboot_points(&mmt, 1000, Duration::from_secs(0)));
+        let lines = schema.render(&points);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with(
+            "environment,address=123456,room=Living\\ room \
+             rssi=200,counter=42,temperature=21500,reboot_previous_counter=1000,"
+        ));
+    }
+
+    #[test]
+    fn render_timestamps() {
+        // Points of measurements received at different times are not combined
+        let config = config::Schema {
+            measurement: "environment".into(),
+            field: "{metric}".into(),
+            ..Default::default()
+        };
+        let schema = schema(config);
+        let first = measurement();
+        let mut second = measurement();
+        second.timestamp += Duration::from_secs(1);
+        let points = vec![
+            Point::new("counter", &first, 42),
+            Point::new("counter", &second, 43),
+        ];
+        let lines = schema.render(&points);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" counter=42 1607500000123"));
+        assert!(lines[1].ends_with(" counter=43 1607500001123"));
+    }
+
+    #[test]
+    fn render_interleaved() {
+        // Lines keep the order of their first point
+        let config = config::Schema {
+            measurement: "environment".into(),
+            field: "{metric}".into(),
+            ..Default::default()
+        };
+        let schema = schema(config);
+        let first = measurement();
+        let mut second = measurement();
+        second.timestamp += Duration::from_secs(1);
+        let points = vec![
+            Point::new("counter", &first, 42),
+            Point::new("counter", &second, 43),
+            Point::new("rssi", &first, 200),
+            Point::new("rssi", &second, 201),
+        ];
+        let lines = schema.render(&points);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" counter=42,rssi=200 1607500000123"));
+        assert!(lines[1].ends_with(" counter=43,rssi=201 1607500001123"));
+    }
+
+    #[test]
+    fn escape_special() {
+        assert_eq!(escape("a b,c=d", &[',', '=', ' ']), "a\\ b\\,c\\=d");
+        assert_eq!(escape("C:\\temp\\", &[',', ' ']), "C:\\\\temp\\\\");
+        // A trailing backslash doesn't escape the separator
+        let schema = schema(config::Schema::default());
+        let mut mmt = measurement();
+        mmt.local_name = "Sensilo\\".into();
+        let lines = schema.render(&[Point::new("counter", &mmt, 42)]);
+        assert_eq!(
+            lines,
+            vec!["counter,address=123456,local_name=Sensilo\\\\ value=42 1607500000123"]
+        );
+    }
+
+    /// Serve the responses (in order) on a local port. Return the port and
+    /// the request lines.
+    fn serve(responses: Vec<&'static str>) -> (u16, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            let mut responses = responses.into_iter();
+            loop {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                // Requests on the same connection
+                loop {
+                    let mut request = String::new();
+                    if reader.read_line(&mut request).unwrap() == 0 {
+                        break;
+                    }
+                    let mut len = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                        let line = line.to_lowercase();
+                        if let Some(value) = line.strip_prefix("content-length:") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+                    reader.read_exact(&mut vec![0; len]).unwrap();
+                    requests.push(request.trim_end().to_string());
+                    let response = responses.next().unwrap();
+                    reader.get_mut().write_all(response.as_bytes()).unwrap();
+                    if responses.len() == 0 {
+                        return requests;
+                    }
+                }
+            }
+        });
+        (port, server)
+    }
+
+    fn config(port: u16, create_database: bool) -> config::InfluxDb {
+        config::InfluxDb {
+            connection_string: format!("http://127.0.0.1:{}", port),
+            user: "influxuser".into(),
+            password: "influxpass".into(),
+            database: "sensilo".into(),
+            org: None,
+            bucket: None,
+            token: None,
+            min_interval_s: None,
+            schema: Default::default(),
+            tag_approx_time: false,
+            create_database,
+            gzip: false,
+            connection: Default::default(),
+        }
+    }
+
+    fn config_v2(port: u16, create_database: bool) -> config::InfluxDb {
+        config::InfluxDb {
+            user: String::new(),
+            password: String::new(),
+            database: String::new(),
+            org: Some("home".into()),
+            bucket: Some("sensilo".into()),
+            token: Some("secret".into()),
+            ..config(port, create_database)
         }
     }
-    Ok(())
+
+    fn sink(config: config::InfluxDb) -> InfluxDbSink {
+        let http = config::Http {
+            no_proxy: Some(vec!["127.0.0.1".into()]),
+            ..Default::default()
+        };
+        let timeouts = Timeouts::new(&config.connection, Duration::from_secs(5));
+        let client = http::Client::new(&http, timeouts).unwrap();
+        InfluxDbSink::new(&config, client, &[], &[]).unwrap()
+    }
+
+    #[test]
+    fn check() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let err = smol::block_on(sink(config(port, false)).check()).unwrap_err();
+        assert!(err.to_string().contains("CREATE DATABASE sensilo"));
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                "GET /ping HTTP/1.1",
+                "POST /write?db=sensilo&precision=ms HTTP/1.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn create_database() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 32\r\n\r\n{\"results\":[{\"statement_id\":0}]}",
+            "HTTP/1.1 204 No Content\r\n\r\n",
+        ]);
+        smol::block_on(sink(config(port, true)).check()).unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[2], "POST /query HTTP/1.1");
+        assert_eq!(requests[3], "POST /write?db=sensilo&precision=ms HTTP/1.1");
+    }
+
+    #[test]
+    fn api() {
+        assert_eq!(Api::new(&config(0, false)).unwrap(), Api::V1);
+        assert_eq!(
+            Api::new(&config_v2(0, false)).unwrap(),
+            Api::V2 {
+                org: "home".into(),
+                bucket: "sensilo".into(),
+                token: "secret".into(),
+            }
+        );
+        let missing_token = config::InfluxDb {
+            token: None,
+            ..config_v2(0, false)
+        };
+        assert!(Api::new(&missing_token).is_err());
+        let both = config::InfluxDb {
+            database: "sensilo".into(),
+            ..config_v2(0, false)
+        };
+        assert!(Api::new(&both).is_err());
+        let neither = config::InfluxDb {
+            database: String::new(),
+            ..config(0, false)
+        };
+        assert!(Api::new(&neither).is_err());
+    }
+
+    #[test]
+    fn check_bucket() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 14\r\n\r\n{\"buckets\":[]}",
+        ]);
+        let err = smol::block_on(sink(config_v2(port, false)).check()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("InfluxDB bucket sensilo not found"));
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                "GET /ping HTTP/1.1",
+                "GET /api/v2/buckets?org=home&name=sensilo HTTP/1.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn create_bucket() {
+        let (port, server) = serve(vec![
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 36\r\n\r\n{\"orgs\":[{\"id\":\"0123456789abcdef\"}]}",
+            "HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\n{}",
+        ]);
+        smol::block_on(sink(config_v2(port, true)).check()).unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[2], "GET /api/v2/orgs?org=home HTTP/1.1");
+        assert_eq!(requests[3], "POST /api/v2/buckets HTTP/1.1");
+    }
+
+    #[test]
+    fn write_v2() {
+        let (port, server) = serve(vec!["HTTP/1.1 204 No Content\r\n\r\n"]);
+        let mut sink = sink(config_v2(port, false));
+        assert_eq!(sink.auth(), "Token secret");
+        let points = measurement_points(&measurement(), &config::Units::default());
+        smol::block_on(sink.submit(&points)).unwrap();
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests,
+            vec!["POST /api/v2/write?org=home&bucket=sensilo&precision=ms HTTP/1.1"]
+        );
+    }
+
+    #[test]
+    fn encode_query() {
+        assert_eq!(
+            url_encode("CREATE DATABASE \"a b\""),
+            "CREATE%20DATABASE%20%22a%20b%22"
+        );
+        assert_eq!(url_encode("sensilo_1-2"), "sensilo_1-2");
+    }
 }
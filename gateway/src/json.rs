@@ -0,0 +1,264 @@
+//! Minimal JSON serialization of measurements. The state snapshots are
+//! parsed with serde_json.
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Units;
+use crate::measurement::Measurement;
+use crate::units;
+
+pub use serde_json::Value;
+
+/// Parse a JSON document.
+pub fn parse(input: &str) -> serde_json::Result<Value> {
+    serde_json::from_str(input)
+}
+
+/// Encode a string as JSON string literal.
+pub fn string(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len() + 2);
+    encoded.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => encoded.push_str("\\\""),
+            '\\' => encoded.push_str("\\\\"),
+            '\n' => encoded.push_str("\\n"),
+            '\r' => encoded.push_str("\\r"),
+            '\t' => encoded.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(encoded, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => encoded.push(c),
+        }
+    }
+    encoded.push('"');
+    encoded
+}
+
+/// Encode the analog inputs of a measurement as JSON object, with the metric
+/// names as keys and the voltages (in mV) as values.
+pub fn analog(mmt: &Measurement) -> Option<String> {
+    if mmt.analog.is_empty() {
+        return None;
+    }
+    let inputs: Vec<String> = mmt
+        .analog
+        .iter()
+        .map(|(channel, input)| format!("{}:{}", string(&input.metric(*channel)), input.millivolts))
+        .collect();
+    Some(format!("{{{}}}", inputs.join(",")))
+}
+
+/// Encode a measurement as JSON object (on a single line), with the
+/// temperature and humidity converted to the configured units.
+pub fn measurement(mmt: &Measurement, units: &Units) -> String {
+    let mut fields = vec![
+        ("timestamp", millis(mmt.timestamp)),
+        ("address", string(&mmt.address.to_string())),
+        ("local_name", string(&mmt.local_name)),
+        ("counter", mmt.counter.to_string()),
+        ("rssi", mmt.rssi.to_string()),
+    ];
+    if let Some(ref temp) = mmt.temperature {
+        fields.push(("temperature", units::temperature(temp, units)));
+    }
+    if let Some(ref humi) = mmt.humidity {
+        fields.push(("humidity", units::humidity(humi, units)));
+    }
+    if let Some(ref lux) = mmt.ambient_light {
+        // NaN and infinity are not valid JSON numbers
+        if lux.as_lux().is_finite() {
+            fields.push(("ambient_light", format!("{:.2}", lux.as_lux())));
+        }
+    }
+    if let Some(ref als) = mmt.ambient_light_als {
+        fields.push(("ambient_light_als", als.as_counts().to_string()));
+    }
+    if let Some(ref white) = mmt.ambient_light_white {
+        fields.push(("ambient_light_white", white.as_counts().to_string()));
+    }
+    if let Some(ref temp) = mmt.thermocouple_temperature {
+        fields.push(("thermocouple_temperature", units::temperature(temp, units)));
+    }
+    if let Some(ref temp) = mmt.secondary_temperature {
+        fields.push(("secondary_temperature", units::temperature(temp, units)));
+    }
+    if let Some(ref humi) = mmt.secondary_humidity {
+        fields.push(("secondary_humidity", units::humidity(humi, units)));
+    }
+    if let Some(ref pulses) = mmt.pulses {
+        fields.push(("pulse_count", pulses.count.to_string()));
+        fields.push(("pulse_delta", pulses.delta.to_string()));
+    }
+    if let Some(rate) = mmt.pulse_rate {
+        fields.push(("pulse_rate", format!("{:.3}", rate)));
+    }
+    if let Some(ref contact) = mmt.contact {
+        fields.push(("contact_open", contact.open.to_string()));
+        fields.push(("contact_events", contact.events.to_string()));
+        if mmt.contact_changed {
+            fields.push(("contact_changed", "true".into()));
+        }
+    }
+    if mmt.backfilled {
+        fields.push(("backfilled", "true".into()));
+    }
+    if let Some(status) = mmt.status {
+        fields.push(("buzzer", status.has_buzzer().to_string()));
+        if status.heated() {
+            fields.push(("heated", "true".into()));
+        }
+        if status.sensors_disagree() {
+            fields.push(("sensors_disagree", "true".into()));
+        }
+    }
+    if let Some(telemetry) = mmt.telemetry {
+        fields.push(("tx_beacons", telemetry.tx_beacons.to_string()));
+        fields.push(("i2c_errors", telemetry.i2c_errors.to_string()));
+        fields.push(("sensor_retries", telemetry.sensor_retries.to_string()));
+    }
+    if let Some(timing) = mmt.timing {
+        fields.push(("measurement_duration_us", timing.duration_us.to_string()));
+        fields.push(("start_latency_us", timing.start_latency_us.to_string()));
+    }
+    if let Some(battery) = mmt.battery {
+        fields.push(("battery_voltage", battery.millivolts.to_string()));
+    }
+    if let Some(advertisements) = mmt.advertisements {
+        fields.push(("advertisements", advertisements.to_string()));
+    }
+    if let Some(analog) = analog(mmt) {
+        fields.push(("analog", analog));
+    }
+    if !mmt.external_temperatures.is_empty() {
+        let probes: Vec<String> = mmt
+            .external_temperatures
+            .iter()
+            .map(|(index, temp)| {
+                format!(
+                    "{}:{}",
+                    string(&index.to_string()),
+                    units::temperature(temp, units)
+                )
+            })
+            .collect();
+        fields.push(("external_temperatures", format!("{{{}}}", probes.join(","))));
+    }
+    if !mmt.unknown_entries.is_empty() {
+        let entries: Vec<String> = mmt
+            .unknown_entries
+            .iter()
+            .map(|(payload_type, value)| {
+                format!(
+                    "{}:{}",
+                    string(&format!("raw_0x{:02x}", payload_type)),
+                    string(&base16::encode_lower(value))
+                )
+            })
+            .collect();
+        fields.push(("unknown", format!("{{{}}}", entries.join(","))));
+    }
+    object(fields)
+}
+
+/// Encode a reboot of a device (detected from the counter of the measurement)
+/// as JSON event object, with the counter and the time of the last
+/// measurement before the reboot.
+#[cfg_attr(not(feature = "sink-mqtt"), allow(dead_code))]
+pub fn reboot(mmt: &Measurement, previous_counter: u16, elapsed: Duration) -> String {
+    let previous_timestamp = mmt.timestamp.checked_sub(elapsed).unwrap_or(UNIX_EPOCH);
+    object(vec![
+        ("event", string("reboot")),
+        ("timestamp", millis(mmt.timestamp)),
+        ("address", string(&mmt.address.to_string())),
+        ("local_name", string(&mmt.local_name)),
+        ("counter", mmt.counter.to_string()),
+        ("previous_counter", previous_counter.to_string()),
+        ("previous_timestamp", millis(previous_timestamp)),
+    ])
+}
+
+/// Milliseconds since the Unix epoch.
+fn millis(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .to_string()
+}
+
+fn object(fields: Vec<(&str, String)>) -> String {
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|(key, value)| format!("{}:{}", string(key), value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::{MeasurementBuilder, Temperature};
+    use crate::types::Address;
+
+    #[test]
+    fn encode_string() {
+        assert_eq!(string("Sensilo"), "\"Sensilo\"");
+        assert_eq!(string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+
+    #[test]
+    fn encode_measurement() {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .local_name("Sensilo")
+            .counter(42)
+            .temperature(Temperature::from_millidegrees_celsius(21500));
+        let json = measurement(&builder.build().unwrap(), &Units::default());
+        assert!(json.starts_with("{\"timestamp\":"));
+        assert!(json.ends_with(
+            ",\"address\":\"123456\",\"local_name\":\"Sensilo\",\
+             \"counter\":42,\"rssi\":200,\"temperature\":21500}"
+        ));
+    }
+
+    #[test]
+    fn encode_reboot() {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .local_name("Sensilo")
+            .counter(3)
+            .timestamp(UNIX_EPOCH + Duration::from_secs(100));
+        let json = reboot(&builder.build().unwrap(), 1000, Duration::from_secs(30));
+        assert_eq!(
+            json,
+            "{\"event\":\"reboot\",\"timestamp\":100000,\"address\":\"123456\",\
+             \"local_name\":\"Sensilo\",\"counter\":3,\"previous_counter\":1000,\
+             \"previous_timestamp\":70000}"
+        );
+    }
+
+    #[test]
+    fn encode_analog() {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .local_name("Sensilo")
+            .counter(42)
+            .analog(4, 1234)
+            .analog(5, 0);
+        let mut mmt = builder.build().unwrap();
+        assert_eq!(analog(&mmt).unwrap(), "{\"analog_4\":1234,\"analog_5\":0}");
+        mmt.analog.get_mut(&4).unwrap().name = Some("soil_moisture".into());
+        assert_eq!(
+            analog(&mmt).unwrap(),
+            "{\"soil_moisture\":1234,\"analog_5\":0}"
+        );
+    }
+
+    #[test]
+    fn parse_encoded() {
+        // Strings written by the encoder can be parsed
+        let encoded = string("a\"b\\c\nd\u{1}");
+        assert_eq!(parse(&encoded).unwrap().as_str(), Some("a\"b\\c\nd\u{1}"));
+    }
+}
@@ -0,0 +1,13 @@
+//! Parsers of the Sensilo Gateway.
+//!
+//! The parsers process radio data from untrusted devices. They are part of a
+//! library (used by the gateway binary), so that they can be fuzzed (see the
+//! `fuzz` directory).
+pub mod advertising;
+pub mod hci;
+pub mod measurement;
+pub mod protocol;
+pub mod types;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,123 @@
+//! Log and status output.
+//!
+//! By default, the status messages (e.g. the loaded devices) are printed to
+//! stdout and the log records are written to stderr by env_logger (filtered
+//! with `RUST_LOG`).
+//!
+//! With the JSON format (e.g. for containers), both are written to stdout as
+//! one JSON object per line. The status messages become records with the
+//! level `INFO`, which is the default level of the gateway in this format.
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use env_logger::fmt::{Target, WriteStyle};
+use log::LevelFilter;
+
+use crate::json;
+
+/// Whether the JSON format is used (set once at startup).
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Print a status message (see the module documentation). Without arguments,
+/// an empty line is printed (which is omitted in the JSON format).
+macro_rules! status {
+    () => {
+        $crate::logging::status(module_path!(), None)
+    };
+    ($($arg:tt)*) => {
+        $crate::logging::status(module_path!(), Some(format_args!($($arg)*)))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Initialize the logger. Must be called once, before anything is logged.
+pub fn init(format: Format, color: bool) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if !color {
+        builder.write_style(WriteStyle::Never);
+    }
+    if format == Format::Json {
+        JSON.store(true, Ordering::Relaxed);
+        if std::env::var_os("RUST_LOG").is_none() {
+            builder.filter_module("sensilo_gateway", LevelFilter::Info);
+        }
+        builder.target(Target::Stdout).format(|buf, record| {
+            let line = json_record(
+                &buf.timestamp_millis().to_string(),
+                record.level(),
+                record.target(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// Whether the JSON format is used.
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Print a status message, use the `status!` macro instead.
+pub fn status(target: &str, message: Option<fmt::Arguments>) {
+    match (message, is_json()) {
+        (Some(message), true) => log::info!(target: target, "{}", message),
+        (None, true) => {}
+        (Some(message), false) => println!("{}", message),
+        (None, false) => println!(),
+    }
+}
+
+/// Encode a log record as JSON object.
+fn json_record(timestamp: &str, level: log::Level, target: &str, message: &str) -> String {
+    format!(
+        "{{\"timestamp\":{},\"level\":{},\"target\":{},\"message\":{}}}",
+        json::string(timestamp),
+        json::string(&level.to_string()),
+        json::string(target),
+        json::string(message)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!(Format::parse("text"), Some(Format::Text));
+        assert_eq!(Format::parse("json"), Some(Format::Json));
+        assert_eq!(Format::parse("JSON"), None);
+    }
+
+    #[test]
+    fn record() {
+        assert_eq!(
+            json_record(
+                "2021-03-04T05:06:07.890Z",
+                log::Level::Warn,
+                "sensilo_gateway::pipeline",
+                "Device \"Kitchen\" is\nmissing"
+            ),
+            "{\"timestamp\":\"2021-03-04T05:06:07.890Z\",\"level\":\"WARN\",\
+             \"target\":\"sensilo_gateway::pipeline\",\"message\":\"Device \\\"Kitchen\\\" is\\nmissing\"}"
+        );
+    }
+}
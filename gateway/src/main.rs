@@ -1,240 +1,865 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::StreamExt;
-use hci::protocol::{
-    BasicDataType_Data, HciEvent_Event, HciMessage, HciMessage_Message, LeMetaEvent_Event,
-};
-use lru::LruCache;
-use pcap_async::{Config, Handle, Packet, PacketStream};
 
+#[macro_use]
+mod logging;
+
+#[cfg(feature = "acme")]
+mod acme;
+mod aggregate;
+mod api;
+mod auth;
+#[cfg(feature = "sink-mqtt")]
+mod availability;
+mod backfill;
+mod bthome;
+mod capture;
+mod channels;
+#[cfg(feature = "sink-influxdb")]
+mod clock;
 mod config;
+mod contacts;
+mod crc;
+mod csv;
+mod daemon;
+mod decoder;
+mod dedup;
+mod delta;
+mod diagnostics;
+mod display;
+mod eddystone;
+mod exec;
+mod expectations;
+mod frames;
+mod gaps;
+mod graphite;
+mod hexdump;
+// Only compiled with a user of the client
+#[cfg(any(feature = "sink-influxdb", feature = "acme"))]
+mod http;
 mod influxdb;
-mod measurement;
-mod types;
+mod json;
+mod mdns;
+mod merge;
+#[cfg(feature = "sink-mqtt")]
+mod mqtt;
+mod pipeline;
+mod postgres;
+mod pulses;
+mod queue;
+mod ratelimit;
+mod retry;
+mod rpa;
+mod rssi;
+mod scanrsp;
+mod smooth;
+mod state;
+mod summary;
+mod template;
+mod timesync;
+mod units;
+#[cfg(feature = "api-tls")]
+mod x509;
+
+// The parsers are part of the library, so that they can be fuzzed
+use sensilo_gateway::{advertising, hci, measurement, protocol, types};
 
-use measurement::MeasurementBuilder;
+use advertising::AdStructure;
+use capture::HciPacket;
+use channels::ChannelStats;
+use decoder::Decoder;
+use dedup::Deduplicator;
+use diagnostics::{ErrorKind, ParseError, ParseErrors};
+use frames::{RawFrame, RawFrames};
+use measurement::{Measurement, MeasurementBuilder};
+use merge::FrameMerger;
+use pipeline::Pipeline;
+use rpa::Resolver;
+use rssi::RssiFilter;
+use scanrsp::{Received, ScanResponseMerger};
 use types::Address;
 
-// Store a LRU cache with the last `DEDUPLICATION_LRU_SIZE` counters for every address.
-// If a counter value is contained in the cache, ignore the message.
-const DEDUPLICATION_LRU_SIZE: usize = 5;
-type DeduplicationCache = HashMap<Address, LruCache<u16, ()>>;
+/// Interval of the main loop without packets, for the timeouts (frame
+/// merging, aggregation windows, availability).
+const TICK: Duration = Duration::from_secs(1);
+
+/// What the main loop wakes up for.
+enum Event {
+    /// Packets of the capture (`None` once it has ended)
+    Packets(Option<Vec<HciPacket>>),
+    /// A request of the API
+    Control(api::Control),
+    /// The interval `TICK` has elapsed
+    Tick,
+}
+
+/// Address resolution, RSSI filter, payload decoder, reception statistics and
+/// parse errors of every configured device, and the advertisements waiting
+/// for a scan response.
+struct Devices {
+    resolver: Resolver,
+    rssi: RssiFilter,
+    decoders: HashMap<Address, Box<dyn Decoder>>,
+    /// Whether the payloads with unknown entry types are rejected
+    strict: bool,
+    /// Whether the entries with unknown types are forwarded to the sinks
+    forward_unknown: bool,
+    channels: ChannelStats,
+    errors: ParseErrors,
+    scan_responses: ScanResponseMerger,
+}
+
+impl Devices {
+    fn new(config: &config::Config, addresses: &[Address]) -> anyhow::Result<Self> {
+        Ok(Self {
+            resolver: Resolver::new(&config.devices, addresses)?,
+            rssi: RssiFilter::new(&config.devices, addresses, config.filter.min_rssi),
+            decoders: decoder::for_devices(&config.devices, addresses)?,
+            strict: config.payload.mode == config::ParseMode::Strict,
+            forward_unknown: config.payload.forward_unknown,
+            channels: ChannelStats::default(),
+            errors: ParseErrors::new(config.debug.parse_errors),
+            scan_responses: ScanResponseMerger::new(),
+        })
+    }
+
+    /// Number of failed payload verifications of a device (if its payloads
+    /// are authenticated).
+    fn verification_failures(&self, address: Address) -> Option<u64> {
+        self.decoders
+            .get(&address)
+            .and_then(|decoder| decoder.verification_failures())
+    }
+
+    /// Add the counters of the replay protection to a snapshot.
+    fn snapshot(&self, snapshot: &mut state::Snapshot) {
+        for (address, decoder) in &self.decoders {
+            if let Some(counter) = decoder.replay_counter() {
+                snapshot.devices.entry(*address).or_default().replay_counter = Some(counter);
+            }
+        }
+    }
+
+    /// Restore the counters of the replay protection from a snapshot.
+    fn restore(&mut self, snapshot: &state::Snapshot) {
+        for (address, state) in &snapshot.devices {
+            if let (Some(decoder), Some(counter)) =
+                (self.decoders.get_mut(address), state.replay_counter)
+            {
+                decoder.restore_replay_counter(counter);
+            }
+        }
+    }
+
+    /// Number of frames of a device below its RSSI threshold (if it has one).
+    fn rssi_filtered(&self, address: Address) -> Option<u64> {
+        self.rssi.filtered(address)
+    }
+}
 
 fn print_usage(args: &[String]) {
     println!("Sensilo Gateway\n");
-    println!("Usage: {} [-h|--help] [CONFIGFILE]", args[0]);
+    println!("Usage: {} [OPTIONS] [--daemonize] [CONFIGFILE]", args[0]);
+    println!("       {} [OPTIONS] --check [CONFIGFILE]", args[0]);
+    println!(
+        "       {} [OPTIONS] import CAPTUREFILE [CONFIGFILE]",
+        args[0]
+    );
+    println!(
+        "       {} [OPTIONS] state export SNAPSHOT [CONFIGFILE]",
+        args[0]
+    );
+    println!(
+        "       {} [OPTIONS] state import SNAPSHOT [CONFIGFILE]",
+        args[0]
+    );
+    println!("       {} protocol", args[0]);
+    println!();
+    println!("Options:");
+    println!("  -h, --help           Show this help");
+    println!("  --no-color           Disable colored log output (also with NO_COLOR set)");
+    println!("  --log-format=FORMAT  text (default) or json (also with SENSILO_LOG_FORMAT)");
+    println!();
+    println!("The config file defaults to $SENSILO_CONFIG, or config.toml in the current");
+    println!("directory. Config values can be set with SENSILO__SECTION__KEY variables.");
+}
+
+enum Command<'a> {
+    /// Capture live (the default)
+    Run { daemonize: bool },
+    /// Check the connections to the sinks
+    Check,
+    /// Import a capture file
+    Import(&'a Path),
+    /// Export the saved state to a snapshot
+    StateExport(&'a Path),
+    /// Import a snapshot into the state files
+    StateImport(&'a Path),
+    /// Print the description of the payload entries
+    Protocol,
+}
+
+/// Options that apply to every command.
+struct Options {
+    color: bool,
+    log_format: logging::Format,
+}
+
+/// Parse the options. The defaults are taken from the environment.
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        color: std::env::var_os("NO_COLOR").is_none(),
+        log_format: match std::env::var("SENSILO_LOG_FORMAT") {
+            Ok(format) => logging::Format::parse(&format)?,
+            Err(_) => logging::Format::Text,
+        },
+    };
+    for arg in &args[1..] {
+        if arg == "--no-color" {
+            options.color = false;
+        } else if let Some(format) = arg.strip_prefix("--log-format=") {
+            options.log_format = logging::Format::parse(format)?;
+        }
+    }
+    Some(options)
+}
+
+/// Parse the command and the config file (if specified).
+fn parse_args(args: &[String]) -> Option<(Command<'_>, Option<&str>)> {
+    let daemonize = args.iter().any(|arg| arg == "--daemonize");
+    let check = args.iter().any(|arg| arg == "--check");
+    let is_option = |arg: &str| {
+        ["--daemonize", "--check", "--no-color"].contains(&arg) || arg.starts_with("--log-format=")
+    };
+    if args[1..]
+        .iter()
+        .any(|arg| arg.starts_with('-') && !is_option(arg))
+    {
+        return None;
+    }
+    let positional: Vec<&str> = args[1..]
+        .iter()
+        .filter(|arg| !arg.starts_with('-'))
+        .map(|arg| arg.as_str())
+        .collect();
+    let (command, rest) = match positional.as_slice() {
+        ["import", file, rest @ ..] => (Command::Import(Path::new(*file)), rest),
+        ["state", "export", file, rest @ ..] => (Command::StateExport(Path::new(*file)), rest),
+        ["state", "import", file, rest @ ..] => (Command::StateImport(Path::new(*file)), rest),
+        ["protocol"] => (Command::Protocol, &[][..]),
+        ["import", ..] | ["state", ..] | ["protocol", ..] => return None,
+        rest if check => (Command::Check, rest),
+        rest => (Command::Run { daemonize }, rest),
+    };
+    if daemonize && !matches!(command, Command::Run { .. })
+        || check && !matches!(command, Command::Check)
+    {
+        return None;
+    }
+    match rest {
+        [] => Some((command, None)),
+        [configfile] => Some((command, Some(*configfile))),
+        _ => None,
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    let result = run();
+    if let (Err(e), true) = (&result, logging::is_json()) {
+        log::error!("{:#}", e);
+        std::process::exit(1);
+    }
+    result
+}
 
+fn run() -> anyhow::Result<()> {
     // Parse args
     let args: Vec<String> = std::env::args().collect();
     if args.iter().any(|arg| arg == "-h" || arg == "--help") {
         print_usage(&args);
         std::process::exit(0);
     }
-    if args.len() > 2 {
-        print_usage(&args);
-        std::process::exit(1);
+    let (options, (command, configfile)) = match (parse_options(&args), parse_args(&args)) {
+        (Some(options), Some(parsed)) => (options, parsed),
+        _ => {
+            print_usage(&args);
+            std::process::exit(1);
+        }
+    };
+
+    // Without the log header, to keep the output parseable
+    if let Command::Protocol = command {
+        println!("{}", protocol::to_json());
+        return Ok(());
     }
 
-    println!("Sensilo Gateway\n");
+    logging::init(options.log_format, options.color);
+
+    status!("Sensilo Gateway");
+    status!();
 
-    // Parse config
-    let configfile = args.iter().nth(1).map(|s| &**s).unwrap_or("config.toml");
-    println!("Loading config from {}...", configfile);
-    let config: config::Config = toml::from_str(&std::fs::read_to_string(configfile)?)?;
+    // Parse config. Without a config file, the config can be passed entirely
+    // through the environment.
+    let env_configfile = std::env::var("SENSILO_CONFIG").ok();
+    let required = configfile.is_some() || env_configfile.is_some();
+    let configfile = Path::new(
+        configfile
+            .or(env_configfile.as_deref())
+            .unwrap_or("config.toml"),
+    );
+    if required || configfile.exists() {
+        status!("Loading config from {}...", configfile.display());
+    } else {
+        status!("Loading config from the environment...");
+    }
+    let config = config::load(configfile, required)?;
     let addresses: Vec<Address> = config
         .devices
         .iter()
         .map(|dev| Address::from_hex(&dev.hex_addr))
         .collect();
 
-    println!("Available bluetooth capture interfaces:");
-    for iface in pcap_async::Info::all().expect("Could not get list of interfaces") {
-        if iface.name.contains("blue") || iface.name.contains("ble") {
-            println!("  - {}", iface.name);
-            for ip in iface.ips {
-                println!("    - {}", ip);
-            }
+    status!("Listening for beacons from the following devices:");
+    for dev in &config.devices {
+        let details: Vec<&str> = dev
+            .location
+            .iter()
+            .chain(&dev.site)
+            .map(String::as_str)
+            .collect();
+        if details.is_empty() {
+            status!("  - [{}] {}", dev.hex_addr, dev.name);
+        } else {
+            status!(
+                "  - [{}] {} ({})",
+                dev.hex_addr,
+                dev.name,
+                details.join(", ")
+            );
         }
     }
 
-    println!("Listening for beacons from the following devices:");
-    for dev in &config.devices {
-        if let Some(ref location) = dev.location {
-            println!("  - [{}] {} ({})", dev.hex_addr, dev.name, location);
-        } else {
-            println!("  - [{}] {}", dev.hex_addr, dev.name);
+    let daemonize = match command {
+        Command::Run { daemonize } => daemonize,
+        Command::Check => {
+            let mut pipeline = Pipeline::new(&config, &addresses)?;
+            status!();
+            status!("Checking the sinks...");
+            if !smol::block_on(pipeline.check()) {
+                anyhow::bail!("Not all sinks are reachable");
+            }
+            return Ok(());
+        }
+        Command::Import(path) => {
+            let mut pipeline = Pipeline::new(&config, &addresses)?;
+            pipeline.ignore_host_clock();
+            status!();
+            return smol::block_on(import(path, &config, &addresses, &mut pipeline));
+        }
+        Command::StateExport(path) => {
+            status!();
+            return state::export(&config, path);
         }
+        Command::StateImport(path) => {
+            status!();
+            return state::import(&config, path);
+        }
+        Command::Protocol => unreachable!(),
+    };
+    if daemonize {
+        status!("Detaching from the terminal...");
+        daemon::daemonize(&config.daemon)?;
     }
 
-    // Create HTTP client
-    let agent = influxdb::make_ureq_agent();
+    let mut pipeline = Pipeline::new(&config, &addresses)?;
 
-    println!();
-    smol::block_on(async {
-        println!("Opening device bluetooth0...");
-        let handle = Handle::live_capture("bluetooth0").expect("No handle created");
-        //let handle = Handle::file_capture("/tmp/ble.pcap").expect("No handle created");
-
-        let mut pcap_config = Config::default();
-        pcap_config.with_blocking(true);
-
-        let mut stream = PacketStream::new(pcap_config, std::sync::Arc::clone(&handle))
-            .expect("Failed to build");
-
-        let mut deduplication_cache: DeduplicationCache = HashMap::new();
-        while let Some(packets_result) = stream.next().await {
-            if let Ok(packets) = packets_result {
-                for packet in packets {
-                    log::trace!("{:?}", packet);
-                    // TODO: Non-await?
-                    let _ = process_packet(
-                        packet,
-                        &mut deduplication_cache,
-                        &config,
-                        &addresses,
-                        agent.clone(),
+    // Dump the retained raw frames on SIGUSR1, toggle the hex dump on
+    // SIGUSR2, reload the config on SIGHUP
+    let dump_requested = Arc::new(AtomicBool::new(false));
+    let hexdump_toggled = Arc::new(AtomicBool::new(false));
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        signal_hook::flag::register(signal_hook::SIGUSR1, Arc::clone(&dump_requested))?;
+        signal_hook::flag::register(signal_hook::SIGUSR2, Arc::clone(&hexdump_toggled))?;
+        signal_hook::flag::register(signal_hook::SIGHUP, Arc::clone(&reload_requested))?;
+    }
+    let mut hexdump = config.debug.hexdump;
+
+    status!();
+    let result = smol::block_on(async {
+        // The sinks retry every submission, so unreachable sinks are only
+        // reported
+        status!("Checking the sinks...");
+        if !pipeline.check().await {
+            status!("Warning: Not all sinks are reachable");
+        }
+        status!();
+
+        let mut stream = capture::open(&config.capture, &config.devices, &addresses)?;
+        if let Some(ref time_sync) = config.time_sync {
+            // Every HCI command needs CAP_NET_RAW
+            if config.daemon.user.is_some() {
+                anyhow::bail!("The time sync cannot be combined with a daemon user");
+            }
+            timesync::start(time_sync)?;
+        }
+        let mut control = None;
+        let mut api_server = None;
+        // The service is announced while the responder is kept
+        let mut _mdns = None;
+        if let Some(ref api) = config.api {
+            let (server, receiver) = api::Server::start(api, &config.devices, &addresses)?;
+            status!("Serving the API on {}", server.url());
+            if api.mdns {
+                _mdns = Some(mdns::start(api, server.addr())?);
+            }
+            pipeline.set_api(server.clone());
+            control = Some(receiver);
+            api_server = Some(server);
+        }
+
+        // Opening the capture device may require root privileges (or
+        // CAP_NET_RAW), the rest of the gateway does not
+        daemon::drop_privileges(&config.daemon)?;
+        // The ACME files are written by the daemon user
+        if let Some(ref server) = api_server {
+            server.start_acme(&config.http)?;
+        }
+
+        let window = Duration::from_secs(config.dedup.window_s);
+        let mut deduplicator = Deduplicator::new(window);
+        let mut devices = Devices::new(&config, &addresses)?;
+        let state_file = config.state.file.as_deref();
+        let snapshot = match state_file {
+            Some(path) => state::Snapshot::load(path)?,
+            None => state::Snapshot::default(),
+        };
+        let clock = state::Clock::now();
+        pipeline.restore(&snapshot, &clock);
+        deduplicator.restore(&snapshot, &clock);
+        devices.restore(&snapshot);
+        if let Some(path) = state_file {
+            status!(
+                "Restored the state of {} device(s) from {}",
+                snapshot.devices.len(),
+                path.display()
+            );
+        }
+        let mut throttle =
+            state::SaveThrottle::new(Duration::from_secs(config.state.save_interval_s));
+        let mut merger = FrameMerger::new();
+        let mut raw_frames = RawFrames::new(config.debug.raw_frames);
+        let mut reload = false;
+        let mut ticks = smol::Timer::interval(TICK);
+        loop {
+            let requests = async {
+                match control.as_ref().map(|control| control.recv()) {
+                    Some(request) => match request.await {
+                        Ok(request) => Event::Control(request),
+                        Err(_) => smol::future::pending().await,
+                    },
+                    None => smol::future::pending().await,
+                }
+            };
+            let event = smol::future::or(
+                async { Event::Packets(stream.next().await) },
+                smol::future::or(requests, async {
+                    ticks.next().await;
+                    Event::Tick
+                }),
+            )
+            .await;
+            let packets = match event {
+                Event::Packets(Some(packets)) => packets,
+                Event::Packets(None) => break,
+                Event::Control(api::Control::Dump(reply)) => {
+                    let dump = format!("{}{}", raw_frames.dump(), devices.errors.dump());
+                    let _ = reply.send(dump);
+                    vec![]
+                }
+                Event::Control(api::Control::Reload) => {
+                    reload_requested.store(true, Ordering::Relaxed);
+                    vec![]
+                }
+                Event::Tick => vec![],
+            };
+            let mut changed = false;
+            for packet in packets {
+                let now = Instant::now();
+                for measurement in process_packet(
+                    &packet,
+                    &mut deduplicator,
+                    &mut raw_frames,
+                    &mut devices,
+                    hexdump,
+                    now,
+                ) {
+                    changed = true;
+                    submit(
+                        measurement,
+                        &deduplicator,
+                        &devices,
+                        &mut merger,
+                        &mut pipeline,
+                        now,
                     )
                     .await;
                 }
-            } else {
-                println!("Error: {:?}", packets_result);
             }
+
+            // Frames, aggregation windows and devices that time out (also
+            // without packets, e.g. the last device going offline)
+            expire(&mut merger, &mut pipeline, Instant::now()).await;
+
+            // Signals
+            if dump_requested.swap(false, Ordering::Relaxed) {
+                print!("{}", raw_frames.dump());
+                print!("{}", devices.errors.dump());
+            }
+            if hexdump_toggled.swap(false, Ordering::Relaxed) {
+                hexdump = !hexdump;
+                status!("Hex dump {}", if hexdump { "enabled" } else { "disabled" });
+            }
+            if reload_requested.swap(false, Ordering::Relaxed)
+                && reloadable(&config, configfile, required)
+            {
+                status!("Reloading the config...");
+                reload = true;
+                break;
+            }
+
+            // Changes that were throttled are saved while idle as well
+            if changed {
+                throttle.changed();
+            }
+            if let (Some(path), true) = (state_file, throttle.due(Instant::now())) {
+                save_state(path, &pipeline, &deduplicator, &devices);
+            }
+        }
+        for measurement in merger.drain() {
+            pipeline
+                .handle_measurement(measurement, Instant::now())
+                .await;
         }
+        pipeline.drain(Instant::now()).await;
+        if let Some(path) = state_file {
+            save_state(path, &pipeline, &deduplicator, &devices);
+        }
+
+        Ok(reload)
+    });
+    daemon::remove_pid_file(&config.daemon);
+    if result? {
+        daemon::restart()?;
+    }
+    Ok(())
+}
 
-        Ok(())
-    })
+/// Whether the config can be reloaded: The gateway is restarted with the new
+/// config, which must be valid, and must be able to open the capture device
+/// again.
+fn reloadable(config: &config::Config, configfile: &Path, required: bool) -> bool {
+    if config.daemon.user.is_some() {
+        log::error!(
+            "Cannot reload the config after dropping the privileges, restart the gateway instead"
+        );
+        return false;
+    }
+    match config::load(configfile, required) {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("Not reloading, the config is invalid: {:#}", e);
+            false
+        }
+    }
+}
+
+/// Save a snapshot of the device state. Errors are logged, the gateway keeps
+/// running.
+fn save_state(path: &Path, pipeline: &Pipeline, deduplicator: &Deduplicator, devices: &Devices) {
+    let clock = state::Clock::now();
+    let mut snapshot = state::Snapshot::default();
+    pipeline.snapshot(&mut snapshot, &clock);
+    deduplicator.snapshot(&mut snapshot, &clock);
+    devices.snapshot(&mut snapshot);
+    if let Err(e) = snapshot.save(path) {
+        log::error!("{:#}", e);
+    }
 }
 
-async fn process_packet(
-    packet: Packet,
-    deduplication_cache: &mut DeduplicationCache,
+/// Decode a capture file and submit the measurements, as if they had been
+/// received live at the time of the packets.
+///
+/// The deduplication window, frame merging, aggregation and rate limiting
+/// run on the clock of the capture (the time since the first packet), not on
+/// the wall clock.
+async fn import(
+    path: &Path,
     config: &config::Config,
     addresses: &[Address],
-    agent: ureq::Agent,
-) -> Option<()> {
-    // Validate length
-    if packet.original_length() != packet.actual_length() {
-        log::debug!(
-            "Invalid packet length: {} != {}",
-            packet.original_length(),
-            packet.actual_length()
-        );
-        return None;
+    pipeline: &mut Pipeline<'_>,
+) -> anyhow::Result<()> {
+    status!("Importing capture file {}...", path.display());
+    let packets = capture::read_file(path)?;
+    let first = match packets.first() {
+        Some(packet) => packet.timestamp,
+        None => {
+            status!("No HCI packets found");
+            return Ok(());
+        }
+    };
+
+    let start = Instant::now();
+    let mut now = start;
+    let mut deduplicator = Deduplicator::new(Duration::from_secs(config.dedup.window_s));
+    let mut merger = FrameMerger::new();
+    let mut raw_frames = RawFrames::new(0);
+    let mut devices = Devices::new(config, addresses)?;
+    let mut frames = 0;
+    for packet in &packets {
+        // Packets of merged captures may be out of order, the clock must not
+        // go backwards
+        let elapsed = packet.timestamp.duration_since(first).unwrap_or_default();
+        now = now.max(start + elapsed);
+        for measurement in process_packet(
+            packet,
+            &mut deduplicator,
+            &mut raw_frames,
+            &mut devices,
+            config.debug.hexdump,
+            now,
+        ) {
+            frames += 1;
+            submit(
+                measurement,
+                &deduplicator,
+                &devices,
+                &mut merger,
+                pipeline,
+                now,
+            )
+            .await;
+        }
+        expire(&mut merger, pipeline, now).await;
+    }
+    for measurement in merger.drain() {
+        pipeline.handle_measurement(measurement, now).await;
     }
+    pipeline.drain(now).await;
 
-    // Try to parse HCI message
-    let payload = &packet.data()[4..];
-    let parsed = HciMessage::parse(payload)
-        .map_err(|e| {
-            log::debug!("Could not parse HCI message");
-            e
-        })
-        .ok()?;
+    status!(
+        "Imported {} frame(s) from {} HCI packet(s)",
+        frames,
+        packets.len()
+    );
+    Ok(())
+}
 
-    if !parsed.0.is_empty() {
-        log::debug!("Payload parsed incompletely");
-        return None;
+/// Update the reception statistics of the device in the pipeline, and submit
+/// the measurement (once its frames are merged).
+async fn submit(
+    measurement: Measurement,
+    deduplicator: &Deduplicator,
+    devices: &Devices,
+    merger: &mut FrameMerger,
+    pipeline: &mut Pipeline<'_>,
+    now: Instant,
+) {
+    let address = measurement.address;
+    pipeline.set_dedup_stats(address, deduplicator.stats(address));
+    if let Some(failures) = devices.verification_failures(address) {
+        pipeline.set_verification_failures(address, failures);
+    }
+    if let Some(filtered) = devices.rssi_filtered(address) {
+        pipeline.set_rssi_filtered(address, filtered);
+    }
+    if let Some(counts) = devices.channels.get(address) {
+        pipeline.set_channel_counts(address, counts);
     }
+    for measurement in merger.add(measurement, now) {
+        pipeline.handle_measurement(measurement, now).await;
+    }
+}
 
-    // Extract event
-    let event = if let HciMessage_Message::HciEvent(val) = parsed.1.get_message() {
-        val
-    } else {
-        log::trace!("Ignoring non-event message");
-        return None;
-    };
+/// Submit the measurements whose frames timed out, and the aggregation
+/// windows that have ended.
+async fn expire(merger: &mut FrameMerger, pipeline: &mut Pipeline<'_>, now: Instant) {
+    for measurement in merger.expire(now) {
+        pipeline.handle_measurement(measurement, now).await;
+    }
+    pipeline.expire(now).await;
+}
 
-    // We're only interested in LE meta events
-    let le_event = if let HciEvent_Event::LeMetaEvent(val) = event.get_event() {
-        val
-    } else {
-        log::trace!("Ignoring non-LeMetaEvent event");
-        return None;
-    };
+fn process_packet(
+    packet: &HciPacket,
+    deduplicator: &mut Deduplicator,
+    raw_frames: &mut RawFrames,
+    devices: &mut Devices,
+    hexdump: bool,
+    now: Instant,
+) -> Vec<Measurement> {
+    log::trace!(
+        "HCI packet at {:?} (channel {:?}): {:?}",
+        packet.timestamp,
+        packet.channel,
+        packet.data
+    );
 
-    // We're only interested in advertising reports
-    let adv_report = if let LeMetaEvent_Event::LeAdvertisingReport(val) = le_event.get_event() {
-        val
-    } else {
-        log::trace!("Ignoring non-LeAdvertisingReport");
+    // Scannable advertisements wait for their scan response
+    let mut received = devices.scan_responses.expire(now);
+
+    // We're only interested in advertising reports (sent as LE meta events)
+    match hci::parse_packet(&packet.data) {
+        Ok(Some(reports)) => {
+            for report in reports {
+                let report = Received {
+                    report,
+                    timestamp: packet.timestamp,
+                    channel: packet.channel,
+                };
+                received.extend(devices.scan_responses.add(report, now));
+            }
+        }
+        Ok(None) => log::trace!("Ignoring packet without advertising reports"),
+        Err(e) => log::debug!("Could not parse HCI packet: {}", e),
+    }
+
+    received
+        .iter()
+        .filter_map(|received| {
+            process_report(received, deduplicator, raw_frames, devices, hexdump, now)
+        })
+        .collect()
+}
+
+fn process_report(
+    received: &Received,
+    deduplicator: &mut Deduplicator,
+    raw_frames: &mut RawFrames,
+    devices: &mut Devices,
+    hexdump: bool,
+    now: Instant,
+) -> Option<Measurement> {
+    let report = &received.report;
+
+    // Filter by address (private addresses are resolved to the configured
+    // device address)
+    let address = devices.resolver.resolve(report.address);
+    let decoder = match devices.decoders.get_mut(&address) {
+        Some(decoder) => decoder,
+        None => {
+            log::trace!("Ignoring device with address {}", address);
+            return None;
+        }
+    };
+    if !devices.rssi.accept(address, report.rssi) {
+        log::debug!(
+            "Ignoring frame of {} with RSSI {} dBm",
+            address,
+            report.rssi as i8
+        );
         return None;
+    }
+
+    // Decode payload
+    log::trace!("Frame: {:?}", report);
+    let payload = decoder.payload(&report.data)?;
+    log::trace!("Payload: {:?}", payload);
+    raw_frames.push(
+        address,
+        RawFrame {
+            timestamp: received.timestamp,
+            rssi: report.rssi,
+            payload: payload.to_vec(),
+        },
+    );
+    let mut builder = MeasurementBuilder::new(address, report.rssi);
+    builder.timestamp(received.timestamp).strict(devices.strict);
+    if let Err(e) = decoder.decode(payload, &mut builder) {
+        log::warn!("Could not parse payload of {}: {}", address, e);
+        devices.errors.push(
+            address,
+            ParseError {
+                timestamp: received.timestamp,
+                kind: ErrorKind::of(e),
+                message: e.to_string(),
+                payload: payload.to_vec(),
+            },
+        );
+    }
+    for payload_type in builder.unknown_entries().keys() {
+        devices.errors.push(
+            address,
+            ParseError {
+                timestamp: received.timestamp,
+                kind: ErrorKind::UnknownType,
+                message: format!("Unknown payload type 0x{:02x}", payload_type),
+                payload: payload.to_vec(),
+            },
+        );
+    }
+    for datum in &report.data {
+        if let AdStructure::CompleteLocalName(name) = datum {
+            builder.local_name(name);
+        }
+    }
+    let mut measurement = match builder.build() {
+        Ok(measurement) => measurement,
+        Err(e) => {
+            log::debug!("Ignoring advertising report from {}: {}", address, e);
+            return None;
+        }
     };
 
-    // Filter by address
-    let address = Address::from_inverted_slice(&adv_report.get_address());
-    if !addresses.contains(&address) {
-        log::trace!("Ignoring device with address {}", address);
-        return None;
+    if !devices.forward_unknown {
+        measurement.unknown_entries.clear();
     }
 
-    // Get data
-    let mut builder = MeasurementBuilder::new(address, adv_report.get_rssi());
-    log::trace!("Frame: {:?}", adv_report);
-    for datum in adv_report.get_data() {
-        match datum.get_data() {
-            BasicDataType_Data::CompleteLocalName(name) => {
-                builder.local_name(name.get_local_name());
-            }
-            BasicDataType_Data::ManufacturerSpecificData(data) => {
-                if data.get_company_identifier_code() == 0xffff {
-                    let payload = data.get_data();
-                    log::trace!("Payload: {:?}", payload);
-                    if let Err(e) = builder.parse_payload(&payload) {
-                        log::warn!("Could not parse payload: {}", e);
-                    }
-                } else {
-                    // Not a Sensilo advertisement frame
-                }
-            }
-            other => {
-                log::debug!("Ignoring datum in advertising report: {:?}", other);
-            }
+    // Count the frame (including duplicates) per advertising channel
+    if let Some(channel) = received.channel {
+        devices.channels.record(address, channel);
+    }
+
+    // Warn about saturated light sensor channels
+    for (channel, counts) in &[
+        ("ALS", &measurement.ambient_light_als),
+        ("WHITE", &measurement.ambient_light_white),
+    ] {
+        if counts.as_ref().is_some_and(|c| c.is_saturated()) {
+            log::warn!(
+                "Ambient light {} channel of {} is saturated",
+                channel,
+                measurement.address
+            );
         }
     }
-    let measurement = builder.build().unwrap();
+
+    // Warn about a drifting sensor of a node with redundant sensors
+    if measurement.status.is_some_and(|s| s.sensors_disagree()) {
+        log::warn!(
+            "Temperature and humidity sensors of {} disagree",
+            measurement.address
+        );
+    }
 
     // Deduplicate beacons
-    let lru = deduplication_cache
-        .entry(address)
-        .or_insert_with(|| LruCache::new(DEDUPLICATION_LRU_SIZE));
-    if lru.get(&measurement.counter).is_some() {
+    let frame_index = measurement.frame.map_or(0, |frame| frame.index);
+    if deduplicator.is_duplicate(address, measurement.counter, frame_index, now) {
         log::debug!("Ignoring duplicate frame (counter {})", measurement.counter);
         return None;
-    } else {
-        lru.put(measurement.counter, ());
     }
 
-    println!(
-        "{} ({} RSSI): [{}] {} °C | {} %RH | {} Lux",
-        measurement.local_name,
-        measurement.rssi,
-        measurement.counter,
-        measurement
-            .temperature
-            .as_ref()
-            .map(|t| t.as_degrees_celsius())
-            .unwrap_or(-1.0),
-        measurement
-            .humidity
-            .as_ref()
-            .map(|h| h.as_percent())
-            .unwrap_or(-1.0),
-        measurement
-            .ambient_light
-            .as_ref()
-            .map(|h| h.as_lux())
-            .unwrap_or(-1.0),
-    );
-
-    // TODO non-await
-    match influxdb::submit_measurement(agent, &config.influxdb, &measurement).await {
-        Ok(_) => log::info!("Measurement submitted"),
-        Err(e) => log::error!("Measurement submission failed: {:#}", e),
+    if hexdump {
+        if let Some(dump) = decoder.annotate(address, report.rssi, payload) {
+            print!("{}", dump);
+        }
     }
 
-    Some(())
+    Some(measurement)
 }
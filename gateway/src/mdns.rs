@@ -0,0 +1,223 @@
+//! Announcement of the API via mDNS / DNS-SD (RFC 6762 and 6763).
+//!
+//! With `mdns = true` in the `[api]` section, the API is announced as a
+//! `_sensilo._tcp` service on the local network, so that companion tools can
+//! find the gateway without configuration. The service is the HTTP API (see
+//! the `api` module), the gateway has no other endpoints. The responder is
+//! mdns-sd, which runs on its own thread and shares the port with a system
+//! responder like Avahi.
+//!
+//! The announced address is the listening address of the API, or the IPv4
+//! addresses of all interfaces if it listens on all interfaces.
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+
+use crate::config;
+
+const SERVICE_TYPE: &str = "_sensilo._tcp.local.";
+
+/// Truncate a label to the maximum length of 63 bytes.
+fn label(value: &str) -> String {
+    let mut end = value.len().min(63);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string()
+}
+
+/// The announced service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// Instance name, e.g. `Sensilo Gateway on raspberrypi`
+    pub instance: String,
+    /// Host name, without `.local`
+    pub host: String,
+    /// The listening address, `None` for all interfaces
+    pub ip: Option<Ipv4Addr>,
+    pub port: u16,
+    pub txt: Vec<(&'static str, String)>,
+}
+
+impl Service {
+    pub fn new(config: &config::Api, host: &str, ip: Option<Ipv4Addr>, port: u16) -> Self {
+        let instance = match config.mdns_name {
+            Some(ref instance) => instance.clone(),
+            None => format!("Sensilo Gateway on {}", host),
+        };
+        let mut txt = vec![
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("path", "/api".into()),
+        ];
+        if config.tls.is_some() || config.acme.is_some() {
+            txt.push(("tls", "1".into()));
+        }
+        Self {
+            instance: label(&instance),
+            host: label(host),
+            ip,
+            port,
+            txt,
+        }
+    }
+
+    /// The service info for the responder.
+    fn info(&self) -> Result<ServiceInfo> {
+        let ips: Vec<IpAddr> = self.ip.into_iter().map(IpAddr::V4).collect();
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.instance,
+            &format!("{}.local.", self.host),
+            &ips[..],
+            self.port,
+            &self.txt[..],
+        )?;
+        Ok(match self.ip {
+            Some(_) => info,
+            // The addresses of the interfaces
+            None => info.enable_addr_auto(),
+        })
+    }
+}
+
+/// The host name, without the domain.
+#[cfg(unix)]
+fn hostname() -> std::io::Result<String> {
+    use std::ffi::CStr;
+
+    let mut buf = [0u8; 256];
+    // Safety: The buffer is valid for its length, and the last byte is never
+    // overwritten (the name is always terminated)
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() - 1) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let hostname = CStr::from_bytes_until_nul(&buf).unwrap_or_default();
+    let hostname = hostname.to_string_lossy();
+    Ok(hostname.split('.').next().unwrap_or_default().to_string())
+}
+
+/// The address of the API to announce, `None` for all interfaces.
+fn announced_ip(addr: SocketAddr) -> Result<Option<Ipv4Addr>> {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ok(None),
+        IpAddr::V4(ip) if ip.is_loopback() => {
+            bail!(
+                "mDNS: The API only listens on the loopback interface ({})",
+                addr
+            )
+        }
+        IpAddr::V4(ip) => Ok(Some(ip)),
+        IpAddr::V6(_) => bail!("mDNS is only supported with an IPv4 listen address"),
+    }
+}
+
+/// Start announcing the API listening on `addr`. The service is announced
+/// until the returned responder is dropped.
+#[cfg(unix)]
+pub fn start(config: &config::Api, addr: SocketAddr) -> Result<ServiceDaemon> {
+    let ip = announced_ip(addr)?;
+    let host = hostname().context("mDNS: Could not get the host name")?;
+    let service = Service::new(config, &host, ip, addr.port());
+    let daemon = ServiceDaemon::new().context("mDNS: Could not start the responder")?;
+    match ip {
+        Some(ip) => {
+            daemon.disable_interface(IfKind::All)?;
+            daemon.enable_interface(IfKind::Addr(IpAddr::V4(ip)))?;
+        }
+        None => daemon.disable_interface(IfKind::IPv6)?,
+    }
+    daemon
+        .register(service.info()?)
+        .context("mDNS: Could not register the service")?;
+    status!(
+        "Announcing the API via mDNS as \"{}\" ({}.local, port {})",
+        service.instance,
+        service.host,
+        service.port
+    );
+    Ok(daemon)
+}
+
+#[cfg(not(unix))]
+pub fn start(_config: &config::Api, addr: SocketAddr) -> Result<ServiceDaemon> {
+    announced_ip(addr)?;
+    bail!("mDNS is only available on unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(ip: Option<Ipv4Addr>) -> Service {
+        Service::new(&Default::default(), "raspberrypi", ip, 8080)
+    }
+
+    #[test]
+    fn names() {
+        let service = service(None);
+        assert_eq!(service.instance, "Sensilo Gateway on raspberrypi");
+        assert_eq!(service.host, "raspberrypi");
+        let config = config::Api {
+            mdns_name: Some("Basement".into()),
+            ..Default::default()
+        };
+        let service = Service::new(&config, "raspberrypi", None, 8080);
+        assert_eq!(service.instance, "Basement");
+
+        assert_eq!(label("Sensilo"), "Sensilo");
+        assert_eq!(label(&"ä".repeat(40)).len(), 62);
+    }
+
+    #[test]
+    fn info() {
+        let info = service(Some(Ipv4Addr::new(192, 168, 1, 20)))
+            .info()
+            .unwrap();
+        assert_eq!(
+            info.get_fullname(),
+            "Sensilo Gateway on raspberrypi._sensilo._tcp.local."
+        );
+        assert_eq!(info.get_hostname(), "raspberrypi.local.");
+        assert_eq!(info.get_port(), 8080);
+        assert_eq!(info.get_property_val_str("path"), Some("/api"));
+        assert_eq!(info.get_property_val_str("tls"), None);
+        assert!(info
+            .get_addresses()
+            .iter()
+            .any(|ip| ip.to_string() == "192.168.1.20"));
+        assert!(!info.is_addr_auto());
+
+        let info = service(None).info().unwrap();
+        assert!(info.get_addresses().is_empty());
+        assert!(info.is_addr_auto());
+    }
+
+    #[test]
+    fn tls() {
+        let config = config::Api {
+            tls: Some(config::ApiTls {
+                certificate: "cert.pem".into(),
+                private_key: "key.pem".into(),
+                self_signed: true,
+                names: vec!["localhost".into()],
+            }),
+            ..Default::default()
+        };
+        let service = Service::new(&config, "raspberrypi", None, 8443);
+        let info = service.info().unwrap();
+        assert_eq!(info.get_property_val_str("tls"), Some("1"));
+    }
+
+    #[test]
+    fn listen_address() {
+        let addr = |addr: &str| announced_ip(addr.parse().unwrap());
+        assert_eq!(addr("0.0.0.0:8080").unwrap(), None);
+        assert_eq!(
+            addr("192.168.1.20:8080").unwrap(),
+            Some(Ipv4Addr::new(192, 168, 1, 20))
+        );
+        assert!(addr("127.0.0.1:8080").is_err());
+        assert!(addr("[::]:8080").is_err());
+    }
+}
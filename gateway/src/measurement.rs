@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+use std::slice::Iter;
+use std::time::SystemTime;
+
 use crate::types::Address;
 
 /// A temperature measurement.
@@ -12,12 +16,147 @@ pub struct Humidity(i32);
 #[derive(Debug, Clone, PartialEq)]
 pub struct AmbientLight(f32);
 
+/// A pulse counter reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pulses {
+    /// Pulses since the device started (wraps around)
+    pub count: u32,
+    /// Pulses since the previous measurement
+    pub delta: u16,
+}
+
+/// The state of a door/window contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contact {
+    pub open: bool,
+    /// Number of state changes since the device started (wraps around)
+    pub events: u16,
+}
+
+/// Optional hardware of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub flags: u8,
+}
+
+impl Status {
+    const BUZZER: u8 = 1 << 0;
+    const HEATED: u8 = 1 << 1;
+    const DISAGREEMENT: u8 = 1 << 2;
+
+    /// Whether the device has a buzzer.
+    pub fn has_buzzer(self) -> bool {
+        self.flags & Self::BUZZER != 0
+    }
+
+    /// Whether the temperature and humidity were measured shortly after a
+    /// heater pulse of the sensor (and are too warm and too dry).
+    pub fn heated(self) -> bool {
+        self.flags & Self::HEATED != 0
+    }
+
+    /// Whether the two temperature and humidity sensors of a device with
+    /// redundant sensors diverge by more than the threshold of the device
+    /// (one of them has probably drifted).
+    pub fn sensors_disagree(self) -> bool {
+        self.flags & Self::DISAGREEMENT != 0
+    }
+}
+
+/// Health counters of a device, sent periodically. All counters are counted
+/// since the device started (and wrap around).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Telemetry {
+    /// Transmitted beacons
+    pub tx_beacons: u16,
+    /// Failed I²C transactions
+    pub i2c_errors: u16,
+    /// Sensor operations that were retried
+    pub sensor_retries: u16,
+}
+
+/// Timing diagnostics of a measurement, measured by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    /// Time from the start of the measurement until it was collected, in µs
+    pub duration_us: u32,
+    /// How much later than scheduled the measurement started, in µs
+    /// (negative if it started too early)
+    pub start_latency_us: i16,
+}
+
+/// Battery voltage of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Battery {
+    pub millivolts: u16,
+}
+
+/// A past measurement from the backlog of a device, sent along with a current
+/// measurement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BacklogSample {
+    /// Counter difference to the measurement it is sent with
+    pub age: u16,
+    pub temperature: Temperature,
+    pub humidity: Humidity,
+}
+
+/// Temperature and humidity relative to a previous (full) measurement of a
+/// device, from which the gateway reconstructs the absolute values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// Counter difference to the full measurement
+    pub reference_age: u8,
+    /// Centidegrees Celsius (`None` if the sensor failed)
+    pub temperature: Option<i16>,
+    /// Centipercent relative humidity (`None` if the sensor failed)
+    pub humidity: Option<i16>,
+}
+
+/// The voltage of an analog input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalogInput {
+    pub millivolts: u16,
+    /// Metric name from the device config
+    pub name: Option<String>,
+}
+
+impl AnalogInput {
+    /// Name of the metric: The configured name, or `analog_<channel>`.
+    pub fn metric(&self, channel: u8) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("analog_{}", channel),
+        }
+    }
+}
+
+/// Information about a payload that is split across multiple frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Protocol version
+    pub version: u8,
+    /// Index of this frame
+    pub index: u8,
+    /// Total number of frames
+    pub count: u8,
+}
+
+/// A raw light sensor channel reading (e.g. the VEML7700 ALS or WHITE channel).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LightCounts(u16);
+
 impl Temperature {
     /// Create a new `Temperature` from little endian bytes.
     pub fn from_le_bytes(raw: [u8; 4]) -> Self {
         Self(i32::from_le_bytes(raw))
     }
 
+    /// Create a new `Temperature` from milli-degrees celsius.
+    pub fn from_millidegrees_celsius(val: i32) -> Self {
+        Self(val)
+    }
+
     /// Return temperature in milli-degrees celsius.
     pub fn as_millidegrees_celsius(&self) -> i32 {
         self.0
@@ -27,6 +166,17 @@ impl Temperature {
     pub fn as_degrees_celsius(&self) -> f32 {
         self.0 as f32 / 1000.0
     }
+
+    /// Return temperature in milli-degrees fahrenheit.
+    pub fn as_millidegrees_fahrenheit(&self) -> i32 {
+        // Round to nearest
+        (self.0 * 9 + if self.0 < 0 { -2 } else { 2 }) / 5 + 32_000
+    }
+
+    /// Return temperature in degrees fahrenheit.
+    pub fn as_degrees_fahrenheit(&self) -> f32 {
+        self.0 as f32 * 9.0 / 5000.0 + 32.0
+    }
 }
 
 impl Humidity {
@@ -35,6 +185,11 @@ impl Humidity {
         Self(i32::from_le_bytes(raw))
     }
 
+    /// Create a new `Humidity` from 1/1000 %RH.
+    pub fn from_millipercent(val: i32) -> Self {
+        Self(val)
+    }
+
     /// Return relative humidity in 1/1000 %RH.
     pub fn as_millipercent(&self) -> i32 {
         self.0
@@ -52,31 +207,140 @@ impl AmbientLight {
         Self(f32::from_le_bytes(raw))
     }
 
+    /// Create a new `AmbientLight` from lux.
+    pub fn from_lux(val: f32) -> Self {
+        Self(val)
+    }
+
     /// Return ambient light in lux.
     pub fn as_lux(&self) -> f32 {
         self.0
     }
 }
 
-#[derive(Debug)]
-pub struct Measurement<'a> {
+impl LightCounts {
+    /// Create a new `LightCounts` from little endian bytes.
+    pub fn from_le_bytes(raw: [u8; 2]) -> Self {
+        Self(u16::from_le_bytes(raw))
+    }
+
+    /// Create a new `LightCounts` from raw sensor counts.
+    pub fn from_counts(val: u16) -> Self {
+        Self(val)
+    }
+
+    /// Return the raw sensor counts.
+    pub fn as_counts(&self) -> u16 {
+        self.0
+    }
+
+    /// Return whether the channel is saturated.
+    pub fn is_saturated(&self) -> bool {
+        self.0 == u16::MAX
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Measurement {
     pub address: Address,
     pub rssi: u8,
-    pub local_name: &'a str,
+    pub local_name: String,
     pub counter: u16,
+    pub frame: Option<FrameInfo>,
     pub temperature: Option<Temperature>,
     pub humidity: Option<Humidity>,
     pub ambient_light: Option<AmbientLight>,
+    pub ambient_light_als: Option<LightCounts>,
+    pub ambient_light_white: Option<LightCounts>,
+    pub thermocouple_temperature: Option<Temperature>,
+    /// Temperature and humidity of the second sensor of a device with
+    /// redundant sensors
+    pub secondary_temperature: Option<Temperature>,
+    pub secondary_humidity: Option<Humidity>,
+    /// Temperatures of external probes, by probe index
+    pub external_temperatures: BTreeMap<u8, Temperature>,
+    pub pulses: Option<Pulses>,
+    /// Pulses per second (calculated by the gateway)
+    pub pulse_rate: Option<f64>,
+    /// Analog inputs, by input number
+    pub analog: BTreeMap<u8, AnalogInput>,
+    pub contact: Option<Contact>,
+    /// Whether the contact state changed since the previous measurement
+    /// (detected by the gateway)
+    pub contact_changed: bool,
+    pub status: Option<Status>,
+    pub telemetry: Option<Telemetry>,
+    pub timing: Option<Timing>,
+    pub battery: Option<Battery>,
+    /// Advertisements sent by the device since it started (reported by
+    /// Eddystone-TLM beacons)
+    pub advertisements: Option<u32>,
+    pub backlog: Option<BacklogSample>,
+    /// Set until the values are reconstructed by the gateway
+    pub delta: Option<Delta>,
+    /// Values of the entries with an unknown type (only kept if they are
+    /// forwarded to the sinks)
+    pub unknown_entries: BTreeMap<u8, Vec<u8>>,
+    /// Whether the measurement was reconstructed from the backlog of the
+    /// device (by the gateway)
+    pub backfilled: bool,
+    /// Whether the timestamp is approximate, because the host clock was not
+    /// synchronized (detected by the gateway)
+    pub approx_time: bool,
+    /// When the (first frame of the) measurement was received
+    pub timestamp: SystemTime,
+    /// The smoothed values before the smoothing, if the raw values are kept
+    /// (set by the gateway)
+    pub raw: Option<Box<Measurement>>,
+}
+
+/// The protocol version of a payload (without company identifier). Payloads
+/// of version 2 and later start with a frame info entry, which contains the
+/// version. Version 1 payloads only have it if they are split across frames.
+pub fn protocol_version(payload: &[u8]) -> u8 {
+    match payload {
+        [_, _, 0x00, version, ..] if *version >= 2 => *version,
+        _ => 1,
+    }
+}
+
+/// Length of the entry headers (type, and length since protocol version 2).
+pub fn entry_header_len(payload: &[u8]) -> usize {
+    if protocol_version(payload) >= 2 {
+        2
+    } else {
+        1
+    }
 }
 
 pub struct MeasurementBuilder<'a> {
     address: Address,
     rssi: u8,
+    timestamp: Option<SystemTime>,
     local_name: Option<&'a str>,
     counter: Option<u16>,
+    frame: Option<FrameInfo>,
     temperature: Option<Temperature>,
     humidity: Option<Humidity>,
     ambient_light: Option<AmbientLight>,
+    ambient_light_als: Option<LightCounts>,
+    ambient_light_white: Option<LightCounts>,
+    thermocouple_temperature: Option<Temperature>,
+    secondary_temperature: Option<Temperature>,
+    secondary_humidity: Option<Humidity>,
+    external_temperatures: BTreeMap<u8, Temperature>,
+    pulses: Option<Pulses>,
+    analog: BTreeMap<u8, AnalogInput>,
+    contact: Option<Contact>,
+    status: Option<Status>,
+    telemetry: Option<Telemetry>,
+    timing: Option<Timing>,
+    battery: Option<Battery>,
+    advertisements: Option<u32>,
+    backlog: Option<BacklogSample>,
+    delta: Option<Delta>,
+    unknown_entries: BTreeMap<u8, Vec<u8>>,
+    strict: bool,
     parse_error: bool,
 }
 
@@ -85,15 +349,41 @@ impl<'a> MeasurementBuilder<'a> {
         MeasurementBuilder {
             address,
             rssi,
+            timestamp: None,
             local_name: None,
             counter: None,
+            frame: None,
             temperature: None,
             humidity: None,
             ambient_light: None,
+            ambient_light_als: None,
+            ambient_light_white: None,
+            thermocouple_temperature: None,
+            secondary_temperature: None,
+            secondary_humidity: None,
+            external_temperatures: BTreeMap::new(),
+            pulses: None,
+            analog: BTreeMap::new(),
+            contact: None,
+            status: None,
+            telemetry: None,
+            timing: None,
+            battery: None,
+            advertisements: None,
+            backlog: None,
+            delta: None,
+            unknown_entries: BTreeMap::new(),
+            strict: false,
             parse_error: false,
         }
     }
 
+    /// Set the reception time (the current time by default).
+    pub fn timestamp(&mut self, timestamp: SystemTime) -> &mut Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
     pub fn local_name(&mut self, name: &'a str) -> &mut Self {
         self.local_name = Some(name);
         self
@@ -104,6 +394,11 @@ impl<'a> MeasurementBuilder<'a> {
         self
     }
 
+    pub fn frame(&mut self, frame: FrameInfo) -> &mut Self {
+        self.frame = Some(frame);
+        self
+    }
+
     pub fn temperature(&mut self, val: Temperature) -> &mut Self {
         self.temperature = Some(val);
         self
@@ -119,71 +414,417 @@ impl<'a> MeasurementBuilder<'a> {
         self
     }
 
+    pub fn ambient_light_als(&mut self, val: LightCounts) -> &mut Self {
+        self.ambient_light_als = Some(val);
+        self
+    }
+
+    pub fn ambient_light_white(&mut self, val: LightCounts) -> &mut Self {
+        self.ambient_light_white = Some(val);
+        self
+    }
+
+    pub fn thermocouple_temperature(&mut self, val: Temperature) -> &mut Self {
+        self.thermocouple_temperature = Some(val);
+        self
+    }
+
+    pub fn secondary_temperature(&mut self, val: Temperature) -> &mut Self {
+        self.secondary_temperature = Some(val);
+        self
+    }
+
+    pub fn secondary_humidity(&mut self, val: Humidity) -> &mut Self {
+        self.secondary_humidity = Some(val);
+        self
+    }
+
+    pub fn external_temperature(&mut self, index: u8, val: Temperature) -> &mut Self {
+        self.external_temperatures.insert(index, val);
+        self
+    }
+
+    pub fn pulses(&mut self, val: Pulses) -> &mut Self {
+        self.pulses = Some(val);
+        self
+    }
+
+    pub fn analog(&mut self, channel: u8, millivolts: u16) -> &mut Self {
+        let input = AnalogInput {
+            millivolts,
+            name: None,
+        };
+        self.analog.insert(channel, input);
+        self
+    }
+
+    pub fn contact(&mut self, val: Contact) -> &mut Self {
+        self.contact = Some(val);
+        self
+    }
+
+    pub fn status(&mut self, val: Status) -> &mut Self {
+        self.status = Some(val);
+        self
+    }
+
+    pub fn telemetry(&mut self, val: Telemetry) -> &mut Self {
+        self.telemetry = Some(val);
+        self
+    }
+
+    pub fn timing(&mut self, val: Timing) -> &mut Self {
+        self.timing = Some(val);
+        self
+    }
+
+    pub fn battery(&mut self, val: Battery) -> &mut Self {
+        self.battery = Some(val);
+        self
+    }
+
+    pub fn advertisements(&mut self, val: u32) -> &mut Self {
+        self.advertisements = Some(val);
+        self
+    }
+
+    pub fn backlog(&mut self, val: BacklogSample) -> &mut Self {
+        self.backlog = Some(val);
+        self
+    }
+
+    pub fn delta(&mut self, val: Delta) -> &mut Self {
+        self.delta = Some(val);
+        self
+    }
+
+    /// Reject payloads with unknown entry types (instead of skipping them).
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// The entries of the parsed payload with an unknown type. In protocol
+    /// version 1, their length is unknown, so the value is the rest of the
+    /// payload.
+    pub fn unknown_entries(&self) -> &BTreeMap<u8, Vec<u8>> {
+        &self.unknown_entries
+    }
+
     pub fn parse_payload(&mut self, payload: &[u8]) -> Result<&mut Self, &'static str> {
         let mut bytes = payload.iter();
 
+        // Parse counter
+        let counter = self.consume(&mut bytes, "counter", 2)?;
+        self.counter(u16::from_le_bytes([counter[0], counter[1]]));
+
+        // Parse data
+        if protocol_version(payload) >= 2 {
+            self.parse_entries_v2(bytes)?;
+        } else {
+            self.parse_entries_v1(bytes)?;
+        }
+        Ok(self)
+    }
+
+    /// Parse entries without length (type + value). After an unknown type,
+    /// the rest of the payload can't be parsed.
+    fn parse_entries_v1(&mut self, mut bytes: Iter<'_, u8>) -> Result<(), &'static str> {
+        while let Some(&payload_type) = bytes.next() {
+            if self.parse_entry(payload_type, &mut bytes)? {
+                continue;
+            }
+            if self.strict {
+                log::warn!("Malformed payload: Unknown payload type: {}", payload_type);
+                self.parse_error = true;
+                return Err("Unknown payload type");
+            }
+            log::info!("Unknown payload type: {}", payload_type);
+            self.unknown_entries
+                .insert(payload_type, bytes.copied().collect());
+            break;
+        }
+        Ok(())
+    }
+
+    /// Parse the frame info and the length-prefixed entries (type + length +
+    /// value) of protocol version 2. Unknown types are skipped, as well as
+    /// additional bytes at the end of the value of a known type.
+    fn parse_entries_v2(&mut self, mut bytes: Iter<'_, u8>) -> Result<(), &'static str> {
+        // The frame info entry has no length (it contains the version)
+        bytes.next();
+        self.parse_entry(0x00, &mut bytes)?;
+        while let Some(&payload_type) = bytes.next() {
+            let len = self.consume(&mut bytes, "entry length", 1)?[0];
+            let value = self.consume(&mut bytes, "entry value", usize::from(len))?;
+            let mut value = value.iter();
+            if !self.parse_entry(payload_type, &mut value)? {
+                if self.strict {
+                    log::warn!("Malformed payload: Unknown payload type: {}", payload_type);
+                    self.parse_error = true;
+                    return Err("Unknown payload type");
+                }
+                log::info!("Skipping unknown payload type: {}", payload_type);
+                self.unknown_entries
+                    .insert(payload_type, value.copied().collect());
+            } else if value.len() > 0 {
+                if self.strict {
+                    log::warn!("Malformed payload: Trailing bytes in type {}", payload_type);
+                    self.parse_error = true;
+                    return Err("Trailing bytes");
+                }
+                log::debug!("Ignoring trailing bytes in payload type {}", payload_type);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take `count` bytes, or fail with a malformed payload.
+    fn consume<'b>(
+        &mut self,
+        bytes: &mut Iter<'b, u8>,
+        name: &str,
+        count: usize,
+    ) -> Result<&'b [u8], &'static str> {
+        let rest = bytes.as_slice();
+        if rest.len() < count {
+            log::warn!("Malformed payload: Missing {}", name);
+            self.parse_error = true;
+            return Err("Malformed payload");
+        }
+        let (data, rest) = rest.split_at(count);
+        *bytes = rest.iter();
+        Ok(data)
+    }
+
+    /// Parse the value of an entry. Return `false` if the type is unknown
+    /// (without consuming any bytes).
+    fn parse_entry(
+        &mut self,
+        payload_type: u8,
+        bytes: &mut Iter<'_, u8>,
+    ) -> Result<bool, &'static str> {
         macro_rules! consume {
             ($name:expr, $count:expr) => {{
                 let mut data = [0; $count];
-                for i in 0..$count {
-                    data[i] = *bytes.next().ok_or_else(|| {
-                        log::warn!("Malformed payload: Missing {}", $name);
-                        self.parse_error = true;
-                        "Malformed payload"
-                    })?;
-                }
+                data.copy_from_slice(self.consume(bytes, $name, $count)?);
                 data
             }};
         }
 
-        // Parse counter
-        let counter = consume!("counter", 2);
-        self.counter(u16::from_le_bytes(counter));
-
-        // Parse data
-        while let Some(payload_type) = bytes.next() {
-            match payload_type {
-                0x01 => {
-                    let raw = consume!("temperature", 4);
-                    self.temperature(Temperature::from_le_bytes(raw));
-                }
-                0x02 => {
-                    let raw = consume!("humidity", 4);
-                    self.humidity(Humidity::from_le_bytes(raw));
-                }
-                0x04 => {
-                    let raw = consume!("ambient light", 4);
-                    self.ambient_light(AmbientLight::from_le_bytes(raw));
-                }
-                other => {
-                    log::info!("Unknown payload type: {}", other);
-                }
+        match payload_type {
+            0x00 => {
+                let raw = consume!("frame info", 2);
+                self.frame(FrameInfo {
+                    version: raw[0],
+                    index: raw[1] >> 4,
+                    count: raw[1] & 0x0f,
+                });
+            }
+            0x01 => {
+                let raw = consume!("temperature", 4);
+                self.temperature(Temperature::from_le_bytes(raw));
+            }
+            0x02 => {
+                let raw = consume!("humidity", 4);
+                self.humidity(Humidity::from_le_bytes(raw));
+            }
+            0x04 => {
+                let raw = consume!("ambient light", 4);
+                self.ambient_light(AmbientLight::from_le_bytes(raw));
+            }
+            0x05 => {
+                let raw = consume!("ambient light ALS counts", 2);
+                self.ambient_light_als(LightCounts::from_le_bytes(raw));
+            }
+            0x06 => {
+                let raw = consume!("ambient light WHITE counts", 2);
+                self.ambient_light_white(LightCounts::from_le_bytes(raw));
+            }
+            0x07 => {
+                let raw = consume!("thermocouple temperature", 4);
+                self.thermocouple_temperature(Temperature::from_le_bytes(raw));
+            }
+            0x08 => {
+                let raw = consume!("external temperature", 5);
+                let temperature = Temperature::from_le_bytes([raw[1], raw[2], raw[3], raw[4]]);
+                self.external_temperature(raw[0], temperature);
+            }
+            0x09 => {
+                let raw = consume!("pulse counter", 6);
+                self.pulses(Pulses {
+                    count: u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+                    delta: u16::from_le_bytes([raw[4], raw[5]]),
+                });
+            }
+            0x0a => {
+                let raw = consume!("analog input", 3);
+                self.analog(raw[0], u16::from_le_bytes([raw[1], raw[2]]));
             }
+            0x0b => {
+                let raw = consume!("contact", 3);
+                self.contact(Contact {
+                    open: raw[0] != 0,
+                    events: u16::from_le_bytes([raw[1], raw[2]]),
+                });
+            }
+            0x0c => {
+                let raw = consume!("status", 1);
+                self.status(Status { flags: raw[0] });
+            }
+            0x0d => {
+                // Verified by the decoder (if a key is configured)
+                consume!("MAC", 4);
+            }
+            0x0e => {
+                let raw = consume!("telemetry", 6);
+                self.telemetry(Telemetry {
+                    tx_beacons: u16::from_le_bytes([raw[0], raw[1]]),
+                    i2c_errors: u16::from_le_bytes([raw[2], raw[3]]),
+                    sensor_retries: u16::from_le_bytes([raw[4], raw[5]]),
+                });
+            }
+            0x0f => {
+                let raw = consume!("backlog sample", 6);
+                let centidegrees = i16::from_le_bytes([raw[2], raw[3]]);
+                let centipercent = u16::from_le_bytes([raw[4], raw[5]]);
+                self.backlog(BacklogSample {
+                    age: u16::from_le_bytes([raw[0], raw[1]]),
+                    temperature: Temperature(i32::from(centidegrees) * 10),
+                    humidity: Humidity(i32::from(centipercent) * 10),
+                });
+            }
+            0x10 => {
+                let raw = consume!("delta", 5);
+                // The lowest value means that the sensor failed
+                let centi = |lo, hi| Some(i16::from_le_bytes([lo, hi])).filter(|&v| v != i16::MIN);
+                self.delta(Delta {
+                    reference_age: raw[0],
+                    temperature: centi(raw[1], raw[2]),
+                    humidity: centi(raw[3], raw[4]),
+                });
+            }
+            0x11 => {
+                // Verified by the decoder (if configured)
+                consume!("CRC", 1);
+            }
+            0x12 => {
+                let raw = consume!("timing", 6);
+                self.timing(Timing {
+                    duration_us: u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+                    start_latency_us: i16::from_le_bytes([raw[4], raw[5]]),
+                });
+            }
+            0x13 => {
+                let raw = consume!("secondary temperature", 4);
+                self.secondary_temperature(Temperature::from_le_bytes(raw));
+            }
+            0x14 => {
+                let raw = consume!("secondary humidity", 4);
+                self.secondary_humidity(Humidity::from_le_bytes(raw));
+            }
+            _ => return Ok(false),
         }
-
-        Ok(self)
+        Ok(true)
     }
 
-    pub fn build(self) -> Result<Measurement<'a>, &'static str> {
+    pub fn build(self) -> Result<Measurement, &'static str> {
         if self.parse_error {
             return Err("Error while parsing packet");
         }
         Ok(Measurement {
             address: self.address,
             rssi: self.rssi,
-            local_name: self.local_name.ok_or("Missing local name")?,
+            local_name: self.local_name.ok_or("Missing local name")?.to_string(),
             counter: self.counter.ok_or("Missing counter")?,
+            frame: self.frame,
             temperature: self.temperature,
             humidity: self.humidity,
             ambient_light: self.ambient_light,
+            ambient_light_als: self.ambient_light_als,
+            ambient_light_white: self.ambient_light_white,
+            thermocouple_temperature: self.thermocouple_temperature,
+            secondary_temperature: self.secondary_temperature,
+            secondary_humidity: self.secondary_humidity,
+            external_temperatures: self.external_temperatures,
+            pulses: self.pulses,
+            pulse_rate: None,
+            analog: self.analog,
+            contact: self.contact,
+            contact_changed: false,
+            status: self.status,
+            telemetry: self.telemetry,
+            timing: self.timing,
+            battery: self.battery,
+            advertisements: self.advertisements,
+            backlog: self.backlog,
+            delta: self.delta,
+            unknown_entries: self.unknown_entries,
+            backfilled: false,
+            approx_time: false,
+            timestamp: self.timestamp.unwrap_or_else(SystemTime::now),
+            raw: None,
         })
     }
 }
 
+impl Measurement {
+    /// Merge the values of another frame of the same measurement into this
+    /// measurement. Values that are already set take precedence.
+    pub fn merge(&mut self, other: Measurement) {
+        self.rssi = self.rssi.max(other.rssi);
+        self.temperature = self.temperature.take().or(other.temperature);
+        self.humidity = self.humidity.take().or(other.humidity);
+        self.ambient_light = self.ambient_light.take().or(other.ambient_light);
+        self.ambient_light_als = self.ambient_light_als.take().or(other.ambient_light_als);
+        self.ambient_light_white = self
+            .ambient_light_white
+            .take()
+            .or(other.ambient_light_white);
+        self.thermocouple_temperature = self
+            .thermocouple_temperature
+            .take()
+            .or(other.thermocouple_temperature);
+        self.secondary_temperature = self
+            .secondary_temperature
+            .take()
+            .or(other.secondary_temperature);
+        self.secondary_humidity = self.secondary_humidity.take().or(other.secondary_humidity);
+        self.pulses = self.pulses.take().or(other.pulses);
+        self.contact = self.contact.take().or(other.contact);
+        self.status = self.status.take().or(other.status);
+        self.telemetry = self.telemetry.take().or(other.telemetry);
+        self.timing = self.timing.take().or(other.timing);
+        self.battery = self.battery.take().or(other.battery);
+        self.advertisements = self.advertisements.take().or(other.advertisements);
+        self.backlog = self.backlog.take().or(other.backlog);
+        self.delta = self.delta.take().or(other.delta);
+        for (channel, input) in other.analog {
+            self.analog.entry(channel).or_insert(input);
+        }
+        for (index, temperature) in other.external_temperatures {
+            self.external_temperatures
+                .entry(index)
+                .or_insert(temperature);
+        }
+        for (payload_type, value) in other.unknown_entries {
+            self.unknown_entries.entry(payload_type).or_insert(value);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::advertising::AdStructure;
+    use crate::hci;
+    use crate::test_util::Xorshift;
+
     #[test]
     fn test_parse_payload() {
         #[rustfmt::skip]
@@ -197,17 +838,732 @@ mod tests {
             // Payload type 4: Ambient light
             4, 80, 252, 152, 66,
         ];
-        let address = [1, 2, 3, 4, 5, 6, 7, 8];
-        let mut builder = MeasurementBuilder::new(&address, 123);
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
         builder.local_name("Sensilo");
         builder.parse_payload(&payload).unwrap();
         let measurement = builder.build().unwrap();
-        assert_eq!(measurement.address, &address);
+        assert_eq!(measurement.address, address);
         assert_eq!(measurement.rssi, 123);
         assert_eq!(measurement.local_name, "Sensilo");
         assert_eq!(measurement.counter, 1076);
         assert_eq!(measurement.temperature, Some(Temperature(25_338)));
         assert_eq!(measurement.humidity, Some(Humidity(49_382)));
         assert_eq!(measurement.ambient_light, Some(AmbientLight(76.4928)));
+        assert_eq!(measurement.ambient_light_als, None);
+        assert_eq!(measurement.ambient_light_white, None);
+        assert_eq!(measurement.thermocouple_temperature, None);
+    }
+
+    #[test]
+    fn test_parse_payload_thermocouple() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            54, 4,
+            // Payload type 7: Thermocouple temperature (-12.75 °C)
+            7, 0x32, 0xce, 0xff, 0xff,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.counter, 1078);
+        assert_eq!(measurement.temperature, None);
+        assert_eq!(
+            measurement.thermocouple_temperature,
+            Some(Temperature(-12_750))
+        );
+    }
+
+    #[test]
+    fn test_parse_payload_external_temperatures() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            55, 4,
+            // Payload type 8: External temperature, probe 0 (4.5 °C)
+            8, 0, 0x94, 0x11, 0, 0,
+            // Payload type 8: External temperature, probe 2 (18 °C)
+            8, 2, 0x50, 0x46, 0, 0,
+            // Payload type 8: Truncated
+            8, 3, 0x50,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        assert!(builder.parse_payload(&payload).is_err());
+        assert!(builder.build().is_err());
+
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload[..14]).unwrap();
+        let measurement = builder.build().unwrap();
+        let temperatures: Vec<_> = measurement.external_temperatures.into_iter().collect();
+        assert_eq!(
+            temperatures,
+            vec![(0, Temperature(4_500)), (2, Temperature(18_000))]
+        );
+    }
+
+    #[test]
+    fn test_parse_payload_pulses() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            56, 4,
+            // Payload type 9: Pulse counter
+            9, 0x10, 0x27, 0, 0, 12, 0,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.pulses,
+            Some(Pulses {
+                count: 10_000,
+                delta: 12
+            })
+        );
+        assert_eq!(measurement.pulse_rate, None);
+    }
+
+    #[test]
+    fn test_parse_payload_analog() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            57, 4,
+            // Payload type 10: Analog input 4 (1.234 V)
+            10, 4, 0xd2, 0x04,
+            // Payload type 10: Analog input 5 (0 V)
+            10, 5, 0, 0,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.analog.len(), 2);
+        assert_eq!(measurement.analog[&4].millivolts, 1234);
+        assert_eq!(measurement.analog[&4].metric(4), "analog_4");
+        assert_eq!(measurement.analog[&5].millivolts, 0);
+    }
+
+    #[test]
+    fn test_parse_payload_contact() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Payload type 11: Contact (open, event 3)
+            11, 1, 3, 0,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.contact,
+            Some(Contact {
+                open: true,
+                events: 3
+            })
+        );
+        assert!(!measurement.contact_changed);
+    }
+
+    #[test]
+    fn test_parse_payload_status() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Payload type 12: Status (buzzer)
+            12, 1,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.status, Some(Status { flags: 1 }));
+        assert!(measurement.status.unwrap().has_buzzer());
+        assert!(!Status { flags: 0 }.has_buzzer());
+        assert!(!measurement.status.unwrap().heated());
+        assert!(Status { flags: 2 }.heated());
+        assert!(!measurement.status.unwrap().sensors_disagree());
+        assert!(Status { flags: 4 }.sensors_disagree());
+    }
+
+    #[test]
+    fn test_parse_payload_telemetry() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Payload type 14: Telemetry (1000 beacons, 2 I²C errors, 1 retry)
+            14, 0xe8, 0x03, 2, 0, 1, 0,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.telemetry,
+            Some(Telemetry {
+                tx_beacons: 1000,
+                i2c_errors: 2,
+                sensor_retries: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_payload_timing() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Payload type 18: Timing (12.5 ms, started 61 µs early)
+            18, 0xd4, 0x30, 0, 0, 0xc3, 0xff,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.timing,
+            Some(Timing {
+                duration_us: 12_500,
+                start_latency_us: -61,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_payload_backlog() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Payload type 15: Backlog sample (40 measurements ago, -5.5 °C, 81.25 %RH)
+            15, 40, 0, 0xda, 0xfd, 0xbd, 0x1f,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.backlog,
+            Some(BacklogSample {
+                age: 40,
+                temperature: Temperature::from_millidegrees_celsius(-5500),
+                humidity: Humidity::from_millipercent(81250),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_payload_delta() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Payload type 16: Delta (3 measurements ago, -0.25 °C, sensor failed)
+            16, 3, 0xe7, 0xff, 0x00, 0x80,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.delta,
+            Some(Delta {
+                reference_age: 3,
+                temperature: Some(-25),
+                humidity: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_payload_unknown_type() {
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        // The rest of the payload is not parsed
+        builder.parse_payload(&[58, 4, 0x7f, 0x01, 0x02]).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.unknown_entries.get(&0x7f), Some(&vec![1, 2]));
+
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo").strict(true);
+        assert!(builder.parse_payload(&[58, 4, 0x7f, 0x01, 0x02]).is_err());
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_merge_external_temperatures() {
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder
+            .local_name("Sensilo")
+            .counter(1)
+            .external_temperature(0, Temperature(4_500));
+        let mut measurement = builder.build().unwrap();
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder
+            .local_name("Sensilo")
+            .counter(1)
+            .external_temperature(0, Temperature(0))
+            .external_temperature(1, Temperature(18_000));
+        measurement.merge(builder.build().unwrap());
+        assert_eq!(measurement.external_temperatures.len(), 2);
+        assert_eq!(measurement.external_temperatures[&0], Temperature(4_500));
+        assert_eq!(measurement.external_temperatures[&1], Temperature(18_000));
+    }
+
+    #[test]
+    fn test_temperature_fahrenheit() {
+        let temp = Temperature::from_millidegrees_celsius(21500);
+        assert_eq!(temp.as_millidegrees_fahrenheit(), 70700);
+        assert!((temp.as_degrees_fahrenheit() - 70.7).abs() < 0.001);
+        let temp = Temperature::from_millidegrees_celsius(-40000);
+        assert_eq!(temp.as_millidegrees_fahrenheit(), -40000);
+        let temp = Temperature::from_millidegrees_celsius(-1);
+        assert_eq!(temp.as_millidegrees_fahrenheit(), 31998);
+    }
+
+    #[test]
+    fn test_parse_payload_raw_light() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            53, 4,
+            // Payload type 4: Ambient light
+            4, 80, 252, 152, 66,
+            // Payload type 5: Raw ALS counts
+            5, 0x2c, 0x01,
+            // Payload type 6: Raw WHITE counts
+            6, 0xff, 0xff,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.counter, 1077);
+        assert_eq!(measurement.temperature, None);
+        assert_eq!(measurement.ambient_light, Some(AmbientLight(76.4928)));
+        assert_eq!(measurement.ambient_light_als, Some(LightCounts(300)));
+        assert_eq!(measurement.ambient_light_white, Some(LightCounts(0xffff)));
+        assert!(!measurement.ambient_light_als.unwrap().is_saturated());
+        assert!(measurement.ambient_light_white.unwrap().is_saturated());
+    }
+
+    #[test]
+    fn test_parse_payload_frame_info() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            54, 4,
+            // Payload type 0: Frame info (version 1, frame 1 of 2)
+            0, 1, 0x12,
+            // Payload type 2: Humidity
+            2, 230, 192, 0, 0,
+        ];
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(
+            measurement.frame,
+            Some(FrameInfo {
+                version: 1,
+                index: 1,
+                count: 2
+            })
+        );
+        assert_eq!(measurement.humidity, Some(Humidity(49_382)));
+    }
+
+    #[test]
+    fn test_parse_payload_v2() {
+        #[rustfmt::skip]
+        let payload = [
+            // 2 byte counter
+            58, 4,
+            // Frame info (without length): Version 2, frame 0 of 1
+            0, 2, 0x01,
+            // Payload type 0x7f (unknown): 3 bytes
+            0x7f, 3, 1, 2, 3,
+            // Payload type 12: Status, with an additional byte
+            12, 2, 0x01, 0xff,
+            // Payload type 2: Humidity
+            2, 4, 230, 192, 0, 0,
+        ];
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        let measurement = builder.build().unwrap();
+        assert_eq!(measurement.frame.map(|frame| frame.version), Some(2));
+        assert_eq!(measurement.unknown_entries.get(&0x7f), Some(&vec![1, 2, 3]));
+        assert_eq!(measurement.status, Some(Status { flags: 0x01 }));
+        assert_eq!(measurement.humidity, Some(Humidity(49_382)));
+
+        // The strict mode rejects unknown types and trailing bytes
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo").strict(true);
+        assert!(builder.parse_payload(&payload).is_err());
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo").strict(true);
+        let mut without_unknown = payload[..5].to_vec();
+        without_unknown.extend_from_slice(&payload[10..]);
+        assert_eq!(
+            builder.parse_payload(&without_unknown).err(),
+            Some("Trailing bytes")
+        );
+
+        // A value longer than the payload
+        let mut builder = MeasurementBuilder::new(address, 123);
+        builder.local_name("Sensilo");
+        assert!(builder.parse_payload(&payload[..19]).is_err());
+    }
+
+    /// Parse a payload of a device called "Sensilo".
+    fn parse(payload: &[u8]) -> Measurement {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 123);
+        builder.local_name("Sensilo");
+        builder.parse_payload(payload).unwrap();
+        builder.build().unwrap()
+    }
+
+    /// Payloads in the format sent by the firmware (without the company
+    /// identifier), with the expected values.
+    #[test]
+    fn test_golden_vectors() {
+        // SHTC3 and VEML6030, with the status entry
+        #[rustfmt::skip]
+        let measurement = parse(&[
+            0x34, 0x04,
+            0x01, 0xde, 0x58, 0x00, 0x00,
+            0x02, 0xbc, 0xb1, 0x00, 0x00,
+            0x04, 0x00, 0x00, 0xf7, 0x42,
+            0x0c, 0x00,
+        ]);
+        assert_eq!(measurement.counter, 1076);
+        assert_eq!(measurement.frame, None);
+        assert_eq!(measurement.temperature, Some(Temperature(22_750)));
+        assert_eq!(measurement.humidity, Some(Humidity(45_500)));
+        assert_eq!(measurement.ambient_light, Some(AmbientLight(123.5)));
+        assert_eq!(measurement.status, Some(Status { flags: 0 }));
+
+        // First frame of a multi-frame measurement (see the hex dump in the
+        // README)
+        let measurement = parse(&[0x2a, 0x00, 0x00, 0x01, 0x12, 0x01, 0xfc, 0x53, 0x00, 0x00]);
+        assert_eq!(measurement.counter, 42);
+        assert_eq!(
+            measurement.frame,
+            Some(FrameInfo {
+                version: 1,
+                index: 1,
+                count: 2
+            })
+        );
+        assert_eq!(measurement.temperature, Some(Temperature(21_500)));
+
+        // Second frame with external probes (one below zero) and analog inputs
+        #[rustfmt::skip]
+        let measurement = parse(&[
+            0x2a, 0x00,
+            0x00, 0x01, 0x22,
+            0x08, 0x00, 0x7e, 0xeb, 0xff, 0xff,
+            0x08, 0x01, 0xde, 0x58, 0x00, 0x00,
+            0x0a, 0x04, 0x10, 0x0e,
+        ]);
+        assert_eq!(measurement.external_temperatures[&0], Temperature(-5_250));
+        assert_eq!(measurement.external_temperatures[&1], Temperature(22_750));
+        assert_eq!(measurement.analog[&4].millivolts, 3600);
+    }
+
+    /// Decode the first advertising report of an HCI packet, the way the
+    /// gateway does.
+    fn parse_report(packet: &[u8]) -> Measurement {
+        let reports = hci::parse_packet(packet).unwrap().unwrap();
+        let mut builder = MeasurementBuilder::new(reports[0].address, reports[0].rssi);
+        for structure in &reports[0].data {
+            match structure {
+                AdStructure::CompleteLocalName(name) => {
+                    builder.local_name(name);
+                }
+                AdStructure::ManufacturerSpecificData {
+                    company_identifier: 0xffff,
+                    data,
+                } => {
+                    builder.parse_payload(data).unwrap();
+                }
+                _ => {}
+            }
+        }
+        builder.build().unwrap()
+    }
+
+    /// Complete HCI advertising reports in the layout the firmware sends
+    /// (complete local name, then the manufacturer specific data, within 31
+    /// bytes), with the expected values. They are written byte by byte, not
+    /// generated with the encoder of the roundtrip test.
+    #[test]
+    fn test_golden_advertisements() {
+        // SHTC3 with the status entry, in a single frame
+        #[rustfmt::skip]
+        let measurement = parse_report(&[
+            // HCI event packet, LE meta event, parameter length
+            0x04, 0x3e, 39,
+            // Advertising report, one report, ADV_NONCONN_IND, random address
+            0x02, 1, 0x03, 0x01, 0xe6, 0xd5, 0xc4, 0xb3, 0xa2, 0xc1,
+            // Data length
+            27,
+            8, 0x09, b'S', b'e', b'n', b's', b'i', b'l', b'o',
+            17, 0xff, 0xff, 0xff,
+            0x37, 0x0c,
+            0x01, 0x64, 0x5a, 0x00, 0x00,
+            0x02, 0x8e, 0xa3, 0x00, 0x00,
+            0x0c, 0x00,
+            // RSSI
+            0xc4,
+        ]);
+        assert_eq!(measurement.address, Address::from_hex("c1a2b3c4d5e6"));
+        assert_eq!(measurement.rssi, 0xc4);
+        assert_eq!(measurement.local_name, "Sensilo");
+        assert_eq!(measurement.counter, 3127);
+        assert_eq!(measurement.frame, None);
+        assert_eq!(measurement.temperature, Some(Temperature(23_140)));
+        assert_eq!(measurement.humidity, Some(Humidity(41_870)));
+        assert_eq!(measurement.status, Some(Status { flags: 0 }));
+
+        // Both frames of the next measurement, with the VEML6030 (which
+        // doesn't fit into the first frame)
+        #[rustfmt::skip]
+        let measurement = parse_report(&[
+            0x04, 0x3e, 40,
+            0x02, 1, 0x03, 0x01, 0xe6, 0xd5, 0xc4, 0xb3, 0xa2, 0xc1,
+            28,
+            8, 0x09, b'S', b'e', b'n', b's', b'i', b'l', b'o',
+            18, 0xff, 0xff, 0xff,
+            0x38, 0x0c,
+            0x00, 0x01, 0x12,
+            0x01, 0x64, 0x5a, 0x00, 0x00,
+            0x02, 0x8e, 0xa3, 0x00, 0x00,
+            0xbf,
+        ]);
+        assert_eq!(measurement.counter, 3128);
+        assert_eq!(
+            measurement.frame,
+            Some(FrameInfo {
+                version: 1,
+                index: 1,
+                count: 2
+            })
+        );
+        assert_eq!(measurement.temperature, Some(Temperature(23_140)));
+        assert_eq!(measurement.ambient_light, None);
+        #[rustfmt::skip]
+        let measurement = parse_report(&[
+            0x04, 0x3e, 37,
+            0x02, 1, 0x03, 0x01, 0xe6, 0xd5, 0xc4, 0xb3, 0xa2, 0xc1,
+            25,
+            8, 0x09, b'S', b'e', b'n', b's', b'i', b'l', b'o',
+            15, 0xff, 0xff, 0xff,
+            0x38, 0x0c,
+            0x00, 0x01, 0x22,
+            0x04, 0x7b, 0x14, 0xae, 0x42,
+            0x0c, 0x00,
+            0xbf,
+        ]);
+        assert_eq!(measurement.counter, 3128);
+        assert_eq!(measurement.frame.map(|frame| frame.index), Some(2));
+        assert_eq!(measurement.temperature, None);
+        assert_eq!(measurement.ambient_light, Some(AmbientLight(87.04)));
+        assert_eq!(measurement.status, Some(Status { flags: 0 }));
+    }
+
+    /// Encode random sensor sets the way the firmware does, and check that
+    /// parsing them results in the same values.
+    #[test]
+    fn test_roundtrip() {
+        let mut rng = Xorshift::new(0xdead_beef);
+        let mut random = move || rng.next_u32();
+
+        for _ in 0..1000 {
+            let address = Address([1, 2, 3, 4, 5, 6]);
+            let mut expected = MeasurementBuilder::new(address, 123);
+            expected.local_name("Sensilo");
+            let counter = random() as u16;
+            expected.counter(counter);
+            let mut payload = counter.to_le_bytes().to_vec();
+
+            // Every entry type is present with a probability of 1/2
+            for ty in 0x00..=0x14 {
+                if random() % 2 == 0 {
+                    continue;
+                }
+                let value = random();
+                let bytes = value.to_le_bytes();
+                payload.push(ty);
+                match ty {
+                    0x00 => {
+                        // Protocol version 1 (the entries have no length)
+                        payload.extend_from_slice(&[1, bytes[1]]);
+                        expected.frame(FrameInfo {
+                            version: 1,
+                            index: bytes[1] >> 4,
+                            count: bytes[1] & 0x0f,
+                        });
+                    }
+                    0x01 => {
+                        payload.extend_from_slice(&bytes);
+                        expected.temperature(Temperature(value as i32));
+                    }
+                    0x02 => {
+                        payload.extend_from_slice(&bytes);
+                        expected.humidity(Humidity(value as i32));
+                    }
+                    // Type 0x03 (particulate matter) is not defined yet
+                    0x03 => {
+                        payload.pop();
+                    }
+                    0x04 => {
+                        // NaN is not equal to itself
+                        let lux = (value % 1_000_000) as f32 / 10.0;
+                        payload.extend_from_slice(&lux.to_le_bytes());
+                        expected.ambient_light(AmbientLight(lux));
+                    }
+                    0x05 => {
+                        payload.extend_from_slice(&bytes[..2]);
+                        expected.ambient_light_als(LightCounts(value as u16));
+                    }
+                    0x06 => {
+                        payload.extend_from_slice(&bytes[..2]);
+                        expected.ambient_light_white(LightCounts(value as u16));
+                    }
+                    0x07 => {
+                        payload.extend_from_slice(&bytes);
+                        expected.thermocouple_temperature(Temperature(value as i32));
+                    }
+                    0x08 => {
+                        let index = (random() % 4) as u8;
+                        payload.push(index);
+                        payload.extend_from_slice(&bytes);
+                        expected.external_temperature(index, Temperature(value as i32));
+                    }
+                    0x09 => {
+                        let delta = random() as u16;
+                        payload.extend_from_slice(&bytes);
+                        payload.extend_from_slice(&delta.to_le_bytes());
+                        expected.pulses(Pulses {
+                            count: value,
+                            delta,
+                        });
+                    }
+                    0x0a => {
+                        payload.extend_from_slice(&bytes[..3]);
+                        expected.analog(bytes[0], u16::from_le_bytes([bytes[1], bytes[2]]));
+                    }
+                    0x0b => {
+                        let open = value % 2 == 1;
+                        payload.push(u8::from(open));
+                        payload.extend_from_slice(&bytes[2..]);
+                        expected.contact(Contact {
+                            open,
+                            events: u16::from_le_bytes([bytes[2], bytes[3]]),
+                        });
+                    }
+                    0x0c => {
+                        payload.push(bytes[0]);
+                        expected.status(Status { flags: bytes[0] });
+                    }
+                    // The MAC is not part of the measurement
+                    0x0d => {
+                        payload.extend_from_slice(&bytes);
+                    }
+                    0x0e => {
+                        let retries = random() as u16;
+                        payload.extend_from_slice(&bytes);
+                        payload.extend_from_slice(&retries.to_le_bytes());
+                        expected.telemetry(Telemetry {
+                            tx_beacons: u16::from_le_bytes([bytes[0], bytes[1]]),
+                            i2c_errors: u16::from_le_bytes([bytes[2], bytes[3]]),
+                            sensor_retries: retries,
+                        });
+                    }
+                    0x0f => {
+                        let humidity = random() as u16;
+                        payload.extend_from_slice(&bytes);
+                        payload.extend_from_slice(&humidity.to_le_bytes());
+                        expected.backlog(BacklogSample {
+                            age: u16::from_le_bytes([bytes[0], bytes[1]]),
+                            temperature: Temperature(
+                                i32::from(i16::from_le_bytes([bytes[2], bytes[3]])) * 10,
+                            ),
+                            humidity: Humidity(i32::from(humidity) * 10),
+                        });
+                    }
+                    0x10 => {
+                        let humidity = random() as u16;
+                        payload.extend_from_slice(&bytes[..3]);
+                        payload.extend_from_slice(&humidity.to_le_bytes());
+                        let centi = |raw: i16| Some(raw).filter(|&raw| raw != i16::MIN);
+                        expected.delta(Delta {
+                            reference_age: bytes[0],
+                            temperature: centi(i16::from_le_bytes([bytes[1], bytes[2]])),
+                            humidity: centi(humidity as i16),
+                        });
+                    }
+                    // The CRC is not part of the measurement
+                    0x11 => {
+                        payload.push(bytes[0]);
+                    }
+                    0x12 => {
+                        let latency = random() as i16;
+                        payload.extend_from_slice(&bytes);
+                        payload.extend_from_slice(&latency.to_le_bytes());
+                        expected.timing(Timing {
+                            duration_us: value,
+                            start_latency_us: latency,
+                        });
+                    }
+                    0x13 => {
+                        payload.extend_from_slice(&bytes);
+                        expected.secondary_temperature(Temperature(value as i32));
+                    }
+                    0x14 => {
+                        payload.extend_from_slice(&bytes);
+                        expected.secondary_humidity(Humidity(value as i32));
+                    }
+                    _ => unreachable!(),
+                }
+            }
+
+            let mut expected = expected.build().unwrap();
+            let measurement = parse(&payload);
+            expected.timestamp = measurement.timestamp;
+            // The values don't implement PartialEq as a whole
+            assert_eq!(
+                format!("{:?}", measurement),
+                format!("{:?}", expected),
+                "Payload: {:02x?}",
+                payload
+            );
+        }
     }
 }
@@ -0,0 +1,187 @@
+//! Merge measurements that are split across multiple beacon frames.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::measurement::Measurement;
+use crate::types::Address;
+
+/// All frames of a measurement are sent within a single beacon burst. If not
+/// all frames were received after this timeout, the partial measurement is
+/// emitted anyway.
+const MERGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct PendingMeasurement {
+    measurement: Measurement,
+    /// Bitmask of the frame indices that were received
+    received: u16,
+    /// When the first frame was received
+    first_seen: Instant,
+}
+
+impl PendingMeasurement {
+    fn is_complete(&self) -> bool {
+        let count = self.measurement.frame.map_or(1, |f| f.count);
+        (0..count.min(16)).all(|i| self.received & (1 << i) != 0)
+    }
+}
+
+/// Collects the frames of multi-frame measurements until they are complete.
+#[derive(Default)]
+pub struct FrameMerger {
+    pending: HashMap<Address, PendingMeasurement>,
+}
+
+impl FrameMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a (deduplicated) measurement frame.
+    ///
+    /// Return the measurements that are ready to be processed. This includes
+    /// pending measurements of the same device that were superseded by a
+    /// newer counter.
+    pub fn add(&mut self, measurement: Measurement, now: Instant) -> Vec<Measurement> {
+        let mut ready = vec![];
+
+        // Flush incomplete measurements with a different counter
+        if let Some(pending) = self.pending.get(&measurement.address) {
+            if pending.measurement.counter != measurement.counter {
+                let pending = self.pending.remove(&measurement.address).unwrap();
+                log::debug!(
+                    "Incomplete measurement from {} (counter {})",
+                    pending.measurement.address,
+                    pending.measurement.counter
+                );
+                ready.push(pending.measurement);
+            }
+        }
+
+        // Single-frame measurements don't need to be merged
+        let frame = match measurement.frame {
+            Some(frame) if frame.count > 1 => frame,
+            _ => {
+                ready.push(measurement);
+                return ready;
+            }
+        };
+
+        let address = measurement.address;
+        let bit = 1u16.checked_shl(u32::from(frame.index)).unwrap_or(0);
+        match self.pending.get_mut(&address) {
+            Some(pending) => {
+                pending.measurement.merge(measurement);
+                pending.received |= bit;
+            }
+            None => {
+                self.pending.insert(
+                    address,
+                    PendingMeasurement {
+                        measurement,
+                        received: bit,
+                        first_seen: now,
+                    },
+                );
+            }
+        }
+        if self.pending[&address].is_complete() {
+            ready.push(self.pending.remove(&address).unwrap().measurement);
+        }
+
+        ready
+    }
+
+    /// Remove and return all pending measurements that timed out.
+    pub fn expire(&mut self, now: Instant) -> Vec<Measurement> {
+        let expired: Vec<Address> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.first_seen) >= MERGE_TIMEOUT)
+            .map(|(address, _)| *address)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|address| self.pending.remove(&address))
+            .map(|pending| {
+                log::debug!(
+                    "Incomplete measurement from {} (counter {}) timed out",
+                    pending.measurement.address,
+                    pending.measurement.counter
+                );
+                pending.measurement
+            })
+            .collect()
+    }
+
+    /// Remove and return all pending measurements.
+    pub fn drain(&mut self) -> Vec<Measurement> {
+        self.pending
+            .drain()
+            .map(|(_, pending)| pending.measurement)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::MeasurementBuilder;
+
+    fn frame(counter: u16, frame_info: Option<u8>, entry: &[u8]) -> Measurement {
+        let counter = counter.to_le_bytes();
+        let mut payload = vec![counter[0], counter[1]];
+        if let Some(info) = frame_info {
+            payload.extend_from_slice(&[0, 1, info]);
+        }
+        payload.extend_from_slice(entry);
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 100);
+        builder.local_name("Sensilo");
+        builder.parse_payload(&payload).unwrap();
+        builder.build().unwrap()
+    }
+
+    const TEMP: [u8; 5] = [1, 250, 98, 0, 0];
+    const HUMI: [u8; 5] = [2, 230, 192, 0, 0];
+
+    #[test]
+    fn single_frame() {
+        let mut merger = FrameMerger::new();
+        let ready = merger.add(frame(1, None, &TEMP), Instant::now());
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0].temperature.is_some());
+    }
+
+    #[test]
+    fn merge_frames() {
+        let mut merger = FrameMerger::new();
+        let now = Instant::now();
+        assert!(merger.add(frame(1, Some(0x02), &TEMP), now).is_empty());
+        let ready = merger.add(frame(1, Some(0x12), &HUMI), now);
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0].temperature.is_some());
+        assert!(ready[0].humidity.is_some());
+    }
+
+    #[test]
+    fn flush_on_new_counter() {
+        let mut merger = FrameMerger::new();
+        let now = Instant::now();
+        assert!(merger.add(frame(1, Some(0x02), &TEMP), now).is_empty());
+        let ready = merger.add(frame(2, Some(0x12), &HUMI), now);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].counter, 1);
+        assert!(ready[0].humidity.is_none());
+    }
+
+    #[test]
+    fn expire() {
+        let mut merger = FrameMerger::new();
+        let now = Instant::now();
+        assert!(merger.add(frame(1, Some(0x02), &TEMP), now).is_empty());
+        assert!(merger.expire(now).is_empty());
+        let ready = merger.expire(now + MERGE_TIMEOUT);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].counter, 1);
+    }
+}
@@ -0,0 +1,486 @@
+//! Publish measurements to an MQTT broker (MQTT 3.1.1, QoS 0).
+//!
+//! The connection can optionally be secured with TLS, including client
+//! certificate authentication (e.g. for AWS IoT Core).
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::{ClientConfig, ClientSession, StreamOwned};
+
+use crate::availability::Availability;
+use crate::config::{self, Units};
+use crate::json;
+use crate::measurement::Measurement;
+use crate::retry::Timeouts;
+use crate::template::{expand, Devices};
+use crate::types::Address;
+
+/// Control packet types (already shifted into the upper nibble)
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+
+/// Protocol level of MQTT 3.1.1
+const PROTOCOL_LEVEL: u8 = 4;
+
+/// Connect flags
+const FLAG_USERNAME: u8 = 0x80;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_CLEAN_SESSION: u8 = 0x02;
+
+/// Default timeout for connecting, and for every read and write.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Encode the remaining length of a control packet.
+fn encode_remaining_length(mut len: usize, buf: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+/// Encode a length-prefixed string (or binary data).
+fn encode_string(value: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    encode_remaining_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn connect_packet(config: &config::Mqtt) -> Vec<u8> {
+    let mut flags = FLAG_CLEAN_SESSION;
+    if config.username.is_some() {
+        flags |= FLAG_USERNAME;
+    }
+    if config.password.is_some() {
+        flags |= FLAG_PASSWORD;
+    }
+    let mut body = vec![];
+    encode_string(b"MQTT", &mut body);
+    body.push(PROTOCOL_LEVEL);
+    body.push(flags);
+    // Keep alive is disabled, the gateway publishes regularly anyway
+    body.extend_from_slice(&0u16.to_be_bytes());
+    encode_string(config.client_id.as_bytes(), &mut body);
+    if let Some(ref username) = config.username {
+        encode_string(username.as_bytes(), &mut body);
+    }
+    if let Some(ref password) = config.password {
+        encode_string(password.as_bytes(), &mut body);
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    encode_string(topic.as_bytes(), &mut body);
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, &body)
+}
+
+/// Load the TLS client config (root certificates, client certificate and
+/// ALPN protocols).
+fn tls_config(config: &config::MqttTls) -> Result<ClientConfig> {
+    let mut tls = ClientConfig::new();
+    match config.ca_file {
+        Some(ref path) => {
+            let file = File::open(path).with_context(|| format!("Could not open {}", path))?;
+            let (valid, _) = tls
+                .root_store
+                .add_pem_file(&mut BufReader::new(file))
+                .map_err(|_| anyhow!("Invalid CA file {}", path))?;
+            if valid == 0 {
+                bail!("No valid CA certificates in {}", path);
+            }
+        }
+        None => tls
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let file =
+                File::open(cert_path).with_context(|| format!("Could not open {}", cert_path))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .map_err(|_| anyhow!("Invalid client certificate {}", cert_path))?
+                .into_iter()
+                .map(rustls::Certificate)
+                .collect();
+            let key = load_private_key(key_path)?;
+            tls.set_single_client_cert(certs, key)
+                .map_err(|e| anyhow!("Invalid client certificate: {}", e))?;
+        }
+        (None, None) => {}
+        _ => bail!("Both client_cert and client_key must be specified"),
+    }
+
+    tls.alpn_protocols = config
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    Ok(tls)
+}
+
+/// Load a PKCS#8 or RSA private key from a PEM file.
+fn load_private_key(path: &str) -> Result<rustls::PrivateKey> {
+    let read = || -> Result<BufReader<File>> {
+        Ok(BufReader::new(
+            File::open(path).with_context(|| format!("Could not open {}", path))?,
+        ))
+    };
+    let invalid = |_| anyhow!("Invalid private key {}", path);
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut read()?).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut read()?).map_err(invalid)?;
+    }
+    keys.into_iter()
+        .next()
+        .map(rustls::PrivateKey)
+        .ok_or_else(|| anyhow!("No private key found in {}", path))
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientSession, TcpStream>>),
+}
+
+impl Stream {
+    fn get_mut(&mut self) -> &mut dyn ReadWrite {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.as_mut(),
+        }
+    }
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+/// Connect to the first address of the host that accepts the connection.
+fn connect_tcp(host: &str, port: u16, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut result = Err(std::io::ErrorKind::NotFound.into());
+    for addr in (host, port).to_socket_addrs()? {
+        result = TcpStream::connect_timeout(&addr, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Connect to the broker (blocking).
+fn connect(config: &config::Mqtt, tls: Option<&Arc<ClientConfig>>) -> Result<Stream> {
+    let port = config
+        .port
+        .unwrap_or(if tls.is_some() { 8883 } else { 1883 });
+    let timeouts = Timeouts::new(&config.connection, TIMEOUT);
+    let tcp = connect_tcp(&config.host, port, timeouts.connect)
+        .with_context(|| format!("Could not connect to {}:{}", config.host, port))?;
+    tcp.set_read_timeout(Some(timeouts.read))?;
+    tcp.set_write_timeout(Some(timeouts.write))?;
+    let mut stream = match tls {
+        Some(tls) => {
+            let hostname = webpki::DNSNameRef::try_from_ascii_str(&config.host)
+                .map_err(|_| anyhow!("Invalid hostname: {}", config.host))?;
+            let session = ClientSession::new(tls, hostname);
+            Stream::Tls(Box::new(StreamOwned::new(session, tcp)))
+        }
+        None => Stream::Plain(tcp),
+    };
+
+    let io = stream.get_mut();
+    io.write_all(&connect_packet(config))?;
+    io.flush()?;
+    let mut connack = [0; 4];
+    io.read_exact(&mut connack)?;
+    if connack[0] != CONNACK || connack[1] != 2 {
+        bail!("Invalid CONNACK packet");
+    }
+    match connack[3] {
+        0 => Ok(stream),
+        1 => bail!("Connection refused: Unacceptable protocol version"),
+        2 => bail!("Connection refused: Client identifier rejected"),
+        3 => bail!("Connection refused: Server unavailable"),
+        4 => bail!("Connection refused: Bad user name or password"),
+        5 => bail!("Connection refused: Not authorized"),
+        other => bail!("Connection refused: Unknown reason ({})", other),
+    }
+}
+
+pub struct MqttSink {
+    config: config::Mqtt,
+    devices: Devices,
+    tls: Option<Arc<ClientConfig>>,
+    stream: Option<Stream>,
+    /// Only if the availability is published
+    availability: Option<Availability>,
+}
+
+impl MqttSink {
+    /// The addresses must be in the same order as the devices in the config.
+    pub fn new(
+        config: &config::Mqtt,
+        devices: &[config::Device],
+        addresses: &[Address],
+    ) -> Result<Self> {
+        let tls = match config.tls {
+            Some(ref tls) => Some(Arc::new(tls_config(tls)?)),
+            None => None,
+        };
+        let availability = config.availability_topic.as_ref().map(|_| {
+            let timeout = config.offline_after_s.map(Duration::from_secs);
+            Availability::new(devices, addresses, timeout)
+        });
+        Ok(Self {
+            config: config.clone(),
+            devices: Devices::new(devices, addresses),
+            tls,
+            stream: None,
+            availability,
+        })
+    }
+
+    /// Connect to the broker (if not connected yet).
+    pub async fn check(&mut self) -> Result<()> {
+        if self.stream.is_none() {
+            let config = self.config.clone();
+            let tls = self.tls.clone();
+            let stream = smol::unblock(move || connect(&config, tls.as_ref())).await?;
+            self.stream = Some(stream);
+        }
+        Ok(())
+    }
+
+    /// Publish the availability of the devices that came online (with the
+    /// measurements) or went offline (at the instant `now`).
+    pub async fn update_availability(
+        &mut self,
+        measurements: &[Measurement],
+        now: Instant,
+    ) -> Result<()> {
+        let (availability, template) =
+            match (&mut self.availability, &self.config.availability_topic) {
+                (Some(availability), Some(template)) => (availability, template),
+                _ => return Ok(()),
+            };
+        let mut changes = vec![];
+        for mmt in measurements {
+            if availability.seen(mmt.address, &mmt.local_name, now) {
+                changes.push((mmt.address, mmt.local_name.clone(), "online"));
+            }
+        }
+        for (address, local_name) in availability.expire(now) {
+            changes.push((address, local_name, "offline"));
+        }
+        let messages: Vec<(String, String, bool)> = changes
+            .into_iter()
+            .map(|(address, local_name, state)| {
+                let hex = address.to_string();
+                let vars = self
+                    .devices
+                    .vars("availability", &hex, &local_name, address);
+                (expand(template, &vars), state.to_string(), true)
+            })
+            .collect();
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.publish(messages).await
+    }
+
+    /// Publish an event of a device (a JSON object, see `json::reboot`), if
+    /// an event topic is configured.
+    pub async fn publish_event(&mut self, mmt: &Measurement, payload: &str) -> Result<()> {
+        match self.event_message(mmt, payload) {
+            Some(message) => self.publish(vec![message]).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The topic, payload and retain flag of an event (which is not
+    /// retained, it would be repeated to every new subscriber).
+    fn event_message(&self, mmt: &Measurement, payload: &str) -> Option<(String, String, bool)> {
+        let template = self.config.event_topic.as_ref()?;
+        let address = mmt.address.to_string();
+        let vars = self
+            .devices
+            .vars("event", &address, &mmt.local_name, mmt.address);
+        Some((expand(template, &vars), payload.to_string(), false))
+    }
+
+    /// Publish measurements as JSON objects.
+    pub async fn submit(&mut self, measurements: &[Measurement], units: &Units) -> Result<()> {
+        let messages: Vec<(String, String, bool)> = measurements
+            .iter()
+            .map(|mmt| {
+                let address = mmt.address.to_string();
+                let vars = self
+                    .devices
+                    .vars("measurement", &address, &mmt.local_name, mmt.address);
+                (
+                    expand(&self.config.topic, &vars),
+                    json::measurement(mmt, units),
+                    self.config.retain,
+                )
+            })
+            .collect();
+        self.publish(messages).await
+    }
+
+    /// Publish messages (topic, payload and retain flag).
+    async fn publish(&mut self, messages: Vec<(String, String, bool)>) -> Result<()> {
+        let config = self.config.clone();
+        let tls = self.tls.clone();
+        let stream = self.stream.take();
+        // The connection is blocking, so it is moved to a thread pool
+        let (stream, result) = smol::unblock(move || {
+            let mut stream = match stream {
+                Some(stream) => stream,
+                None => match connect(&config, tls.as_ref()) {
+                    Ok(stream) => stream,
+                    Err(e) => return (None, Err(e)),
+                },
+            };
+            let io = stream.get_mut();
+            for (topic, payload, retain) in &messages {
+                let packet = publish_packet(topic, payload.as_bytes(), *retain);
+                if let Err(e) = io.write_all(&packet) {
+                    return (None, Err(e.into()));
+                }
+            }
+            match io.flush() {
+                Ok(()) => (Some(stream), Ok(())),
+                Err(e) => (None, Err(e.into())),
+            }
+        })
+        .await;
+        self.stream = stream;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::MeasurementBuilder;
+
+    fn config() -> config::Mqtt {
+        config::Mqtt {
+            host: "localhost".into(),
+            port: None,
+            client_id: "gw".into(),
+            username: Some("u".into()),
+            password: Some("p".into()),
+            topic: "sensilo/{name}".into(),
+            retain: false,
+            tls: None,
+            min_interval_s: None,
+            availability_topic: None,
+            offline_after_s: None,
+            event_topic: None,
+            connection: Default::default(),
+        }
+    }
+
+    #[test]
+    fn remaining_length() {
+        for (len, expected) in &[
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xff, 0x7f]),
+            (2_097_152, vec![0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let mut buf = vec![];
+            encode_remaining_length(*len, &mut buf);
+            assert_eq!(&buf, expected);
+        }
+    }
+
+    #[test]
+    fn connect() {
+        #[rustfmt::skip]
+        let expected = vec![
+            0x10, 20,
+            0, 4, b'M', b'Q', b'T', b'T', 4, 0xc2, 0, 0,
+            0, 2, b'g', b'w',
+            0, 1, b'u',
+            0, 1, b'p',
+        ];
+        assert_eq!(connect_packet(&config()), expected);
+    }
+
+    #[test]
+    fn publish() {
+        assert_eq!(
+            publish_packet("a/b", b"{}", true),
+            vec![0x31, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']
+        );
+    }
+
+    #[test]
+    fn event() {
+        let address = Address([1, 2, 3, 4, 5, 6]);
+        let devices = [config::Device {
+            name: "Living room".into(),
+            hex_addr: "010203040506".into(),
+            location: None,
+            site: None,
+            interval_s: None,
+            protocol: config::Protocol::Sensilo,
+            analog: vec![],
+            expects: vec![],
+            key: None,
+            crc: false,
+            irk: None,
+            min_rssi: None,
+        }];
+        let mut builder = MeasurementBuilder::new(address, 200);
+        builder.local_name("Sensilo").counter(3);
+        let mmt = builder.build().unwrap();
+        let payload = json::reboot(&mmt, 1000, Duration::from_secs(30));
+
+        let sink = MqttSink::new(&config(), &devices, &[address]).unwrap();
+        assert_eq!(sink.event_message(&mmt, &payload), None);
+
+        let config = config::Mqtt {
+            event_topic: Some("sensilo/{name}/event".into()),
+            ..config()
+        };
+        let sink = MqttSink::new(&config, &devices, &[address]).unwrap();
+        let (topic, message, retain) = sink.event_message(&mmt, &payload).unwrap();
+        assert_eq!(topic, "sensilo/Living room/event");
+        assert_eq!(message, payload);
+        assert!(!retain);
+    }
+
+    #[test]
+    fn tls_requires_key_and_cert() {
+        let tls = config::MqttTls {
+            ca_file: None,
+            client_cert: Some("client.pem".into()),
+            client_key: None,
+            alpn: vec![],
+        };
+        assert!(tls_config(&tls).is_err());
+    }
+}
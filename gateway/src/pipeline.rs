@@ -0,0 +1,750 @@
+//! Process received measurements and send them to the sinks.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::aggregate::Aggregator;
+use crate::api;
+use crate::backfill::Backfill;
+use crate::channels::ChannelCounts;
+#[cfg(feature = "sink-influxdb")]
+use crate::clock::SyncMonitor;
+use crate::config::{self, Units};
+use crate::contacts::ContactTracker;
+use crate::dedup::DedupStats;
+use crate::delta::DeltaDecoder;
+use crate::display;
+use crate::exec::ExecSink;
+use crate::expectations::{Expectation, Expectations};
+use crate::gaps::{CounterEvent, GapDetector};
+use crate::graphite::GraphiteSink;
+#[cfg(feature = "sink-influxdb")]
+use crate::http;
+#[cfg(feature = "sink-influxdb")]
+use crate::influxdb::InfluxDbSink;
+use crate::influxdb::{self, Point};
+use crate::json;
+use crate::measurement::Measurement;
+#[cfg(feature = "sink-mqtt")]
+use crate::mqtt::MqttSink;
+use crate::postgres::PostgresSink;
+use crate::pulses::PulseRates;
+use crate::queue::{Queue, Sink};
+use crate::ratelimit::RateLimiter;
+use crate::retry::Retry;
+#[cfg(feature = "sink-influxdb")]
+use crate::retry::Timeouts;
+use crate::smooth::Smoother;
+use crate::state::{Clock, Snapshot};
+use crate::summary::Summaries;
+use crate::types::Address;
+
+pub struct Pipeline<'a> {
+    config: &'a config::Config,
+    gap_detector: GapDetector,
+    backfill: Backfill,
+    deltas: DeltaDecoder,
+    pulse_rates: PulseRates,
+    contacts: ContactTracker,
+    expectations: Expectations,
+    dedup_stats: HashMap<Address, DedupStats>,
+    verification_failures: HashMap<Address, u64>,
+    rssi_filtered: HashMap<Address, u64>,
+    channel_counts: HashMap<Address, ChannelCounts>,
+    /// Metric names of the analog inputs, by device and input number
+    analog_names: HashMap<Address, HashMap<u8, String>>,
+    smoother: Smoother,
+    summaries: Summaries,
+    aggregator: Aggregator,
+    #[cfg(feature = "sink-influxdb")]
+    influxdb: Option<(Queue<Vec<Point>>, RateLimiter)>,
+    /// Checks the host clock if the InfluxDB points are tagged with
+    /// `approx_time`
+    #[cfg(feature = "sink-influxdb")]
+    clock: Option<SyncMonitor>,
+    /// Whether the host clock was not synchronized at the reception of the
+    /// last measurement
+    approx_time: bool,
+    exec: Option<(Queue<Vec<String>>, RateLimiter)>,
+    postgres: Option<(Queue<PostgresBatch>, RateLimiter)>,
+    graphite: Option<(Queue<Vec<Point>>, RateLimiter)>,
+    #[cfg(feature = "sink-mqtt")]
+    mqtt: Option<(Queue<MqttBatch>, RateLimiter)>,
+    api: Option<api::Server>,
+}
+
+impl<'a> Pipeline<'a> {
+    /// Create a new pipeline. The addresses must be in the same order as the
+    /// devices in the config.
+    pub fn new(config: &'a config::Config, addresses: &[Address]) -> Result<Self> {
+        let mut gap_detector = GapDetector::new(
+            config
+                .stats
+                .loss_windows_s
+                .iter()
+                .map(|&window| Duration::from_secs(window))
+                .collect(),
+        );
+        let mut pulse_rates = PulseRates::new();
+        let mut analog_names = HashMap::new();
+        let mut expectations = Expectations::new();
+        for (dev, address) in config.devices.iter().zip(addresses) {
+            if !dev.expects.is_empty() {
+                expectations.set_expected(*address, dev.expects.clone());
+            }
+            let names = dev
+                .analog
+                .iter()
+                .map(|input| (input.channel, input.name.clone()))
+                .collect();
+            analog_names.insert(*address, names);
+            if let Some(interval_s) = dev.interval_s {
+                gap_detector.set_interval(*address, Duration::from_secs(interval_s));
+                pulse_rates.set_interval(*address, Duration::from_secs(interval_s));
+            }
+        }
+
+        #[cfg(feature = "sink-influxdb")]
+        let influxdb = match config.influxdb {
+            Some(ref influxdb) => {
+                let default = Duration::from_secs(config.http.timeout_s);
+                let timeouts = Timeouts::new(&influxdb.connection, default);
+                let client = http::Client::new(&config.http, timeouts)?;
+                let sink = InfluxDbSink::new(influxdb, client, &config.devices, addresses)?;
+                Some((
+                    Queue::spawn("InfluxDB", sink, Retry::new(&influxdb.connection))?,
+                    RateLimiter::new(influxdb.min_interval_s.map(Duration::from_secs)),
+                ))
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "sink-influxdb"))]
+        if config.influxdb.is_some() {
+            anyhow::bail!(
+                "The InfluxDB sink is not included in this build (feature sink-influxdb)"
+            );
+        }
+        let exec = match config.exec {
+            // Failed commands are not retried
+            Some(ref exec) => Some((
+                Queue::spawn(
+                    "Exec",
+                    ExecSink::new(exec)?,
+                    Retry::new(&Default::default()),
+                )?,
+                RateLimiter::new(exec.min_interval_s.map(Duration::from_secs)),
+            )),
+            None => None,
+        };
+        let postgres = match config.postgres {
+            Some(ref postgres) => Some((
+                Queue::spawn(
+                    "PostgreSQL",
+                    PostgresSink::new(postgres)?,
+                    Retry::new(&postgres.connection),
+                )?,
+                RateLimiter::new(postgres.min_interval_s.map(Duration::from_secs)),
+            )),
+            None => None,
+        };
+        #[cfg(feature = "sink-mqtt")]
+        let mqtt = match config.mqtt {
+            Some(ref mqtt) => Some((
+                Queue::spawn(
+                    "MQTT",
+                    MqttSink::new(mqtt, &config.devices, addresses)?,
+                    Retry::new(&mqtt.connection),
+                )?,
+                RateLimiter::new(mqtt.min_interval_s.map(Duration::from_secs)),
+            )),
+            None => None,
+        };
+        #[cfg(not(feature = "sink-mqtt"))]
+        if config.mqtt.is_some() {
+            anyhow::bail!("The MQTT sink is not included in this build (feature sink-mqtt)");
+        }
+        let graphite = match config.graphite {
+            Some(ref graphite) => Some((
+                Queue::spawn(
+                    "Graphite",
+                    GraphiteSink::new(graphite, &config.devices, addresses),
+                    Retry::new(&graphite.connection),
+                )?,
+                RateLimiter::new(graphite.min_interval_s.map(Duration::from_secs)),
+            )),
+            None => None,
+        };
+
+        Ok(Self {
+            config,
+            gap_detector,
+            backfill: Backfill::new(),
+            deltas: DeltaDecoder::new(),
+            pulse_rates,
+            contacts: ContactTracker::new(),
+            expectations,
+            dedup_stats: HashMap::new(),
+            verification_failures: HashMap::new(),
+            rssi_filtered: HashMap::new(),
+            channel_counts: HashMap::new(),
+            analog_names,
+            smoother: Smoother::new(config.smoothing.as_ref())?,
+            summaries: Summaries::new(config.summary.as_ref()),
+            aggregator: Aggregator::new(config.aggregation.as_ref()),
+            #[cfg(feature = "sink-influxdb")]
+            influxdb,
+            #[cfg(feature = "sink-influxdb")]
+            clock: config
+                .influxdb
+                .as_ref()
+                .filter(|influxdb| influxdb.tag_approx_time)
+                .map(|_| SyncMonitor::new()),
+            approx_time: false,
+            exec,
+            postgres,
+            graphite,
+            #[cfg(feature = "sink-mqtt")]
+            mqtt,
+            api: None,
+        })
+    }
+
+    /// Check the connections to the configured sinks (see the `check` methods
+    /// of the sinks) and print the results. Return whether all sinks are
+    /// reachable.
+    pub async fn check(&mut self) -> bool {
+        let mut results = vec![];
+        #[cfg(feature = "sink-influxdb")]
+        if let Some((ref influxdb, _)) = self.influxdb {
+            results.push(("InfluxDB", influxdb.check().await));
+        }
+        if let Some((ref exec, _)) = self.exec {
+            results.push(("Exec", exec.check().await));
+        }
+        if let Some((ref postgres, _)) = self.postgres {
+            results.push(("PostgreSQL", postgres.check().await));
+        }
+        #[cfg(feature = "sink-mqtt")]
+        if let Some((ref mqtt, _)) = self.mqtt {
+            results.push(("MQTT", mqtt.check().await));
+        }
+        if let Some((ref graphite, _)) = self.graphite {
+            results.push(("Graphite", graphite.check().await));
+        }
+        if results.is_empty() {
+            status!("No sinks configured");
+        }
+        let mut ok = true;
+        for (sink, result) in results {
+            match result {
+                Ok(()) => status!("  - {}: OK", sink),
+                Err(e) => {
+                    status!("  - {}: {:#}", sink, e);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    /// Add the device state (last counters, contacts, pulse counts and
+    /// missing metrics) to a snapshot.
+    pub fn snapshot(&self, snapshot: &mut Snapshot, clock: &Clock) {
+        for (address, counter, instant) in self.gap_detector.last_seen() {
+            let state = snapshot.devices.entry(address).or_default();
+            state.counter = Some((counter, clock.system_time(instant)));
+        }
+        for (address, events) in self.contacts.events() {
+            snapshot.devices.entry(address).or_default().contact_events = Some(events);
+        }
+        for (address, count, instant) in self.pulse_rates.last() {
+            let state = snapshot.devices.entry(address).or_default();
+            state.pulses = Some((count, clock.system_time(instant)));
+        }
+        for (address, missing) in self.expectations.missing() {
+            if !missing.is_empty() {
+                let state = snapshot.devices.entry(address).or_default();
+                state.missing = missing.iter().copied().collect();
+            }
+        }
+    }
+
+    /// Restore the device state from a snapshot, so that e.g. a reboot or a
+    /// missing metric is not reported again after a restart. Times before the
+    /// boot of the host can't be restored.
+    pub fn restore(&mut self, snapshot: &Snapshot, clock: &Clock) {
+        for (address, state) in &snapshot.devices {
+            if let Some((counter, time)) = state.counter {
+                match clock.instant(time) {
+                    Some(instant) => self.gap_detector.restore(*address, counter, instant),
+                    None => log::debug!("Not restoring the counter of {}: Too old", address),
+                }
+            }
+            if let Some(events) = state.contact_events {
+                self.contacts.restore(*address, events);
+            }
+            if let Some((count, time)) = state.pulses {
+                if let Some(instant) = clock.instant(time) {
+                    self.pulse_rates.restore(*address, count, instant);
+                }
+            }
+            if !state.missing.is_empty() {
+                self.expectations
+                    .restore(*address, state.missing.iter().copied());
+            }
+        }
+    }
+
+    /// Update the deduplication statistics of a device.
+    pub fn set_dedup_stats(&mut self, address: Address, stats: DedupStats) {
+        self.dedup_stats.insert(address, stats);
+    }
+
+    /// Publish the accepted measurements on the API.
+    pub fn set_api(&mut self, server: api::Server) {
+        self.api = Some(server);
+    }
+
+    /// Don't check the host clock: For imported captures, its current state
+    /// says nothing about the timestamps of the capture.
+    pub fn ignore_host_clock(&mut self) {
+        #[cfg(feature = "sink-influxdb")]
+        {
+            self.clock = None;
+        }
+    }
+
+    /// Update the number of failed payload verifications of a device.
+    pub fn set_verification_failures(&mut self, address: Address, failures: u64) {
+        self.verification_failures.insert(address, failures);
+    }
+
+    /// Update the number of frames of a device that were below the RSSI
+    /// threshold.
+    pub fn set_rssi_filtered(&mut self, address: Address, filtered: u64) {
+        self.rssi_filtered.insert(address, filtered);
+    }
+
+    /// Update the number of frames per advertising channel of a device.
+    pub fn set_channel_counts(&mut self, address: Address, counts: ChannelCounts) {
+        self.channel_counts.insert(address, counts);
+    }
+
+    /// Handle a received (and merged) measurement. `now` is the time of
+    /// reception (which is in the past when importing a capture).
+    pub async fn handle_measurement(&mut self, mut measurement: Measurement, now: Instant) {
+        // The state of the clock when the timestamp was taken (not when the
+        // points are sent, which may be much later after retries)
+        #[cfg(feature = "sink-influxdb")]
+        if let Some(ref mut clock) = self.clock {
+            self.approx_time = clock.is_approximate(now).await;
+        }
+        measurement.approx_time = self.approx_time;
+
+        // Reconstruct the values of delta-encoded measurements
+        if !self.deltas.apply(&mut measurement) {
+            log::info!(
+                "{}: Missed the full measurement of delta {}, dropping the temperature and humidity",
+                measurement.local_name,
+                measurement.counter
+            );
+        }
+        status!(
+            "{}",
+            display::measurement(&measurement, &self.config.display, &self.config.units)
+        );
+
+        // Detect missed beacons and reboots
+        let mut points = vec![];
+        match self
+            .gap_detector
+            .update(measurement.address, measurement.counter, now)
+        {
+            Some(CounterEvent::Gap(gap)) => {
+                status!(
+                    "{}: Missed {} beacon(s) in {:.1} s",
+                    measurement.local_name,
+                    gap.missed,
+                    gap.elapsed.as_secs_f32()
+                );
+                points.extend(influxdb::gap_points(&measurement, &gap));
+                self.backfill.add_gap(&measurement, &gap);
+            }
+            Some(CounterEvent::Reboot {
+                previous_counter,
+                elapsed,
+            }) => {
+                status!(
+                    "{}: Device rebooted (counter {} -> {}, last seen {:.1} s ago)",
+                    measurement.local_name,
+                    previous_counter,
+                    measurement.counter,
+                    elapsed.as_secs_f32()
+                );
+                points.extend(influxdb::reboot_points(
+                    &measurement,
+                    previous_counter,
+                    elapsed,
+                ));
+                #[cfg(feature = "sink-mqtt")]
+                if let Some((ref mqtt, _)) = self.mqtt {
+                    let payload = json::reboot(&measurement, previous_counter, elapsed);
+                    mqtt.push(MqttBatch::Event(Box::new(measurement.clone()), payload));
+                }
+                self.backfill.reset(measurement.address);
+            }
+            None => {}
+        }
+        // Pass a missed measurement from the backlog of the device
+        if let Some(backfilled) = self.backfill.take(&measurement) {
+            status!(
+                "{}: Recovered measurement {} from the backlog",
+                backfilled.local_name,
+                backfilled.counter
+            );
+            self.submit(vec![], vec![backfilled], now).await;
+        }
+        // Detect missing metrics (e.g. failed sensors)
+        for expectation in self.expectations.update(&measurement) {
+            match expectation {
+                Expectation::Missing(metric) => status!(
+                    "{}: Expected metric {} is missing",
+                    measurement.local_name,
+                    metric.name()
+                ),
+                Expectation::Restored(metric) => status!(
+                    "{}: Expected metric {} is back",
+                    measurement.local_name,
+                    metric.name()
+                ),
+            }
+            points.extend(influxdb::expectation_points(&measurement, &expectation));
+        }
+
+        // Calculate the pulse rate
+        if let Some(ref pulses) = measurement.pulses {
+            measurement.pulse_rate = self.pulse_rates.update(measurement.address, pulses, now);
+        }
+
+        // Name the analog inputs
+        if let Some(names) = self.analog_names.get(&measurement.address) {
+            for (channel, input) in measurement.analog.iter_mut() {
+                input.name = names.get(channel).cloned();
+            }
+        }
+
+        self.smoother.apply(&mut measurement);
+        points.extend(self.summaries.add(&measurement, &self.config.units));
+
+        // Contact state changes are sent immediately (bypassing aggregation
+        // and rate limiting)
+        if let Some(contact) = measurement.contact {
+            if self.contacts.update(measurement.address, &contact) {
+                status!(
+                    "{}: Contact {} (event {})",
+                    measurement.local_name,
+                    if contact.open { "opened" } else { "closed" },
+                    contact.events
+                );
+                measurement.contact_changed = true;
+                points.extend(influxdb::contact_points(&measurement, &contact));
+                self.submit(points, vec![measurement], now).await;
+                return;
+            }
+        }
+
+        let measurements = self.aggregator.add(measurement, now);
+        self.submit(points, measurements, now).await;
+    }
+
+    /// Submit the aggregated measurements of all windows that have ended.
+    ///
+    /// Called on every tick of the main loop, so that the window of a device
+    /// that stopped sending is still submitted (at most a tick late).
+    pub async fn expire(&mut self, now: Instant) {
+        let measurements = self.aggregator.expire(now);
+        self.submit(vec![], measurements, now).await;
+    }
+
+    /// Submit all pending aggregated measurements, and wait until the sinks
+    /// have processed their queues.
+    pub async fn drain(&mut self, now: Instant) {
+        let measurements = self.aggregator.drain();
+        self.submit(vec![], measurements, now).await;
+        #[cfg(feature = "sink-influxdb")]
+        if let Some((ref influxdb, _)) = self.influxdb {
+            influxdb.flush().await;
+        }
+        if let Some((ref exec, _)) = self.exec {
+            exec.flush().await;
+        }
+        if let Some((ref postgres, _)) = self.postgres {
+            postgres.flush().await;
+        }
+        #[cfg(feature = "sink-mqtt")]
+        if let Some((ref mqtt, _)) = self.mqtt {
+            mqtt.flush().await;
+        }
+        if let Some((ref graphite, _)) = self.graphite {
+            graphite.flush().await;
+        }
+    }
+
+    /// Submit (possibly aggregated) measurements to all sinks, along with
+    /// event points (e.g. gaps and reboots) for InfluxDB and Graphite. The
+    /// batches are only queued, the sinks submit them on their own threads.
+    async fn submit(
+        &mut self,
+        event_points: Vec<Point>,
+        measurements: Vec<Measurement>,
+        now: Instant,
+    ) {
+        // Live stream of the API (not rate limited)
+        let units = &self.config.units;
+        if let Some(ref api) = self.api {
+            for measurement in &measurements {
+                api.publish(measurement, units);
+            }
+        }
+
+        // Exec sink
+        if let Some((ref exec, ref mut limiter)) = self.exec {
+            let lines: Vec<String> = measurements
+                .iter()
+                .filter(|measurement| {
+                    bypasses_rate_limit(measurement) || limiter.allow(measurement.address, now)
+                })
+                .map(|measurement| json::measurement(measurement, units))
+                .collect();
+            if !lines.is_empty() {
+                exec.push(lines);
+            }
+        }
+
+        // PostgreSQL
+        if let Some((ref postgres, ref mut limiter)) = self.postgres {
+            let allowed: Vec<Measurement> = measurements
+                .iter()
+                .filter(|measurement| {
+                    bypasses_rate_limit(measurement) || limiter.allow(measurement.address, now)
+                })
+                .cloned()
+                .collect();
+            if !allowed.is_empty() {
+                postgres.push((allowed, *units));
+            }
+        }
+
+        // MQTT
+        #[cfg(feature = "sink-mqtt")]
+        if let Some((ref mqtt, ref mut limiter)) = self.mqtt {
+            // Backfilled measurements are from the past
+            let live: Vec<Measurement> = measurements
+                .iter()
+                .filter(|measurement| !measurement.backfilled)
+                .cloned()
+                .collect();
+            mqtt.push(MqttBatch::Availability(live, now));
+            let allowed: Vec<Measurement> = measurements
+                .iter()
+                .filter(|measurement| {
+                    bypasses_rate_limit(measurement) || limiter.allow(measurement.address, now)
+                })
+                .cloned()
+                .collect();
+            if !allowed.is_empty() {
+                mqtt.push(MqttBatch::Measurements(allowed, *units));
+            }
+        }
+
+        // Measurements that bypass the rate limiting
+        let urgent: Vec<Address> = measurements
+            .iter()
+            .filter(|measurement| bypasses_rate_limit(measurement))
+            .map(|measurement| measurement.address)
+            .collect();
+
+        // Measurement and packet loss points, used by InfluxDB and Graphite
+        let measurement_points: Vec<(Address, Vec<Point>)> = measurements
+            .iter()
+            .map(|measurement| {
+                let mut points = influxdb::measurement_points(measurement, units);
+                // The statistics are only current for live measurements
+                if measurement.backfilled {
+                    return (measurement.address, points);
+                }
+                let losses = self.gap_detector.loss(measurement.address, now);
+                for loss in &losses {
+                    log::debug!(
+                        "Packet loss of {} in the last {} s: {:.1} % ({} received, {} missed)",
+                        measurement.local_name,
+                        loss.window.as_secs(),
+                        loss.as_percent(),
+                        loss.received,
+                        loss.missed
+                    );
+                }
+                points.extend(influxdb::loss_points(measurement, &losses));
+                if let Some(stats) = self.dedup_stats.get(&measurement.address) {
+                    log::debug!(
+                        "Deduplication of {}: {} frames accepted, {} duplicates",
+                        measurement.local_name,
+                        stats.accepted,
+                        stats.duplicates
+                    );
+                    points.extend(influxdb::dedup_points(measurement, stats));
+                }
+                if let Some(&failures) = self.verification_failures.get(&measurement.address) {
+                    points.extend(influxdb::verification_points(measurement, failures));
+                }
+                if let Some(&filtered) = self.rssi_filtered.get(&measurement.address) {
+                    points.extend(influxdb::rssi_filter_points(measurement, filtered));
+                }
+                if let Some(counts) = self.channel_counts.get(&measurement.address) {
+                    log::debug!(
+                        "Frames of {} per channel: {:?}",
+                        measurement.local_name,
+                        counts.iter().collect::<Vec<_>>()
+                    );
+                    points.extend(influxdb::channel_points(measurement, counts));
+                }
+                (measurement.address, points)
+            })
+            .collect();
+
+        // Graphite / StatsD
+        if let Some((ref graphite, ref mut limiter)) = self.graphite {
+            let mut graphite_points = event_points.clone();
+            for (address, points) in &measurement_points {
+                if urgent.contains(address) || limiter.allow(*address, now) {
+                    graphite_points.extend(points.iter().cloned());
+                }
+            }
+            if !graphite_points.is_empty() {
+                graphite.push(graphite_points);
+            }
+        }
+
+        // InfluxDB
+        #[cfg(feature = "sink-influxdb")]
+        if let Some((ref influxdb, ref mut limiter)) = self.influxdb {
+            // The events are from the last received measurement
+            let mut points = event_points;
+            if self.approx_time {
+                influxdb::tag_approx_time(&mut points);
+            }
+            // The measurement points are in the order of the measurements
+            for (measurement, (address, measurement_points)) in
+                measurements.iter().zip(measurement_points)
+            {
+                if !urgent.contains(&address) && !limiter.allow(address, now) {
+                    log::debug!(
+                        "Not sending measurement of {} to InfluxDB (rate limited)",
+                        address
+                    );
+                    continue;
+                }
+                let start = points.len();
+                points.extend(measurement_points);
+                points.extend(influxdb::unknown_points(measurement));
+                if measurement.approx_time {
+                    influxdb::tag_approx_time(&mut points[start..]);
+                }
+            }
+            if !points.is_empty() {
+                influxdb.push(points);
+            }
+        }
+    }
+}
+
+/// Contact changes and backfilled measurements are sent to all sinks, even if
+/// they are rate limited.
+fn bypasses_rate_limit(measurement: &Measurement) -> bool {
+    measurement.contact_changed || measurement.backfilled
+}
+
+#[cfg(feature = "sink-influxdb")]
+impl Sink for InfluxDbSink {
+    type Batch = Vec<Point>;
+
+    async fn check(&mut self) -> Result<()> {
+        InfluxDbSink::check(self).await
+    }
+
+    async fn submit(&mut self, points: &Vec<Point>) -> Result<()> {
+        InfluxDbSink::submit(self, points).await?;
+        log::info!("Measurement submitted");
+        Ok(())
+    }
+}
+
+impl Sink for ExecSink {
+    type Batch = Vec<String>;
+
+    async fn check(&mut self) -> Result<()> {
+        ExecSink::check(self).await
+    }
+
+    async fn submit(&mut self, lines: &Vec<String>) -> Result<()> {
+        ExecSink::submit(self, lines).await
+    }
+}
+
+/// The measurements, and the units of their rows.
+pub type PostgresBatch = (Vec<Measurement>, Units);
+
+impl Sink for PostgresSink {
+    type Batch = PostgresBatch;
+
+    async fn check(&mut self) -> Result<()> {
+        PostgresSink::check(self).await
+    }
+
+    async fn submit(&mut self, (measurements, units): &Self::Batch) -> Result<()> {
+        PostgresSink::submit(self, measurements, units).await
+    }
+}
+
+/// The MQTT sink publishes the availability of the devices (which also
+/// changes without measurements), the measurements and the events.
+#[cfg(feature = "sink-mqtt")]
+pub enum MqttBatch {
+    /// The live measurements, and the time of the update
+    Availability(Vec<Measurement>, Instant),
+    Measurements(Vec<Measurement>, Units),
+    /// An event of the device of the measurement (JSON object)
+    Event(Box<Measurement>, String),
+}
+
+#[cfg(feature = "sink-mqtt")]
+impl Sink for MqttSink {
+    type Batch = MqttBatch;
+
+    async fn check(&mut self) -> Result<()> {
+        MqttSink::check(self).await
+    }
+
+    async fn submit(&mut self, batch: &MqttBatch) -> Result<()> {
+        match batch {
+            MqttBatch::Availability(live, now) => self.update_availability(live, *now).await,
+            MqttBatch::Measurements(measurements, units) => {
+                MqttSink::submit(self, measurements, units).await
+            }
+            MqttBatch::Event(mmt, payload) => self.publish_event(mmt, payload).await,
+        }
+    }
+}
+
+impl Sink for GraphiteSink {
+    type Batch = Vec<Point>;
+
+    async fn check(&mut self) -> Result<()> {
+        GraphiteSink::check(self).await
+    }
+
+    async fn submit(&mut self, points: &Vec<Point>) -> Result<()> {
+        GraphiteSink::submit(self, points).await
+    }
+}
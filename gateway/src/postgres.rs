@@ -0,0 +1,954 @@
+//! Write measurements to PostgreSQL (or TimescaleDB).
+//!
+//! This is a minimal client for the PostgreSQL frontend/backend protocol
+//! (version 3). The measurements are inserted with the extended query
+//! protocol (with the values as bound parameters), the schema is created with
+//! the simple query protocol. Only cleartext or SCRAM-SHA-256 password
+//! authentication is supported. TLS is not supported.
+//!
+//! With a retention, old measurements are deleted after an insert, at most
+//! once per `PRUNE_INTERVAL`. This runs on the thread of the sink, so it
+//! doesn't delay the capture. With compaction, the measurements of every
+//! complete hour are first aggregated into the hourly table, in the same
+//! transaction.
+use std::convert::TryInto;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use ring::{digest, hmac, pbkdf2, rand::SecureRandom};
+use smol::net::TcpStream;
+
+use crate::config::{self, Units};
+use crate::json;
+use crate::measurement::Measurement;
+use crate::retry::{timeout, Timeouts};
+use crate::units;
+
+/// Protocol version 3.0
+const PROTOCOL_VERSION: i32 = 196_608;
+
+/// Authentication request types
+const AUTH_OK: i32 = 0;
+const AUTH_CLEARTEXT_PASSWORD: i32 = 3;
+const AUTH_MD5_PASSWORD: i32 = 5;
+const AUTH_SASL: i32 = 10;
+const AUTH_SASL_CONTINUE: i32 = 11;
+const AUTH_SASL_FINAL: i32 = 12;
+
+const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// A backend message.
+struct Message {
+    tag: u8,
+    body: Vec<u8>,
+}
+
+/// Encode a frontend message.
+fn encode_message(tag: Option<u8>, body: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(body.len() + 5);
+    msg.extend(tag);
+    msg.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    msg.extend_from_slice(body);
+    msg
+}
+
+fn startup_message(user: &str, database: &str) -> Vec<u8> {
+    let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+    for value in &["user", user, "database", database] {
+        body.extend_from_slice(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+    encode_message(None, &body)
+}
+
+fn query_message(sql: &str) -> Vec<u8> {
+    let mut body = sql.as_bytes().to_vec();
+    body.push(0);
+    encode_message(Some(b'Q'), &body)
+}
+
+/// Messages that execute a statement with parameters (in text format, `None`
+/// is NULL) with the unnamed statement and portal: Parse, Bind and Execute.
+fn execute_messages(sql: &str, params: &[Option<String>]) -> Vec<u8> {
+    let mut parse = vec![0];
+    parse.extend_from_slice(sql.as_bytes());
+    parse.push(0);
+    // The parameter types are inferred
+    parse.extend_from_slice(&0i16.to_be_bytes());
+
+    let mut bind = vec![0, 0];
+    // All parameters in text format
+    bind.extend_from_slice(&0i16.to_be_bytes());
+    bind.extend_from_slice(&(params.len() as i16).to_be_bytes());
+    for param in params {
+        match param {
+            Some(value) => {
+                bind.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                bind.extend_from_slice(value.as_bytes());
+            }
+            None => bind.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+    // All results in text format
+    bind.extend_from_slice(&0i16.to_be_bytes());
+
+    // Unnamed portal, no row limit
+    let execute = [0, 0, 0, 0, 0];
+
+    let mut messages = encode_message(Some(b'P'), &parse);
+    messages.extend(encode_message(Some(b'B'), &bind));
+    messages.extend(encode_message(Some(b'E'), &execute));
+    messages
+}
+
+/// Extract the human readable message from an error response.
+fn error_message(body: &[u8]) -> String {
+    body.split(|&b| b == 0)
+        .find(|field| field.first() == Some(&b'M'))
+        .map(|field| String::from_utf8_lossy(&field[1..]).into_owned())
+        .unwrap_or_else(|| "Unknown error".into())
+}
+
+/// SCRAM-SHA-256 client (RFC 5802 / RFC 7677).
+struct Scram {
+    password: String,
+    client_first_bare: String,
+    nonce: String,
+    /// Salted password and auth message, after the server first message
+    state: Option<(Vec<u8>, String)>,
+}
+
+impl Scram {
+    fn new(user: &str, password: &str, nonce: &str) -> Self {
+        Self {
+            password: password.to_string(),
+            client_first_bare: format!("n={},r={}", user, nonce),
+            nonce: nonce.to_string(),
+            state: None,
+        }
+    }
+
+    fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    fn client_final(&mut self, server_first: &str) -> Result<String> {
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for attr in server_first.split(',') {
+            match attr.split_at(attr.find('=').map_or(0, |i| i + 1)) {
+                ("r=", value) => nonce = Some(value),
+                ("s=", value) => salt = Some(base64::decode(value)?),
+                ("i=", value) => iterations = Some(value.parse::<u32>()?),
+                _ => {}
+            }
+        }
+        let nonce = nonce.ok_or_else(|| anyhow!("Missing SCRAM nonce"))?;
+        let salt = salt.ok_or_else(|| anyhow!("Missing SCRAM salt"))?;
+        let iterations = iterations
+            .and_then(NonZeroU32::new)
+            .ok_or_else(|| anyhow!("Invalid SCRAM iteration count"))?;
+        if !nonce.starts_with(&self.nonce) {
+            bail!("Invalid SCRAM server nonce");
+        }
+
+        let mut salted_password = [0; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            self.password.as_bytes(),
+            &mut salted_password,
+        );
+        let salted_key = hmac::Key::new(hmac::HMAC_SHA256, &salted_password);
+        let client_key = hmac::sign(&salted_key, b"Client Key");
+        let stored_key = digest::digest(&digest::SHA256, client_key.as_ref());
+
+        let client_final_without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final_without_proof
+        );
+        let signature = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, stored_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        let proof: Vec<u8> = client_key
+            .as_ref()
+            .iter()
+            .zip(signature.as_ref())
+            .map(|(a, b)| a ^ b)
+            .collect();
+
+        self.state = Some((salted_password.to_vec(), auth_message));
+        Ok(format!(
+            "{},p={}",
+            client_final_without_proof,
+            base64::encode(proof)
+        ))
+    }
+
+    fn verify_server_final(&self, server_final: &str) -> Result<()> {
+        let (salted_password, auth_message) = self
+            .state
+            .as_ref()
+            .ok_or_else(|| anyhow!("Unexpected SCRAM final message"))?;
+        let server_key = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, salted_password),
+            b"Server Key",
+        );
+        let expected = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, server_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        match server_final.strip_prefix("v=") {
+            Some(signature) if base64::decode(signature)? == expected.as_ref() => Ok(()),
+            _ => bail!("Invalid SCRAM server signature"),
+        }
+    }
+}
+
+/// Default timeout for connecting, and for every read and write.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimum time between two deletions of old measurements.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+struct Connection {
+    stream: TcpStream,
+    timeouts: Timeouts,
+}
+
+impl Connection {
+    async fn connect(config: &config::Postgres) -> Result<Self> {
+        let timeouts = Timeouts::new(&config.connection, TIMEOUT);
+        let connect = async { Ok(TcpStream::connect((config.host.as_str(), config.port)).await?) };
+        let stream = timeout(timeouts.connect, "connecting", connect)
+            .await
+            .with_context(|| format!("Could not connect to {}:{}", config.host, config.port))?;
+        let mut conn = Self { stream, timeouts };
+        conn.send(&startup_message(&config.user, &config.database))
+            .await?;
+        conn.authenticate(config).await?;
+        conn.wait_ready().await?;
+        Ok(conn)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let stream = &mut self.stream;
+        let write = async {
+            stream.write_all(data).await?;
+            stream.flush().await?;
+            Ok(())
+        };
+        timeout(self.timeouts.write, "writing", write).await
+    }
+
+    async fn receive(&mut self) -> Result<Message> {
+        let stream = &mut self.stream;
+        let read = async {
+            let mut header = [0; 5];
+            stream.read_exact(&mut header).await?;
+            let len = i32::from_be_bytes(header[1..5].try_into().unwrap());
+            if len < 4 {
+                bail!("Invalid message length");
+            }
+            let mut body = vec![0; len as usize - 4];
+            stream.read_exact(&mut body).await?;
+            Ok(Message {
+                tag: header[0],
+                body,
+            })
+        };
+        timeout(self.timeouts.read, "reading", read).await
+    }
+
+    async fn authenticate(&mut self, config: &config::Postgres) -> Result<()> {
+        let mut scram: Option<Scram> = None;
+        loop {
+            let msg = self.receive().await?;
+            match msg.tag {
+                b'R' if msg.body.len() >= 4 => {}
+                b'E' => bail!("Authentication failed: {}", error_message(&msg.body)),
+                _ => bail!("Unexpected message during authentication"),
+            }
+            let auth_type = i32::from_be_bytes(msg.body[..4].try_into().unwrap());
+            let data = &msg.body[4..];
+            match auth_type {
+                AUTH_OK => return Ok(()),
+                AUTH_CLEARTEXT_PASSWORD => {
+                    let mut body = config.password.as_bytes().to_vec();
+                    body.push(0);
+                    self.send(&encode_message(Some(b'p'), &body)).await?;
+                }
+                AUTH_MD5_PASSWORD => {
+                    bail!("MD5 password authentication is not supported, use SCRAM-SHA-256")
+                }
+                AUTH_SASL => {
+                    let mechanisms = data.split(|&b| b == 0);
+                    if !mechanisms
+                        .into_iter()
+                        .any(|m| m == SCRAM_SHA_256.as_bytes())
+                    {
+                        bail!("Server does not support SCRAM-SHA-256");
+                    }
+                    let mut nonce = [0; 18];
+                    ring::rand::SystemRandom::new()
+                        .fill(&mut nonce)
+                        .map_err(|_| anyhow!("Could not generate nonce"))?;
+                    // The user name is taken from the startup message
+                    let client = Scram::new("", &config.password, &base64::encode(nonce));
+                    let first = client.client_first();
+                    let mut body = SCRAM_SHA_256.as_bytes().to_vec();
+                    body.push(0);
+                    body.extend_from_slice(&(first.len() as i32).to_be_bytes());
+                    body.extend_from_slice(first.as_bytes());
+                    self.send(&encode_message(Some(b'p'), &body)).await?;
+                    scram = Some(client);
+                }
+                AUTH_SASL_CONTINUE => {
+                    let client = scram
+                        .as_mut()
+                        .ok_or_else(|| anyhow!("Unexpected SASL message"))?;
+                    let client_final = client.client_final(&String::from_utf8_lossy(data))?;
+                    self.send(&encode_message(Some(b'p'), client_final.as_bytes()))
+                        .await?;
+                }
+                AUTH_SASL_FINAL => {
+                    scram
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("Unexpected SASL message"))?
+                        .verify_server_final(&String::from_utf8_lossy(data))?;
+                }
+                other => bail!("Unsupported authentication method ({})", other),
+            }
+        }
+    }
+
+    /// Wait for ReadyForQuery. Return the first error, if any.
+    async fn wait_ready(&mut self) -> Result<()> {
+        let mut error = None;
+        loop {
+            let msg = self.receive().await?;
+            match msg.tag {
+                b'Z' => break,
+                b'E' if error.is_none() => error = Some(error_message(&msg.body)),
+                b'N' => log::debug!("PostgreSQL notice: {}", error_message(&msg.body)),
+                _ => {}
+            }
+        }
+        match error {
+            Some(e) => bail!("PostgreSQL error: {}", e),
+            None => Ok(()),
+        }
+    }
+
+    async fn query(&mut self, sql: &str) -> Result<()> {
+        log::trace!("PostgreSQL query: {}", sql);
+        self.send(&query_message(sql)).await?;
+        self.wait_ready().await
+    }
+
+    /// Execute statements with parameters. They are followed by a single
+    /// Sync, so they run in one transaction (if one fails, none has an
+    /// effect).
+    async fn execute(&mut self, statements: &[(String, Vec<Option<String>>)]) -> Result<()> {
+        let mut messages = vec![];
+        for (sql, params) in statements {
+            log::trace!("PostgreSQL statement: {} {:?}", sql, params);
+            messages.extend(execute_messages(sql, params));
+        }
+        messages.extend(encode_message(Some(b'S'), &[]));
+        self.send(&messages).await?;
+        self.wait_ready().await
+    }
+}
+
+/// Quote a string literal (as escape string, so that backslashes are escaped
+/// independently of `standard_conforming_strings`).
+fn literal(value: &str) -> String {
+    format!("E'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+/// Quote a (possibly schema-qualified) identifier, e.g. `"public"."sensilo"`.
+fn identifier(name: &str) -> String {
+    name.split('.')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Statements that create the table (if it doesn't exist yet).
+fn schema_statements(config: &config::Postgres) -> Vec<String> {
+    let mut statements = vec![format!(
+        "CREATE TABLE IF NOT EXISTS {} (\
+         time TIMESTAMPTZ NOT NULL, \
+         address TEXT NOT NULL, \
+         local_name TEXT NOT NULL, \
+         counter INTEGER NOT NULL, \
+         rssi SMALLINT NOT NULL, \
+         temperature DOUBLE PRECISION, \
+         humidity DOUBLE PRECISION, \
+         ambient_light DOUBLE PRECISION, \
+         ambient_light_als INTEGER, \
+         ambient_light_white INTEGER, \
+         thermocouple_temperature DOUBLE PRECISION, \
+         secondary_temperature DOUBLE PRECISION, \
+         secondary_humidity DOUBLE PRECISION, \
+         external_temperatures DOUBLE PRECISION[], \
+         pulse_count BIGINT, \
+         pulse_delta INTEGER, \
+         pulse_rate DOUBLE PRECISION, \
+         analog JSONB, \
+         contact_open BOOLEAN, \
+         contact_events INTEGER)",
+        identifier(&config.table)
+    )];
+    if config.timescale {
+        statements.push(format!(
+            "SELECT create_hypertable({}, 'time', if_not_exists => TRUE)",
+            literal(&identifier(&config.table))
+        ));
+    }
+    if config.compact {
+        statements.push(format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             time TIMESTAMPTZ NOT NULL, \
+             address TEXT NOT NULL, \
+             local_name TEXT NOT NULL, \
+             samples INTEGER NOT NULL, \
+             rssi DOUBLE PRECISION NOT NULL, \
+             {}, \
+             pulse_count BIGINT, \
+             pulse_delta BIGINT, \
+             contact_events BIGINT, \
+             PRIMARY KEY (address, time))",
+            identifier(&hourly_table(config)),
+            AGGREGATES
+                .iter()
+                .map(|(column, _)| format!("{} DOUBLE PRECISION", column))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    statements
+}
+
+/// The table with the hourly aggregates.
+fn hourly_table(config: &config::Postgres) -> String {
+    format!("{}_hourly", config.table)
+}
+
+/// The aggregated columns of the hourly table (besides the count, the RSSI
+/// and the pulses and contact events), with their aggregate.
+const AGGREGATES: [(&str, &str); 12] = [
+    ("temperature", "avg(temperature)"),
+    ("temperature_min", "min(temperature)"),
+    ("temperature_max", "max(temperature)"),
+    ("humidity", "avg(humidity)"),
+    ("humidity_min", "min(humidity)"),
+    ("humidity_max", "max(humidity)"),
+    ("ambient_light", "avg(ambient_light)"),
+    ("thermocouple_temperature", "avg(thermocouple_temperature)"),
+    ("secondary_temperature", "avg(secondary_temperature)"),
+    ("secondary_humidity", "avg(secondary_humidity)"),
+    ("pulse_rate", "avg(pulse_rate)"),
+    ("contact_open", "avg(contact_open::int)"),
+];
+
+/// Statement that aggregates the measurements before `cutoff` into the
+/// hourly table. Only the hours after the last aggregated hour are
+/// aggregated, because the measurements in a remaining TimescaleDB chunk are
+/// not deleted (so measurements that are inserted later for an aggregated
+/// hour are deleted without being aggregated).
+fn compact_statement(config: &config::Postgres, cutoff: &str) -> String {
+    let hourly = identifier(&hourly_table(config));
+    format!(
+        "INSERT INTO {hourly} (time, address, local_name, samples, rssi, {}, \
+         pulse_count, pulse_delta, contact_events) \
+         SELECT date_trunc('hour', time), address, max(local_name), count(*), avg(rssi), {}, \
+         max(pulse_count), sum(pulse_delta), sum(contact_events) \
+         FROM {} \
+         WHERE time < {cutoff} \
+         AND time >= coalesce((SELECT max(time) FROM {hourly}) + interval '1 hour', '-infinity') \
+         GROUP BY 1, 2 \
+         ON CONFLICT DO NOTHING",
+        AGGREGATES
+            .iter()
+            .map(|(column, _)| *column)
+            .collect::<Vec<_>>()
+            .join(", "),
+        AGGREGATES
+            .iter()
+            .map(|(_, aggregate)| *aggregate)
+            .collect::<Vec<_>>()
+            .join(", "),
+        identifier(&config.table),
+        hourly = hourly,
+        cutoff = cutoff,
+    )
+}
+
+/// Statement that deletes the measurements older than the retention. Drops
+/// whole chunks of a TimescaleDB hypertable, which is much cheaper than a
+/// `DELETE`. With compaction, the measurements are aggregated first, and
+/// only complete hours are deleted.
+fn prune_statement(config: &config::Postgres) -> Option<String> {
+    let interval = format!("make_interval(days => {})", config.retention_days?);
+    let cutoff = format!("date_trunc('hour', now() - {})", interval);
+    let delete = match (config.timescale, config.compact) {
+        (true, false) => format!(
+            "SELECT drop_chunks({}, older_than => {})",
+            literal(&identifier(&config.table)),
+            interval
+        ),
+        (true, true) => format!(
+            "SELECT drop_chunks({}, older_than => {})",
+            literal(&identifier(&config.table)),
+            cutoff
+        ),
+        (false, false) => format!(
+            "DELETE FROM {} WHERE time < now() - {}",
+            identifier(&config.table),
+            interval
+        ),
+        (false, true) => format!(
+            "DELETE FROM {} WHERE time < {}",
+            identifier(&config.table),
+            cutoff
+        ),
+    };
+    Some(if config.compact {
+        // Both statements run in one (implicit) transaction
+        format!("{}; {}", compact_statement(config, &cutoff), delete)
+    } else {
+        delete
+    })
+}
+
+/// Array of the external temperatures (in text format). The probe with index
+/// `i` is stored at position `i + 1` (arrays are 1-based), missing probes are
+/// NULL.
+fn external_temperatures(mmt: &Measurement, units: &Units) -> Option<String> {
+    let (&max_index, _) = mmt.external_temperatures.iter().next_back()?;
+    let values: Vec<String> = (0..=max_index)
+        .map(|index| {
+            mmt.external_temperatures
+                .get(&index)
+                .map_or_else(|| "NULL".to_string(), |t| units::temperature(t, units))
+        })
+        .collect();
+    Some(format!("{{{}}}", values.join(",")))
+}
+
+/// The columns of the inserted measurements.
+const COLUMNS: [&str; 20] = [
+    "time",
+    "address",
+    "local_name",
+    "counter",
+    "rssi",
+    "temperature",
+    "humidity",
+    "ambient_light",
+    "ambient_light_als",
+    "ambient_light_white",
+    "thermocouple_temperature",
+    "secondary_temperature",
+    "secondary_humidity",
+    "external_temperatures",
+    "pulse_count",
+    "pulse_delta",
+    "pulse_rate",
+    "analog",
+    "contact_open",
+    "contact_events",
+];
+
+/// The maximum number of parameters of a statement.
+const MAX_PARAMS: usize = u16::MAX as usize;
+
+/// The values of a measurement, in the order of `COLUMNS`.
+fn row(mmt: &Measurement, units: &Units) -> Vec<Option<String>> {
+    let timestamp = mmt
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    vec![
+        Some(format!("{:.3}", timestamp)),
+        Some(mmt.address.to_string()),
+        Some(mmt.local_name.clone()),
+        Some(mmt.counter.to_string()),
+        Some((mmt.rssi as i8).to_string()),
+        mmt.temperature
+            .as_ref()
+            .map(|t| units::temperature(t, units)),
+        mmt.humidity.as_ref().map(|h| units::humidity(h, units)),
+        mmt.ambient_light
+            .as_ref()
+            .filter(|l| l.as_lux().is_finite())
+            .map(|l| l.as_lux().to_string()),
+        mmt.ambient_light_als
+            .as_ref()
+            .map(|c| c.as_counts().to_string()),
+        mmt.ambient_light_white
+            .as_ref()
+            .map(|c| c.as_counts().to_string()),
+        mmt.thermocouple_temperature
+            .as_ref()
+            .map(|t| units::temperature(t, units)),
+        mmt.secondary_temperature
+            .as_ref()
+            .map(|t| units::temperature(t, units)),
+        mmt.secondary_humidity
+            .as_ref()
+            .map(|h| units::humidity(h, units)),
+        external_temperatures(mmt, units),
+        mmt.pulses.as_ref().map(|p| p.count.to_string()),
+        mmt.pulses.as_ref().map(|p| p.delta.to_string()),
+        mmt.pulse_rate
+            .filter(|rate| rate.is_finite())
+            .map(|rate| rate.to_string()),
+        json::analog(mmt),
+        mmt.contact.as_ref().map(|c| c.open.to_string()),
+        mmt.contact.as_ref().map(|c| c.events.to_string()),
+    ]
+}
+
+/// Statements that insert the measurements, with their parameters. The
+/// values are bound as parameters, so they are never parsed as SQL. Large
+/// batches are split up because of the maximum number of parameters (but
+/// still inserted in one transaction).
+fn insert_statements(
+    table: &str,
+    measurements: &[Measurement],
+    units: &Units,
+) -> Vec<(String, Vec<Option<String>>)> {
+    measurements
+        .chunks(MAX_PARAMS / COLUMNS.len())
+        .map(|chunk| {
+            let rows: Vec<String> = (0..chunk.len())
+                .map(|i| {
+                    let first = i * COLUMNS.len() + 1;
+                    // The time is passed as unix time
+                    let mut values = vec![format!("to_timestamp(${}::float8)", first)];
+                    values.extend((first + 1..first + COLUMNS.len()).map(|n| format!("${}", n)));
+                    format!("({})", values.join(", "))
+                })
+                .collect();
+            let sql = format!(
+                "INSERT INTO {} ({}) VALUES {}",
+                identifier(table),
+                COLUMNS.join(", "),
+                rows.join(", ")
+            );
+            let params = chunk.iter().flat_map(|mmt| row(mmt, units)).collect();
+            (sql, params)
+        })
+        .collect()
+}
+
+pub struct PostgresSink {
+    config: config::Postgres,
+    connection: Option<Connection>,
+    /// When the old measurements were last deleted
+    last_prune: Option<Instant>,
+}
+
+impl PostgresSink {
+    pub fn new(config: &config::Postgres) -> Result<Self> {
+        // The table name is quoted, but only plain (optionally
+        // schema-qualified) names are accepted
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        if config.table.is_empty() || !config.table.chars().all(valid) {
+            bail!("Invalid PostgreSQL table name: {}", config.table);
+        }
+        if config.compact && config.retention_days.is_none() {
+            bail!("PostgreSQL: `compact` requires `retention_days`");
+        }
+        Ok(Self {
+            config: config.clone(),
+            connection: None,
+            last_prune: None,
+        })
+    }
+
+    /// Connect (if not connected yet) and create the table.
+    pub async fn check(&mut self) -> Result<()> {
+        if self.connection.is_none() {
+            let mut conn = Connection::connect(&self.config).await?;
+            for statement in schema_statements(&self.config) {
+                conn.query(&statement).await?;
+            }
+            self.connection = Some(conn);
+        }
+        Ok(())
+    }
+
+    /// Insert a batch of measurements.
+    pub async fn submit(&mut self, measurements: &[Measurement], units: &Units) -> Result<()> {
+        self.check().await?;
+        let statements = insert_statements(&self.config.table, measurements, units);
+        let result = self.connection.as_mut().unwrap().execute(&statements).await;
+        if result.is_err() {
+            // Reconnect on the next submission
+            self.connection = None;
+        } else {
+            self.prune().await;
+        }
+        result
+    }
+
+    /// Delete the old measurements, if the last deletion was more than
+    /// `PRUNE_INTERVAL` ago. Errors are only logged, the measurements have
+    /// been inserted already (and a retry would insert them again).
+    async fn prune(&mut self) {
+        let sql = match prune_statement(&self.config) {
+            Some(sql) => sql,
+            None => return,
+        };
+        if self
+            .last_prune
+            .is_some_and(|last| last.elapsed() < PRUNE_INTERVAL)
+        {
+            return;
+        }
+        self.last_prune = Some(Instant::now());
+        if let Some(ref mut conn) = self.connection {
+            if let Err(e) = conn.query(&sql).await {
+                log::warn!("Could not delete old measurements from PostgreSQL: {:#}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::measurement::{MeasurementBuilder, Temperature};
+    use crate::types::Address;
+
+    #[test]
+    fn encode_startup_message() {
+        let msg = startup_message("u", "db");
+        assert_eq!(&msg[..4], &(msg.len() as i32).to_be_bytes());
+        assert_eq!(&msg[8..], b"user\0u\0database\0db\0\0");
+    }
+
+    #[test]
+    fn parse_error_message() {
+        assert_eq!(
+            error_message(b"SERROR\0C42P01\0Mrelation does not exist\0\0"),
+            "relation does not exist"
+        );
+    }
+
+    /// Example from RFC 7677
+    #[test]
+    fn scram() {
+        let mut scram = Scram::new("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let client_final = scram
+            .client_final(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(scram
+            .verify_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .is_ok());
+        assert!(scram.verify_server_final("v=AAAA").is_err());
+    }
+
+    #[test]
+    fn insert() {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 0xc4);
+        builder
+            .local_name("O'Brien\\")
+            .counter(42)
+            .timestamp(UNIX_EPOCH + Duration::from_millis(1_607_500_000_123))
+            .temperature(Temperature::from_millidegrees_celsius(21500))
+            .secondary_temperature(Temperature::from_millidegrees_celsius(21750))
+            .external_temperature(1, Temperature::from_millidegrees_celsius(4500));
+        let statements =
+            insert_statements("sensilo", &[builder.build().unwrap()], &Units::default());
+        assert_eq!(statements.len(), 1);
+        let (sql, params) = &statements[0];
+        assert!(sql.starts_with("INSERT INTO \"sensilo\" (time, address, "));
+        assert!(sql.ends_with(" VALUES (to_timestamp($1::float8), $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20)"));
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(
+            params[..],
+            [
+                some("1607500000.123"),
+                some("123456"),
+                some("O'Brien\\"),
+                some("42"),
+                some("-60"),
+                some("21500"),
+                None,
+                None,
+                None,
+                None,
+                None,
+                some("21750"),
+                None,
+                some("{NULL,4500}"),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn insert_chunks() {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 0xc4);
+        builder.local_name("Sensilo").counter(42);
+        let measurements = vec![builder.build().unwrap(); 4000];
+        let statements = insert_statements("sensilo", &measurements, &Units::default());
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].1.len(), 3276 * 20);
+        assert_eq!(statements[1].1.len(), 724 * 20);
+        assert!(statements[1].0.ends_with(", $14480)"));
+    }
+
+    #[test]
+    fn encode_execute() {
+        let messages = execute_messages("SELECT $1, $2", &[Some("a".into()), None]);
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            b'P', 0, 0, 0, 21, 0, b'S', b'E', b'L', b'E', b'C', b'T', b' ',
+            b'$', b'1', b',', b' ', b'$', b'2', 0, 0, 0,
+            b'B', 0, 0, 0, 21, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, b'a', 0xff, 0xff, 0xff, 0xff, 0, 0,
+            b'E', 0, 0, 0, 9, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(messages, expected);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(literal("it's a \\"), "E'it''s a \\\\'");
+        assert_eq!(identifier("sensilo"), "\"sensilo\"");
+        assert_eq!(identifier("public.Sensilo"), "\"public\".\"Sensilo\"");
+        assert_eq!(identifier("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn invalid_table_name() {
+        let mut config = config::Postgres {
+            host: "localhost".into(),
+            port: 5432,
+            user: "sensilo".into(),
+            password: "".into(),
+            database: "sensilo".into(),
+            table: "measurements; DROP TABLE x".into(),
+            timescale: false,
+            min_interval_s: None,
+            retention_days: None,
+            compact: false,
+            connection: Default::default(),
+        };
+        assert!(PostgresSink::new(&config).is_err());
+        config.table = "public.measurements".into();
+        assert!(PostgresSink::new(&config).is_ok());
+    }
+
+    #[test]
+    fn prune() {
+        let mut config = config::Postgres {
+            host: "localhost".into(),
+            port: 5432,
+            user: "sensilo".into(),
+            password: "".into(),
+            database: "sensilo".into(),
+            table: "sensilo".into(),
+            timescale: false,
+            min_interval_s: None,
+            retention_days: None,
+            compact: false,
+            connection: Default::default(),
+        };
+        assert_eq!(prune_statement(&config), None);
+        config.retention_days = Some(30);
+        assert_eq!(
+            prune_statement(&config).unwrap(),
+            "DELETE FROM \"sensilo\" WHERE time < now() - make_interval(days => 30)"
+        );
+        config.timescale = true;
+        assert_eq!(
+            prune_statement(&config).unwrap(),
+            "SELECT drop_chunks(E'\"sensilo\"', older_than => make_interval(days => 30))"
+        );
+    }
+
+    #[test]
+    fn compact() {
+        let mut config = config::Postgres {
+            host: "localhost".into(),
+            port: 5432,
+            user: "sensilo".into(),
+            password: "".into(),
+            database: "sensilo".into(),
+            table: "public.sensilo".into(),
+            timescale: false,
+            min_interval_s: None,
+            retention_days: None,
+            compact: true,
+            connection: Default::default(),
+        };
+        assert!(PostgresSink::new(&config).is_err());
+        config.retention_days = Some(30);
+        assert!(PostgresSink::new(&config).is_ok());
+
+        let schema = schema_statements(&config);
+        assert_eq!(schema.len(), 2);
+        assert!(schema[1].starts_with(
+            "CREATE TABLE IF NOT EXISTS \"public\".\"sensilo_hourly\" (time TIMESTAMPTZ NOT NULL, "
+        ));
+        assert!(schema[1].contains(", temperature DOUBLE PRECISION, temperature_min "));
+
+        let cutoff = "date_trunc('hour', now() - make_interval(days => 30))";
+        let sql = prune_statement(&config).unwrap();
+        let (compact, delete) = sql.split_once("; ").unwrap();
+        assert_eq!(compact, compact_statement(&config, cutoff));
+        assert!(compact.starts_with("INSERT INTO \"public\".\"sensilo_hourly\" (time, address, "));
+        assert!(compact.contains(&format!(
+            " FROM \"public\".\"sensilo\" WHERE time < {} ",
+            cutoff
+        )));
+        assert!(compact
+            .contains("(SELECT max(time) FROM \"public\".\"sensilo_hourly\") + interval '1 hour'"));
+        assert_eq!(
+            delete,
+            format!("DELETE FROM \"public\".\"sensilo\" WHERE time < {}", cutoff)
+        );
+
+        config.timescale = true;
+        let sql = prune_statement(&config).unwrap();
+        let (_, delete) = sql.split_once("; ").unwrap();
+        assert_eq!(
+            delete,
+            format!(
+                "SELECT drop_chunks(E'\"public\".\"sensilo\"', older_than => {})",
+                cutoff
+            )
+        );
+    }
+}
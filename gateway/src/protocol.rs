@@ -0,0 +1,426 @@
+//! Machine-readable description of the payload entries.
+//!
+//! The entry types are described once here: The hex dumps take their names
+//! and lengths from this table, a test checks them against the parser, and
+//! `sensilo-gateway protocol` prints the table as JSON for dashboards and
+//! third-party decoders.
+//!
+//! The table is part of the gateway library, there is no separate protocol
+//! crate. The firmware doesn't use it, so its encoder
+//! (`firmware/src/payload.rs`) must be kept in sync by hand.
+
+/// The encoding of a field. All integers are little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    U8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    /// A number of raw bytes
+    Bytes(usize),
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::U8 => "u8",
+            Encoding::U16 => "u16",
+            Encoding::I16 => "i16",
+            Encoding::U32 => "u32",
+            Encoding::I32 => "i32",
+            Encoding::F32 => "f32",
+            Encoding::Bytes(_) => "bytes",
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            Encoding::U8 => 1,
+            Encoding::U16 | Encoding::I16 => 2,
+            Encoding::U32 | Encoding::I32 | Encoding::F32 => 4,
+            Encoding::Bytes(len) => len,
+        }
+    }
+}
+
+/// A field of an entry value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub encoding: Encoding,
+    /// Unit of the value (`None` for counts, flags and raw values)
+    pub unit: Option<&'static str>,
+    pub description: &'static str,
+}
+
+/// A payload entry type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryType {
+    pub id: u8,
+    pub name: &'static str,
+    pub fields: &'static [Field],
+}
+
+impl EntryType {
+    /// Length of the value (receivers accept longer values since protocol
+    /// version 2).
+    pub fn value_len(&self) -> usize {
+        self.fields.iter().map(|field| field.encoding.size()).sum()
+    }
+}
+
+const fn field(
+    name: &'static str,
+    encoding: Encoding,
+    unit: Option<&'static str>,
+    description: &'static str,
+) -> Field {
+    Field {
+        name,
+        encoding,
+        unit,
+        description,
+    }
+}
+
+const fn entry(id: u8, name: &'static str, fields: &'static [Field]) -> EntryType {
+    EntryType { id, name, fields }
+}
+
+/// All entry types known to the gateway.
+pub const ENTRY_TYPES: &[EntryType] = &[
+    entry(
+        0x00,
+        "frame info",
+        &[
+            field("version", Encoding::U8, None, "Protocol version"),
+            field(
+                "frame",
+                Encoding::U8,
+                None,
+                "Frame index (upper 4 bits) and frame count (lower 4 bits)",
+            ),
+        ],
+    ),
+    entry(
+        0x01,
+        "temperature",
+        &[field(
+            "temperature",
+            Encoding::I32,
+            Some("m°C"),
+            "Temperature",
+        )],
+    ),
+    entry(
+        0x02,
+        "humidity",
+        &[field(
+            "humidity",
+            Encoding::I32,
+            Some("m%RH"),
+            "Relative humidity",
+        )],
+    ),
+    entry(
+        0x04,
+        "ambient light",
+        &[field(
+            "ambient_light",
+            Encoding::F32,
+            Some("lx"),
+            "Illuminance",
+        )],
+    ),
+    entry(
+        0x05,
+        "ambient light ALS",
+        &[field(
+            "counts",
+            Encoding::U16,
+            None,
+            "Raw ALS channel counts",
+        )],
+    ),
+    entry(
+        0x06,
+        "ambient light WHITE",
+        &[field(
+            "counts",
+            Encoding::U16,
+            None,
+            "Raw WHITE channel counts",
+        )],
+    ),
+    entry(
+        0x07,
+        "thermocouple",
+        &[field(
+            "temperature",
+            Encoding::I32,
+            Some("m°C"),
+            "Thermocouple temperature",
+        )],
+    ),
+    entry(
+        0x08,
+        "external temperature",
+        &[
+            field("probe", Encoding::U8, None, "Probe index"),
+            field(
+                "temperature",
+                Encoding::I32,
+                Some("m°C"),
+                "Probe temperature",
+            ),
+        ],
+    ),
+    entry(
+        0x09,
+        "pulse counter",
+        &[
+            field("count", Encoding::U32, None, "Pulses since startup"),
+            field(
+                "delta",
+                Encoding::U16,
+                None,
+                "Pulses since the previous measurement",
+            ),
+        ],
+    ),
+    entry(
+        0x0a,
+        "analog input",
+        &[
+            field("input", Encoding::U8, None, "Analog input number"),
+            field("voltage", Encoding::U16, Some("mV"), "Input voltage"),
+        ],
+    ),
+    entry(
+        0x0b,
+        "contact",
+        &[
+            field("open", Encoding::U8, None, "1 if open, 0 if closed"),
+            field("events", Encoding::U16, None, "State changes since startup"),
+        ],
+    ),
+    entry(
+        0x0c,
+        "status",
+        &[field(
+            "flags",
+            Encoding::U8,
+            None,
+            "Bit 0: buzzer present, bit 1: heated readings, bit 2: sensors disagree",
+        )],
+    ),
+    entry(
+        0x0d,
+        "MAC",
+        &[field(
+            "mac",
+            Encoding::Bytes(4),
+            None,
+            "Truncated HMAC-SHA256 of the payload",
+        )],
+    ),
+    entry(
+        0x0e,
+        "telemetry",
+        &[
+            field("tx_beacons", Encoding::U16, None, "Transmitted beacons"),
+            field("i2c_errors", Encoding::U16, None, "Failed I²C transactions"),
+            field(
+                "sensor_retries",
+                Encoding::U16,
+                None,
+                "Retried sensor operations",
+            ),
+        ],
+    ),
+    entry(
+        0x0f,
+        "backlog sample",
+        &[
+            field("age", Encoding::U16, None, "Measurements before this one"),
+            field("temperature", Encoding::I16, Some("c°C"), "Temperature"),
+            field("humidity", Encoding::U16, Some("c%RH"), "Relative humidity"),
+        ],
+    ),
+    entry(
+        0x10,
+        "delta",
+        &[
+            field(
+                "reference_age",
+                Encoding::U8,
+                None,
+                "Measurements since the full measurement",
+            ),
+            field(
+                "temperature",
+                Encoding::I16,
+                Some("c°C"),
+                "Temperature difference (-32768 if the sensor failed)",
+            ),
+            field(
+                "humidity",
+                Encoding::I16,
+                Some("c%RH"),
+                "Humidity difference (-32768 if the sensor failed)",
+            ),
+        ],
+    ),
+    entry(
+        0x11,
+        "CRC",
+        &[field(
+            "crc",
+            Encoding::U8,
+            None,
+            "CRC-8 of the preceding payload",
+        )],
+    ),
+    entry(
+        0x12,
+        "timing",
+        &[
+            field(
+                "duration",
+                Encoding::U32,
+                Some("µs"),
+                "Measurement duration",
+            ),
+            field(
+                "start_latency",
+                Encoding::I16,
+                Some("µs"),
+                "Delay of the measurement start (negative if too early)",
+            ),
+        ],
+    ),
+    entry(
+        0x13,
+        "secondary temperature",
+        &[field(
+            "temperature",
+            Encoding::I32,
+            Some("m°C"),
+            "Temperature of the second sensor",
+        )],
+    ),
+    entry(
+        0x14,
+        "secondary humidity",
+        &[field(
+            "humidity",
+            Encoding::I32,
+            Some("m%RH"),
+            "Relative humidity of the second sensor",
+        )],
+    ),
+];
+
+/// The description of an entry type, if it is known.
+pub fn entry_type(id: u8) -> Option<&'static EntryType> {
+    ENTRY_TYPES.iter().find(|entry| entry.id == id)
+}
+
+/// The entry types as JSON. The strings of the table don't need escaping.
+pub fn to_json() -> String {
+    let entries: Vec<String> = ENTRY_TYPES
+        .iter()
+        .map(|entry| {
+            let fields: Vec<String> = entry
+                .fields
+                .iter()
+                .map(|field| {
+                    let unit = field
+                        .unit
+                        .map_or("null".to_string(), |unit| format!("\"{}\"", unit));
+                    format!(
+                        "{{\"name\":\"{}\",\"encoding\":\"{}\",\"length\":{},\"unit\":{},\"description\":\"{}\"}}",
+                        field.name,
+                        field.encoding.name(),
+                        field.encoding.size(),
+                        unit,
+                        field.description
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"type\":{},\"name\":\"{}\",\"length\":{},\"fields\":[{}]}}",
+                entry.id,
+                entry.name,
+                entry.value_len(),
+                fields.join(",")
+            )
+        })
+        .collect();
+    format!(
+        "{{\"byte_order\":\"little-endian\",\"header\":[{{\"name\":\"counter\",\"encoding\":\"u16\",\"length\":2}}],\"entry_types\":[{}]}}",
+        entries.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::MeasurementBuilder;
+    use crate::types::Address;
+
+    /// Parse a payload with a single entry.
+    fn parse(id: u8, len: usize) -> Result<(), &'static str> {
+        let mut payload = vec![1, 0, id];
+        payload.extend(vec![0; len]);
+        let mut builder = MeasurementBuilder::new(Address([1; 6]), 0);
+        builder.local_name("Sensilo");
+        builder.strict(true);
+        builder.parse_payload(&payload)?;
+        builder.build().map(drop)
+    }
+
+    #[test]
+    fn matches_parser() {
+        for entry in ENTRY_TYPES {
+            assert_eq!(parse(entry.id, entry.value_len()), Ok(()), "{}", entry.name);
+            assert!(
+                parse(entry.id, entry.value_len() - 1).is_err(),
+                "{}",
+                entry.name
+            );
+        }
+        // All other types are unknown
+        for id in 0..=255 {
+            if entry_type(id).is_none() {
+                assert_eq!(parse(id, 1), Err("Unknown payload type"));
+            }
+        }
+    }
+
+    #[test]
+    fn json() {
+        for entry in ENTRY_TYPES {
+            let strings = entry
+                .fields
+                .iter()
+                .flat_map(|field| vec![field.name, field.description])
+                .chain(std::iter::once(entry.name));
+            for string in strings {
+                assert!(!string.contains(&['"', '\\'][..]), "{}", string);
+            }
+        }
+        let json = to_json();
+        assert!(json.starts_with("{\"byte_order\":\"little-endian\","));
+        assert!(json.contains(
+            "{\"type\":10,\"name\":\"analog input\",\"length\":3,\"fields\":[\
+             {\"name\":\"input\",\"encoding\":\"u8\",\"length\":1,\"unit\":null,\
+             \"description\":\"Analog input number\"},\
+             {\"name\":\"voltage\",\"encoding\":\"u16\",\"length\":2,\"unit\":\"mV\",\
+             \"description\":\"Input voltage\"}]}"
+        ));
+    }
+}
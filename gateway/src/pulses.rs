@@ -0,0 +1,106 @@
+//! Calculate the rate of pulse counters (e.g. rain gauges or power meters).
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::measurement::Pulses;
+use crate::types::Address;
+
+/// Tracks the last pulse count of every device.
+///
+/// The rate is calculated from the difference of the cumulative counts and
+/// the time between the two measurements, so that missed beacons don't
+/// distort it. For the first measurement (or after a reboot), the pulses since
+/// the previous measurement are divided by the expected measurement interval,
+/// if it is known.
+pub struct PulseRates {
+    last: HashMap<Address, (u32, Instant)>,
+    intervals: HashMap<Address, Duration>,
+}
+
+impl PulseRates {
+    pub fn new() -> Self {
+        Self {
+            last: HashMap::new(),
+            intervals: HashMap::new(),
+        }
+    }
+
+    /// Set the expected measurement interval of a device.
+    pub fn set_interval(&mut self, address: Address, interval: Duration) {
+        self.intervals.insert(address, interval);
+    }
+
+    /// Return the last count of every device, and when it was received.
+    pub fn last(&self) -> impl Iterator<Item = (Address, u32, Instant)> + '_ {
+        self.last
+            .iter()
+            .map(|(address, (count, instant))| (*address, *count, *instant))
+    }
+
+    /// Restore the last count of a device.
+    pub fn restore(&mut self, address: Address, count: u32, instant: Instant) {
+        self.last.insert(address, (count, instant));
+    }
+
+    /// Update the count of a device. Return the rate in pulses per second.
+    pub fn update(&mut self, address: Address, pulses: &Pulses, now: Instant) -> Option<f64> {
+        let previous = self.last.insert(address, (pulses.count, now));
+        match previous {
+            Some((count, time)) if count <= pulses.count && now > time => {
+                let elapsed = now.duration_since(time).as_secs_f64();
+                Some(f64::from(pulses.count - count) / elapsed)
+            }
+            _ => self
+                .intervals
+                .get(&address)
+                .map(|interval| f64::from(pulses.delta) / interval.as_secs_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    #[test]
+    fn rate() {
+        let mut rates = PulseRates::new();
+        let now = Instant::now();
+        let pulses = |count, delta| Pulses { count, delta };
+        assert_eq!(rates.update(ADDR, &pulses(10, 10), now), None);
+        assert_eq!(
+            rates.update(ADDR, &pulses(16, 6), now + Duration::from_secs(3)),
+            Some(2.0)
+        );
+        // Missed beacons: The rate is based on the cumulative count
+        assert_eq!(
+            rates.update(ADDR, &pulses(28, 3), now + Duration::from_secs(9)),
+            Some(2.0)
+        );
+    }
+
+    #[test]
+    fn rate_after_reboot() {
+        let mut rates = PulseRates::new();
+        rates.set_interval(ADDR, Duration::from_secs(3));
+        let now = Instant::now();
+        rates.update(
+            ADDR,
+            &Pulses {
+                count: 1000,
+                delta: 1,
+            },
+            now,
+        );
+        assert_eq!(
+            rates.update(
+                ADDR,
+                &Pulses { count: 3, delta: 3 },
+                now + Duration::from_secs(3)
+            ),
+            Some(1.0)
+        );
+    }
+}
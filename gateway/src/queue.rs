@@ -0,0 +1,230 @@
+//! Submission queues of the sinks.
+//!
+//! Every sink runs on its own thread and receives its batches through a
+//! bounded queue, so that a slow or failing sink (e.g. while it waits for the
+//! backoff of a retry) delays neither the capture nor the other sinks. If the
+//! queue of a sink is full, further batches are dropped until it catches up.
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Result};
+use smol::channel::{self, Sender, TrySendError};
+
+use crate::retry::Retry;
+
+/// The number of batches that are queued per sink.
+pub const CAPACITY: usize = 100;
+
+/// A sink that is fed by a queue.
+pub trait Sink: Send + 'static {
+    type Batch: Send + 'static;
+
+    /// Check the connection (e.g. by connecting to the server).
+    async fn check(&mut self) -> Result<()>;
+
+    /// Submit a batch (once, the retries are done by the queue).
+    async fn submit(&mut self, batch: &Self::Batch) -> Result<()>;
+}
+
+enum Message<B> {
+    Batch(B),
+    Check(Sender<Result<()>>),
+    Flush(Sender<()>),
+}
+
+pub struct Queue<B> {
+    name: &'static str,
+    sender: Sender<Message<B>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<B: Send + 'static> Queue<B> {
+    /// Move the sink to its own thread.
+    pub fn spawn<S>(name: &'static str, mut sink: S, retry: Retry) -> Result<Self>
+    where
+        S: Sink<Batch = B>,
+    {
+        let (sender, receiver) = channel::bounded(CAPACITY);
+        let thread = thread::Builder::new()
+            .name(format!("sink {}", name))
+            .spawn(move || {
+                smol::block_on(async {
+                    while let Ok(message) = receiver.recv().await {
+                        match message {
+                            Message::Batch(batch) => {
+                                if let Err(e) = submit(name, &mut sink, &batch, &retry).await {
+                                    log::error!("{} submission failed: {:#}", name, e);
+                                }
+                            }
+                            Message::Check(reply) => {
+                                let _ = reply.send(sink.check().await).await;
+                            }
+                            Message::Flush(reply) => {
+                                let _ = reply.send(()).await;
+                            }
+                        }
+                    }
+                })
+            })?;
+        Ok(Self {
+            name,
+            sender,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue a batch for submission.
+    pub fn push(&self, batch: B) {
+        match self.sender.try_send(Message::Batch(batch)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("{} queue is full, dropping a batch", self.name)
+            }
+            Err(TrySendError::Closed(_)) => log::error!("{} sink has stopped", self.name),
+        }
+    }
+
+    /// Check the connection of the sink (after the queued batches).
+    pub async fn check(&self) -> Result<()> {
+        let (reply, result) = channel::bounded(1);
+        self.sender
+            .send(Message::Check(reply))
+            .await
+            .map_err(|_| anyhow!("The sink has stopped"))?;
+        result
+            .recv()
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("The sink has stopped")))
+    }
+
+    /// Wait until the queued batches are submitted (or have failed).
+    pub async fn flush(&self) {
+        let (reply, done) = channel::bounded(1);
+        if self.sender.send(Message::Flush(reply)).await.is_ok() {
+            let _ = done.recv().await;
+        }
+    }
+}
+
+impl<B> Drop for Queue<B> {
+    /// Submit the queued batches before the sink is dropped.
+    fn drop(&mut self) {
+        self.sender.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Submit a batch, and repeat it according to the retry policy if it fails.
+async fn submit<S: Sink>(name: &str, sink: &mut S, batch: &S::Batch, retry: &Retry) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match sink.submit(batch).await {
+            Ok(()) => return Ok(()),
+            Err(e) => match retry.delay(attempt) {
+                Some(delay) => {
+                    log::warn!(
+                        "{} submission failed, retrying in {:?}: {:#}",
+                        name,
+                        delay,
+                        e
+                    );
+                    smol::Timer::after(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use crate::config;
+
+    use super::*;
+
+    /// Records the batches, and fails the first `failures` submissions.
+    struct Recorder {
+        batches: Arc<Mutex<Vec<u32>>>,
+        failures: u32,
+        delay: Duration,
+    }
+
+    impl Sink for Recorder {
+        type Batch = u32;
+
+        async fn check(&mut self) -> Result<()> {
+            Err(anyhow!("unreachable"))
+        }
+
+        async fn submit(&mut self, batch: &u32) -> Result<()> {
+            smol::Timer::after(self.delay).await;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(anyhow!("failed"));
+            }
+            self.batches.lock().unwrap().push(*batch);
+            Ok(())
+        }
+    }
+
+    fn queue(failures: u32, delay: Duration) -> (Queue<u32>, Arc<Mutex<Vec<u32>>>) {
+        let batches = Arc::new(Mutex::new(vec![]));
+        let sink = Recorder {
+            batches: Arc::clone(&batches),
+            failures,
+            delay,
+        };
+        let retry = Retry::new(&config::Connection {
+            retries: 2,
+            backoff_ms: 10,
+            ..Default::default()
+        });
+        (Queue::spawn("Test", sink, retry).unwrap(), batches)
+    }
+
+    #[test]
+    fn retries_in_order() {
+        let (queue, batches) = queue(2, Duration::from_millis(0));
+        queue.push(1);
+        queue.push(2);
+        smol::block_on(queue.flush());
+        assert_eq!(*batches.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn push_does_not_wait() {
+        let (queue, batches) = queue(0, Duration::from_millis(50));
+        let start = Instant::now();
+        for batch in 0..3 {
+            queue.push(batch);
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // The queued batches are submitted when the queue is dropped
+        drop(queue);
+        assert_eq!(*batches.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn full() {
+        let (queue, batches) = queue(0, Duration::from_millis(1));
+        for batch in 0..CAPACITY as u32 * 2 {
+            queue.push(batch);
+        }
+        drop(queue);
+        let batches = batches.lock().unwrap();
+        assert!(batches.len() < CAPACITY * 2);
+        assert_eq!(batches[..3], [0, 1, 2]);
+    }
+
+    #[test]
+    fn check() {
+        let (queue, _) = queue(0, Duration::from_millis(0));
+        let err = smol::block_on(queue.check()).unwrap_err();
+        assert_eq!(err.to_string(), "unreachable");
+    }
+}
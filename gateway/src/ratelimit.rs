@@ -0,0 +1,65 @@
+//! Limit the rate at which measurements of a device are sent to a sink.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::types::Address;
+
+/// Allows at most one measurement per device within the minimal interval.
+///
+/// Without an interval, all measurements are allowed.
+pub struct RateLimiter {
+    min_interval: Option<Duration>,
+    last_allowed: HashMap<Address, Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(min_interval: Option<Duration>) -> Self {
+        Self {
+            min_interval,
+            last_allowed: HashMap::new(),
+        }
+    }
+
+    /// Return whether a measurement of the device may be sent now.
+    pub fn allow(&mut self, address: Address, now: Instant) -> bool {
+        let min_interval = match self.min_interval {
+            Some(min_interval) => min_interval,
+            None => return true,
+        };
+        match self.last_allowed.get(&address) {
+            Some(last) if now.duration_since(*last) < min_interval => false,
+            _ => {
+                self.last_allowed.insert(address, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR1: Address = Address([1, 2, 3, 4, 5, 6]);
+    const ADDR2: Address = Address([1, 2, 3, 4, 5, 7]);
+
+    #[test]
+    fn unlimited() {
+        let mut limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        assert!(limiter.allow(ADDR1, now));
+        assert!(limiter.allow(ADDR1, now));
+    }
+
+    #[test]
+    fn limited() {
+        let mut limiter = RateLimiter::new(Some(Duration::from_secs(10)));
+        let now = Instant::now();
+        assert!(limiter.allow(ADDR1, now));
+        assert!(limiter.allow(ADDR2, now));
+        assert!(!limiter.allow(ADDR1, now + Duration::from_secs(3)));
+        assert!(!limiter.allow(ADDR1, now + Duration::from_secs(9)));
+        assert!(limiter.allow(ADDR1, now + Duration::from_secs(10)));
+        assert!(!limiter.allow(ADDR1, now + Duration::from_secs(13)));
+    }
+}
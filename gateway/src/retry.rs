@@ -0,0 +1,129 @@
+//! Timeouts and retries of the sinks.
+//!
+//! Every sink with a network connection has a `connection` section with its
+//! timeouts and retry policy. Failed submissions are retried with an
+//! exponential backoff by the queue of the sink (see `queue`), which delays
+//! the following submissions to the same sink only.
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl Timeouts {
+    /// The configured timeouts, or the `default` timeout.
+    pub fn new(config: &config::Connection, default: Duration) -> Self {
+        let timeout = |seconds: Option<u64>| seconds.map(Duration::from_secs).unwrap_or(default);
+        Self {
+            connect: timeout(config.connect_timeout_s),
+            read: timeout(config.read_timeout_s),
+            write: timeout(config.write_timeout_s),
+        }
+    }
+}
+
+/// Fail with a timeout error if the future doesn't complete in time.
+pub async fn timeout<T>(
+    duration: Duration,
+    what: &str,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    smol::future::or(future, async {
+        smol::Timer::after(duration).await;
+        Err(anyhow!("Timeout while {} (after {:?})", what, duration))
+    })
+    .await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Retry {
+    pub fn new(config: &config::Connection) -> Self {
+        Self {
+            retries: config.retries,
+            backoff: Duration::from_millis(config.backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+        }
+    }
+
+    /// The delay before the retry after the failed `attempt` (starting at 0),
+    /// or `None` if all retries failed.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.retries {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt);
+        Some(
+            self.backoff
+                .checked_mul(factor)
+                .unwrap_or(self.max_backoff)
+                .min(self.max_backoff),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts() {
+        let config = config::Connection {
+            read_timeout_s: Some(30),
+            ..Default::default()
+        };
+        let timeouts = Timeouts::new(&config, Duration::from_secs(5));
+        assert_eq!(timeouts.connect, Duration::from_secs(5));
+        assert_eq!(timeouts.read, Duration::from_secs(30));
+        assert_eq!(timeouts.write, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn backoff() {
+        let config = config::Connection {
+            retries: 4,
+            backoff_ms: 1000,
+            max_backoff_ms: 5000,
+            ..Default::default()
+        };
+        let retry = Retry::new(&config);
+        let delays: Vec<_> = (0..5).map(|attempt| retry.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                Some(Duration::from_secs(5)),
+                None,
+            ]
+        );
+        assert_eq!(Retry::new(&Default::default()).delay(0), None);
+    }
+
+    #[test]
+    fn timeout_expires() {
+        let result: Result<()> = smol::block_on(timeout(
+            Duration::from_millis(10),
+            "waiting",
+            smol::future::pending(),
+        ));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Timeout while waiting (after 10ms)"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Resolution of resolvable private addresses (Bluetooth Core Specification,
+//! Vol 6, Part B, 1.3.2.2).
+//!
+//! Devices with the `private-address` firmware feature send their beacons
+//! from an address that changes regularly. With the identity resolving key
+//! (IRK) of a device, the gateway maps these addresses to the configured
+//! device address, which is then used everywhere else.
+use std::collections::HashMap;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use anyhow::{bail, Context, Result};
+
+use crate::config;
+use crate::types::Address;
+
+/// Maximum number of cached addresses. The cache is cleared when it is full
+/// (e.g. because of many phones with private addresses nearby).
+const MAX_CACHED: usize = 1024;
+
+type Irk = [u8; 16];
+
+fn parse_irk(hex: &str) -> Result<Irk> {
+    match base16::decode(hex) {
+        Ok(bytes) if bytes.len() == 16 => {
+            let mut irk = [0; 16];
+            irk.copy_from_slice(&bytes);
+            Ok(irk)
+        }
+        _ => bail!("Invalid IRK (expected 32 hex digits)"),
+    }
+}
+
+/// Whether the address is a resolvable private address (the two most
+/// significant bits are `0b01`).
+fn is_resolvable(address: Address) -> bool {
+    address.0[0] >> 6 == 0b01
+}
+
+/// The random address hash function `ah` (Vol 3, Part H, 2.2.2).
+fn ah(irk: &Irk, prand: &[u8]) -> [u8; 3] {
+    let mut block = GenericArray::from([0; 16]);
+    block[13..].copy_from_slice(prand);
+    Aes128::new(&GenericArray::from(*irk)).encrypt_block(&mut block);
+    [block[13], block[14], block[15]]
+}
+
+/// Whether the address was generated with the IRK.
+fn matches(irk: &Irk, address: Address) -> bool {
+    let (prand, hash) = address.0.split_at(3);
+    is_resolvable(address) && ah(irk, prand) == hash
+}
+
+pub struct Resolver {
+    /// IRK and identity address of the devices with private addresses
+    irks: Vec<(Irk, Address)>,
+    /// Resolved (or unresolvable) addresses
+    cache: HashMap<Address, Option<Address>>,
+}
+
+impl Resolver {
+    /// Create a resolver for the devices with an IRK. The addresses must be
+    /// in the same order as the devices in the config.
+    pub fn new(devices: &[config::Device], addresses: &[Address]) -> Result<Self> {
+        let mut irks = vec![];
+        for (device, address) in devices.iter().zip(addresses) {
+            if let Some(ref irk) = device.irk {
+                let irk = parse_irk(irk)
+                    .with_context(|| format!("Invalid config of device {}", device.name))?;
+                irks.push((irk, *address));
+            }
+        }
+        Ok(Self {
+            irks,
+            cache: HashMap::new(),
+        })
+    }
+
+    /// Return the identity address of a device if the address is one of its
+    /// private addresses, otherwise the address itself.
+    pub fn resolve(&mut self, address: Address) -> Address {
+        if self.irks.is_empty() || !is_resolvable(address) {
+            return address;
+        }
+        if let Some(resolved) = self.cache.get(&address) {
+            return resolved.unwrap_or(address);
+        }
+        let resolved = self
+            .irks
+            .iter()
+            .find(|(irk, _)| matches(irk, address))
+            .map(|(_, identity)| *identity);
+        if let Some(identity) = resolved {
+            log::debug!("Resolved private address {} of {}", address, identity);
+        }
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.clear();
+        }
+        self.cache.insert(address, resolved);
+        resolved.unwrap_or(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample data from the specification (Vol 3, Part H, D.7)
+    const IRK: &str = "ec0234a357c8ad05341010a60a397d9b";
+
+    fn device(irk: Option<&str>) -> config::Device {
+        config::Device {
+            name: "Sensilo1".into(),
+            hex_addr: "864fe067997a".into(),
+            location: None,
+            site: None,
+            interval_s: None,
+            protocol: config::Protocol::Sensilo,
+            analog: vec![],
+            expects: vec![],
+            key: None,
+            crc: false,
+            irk: irk.map(Into::into),
+            min_rssi: None,
+        }
+    }
+
+    #[test]
+    fn ah_sample_data() {
+        let irk = parse_irk(IRK).unwrap();
+        assert_eq!(ah(&irk, &[0x70, 0x81, 0x94]), [0x0d, 0xfb, 0xaa]);
+    }
+
+    #[test]
+    fn resolve() {
+        let identity = Address::from_hex("864fe067997a");
+        let mut resolver = Resolver::new(&[device(Some(IRK))], &[identity]).unwrap();
+
+        let private = Address::from_hex("7081940dfbaa");
+        assert_eq!(resolver.resolve(private), identity);
+        // Cached
+        assert_eq!(resolver.resolve(private), identity);
+
+        // Wrong hash, not resolvable, identity address
+        for address in &["7081940dfbab", "b081940dfbaa", "864fe067997a"] {
+            let address = Address::from_hex(address);
+            assert_eq!(resolver.resolve(address), address);
+        }
+
+        // Without IRK
+        let mut resolver = Resolver::new(&[device(None)], &[identity]).unwrap();
+        assert_eq!(resolver.resolve(private), private);
+    }
+
+    #[test]
+    fn invalid_irk() {
+        let identity = Address::from_hex("864fe067997a");
+        assert!(Resolver::new(&[device(Some("ec0234"))], &[identity]).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Filtering of weak frames.
+//!
+//! Frames of an identical deployment next door (with the same device
+//! addresses), or reflections with garbage data, are usually received with a
+//! much lower RSSI than the frames of the own devices. Frames below the
+//! threshold of a device are ignored (and counted).
+use std::collections::HashMap;
+
+use crate::config;
+use crate::types::Address;
+
+pub struct RssiFilter {
+    /// Thresholds in dBm, by device
+    thresholds: HashMap<Address, i8>,
+    filtered: HashMap<Address, u64>,
+}
+
+impl RssiFilter {
+    /// The threshold of a device is taken from its config, or the global
+    /// threshold. The addresses must be in the same order as the devices in
+    /// the config.
+    pub fn new(devices: &[config::Device], addresses: &[Address], global: Option<i8>) -> Self {
+        let thresholds = devices
+            .iter()
+            .zip(addresses)
+            .filter_map(|(device, address)| Some((*address, device.min_rssi.or(global)?)))
+            .collect();
+        Self {
+            thresholds,
+            filtered: HashMap::new(),
+        }
+    }
+
+    /// Whether a frame of a device with the RSSI (as reported in the HCI
+    /// event, a signed value) is accepted.
+    pub fn accept(&mut self, address: Address, rssi: u8) -> bool {
+        match self.thresholds.get(&address) {
+            Some(&threshold) if (rssi as i8) < threshold => {
+                *self.filtered.entry(address).or_default() += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Number of frames of a device below the threshold (if it has one).
+    pub fn filtered(&self, address: Address) -> Option<u64> {
+        self.thresholds
+            .get(&address)
+            .map(|_| self.filtered.get(&address).copied().unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(min_rssi: Option<i8>) -> config::Device {
+        config::Device {
+            name: "Sensilo".into(),
+            hex_addr: "010203040506".into(),
+            location: None,
+            site: None,
+            interval_s: None,
+            protocol: config::Protocol::Sensilo,
+            analog: vec![],
+            expects: vec![],
+            key: None,
+            crc: false,
+            irk: None,
+            min_rssi,
+        }
+    }
+
+    #[test]
+    fn thresholds() {
+        let addresses = [Address([1; 6]), Address([2; 6]), Address([3; 6])];
+        let devices = [device(None), device(Some(-70)), device(None)];
+        let mut filter = RssiFilter::new(&devices, &addresses, Some(-90));
+
+        // The global threshold
+        assert!(filter.accept(addresses[0], -90i8 as u8));
+        assert!(!filter.accept(addresses[0], -91i8 as u8));
+        // The device threshold takes precedence
+        assert!(filter.accept(addresses[1], -70i8 as u8));
+        assert!(!filter.accept(addresses[1], -80i8 as u8));
+        assert!(!filter.accept(addresses[1], -85i8 as u8));
+        assert_eq!(filter.filtered(addresses[0]), Some(1));
+        assert_eq!(filter.filtered(addresses[1]), Some(2));
+        assert_eq!(filter.filtered(addresses[2]), Some(0));
+
+        let mut filter = RssiFilter::new(&devices[..1], &addresses[..1], None);
+        assert!(filter.accept(addresses[0], -120i8 as u8));
+        assert_eq!(filter.filtered(addresses[0]), None);
+    }
+}
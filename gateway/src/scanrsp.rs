@@ -0,0 +1,150 @@
+//! Merge scannable advertisements with their scan responses.
+//!
+//! Devices may put part of their advertising data (e.g. the local name, or
+//! the payload) into the scan response, which an active scanner requests right
+//! after a scannable advertisement. Scannable advertisements are held until
+//! the scan response from the same address arrives, and their AD structures
+//! are then processed together. Without a scan response (e.g. with a passive
+//! scanner), the advertisement is processed alone after a short timeout.
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::advertising::{AdvertisingReport, ReportKind};
+use crate::types::Address;
+
+/// The scan response is sent within a few milliseconds after the
+/// advertisement (but may be delivered later by the controller).
+const SCAN_RESPONSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// An advertising report with the time and channel of its HCI packet.
+#[derive(Debug)]
+pub struct Received {
+    pub report: AdvertisingReport,
+    pub timestamp: SystemTime,
+    pub channel: Option<u8>,
+}
+
+/// Holds the scannable advertisements until their scan response arrives.
+#[derive(Default)]
+pub struct ScanResponseMerger {
+    /// Advertisements by address, with the time they were received
+    pending: HashMap<Address, (Received, Instant)>,
+}
+
+impl ScanResponseMerger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a report. Return the reports that can be processed: Scannable
+    /// advertisements are returned with the AD structures of their scan
+    /// response appended (or without them, see `expire`).
+    pub fn add(&mut self, received: Received, now: Instant) -> Vec<Received> {
+        let address = received.report.address;
+        match received.report.kind {
+            ReportKind::NonScannable => vec![received],
+            // A previous advertisement didn't get a scan response
+            ReportKind::Scannable => self
+                .pending
+                .insert(address, (received, now))
+                .map(|(previous, _)| previous)
+                .into_iter()
+                .collect(),
+            ReportKind::ScanResponse => match self.pending.remove(&address) {
+                Some((mut advertisement, _)) => {
+                    let report = &mut advertisement.report;
+                    report.rssi = report.rssi.max(received.report.rssi);
+                    report.data.extend(received.report.data);
+                    vec![advertisement]
+                }
+                None => vec![received],
+            },
+        }
+    }
+
+    /// Return the advertisements that didn't get a scan response within the
+    /// timeout.
+    pub fn expire(&mut self, now: Instant) -> Vec<Received> {
+        let expired: Vec<Address> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, since))| now.duration_since(*since) >= SCAN_RESPONSE_TIMEOUT)
+            .map(|(address, _)| *address)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|address| self.pending.remove(&address))
+            .map(|(received, _)| received)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::advertising::AdStructure;
+
+    const ADDR: Address = Address([1, 2, 3, 4, 5, 6]);
+
+    fn received(kind: ReportKind, rssi: u8, data: Vec<AdStructure>) -> Received {
+        Received {
+            report: AdvertisingReport {
+                address: ADDR,
+                rssi,
+                kind,
+                data,
+            },
+            timestamp: SystemTime::UNIX_EPOCH,
+            channel: None,
+        }
+    }
+
+    fn payload() -> AdStructure {
+        AdStructure::ManufacturerSpecificData {
+            company_identifier: 0xffff,
+            data: vec![52, 4],
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut merger = ScanResponseMerger::new();
+        let now = Instant::now();
+        let name = AdStructure::CompleteLocalName("Sensilo".into());
+
+        let beacon = received(ReportKind::NonScannable, 200, vec![payload()]);
+        assert_eq!(merger.add(beacon, now).len(), 1);
+
+        let advertisement = received(ReportKind::Scannable, 190, vec![payload()]);
+        assert!(merger.add(advertisement, now).is_empty());
+        let response = received(ReportKind::ScanResponse, 195, vec![name.clone()]);
+        let merged = merger.add(response, now + Duration::from_millis(5));
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].report.rssi, 195);
+        assert_eq!(merged[0].report.data, vec![payload(), name.clone()]);
+
+        // A scan response without advertisement is passed on
+        let response = received(ReportKind::ScanResponse, 195, vec![name]);
+        assert_eq!(merger.add(response, now).len(), 1);
+    }
+
+    #[test]
+    fn expire() {
+        let mut merger = ScanResponseMerger::new();
+        let now = Instant::now();
+        let advertisement = received(ReportKind::Scannable, 190, vec![payload()]);
+        assert!(merger.add(advertisement, now).is_empty());
+        assert!(merger.expire(now + Duration::from_millis(50)).is_empty());
+        let expired = merger.expire(now + SCAN_RESPONSE_TIMEOUT);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].report.data, vec![payload()]);
+
+        // The next advertisement replaces an unanswered one
+        let advertisement = received(ReportKind::Scannable, 190, vec![payload()]);
+        assert!(merger.add(advertisement, now).is_empty());
+        let advertisement = received(ReportKind::Scannable, 190, vec![]);
+        let previous = merger.add(advertisement, now);
+        assert_eq!(previous[0].report.data, vec![payload()]);
+    }
+}
@@ -0,0 +1,272 @@
+//! Smooth the values of a device, to filter out glitches (e.g. a single
+//! wrong reading because of a marginal radio reception).
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{bail, Result};
+
+use crate::config::{self, Metric, SmoothingFilter};
+use crate::measurement::{AmbientLight, Humidity, Measurement, Temperature};
+use crate::types::Address;
+
+/// A series of values of a device: The metric and the probe or input number
+/// (0 for the other metrics).
+type Series = (Address, Metric, u8);
+
+enum State {
+    /// The last values, oldest first
+    Median(VecDeque<f64>),
+    Ewma(f64),
+}
+
+/// Replaces the values of the configured metrics with the smoothed values.
+///
+/// Without config, the measurements are not changed.
+pub struct Smoother {
+    filters: HashMap<Metric, SmoothingFilter>,
+    keep_raw: bool,
+    states: HashMap<Series, State>,
+}
+
+impl Smoother {
+    pub fn new(config: Option<&config::Smoothing>) -> Result<Self> {
+        let mut filters = HashMap::new();
+        for (name, &filter) in config.iter().flat_map(|config| &config.metrics) {
+            let metric = match Metric::from_name(name) {
+                Some(metric) => metric,
+                None => bail!("Unknown metric {}", name),
+            };
+            match metric {
+                Metric::Pulses | Metric::Contact => {
+                    bail!("The metric {} cannot be smoothed", metric.name())
+                }
+                _ => {}
+            }
+            match filter {
+                SmoothingFilter::Median { n: 0 } => {
+                    bail!(
+                        "Invalid median filter for {}: n must be at least 1",
+                        metric.name()
+                    )
+                }
+                SmoothingFilter::Ewma { alpha } if !(alpha > 0.0 && alpha <= 1.0) => bail!(
+                    "Invalid EWMA filter for {}: alpha must be in (0, 1]",
+                    metric.name()
+                ),
+                _ => {}
+            }
+            filters.insert(metric, filter);
+        }
+        Ok(Self {
+            filters,
+            keep_raw: config.is_some_and(|config| config.keep_raw),
+            states: HashMap::new(),
+        })
+    }
+
+    /// Smooth the values of a measurement. If the raw values are kept, they
+    /// are added to the measurement (only the smoothed metrics).
+    pub fn apply(&mut self, measurement: &mut Measurement) {
+        if self.filters.is_empty() {
+            return;
+        }
+        let raw = measurement.clone();
+        let address = measurement.address;
+
+        if let Some(temperature) = measurement.temperature.as_mut() {
+            let value = f64::from(temperature.as_millidegrees_celsius());
+            if let Some(v) = self.smooth((address, Metric::Temperature, 0), value) {
+                *temperature = Temperature::from_millidegrees_celsius(v.round() as i32);
+            }
+        }
+        if let Some(humidity) = measurement.humidity.as_mut() {
+            let value = f64::from(humidity.as_millipercent());
+            if let Some(v) = self.smooth((address, Metric::Humidity, 0), value) {
+                *humidity = Humidity::from_millipercent(v.round() as i32);
+            }
+        }
+        if let Some(light) = measurement.ambient_light.as_mut() {
+            let value = f64::from(light.as_lux());
+            if let Some(v) = self.smooth((address, Metric::AmbientLight, 0), value) {
+                *light = AmbientLight::from_lux(v as f32);
+            }
+        }
+        if let Some(temperature) = measurement.thermocouple_temperature.as_mut() {
+            let value = f64::from(temperature.as_millidegrees_celsius());
+            let series = (address, Metric::ThermocoupleTemperature, 0);
+            if let Some(v) = self.smooth(series, value) {
+                *temperature = Temperature::from_millidegrees_celsius(v.round() as i32);
+            }
+        }
+        for (&index, temperature) in measurement.external_temperatures.iter_mut() {
+            let value = f64::from(temperature.as_millidegrees_celsius());
+            let series = (address, Metric::ExternalTemperature, index);
+            if let Some(v) = self.smooth(series, value) {
+                *temperature = Temperature::from_millidegrees_celsius(v.round() as i32);
+            }
+        }
+        for (&channel, input) in measurement.analog.iter_mut() {
+            let value = f64::from(input.millivolts);
+            if let Some(v) = self.smooth((address, Metric::Analog, channel), value) {
+                input.millivolts = v.round() as u16;
+            }
+        }
+
+        if self.keep_raw {
+            measurement.raw = Some(Box::new(self.smoothed_only(raw)));
+        }
+    }
+
+    /// Add a value to the series, and return the smoothed value (if the
+    /// metric is smoothed).
+    fn smooth(&mut self, series: Series, value: f64) -> Option<f64> {
+        let filter = *self.filters.get(&series.1)?;
+        let state = self.states.entry(series).or_insert_with(|| match filter {
+            SmoothingFilter::Median { .. } => State::Median(VecDeque::new()),
+            SmoothingFilter::Ewma { .. } => State::Ewma(value),
+        });
+        Some(match (state, filter) {
+            (State::Median(values), SmoothingFilter::Median { n }) => {
+                values.push_back(value);
+                while values.len() > n {
+                    values.pop_front();
+                }
+                median(values.iter().copied())
+            }
+            (State::Ewma(average), SmoothingFilter::Ewma { alpha }) => {
+                *average = alpha * value + (1.0 - alpha) * *average;
+                *average
+            }
+            // The filters don't change at runtime
+            _ => value,
+        })
+    }
+
+    /// Remove the values of the metrics that are not smoothed.
+    fn smoothed_only(&self, mut raw: Measurement) -> Measurement {
+        let smoothed = |metric| self.filters.contains_key(&metric);
+        if !smoothed(Metric::Temperature) {
+            raw.temperature = None;
+        }
+        if !smoothed(Metric::Humidity) {
+            raw.humidity = None;
+        }
+        if !smoothed(Metric::AmbientLight) {
+            raw.ambient_light = None;
+        }
+        if !smoothed(Metric::ThermocoupleTemperature) {
+            raw.thermocouple_temperature = None;
+        }
+        if !smoothed(Metric::ExternalTemperature) {
+            raw.external_temperatures.clear();
+        }
+        if !smoothed(Metric::Analog) {
+            raw.analog.clear();
+        }
+        raw.secondary_temperature = None;
+        raw.secondary_humidity = None;
+        raw.ambient_light_als = None;
+        raw.ambient_light_white = None;
+        raw.pulses = None;
+        raw.pulse_rate = None;
+        raw.contact = None;
+        raw
+    }
+}
+
+/// Median of the values (the mean of the two middle values for an even
+/// count).
+fn median(values: impl Iterator<Item = f64>) -> f64 {
+    let mut values: Vec<f64> = values.collect();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        values[middle]
+    } else {
+        (values[middle - 1] + values[middle]) / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use crate::measurement::MeasurementBuilder;
+
+    fn measurement(temperature: i32, humidity: i32) -> Measurement {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .local_name("Sensilo")
+            .counter(1)
+            .temperature(Temperature::from_millidegrees_celsius(temperature))
+            .humidity(Humidity::from_millipercent(humidity));
+        builder.build().unwrap()
+    }
+
+    fn smoother(metrics: &[(Metric, SmoothingFilter)], keep_raw: bool) -> Smoother {
+        let config = config::Smoothing {
+            keep_raw,
+            metrics: metrics
+                .iter()
+                .map(|&(metric, filter)| (metric.name().to_string(), filter))
+                .collect::<BTreeMap<_, _>>(),
+        };
+        Smoother::new(Some(&config)).unwrap()
+    }
+
+    #[test]
+    fn median_filter() {
+        let mut smoother = smoother(
+            &[(Metric::Temperature, SmoothingFilter::Median { n: 3 })],
+            false,
+        );
+        let mut temperatures = vec![];
+        for &value in &[20000, 20100, 85000, 20200, 20300] {
+            let mut mmt = measurement(value, 50000);
+            smoother.apply(&mut mmt);
+            temperatures.push(mmt.temperature.unwrap().as_millidegrees_celsius());
+            // Not smoothed
+            assert_eq!(mmt.humidity.unwrap().as_millipercent(), 50000);
+            assert!(mmt.raw.is_none());
+        }
+        assert_eq!(temperatures, vec![20000, 20050, 20100, 20200, 20300]);
+    }
+
+    #[test]
+    fn ewma_filter() {
+        let mut smoother = smoother(
+            &[(Metric::Humidity, SmoothingFilter::Ewma { alpha: 0.25 })],
+            true,
+        );
+        let mut humidities = vec![];
+        for &value in &[40000, 48000, 40000] {
+            let mut mmt = measurement(21000, value);
+            smoother.apply(&mut mmt);
+            humidities.push(mmt.humidity.unwrap().as_millipercent());
+
+            let raw = mmt.raw.unwrap();
+            assert_eq!(raw.humidity.unwrap().as_millipercent(), value);
+            assert!(raw.temperature.is_none());
+        }
+        assert_eq!(humidities, vec![40000, 42000, 41500]);
+    }
+
+    #[test]
+    fn invalid() {
+        let config = |metric, filter| config::Smoothing {
+            keep_raw: false,
+            metrics: vec![(String::from(metric), filter)].into_iter().collect(),
+        };
+        let invalid = [
+            config("pulses", SmoothingFilter::Median { n: 3 }),
+            config("pressure", SmoothingFilter::Median { n: 3 }),
+            config("temperature", SmoothingFilter::Median { n: 0 }),
+            config("temperature", SmoothingFilter::Ewma { alpha: 0.0 }),
+            config("temperature", SmoothingFilter::Ewma { alpha: 1.5 }),
+        ];
+        for config in &invalid {
+            assert!(Smoother::new(Some(config)).is_err());
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! Snapshots of the device state (last received counters, contacts, pulse
+//! counts, missing metrics, the deduplication window and the counters of the
+//! replay protection).
+//!
+//! The gateway keeps the state in memory, a snapshot is saved to the state
+//! file (at most once per save interval) and restored at startup. Snapshots
+//! can be exported to a single portable JSON file and imported on another host
+//! or after an upgrade. All times are wall clock times, the internal monotonic
+//! instants are not meaningful across reboots.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::config::{self, Metric};
+use crate::json::{self, Value};
+use crate::types::Address;
+
+/// Version of the snapshot format.
+const VERSION: u64 = 1;
+
+/// The state of a device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceState {
+    /// Last received counter, and when it was received
+    pub counter: Option<(u16, SystemTime)>,
+    /// Last contact event counter
+    pub contact_events: Option<u16>,
+    /// Last pulse count, and when it was received
+    pub pulses: Option<(u32, SystemTime)>,
+    /// Expected metrics that are missing
+    pub missing: Vec<Metric>,
+    /// Deduplication window (counter, frame index and time of reception),
+    /// oldest first
+    pub dedup: Vec<(u16, u8, SystemTime)>,
+    /// Counter of the last authenticated payload (for the replay protection)
+    pub replay_counter: Option<u16>,
+}
+
+/// The state of all devices.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub devices: HashMap<Address, DeviceState>,
+}
+
+/// Limits how often the state file is written: The state changes with almost
+/// every batch of packets, and writing the file that often wears out SD
+/// cards.
+pub struct SaveThrottle {
+    interval: Duration,
+    last_save: Option<Instant>,
+    pending: bool,
+}
+
+impl SaveThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_save: None,
+            pending: false,
+        }
+    }
+
+    /// Remember that the state changed.
+    pub fn changed(&mut self) {
+        self.pending = true;
+    }
+
+    /// Whether the changed state should be saved now. The save is assumed to
+    /// happen.
+    pub fn due(&mut self, now: Instant) -> bool {
+        if !self.pending
+            || self
+                .last_save
+                .is_some_and(|last| now.saturating_duration_since(last) < self.interval)
+        {
+            return false;
+        }
+        self.last_save = Some(now);
+        self.pending = false;
+        true
+    }
+}
+
+/// Converts between the monotonic instants used by the gateway and wall
+/// clock times.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    wall: SystemTime,
+    instant: Instant,
+}
+
+impl Clock {
+    pub fn now() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            instant: Instant::now(),
+        }
+    }
+
+    /// Time elapsed since an instant.
+    pub fn elapsed(&self, instant: Instant) -> Duration {
+        self.instant.saturating_duration_since(instant)
+    }
+
+    pub fn system_time(&self, instant: Instant) -> SystemTime {
+        self.wall
+            .checked_sub(self.elapsed(instant))
+            .unwrap_or(UNIX_EPOCH)
+    }
+
+    /// Convert a wall clock time to an instant. Times in the future (e.g.
+    /// after the clock of the host was adjusted) are treated as now. Return
+    /// `None` if the time is before the start of the monotonic clock (usually
+    /// the boot of the host).
+    pub fn instant(&self, time: SystemTime) -> Option<Instant> {
+        let age = self.wall.duration_since(time).unwrap_or_default();
+        self.instant.checked_sub(age)
+    }
+}
+
+fn to_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+fn from_millis(value: &Value) -> Option<SystemTime> {
+    value
+        .as_u64()
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Get an integer member of an object that must fit into `T`.
+fn integer<T: TryFrom<u64>>(value: &Value, key: &str) -> Result<Option<T>> {
+    match value.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(member) => member
+            .as_u64()
+            .and_then(|n| T::try_from(n).ok())
+            .map(Some)
+            .ok_or_else(|| anyhow!("Invalid {}", key)),
+    }
+}
+
+fn timestamp(value: &Value, key: &str) -> Result<SystemTime> {
+    value
+        .get(key)
+        .and_then(from_millis)
+        .ok_or_else(|| anyhow!("Invalid or missing {}", key))
+}
+
+impl DeviceState {
+    fn to_json(&self) -> String {
+        let mut fields = vec![];
+        if let Some((counter, time)) = self.counter {
+            fields.push(format!("\"counter\":{}", counter));
+            fields.push(format!("\"last_seen\":{}", to_millis(time)));
+        }
+        if let Some(events) = self.contact_events {
+            fields.push(format!("\"contact_events\":{}", events));
+        }
+        if let Some((count, time)) = self.pulses {
+            fields.push(format!("\"pulse_count\":{}", count));
+            fields.push(format!("\"pulse_time\":{}", to_millis(time)));
+        }
+        if !self.missing.is_empty() {
+            let names: Vec<String> = self
+                .missing
+                .iter()
+                .map(|metric| json::string(metric.name()))
+                .collect();
+            fields.push(format!("\"missing_metrics\":[{}]", names.join(",")));
+        }
+        if !self.dedup.is_empty() {
+            let entries: Vec<String> = self
+                .dedup
+                .iter()
+                .map(|(counter, frame_index, time)| {
+                    format!("[{},{},{}]", counter, frame_index, to_millis(*time))
+                })
+                .collect();
+            fields.push(format!("\"dedup\":[{}]", entries.join(",")));
+        }
+        if let Some(counter) = self.replay_counter {
+            fields.push(format!("\"replay_counter\":{}", counter));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+
+    fn from_json(value: &Value) -> Result<Self> {
+        let mut state = DeviceState::default();
+        if let Some(counter) = integer(value, "counter")? {
+            state.counter = Some((counter, timestamp(value, "last_seen")?));
+        }
+        state.contact_events = integer(value, "contact_events")?;
+        if let Some(count) = integer(value, "pulse_count")? {
+            state.pulses = Some((count, timestamp(value, "pulse_time")?));
+        }
+        if let Some(missing) = value.get("missing_metrics") {
+            for name in missing
+                .as_array()
+                .ok_or_else(|| anyhow!("Invalid missing_metrics"))?
+            {
+                let name = name
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Invalid missing_metrics"))?;
+                // Metrics of newer versions are ignored
+                match Metric::from_name(name) {
+                    Some(metric) => state.missing.push(metric),
+                    None => log::warn!("Ignoring unknown metric in snapshot: {}", name),
+                }
+            }
+        }
+        if let Some(dedup) = value.get("dedup") {
+            for entry in dedup.as_array().ok_or_else(|| anyhow!("Invalid dedup"))? {
+                let invalid = || anyhow!("Invalid dedup entry: {:?}", entry);
+                match entry.as_array().ok_or_else(invalid)?.as_slice() {
+                    [counter, frame_index, time] => state.dedup.push((
+                        counter
+                            .as_u64()
+                            .and_then(|n| u16::try_from(n).ok())
+                            .ok_or_else(invalid)?,
+                        frame_index
+                            .as_u64()
+                            .and_then(|n| u8::try_from(n).ok())
+                            .ok_or_else(invalid)?,
+                        from_millis(time).ok_or_else(invalid)?,
+                    )),
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        state.replay_counter = integer(value, "replay_counter")?;
+        Ok(state)
+    }
+}
+
+impl Snapshot {
+    /// Encode the snapshot as JSON (one device per line).
+    pub fn to_json(&self, saved: SystemTime) -> String {
+        let mut devices: Vec<(String, String)> = self
+            .devices
+            .iter()
+            .map(|(address, state)| (base16::encode_lower(&address.0), state.to_json()))
+            .collect();
+        devices.sort();
+        let devices: Vec<String> = devices
+            .into_iter()
+            .map(|(address, state)| format!("    {}: {}", json::string(&address), state))
+            .collect();
+        format!(
+            "{{\n  \"version\": {},\n  \"saved\": {},\n  \"devices\": {{\n{}\n  }}\n}}\n",
+            VERSION,
+            to_millis(saved),
+            devices.join(",\n")
+        )
+    }
+
+    pub fn from_json(input: &str) -> Result<Self> {
+        let document = json::parse(input).map_err(|e| anyhow!("Invalid JSON: {}", e))?;
+        match document.get("version").and_then(Value::as_u64) {
+            Some(VERSION) => {}
+            Some(version) => bail!("Unsupported snapshot version: {}", version),
+            None => bail!("Missing snapshot version"),
+        }
+        let mut snapshot = Snapshot::default();
+        let devices = document
+            .get("devices")
+            .and_then(Value::as_object)
+            .ok_or_else(|| anyhow!("Missing devices"))?;
+        for (hex, value) in devices {
+            let mut address = [0; 6];
+            if hex.len() != 12 || base16::decode_slice(hex, &mut address).is_err() {
+                bail!("Invalid address: {}", hex);
+            }
+            let state = DeviceState::from_json(value).with_context(|| format!("Device {}", hex))?;
+            snapshot.devices.insert(Address(address), state);
+        }
+        Ok(snapshot)
+    }
+
+    /// Load a snapshot. A missing file results in an empty snapshot.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(json) => Self::from_json(&json)
+                .with_context(|| format!("Invalid state snapshot in {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Could not read state snapshot {}", path.display()))
+            }
+        }
+    }
+
+    /// Save the snapshot. The file is replaced atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = tmp_path(path);
+        fs::write(&tmp, self.to_json(SystemTime::now()))
+            .and_then(|_| fs::rename(&tmp, path))
+            .with_context(|| format!("Could not write state snapshot {}", path.display()))
+    }
+}
+
+/// The temporary file next to a file (with a suffix, so that files that only
+/// differ in the extension don't share it).
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Export the saved state to a snapshot.
+pub fn export(config: &config::Config, path: &Path) -> Result<()> {
+    let snapshot = match config.state.file {
+        Some(ref file) => Snapshot::load(file)?,
+        None => bail!("No state file is configured"),
+    };
+    snapshot.save(path)?;
+    status!(
+        "Exported the state of {} device(s) to {}",
+        snapshot.devices.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Import a snapshot into the state file. The gateway must not be running,
+/// it would overwrite the file.
+pub fn import(config: &config::Config, path: &Path) -> Result<()> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("Could not read snapshot {}", path.display()))?;
+    let snapshot = Snapshot::from_json(&json)
+        .with_context(|| format!("Invalid snapshot in {}", path.display()))?;
+    let file = match config.state.file {
+        Some(ref file) => file,
+        None => bail!("No state file is configured"),
+    };
+    snapshot.save(file)?;
+    status!("Wrote the device state to {}", file.display());
+    status!(
+        "Imported the state of {} device(s) from {}",
+        snapshot.devices.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: Address = Address([0x86, 0x4f, 0xe0, 0x67, 0x99, 0x7a]);
+
+    fn time(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let mut snapshot = Snapshot::default();
+        snapshot.devices.insert(
+            ADDR,
+            DeviceState {
+                counter: Some((1076, time(1_607_500_000_123))),
+                contact_events: Some(4),
+                pulses: Some((12345, time(1_607_500_000_000))),
+                missing: vec![Metric::Humidity, Metric::AmbientLight],
+                dedup: vec![(1075, 0, time(1_607_499_997_000)), (1076, 1, time(1))],
+                replay_counter: Some(1076),
+            },
+        );
+        snapshot
+            .devices
+            .insert(Address([1, 2, 3, 4, 5, 6]), DeviceState::default());
+        let json = snapshot.to_json(time(1_607_500_001_000));
+        assert!(json.contains(
+            "\"010203040506\": {},\n    \
+             \"864fe067997a\": {\"counter\":1076,\"last_seen\":1607500000123,"
+        ));
+        assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn json_invalid() {
+        assert!(Snapshot::from_json("").is_err());
+        assert!(Snapshot::from_json("{\"devices\": {}}").is_err());
+        assert!(Snapshot::from_json("{\"version\": 2, \"devices\": {}}").is_err());
+        for device in &[
+            "\"xyz\": {}",
+            "\"864fe067997a\": {\"counter\": 1}",
+            "\"864fe067997a\": {\"counter\": 70000, \"last_seen\": 0}",
+            "\"864fe067997a\": {\"dedup\": [[1, 2]]}",
+            "\"864fe067997a\": {\"dedup\": [[1, 256, 0]]}",
+            "\"864fe067997a\": {\"replay_counter\": 70000}",
+        ] {
+            let json = format!("{{\"version\": 1, \"devices\": {{{}}}}}", device);
+            assert!(Snapshot::from_json(&json).is_err(), "{}", device);
+        }
+        // Unknown metrics and members are ignored
+        let json = "{\"version\": 1, \"devices\": {\"864fe067997a\": \
+                    {\"missing_metrics\": [\"co2\", \"humidity\"], \"new\": true}}}";
+        assert_eq!(
+            Snapshot::from_json(json).unwrap().devices[&ADDR].missing,
+            vec![Metric::Humidity]
+        );
+    }
+
+    #[test]
+    fn save_throttle() {
+        let mut throttle = SaveThrottle::new(Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(!throttle.due(now));
+        throttle.changed();
+        assert!(throttle.due(now));
+        assert!(!throttle.due(now));
+        // Changes within the interval are saved after it
+        throttle.changed();
+        assert!(!throttle.due(now + Duration::from_secs(10)));
+        assert!(throttle.due(now + Duration::from_secs(30)));
+        assert!(!throttle.due(now + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn tmp_paths() {
+        assert_eq!(
+            tmp_path(Path::new("/var/lib/state.json")),
+            Path::new("/var/lib/state.json.tmp")
+        );
+        assert_ne!(
+            tmp_path(Path::new("state.json")),
+            tmp_path(Path::new("state.dedup"))
+        );
+    }
+
+    #[test]
+    fn clock() {
+        let clock = Clock::now();
+        let instant = clock.instant - Duration::from_secs(5);
+        let time = clock.system_time(instant);
+        assert_eq!(time, clock.wall - Duration::from_secs(5));
+        assert_eq!(clock.instant(time), Some(instant));
+        // Times in the future are treated as now
+        assert_eq!(
+            clock.instant(clock.wall + Duration::from_secs(5)),
+            Some(clock.instant)
+        );
+    }
+}
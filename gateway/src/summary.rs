@@ -0,0 +1,203 @@
+//! Minimum, maximum and mean of the values of every device over a period
+//! (e.g. a day), for long-term dashboards.
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::config;
+use crate::influxdb::{self, Point};
+use crate::measurement::Measurement;
+use crate::types::Address;
+
+/// A series of values: The metric name and the additional tags (e.g. the
+/// probe index).
+type Series = (String, Vec<(&'static str, String)>);
+
+#[derive(Debug, Clone, Copy)]
+struct Stats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+impl Stats {
+    fn new(value: f64) -> Self {
+        Self {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The summary of a device in the current period.
+struct Period {
+    /// Start of the period, in seconds since the Unix epoch
+    start: u64,
+    local_name: String,
+    stats: Vec<(Series, Stats)>,
+}
+
+/// Collects the values of every device, and emits summary points when a
+/// period has ended.
+///
+/// A period ends with the first measurement of the device in the next
+/// period, the summary point has the start of the period as timestamp.
+pub struct Summaries {
+    period_s: Option<u64>,
+    metric: String,
+    periods: HashMap<Address, Period>,
+}
+
+impl Summaries {
+    pub fn new(config: Option<&config::Summary>) -> Self {
+        Self {
+            period_s: config.map(|c| c.period_s.max(1)),
+            metric: config.map_or_else(String::new, |c| c.metric.clone()),
+            periods: HashMap::new(),
+        }
+    }
+
+    /// Add the values of a measurement. Return the summary points of the
+    /// previous period, if the measurement belongs to a new period.
+    pub fn add(&mut self, mmt: &Measurement, units: &config::Units) -> Vec<Point> {
+        let period_s = match self.period_s {
+            Some(period_s) => period_s,
+            None => return vec![],
+        };
+        let secs = mmt
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let start = secs - secs % period_s;
+
+        let mut points = vec![];
+        let ended = match self.periods.get(&mmt.address) {
+            Some(period) => period.start != start,
+            None => false,
+        };
+        if ended {
+            let period = self.periods.remove(&mmt.address).unwrap();
+            points = self.points(mmt.address, period);
+        }
+        let period = self.periods.entry(mmt.address).or_insert_with(|| Period {
+            start,
+            local_name: mmt.local_name.clone(),
+            stats: vec![],
+        });
+        period.local_name = mmt.local_name.clone();
+        for point in influxdb::value_points(mmt, units) {
+            let value = match point
+                .fields
+                .first()
+                .and_then(|(_, v)| v.parse::<f64>().ok())
+            {
+                Some(value) => value,
+                None => continue,
+            };
+            let series = (point.metric.into_owned(), point.tags);
+            match period.stats.iter_mut().find(|(s, _)| *s == series) {
+                Some((_, stats)) => stats.add(value),
+                None => period.stats.push((series, Stats::new(value))),
+            }
+        }
+        points
+    }
+
+    fn points(&self, address: Address, period: Period) -> Vec<Point> {
+        let timestamp = UNIX_EPOCH + Duration::from_secs(period.start);
+        let local_name = period.local_name;
+        period
+            .stats
+            .into_iter()
+            .map(|((metric, tags), stats)| {
+                let mut point_tags = vec![("metric", metric)];
+                point_tags.extend(tags);
+                Point {
+                    metric: self.metric.clone().into(),
+                    address,
+                    local_name: local_name.clone(),
+                    tags: point_tags,
+                    fields: vec![
+                        ("min", stats.min.to_string()),
+                        ("max", stats.max.to_string()),
+                        ("mean", format!("{:.3}", stats.sum / stats.count as f64)),
+                        ("count", stats.count.to_string()),
+                    ],
+                    timestamp,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::measurement::{MeasurementBuilder, Temperature};
+
+    const DAY: u64 = 86400;
+
+    fn measurement(secs: u64, temperature: i32) -> Measurement {
+        let mut builder = MeasurementBuilder::new(Address([1, 2, 3, 4, 5, 6]), 200);
+        builder
+            .timestamp(UNIX_EPOCH + Duration::from_secs(secs))
+            .local_name("Sensilo")
+            .counter(1)
+            .temperature(Temperature::from_millidegrees_celsius(temperature));
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn disabled() {
+        let mut summaries = Summaries::new(None);
+        let units = config::Units::default();
+        assert!(summaries.add(&measurement(0, 20000), &units).is_empty());
+        assert!(summaries.add(&measurement(DAY, 20000), &units).is_empty());
+    }
+
+    #[test]
+    fn daily() {
+        let config = config::Summary {
+            period_s: DAY,
+            metric: "daily".into(),
+        };
+        let mut summaries = Summaries::new(Some(&config));
+        let units = config::Units {
+            milli: false,
+            ..Default::default()
+        };
+        let start = 18600 * DAY;
+        for (offset, temperature) in &[(10, 20000), (3600, 22000), (DAY - 1, 24500)] {
+            assert!(summaries
+                .add(&measurement(start + offset, *temperature), &units)
+                .is_empty());
+        }
+
+        let points = summaries.add(&measurement(start + DAY + 5, 19000), &units);
+        assert_eq!(points.len(), 1);
+        let point = &points[0];
+        assert_eq!(point.metric, "daily");
+        assert_eq!(point.tags, vec![("metric", "temperature".to_string())]);
+        assert_eq!(
+            point.fields,
+            vec![
+                ("min", "20".to_string()),
+                ("max", "24.5".to_string()),
+                ("mean", "22.167".to_string()),
+                ("count", "3".to_string()),
+            ]
+        );
+        assert_eq!(point.timestamp, UNIX_EPOCH + Duration::from_secs(start));
+    }
+}
@@ -0,0 +1,83 @@
+//! Templates for metric names, with placeholders for device information.
+use std::collections::HashMap;
+
+use crate::config;
+use crate::types::Address;
+
+/// Names of the config devices, used in the templates.
+struct DeviceInfo {
+    name: String,
+    location: Option<String>,
+    site: Option<String>,
+}
+
+/// Device information of all configured devices.
+pub struct Devices(HashMap<Address, DeviceInfo>);
+
+impl Devices {
+    /// The addresses must be in the same order as the devices in the config.
+    pub fn new(devices: &[config::Device], addresses: &[Address]) -> Self {
+        Self(
+            devices
+                .iter()
+                .zip(addresses)
+                .map(|(dev, address)| {
+                    (
+                        *address,
+                        DeviceInfo {
+                            name: dev.name.clone(),
+                            location: dev.location.clone(),
+                            site: dev.site.clone(),
+                        },
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Return the placeholder values of a metric of a device: `metric`,
+    /// `address`, `local_name`, `name`, `location` and `site`.
+    pub fn vars<'a>(
+        &'a self,
+        metric: &'a str,
+        address: &'a str,
+        local_name: &'a str,
+        device: Address,
+    ) -> Vec<(&'static str, &'a str)> {
+        let info = self.0.get(&device);
+        vec![
+            ("metric", metric),
+            ("address", address),
+            ("local_name", local_name),
+            ("name", info.map_or("", |d| &d.name)),
+            (
+                "location",
+                info.and_then(|d| d.location.as_deref()).unwrap_or(""),
+            ),
+            ("site", info.and_then(|d| d.site.as_deref()).unwrap_or("")),
+        ]
+    }
+}
+
+/// Replace all `{name}` placeholders in the template.
+pub fn expand(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut result = template.to_string();
+    for (name, value) in vars {
+        result = result.replace(&format!("{{{}}}", name), value);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = [("metric", "temperature"), ("name", "Kitchen")];
+        assert_eq!(
+            expand("sensilo.{name}.{metric}.{unknown}", &vars),
+            "sensilo.Kitchen.temperature.{unknown}"
+        );
+    }
+}
@@ -0,0 +1,19 @@
+//! Helpers of the unit tests.
+
+/// Xorshift, to get reproducible inputs without additional dependencies.
+pub struct Xorshift(u32);
+
+impl Xorshift {
+    /// The seed must not be zero (the sequence would only contain zeros).
+    pub fn new(seed: u32) -> Self {
+        assert_ne!(seed, 0);
+        Self(seed)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
@@ -0,0 +1,250 @@
+//! Broadcast of the time to the nodes.
+//!
+//! The nodes have no calendar clock. With a `[time_sync]` section, the gateway
+//! broadcasts time beacons (non-connectable advertisements) through a raw HCI
+//! socket, which the nodes listen for from time to time. A time beacon has the
+//! complete local name `SensiloTime` and manufacturer specific data (company
+//! identifier `0xffff`) with the Unix time (u32) and the UTC offset of the
+//! local time in minutes (i16). The advertising data is updated every second.
+//!
+//! The time beacons are not authenticated.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::config;
+
+/// Local name of the time beacons.
+const LOCAL_NAME: &str = "SensiloTime";
+
+/// Company identifier of the manufacturer specific data.
+const COMPANY_IDENTIFIER: u16 = 0xffff;
+
+/// H4 packet type of HCI commands.
+const HCI_COMMAND_PKT: u8 = 0x01;
+
+// Opcodes of the LE controller commands (OGF 0x08)
+const LE_SET_ADVERTISING_PARAMETERS: u16 = 0x2006;
+const LE_SET_ADVERTISING_DATA: u16 = 0x2008;
+const LE_SET_ADVERTISING_ENABLE: u16 = 0x200a;
+
+/// Advertising type of non-connectable undirected advertisements.
+const ADV_NONCONN_IND: u8 = 0x03;
+
+/// Maximum length of the advertising data.
+const MAX_ADVERTISING_DATA_LEN: usize = 31;
+
+/// An HCI command packet (in H4 format).
+fn command(opcode: u16, params: &[u8]) -> Vec<u8> {
+    let mut packet = vec![HCI_COMMAND_PKT];
+    packet.extend_from_slice(&opcode.to_le_bytes());
+    packet.push(params.len() as u8);
+    packet.extend_from_slice(params);
+    packet
+}
+
+/// Parameters of the LE Set Advertising Parameters command.
+fn advertising_parameters(interval_ms: u16) -> Vec<u8> {
+    // In units of 0.625 ms, non-connectable advertisements must be at least
+    // 100 ms apart (before Bluetooth 5.0)
+    let interval = (u32::from(interval_ms) * 8 / 5).clamp(0xa0, 0x4000) as u16;
+    let mut params = vec![];
+    params.extend_from_slice(&interval.to_le_bytes());
+    params.extend_from_slice(&interval.to_le_bytes());
+    params.push(ADV_NONCONN_IND);
+    // Public own address, no peer address
+    params.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+    // All advertising channels, no filter
+    params.extend_from_slice(&[0x07, 0]);
+    params
+}
+
+/// Parameters of the LE Set Advertising Data command: The length and the AD
+/// structures of a time beacon (padded to 31 bytes).
+fn advertising_data(unix_time: u32, utc_offset_min: i16) -> Vec<u8> {
+    let mut data = vec![LOCAL_NAME.len() as u8 + 1, 0x09];
+    data.extend_from_slice(LOCAL_NAME.as_bytes());
+    data.extend_from_slice(&[9, 0xff]);
+    data.extend_from_slice(&COMPANY_IDENTIFIER.to_le_bytes());
+    data.extend_from_slice(&unix_time.to_le_bytes());
+    data.extend_from_slice(&utc_offset_min.to_le_bytes());
+    let mut params = vec![data.len() as u8];
+    params.extend_from_slice(&data);
+    params.resize(1 + MAX_ADVERTISING_DATA_LEN, 0);
+    params
+}
+
+/// UTC offset of the local time at the Unix time, in minutes.
+#[cfg(unix)]
+fn utc_offset_min(unix_time: u32) -> i16 {
+    let time = unix_time as libc::time_t;
+    // Safety: `localtime_r` only writes to the struct
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    (tm.tm_gmtoff / 60) as i16
+}
+
+#[cfg(not(unix))]
+fn utc_offset_min(_unix_time: u32) -> i16 {
+    0
+}
+
+/// The advertising data with the current time.
+fn current_advertising_data() -> Vec<u8> {
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+    advertising_data(unix_time, utc_offset_min(unix_time))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io::{self, Write};
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+
+    use super::*;
+
+    const BTPROTO_HCI: libc::c_int = 1;
+    const HCI_CHANNEL_RAW: u16 = 0;
+
+    #[repr(C)]
+    struct SockaddrHci {
+        hci_family: libc::sa_family_t,
+        hci_dev: u16,
+        hci_channel: u16,
+    }
+
+    /// Open a raw HCI socket on the adapter `hciN`, for sending commands.
+    fn open_socket(device: u16) -> io::Result<File> {
+        // Safety: The file descriptor is owned by the returned file (and
+        // closed by it on errors). The struct matches the kernel ABI.
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                BTPROTO_HCI,
+            );
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let file = File::from_raw_fd(fd);
+            let address = SockaddrHci {
+                hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+                hci_dev: device,
+                hci_channel: HCI_CHANNEL_RAW,
+            };
+            let result = libc::bind(
+                fd,
+                &address as *const SockaddrHci as *const libc::sockaddr,
+                mem::size_of::<SockaddrHci>() as libc::socklen_t,
+            );
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(file)
+        }
+    }
+
+    pub fn start(config: &config::TimeSync) -> Result<()> {
+        status!("Broadcasting the time on hci{}...", config.device);
+        let mut socket = open_socket(config.device)
+            .with_context(|| format!("Could not open HCI socket on hci{}", config.device))?;
+        // The command status is not checked, the commands fail e.g. if the
+        // adapter is already advertising
+        socket
+            .write_all(&command(
+                LE_SET_ADVERTISING_PARAMETERS,
+                &advertising_parameters(config.interval_ms),
+            ))
+            .context("Could not set the advertising parameters")?;
+        socket
+            .write_all(&command(
+                LE_SET_ADVERTISING_DATA,
+                &current_advertising_data(),
+            ))
+            .context("Could not set the advertising data")?;
+        socket
+            .write_all(&command(LE_SET_ADVERTISING_ENABLE, &[1]))
+            .context("Could not enable advertising")?;
+
+        std::thread::spawn(move || {
+            let mut failing = false;
+            loop {
+                std::thread::sleep(Duration::from_secs(1));
+                let result = socket.write_all(&command(
+                    LE_SET_ADVERTISING_DATA,
+                    &current_advertising_data(),
+                ));
+                match result {
+                    Err(e) if !failing => {
+                        status!("Warning: Could not update the time beacon: {}", e);
+                        failing = true;
+                    }
+                    Ok(()) if failing => {
+                        status!("Time beacon updated again");
+                        failing = false;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Start broadcasting the time beacons.
+#[cfg(target_os = "linux")]
+pub fn start(config: &config::TimeSync) -> Result<()> {
+    linux::start(config)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn start(_config: &config::TimeSync) -> Result<()> {
+    anyhow::bail!("The time sync is only available on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            command(LE_SET_ADVERTISING_ENABLE, &[1]),
+            vec![0x01, 0x0a, 0x20, 1, 1]
+        );
+        assert_eq!(
+            advertising_parameters(100),
+            vec![0xa0, 0, 0xa0, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0x07, 0]
+        );
+        // Clamped to the minimum interval
+        assert_eq!(&advertising_parameters(20)[..4], &[0xa0, 0, 0xa0, 0]);
+        assert_eq!(
+            &advertising_parameters(1000)[..4],
+            &[0x40, 0x06, 0x40, 0x06]
+        );
+    }
+
+    #[test]
+    fn time_beacon() {
+        let params = advertising_data(1_607_500_000, 60);
+        #[rustfmt::skip]
+        assert_eq!(
+            params,
+            vec![
+                23,
+                12, 0x09, b'S', b'e', b'n', b's', b'i', b'l', b'o', b'T', b'i', b'm', b'e',
+                9, 0xff, 0xff, 0xff, 0xe0, 0x80, 0xd0, 0x5f, 60, 0,
+                0, 0, 0, 0, 0, 0, 0, 0,
+            ]
+        );
+    }
+}
@@ -0,0 +1,22 @@
+//! Convert measurement values to the configured units.
+use crate::config::{TemperatureUnit, Units};
+use crate::measurement::{Humidity, Temperature};
+
+/// Format a temperature in the configured unit.
+pub fn temperature(temp: &Temperature, units: &Units) -> String {
+    match (units.temperature, units.milli) {
+        (TemperatureUnit::Celsius, true) => temp.as_millidegrees_celsius().to_string(),
+        (TemperatureUnit::Celsius, false) => format!("{:.3}", temp.as_degrees_celsius()),
+        (TemperatureUnit::Fahrenheit, true) => temp.as_millidegrees_fahrenheit().to_string(),
+        (TemperatureUnit::Fahrenheit, false) => format!("{:.3}", temp.as_degrees_fahrenheit()),
+    }
+}
+
+/// Format a relative humidity in the configured unit.
+pub fn humidity(humi: &Humidity, units: &Units) -> String {
+    if units.milli {
+        humi.as_millipercent().to_string()
+    } else {
+        format!("{:.3}", humi.as_percent())
+    }
+}
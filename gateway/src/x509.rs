@@ -0,0 +1,228 @@
+//! Certificates for the HTTPS server of the API.
+//!
+//! Loads the certificate chain and private key from PEM files, or generates a
+//! self-signed certificate (or a certificate request for ACME) with an ECDSA
+//! P-256 key. The certificates are generated with rcgen and parsed with
+//! x509-parser.
+#[cfg(feature = "acme")]
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{BufReader, Write};
+use std::net::IpAddr;
+use std::path::Path;
+#[cfg(feature = "acme")]
+use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
+use rustls::{Certificate, PrivateKey};
+#[cfg(feature = "acme")]
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use crate::config;
+
+/// Validity of a self-signed certificate.
+const VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 3600);
+
+/// The parameters of a certificate for the names (the first name is also the
+/// common name).
+fn params(names: &[String]) -> Result<CertificateParams> {
+    let common_name = names
+        .first()
+        .ok_or_else(|| anyhow!("A certificate needs at least one name"))?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name.as_str());
+    params.subject_alt_names = names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        })
+        .collect();
+    Ok(params)
+}
+
+/// Generate a self-signed certificate for the names (the first name is also
+/// the common name). Returns the certificate and the PKCS#8 private key (DER).
+pub fn self_signed(names: &[String], now: SystemTime) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut params = params(names)?;
+    params.not_before = now.into();
+    params.not_after = (now + VALIDITY).into();
+    let certificate = rcgen::Certificate::from_params(params)?;
+    Ok((
+        certificate.serialize_der()?,
+        certificate.serialize_private_key_der(),
+    ))
+}
+
+/// Generate a key and a certificate signing request (PKCS#10) for the names.
+/// Returns the request and the PKCS#8 private key (DER).
+#[cfg(feature = "acme")]
+pub fn certificate_request(names: &[String]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let certificate = rcgen::Certificate::from_params(params(names)?)?;
+    Ok((
+        certificate.serialize_request_der()?,
+        certificate.serialize_private_key_der(),
+    ))
+}
+
+/// The expiry and the DNS names of a certificate, `None` if it can't be
+/// parsed.
+#[cfg(feature = "acme")]
+pub fn expiry_and_names(certificate: &Certificate) -> Option<(SystemTime, Vec<String>)> {
+    let (_, certificate) = X509Certificate::from_der(&certificate.0).ok()?;
+    let not_after = u64::try_from(certificate.validity().not_after.timestamp()).ok()?;
+    let mut names = vec![];
+    if let Some(alt_names) = certificate.subject_alternative_name().ok()? {
+        for name in &alt_names.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Some((UNIX_EPOCH + Duration::from_secs(not_after), names))
+}
+
+/// The public key of a certificate (for an EC key the uncompressed point),
+/// `None` if it can't be parsed.
+#[cfg(feature = "acme")]
+pub fn public_key(certificate: &Certificate) -> Option<Vec<u8>> {
+    let (_, certificate) = X509Certificate::from_der(&certificate.0).ok()?;
+    Some(certificate.public_key().subject_public_key.data.to_vec())
+}
+
+/// Encode DER as PEM.
+pub fn pem(label: &str, der: &[u8]) -> String {
+    let pem = pem::Pem {
+        tag: label.into(),
+        contents: der.to_vec(),
+    };
+    let config = pem::EncodeConfig {
+        line_ending: pem::LineEnding::LF,
+    };
+    pem::encode_config(&pem, config)
+}
+
+/// The SHA-256 fingerprint of a certificate (e.g. for pinning it on clients).
+pub fn fingerprint(certificate: &Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, &certificate.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Generate a self-signed certificate and write it and the key (readable only
+/// by the owner) to the configured files.
+fn generate(config: &config::ApiTls) -> Result<()> {
+    let (certificate, private_key) = self_signed(&config.names, SystemTime::now())?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&config.private_key)
+        .and_then(|mut file| file.write_all(pem("PRIVATE KEY", &private_key).as_bytes()))
+        .with_context(|| format!("Could not write {}", config.private_key))?;
+    fs::write(&config.certificate, pem("CERTIFICATE", &certificate))
+        .with_context(|| format!("Could not write {}", config.certificate))?;
+    log::info!(
+        "Generated a self-signed certificate for {}",
+        config.names.join(", ")
+    );
+    Ok(())
+}
+
+/// Load the certificate chain and the private key, after generating them if
+/// they don't exist (and a self-signed certificate is configured).
+pub fn load(config: &config::ApiTls) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let exists = |path: &str| Path::new(path).exists();
+    if config.self_signed && !exists(&config.certificate) {
+        if exists(&config.private_key) {
+            bail!(
+                "The private key {} exists without the certificate {}",
+                config.private_key,
+                config.certificate
+            );
+        }
+        generate(config)?;
+    }
+
+    let read = |path: &str| -> Result<Vec<u8>> {
+        fs::read(path).with_context(|| format!("Could not read {}", path))
+    };
+    let certificates: Vec<Certificate> =
+        rustls_pemfile::certs(&mut &read(&config.certificate)?[..])
+            .map_err(|_| anyhow!("Invalid certificate {}", config.certificate))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+    if certificates.is_empty() {
+        bail!("No certificate found in {}", config.certificate);
+    }
+    let key = read(&config.private_key)?;
+    let invalid = |_| anyhow!("Invalid private key {}", config.private_key);
+    let mut keys =
+        rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(&key[..])).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(&key[..])).map_err(invalid)?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| anyhow!("No private key found in {}", config.private_key))?;
+    Ok((certificates, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "acme")]
+    #[test]
+    fn parse() {
+        let names: Vec<String> = vec!["gateway.example.com".into(), "10.0.0.1".into()];
+        let now = UNIX_EPOCH + Duration::from_secs(1_607_500_000);
+        let (certificate, _) = self_signed(&names, now).unwrap();
+        assert_eq!(
+            expiry_and_names(&Certificate(certificate)),
+            Some((now + VALIDITY, vec!["gateway.example.com".into()]))
+        );
+        assert_eq!(expiry_and_names(&Certificate(vec![0x30, 0x81])), None);
+
+        // The request is signed with the key of the request
+        let (request, _) = certificate_request(&names).unwrap();
+        let (_, request) =
+            x509_parser::certification_request::X509CertificationRequest::from_der(&request)
+                .unwrap();
+        let info = &request.certification_request_info;
+        ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_ASN1,
+            &info.subject_pki.subject_public_key.data,
+        )
+        .verify(info.raw, &request.signature_value.data)
+        .unwrap();
+    }
+
+    #[test]
+    fn pem_roundtrip() {
+        let (certificate, private_key) = self_signed(
+            &["localhost".into(), "192.168.1.2".into()],
+            SystemTime::now(),
+        )
+        .unwrap();
+        let encoded = pem("CERTIFICATE", &certificate);
+        assert!(encoded.lines().all(|line| line.len() <= 64));
+        let decoded = rustls_pemfile::certs(&mut encoded.as_bytes()).unwrap();
+        assert_eq!(decoded, [certificate]);
+        let encoded = pem("PRIVATE KEY", &private_key);
+        let decoded = rustls_pemfile::pkcs8_private_keys(&mut encoded.as_bytes());
+        assert_eq!(decoded.unwrap(), [private_key]);
+    }
+}